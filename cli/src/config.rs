@@ -6,20 +6,26 @@
 
 use bitcoin::util::bip32::ExtendedPubKey;
 use directories::BaseDirs;
+use emulator_connect::connections::cache::{self, CachedEmulator};
+use emulator_connect::connections::discovery::{Discovery, Undiscovered};
 use emulator_connect::connections::federated::FederatedEmulatorConnection;
-use emulator_connect::connections::hd::HDOracleEmulatorConnection;
+use emulator_connect::connections::hd::{HDOracleEmulatorConnection, DEFAULT_REQUEST_TIMEOUT};
+use emulator_connect::connections::health::{self, HealthMonitor, Probe};
+use emulator_connect::derivation::DerivationScheme;
+use emulator_connect::noise;
 use emulator_connect::CTVEmulator;
 use serde::*;
 use std::collections::HashMap;
 use std::convert::TryFrom;
-use std::net::ToSocketAddrs;
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
-use tokio::sync::Mutex;
 /// EmulatorConfig is used to determine how this sapio-cli instance should stub
 /// out CTV. Emulators are specified by EPK and interface address. Threshold
 /// should be <= emulators.len().
+///
+/// An emulator's address may be a `srv://` or `seed://` name to discover it
+/// by (see `emulator_connect::connections::discovery`).
 #[derive(Serialize, Deserialize, Debug)]
 pub struct EmulatorConfig {
     /// if the emulator should be used or not. We tag explicitly for convenience
@@ -28,44 +34,156 @@ pub struct EmulatorConfig {
     pub emulators: Vec<(ExtendedPubKey, String)>,
     /// threshold could be larger than u8, but that seems very unlikely/an error.
     pub threshold: u8,
+    /// refuse to talk to emulators over unencrypted connections
+    #[serde(default)]
+    pub require_noise: bool,
+    /// how the emulators derive their keys, which they must all share
+    #[serde(default)]
+    pub derivation: DerivationScheme,
+    /// a file to cache the emulators' clauses and signatures in, so that
+    /// compiling the same contract again needn't contact them. It is cleared
+    /// if the emulators, threshold, or derivation scheme change.
+    #[serde(default)]
+    pub cache: Option<PathBuf>,
+    /// whether to use real OP_CHECKTEMPLATEVERIFY instead of the emulators
+    #[serde(default)]
+    pub ctv: CTVMode,
+    /// probe a federation's emulators, and stop asking those which are down
+    /// to sign until they recover
+    #[serde(default)]
+    pub health_check: Option<HealthCheck>,
+}
+
+/// How a federation's emulators are probed, see
+/// `emulator_connect::connections::health`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HealthCheck {
+    /// seconds between probes of each emulator
+    #[serde(default = "HealthCheck::default_interval")]
+    pub interval: u64,
+    /// how many failures in a row mark an emulator down
+    #[serde(default = "HealthCheck::default_failures")]
+    pub failures: u32,
+}
+
+impl HealthCheck {
+    fn default_interval() -> u64 {
+        health::DEFAULT_PROBE_INTERVAL.as_secs()
+    }
+    fn default_failures() -> u32 {
+        health::DEFAULT_FAILURES
+    }
+}
+
+/// When real OP_CHECKTEMPLATEVERIFY is used instead of the emulators
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CTVMode {
+    /// always emulate CTV
+    Emulate,
+    /// always use CTV, for networks known to enforce it
+    Native,
+    /// use CTV if the api node reports it active, see
+    /// `emulator_connect::connections::native`
+    Detect,
+}
+
+impl Default for CTVMode {
+    fn default() -> Self {
+        CTVMode::Emulate
+    }
 }
 
 impl EmulatorConfig {
     /// Converts a config instance into an emulator trait object. Intenrally, we
     /// are using a Federated Emulator Connection if emulators.len() > 1, or a
-    /// bare HDOracleEmulatorConnection if emulators.len() == 1
-    pub fn get_emulator(&self) -> Result<Arc<dyn CTVEmulator>, Box<dyn std::error::Error>> {
+    /// bare HDOracleEmulatorConnection if emulators.len() == 1, either of which
+    /// is wrapped in a CachedEmulator if there is a cache file.
+    ///
+    /// Fails if any emulator's key is for a different network than `network`
+    /// (i.e., an xpub on a test network or a tpub on mainnet).
+    pub fn get_emulator(
+        &self,
+        network: bitcoin::network::constants::Network,
+    ) -> Result<Arc<dyn CTVEmulator>, Box<dyn std::error::Error>> {
         if self.emulators.len() < self.threshold as usize {
             Err(String::from("Too High Thresh"))?;
         } else if self.emulators.len() == 0 {
             Err(String::from("Too High Thresh"))?;
         }
-        let _n_emulators = self.emulators.len();
+        for (epk, host) in self.emulators.iter() {
+            if !emulator_connect::same_key_network(epk.network, network) {
+                Err(format!("Emulator {} Is For a Different Network", host))?;
+            }
+        }
         let rt = Arc::new(tokio::runtime::Runtime::new().unwrap());
         let secp = Arc::new(bitcoin::secp256k1::Secp256k1::new());
-        let mut it =
-            self.emulators
-                .iter()
-                .map(|(epk, host)| -> Result<_, Box<dyn std::error::Error>> {
-                    Ok(HDOracleEmulatorConnection {
-                        runtime: rt.clone(),
-                        connection: Mutex::new(None),
-                        reconnect: host.to_socket_addrs()?.next().unwrap(),
-                        root: *epk,
-                        secp: secp.clone(),
-                    })
-                });
-        Ok(if self.emulators.len() == 1 {
-            Arc::new(it.next().unwrap()?)
+        let mut members: Vec<Arc<dyn CTVEmulator>> = vec![];
+        let mut probes: Vec<Option<Arc<dyn Probe>>> = vec![];
+        let mut undiscovered = vec![];
+        for (idx, (epk, host)) in self.emulators.iter().enumerate() {
+            let noise_key = noise::ephemeral_key();
+            let reconnect = match Discovery::parse(host)? {
+                None => Some(host.parse()?),
+                Some(discovery) => tokio::task::block_in_place(|| {
+                    rt.block_on(discovery.find(
+                        epk,
+                        self.derivation,
+                        &noise_key,
+                        self.require_noise,
+                    ))
+                })?,
+            };
+            let reconnect = match reconnect {
+                Some(reconnect) => reconnect,
+                None => {
+                    eprintln!("Could Not Discover Emulator {}", host);
+                    undiscovered.push(idx);
+                    probes.push(None);
+                    members.push(Arc::new(Undiscovered::new(*epk, self.derivation)));
+                    continue;
+                }
+            };
+            let conn = Arc::new(HDOracleEmulatorConnection {
+                runtime: rt.clone(),
+                pool: Default::default(),
+                reconnect,
+                root: *epk,
+                secp: secp.clone(),
+                noise_key,
+                require_noise: self.require_noise,
+                auth_token: None,
+                timeout: Some(DEFAULT_REQUEST_TIMEOUT),
+                derivation: self.derivation,
+            });
+            probes.push(Some(conn.clone()));
+            members.push(conn);
+        }
+        let emulator: Arc<dyn CTVEmulator> = if members.len() == 1 {
+            members.pop().unwrap()
         } else {
-            Arc::new(FederatedEmulatorConnection::new(
-                it.map(|n| -> Result<_, Box<dyn std::error::Error>> {
-                    let b: Arc<dyn CTVEmulator> = Arc::new(n?);
-                    Ok(b)
-                })
-                .collect::<Result<Vec<_>, _>>()?,
-                self.threshold,
-            ))
+            let n = members.len();
+            let federation = FederatedEmulatorConnection::new(members, self.threshold);
+            match &self.health_check {
+                Some(check) => {
+                    let monitor = Arc::new(HealthMonitor::new(n).with_failures(check.failures));
+                    for idx in undiscovered {
+                        monitor.mark_down(idx, "Member Was Not Discovered");
+                    }
+                    monitor.spawn(probes, std::time::Duration::from_secs(check.interval));
+                    Arc::new(federation.with_health(monitor))
+                }
+                None => Arc::new(federation),
+            }
+        };
+        Ok(match &self.cache {
+            Some(path) => {
+                let roots: Vec<ExtendedPubKey> =
+                    self.emulators.iter().map(|(epk, _)| *epk).collect();
+                let config = cache::fingerprint(&roots[..], self.threshold, self.derivation);
+                Arc::new(CachedEmulator::open(emulator, path, config)?)
+            }
+            None => emulator,
         })
     }
 }
@@ -232,9 +350,7 @@ impl ConfigVerifier {
             3 => Ok(bitcoin::network::constants::Network::Bitcoin),
             11 => Ok(bitcoin::network::constants::Network::Testnet),
             7 => Ok(bitcoin::network::constants::Network::Regtest),
-            5 => {
-                todo!()
-            }
+            5 => Ok(bitcoin::network::constants::Network::Signet),
             _ => Err(ConfigError::TooManyActiveNetworks),
         }
     }
@@ -308,6 +424,11 @@ impl std::default::Default for ConfigVerifier {
                 threshold: 1u8,
                 emulators: vec![(ExtendedPubKey::from_str("tpubD6NzVbkrYhZ4Wf398td3H8YhWBsXx9Sxa4W3cQWkNW3N3DHSNB2qtPoUMXrA6JNaPxodQfRpoZNE5tGM9iZ4xfUEFRJEJvfs8W5paUagYCE").unwrap(),
                     "ctv.d31373.org:8367".into())],
+                require_noise: false,
+                derivation: Default::default(),
+                cache: None,
+                ctv: Default::default(),
+                health_check: None,
             }),
             plugin_map: None,
         };
@@ -4,11 +4,9 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use bitcoin::consensus::serialize;
 use bitcoin::consensus::Decodable;
 use bitcoin::hashes::Hash;
 use bitcoin::secp256k1::Secp256k1;
-use bitcoin::util::bip32::ExtendedPrivKey;
 use bitcoin::util::bip32::ExtendedPubKey;
 use bitcoin::util::psbt::PartiallySignedTransaction;
 use bitcoin::Amount;
@@ -18,18 +16,25 @@ use bitcoincore_rpc_async as rpc;
 use bitcoincore_rpc_async::RpcApi;
 use clap::clap_app;
 use config::*;
+use emulator_connect::connections::native::PassThroughEmulator;
+use emulator_connect::offline;
 use emulator_connect::servers::hd::HDOracleEmulator;
+use emulator_connect::servers::reload;
+use emulator_connect::servers::seed;
+use emulator_connect::servers::tor;
 use emulator_connect::CTVAvailable;
 use emulator_connect::CTVEmulator;
 use sapio::contract::Compiled;
 use sapio::contract::Context;
 use sapio::util::extended_address::ExtendedAddress;
+use sapio_base::psbt::PSBTVersion;
 use sapio_base::txindex::TxIndex;
 use sapio_base::txindex::TxIndexLogger;
 use sapio_base::util::CTVHash;
 use sapio_wasm_plugin::host::{PluginHandle, WasmPluginHandle};
 use std::collections::HashMap;
 use std::rc::Rc;
+use std::str::FromStr;
 use std::sync::Arc;
 #[deny(missing_docs)]
 use tokio::io::AsyncReadExt;
@@ -62,11 +67,31 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 (about: "Show a psbt")
                 (@arg psbt: -p --psbt +takes_value +required #{1,2} {check_file} "The file containing the PSBT to Get a Key For")
             )
+            (@subcommand export =>
+                (about: "Export PSBTs to be signed by an offline emulator (emulator_server --offline)")
+                (@arg psbt: -p --psbt +takes_value +required +multiple {check_file} "The files containing the PSBTs to Sign")
+                (@arg oracle: --oracle +takes_value "The root xpub of the emulator to sign with (defaults to the configured emulator, if there is only one)")
+                (@arg out: -o --output +takes_value +required {check_file_not} "The file to save the request bundle to")
+                (@arg qr: --qr "Write the bundle as QR chunks, one per line")
+            )
+            (@subcommand import =>
+                (about: "Merge an offline emulator's signatures into the PSBTs exported for it")
+                (@arg bundle: -b --bundle +takes_value +required {check_file} "The file containing the response bundle (JSON, or QR chunks one per line)")
+                (@arg psbt: -p --psbt +takes_value +required +multiple {check_file} "The files containing the exported PSBTs, in the order they were exported")
+                (@arg oracle: --oracle +takes_value "The root xpub of the emulator which signed (defaults to the configured emulator, if there is only one)")
+                (@arg out: -o --output +takes_value +required +multiple {check_file_not} "The files to save the signed PSBTs to, one for each PSBT")
+            )
             (@subcommand server =>
                 (about: "run an emulation server")
-                (@arg sync: --sync  "Run in Synchronous mode")
-                (@arg seed: +takes_value +required {check_file} "The file containing the Seed")
-                (@arg interface: +required +takes_value "The Interface to Bind")
+                (@arg log: --log +takes_value "Log filter, e.g. info or emulator_connect=debug (defaults to RUST_LOG, or info)")
+                (@arg seed: +takes_value +required {check_file} "The file containing the Seed (or xprv), which may be encrypted with emulator_server encrypt_seed")
+                (@arg unlock: --unlock +takes_value "How to get the passphrase of an encrypted seed: env:VAR, prompt, or socket:/path (defaults to EMULATOR_SEED_PASSPHRASE if set, otherwise prompt)")
+                (@arg interface: +required +takes_value "The Interface to Bind (host:port, tcp://host:port, http://host:port for JSON-RPC, or unix:///path)")
+                (@arg tor: --tor +takes_value "Also publish the oracle as a Tor onion service, via the Tor control port at this address (e.g. 127.0.0.1:9051)")
+                (@arg onion_key: --("onion-key") +takes_value requires[tor] "File holding the onion service's key, created if missing, so that its address is stable across restarts")
+                (@arg derivation: --derivation +takes_value "How keys are derived for a CTV hash: unhardened (the default), short, or hardened, clients must be configured with the same scheme")
+                (@arg watch_only: --("watch-only") "Run without the private key, verifying signed transactions rather than signing: the seed file holds the root xpub instead")
+                (@arg config: --config +takes_value {check_file} "JSON file with the policy, limits, allowed keys, and log filter, reloaded on SIGHUP")
             )
         )
         (@subcommand contract =>
@@ -77,9 +102,31 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 (@arg outpoint: --outpoint +takes_value "Use this specific outpoint")
                 (@arg json: "JSON to Bind")
             )
+            (@subcommand paths =>
+                (about: "List the annotated spend paths of a compiled contract")
+                (@arg json: "JSON of the compiled contract")
+            )
+            (@subcommand explain =>
+                (about: "List the transactions of a compiled contract, with the keys and timelocks each requires")
+                (@arg role: --role +takes_value "Only list the transactions of spend paths for this role")
+                (@arg keys: --keys +takes_value "Only list the transactions these comma separated keys may take alone")
+                (@arg json: "JSON of the compiled contract")
+            )
+            (@subcommand graph =>
+                (about: "Render the transaction graph of a compiled contract")
+                (@arg format: --format +takes_value "Graph format, dot (default) or mermaid")
+                (@arg json: "JSON of the compiled contract")
+            )
+            (@subcommand diff =>
+                (about: "List the branches, amounts, and timelocks that differ between two compiled contracts")
+                (@arg json: --json "Output the differences as JSON")
+                (@arg old: +required {check_file} "File with the JSON of the old compiled contract")
+                (@arg new: +required {check_file} "File with the JSON of the new compiled contract")
+            )
             (@subcommand for_tux =>
                 (about: "Translate for TUX viewer")
                 (@arg psbts: --psbt "Output in PSBT format instead of tx hex.")
+                (@arg psbt_v2: --("psbt-v2") requires[psbts] "Output PSBTs as version 2 (BIP-370).")
                 (@arg finalize: --finalize "Attempt finalizing via miniscript...")
                 (@arg json: "JSON to translate")
             )
@@ -120,14 +167,25 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let config = Config::setup(&matches, "org", "judica", "sapio-cli").await?;
 
     let cfg = config.active;
-    let emulator: Arc<dyn CTVEmulator> = if let Some(emcfg) = &cfg.emulator_nodes {
-        if emcfg.enabled {
-            emcfg.get_emulator()?.into()
-        } else {
-            Arc::new(CTVAvailable)
-        }
-    } else {
-        Arc::new(CTVAvailable)
+    let emulator: Arc<dyn CTVEmulator> = match &cfg.emulator_nodes {
+        Some(emcfg) if emcfg.enabled => match emcfg.ctv {
+            CTVMode::Emulate => emcfg.get_emulator(config.network)?,
+            CTVMode::Native => Arc::new(CTVAvailable),
+            CTVMode::Detect => {
+                let client =
+                    rpc::Client::new(cfg.api_node.url.clone(), cfg.api_node.auth.clone()).await?;
+                // getdeploymentinfo is only in newer nodes
+                let info: serde_json::Value = match client.call("getdeploymentinfo", &[]).await {
+                    Ok(info) => info,
+                    Err(_) => client.call("getblockchaininfo", &[]).await?,
+                };
+                Arc::new(PassThroughEmulator::from_deployment_info(
+                    emcfg.get_emulator(config.network)?,
+                    &info,
+                ))
+            }
+        },
+        _ => Arc::new(CTVAvailable),
     };
     let plugin_map = cfg.plugin_map.map(|x| {
         x.into_iter()
@@ -146,30 +204,156 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     match matches.subcommand() {
         Some(("emulator", sign_matches)) => match sign_matches.subcommand() {
             Some(("sign", args)) => {
-                let psbt = decode_psbt_file(args, "psbt")?;
+                let (psbt, version) = decode_psbt_file(args, "psbt")?;
                 let psbt = emulator.sign(psbt)?;
-                let bytes = serialize(&psbt);
-                std::fs::write(args.value_of_os("out").unwrap(), &base64::encode(bytes))?;
+                std::fs::write(
+                    args.value_of_os("out").unwrap(),
+                    &encode_psbt(&psbt, version),
+                )?;
             }
             Some(("get_key", args)) => {
-                let psbt = decode_psbt_file(args, "psbt")?;
+                let (psbt, _) = decode_psbt_file(args, "psbt")?;
                 let h = emulator.get_signer_for(psbt.extract_tx().get_ctv_hash(0))?;
                 println!("{}", h);
             }
             Some(("show", args)) => {
-                let psbt = decode_psbt_file(args, "psbt")?;
-                println!("{:?}", psbt);
+                let (psbt, version) = decode_psbt_file(args, "psbt")?;
+                println!("{:?} {:?}", version, psbt);
+            }
+            Some(("export", args)) => {
+                let root = offline_root(args, &cfg.emulator_nodes)?;
+                let psbts = decode_psbt_files(args, "psbt")?;
+                let psbts = psbts.into_iter().map(|(psbt, _)| psbt).collect();
+                let bundle = offline::RequestBundle::new(root, psbts).to_vec()?;
+                let bundle = if args.is_present("qr") {
+                    offline::to_chunks(&bundle[..], offline::CHUNK_SIZE)
+                        .join("\n")
+                        .into_bytes()
+                } else {
+                    bundle
+                };
+                std::fs::write(args.value_of_os("out").unwrap(), &bundle[..])?;
+            }
+            Some(("import", args)) => {
+                let root = offline_root(args, &cfg.emulator_nodes)?;
+                let (psbts, versions): (Vec<_>, Vec<_>) =
+                    decode_psbt_files(args, "psbt")?.into_iter().unzip();
+                let outs: Vec<_> = args.values_of_os("out").unwrap().collect();
+                if outs.len() != psbts.len() {
+                    return Err("Give an Output File For Each PSBT".into());
+                }
+                let bundle = std::fs::read(args.value_of_os("bundle").unwrap())?;
+                let merged =
+                    offline::ResponseBundle::from_slice(&bundle[..])?.merge(&root, psbts)?;
+                for ((out, psbt), version) in outs.into_iter().zip(merged).zip(versions) {
+                    match psbt {
+                        Ok(psbt) => std::fs::write(out, &encode_psbt(&psbt, version))?,
+                        Err(e) => eprintln!("Not Signed ({}): {}", out.to_string_lossy(), e),
+                    }
+                }
             }
             Some(("server", args)) => {
                 let filename = args.value_of("seed").unwrap();
-                let contents = tokio::fs::read(filename).await?;
 
-                let root = ExtendedPrivKey::new_master(config.network, &contents[..]).unwrap();
-                let pk_root = ExtendedPubKey::from_private(&Secp256k1::new(), &root);
-                let oracle = HDOracleEmulator::new(root, args.is_present("sync"));
-                let server = oracle.bind(args.value_of("interface").unwrap());
+                let (root, pk_root) = if args.is_present("watch_only") {
+                    let contents = tokio::fs::read(filename).await?;
+                    let xpub: ExtendedPubKey =
+                        std::str::from_utf8(&contents[..])?.trim().parse()?;
+                    if !emulator_connect::same_key_network(xpub.network, config.network) {
+                        return Err("Watch-Only Key Is For a Different Network".into());
+                    }
+                    (None, xpub)
+                } else {
+                    let unlock = match args.value_of("unlock") {
+                        Some(unlock) => unlock.parse()?,
+                        None if std::env::var_os("EMULATOR_SEED_PASSPHRASE").is_some() => {
+                            seed::Unlock::Env("EMULATOR_SEED_PASSPHRASE".into())
+                        }
+                        None => seed::Unlock::Prompt,
+                    };
+                    let root = unlock.root(filename, config.network).await?;
+                    let pk_root = ExtendedPubKey::from_private(&Secp256k1::new(), &root);
+                    (Some(root), pk_root)
+                };
+                let filter = match args.value_of("log") {
+                    Some(directives) => tracing_subscriber::EnvFilter::new(directives),
+                    None => tracing_subscriber::EnvFilter::try_from_default_env()
+                        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
+                };
+                let subscriber = tracing_subscriber::fmt()
+                    .with_env_filter(filter)
+                    .with_filter_reloading();
+                let filter_handle = subscriber.reload_handle();
+                subscriber.init();
+                let derivation = match args.value_of("derivation") {
+                    Some(scheme) => scheme.parse()?,
+                    None => Default::default(),
+                };
+                let oracle = match root {
+                    Some(root) => HDOracleEmulator::new(root),
+                    None => HDOracleEmulator::watch_only(pk_root),
+                };
+                let (mut oracle, shutdown) = oracle.with_derivation(derivation).with_shutdown();
+                tokio::spawn(shutdown.shutdown_on_signal());
+                if let Some(path) = args.value_of("config") {
+                    let server_config = reload::ServerConfig::from_file(path)?;
+                    if let Some(log) = &server_config.log {
+                        filter_handle.reload(tracing_subscriber::EnvFilter::new(log))?;
+                    }
+                    let (configured, reloader) = oracle.with_reload(&server_config);
+                    oracle = configured;
+                    tokio::spawn(reloader.reload_on_signal(path.into(), move |c| {
+                        if let Some(log) = &c.log {
+                            let filter = tracing_subscriber::EnvFilter::new(log);
+                            if let Err(e) = filter_handle.reload(filter) {
+                                eprintln!("Could Not Reload Log Filter: {}", e);
+                            }
+                        }
+                    }));
+                }
+                let interface = args.value_of("interface").unwrap();
                 println!("Running Oracle With Key: {}", pk_root);
-                server.await?;
+                // held until the server stops, as dropping it removes the service
+                let _onion = match args.value_of("tor") {
+                    Some(control) => {
+                        if interface.starts_with("unix://") {
+                            return Err("Onion Services Require a TCP Interface".into());
+                        }
+                        let local = interface
+                            .strip_prefix("http://")
+                            .or_else(|| interface.strip_prefix("tcp://"))
+                            .unwrap_or(interface);
+                        let local = tokio::net::lookup_host(local)
+                            .await?
+                            .next()
+                            .ok_or("Could Not Resolve Interface")?;
+                        let key_file = args.value_of("onion_key");
+                        let key = match key_file {
+                            Some(f) if std::path::Path::new(f).exists() => {
+                                Some(tokio::fs::read_to_string(f).await?.trim().to_string())
+                            }
+                            _ => None,
+                        };
+                        let service =
+                            tor::publish(control, None, local, local.port(), key.as_deref())
+                                .await?;
+                        if let (Some(f), None) = (key_file, key) {
+                            seed::write_secret(f, service.key.as_bytes())?;
+                        }
+                        println!("Running Oracle At: {}:{}", service.onion, local.port());
+                        Some(service)
+                    }
+                    None => None,
+                };
+                if let Some(path) = interface.strip_prefix("unix://") {
+                    oracle.bind_unix(path).await?
+                } else if let Some(addr) = interface.strip_prefix("http://") {
+                    oracle.bind_http(addr).await?
+                } else {
+                    oracle
+                        .bind(interface.strip_prefix("tcp://").unwrap_or(interface))
+                        .await?
+                }
             }
             _ => unreachable!(),
         },
@@ -262,6 +446,106 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
                 println!("{}", serde_json::to_string_pretty(&(txns, meta))?);
             }
+            Some(("paths", args)) => {
+                let j: Compiled = if let Some(json) = args.value_of("json") {
+                    serde_json::from_str(json)?
+                } else {
+                    let mut s = String::new();
+                    tokio::io::stdin().read_to_string(&mut s).await?;
+                    serde_json::from_str(&s)?
+                };
+                for (name, path) in j.spend_paths.iter() {
+                    println!("{} -- {}", name, path.label);
+                    if !path.roles.is_empty() {
+                        println!("    roles: {}", path.roles.join(", "));
+                    }
+                    if let Some(doc) = &path.doc {
+                        println!("    {}", doc);
+                    }
+                    for h in path.templates.iter() {
+                        println!("    template: {}", h);
+                    }
+                }
+            }
+            Some(("explain", args)) => {
+                use sapio::contract::explore::Explorer;
+                let j: Compiled = if let Some(json) = args.value_of("json") {
+                    serde_json::from_str(json)?
+                } else {
+                    let mut s = String::new();
+                    tokio::io::stdin().read_to_string(&mut s).await?;
+                    serde_json::from_str(&s)?
+                };
+                let keys = args
+                    .value_of("keys")
+                    .map(|ks| {
+                        ks.split(',')
+                            .map(|k| bitcoin::PublicKey::from_str(k.trim()))
+                            .collect::<Result<Vec<_>, _>>()
+                    })
+                    .transpose()?;
+                let explorer = Explorer::new(&j);
+                let mut steps: Vec<_> = match &keys {
+                    Some(keys) => explorer.unilateral(keys).collect(),
+                    None => explorer.iter().collect(),
+                };
+                if let Some(role) = args.value_of("role") {
+                    steps.retain(|s| {
+                        s.spend_path
+                            .map_or(false, |(_, p)| p.roles.iter().any(|r| r == role))
+                    });
+                }
+                for step in steps {
+                    let indent = "    ".repeat(step.path.len());
+                    let label = step.template.metadata_map_s2s.label().unwrap_or("-");
+                    match step.spend_path {
+                        Some((name, _)) => println!("{}{} -- {}", indent, name, label),
+                        None => println!("{}{}", indent, label),
+                    }
+                    println!("{}    template: {}", indent, step.template.hash());
+                    for r in step.requirements.iter() {
+                        let keys: Vec<_> = r.keys.iter().map(|k| k.to_string()).collect();
+                        let timelocks: Vec<_> = r.timelocks.iter().map(|t| t.to_string()).collect();
+                        println!(
+                            "{}    requires: keys [{}], timelocks [{}]{}",
+                            indent,
+                            keys.join(", "),
+                            timelocks.join(", "),
+                            if r.preimages.is_empty() {
+                                ""
+                            } else {
+                                ", preimages"
+                            }
+                        );
+                    }
+                }
+            }
+            Some(("graph", args)) => {
+                use sapio::contract::graph::{render, Format};
+                let j: Compiled = if let Some(json) = args.value_of("json") {
+                    serde_json::from_str(json)?
+                } else {
+                    let mut s = String::new();
+                    tokio::io::stdin().read_to_string(&mut s).await?;
+                    serde_json::from_str(&s)?
+                };
+                let format = Format::from_str(args.value_of("format").unwrap_or("dot"))?;
+                print!("{}", render(&j, format));
+            }
+            Some(("diff", args)) => {
+                let old: Compiled =
+                    serde_json::from_slice(&std::fs::read(args.value_of_os("old").unwrap())?)?;
+                let new: Compiled =
+                    serde_json::from_slice(&std::fs::read(args.value_of_os("new").unwrap())?)?;
+                let changes = sapio::contract::diff::diff(&old, &new);
+                if args.is_present("json") {
+                    println!("{}", serde_json::to_string_pretty(&changes)?);
+                } else {
+                    for change in changes.iter() {
+                        println!("{}", change);
+                    }
+                }
+            }
             Some(("for_tux", args)) => {
                 use serde::{Deserialize, Serialize};
                 /// A `Program` is a wrapper type for a list of
@@ -292,6 +576,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     serde_json::from_str(&s)?
                 };
                 let encode_as_psbt = args.is_present("psbts");
+                let psbt_version = if args.is_present("psbt_v2") {
+                    PSBTVersion::V2
+                } else {
+                    PSBTVersion::V0
+                };
                 let finalize_psbt = args.is_present("finalize");
                 let secp = Secp256k1::new();
                 let program = Program {
@@ -305,8 +594,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                                     .ok();
                             }
                             let h = if encode_as_psbt {
-                                let bytes = serialize(&u);
-                                base64::encode(bytes)
+                                encode_psbt(&u, psbt_version)
                             } else {
                                 bitcoin::consensus::encode::serialize_hex(&u.extract_tx())
                             };
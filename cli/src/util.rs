@@ -4,8 +4,9 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use bitcoin::consensus::deserialize;
+use bitcoin::util::bip32::ExtendedPubKey;
 use bitcoin::util::psbt::PartiallySignedTransaction;
+use sapio_base::psbt::{self, PSBTVersion};
 
 /// Checks that a file exists during argument parsing
 ///
@@ -24,13 +25,46 @@ pub fn check_file_not(p: &str) -> Result<(), String> {
     Ok(())
 }
 
-/// Reads a PSBT from a file and checks that it is correctly formatted
+/// Reads a PSBT from a file and checks that it is correctly formatted,
+/// returning it with the version it was written in.
 pub fn decode_psbt_file(
     a: &clap::ArgMatches,
     b: &str,
-) -> Result<PartiallySignedTransaction, Box<dyn std::error::Error>> {
-    let bytes = std::fs::read_to_string(a.value_of_os(b).unwrap())?;
+) -> Result<(PartiallySignedTransaction, PSBTVersion), Box<dyn std::error::Error>> {
+    read_psbt(a.value_of_os(b).unwrap())
+}
+
+/// Reads the PSBTs from every file given for an argument, in order
+pub fn decode_psbt_files(
+    a: &clap::ArgMatches,
+    b: &str,
+) -> Result<Vec<(PartiallySignedTransaction, PSBTVersion)>, Box<dyn std::error::Error>> {
+    a.values_of_os(b).unwrap().map(read_psbt).collect()
+}
+
+/// Reads a base64 PSBT, of either version, from the file at `p`
+fn read_psbt(
+    p: &std::ffi::OsStr,
+) -> Result<(PartiallySignedTransaction, PSBTVersion), Box<dyn std::error::Error>> {
+    let bytes = std::fs::read_to_string(p)?;
     let bytes = base64::decode(&bytes.trim()[..])?;
-    let psbt: PartiallySignedTransaction = deserialize(&bytes[..])?;
-    Ok(psbt)
+    Ok(psbt::decode(&bytes[..])?)
+}
+
+/// A PSBT in base64, written as `version`
+pub fn encode_psbt(psbt: &PartiallySignedTransaction, version: PSBTVersion) -> String {
+    base64::encode(psbt::encode(psbt, version))
+}
+
+/// The root of the emulator an offline bundle is for: given with `--oracle`,
+/// or the only configured emulator
+pub fn offline_root(
+    a: &clap::ArgMatches,
+    emulators: &Option<crate::config::EmulatorConfig>,
+) -> Result<ExtendedPubKey, Box<dyn std::error::Error>> {
+    match (a.value_of("oracle"), emulators) {
+        (Some(xpub), _) => Ok(xpub.parse()?),
+        (None, Some(emcfg)) if emcfg.emulators.len() == 1 => Ok(emcfg.emulators[0].0),
+        (None, _) => Err("Choose an Emulator With --oracle".into()),
+    }
 }
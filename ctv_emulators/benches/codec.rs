@@ -0,0 +1,66 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Compares the JSON and CBOR encodings of oracle messages.
+//!
+//! Run with `cargo bench -p ctv_emulators`; the encoded sizes are printed
+//! before each group.
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::{OutPoint, Script, Transaction, TxIn, TxOut};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use emulator_connect::msgs::{Codec, Request, PSBT};
+
+fn request(n: usize) -> Request {
+    let tx = Transaction {
+        version: 2,
+        lock_time: 0,
+        input: (0..n)
+            .map(|i| TxIn {
+                previous_output: OutPoint::new(Default::default(), i as u32),
+                script_sig: Script::new(),
+                sequence: 0xffff_ffff,
+                witness: vec![],
+            })
+            .collect(),
+        output: (0..n)
+            .map(|i| TxOut {
+                value: 1000 * i as u64,
+                script_pubkey: Script::new_v0_wsh(&Default::default()),
+            })
+            .collect(),
+    };
+    let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+    for input in psbt.inputs.iter_mut() {
+        input.witness_utxo = Some(TxOut {
+            value: 100_000,
+            script_pubkey: Script::new_v0_wsh(&Default::default()),
+        });
+    }
+    Request::SignPSBT(PSBT(psbt))
+}
+
+fn codecs(c: &mut Criterion) {
+    for n in [1usize, 10, 100].iter() {
+        let r = request(*n);
+        let mut group = c.benchmark_group(format!("sign_psbt_{}_inputs", n));
+        for codec in [Codec::Json, Codec::Cbor].iter() {
+            let bytes = codec.encode(&r).unwrap();
+            println!("{:?} {} inputs: {} bytes", codec, n, bytes.len());
+            group.bench_with_input(BenchmarkId::new("encode", format!("{:?}", codec)), &r, |b, r| {
+                b.iter(|| codec.encode(black_box(r)).unwrap())
+            });
+            group.bench_with_input(
+                BenchmarkId::new("decode", format!("{:?}", codec)),
+                &bytes,
+                |b, v| b.iter(|| codec.decode::<Request>(black_box(&v[..])).unwrap()),
+            );
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, codecs);
+criterion_main!(benches);
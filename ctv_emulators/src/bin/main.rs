@@ -6,30 +6,215 @@
 
 use bitcoin::secp256k1::Secp256k1;
 use bitcoin::util::bip32::*;
+use bitcoin::Network;
+use clap::clap_app;
+use emulator_connect::derivation::DerivationScheme;
+use emulator_connect::offline;
 use emulator_connect::servers::hd::*;
+use emulator_connect::servers::{history, policy, reload, seed};
+use serde_derive::Deserialize;
+use std::path::PathBuf;
+
+/// The passphrase an encrypted seed file is decrypted (or encrypted) with, if
+/// set
+const SEED_PASSPHRASE: &str = "EMULATOR_SEED_PASSPHRASE";
+/// The BIP-39 passphrase of a mnemonic, if it has one
+const MNEMONIC_PASSPHRASE: &str = "EMULATOR_MNEMONIC_PASSPHRASE";
+
+/// The server's config file. Flags given on the command line take precedence
+/// over it.
+///
+/// The fields of `reload::ServerConfig` (policy, limits, authorization, and
+/// log filter) are re-read from the file on SIGHUP, the others only at start.
+#[derive(Deserialize, Default)]
+struct Config {
+    /// the interface to bind
+    listen: Option<String>,
+    /// a file with the root seed or xprv, raw or encrypted (see `seed`)
+    seed: Option<PathBuf>,
+    /// how to get the passphrase of an encrypted seed, see `seed::Unlock`
+    unlock: Option<String>,
+    /// a file with a BIP-39 mnemonic to derive the root seed from
+    mnemonic: Option<PathBuf>,
+    /// the network of the root key
+    network: Option<Network>,
+    #[serde(default)]
+    derivation: DerivationScheme,
+    /// a directory to keep the signing history (and the amounts signed for
+    /// against the spend cap) in, see `history`
+    history: Option<PathBuf>,
+    #[serde(flatten)]
+    server: reload::ServerConfig,
+}
 
-use tokio;
-use tokio::io::AsyncReadExt;
 #[tokio::main]
-async fn main() -> Result<(), std::io::Error> {
-    let filename = std::env::args().nth(1).expect("No Seed File Provided");
-    let mut file = tokio::fs::File::open(filename)
-        .await
-        .expect("File Not Found");
-    let mut contents = vec![];
-    file.read_to_end(&mut contents).await?;
-
-    let root =
-        ExtendedPrivKey::new_master(bitcoin::network::constants::Network::Regtest, &contents[..])
-            .unwrap();
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let matches = clap_app!(emulator_server =>
+        (about: "Run a CTV emulator oracle")
+        (@arg config: -c --config +takes_value "JSON config file with any of the options below, along with the policy, limits, and allowed keys, which are reloaded on SIGHUP")
+        (@arg listen: -l --listen +takes_value "The interface to bind (host:port, tcp://host:port, http://host:port for JSON-RPC, or unix:///path)")
+        (@arg seed: -s --seed +takes_value conflicts_with[mnemonic] "File containing the root seed or xprv, which may be encrypted (see --unlock)")
+        (@arg unlock: --unlock +takes_value "How to get the passphrase of an encrypted seed: env:VAR, prompt, or socket:/path to wait for it on a unix socket (defaults to EMULATOR_SEED_PASSPHRASE if set, otherwise prompt)")
+        (@arg mnemonic: -m --mnemonic +takes_value "File containing a BIP-39 mnemonic for the root seed (with the passphrase in EMULATOR_MNEMONIC_PASSPHRASE, if any)")
+        (@arg network: -n --network +takes_value "The network of the root key: bitcoin, testnet, signet, or regtest (the default)")
+        (@arg policy: --policy +takes_value "JSON file with the rules the oracle checks before signing")
+        (@arg log: --log +takes_value "Log filter, e.g. info or emulator_connect=debug (defaults to RUST_LOG, or info)")
+        (@arg derivation: --derivation +takes_value "How keys are derived for a CTV hash: unhardened (the default), short, or hardened")
+        (@arg history: --history +takes_value "Directory to keep the signing history in, so that a CTV hash is never signed for two transactions and spend caps are kept across restarts")
+        (@arg offline: --offline +takes_value "Sign the request bundle in this file (JSON or QR chunks, one per line) and exit, rather than listening, for an air-gapped oracle")
+        (@arg response: --response +takes_value requires[offline] "The file to write the response bundle to (defaults to the request file with .response appended)")
+        (@arg qr: --qr requires[offline] "Write the response bundle as QR chunks, one per line")
+        (@subcommand encrypt_seed =>
+            (about: "Encrypt a seed file with the passphrase in EMULATOR_SEED_PASSPHRASE, or prompt for one")
+            (@arg input: +required +takes_value "The plaintext seed (or xprv) file")
+            (@arg output: +required +takes_value "The file to write the encrypted seed to")
+        )
+    )
+    .get_matches();
+
+    if let Some(("encrypt_seed", args)) = matches.subcommand() {
+        let passphrase = match std::env::var(SEED_PASSPHRASE) {
+            Ok(passphrase) => passphrase,
+            Err(_) => {
+                let passphrase = seed::prompt("New Seed Passphrase: ")?;
+                if seed::prompt("Confirm Passphrase: ")? != passphrase {
+                    return Err("Passphrases Do Not Match".into());
+                }
+                passphrase
+            }
+        };
+        let plaintext = std::fs::read(args.value_of("input").unwrap())?;
+        let encrypted = seed::encrypt(&plaintext[..], &passphrase)?;
+        seed::write_secret(args.value_of("output").unwrap(), &encrypted[..])?;
+        return Ok(());
+    }
+
+    let config_path = matches.value_of("config").map(PathBuf::from);
+    let mut config: Config = match &config_path {
+        Some(path) => serde_json::from_slice(&std::fs::read(path)?[..])?,
+        None => Default::default(),
+    };
+    if let Some(listen) = matches.value_of("listen") {
+        config.listen = Some(listen.into());
+    }
+    if let Some(path) = matches.value_of("seed") {
+        config.seed = Some(path.into());
+        config.mnemonic = None;
+    }
+    if let Some(unlock) = matches.value_of("unlock") {
+        config.unlock = Some(unlock.into());
+    }
+    if let Some(path) = matches.value_of("mnemonic") {
+        config.mnemonic = Some(path.into());
+        config.seed = None;
+    }
+    if let Some(network) = matches.value_of("network") {
+        config.network = Some(network.parse()?);
+    }
+    if let Some(scheme) = matches.value_of("derivation") {
+        config.derivation = scheme.parse()?;
+    }
+    if let Some(path) = matches.value_of("history") {
+        config.history = Some(path.into());
+    }
+    // flags which override the reloadable config, on every reload
+    let policy_flag: Option<policy::PolicyConfig> = match matches.value_of("policy") {
+        Some(path) => Some(serde_json::from_slice(&std::fs::read(path)?[..])?),
+        None => None,
+    };
+    let log_flag = matches.value_of("log").map(String::from);
+    let overrides = move |c: &mut reload::ServerConfig| {
+        if let Some(policy) = &policy_flag {
+            c.policy = Some(policy.clone());
+        }
+        if let Some(log) = &log_flag {
+            c.log = Some(log.clone());
+        }
+    };
+    overrides(&mut config.server);
+
+    let filter = match &config.server.log {
+        Some(directives) => tracing_subscriber::EnvFilter::new(directives),
+        None => tracing_subscriber::EnvFilter::try_from_default_env()
+            .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
+    };
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_filter_reloading();
+    let filter_handle = subscriber.reload_handle();
+    subscriber.init();
+
+    let network = config.network.unwrap_or(Network::Regtest);
+    let root = match (&config.seed, &config.mnemonic) {
+        (Some(path), _) => {
+            let unlock = match &config.unlock {
+                Some(unlock) => unlock.parse()?,
+                None if std::env::var_os(SEED_PASSPHRASE).is_some() => {
+                    seed::Unlock::Env(SEED_PASSPHRASE.into())
+                }
+                None => seed::Unlock::Prompt,
+            };
+            unlock.root(path, network).await?
+        }
+        (None, Some(path)) => {
+            let phrase = std::fs::read_to_string(path)?;
+            let passphrase = std::env::var(MNEMONIC_PASSPHRASE).unwrap_or_default();
+            seed::mnemonic_root(&phrase, &passphrase, network)?
+        }
+        (None, None) => return Err("No Seed or Mnemonic Given".into()),
+    };
     let pk_root = ExtendedPubKey::from_private(&Secp256k1::new(), &root);
-    let oracle = HDOracleEmulator::new(root, true);
-    let server = oracle.bind(
-        std::env::args()
-            .nth(2)
-            .expect("No Interface given (e.g., 127.0.0.1:8080"),
-    );
+    let (mut oracle, shutdown) = HDOracleEmulator::new(root)
+        .with_derivation(config.derivation)
+        .with_shutdown();
+    tokio::spawn(shutdown.shutdown_on_signal());
+    if let Some(path) = &config.history {
+        let history = history::SigningHistory::open(path, history::OnConflict::Refuse)?;
+        oracle = oracle.with_signing_history(std::sync::Arc::new(history));
+    }
+    let (oracle, reloader) = oracle.with_reload(&config.server);
+    if let Some(request) = matches.value_of("offline") {
+        let bundle = offline::RequestBundle::from_slice(&std::fs::read(request)?[..])?;
+        let response = oracle.sign_bundle(bundle)?.to_vec()?;
+        let response = if matches.is_present("qr") {
+            offline::to_chunks(&response[..], offline::CHUNK_SIZE)
+                .join("\n")
+                .into_bytes()
+        } else {
+            response
+        };
+        let path = match matches.value_of("response") {
+            Some(path) => PathBuf::from(path),
+            None => PathBuf::from(format!("{}.response", request)),
+        };
+        std::fs::write(&path, &response[..])?;
+        println!("Wrote Response To: {}", path.display());
+        return Ok(());
+    }
+    if let Some(path) = config_path {
+        tokio::spawn(reloader.reload_on_signal(path, move |c| {
+            overrides(c);
+            if let Some(log) = &c.log {
+                let filter = tracing_subscriber::EnvFilter::new(log);
+                if let Err(e) = filter_handle.reload(filter) {
+                    tracing::warn!(error = %e, "could not reload log filter");
+                }
+            }
+        }));
+    }
+
+    let interface = config
+        .listen
+        .ok_or("No Interface Given (e.g., 127.0.0.1:8080)")?;
     println!("Running Oracle With Key: {}", pk_root);
-    server.await?;
+    if let Some(path) = interface.strip_prefix("unix://") {
+        oracle.bind_unix(path).await?
+    } else if let Some(addr) = interface.strip_prefix("http://") {
+        oracle.bind_http(addr).await?
+    } else {
+        oracle
+            .bind(interface.strip_prefix("tcp://").unwrap_or(&interface))
+            .await?
+    }
     Ok(())
 }
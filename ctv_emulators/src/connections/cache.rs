@@ -0,0 +1,213 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Caching what an emulator returns, so that compiling the same contract
+//! again need not contact its oracles.
+//!
+//! Clauses are keyed by CTV hash. Signatures are keyed by txid instead: a
+//! CTV hash does not commit to the coins an input spends, which signatures
+//! do, so the same template spending different coins needs new ones.
+//!
+//! The cache may be persisted to a file of JSON lines, each entry appended as
+//! it is added. The file starts with a fingerprint of the emulator's config
+//! (see `fingerprint`), and is cleared when opened for a different one, as
+//! its clauses would be for other keys.
+use super::*;
+use bitcoin::Txid;
+use derivation::DerivationScheme;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// The contents of a cache
+#[derive(Default)]
+struct Entries {
+    /// the clause for a CTV hash, as miniscript policy
+    clauses: HashMap<Sha256, String>,
+    /// the PSBTs the emulator signed, by txid
+    signatures: HashMap<Txid, msgs::PSBT>,
+}
+
+/// The first line of a cache file
+#[derive(Serialize, Deserialize)]
+struct Header {
+    /// the `fingerprint` of the config the entries are for
+    config: Sha256,
+}
+
+/// Every other line of a cache file
+#[derive(Serialize, Deserialize)]
+enum Entry {
+    Clause(Sha256, String),
+    Signature(Txid, msgs::PSBT),
+}
+
+/// Identifies the clauses an emulator returns: those of a `threshold` of
+/// oracles with `roots` (in order), deriving keys with `scheme`.
+pub fn fingerprint(roots: &[ExtendedPubKey], threshold: u8, scheme: DerivationScheme) -> Sha256 {
+    let mut engine = Sha256::engine();
+    for root in roots {
+        engine.input(&root.encode()[..]);
+    }
+    engine.input(&[threshold, scheme.tag()]);
+    Sha256::from_engine(engine)
+}
+
+/// A `CTVEmulator` which remembers the clauses and signatures of another.
+pub struct CachedEmulator {
+    inner: Arc<dyn CTVEmulator>,
+    entries: Mutex<Entries>,
+    /// the file the cache is persisted to, if any, and its config
+    file: Option<(Mutex<File>, Sha256)>,
+}
+
+impl CachedEmulator {
+    /// caches `inner` in memory
+    pub fn new(inner: Arc<dyn CTVEmulator>) -> Self {
+        CachedEmulator {
+            inner,
+            entries: Mutex::new(Default::default()),
+            file: None,
+        }
+    }
+    /// Caches `inner`, whose config has fingerprint `config`, in the file at
+    /// `path`, loading any entries it has for the same config.
+    ///
+    /// A file for another config, or which can't be read as a cache at all,
+    /// is cleared.
+    pub fn open<P: AsRef<Path>>(
+        inner: Arc<dyn CTVEmulator>,
+        path: P,
+        config: Sha256,
+    ) -> Result<Self, std::io::Error> {
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let mut entries = Entries::default();
+        let mut lines = std::io::BufReader::new(&file).lines().map_while(Result::ok);
+        let header: Option<Header> = lines.next().and_then(|l| serde_json::from_str(&l).ok());
+        let same = header.map_or(false, |h| h.config == config);
+        if same {
+            for line in lines {
+                // skips a line left half written, e.g. by a crash
+                match serde_json::from_str(&line) {
+                    Ok(Entry::Clause(h, clause)) => {
+                        entries.clauses.insert(h, clause);
+                    }
+                    Ok(Entry::Signature(txid, psbt)) => {
+                        entries.signatures.insert(txid, psbt);
+                    }
+                    Err(_) => (),
+                }
+            }
+        } else {
+            drop(lines);
+            start(&mut file, config)?;
+        }
+        Ok(CachedEmulator {
+            inner,
+            entries: Mutex::new(entries),
+            file: Some((Mutex::new(file), config)),
+        })
+    }
+    /// forgets every entry, e.g. once the oracles' keys change
+    pub fn clear(&self) -> Result<(), std::io::Error> {
+        let mut entries = self.entries.lock().unwrap();
+        *entries = Default::default();
+        if let Some((file, config)) = &self.file {
+            start(&mut file.lock().unwrap(), *config)?;
+        }
+        Ok(())
+    }
+
+    /// appends `entry` to the cache file, if there is one
+    fn persist(&self, entry: &Entry) -> Result<(), std::io::Error> {
+        if let Some((file, _)) = &self.file {
+            let mut line = serde_json::to_vec(entry)?;
+            line.push(b'\n');
+            file.lock().unwrap().write_all(&line[..])?;
+        }
+        Ok(())
+    }
+}
+
+/// empties a cache file, leaving only the header for `config`
+fn start(file: &mut File, config: Sha256) -> Result<(), std::io::Error> {
+    file.set_len(0)?;
+    let mut header = serde_json::to_vec(&Header { config })?;
+    header.push(b'\n');
+    file.write_all(&header[..])
+}
+
+impl CTVEmulator for CachedEmulator {
+    fn get_signer_for(&self, h: Sha256) -> Result<Clause, EmulatorError> {
+        if let Some(clause) = self.entries.lock().unwrap().clauses.get(&h) {
+            if let Ok(clause) = clause.parse() {
+                return Ok(clause);
+            }
+        }
+        let clause = self.inner.get_signer_for(h)?;
+        let mut entries = self.entries.lock().unwrap();
+        entries.clauses.insert(h, clause.to_string());
+        self.persist(&Entry::Clause(h, clause.to_string()))?;
+        Ok(clause)
+    }
+    fn sign(
+        &self,
+        mut b: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, EmulatorError> {
+        let txid = b.global.unsigned_tx.txid();
+        let cached = self.entries.lock().unwrap().signatures.get(&txid).cloned();
+        if let Some(msgs::PSBT(signed)) = cached {
+            if b.merge(signed).is_ok() {
+                return Ok(b);
+            }
+        }
+        let signed = msgs::PSBT(self.inner.sign(b)?);
+        let mut entries = self.entries.lock().unwrap();
+        self.persist(&Entry::Signature(txid, signed.clone()))?;
+        entries.signatures.insert(txid, signed.clone());
+        Ok(signed.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cleared_for_another_config() {
+        let path = std::env::temp_dir().join(format!("ctve-cache-{}", std::process::id()));
+        let tx = bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![],
+        };
+        let txid = tx.txid();
+        let psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        let (ours, theirs) = (Sha256::hash(b"ours"), Sha256::hash(b"theirs"));
+        let open = |config| CachedEmulator::open(Arc::new(CTVAvailable), &path, config).unwrap();
+        let signed =
+            |cache: &CachedEmulator| cache.entries.lock().unwrap().signatures.contains_key(&txid);
+
+        let cache = open(ours);
+        cache.sign(psbt.clone()).unwrap();
+        cache.sign(psbt).unwrap();
+        drop(cache);
+        assert!(signed(&open(ours)));
+        // one line for the header, and one for the signature
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+        assert!(!signed(&open(theirs)));
+        assert!(!signed(&open(ours)));
+        let _ = std::fs::remove_file(&path);
+    }
+}
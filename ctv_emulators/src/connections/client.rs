@@ -0,0 +1,327 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A typed async client for an oracle, for applications which speak to one
+//! directly rather than through the `CTVEmulator` trait.
+//!
+//! An `OracleClient` sends `msgs::Request`s either to an oracle mounted in
+//! the same process (see `HDOracleEmulator::handle_request`) or over a stream
+//! the application opened, doing the framing (see `protocol`) itself. Either
+//! way the API is the same: `handle_request` mirrors the server's, and a
+//! method per request returns its response's type, failing with the
+//! `msgs::ServerError` (see `msgs::ServerError::from_io`) if the oracle
+//! refused.
+use super::hd::{Connection, OracleStream};
+use super::*;
+use crate::servers::hd::HDOracleEmulator;
+use tokio::sync::Mutex;
+
+/// Where an `OracleClient`'s requests go
+enum Backend {
+    Embedded(HDOracleEmulator),
+    Remote(Mutex<Connection>),
+}
+
+/// A response to one kind of request
+trait Expected: DeserializeOwned + Clone {
+    /// the response, if it is of this kind
+    fn from_response(r: msgs::Response) -> Option<Self>;
+    /// the response as sent by the oracle
+    fn into_response(self) -> msgs::Response;
+}
+
+macro_rules! expected {
+    ($t:ty, $variant:ident) => {
+        impl Expected for $t {
+            fn from_response(r: msgs::Response) -> Option<Self> {
+                match r {
+                    msgs::Response::$variant(t) => Some(t),
+                    _ => None,
+                }
+            }
+            fn into_response(self) -> msgs::Response {
+                msgs::Response::$variant(self)
+            }
+        }
+    };
+}
+
+expected!(msgs::PSBT, PSBT);
+expected!(msgs::SignedBatch, SignedBatch);
+expected!(msgs::MusigNonces, MusigNonces);
+expected!(msgs::MusigPartialSigs, MusigPartialSigs);
+expected!(msgs::KeyConfirmed, KeyConfirmed);
+expected!(msgs::Authenticated, Authenticated);
+expected!(msgs::DerivedKey, DerivedKey);
+expected!(msgs::Attestation, Attestation);
+expected!(msgs::Alive, Alive);
+
+/// A client for one oracle, see the module docs.
+///
+/// Requests are sent one at a time. Unlike `HDOracleEmulatorConnection`, a
+/// remote client does not reconnect: once a request fails other than with a
+/// `msgs::ServerError` which keeps the connection open, open a new client.
+pub struct OracleClient {
+    backend: Backend,
+}
+
+impl OracleClient {
+    /// a client for an oracle mounted in this process
+    pub fn embedded(oracle: HDOracleEmulator) -> Self {
+        OracleClient {
+            backend: Backend::Embedded(oracle),
+        }
+    }
+    /// Performs the handshake over `stream`, e.g. a connection the
+    /// application opened to the oracle with root `root`, returning a client
+    /// which sends its requests over it.
+    ///
+    /// The connection is encrypted if the oracle supports it, with a random
+    /// key of ours.
+    pub async fn connect<S>(stream: S, root: &ExtendedPubKey) -> Result<Self, std::io::Error>
+    where
+        S: OracleStream + 'static,
+    {
+        let conn =
+            Connection::over(Box::new(stream), root, &noise::ephemeral_key(), false, None).await?;
+        Ok(OracleClient {
+            backend: Backend::Remote(Mutex::new(conn)),
+        })
+    }
+    /// Sends any request, responding as `HDOracleEmulator::handle_request`
+    /// does: a request the oracle refused is responded to with the
+    /// `msgs::ServerError`, rather than failing.
+    pub async fn handle_request(
+        &self,
+        request: msgs::Request,
+    ) -> Result<msgs::Response, std::io::Error> {
+        if let Backend::Embedded(oracle) = &self.backend {
+            return oracle.handle_request(request).await;
+        }
+        let response = match request {
+            msgs::Request::SignPSBT(_) => self.call::<msgs::PSBT>(request).await,
+            msgs::Request::SignBatch(_) => self.call::<msgs::SignedBatch>(request).await,
+            msgs::Request::MusigNonce(_) => self.call::<msgs::MusigNonces>(request).await,
+            msgs::Request::MusigSign(_) => self.call::<msgs::MusigPartialSigs>(request).await,
+            msgs::Request::Authenticate(_) => self.call::<msgs::Authenticated>(request).await,
+            msgs::Request::ConfirmKey(_) => self.call::<msgs::KeyConfirmed>(request).await,
+            msgs::Request::DeriveKey(_) => self.call::<msgs::DerivedKey>(request).await,
+            msgs::Request::Verify(_) => self.call::<msgs::Attestation>(request).await,
+            msgs::Request::Liveness(_) => self.call::<msgs::Alive>(request).await,
+            msgs::Request::SignAnchor(_) => self.call::<msgs::PSBT>(request).await,
+        };
+        match response {
+            Ok(response) => Ok(response.into_response()),
+            Err(e) => match msgs::ServerError::from_io(&e) {
+                Some(server_error) => Ok(msgs::Response::Error(server_error.clone())),
+                None => Err(e),
+            },
+        }
+    }
+    /// sends a request, expecting a response of type `T`
+    async fn call<T: Expected>(&self, request: msgs::Request) -> Result<T, std::io::Error> {
+        match &self.backend {
+            Backend::Embedded(oracle) => match oracle.handle_request(request).await? {
+                msgs::Response::Error(e) => Err(e.into()),
+                response => match T::from_response(response) {
+                    Some(t) => Ok(t),
+                    None => input_error("Oracle Sent an Unexpected Response"),
+                },
+            },
+            Backend::Remote(conn) => {
+                let mut conn = conn.lock().await;
+                let mut responses = conn
+                    .exchange::<T>(std::slice::from_ref(&request), None)
+                    .await?;
+                responses.pop().expect("one response per request")
+            }
+        }
+    }
+    /// has the oracle sign a PSBT, see `msgs::Request::SignPSBT`
+    pub async fn sign_psbt(
+        &self,
+        psbt: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, std::io::Error> {
+        let request = msgs::Request::SignPSBT(msgs::PSBT(psbt));
+        Ok(self.call::<msgs::PSBT>(request).await?.0)
+    }
+    /// has the oracle sign every PSBT at once, see `msgs::Request::SignBatch`
+    pub async fn sign_batch(
+        &self,
+        psbts: Vec<PartiallySignedTransaction>,
+    ) -> Result<Vec<PartiallySignedTransaction>, std::io::Error> {
+        let request = msgs::Request::SignBatch(psbts.into_iter().map(msgs::PSBT).collect());
+        let msgs::SignedBatch(signed) = self.call(request).await?;
+        Ok(signed.into_iter().map(|msgs::PSBT(psbt)| psbt).collect())
+    }
+    /// round one of MuSig2 signing, see `msgs::Request::MusigNonce`
+    pub async fn musig_nonce(
+        &self,
+        request: msgs::MusigNonceRequest,
+    ) -> Result<msgs::MusigNonces, std::io::Error> {
+        self.call(msgs::Request::MusigNonce(request)).await
+    }
+    /// round two of MuSig2 signing, see `msgs::Request::MusigSign`
+    pub async fn musig_sign(
+        &self,
+        request: msgs::MusigSignRequest,
+    ) -> Result<msgs::MusigPartialSigs, std::io::Error> {
+        self.call(msgs::Request::MusigSign(request)).await
+    }
+    /// authorizes the client with `token`, see `msgs::Request::Authenticate`
+    pub async fn authenticate(
+        &self,
+        token: msgs::AuthToken,
+    ) -> Result<msgs::Authenticated, std::io::Error> {
+        self.call(msgs::Request::Authenticate(token)).await
+    }
+    /// asks the oracle to prove it holds `root`, see `msgs::Request::ConfirmKey`
+    pub async fn confirm_key(
+        &self,
+        root: ExtendedPubKey,
+        challenge: Sha256,
+    ) -> Result<msgs::KeyConfirmed, std::io::Error> {
+        let request = msgs::Request::ConfirmKey(msgs::ConfirmKey(root, challenge));
+        self.call(request).await
+    }
+    /// asks for `root`'s key for a CTV hash, see `msgs::Request::DeriveKey`
+    pub async fn derive_key(
+        &self,
+        root: ExtendedPubKey,
+        h: Sha256,
+    ) -> Result<msgs::DerivedKey, std::io::Error> {
+        self.call(msgs::Request::DeriveKey(msgs::DeriveKey(root, h)))
+            .await
+    }
+    /// asks the oracle to check its signatures, see `msgs::Request::Verify`
+    pub async fn verify(
+        &self,
+        psbt: PartiallySignedTransaction,
+    ) -> Result<msgs::Attestation, std::io::Error> {
+        self.call(msgs::Request::Verify(msgs::PSBT(psbt))).await
+    }
+    /// asks the oracle to prove it is online, see `msgs::Request::Liveness`
+    pub async fn liveness(
+        &self,
+        root: ExtendedPubKey,
+        challenge: Sha256,
+    ) -> Result<msgs::Alive, std::io::Error> {
+        let request = msgs::Request::Liveness(msgs::Liveness(root, challenge));
+        self.call(request).await
+    }
+    /// has the oracle sign a child spending `root`'s anchor output `anchor`
+    /// to fees, see `msgs::Request::SignAnchor`
+    pub async fn sign_anchor(
+        &self,
+        root: ExtendedPubKey,
+        anchor: bitcoin::OutPoint,
+        psbt: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, std::io::Error> {
+        let request = msgs::Request::SignAnchor(msgs::AnchorSpend {
+            root,
+            anchor,
+            psbt: msgs::PSBT(psbt),
+        });
+        Ok(self.call::<msgs::PSBT>(request).await?.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockOracle;
+
+    #[test]
+    fn embedded_and_remote_agree() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let mock = MockOracle::new();
+            let h = Sha256::hash(b"embedded");
+            let embedded = OracleClient::embedded(mock.oracle().clone());
+            let remote = OracleClient::connect(mock.connect(), &mock.xpub())
+                .await
+                .unwrap();
+            let msgs::DerivedKey(key, _) = embedded.derive_key(mock.xpub(), h).await.unwrap();
+            let msgs::DerivedKey(remote_key, _) = remote.derive_key(mock.xpub(), h).await.unwrap();
+            assert_eq!(key, remote_key);
+        });
+    }
+
+    #[test]
+    fn sign_anchor() {
+        use crate::derivation::{anchor_hash, DerivationScheme};
+        use bitcoin::{Script, TxOut};
+
+        let secp = Secp256k1::new();
+        let root =
+            |seed: u8| ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[seed; 32]).unwrap();
+        let oracle_root = root(47);
+        let pk_root = ExtendedPubKey::from_private(&secp, &oracle_root);
+        let path = DerivationScheme::default().path(anchor_hash());
+        let anchor_key = pk_root.derive_pub(&secp, &path).unwrap().public_key;
+        let other_key = ExtendedPubKey::from_private(&secp, &root(48)).public_key;
+
+        // the anchor of a template, and a coin of the user's paying for the
+        // child's output
+        let anchor = bitcoin::OutPoint::new(Hash::hash(b"template"), 1);
+        let coin = bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![TxOut {
+                value: 100_000,
+                script_pubkey: Script::new_v0_wpkh(&other_key.wpubkey_hash().unwrap()),
+            }],
+        };
+        let child = |change: u64| {
+            let tx = bitcoin::Transaction {
+                version: 2,
+                lock_time: 0,
+                input: [anchor, bitcoin::OutPoint::new(coin.txid(), 0)]
+                    .iter()
+                    .map(|prevout| bitcoin::TxIn {
+                        previous_output: *prevout,
+                        script_sig: Script::new(),
+                        sequence: 0xffffffff,
+                        witness: vec![],
+                    })
+                    .collect(),
+                output: vec![TxOut {
+                    value: change,
+                    script_pubkey: Script::new_v0_wpkh(&other_key.wpubkey_hash().unwrap()),
+                }],
+            };
+            let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+            psbt.inputs[0].witness_utxo = Some(TxOut {
+                value: 330,
+                script_pubkey: Script::new_v0_wpkh(&anchor_key.wpubkey_hash().unwrap()),
+            });
+            psbt.inputs[1].witness_utxo = Some(coin.output[0].clone());
+            psbt.inputs[1].non_witness_utxo = Some(coin.clone());
+            psbt
+        };
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let client = OracleClient::embedded(HDOracleEmulator::new(oracle_root));
+            let signed = client
+                .sign_anchor(pk_root, anchor, child(90_000))
+                .await
+                .unwrap();
+            assert!(signed.inputs[0].partial_sigs.contains_key(&anchor_key));
+            assert!(signed.inputs[1].partial_sigs.is_empty());
+            // paying out part of the anchor is refused
+            let e = client
+                .sign_anchor(pk_root, anchor, child(100_100))
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                msgs::ServerError::from_io(&e),
+                Some(msgs::ServerError::PolicyRejected(_))
+            ));
+        });
+    }
+}
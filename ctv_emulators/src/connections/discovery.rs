@@ -0,0 +1,207 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Finding the members of a federation through DNS, rather than configuring
+//! each member's address.
+//!
+//! Where an oracle's address is expected, it may instead be given as
+//! `srv://<name>`, for the targets of the DNS SRV records at `name` (e.g.
+//! `_ctv-oracle._tcp.example.com`, by priority then weight), or as
+//! `seed://<host>:<port>`, for every address `host` resolves to, as a
+//! bitcoin DNS seed lists nodes. Every candidate is asked to confirm the
+//! member's root (see `msgs::ConfirmKey`), and the first which does is used,
+//! so all the members of a federation may share one name.
+//!
+//! SRV records are only resolved with the `dns` feature.
+use super::hd::{Connection, OracleAddress};
+use super::*;
+use std::time::Duration;
+
+/// Prefixes an address discovered by SRV records
+pub const SRV_SCHEME: &str = "srv://";
+/// Prefixes an address discovered from a seed list
+pub const SEED_SCHEME: &str = "seed://";
+/// How long each candidate has to confirm a member's root
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How a member's address is found
+#[derive(Clone, Debug)]
+pub enum Discovery {
+    /// the targets of the SRV records at the name
+    Srv(String),
+    /// every address the host resolves to, with the port
+    Seed(String, u16),
+}
+
+impl Discovery {
+    /// The discovery `s` asks for, or None if it is an `OracleAddress`.
+    pub fn parse(s: &str) -> Result<Option<Self>, std::io::Error> {
+        if let Some(name) = s.strip_prefix(SRV_SCHEME) {
+            return Ok(Some(Discovery::Srv(name.into())));
+        }
+        let seed = match s.strip_prefix(SEED_SCHEME) {
+            Some(seed) => seed,
+            None => return Ok(None),
+        };
+        match seed.rsplit_once(':') {
+            Some((host, port)) => Ok(Some(Discovery::Seed(
+                host.into(),
+                port.parse().or_else(|_| input_error("Invalid Port"))?,
+            ))),
+            None => input_error("Seed Address Must Have a Port"),
+        }
+    }
+
+    /// Every address which may be a member's, in the order they are tried.
+    pub async fn candidates(&self) -> Result<Vec<OracleAddress>, std::io::Error> {
+        let targets = match self {
+            Discovery::Srv(name) => srv(name).await?,
+            Discovery::Seed(host, port) => vec![(host.clone(), *port)],
+        };
+        let mut candidates = vec![];
+        for target in targets {
+            match tokio::net::lookup_host(target.clone()).await {
+                Ok(addrs) => candidates.extend(addrs.map(OracleAddress::Tcp)),
+                Err(e) => {
+                    tracing::warn!(host = %target.0, error = %e, "could not resolve candidate");
+                }
+            }
+        }
+        Ok(candidates)
+    }
+
+    /// Finds the address of the member with root `root`: the first
+    /// candidate which confirms it holds the root and derives keys with
+    /// `derivation`. None if no candidate does.
+    ///
+    /// The connection parameters are those of `HDOracleEmulatorConnection`.
+    pub async fn find(
+        &self,
+        root: &ExtendedPubKey,
+        derivation: derivation::DerivationScheme,
+        noise_key: &bitcoin::secp256k1::SecretKey,
+        require_noise: bool,
+    ) -> Result<Option<OracleAddress>, std::io::Error> {
+        for candidate in self.candidates().await? {
+            let confirm = confirm(&candidate, root, derivation, noise_key, require_noise);
+            match servers::limits::within(Some(CONFIRM_TIMEOUT), "Timed Out", confirm).await {
+                Ok(()) => {
+                    tracing::info!(
+                        root = %root.fingerprint(),
+                        address = ?candidate,
+                        "discovered member"
+                    );
+                    return Ok(Some(candidate));
+                }
+                Err(e) => tracing::debug!(
+                    root = %root.fingerprint(),
+                    address = ?candidate,
+                    error = %e,
+                    "candidate is not the member"
+                ),
+            }
+        }
+        tracing::warn!(
+            root = %root.fingerprint(),
+            discovery = ?self,
+            "could not discover member"
+        );
+        Ok(None)
+    }
+}
+
+/// succeeds if the oracle at `address` confirms it holds `root`
+async fn confirm(
+    address: &OracleAddress,
+    root: &ExtendedPubKey,
+    derivation: derivation::DerivationScheme,
+    noise_key: &bitcoin::secp256k1::SecretKey,
+    require_noise: bool,
+) -> Result<(), std::io::Error> {
+    let stream = address.connect().await?;
+    let mut conn = Connection::over(stream, root, noise_key, require_noise, None).await?;
+    let entropy: [u8; 32] = rand::thread_rng().gen();
+    let challenge = Sha256::from_slice(&entropy).unwrap();
+    let req = msgs::Request::ConfirmKey(msgs::ConfirmKey(*root, challenge));
+    let confirmed = conn
+        .exchange::<msgs::KeyConfirmed>(std::slice::from_ref(&req), None)
+        .await?
+        .pop()
+        .expect("one response per request")?;
+    if !confirmed.signed_by(&challenge, root) {
+        return input_error("Invalid Key Confirmation");
+    }
+    if confirmed.3 != derivation {
+        return input_error("Oracle Uses a Different Derivation Scheme");
+    }
+    Ok(())
+}
+
+/// the targets of the SRV records at `name`, by priority then weight
+#[cfg(feature = "dns")]
+async fn srv(name: &str) -> Result<Vec<(String, u16)>, std::io::Error> {
+    let other = |e| std::io::Error::new(std::io::ErrorKind::Other, e);
+    let resolver =
+        trust_dns_resolver::TokioAsyncResolver::tokio_from_system_conf().map_err(other)?;
+    let lookup = resolver.srv_lookup(name).await.map_err(other)?;
+    let mut records: Vec<_> = lookup.iter().collect();
+    records.sort_by_key(|r| (r.priority(), std::cmp::Reverse(r.weight())));
+    Ok(records
+        .into_iter()
+        .map(|r| {
+            let target = r.target().to_utf8();
+            (target.trim_end_matches('.').to_string(), r.port())
+        })
+        .collect())
+}
+
+/// SRV records can't be resolved without the `dns` feature
+#[cfg(not(feature = "dns"))]
+async fn srv(_name: &str) -> Result<Vec<(String, u16)>, std::io::Error> {
+    input_error("SRV Discovery Requires the dns Feature")
+}
+
+/// A member which could not be discovered, and so never signs.
+///
+/// Its keys are still derived from its root (unless they are hardened, see
+/// `derivation`), as a federation's clauses include every member, so that a
+/// contract may be compiled while it is down.
+pub struct Undiscovered {
+    root: ExtendedPubKey,
+    derivation: derivation::DerivationScheme,
+}
+
+impl Undiscovered {
+    /// a member with root `root` deriving keys with `derivation`
+    pub fn new(root: ExtendedPubKey, derivation: derivation::DerivationScheme) -> Self {
+        Undiscovered { root, derivation }
+    }
+}
+
+/// the error for any request needing an undiscovered member
+fn undiscovered() -> EmulatorError {
+    EmulatorError::NetworkIssue(std::io::Error::new(
+        std::io::ErrorKind::NotConnected,
+        "Member Was Not Discovered",
+    ))
+}
+
+impl CTVEmulator for Undiscovered {
+    fn get_signer_for(&self, h: Sha256) -> Result<Clause, EmulatorError> {
+        if self.derivation.hardened() {
+            return Err(undiscovered());
+        }
+        let path = self.derivation.path(h);
+        let key = SECP.with(|secp| self.root.derive_pub(secp, &path))?;
+        Ok(Clause::Key(key.public_key))
+    }
+    fn sign(
+        &self,
+        _b: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, EmulatorError> {
+        Err(undiscovered())
+    }
+}
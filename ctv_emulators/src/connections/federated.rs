@@ -5,11 +5,110 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use super::*;
+use std::fmt;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+/// How long to wait for a quorum by default
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+/// How many times a member is retried after a transient failure by default
+const DEFAULT_RETRIES: u32 = 2;
+/// The delay before the first retry by default, doubled for each after
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(250);
+
 /// Creates a multi-condition emulator with a certain threshold.
 /// It implements CTVEmulator so that it itself can be used as a trait object.
+///
+/// Signing requests are sent to every emulator at once, and succeed as soon
+/// as `threshold` of them have signed. Members which fail transiently (e.g.,
+/// a dropped connection or `msgs::ServerError::RateLimited`) are retried with
+/// exponential backoff until the timeout. If too few members sign, the error
+/// carries a `QuorumError` saying which failed.
+///
+/// With a `health::HealthMonitor` (see `with_health`), members which are down
+/// are not asked to sign while enough others are up, and fail at once with
+/// "Member Is Down". Keys are always derived from every member, as the
+/// federation's clauses include them all.
 pub struct FederatedEmulatorConnection {
     emulators: Vec<Arc<dyn CTVEmulator>>,
     threshold: u8,
+    coordinator: Option<Arc<dyn CTVEmulator>>,
+    timeout: Duration,
+    retries: u32,
+    backoff: Duration,
+    health: Option<Arc<health::HealthMonitor>>,
+}
+
+/// Why a federation could not sign: the members which failed (by index in
+/// the federation) and those which had not responded by the timeout.
+#[derive(Debug, Clone)]
+pub struct QuorumError {
+    /// how many members signed
+    pub signed: usize,
+    /// how many members must sign
+    pub threshold: usize,
+    /// the members which failed to sign, with their last error
+    pub failed: Vec<(usize, String)>,
+    /// the members still signing at the timeout
+    pub timed_out: Vec<usize>,
+}
+
+impl fmt::Display for QuorumError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Only {} of the {} Required Members Signed",
+            self.signed, self.threshold
+        )?;
+        for (member, e) in self.failed.iter() {
+            write!(f, "; member {} failed: {}", member, e)?;
+        }
+        if !self.timed_out.is_empty() {
+            write!(f, "; members {:?} timed out", self.timed_out)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for QuorumError {}
+
+impl QuorumError {
+    /// the `QuorumError` carried by `e`, if any. `FederatedEmulatorConnection`
+    /// returns it as an `EmulatorError::NetworkIssue`.
+    pub fn from_emulator_error(e: &EmulatorError) -> Option<&QuorumError> {
+        match e {
+            EmulatorError::NetworkIssue(e) => e.get_ref().and_then(|inner| inner.downcast_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<QuorumError> for EmulatorError {
+    fn from(e: QuorumError) -> Self {
+        EmulatorError::NetworkIssue(std::io::Error::new(std::io::ErrorKind::Other, e))
+    }
+}
+
+/// if a request which failed with `e` may succeed if sent again
+fn transient(e: &EmulatorError) -> bool {
+    use std::io::ErrorKind::*;
+    match e {
+        EmulatorError::NetworkIssue(e) => match msgs::ServerError::from_io(e) {
+            Some(server_error) => server_error.retryable(),
+            None => matches!(
+                e.kind(),
+                ConnectionRefused
+                    | ConnectionReset
+                    | ConnectionAborted
+                    | NotConnected
+                    | BrokenPipe
+                    | TimedOut
+                    | Interrupted
+                    | UnexpectedEof
+            ),
+        },
+        EmulatorError::BIP32Error(_) => false,
+    }
 }
 
 impl FederatedEmulatorConnection {
@@ -17,6 +116,67 @@ impl FederatedEmulatorConnection {
         FederatedEmulatorConnection {
             emulators,
             threshold,
+            coordinator: None,
+            timeout: DEFAULT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            backoff: DEFAULT_BACKOFF,
+            health: None,
+        }
+    }
+    /// send signing requests to a `ThresholdCoordinator` for the federation
+    /// rather than to every emulator.
+    ///
+    /// Keys are still derived from `emulators`, the coordinator is only used
+    /// to sign (e.g., an `HDOracleEmulatorConnection` to its address).
+    pub fn with_coordinator(mut self, coordinator: Arc<dyn CTVEmulator>) -> Self {
+        self.coordinator = Some(coordinator);
+        self
+    }
+    /// how long to wait for `threshold` members to sign, including retries
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    /// retry a member which fails transiently up to `retries` times, waiting
+    /// `backoff` before the first retry and twice as long before each after.
+    pub fn with_retries(mut self, retries: u32, backoff: Duration) -> Self {
+        self.retries = retries;
+        self.backoff = backoff;
+        self
+    }
+    /// track the members' health with `monitor`, which must be for as many
+    /// members as the federation has, skipping those which are down
+    pub fn with_health(mut self, monitor: Arc<health::HealthMonitor>) -> Self {
+        self.health = Some(monitor);
+        self
+    }
+    /// the members' health, if it is tracked (see `with_health`)
+    pub fn health(&self) -> Option<&Arc<health::HealthMonitor>> {
+        self.health.as_ref()
+    }
+}
+
+/// signs with `emulator`, retrying transient failures up to `retries` times
+/// with exponential `backoff`, unless that would pass `deadline`
+fn sign_with_retries(
+    emulator: &dyn CTVEmulator,
+    b: PartiallySignedTransaction,
+    retries: u32,
+    mut backoff: Duration,
+    deadline: Instant,
+) -> Result<PartiallySignedTransaction, EmulatorError> {
+    let mut attempt = 0;
+    loop {
+        match emulator.sign(b.clone()) {
+            Err(e) if transient(&e) && attempt < retries => {
+                if Instant::now() + backoff >= deadline {
+                    return Err(e);
+                }
+                std::thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+            r => return r,
         }
     }
 }
@@ -34,9 +194,83 @@ impl CTVEmulator for FederatedEmulatorConnection {
         &self,
         mut b: PartiallySignedTransaction,
     ) -> Result<PartiallySignedTransaction, EmulatorError> {
-        for emulator in self.emulators.iter() {
-            b = emulator.sign(b)?;
+        if let Some(coordinator) = &self.coordinator {
+            return coordinator.sign(b);
+        }
+        let threshold = self.threshold as usize;
+        if threshold == 0 {
+            return Ok(b);
+        }
+        let deadline = Instant::now() + self.timeout;
+        let mut pending: Vec<usize> = (0..self.emulators.len()).collect();
+        let mut failed = vec![];
+        if let Some(health) = &self.health {
+            let up = pending.iter().filter(|idx| health.is_up(**idx)).count();
+            // if too few are up, the ones which are down are tried anyways
+            if up >= threshold {
+                for idx in pending.iter().filter(|idx| !health.is_up(**idx)) {
+                    failed.push((*idx, String::from("Member Is Down")));
+                }
+                pending.retain(|idx| health.is_up(*idx));
+            }
+        }
+        let (tx, rx) = mpsc::channel();
+        for idx in pending.iter().copied() {
+            let emulator = self.emulators[idx].clone();
+            let tx = tx.clone();
+            let psbt = b.clone();
+            let (retries, backoff) = (self.retries, self.backoff);
+            let health = self.health.clone();
+            // members may block (e.g., `HDOracleEmulatorConnection`), so they
+            // get a thread each, which is left to finish on its own once we
+            // have enough signatures or time out.
+            std::thread::spawn(move || {
+                let r = sign_with_retries(&*emulator, psbt, retries, backoff, deadline);
+                if let Some(health) = health {
+                    match &r {
+                        Err(e) if transient(e) => health.failed(idx, &e.to_string()),
+                        _ => health.responded(idx),
+                    }
+                }
+                let _ = tx.send((idx, r));
+            });
+        }
+        drop(tx);
+        let mut signed = 0;
+        while signed < threshold {
+            let now = Instant::now();
+            let (idx, r) = match rx.recv_timeout(deadline.saturating_duration_since(now)) {
+                Ok(response) => response,
+                Err(_) => break,
+            };
+            pending.retain(|p| *p != idx);
+            match r.and_then(|p| {
+                b.merge(p)
+                    .or_else(|_| input_error("Member Returned a Different PSBT"))
+                    .map_err(EmulatorError::from)
+            }) {
+                Ok(()) => signed += 1,
+                Err(e) => failed.push((idx, e.to_string())),
+            }
+        }
+        if signed >= threshold {
+            return Ok(b);
+        }
+        Err(QuorumError {
+            signed,
+            threshold,
+            failed,
+            timed_out: pending,
+        }
+        .into())
+    }
+    fn sign_batch(
+        &self,
+        batch: Vec<PartiallySignedTransaction>,
+    ) -> Result<Vec<PartiallySignedTransaction>, EmulatorError> {
+        match &self.coordinator {
+            Some(coordinator) => coordinator.sign_batch(batch),
+            None => batch.into_iter().map(|b| self.sign(b)).collect(),
         }
-        Ok(b)
     }
 }
@@ -5,27 +5,407 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use super::*;
-/// HDOracleEmulatorConnection wraps a tokio runtime and a TCPStream
-/// with a key to be able to talk to an Oracle server.
+use std::path::PathBuf;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Any bidirectional stream an oracle can be spoken to over.
+pub trait OracleStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T> OracleStream for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
+
+/// Where an oracle server may be reached.
+///
+/// The textual format is a URI, either `tcp://host:port`,
+/// `unix:///path/to/socket`, or `socks5://proxy:port/host:port`. A bare
+/// `host:port` is treated as tcp, except for `.onion` hosts which are reached
+/// through Tor's default SOCKS port (see `socks::TOR_DEFAULT_PROXY`).
+#[derive(Clone, Debug)]
+pub enum OracleAddress {
+    /// A resolved TCP socket address
+    Tcp(SocketAddr),
+    /// A unix domain socket path
+    Unix(PathBuf),
+    /// A host reached through a SOCKS5 proxy (e.g. a Tor onion service),
+    /// which resolves `host` itself.
+    Socks5 {
+        proxy: SocketAddr,
+        host: String,
+        port: u16,
+    },
+}
+
+impl OracleAddress {
+    /// open a new connection to the oracle
+    pub async fn connect(&self) -> Result<Box<dyn OracleStream>, std::io::Error> {
+        Ok(match self {
+            OracleAddress::Tcp(addr) => Box::new(TcpStream::connect(addr).await?),
+            OracleAddress::Socks5 { proxy, host, port } => {
+                Box::new(socks::connect(*proxy, host, *port).await?)
+            }
+            #[cfg(unix)]
+            OracleAddress::Unix(path) => Box::new(tokio::net::UnixStream::connect(path).await?),
+            #[cfg(not(unix))]
+            OracleAddress::Unix(_) => return input_error("Unix Sockets Unsupported on this Platform"),
+        })
+    }
+
+    /// parses an address which is reached through a SOCKS5 proxy, None if `s`
+    /// is not one.
+    fn proxied(s: &str) -> Result<Option<Self>, std::io::Error> {
+        use std::net::ToSocketAddrs;
+        let (proxy, target) = match s.strip_prefix("socks5://") {
+            Some(rest) => match rest.split_once('/') {
+                Some(split) => split,
+                None => return input_error("SOCKS5 Address Must be socks5://proxy:port/host:port"),
+            },
+            None => {
+                let target = s.strip_prefix("tcp://").unwrap_or(s);
+                match target.rsplit_once(':') {
+                    Some((host, _)) if host.ends_with(".onion") => {
+                        (socks::TOR_DEFAULT_PROXY, target)
+                    }
+                    _ => return Ok(None),
+                }
+            }
+        };
+        let proxy = proxy.to_socket_addrs()?.next().ok_or_else(|| {
+            input_error::<()>(&format!("Bad Lookup Could Not Resolve Proxy {}", proxy)).unwrap_err()
+        })?;
+        let (host, port) = match target.rsplit_once(':') {
+            Some(split) => split,
+            None => return input_error("Address Must Have a Port"),
+        };
+        Ok(Some(OracleAddress::Socks5 {
+            proxy,
+            host: host.into(),
+            port: port.parse().or_else(|_| input_error("Invalid Port"))?,
+        }))
+    }
+}
+
+/// Parses an `OracleAddress`, resolving any hostname with the system resolver.
+impl std::str::FromStr for OracleAddress {
+    type Err = std::io::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use std::net::ToSocketAddrs;
+        if let Some(path) = s.strip_prefix("unix://") {
+            return Ok(OracleAddress::Unix(path.into()));
+        }
+        if let Some(proxied) = Self::proxied(s)? {
+            return Ok(proxied);
+        }
+        let host = s.strip_prefix("tcp://").unwrap_or(s);
+        host.to_socket_addrs()?
+            .next()
+            .map(OracleAddress::Tcp)
+            .ok_or_else(|| {
+                input_error::<()>(&format!("Bad Lookup Could Not Resolve Address {}", s))
+                    .unwrap_err()
+            })
+    }
+}
+
+/// How many connections to an oracle are kept open by default
+pub const DEFAULT_POOL_SIZE: usize = 4;
+/// How long to wait for the oracle to respond to a request by default, short
+/// enough for a federation to retry a member within its own timeout
+pub const DEFAULT_REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+/// How many requests are sent on a pipelined connection before waiting for
+/// a response, so that neither side blocks writing while the other does.
+const PIPELINE_DEPTH: usize = 8;
+
+/// An open connection to an oracle, after the handshake.
+pub struct Connection {
+    stream: Box<dyn OracleStream>,
+    session: protocol::Session,
+    /// the id of the next request, if the session is pipelined
+    next_id: u64,
+}
+
+/// if a connection may still be used after a request failed with `e`, i.e.
+/// `e` is a `msgs::ServerError` which the server keeps the connection open
+/// after (e.g. a policy refusal).
+fn keeps_open(e: &std::io::Error) -> bool {
+    msgs::ServerError::from_io(e).map_or(false, |e| !e.closes_connection())
+}
+
+impl Connection {
+    /// opens a connection to the oracle at `address` and performs the
+    /// handshake, see `HDOracleEmulatorConnection` for the parameters.
+    async fn open(
+        address: &OracleAddress,
+        root: &ExtendedPubKey,
+        noise_key: &bitcoin::secp256k1::SecretKey,
+        require_noise: bool,
+        auth_token: Option<&msgs::AuthToken>,
+    ) -> Result<Self, std::io::Error> {
+        let stream = address.connect().await?;
+        Self::over(stream, root, noise_key, require_noise, auth_token).await
+    }
+
+    /// Performs the handshake over an already open `stream`, e.g. one an
+    /// application connected itself, or an in-memory pipe (see
+    /// `HDOracleEmulator::serve_stream`). Otherwise the same as `open`.
+    pub async fn over(
+        mut stream: Box<dyn OracleStream>,
+        root: &ExtendedPubKey,
+        noise_key: &bitcoin::secp256k1::SecretKey,
+        require_noise: bool,
+        auth_token: Option<&msgs::AuthToken>,
+    ) -> Result<Self, std::io::Error> {
+        let mut session = protocol::Session::connect(&mut stream, protocol::Hello::ours()).await?;
+        if session.wants_noise() {
+            session
+                .initiate_noise(&mut stream, noise_key, &root.public_key.key)
+                .await?;
+        } else if require_noise {
+            return input_error("Oracle Does Not Support Encrypted Connections");
+        }
+        let mut conn = Connection {
+            stream,
+            session,
+            next_id: 1,
+        };
+        if let Some(token) = auth_token {
+            let req = msgs::Request::Authenticate(token.clone());
+            for r in conn.exchange::<msgs::Authenticated>(&[req], None).await? {
+                r?;
+            }
+        }
+        Ok(conn)
+    }
+
+    /// the capabilities negotiated with the oracle
+    pub fn capabilities(&self) -> protocol::Capabilities {
+        self.session.params.capabilities
+    }
+
+    /// Sends every request and reads their responses, in order.
+    ///
+    /// On a pipelined session (see `protocol`), up to `PIPELINE_DEPTH`
+    /// requests are in flight at once, otherwise each is sent once the
+    /// previous has been responded to. A request which fails with a
+    /// `msgs::ServerError` after which the connection stays open fails
+    /// alone, any other error fails the whole exchange and leaves the
+    /// connection in an unknown state.
+    ///
+    /// If the session is timed (see `protocol`), each request tells the
+    /// oracle how long remains until `deadline`.
+    pub(crate) async fn exchange<T: DeserializeOwned + Clone>(
+        &mut self,
+        reqs: &[msgs::Request],
+        deadline: Option<std::time::Instant>,
+    ) -> Result<Vec<Result<T, std::io::Error>>, std::io::Error> {
+        let pipelined = self.session.pipelined();
+        let first = self.next_id;
+        let mut responses: Vec<Option<Result<T, std::io::Error>>> =
+            reqs.iter().map(|_| None).collect();
+        let mut sent = 0;
+        for received in 0..reqs.len() {
+            let depth = if pipelined { PIPELINE_DEPTH } else { 1 };
+            while sent < reqs.len() && sent - received < depth {
+                let id = if pipelined {
+                    self.next_id += 1;
+                    Some(self.next_id - 1)
+                } else {
+                    None
+                };
+                let v = self.encode(id, deadline, &reqs[sent])?;
+                self.session.write_frame(&mut self.stream, &v[..]).await?;
+                sent += 1;
+            }
+            let (idx, r) = if pipelined {
+                let (id, r) = self.correlated_response::<T>().await?;
+                match (id.checked_sub(first).map(|i| i as usize), r) {
+                    (Some(i), r) if i < reqs.len() && responses[i].is_none() => (i, r),
+                    // an error which responds to no request, e.g. the
+                    // server is at its connection limit
+                    (_, Err(e)) if id == 0 => return Err(e),
+                    _ => return input_error("Oracle Responded With an Unexpected Id"),
+                }
+            } else {
+                (received, self.response::<T>().await)
+            };
+            match r {
+                Err(e) if !keeps_open(&e) => return Err(e),
+                r => responses[idx] = Some(r),
+            }
+        }
+        Ok(responses
+            .into_iter()
+            .map(|r| r.expect("every request has been responded to"))
+            .collect())
+    }
+
+    /// encodes a request, tagged with `id` if the session is pipelined and
+    /// with the time left until `deadline` if it is timed
+    fn encode(
+        &self,
+        id: Option<u64>,
+        deadline: Option<std::time::Instant>,
+        req: &msgs::Request,
+    ) -> Result<Vec<u8>, std::io::Error> {
+        let codec = self.session.codec();
+        if !self.session.timed() {
+            return match id {
+                Some(id) => codec.encode(&msgs::Correlated(id, req)),
+                None => codec.encode(req),
+            };
+        }
+        let ttl = deadline.map(|d| {
+            let left = d.saturating_duration_since(std::time::Instant::now());
+            left.as_millis() as u64
+        });
+        match id {
+            Some(id) => codec.encode(&msgs::Correlated(id, msgs::Timed(ttl, req))),
+            None => codec.encode(&msgs::Timed(ttl, req)),
+        }
+    }
+
+    /// decodes a response or the `msgs::ServerError` sent in its place
+    fn decode<R: DeserializeOwned, E: DeserializeOwned>(
+        &self,
+        v: &[u8],
+    ) -> Result<Result<R, E>, std::io::Error> {
+        let codec = self.session.codec();
+        match codec.decode::<R>(v) {
+            Ok(r) => Ok(Ok(r)),
+            Err(e) => codec.decode::<E>(v).map(Err).or(Err(e)),
+        }
+    }
+
+    /// receive a response via the stream.
+    /// wire format: see `protocol`, responses larger than the negotiated
+    /// maximum are rejected.
+    async fn response<T: DeserializeOwned>(&mut self) -> Result<T, std::io::Error> {
+        let v = self.session.read_frame(&mut self.stream).await?;
+        match self.decode::<T, msgs::ServerError>(&v[..])? {
+            Ok(t) => Ok(t),
+            Err(server_error) => Err(server_error.into()),
+        }
+    }
+
+    /// receive a response and the id of the request it responds to, on a
+    /// pipelined session.
+    async fn correlated_response<T: DeserializeOwned>(
+        &mut self,
+    ) -> Result<(u64, Result<T, std::io::Error>), std::io::Error> {
+        let v = self.session.read_frame(&mut self.stream).await?;
+        let decoded =
+            self.decode::<msgs::Correlated<T>, msgs::Correlated<msgs::ServerError>>(&v[..]);
+        Ok(match decoded? {
+            Ok(msgs::Correlated(id, t)) => (id, Ok(t)),
+            Err(msgs::Correlated(id, server_error)) => (id, Err(server_error.into())),
+        })
+    }
+}
+
+/// Connections to a single oracle, opened as they are needed so that
+/// concurrent requests (e.g. from the members of a federation signing at
+/// once, or several threads) needn't wait for each other.
+pub struct Pool {
+    slots: Vec<Mutex<Option<Connection>>>,
+    next: std::sync::atomic::AtomicUsize,
+}
+
+impl Pool {
+    /// a pool of at most `size` connections (at least one)
+    pub fn new(size: usize) -> Self {
+        Pool {
+            slots: (0..size.max(1)).map(|_| Mutex::new(None)).collect(),
+            next: Default::default(),
+        }
+    }
+
+    /// Takes a slot: an idle open connection if there is one, otherwise an
+    /// idle slot to open one in, otherwise waits for a busy one.
+    async fn acquire(&self) -> tokio::sync::MutexGuard<'_, Option<Connection>> {
+        for open in [true, false].iter() {
+            for slot in self.slots.iter() {
+                if let Ok(guard) = slot.try_lock() {
+                    if guard.is_some() == *open {
+                        return guard;
+                    }
+                }
+            }
+        }
+        let i = self.next.fetch_add(1, std::sync::atomic::Ordering::Relaxed) % self.slots.len();
+        self.slots[i].lock().await
+    }
+}
+
+impl Default for Pool {
+    fn default() -> Self {
+        Pool::new(DEFAULT_POOL_SIZE)
+    }
+}
+
+/// HDOracleEmulatorConnection wraps a tokio runtime and a connection
+/// (TCP or unix socket) with a key to be able to talk to an Oracle server.
 ///
 /// Note that because HDOracleEmulatorConnection uses block_in_place/block_on
 /// internally in the trait object because the CTVEmulator trait is not async.
 ///
 /// This seems to be a limitation with tokio / rust around using async inside non-async
-/// traits.
+/// traits. Async callers should use it as an `AsyncCTVEmulator` instead, which
+/// never blocks.
 pub struct HDOracleEmulatorConnection {
     pub runtime: Arc<tokio::runtime::Runtime>,
-    pub connection: Mutex<Option<TcpStream>>,
-    pub reconnect: SocketAddr,
+    /// the open connections to the oracle, see `with_pool_size`
+    pub pool: Pool,
+    pub reconnect: OracleAddress,
     pub root: ExtendedPubKey,
     pub secp: Arc<bitcoin::secp256k1::Secp256k1<bitcoin::secp256k1::All>>,
+    /// the key we identify ourselves to the oracle with, if the connection
+    /// is encrypted (see `noise`)
+    pub noise_key: bitcoin::secp256k1::SecretKey,
+    /// if connections must be encrypted, rather than only when the oracle
+    /// supports it
+    pub require_noise: bool,
+    /// presented to the oracle on connecting, if it only signs for
+    /// authorized clients (see `servers::auth`)
+    pub auth_token: Option<msgs::AuthToken>,
+    /// how long to wait for a response to each request (including
+    /// connecting), which the oracle is told so that it can abandon requests
+    /// we no longer wait for (see `protocol`), see `with_timeout`
+    pub timeout: Option<std::time::Duration>,
+    /// how the oracle derives its key for a CTV hash, which must match the
+    /// scheme it reports in `confirm_key` (see `derivation`)
+    pub derivation: derivation::DerivationScheme,
 }
 
 impl HDOracleEmulatorConnection {
-    /// Helper function to derive an EPK
-    fn derive(&self, h: Sha256) -> Result<ExtendedPubKey, Error> {
-        let c = hash_to_child_vec(h);
-        self.root.derive_pub(&self.secp, &c)
+    /// Helper function to derive the oracle's key for `h`.
+    ///
+    /// Hardened keys are requested from the oracle, and checked against its
+    /// root's signature.
+    pub(crate) fn derive(&self, h: Sha256) -> Result<bitcoin::PublicKey, EmulatorError> {
+        if !self.derivation.hardened() {
+            let c = self.derivation.path(h);
+            return Ok(self.root.derive_pub(&self.secp, &c)?.public_key);
+        }
+        self.block_on(self.derive_async(h))
+    }
+    /// `derive`, without blocking
+    pub(crate) async fn derive_async(
+        &self,
+        h: Sha256,
+    ) -> Result<bitcoin::PublicKey, EmulatorError> {
+        if !self.derivation.hardened() {
+            let c = self.derivation.path(h);
+            return Ok(self.root.derive_pub(&self.secp, &c)?.public_key);
+        }
+        let req = msgs::Request::DeriveKey(msgs::DeriveKey(self.root, h));
+        let msgs::DerivedKey(key, signature) = self.roundtrip(&req).await?;
+        let msg = msgs::DerivedKey::message(&h, self.derivation, &key);
+        self.secp
+            .verify(&msg, &signature, &self.root.public_key.key)
+            .or_else(|_| input_error("Invalid Derived Key Signature"))?;
+        Ok(key)
+    }
+    /// runs `f` on our runtime from synchronous code, see
+    /// `tokio::task::block_in_place`
+    fn block_on<F: std::future::Future>(&self, f: F) -> F::Output {
+        tokio::task::block_in_place(|| self.runtime.block_on(f))
     }
     /// Creates a new instance of a HDOracleEmulatorConnection.
     ///
@@ -39,76 +419,381 @@ impl HDOracleEmulatorConnection {
     /// Note that as a consequence of new doing the host resolving, if DNS
     /// records change, then a new HDOracleEmulatorConnection would need to be
     /// created to observe it.
+    ///
+    /// If the address is prefixed with `unix://`, the remainder is used as
+    /// the path to a unix domain socket instead, and `socks5://` and `.onion`
+    /// addresses are connected to through a proxy without being resolved
+    /// locally (see `OracleAddress`).
     pub async fn new<A: ToSocketAddrs + std::fmt::Display + Clone>(
         address: A,
         root: ExtendedPubKey,
         runtime: Arc<tokio::runtime::Runtime>,
         secp: Arc<bitcoin::secp256k1::Secp256k1<bitcoin::secp256k1::All>>,
     ) -> Result<Self, std::io::Error> {
+        let uri = address.to_string();
+        let reconnect = if let Some(path) = uri.strip_prefix("unix://") {
+            OracleAddress::Unix(path.into())
+        } else if let Some(proxied) = OracleAddress::proxied(&uri)? {
+            proxied
+        } else {
+            let lookup = match uri.strip_prefix("tcp://") {
+                Some(host) => tokio::net::lookup_host(host).await?.next(),
+                None => tokio::net::lookup_host(address.clone()).await?.next(),
+            };
+            OracleAddress::Tcp(lookup.ok_or_else(|| {
+                input_error::<()>(&format!("Bad Lookup Could Not Resolve Address {}", address))
+                    .unwrap_err()
+            })?)
+        };
         Ok(HDOracleEmulatorConnection {
-            connection: Mutex::new(None),
-            reconnect: tokio::net::lookup_host(address.clone())
-                .await?
-                .next()
-                .ok_or_else(|| {
-                    input_error::<()>(&format!("Bad Lookup Could Not Resolve Address {}", address))
-                        .unwrap_err()
-                })?,
+            pool: Pool::default(),
+            reconnect,
             runtime,
             root,
             secp,
+            noise_key: noise::ephemeral_key(),
+            require_noise: false,
+            auth_token: None,
+            timeout: Some(DEFAULT_REQUEST_TIMEOUT),
+            derivation: Default::default(),
         })
     }
-
-    /// make a request via the tcpstream.
-    /// wire format: length:u32 data:[u8;length]
-    async fn request(t: &mut TcpStream, r: &msgs::Request) -> Result<(), std::io::Error> {
-        let v = serde_json::to_vec(r)?;
-        t.write_u32(v.len() as u32).await?;
-        t.write_all(&v[..]).await
+    /// expect the oracle to derive its keys with `scheme`
+    pub fn with_derivation(mut self, scheme: derivation::DerivationScheme) -> Self {
+        self.derivation = scheme;
+        self
     }
-    /// receive a response via the tcpstream.
-    /// wire format: length:u32 data:[u8;length]
-    ///
-    /// TODO: secure response by limiting the length to a max value.
-    /// This is not super critical because presumably the oracles are not trying to OOM your system.
-    async fn response<T: DeserializeOwned + Clone>(t: &mut TcpStream) -> Result<T, std::io::Error> {
-        let l = t.read_u32().await? as usize;
-        let mut v = vec![0u8; l];
-        t.read_exact(&mut v[..]).await?;
-        let t: T = serde_json::from_slice::<T>(&v[..])?;
-        Ok(t)
+    /// present `token` to the oracle on connecting
+    pub fn with_auth_token(mut self, token: msgs::AuthToken) -> Self {
+        self.auth_token = Some(token);
+        self
+    }
+    /// identify ourselves to the oracle with `key` rather than a random key,
+    /// e.g. to be recognized by its allowlist.
+    pub fn with_noise_key(mut self, key: bitcoin::secp256k1::SecretKey) -> Self {
+        self.noise_key = key;
+        self
+    }
+    /// refuse to talk to the oracle unless the connection is encrypted and
+    /// the oracle proves it holds the root key.
+    pub fn with_required_noise(mut self) -> Self {
+        self.require_noise = true;
+        self
+    }
+    /// wait `timeout` for each response rather than
+    /// `DEFAULT_REQUEST_TIMEOUT`, or without limit if none
+    pub fn with_timeout(mut self, timeout: Option<std::time::Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    /// keep up to `size` connections to the oracle open, rather than
+    /// `DEFAULT_POOL_SIZE`
+    pub fn with_pool_size(mut self, size: usize) -> Self {
+        self.pool = Pool::new(size);
+        self
     }
 }
 
 use tokio::sync::Mutex;
-impl CTVEmulator for HDOracleEmulatorConnection {
-    fn get_signer_for(&self, h: Sha256) -> Result<Clause, EmulatorError> {
-        Ok(Clause::Key(self.derive(h)?.public_key))
+impl HDOracleEmulatorConnection {
+    /// takes a connection from the pool, opening it (and performing the
+    /// handshake) if needed.
+    async fn connected(
+        &self,
+    ) -> Result<tokio::sync::MutexGuard<'_, Option<Connection>>, std::io::Error> {
+        let mut mconn = self.pool.acquire().await;
+        if mconn.is_none() {
+            let conn = Connection::open(
+                &self.reconnect,
+                &self.root,
+                &self.noise_key,
+                self.require_noise,
+                self.auth_token.as_ref(),
+            )
+            .await?;
+            *mconn = Some(conn);
+        }
+        Ok(mconn)
     }
-    fn sign(
+
+    /// sends a request and waits for the response, see `roundtrip_many`.
+    pub(crate) async fn roundtrip<T: DeserializeOwned + Clone>(
+        &self,
+        req: &msgs::Request,
+    ) -> Result<T, std::io::Error> {
+        let mut responses = self.roundtrip_many(std::slice::from_ref(req)).await?;
+        responses.pop().expect("one response per request")
+    }
+
+    /// Sends requests on one connection, pipelined if the oracle supports
+    /// it, and waits for their responses.
+    ///
+    /// A request which fails with a `msgs::ServerError` which the server
+    /// keeps the connection open after (e.g. a policy refusal) fails alone,
+    /// with the error (see `msgs::ServerError::from_io`). On any other error
+    /// the connection is dropped, as it is in an unknown state, and will be
+    /// reopened on the next request.
+    ///
+    /// Fails with `ErrorKind::TimedOut` if the requests aren't all responded
+    /// to within our `timeout`.
+    pub(crate) async fn roundtrip_many<T: DeserializeOwned + Clone>(
+        &self,
+        reqs: &[msgs::Request],
+    ) -> Result<Vec<Result<T, std::io::Error>>, std::io::Error> {
+        let deadline = self.timeout.map(|t| std::time::Instant::now() + t);
+        let exchange = async {
+            let mut mconn = self.connected().await?;
+            // taken while in use, so that it is dropped if we time out
+            let mut conn = mconn.take().expect("connected always opens a connection");
+            let r = conn.exchange(reqs, deadline).await;
+            if r.is_ok() {
+                *mconn = Some(conn);
+            }
+            r
+        };
+        servers::limits::within(self.timeout, "Oracle Did Not Respond in Time", exchange).await
+    }
+
+    /// Records which of the oracle's roots we expect to sign each input, so
+    /// that an oracle with several root keys (see `servers::keys`) uses ours.
+    ///
+    /// Each input's key is derived from its own CTV hash, and is only recorded
+    /// if the input's scripts require it (see `servers::hd::involves_key`),
+    /// as the oracle refuses to sign if it is named on an input it can't
+    /// sign. So inputs spending other contracts, or not locked by the oracle
+    /// at all, may be mixed in one transaction.
+    async fn annotate(
         &self,
         mut b: PartiallySignedTransaction,
     ) -> Result<PartiallySignedTransaction, EmulatorError> {
-        let inp: Result<PartiallySignedTransaction, std::io::Error> =
+        let fingerprint = self.root.fingerprint();
+        for idx in 0..b.inputs.len() {
+            let h = b.global.unsigned_tx.get_ctv_hash(idx as u32);
+            let key = self.derive_async(h).await?;
+            let input = &mut b.inputs[idx];
+            if servers::hd::involves_key(&key, input, &self.secp) {
+                input.bip32_derivation.insert(
+                    key,
+                    (fingerprint, DerivationPath::from(self.derivation.path(h))),
+                );
+            }
+        }
+        Ok(b)
+    }
+
+    /// Asks the oracle to prove it holds our root key.
+    ///
+    /// Returns the fingerprint of the confirmed root. Fails if the oracle
+    /// does not have our root (e.g., a federation member is misconfigured),
+    /// if it derives keys with a different scheme than ours, or if its proof
+    /// is invalid.
+    pub fn confirm_key(&self) -> Result<Fingerprint, EmulatorError> {
+        let entropy: [u8; 32] = rand::thread_rng().gen();
+        let challenge = Sha256::from_slice(&entropy).unwrap();
+        let confirmed: Result<msgs::KeyConfirmed, std::io::Error> =
+            tokio::task::block_in_place(|| {
+                self.runtime.block_on(async {
+                    let req = msgs::Request::ConfirmKey(msgs::ConfirmKey(self.root, challenge));
+                    self.roundtrip(&req).await
+                })
+            });
+        let msgs::KeyConfirmed(signature, nonce, fingerprint, scheme) = confirmed?;
+        if fingerprint != self.root.fingerprint() {
+            input_error::<()>("Oracle Confirmed a Different Key")?;
+        }
+        if scheme != self.derivation {
+            input_error::<()>("Oracle Uses a Different Derivation Scheme")?;
+        }
+        let msg = msgs::KeyConfirmed::message(&nonce, &challenge, &fingerprint, scheme);
+        self.secp
+            .verify(&msg, &signature, &self.root.public_key.key)
+            .or_else(|_| input_error("Invalid Key Confirmation"))?;
+        Ok(fingerprint)
+    }
+
+    /// Asks the oracle to prove it is online and holds our root key now,
+    /// without signing a transaction, e.g. to monitor federation members.
+    ///
+    /// Fails if the proof is invalid, or if the oracle's clock differs from
+    /// ours by more than `max_skew`. The returned proof may be published, as
+    /// anyone with the challenge can check it (see `msgs::Alive::signed_by`).
+    pub fn liveness(
+        &self,
+        challenge: Sha256,
+        max_skew: std::time::Duration,
+    ) -> Result<msgs::Alive, EmulatorError> {
+        let alive: Result<msgs::Alive, std::io::Error> = tokio::task::block_in_place(|| {
+            self.runtime.block_on(async {
+                let req = msgs::Request::Liveness(msgs::Liveness(self.root, challenge));
+                self.roundtrip(&req).await
+            })
+        });
+        let alive = alive?;
+        if !alive.signed_by(&challenge, &self.root) {
+            input_error::<()>("Invalid Liveness Proof")?;
+        }
+        let now = crate::servers::auth::now();
+        if now.max(alive.timestamp) - now.min(alive.timestamp) > max_skew.as_secs() {
+            input_error::<()>("Oracle Clock Is Skewed")?;
+        }
+        Ok(alive)
+    }
+
+    /// Asks the oracle (e.g., a watch-only oracle, see `servers::watch`) to
+    /// check its signatures on a signed PSBT.
+    ///
+    /// Fails if the oracle finds an input lacking a valid signature, or if
+    /// its attestation is for a different transaction or keys than ours. A
+    /// signed attestation may be checked with `msgs::Attestation::signed_by`.
+    pub fn verify(
+        &self,
+        psbt: PartiallySignedTransaction,
+    ) -> Result<msgs::Attestation, EmulatorError> {
+        let tx = psbt.global.unsigned_tx.clone();
+        let attested: Result<msgs::Attestation, std::io::Error> =
             tokio::task::block_in_place(|| {
                 self.runtime.block_on(async {
-                    let mut mconn = self.connection.lock().await;
-                    loop {
-                        if let Some(conn) = &mut *mconn {
-                            Self::request(conn, &msgs::Request::SignPSBT(msgs::PSBT(b.clone())))
-                                .await?;
-                            conn.flush().await?;
-                            return Ok(Self::response::<msgs::PSBT>(conn).await?.0);
-                        } else {
-                            *mconn = Some(TcpStream::connect(&self.reconnect).await?);
-                        }
+                    let req = msgs::Request::Verify(msgs::PSBT(psbt));
+                    self.roundtrip(&req).await
+                })
+            });
+        let attestation = attested?;
+        if attestation.txid != tx.txid() {
+            input_error::<()>("Oracle Attested to a Different Transaction")?;
+        }
+        for (input, key) in attestation.inputs.iter() {
+            if *input >= tx.input.len() || *key != self.derive(tx.get_ctv_hash(*input as u32))? {
+                input_error::<()>("Oracle Attested to a Different Key")?;
+            }
+        }
+        Ok(attestation)
+    }
+
+    /// The key the oracle's anchor outputs are locked to (p2wpkh), see
+    /// `derivation::anchor_hash`.
+    pub fn anchor_key(&self) -> Result<bitcoin::PublicKey, EmulatorError> {
+        self.derive(derivation::anchor_hash())
+    }
+
+    /// Has the oracle sign the input of `psbt` spending `anchor`, an output
+    /// of a template locked to `anchor_key`, to bump the template's fee by
+    /// child-pays-for-parent.
+    ///
+    /// The anchor must go entirely to fees, so the child's outputs (if any
+    /// besides e.g. an empty OP_RETURN) must be paid for by inputs of the
+    /// caller's, which are left for the caller to sign. See
+    /// `msgs::AnchorSpend`.
+    pub fn sign_anchor(
+        &self,
+        anchor: bitcoin::OutPoint,
+        psbt: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, EmulatorError> {
+        let signed: Result<msgs::PSBT, std::io::Error> = tokio::task::block_in_place(|| {
+            self.runtime.block_on(async {
+                let req = msgs::Request::SignAnchor(msgs::AnchorSpend {
+                    root: self.root,
+                    anchor,
+                    psbt: msgs::PSBT(psbt),
+                });
+                self.roundtrip(&req).await
+            })
+        });
+        Ok(signed?.0)
+    }
+
+    /// Signs many PSBTs (e.g., every transaction of a compiled contract) in a
+    /// single round trip.
+    ///
+    /// The server signs either all or none of the PSBTs. Servers which do not
+    /// support batching are sent each PSBT in turn (pipelined, if the server
+    /// supports it), in which case an error may occur after some have been
+    /// signed.
+    pub fn sign_batch(
+        &self,
+        batch: Vec<PartiallySignedTransaction>,
+    ) -> Result<Vec<PartiallySignedTransaction>, EmulatorError> {
+        let annotated = self.block_on(async {
+            let mut annotated = vec![];
+            for b in batch.iter().cloned() {
+                annotated.push(self.annotate(b).await?);
+            }
+            Ok::<_, EmulatorError>(annotated)
+        })?;
+        let signed: Result<Vec<PartiallySignedTransaction>, std::io::Error> =
+            tokio::task::block_in_place(|| {
+                self.runtime.block_on(async {
+                    let batched = match &*self.connected().await? {
+                        Some(conn) => conn.capabilities().contains(protocol::Capabilities::BATCH),
+                        None => false,
+                    };
+                    if batched {
+                        let req = msgs::Request::SignBatch(
+                            annotated.iter().cloned().map(msgs::PSBT).collect(),
+                        );
+                        let msgs::SignedBatch(signed) = self.roundtrip(&req).await?;
+                        Ok(signed.into_iter().map(|p| p.0).collect())
+                    } else {
+                        let reqs: Vec<_> = annotated
+                            .iter()
+                            .map(|b| msgs::Request::SignPSBT(msgs::PSBT(b.clone())))
+                            .collect();
+                        self.roundtrip_many::<msgs::PSBT>(&reqs[..])
+                            .await?
+                            .into_iter()
+                            .map(|r| r.map(|p| p.0))
+                            .collect()
                     }
                 })
             });
+        let signed = signed?;
+        if signed.len() != batch.len() {
+            input_error::<()>("Wrong Number of PSBTs Signed")?;
+        }
+        batch
+            .into_iter()
+            .zip(signed)
+            .map(|(mut b, s)| {
+                b.merge(s)
+                    .or_else(|_e| input_error("Fault Signed PSBT"))?;
+                Ok::<_, EmulatorError>(b)
+            })
+            .collect()
+    }
+}
 
-        b.merge(inp?)
-            .or_else(|_e| input_error("Fault Signed PSBT"))?;
-        Ok(b)
+impl CTVEmulator for HDOracleEmulatorConnection {
+    fn get_signer_for(&self, h: Sha256) -> Result<Clause, EmulatorError> {
+        Ok(Clause::Key(self.derive(h)?))
+    }
+    fn sign(
+        &self,
+        b: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, EmulatorError> {
+        self.block_on(self.sign_async(b))
+    }
+    fn sign_batch(
+        &self,
+        batch: Vec<PartiallySignedTransaction>,
+    ) -> Result<Vec<PartiallySignedTransaction>, EmulatorError> {
+        HDOracleEmulatorConnection::sign_batch(self, batch)
+    }
+}
+
+impl AsyncCTVEmulator for HDOracleEmulatorConnection {
+    fn get_signer_for_async(&self, h: Sha256) -> EmulatorFuture<'_, Clause> {
+        Box::pin(async move { Ok(Clause::Key(self.derive_async(h).await?)) })
+    }
+    fn sign_async(
+        &self,
+        mut b: PartiallySignedTransaction,
+    ) -> EmulatorFuture<'_, PartiallySignedTransaction> {
+        Box::pin(async move {
+            let annotated = self.annotate(b.clone()).await?;
+            let req = msgs::Request::SignPSBT(msgs::PSBT(annotated));
+            let signed = self.roundtrip::<msgs::PSBT>(&req).await?.0;
+            b.merge(signed)
+                .or_else(|_e| input_error("Fault Signed PSBT"))?;
+            Ok(b)
+        })
     }
 }
@@ -0,0 +1,164 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Tracking which members of a federation are up, so that signing doesn't
+//! wait on one which is down.
+//!
+//! A `HealthMonitor` marks a member down after a number of consecutive
+//! failures (connection errors or timeouts, rather than refusals) and up
+//! again once it responds. A `FederatedEmulatorConnection` with a monitor
+//! (see `with_health`) reports every member's outcome to it, and doesn't ask
+//! members which are down to sign unless too few are up to reach its
+//! threshold.
+//!
+//! Members are probed in the background (see `HealthMonitor::spawn`), so
+//! that one which recovers is used again without a signing request having
+//! to find out. `HDOracleEmulatorConnection`s are probed with
+//! `msgs::Request::Liveness`.
+use super::hd::HDOracleEmulatorConnection;
+use super::*;
+use std::sync::{Mutex, Weak};
+use std::time::{Duration, Instant};
+
+/// How often members are probed by default
+pub const DEFAULT_PROBE_INTERVAL: Duration = Duration::from_secs(30);
+/// How many consecutive failures mark a member down by default
+pub const DEFAULT_FAILURES: u32 = 3;
+/// How far a member's clock may be from ours for its liveness proof to count
+const MAX_SKEW: Duration = Duration::from_secs(600);
+
+/// Checks that a member is up without asking it to sign
+pub trait Probe: Send + Sync {
+    /// succeeds if the member responded correctly
+    fn probe(&self) -> Result<(), EmulatorError>;
+}
+
+impl Probe for HDOracleEmulatorConnection {
+    fn probe(&self) -> Result<(), EmulatorError> {
+        let entropy: [u8; 32] = rand::thread_rng().gen();
+        let challenge = Sha256::from_slice(&entropy).unwrap();
+        self.liveness(challenge, MAX_SKEW).map(|_| ())
+    }
+}
+
+/// If a member is up, as last seen
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Health {
+    Up,
+    /// down since the failure which marked it so, with the error
+    Down {
+        since: Instant,
+        error: String,
+    },
+}
+
+/// What is known about a member
+struct Member {
+    /// failures since the member last responded
+    failures: u32,
+    health: Health,
+}
+
+/// The health of each member of a federation, see the module docs.
+pub struct HealthMonitor {
+    members: Mutex<Vec<Member>>,
+    /// how many consecutive failures mark a member down
+    failures: u32,
+}
+
+impl HealthMonitor {
+    /// a monitor for `n` members, all up, which are marked down after
+    /// `DEFAULT_FAILURES` consecutive failures
+    pub fn new(n: usize) -> Self {
+        HealthMonitor {
+            members: Mutex::new(
+                (0..n)
+                    .map(|_| Member {
+                        failures: 0,
+                        health: Health::Up,
+                    })
+                    .collect(),
+            ),
+            failures: DEFAULT_FAILURES,
+        }
+    }
+    /// mark members down after `failures` consecutive failures (at least one)
+    pub fn with_failures(mut self, failures: u32) -> Self {
+        self.failures = failures.max(1);
+        self
+    }
+    /// if member `idx` is up
+    pub fn is_up(&self, idx: usize) -> bool {
+        let members = self.members.lock().unwrap();
+        members.get(idx).map_or(false, |m| m.health == Health::Up)
+    }
+    /// the health of every member, by index in the federation
+    pub fn health(&self) -> Vec<Health> {
+        let members = self.members.lock().unwrap();
+        members.iter().map(|m| m.health.clone()).collect()
+    }
+    /// Records that member `idx` responded, marking it up.
+    pub fn responded(&self, idx: usize) {
+        let mut members = self.members.lock().unwrap();
+        if let Some(member) = members.get_mut(idx) {
+            if member.health != Health::Up {
+                tracing::info!(member = idx, "federation member recovered");
+            }
+            member.failures = 0;
+            member.health = Health::Up;
+        }
+    }
+    /// Records that member `idx` failed to respond with `error`, marking it
+    /// down if it has failed too many times in a row.
+    pub fn failed(&self, idx: usize, error: &str) {
+        let mut members = self.members.lock().unwrap();
+        if let Some(member) = members.get_mut(idx) {
+            member.failures = member.failures.saturating_add(1);
+            if member.failures >= self.failures && member.health == Health::Up {
+                tracing::warn!(member = idx, error, "federation member is down");
+                member.health = Health::Down {
+                    since: Instant::now(),
+                    error: error.into(),
+                };
+            }
+        }
+    }
+    /// Marks member `idx` down at once, e.g. if it could not be found (see
+    /// `discovery::Undiscovered`).
+    pub fn mark_down(&self, idx: usize, error: &str) {
+        let mut members = self.members.lock().unwrap();
+        if let Some(member) = members.get_mut(idx) {
+            member.failures = self.failures;
+            member.health = Health::Down {
+                since: Instant::now(),
+                error: error.into(),
+            };
+        }
+    }
+    /// Probes every member with a probe (by index in the federation) every
+    /// `interval`, each on its own thread, until the monitor is dropped.
+    pub fn spawn(self: &Arc<Self>, probes: Vec<Option<Arc<dyn Probe>>>, interval: Duration) {
+        for (idx, probe) in probes.into_iter().enumerate() {
+            let probe = match probe {
+                Some(probe) => probe,
+                None => continue,
+            };
+            let monitor: Weak<Self> = Arc::downgrade(self);
+            std::thread::spawn(move || loop {
+                std::thread::sleep(interval);
+                let r = probe.probe();
+                let live = match monitor.upgrade() {
+                    Some(live) => live,
+                    None => return,
+                };
+                match r {
+                    Ok(()) => live.responded(idx),
+                    Err(e) => live.failed(idx, &e.to_string()),
+                }
+            });
+        }
+    }
+}
@@ -5,5 +5,12 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use super::*;
+pub mod cache;
+pub mod client;
+pub mod discovery;
 pub mod federated;
 pub mod hd;
+pub mod health;
+pub mod musig;
+pub mod native;
+pub mod socks;
@@ -0,0 +1,119 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::hd::HDOracleEmulatorConnection;
+use super::*;
+use crate::servers::taproot;
+use bitcoin::secp256k1::schnorrsig;
+
+/// An n-of-n federation of oracles whose keys are aggregated with MuSig2.
+///
+/// The emulated covenant is a single taproot key, so spends look like any
+/// other key path spend. The key returned by `get_signer_for` is the
+/// aggregate internal key; it must be used in a taproot output with the
+/// BIP-86 tweak (i.e., no script tree) for the federation to sign.
+pub struct MusigEmulatorConnection {
+    members: Vec<Arc<HDOracleEmulatorConnection>>,
+}
+
+impl MusigEmulatorConnection {
+    pub fn new(members: Vec<Arc<HDOracleEmulatorConnection>>) -> Self {
+        MusigEmulatorConnection { members }
+    }
+    /// aggregates the members' keys for `h`, untweaked
+    fn context(&self, h: Sha256) -> Result<musig::KeyAggContext, EmulatorError> {
+        let keys = self
+            .members
+            .iter()
+            .map(|m| Ok(m.derive(h)?.key))
+            .collect::<Result<Vec<_>, EmulatorError>>()?;
+        Ok(musig::KeyAggContext::new(keys)?)
+    }
+}
+
+impl CTVEmulator for MusigEmulatorConnection {
+    fn get_signer_for(&self, h: Sha256) -> Result<Clause, EmulatorError> {
+        let mut even = vec![0x02];
+        even.extend_from_slice(&self.context(h)?.xonly()[..]);
+        Ok(Clause::Key(
+            bitcoin::PublicKey::from_slice(&even[..])
+                .or_else(|_| input_error("Invalid Aggregate Key"))?,
+        ))
+    }
+    /// Runs both rounds of MuSig2 with every member, and adds the aggregate
+    /// signature to each input all of the members could sign.
+    fn sign(
+        &self,
+        mut b: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, EmulatorError> {
+        let runtime = match self.members.first() {
+            Some(m) => m.runtime.clone(),
+            None => return Ok(b),
+        };
+        let participants: Vec<ExtendedPubKey> = self.members.iter().map(|m| m.root).collect();
+        let (aggnonces, partials) = tokio::task::block_in_place(|| {
+            runtime.block_on(async {
+                let req = msgs::Request::MusigNonce(msgs::MusigNonceRequest {
+                    psbt: msgs::PSBT(b.clone()),
+                    participants,
+                });
+                let mut rounds = vec![];
+                for m in self.members.iter() {
+                    rounds.push(m.roundtrip::<msgs::MusigNonces>(&req).await?);
+                }
+                let nonce_for = |r: &msgs::MusigNonces, idx: usize| {
+                    r.nonces.iter().find(|(i, _)| *i == idx).map(|(_, n)| *n)
+                };
+                let mut aggnonces = vec![];
+                for (idx, _) in rounds[0].nonces.iter() {
+                    let nonces: Option<Vec<musig::PubNonce>> =
+                        rounds.iter().map(|r| nonce_for(r, *idx)).collect();
+                    if let Some(nonces) = nonces {
+                        aggnonces.push((*idx, musig::aggregate_nonces(&nonces[..])?));
+                    }
+                }
+                let mut partials = vec![];
+                for (m, r) in self.members.iter().zip(rounds.iter()) {
+                    let req = msgs::Request::MusigSign(msgs::MusigSignRequest {
+                        session: r.session,
+                        aggnonces: aggnonces.clone(),
+                    });
+                    partials.push(m.roundtrip::<msgs::MusigPartialSigs>(&req).await?.0);
+                }
+                Ok::<_, std::io::Error>((aggnonces, partials))
+            })
+        })?;
+        let tx = b.global.unsigned_tx.clone();
+        let prevouts: Vec<bitcoin::TxOut> = b
+            .inputs
+            .iter()
+            .filter_map(|i| i.witness_utxo.clone())
+            .collect();
+        for (idx, aggnonce) in aggnonces {
+            let mut ctx = self.context(tx.get_ctv_hash(idx as u32))?;
+            ctx.tweak_taproot()?;
+            let msg = taproot::sighash(&tx, &prevouts[..], idx, &taproot::SpendPath::Key);
+            let sigs = partials
+                .iter()
+                .map(|p| p.iter().find(|(i, _)| *i == idx).map(|(_, s)| *s))
+                .collect::<Option<Vec<[u8; 32]>>>()
+                .ok_or_else(|| input_error::<()>("Missing Partial Signature").unwrap_err())?;
+            let sig =
+                musig::aggregate_partial_sigs(&ctx, &aggnonce, &msg.into_inner(), &sigs[..])?;
+            let verified = SECP.with(|secp| {
+                let sig = schnorrsig::Signature::from_slice(&sig[..]).ok()?;
+                let key = schnorrsig::PublicKey::from_slice(&ctx.xonly()[..]).ok()?;
+                let msg = bitcoin::secp256k1::Message::from_slice(&msg[..]).ok()?;
+                secp.schnorrsig_verify(&sig, &msg, &key).ok()
+            });
+            if verified.is_none() {
+                input_error::<()>("Invalid Aggregate Signature")?;
+            }
+            taproot::add_key_sig(&mut b.inputs[idx], &sig[..]);
+        }
+        Ok(b)
+    }
+}
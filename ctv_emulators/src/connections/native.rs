@@ -0,0 +1,68 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Using real OP_CHECKTEMPLATEVERIFY where a network enforces it.
+//!
+//! A `PassThroughEmulator` emits `Clause::TxTemplate` (as `CTVAvailable`
+//! does) on networks where CTV is active, e.g. a signet with it deployed,
+//! and defers to an emulator elsewhere, so that the same contract deploys on
+//! both. Whether CTV is active may be configured, or read from a node's
+//! deployment info, see `ctv_active`.
+use super::*;
+
+/// The name of the CTV deployment, as nodes report it
+const DEPLOYMENT: &str = "checktemplateverify";
+
+/// If the result of a node's `getdeploymentinfo` RPC (or, for nodes without
+/// it, `getblockchaininfo`) shows CTV as active.
+pub fn ctv_active(info: &serde_json::Value) -> bool {
+    ["deployments", "softforks"]
+        .iter()
+        .filter_map(|field| info.get(field)?.get(DEPLOYMENT)?.get("active"))
+        .any(|active| active.as_bool() == Some(true))
+}
+
+/// An emulator which uses real CTV if it is active, and `fallback` otherwise
+pub struct PassThroughEmulator {
+    native: bool,
+    fallback: Arc<dyn CTVEmulator>,
+}
+
+impl PassThroughEmulator {
+    /// emulate with `fallback` unless `native` is set
+    pub fn new(fallback: Arc<dyn CTVEmulator>, native: bool) -> Self {
+        PassThroughEmulator { native, fallback }
+    }
+    /// uses real CTV if the node's deployment info shows it active, see
+    /// `ctv_active`
+    pub fn from_deployment_info(fallback: Arc<dyn CTVEmulator>, info: &serde_json::Value) -> Self {
+        Self::new(fallback, ctv_active(info))
+    }
+    /// if real CTV is used
+    pub fn is_native(&self) -> bool {
+        self.native
+    }
+}
+
+impl CTVEmulator for PassThroughEmulator {
+    fn get_signer_for(&self, h: Sha256) -> Result<Clause, EmulatorError> {
+        if self.native {
+            CTVAvailable.get_signer_for(h)
+        } else {
+            self.fallback.get_signer_for(h)
+        }
+    }
+    fn sign(
+        &self,
+        b: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, EmulatorError> {
+        if self.native {
+            CTVAvailable.sign(b)
+        } else {
+            self.fallback.sign(b)
+        }
+    }
+}
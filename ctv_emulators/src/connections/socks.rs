@@ -0,0 +1,75 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A minimal SOCKS5 client (RFC 1928), enough to reach oracles through Tor.
+//!
+//! Hostnames are always sent to the proxy unresolved, so that looking up an
+//! oracle does not leak to the local resolver (and so `.onion` addresses,
+//! which only the proxy can resolve, work).
+use super::*;
+
+/// The SOCKS port a local Tor daemon listens on by default
+pub const TOR_DEFAULT_PROXY: &str = "127.0.0.1:9050";
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+fn reply_error(code: u8) -> &'static str {
+    match code {
+        1 => "SOCKS: General Failure",
+        2 => "SOCKS: Connection Not Allowed",
+        3 => "SOCKS: Network Unreachable",
+        4 => "SOCKS: Host Unreachable",
+        5 => "SOCKS: Connection Refused",
+        6 => "SOCKS: TTL Expired",
+        7 => "SOCKS: Command Not Supported",
+        8 => "SOCKS: Address Type Not Supported",
+        _ => "SOCKS: Unknown Error",
+    }
+}
+
+/// Opens a connection to `host:port` through the SOCKS5 proxy at `proxy`.
+pub async fn connect(proxy: SocketAddr, host: &str, port: u16) -> std::io::Result<TcpStream> {
+    if host.is_empty() || host.len() > 255 {
+        return input_error("SOCKS: Invalid Hostname");
+    }
+    let mut s = TcpStream::connect(proxy).await?;
+    s.write_all(&[VERSION, 1, NO_AUTH]).await?;
+    let mut choice = [0u8; 2];
+    s.read_exact(&mut choice).await?;
+    if choice != [VERSION, NO_AUTH] {
+        return input_error("SOCKS: Proxy Requires Authentication");
+    }
+    let mut request = vec![VERSION, CONNECT, 0, ATYP_DOMAIN, host.len() as u8];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    s.write_all(&request[..]).await?;
+    let mut reply = [0u8; 4];
+    s.read_exact(&mut reply).await?;
+    if reply[0] != VERSION {
+        return input_error("SOCKS: Invalid Reply");
+    }
+    if reply[1] != 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::ConnectionRefused,
+            reply_error(reply[1]),
+        ));
+    }
+    // the address the proxy bound, which we don't need
+    let len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => s.read_u8().await? as usize,
+        _ => return input_error("SOCKS: Invalid Reply"),
+    };
+    let mut bound = vec![0u8; len + 2];
+    s.read_exact(&mut bound[..]).await?;
+    Ok(s)
+}
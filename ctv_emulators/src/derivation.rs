@@ -0,0 +1,113 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! How an oracle's key for a CTV hash is derived from its root.
+//!
+//! The client and oracle must agree on the scheme, so the oracle reports the
+//! scheme it uses when confirming its key (see `msgs::KeyConfirmed`), and a
+//! client configured with a different scheme refuses to use it.
+//!
+//! With an unhardened scheme a client derives the oracle's keys from the root
+//! xpub itself. With `DerivationScheme::Hardened` it can't, so it asks the
+//! oracle for each key (`msgs::Request::DeriveKey`), which is signed by the
+//! root. Hardened keys can't be aggregated with MuSig2, as every participant
+//! must derive every other participant's key.
+use super::*;
+use serde_derive::{Deserialize, Serialize};
+
+/// Maps a CTV hash to a BIP32 path from an oracle's root
+pub trait Derivation {
+    /// the path for `h`
+    fn path(&self, h: Sha256) -> Vec<ChildNumber>;
+    /// if any step of the path is hardened, so that keys can't be derived
+    /// from the root xpub.
+    fn hardened(&self) -> bool;
+}
+
+/// The derivation schemes an oracle may use
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DerivationScheme {
+    /// The original scheme, see `hash_to_child_vec`: 9 unhardened steps
+    /// covering the whole hash.
+    Unhardened,
+    /// The first 217 bits of the hash as 7 unhardened steps of 31 bits, for
+    /// devices which limit the depth of a path.
+    Short,
+    /// The same steps as `Unhardened`, but hardened.
+    Hardened,
+}
+
+impl Default for DerivationScheme {
+    fn default() -> Self {
+        DerivationScheme::Unhardened
+    }
+}
+
+impl DerivationScheme {
+    /// if this is the original scheme, which oracles do not report
+    pub fn is_default(&self) -> bool {
+        *self == DerivationScheme::Unhardened
+    }
+    /// a byte identifying the scheme in signed messages
+    pub fn tag(&self) -> u8 {
+        match self {
+            DerivationScheme::Unhardened => 0,
+            DerivationScheme::Short => 1,
+            DerivationScheme::Hardened => 2,
+        }
+    }
+}
+
+/// the `n`th run of 31 bits of `h`, most significant first
+fn bits31(h: &[u8; 32], n: usize) -> u32 {
+    (0..31).fold(0u32, |acc, i| {
+        let bit = 31 * n + i;
+        (acc << 1) | ((h[bit / 8] >> (7 - bit % 8)) & 1) as u32
+    })
+}
+
+impl Derivation for DerivationScheme {
+    fn path(&self, h: Sha256) -> Vec<ChildNumber> {
+        match self {
+            DerivationScheme::Unhardened => hash_to_child_vec(h),
+            DerivationScheme::Short => {
+                let h = h.into_inner();
+                (0..7).map(|n| ChildNumber::from(bits31(&h, n))).collect()
+            }
+            DerivationScheme::Hardened => hash_to_child_vec(h)
+                .into_iter()
+                .map(|c| ChildNumber::Hardened {
+                    index: u32::from(c),
+                })
+                .collect(),
+        }
+    }
+    fn hardened(&self) -> bool {
+        *self == DerivationScheme::Hardened
+    }
+}
+
+/// The hash an oracle's anchor key is derived for (with the oracle's scheme,
+/// like any other key), see `msgs::Request::SignAnchor`.
+///
+/// A contract adds a fee-bumping anchor output to a template by locking it
+/// (p2wpkh) to `get_signer_for(anchor_hash())`. The key is the same for every
+/// template, as the template's hash commits to the anchor output.
+pub fn anchor_hash() -> Sha256 {
+    Sha256::hash(b"CTVE anchor")
+}
+
+impl std::str::FromStr for DerivationScheme {
+    type Err = std::io::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unhardened" => Ok(DerivationScheme::Unhardened),
+            "short" => Ok(DerivationScheme::Short),
+            "hardened" => Ok(DerivationScheme::Hardened),
+            _ => input_error("Unknown Derivation Scheme"),
+        }
+    }
+}
@@ -9,7 +9,9 @@ use bitcoin::hashes::sha256::Hash as Sha256;
 use bitcoin::hashes::{Hash, HashEngine};
 use bitcoin::util::bip32::*;
 use sapio_ctv_emulator_trait::Clause;
-pub use sapio_ctv_emulator_trait::{CTVAvailable, CTVEmulator, EmulatorError, NullEmulator};
+pub use sapio_ctv_emulator_trait::{
+    AsyncCTVEmulator, CTVAvailable, CTVEmulator, EmulatorError, EmulatorFuture, NullEmulator,
+};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
@@ -20,13 +22,20 @@ use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
 use bitcoin::secp256k1::{All, Secp256k1};
 use bitcoin::util::psbt::PartiallySignedTransaction;
 use rand::Rng;
+use derivation::Derivation;
 use sapio_base::CTVHash;
 use std::sync::Arc;
 const MAX_MSG: usize = 1_000_000;
 
 pub mod connections;
-mod msgs;
+pub mod derivation;
+pub mod msgs;
+pub mod musig;
+pub mod noise;
+pub mod offline;
+pub mod protocol;
 pub mod servers;
+pub mod testing;
 
 thread_local! {
     pub static SECP: Secp256k1<All> = Secp256k1::new();
@@ -37,6 +46,14 @@ fn input_error<T>(s: &str) -> Result<T, std::io::Error> {
     Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, s))
 }
 
+/// If an extended key for `key_network` may be used on `network`.
+///
+/// Extended keys only distinguish mainnet (xpub/xprv) from the test networks
+/// (tpub/tprv), so keys for testnet, signet, and regtest are interchangeable.
+pub fn same_key_network(key_network: bitcoin::Network, network: bitcoin::Network) -> bool {
+    (key_network == bitcoin::Network::Bitcoin) == (network == bitcoin::Network::Bitcoin)
+}
+
 /// Compute a derivation path from a sha256 hash.
 ///
 /// Format is a bit peculiar, it's 9 u32's with the top bit as 0 (for unhardened
@@ -5,7 +5,8 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use super::*;
-use bitcoin::consensus::encode::{Decodable, Encodable};
+use crate::derivation::DerivationScheme;
+use bitcoin::consensus::encode::Encodable;
 use miniscript::serde;
 use serde::de::Visitor;
 use serde::de::*;
@@ -14,8 +15,42 @@ use std::fmt;
 
 const MAX_MSG: usize = 1_000_000;
 
+/// How messages are encoded within frames, see `protocol::Session::codec`.
+///
+/// JSON is always available, CBOR is used when both peers support it as it
+/// encodes PSBTs (and other byte strings) far more compactly.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    /// serde_json
+    Json,
+    /// serde_cbor
+    Cbor,
+}
+
+impl Codec {
+    /// encode a message
+    pub fn encode<T: Serialize>(&self, t: &T) -> Result<Vec<u8>, std::io::Error> {
+        match self {
+            Codec::Json => Ok(serde_json::to_vec(t)?),
+            Codec::Cbor => serde_cbor::to_vec(t)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+        }
+    }
+    /// decode a message
+    pub fn decode<T: DeserializeOwned>(&self, v: &[u8]) -> Result<T, std::io::Error> {
+        match self {
+            Codec::Json => Ok(serde_json::from_slice(v)?),
+            Codec::Cbor => serde_cbor::from_slice(v)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
 /// a PSBT Wrapper type. Note that Serialize/Deserialize are manually implemented
 /// limited to 1MB in size.
+///
+/// PSBTs are sent as version 0, but version 2 (BIP-370) PSBTs are accepted,
+/// see `sapio_base::psbt`.
 #[derive(Clone)]
 pub struct PSBT(pub PartiallySignedTransaction);
 
@@ -23,15 +58,464 @@ pub struct PSBT(pub PartiallySignedTransaction);
 #[derive(Serialize, Deserialize)]
 pub struct ConfirmKey(pub ExtendedPubKey, pub Sha256);
 
-/// a response from a server to a client with a challenge response
-#[derive(Serialize, Deserialize)]
-pub struct KeyConfirmed(pub bitcoin::secp256k1::Signature, pub Sha256);
+/// a response from a server to a client with a challenge response: a
+/// signature by the confirmed root, a nonce, the root's fingerprint, and the
+/// server's derivation scheme.
+///
+/// The scheme is omitted for `DerivationScheme::Unhardened`, so that older
+/// clients (which only know that scheme) can still confirm keys. The
+/// signature is over `KeyConfirmed::message`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct KeyConfirmed(
+    pub bitcoin::secp256k1::Signature,
+    pub Sha256,
+    pub Fingerprint,
+    #[serde(default, skip_serializing_if = "DerivationScheme::is_default")] pub DerivationScheme,
+);
+
+impl KeyConfirmed {
+    /// the message signed to confirm a key: sha256(nonce || challenge ||
+    /// fingerprint || scheme), where the scheme's tag is omitted for the
+    /// default scheme.
+    pub fn message(
+        nonce: &Sha256,
+        challenge: &Sha256,
+        fingerprint: &Fingerprint,
+        scheme: DerivationScheme,
+    ) -> bitcoin::secp256k1::Message {
+        let mut m = Sha256::engine();
+        m.input(&nonce.into_inner());
+        m.input(&challenge.into_inner());
+        m.input(&fingerprint[..]);
+        if !scheme.is_default() {
+            m.input(&[scheme.tag()]);
+        }
+        bitcoin::secp256k1::Message::from_slice(&Sha256::from_engine(m)[..])
+            .expect("Hashes are always 32 bytes")
+    }
+    /// if this is a valid response to `challenge` by `root`
+    pub fn signed_by(&self, challenge: &Sha256, root: &ExtendedPubKey) -> bool {
+        let KeyConfirmed(signature, nonce, fingerprint, scheme) = self;
+        if *fingerprint != root.fingerprint() {
+            return false;
+        }
+        let msg = Self::message(nonce, challenge, fingerprint, *scheme);
+        SECP.with(|secp| secp.verify(&msg, signature, &root.public_key.key).is_ok())
+    }
+}
+
+/// Asks a server for the key its root (identified by the xpub) uses for a
+/// CTV hash, for schemes where clients can't derive it themselves.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DeriveKey(pub ExtendedPubKey, pub Sha256);
+
+/// The response to a `DeriveKey`: the key, and a signature by the root over
+/// `DerivedKey::message`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DerivedKey(pub bitcoin::PublicKey, pub bitcoin::secp256k1::Signature);
+
+impl DerivedKey {
+    /// the message a root signs for its key: sha256("CTVE derive" || hash ||
+    /// scheme || key)
+    pub fn message(
+        h: &Sha256,
+        scheme: DerivationScheme,
+        key: &bitcoin::PublicKey,
+    ) -> bitcoin::secp256k1::Message {
+        let mut m = Sha256::engine();
+        m.input(b"CTVE derive");
+        m.input(&h.into_inner());
+        m.input(&[scheme.tag()]);
+        m.input(&key.to_bytes()[..]);
+        bitcoin::secp256k1::Message::from_slice(&Sha256::from_engine(m)[..])
+            .expect("Hashes are always 32 bytes")
+    }
+}
+
+/// A token, signed by an issuer an oracle trusts, permitting a client to
+/// request signatures until `expires` (a unix timestamp).
+///
+/// If `subject` is set, the token is only valid on encrypted connections
+/// where the client's key is `subject` (see `noise`), otherwise it is a
+/// bearer token.
+///
+/// As text (e.g. in an HTTP `Authorization: Bearer` header) a token is the
+/// hex of `expires:u64 signature:[u8;64] subject:[u8;33]?`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AuthToken {
+    pub expires: u64,
+    pub subject: Option<bitcoin::secp256k1::PublicKey>,
+    pub signature: bitcoin::secp256k1::Signature,
+}
+
+impl AuthToken {
+    /// the message an issuer signs: sha256("CTVE auth" || expires || subject)
+    pub fn message(
+        expires: u64,
+        subject: Option<&bitcoin::secp256k1::PublicKey>,
+    ) -> bitcoin::secp256k1::Message {
+        let mut m = Sha256::engine();
+        m.input(b"CTVE auth");
+        m.input(&expires.to_be_bytes());
+        if let Some(subject) = subject {
+            m.input(&subject.serialize()[..]);
+        }
+        bitcoin::secp256k1::Message::from_slice(&Sha256::from_engine(m)[..])
+            .expect("Hashes are always 32 bytes")
+    }
+    /// issue a token signed by `issuer`
+    pub fn issue(
+        issuer: &bitcoin::secp256k1::SecretKey,
+        expires: u64,
+        subject: Option<bitcoin::secp256k1::PublicKey>,
+    ) -> Self {
+        let msg = Self::message(expires, subject.as_ref());
+        AuthToken {
+            expires,
+            subject,
+            signature: SECP.with(|secp| secp.sign(&msg, issuer)),
+        }
+    }
+}
+
+impl fmt::Display for AuthToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use bitcoin::hashes::hex::ToHex;
+        let mut v = self.expires.to_be_bytes().to_vec();
+        v.extend_from_slice(&self.signature.serialize_compact()[..]);
+        if let Some(subject) = &self.subject {
+            v.extend_from_slice(&subject.serialize()[..]);
+        }
+        f.write_str(&v.to_hex())
+    }
+}
+
+impl std::str::FromStr for AuthToken {
+    type Err = std::io::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use bitcoin::hashes::hex::FromHex;
+        let v = <Vec<u8> as FromHex>::from_hex(s).or_else(|_| input_error("Invalid Token"))?;
+        if v.len() != 72 && v.len() != 72 + 33 {
+            return input_error("Invalid Token");
+        }
+        let mut expires = [0u8; 8];
+        expires.copy_from_slice(&v[..8]);
+        Ok(AuthToken {
+            expires: u64::from_be_bytes(expires),
+            signature: bitcoin::secp256k1::Signature::from_compact(&v[8..72])
+                .or_else(|_| input_error("Invalid Token"))?,
+            subject: match v.get(72..) {
+                Some(subject) if !subject.is_empty() => Some(
+                    bitcoin::secp256k1::PublicKey::from_slice(subject)
+                        .or_else(|_| input_error("Invalid Token"))?,
+                ),
+                _ => None,
+            },
+        })
+    }
+}
+
+/// The response to `Request::Authenticate`: the client may request
+/// signatures until `expires`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Authenticated {
+    pub expires: u64,
+}
+
+/// A server's statement that transaction `txid` carries a valid signature
+/// for each of `inputs` (by index) by the key it derived for the input, i.e.
+/// that the transaction is the one those keys were derived for. The
+/// response to `Request::Verify`, see `servers::watch`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Attestation {
+    pub txid: bitcoin::Txid,
+    pub inputs: Vec<(usize, bitcoin::PublicKey)>,
+    /// the server's attestation key and its signature over
+    /// `Attestation::message`, if the server has one
+    pub signature: Option<(bitcoin::secp256k1::PublicKey, bitcoin::secp256k1::Signature)>,
+}
+
+impl Attestation {
+    /// the message an attestation key signs: sha256("CTVE attest" || txid ||
+    /// (input:u32 || key) for each input)
+    pub fn message(
+        txid: &bitcoin::Txid,
+        inputs: &[(usize, bitcoin::PublicKey)],
+    ) -> bitcoin::secp256k1::Message {
+        let mut m = Sha256::engine();
+        m.input(b"CTVE attest");
+        m.input(&txid[..]);
+        for (input, key) in inputs {
+            m.input(&(*input as u32).to_be_bytes());
+            m.input(&key.to_bytes()[..]);
+        }
+        bitcoin::secp256k1::Message::from_slice(&Sha256::from_engine(m)[..])
+            .expect("Hashes are always 32 bytes")
+    }
+    /// if the attestation is signed by `attester`
+    pub fn signed_by(&self, attester: &bitcoin::secp256k1::PublicKey) -> bool {
+        match &self.signature {
+            Some((key, signature)) if key == attester => {
+                let msg = Self::message(&self.txid, &self.inputs[..]);
+                SECP.with(|secp| secp.verify(&msg, signature, key).is_ok())
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Asks a server to prove that it is online and holds the root (identified
+/// by the xpub) now, by signing `challenge` along with the time. Unlike
+/// `ConfirmKey`, meant to be sent periodically, e.g. by a federation
+/// monitoring its members.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Liveness(pub ExtendedPubKey, pub Sha256);
+
+/// The response to `Liveness`: the server's unix time, the root's
+/// fingerprint, and the root's signature over `Alive::message`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Alive {
+    pub timestamp: u64,
+    pub fingerprint: Fingerprint,
+    pub signature: bitcoin::secp256k1::Signature,
+}
+
+impl Alive {
+    /// the message a root signs: sha256("CTVE alive" || challenge ||
+    /// timestamp:u64 || fingerprint)
+    pub fn message(
+        challenge: &Sha256,
+        timestamp: u64,
+        fingerprint: &Fingerprint,
+    ) -> bitcoin::secp256k1::Message {
+        let mut m = Sha256::engine();
+        m.input(b"CTVE alive");
+        m.input(&challenge.into_inner());
+        m.input(&timestamp.to_be_bytes());
+        m.input(&fingerprint[..]);
+        bitcoin::secp256k1::Message::from_slice(&Sha256::from_engine(m)[..])
+            .expect("Hashes are always 32 bytes")
+    }
+    /// if this is a valid response to `challenge` by `root`
+    pub fn signed_by(&self, challenge: &Sha256, root: &ExtendedPubKey) -> bool {
+        if self.fingerprint != root.fingerprint() {
+            return false;
+        }
+        let msg = Self::message(challenge, self.timestamp, &self.fingerprint);
+        SECP.with(|secp| {
+            secp.verify(&msg, &self.signature, &root.public_key.key)
+                .is_ok()
+        })
+    }
+}
+
+/// A request or its response tagged with an id, on sessions which
+/// negotiated `protocol::Capabilities::PIPELINE`. Clients number their
+/// requests from 1, servers tag errors which respond to no request with 0.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Correlated<T>(pub u64, pub T);
+
+/// A request and how many milliseconds the client will wait for its
+/// response (if it has a limit), on sessions which negotiated
+/// `protocol::Capabilities::DEADLINE`. The server counts from when it reads
+/// the request, so that the peers' clocks needn't agree.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Timed<T>(pub Option<u64>, pub T);
+
+/// An error a server sends in place of a response
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ServerError {
+    /// The server is serving too many connections, the connection is closed.
+    TooManyConnections,
+    /// Too many requests have come from this client recently, retry later.
+    RateLimited,
+    /// The connection has served as many requests as it may, the connection
+    /// is closed.
+    RequestLimitReached,
+    /// The key a client asked to confirm (by fingerprint) is not one of the
+    /// server's root keys.
+    KeyMismatch(Fingerprint),
+    /// The server only signs for authorized clients, and this client has not
+    /// presented a valid token (or is not on the allowlist).
+    Unauthorized,
+    /// The server could not derive its key for an input.
+    DerivationFailed,
+    /// The server's policy refused to sign, with the violation.
+    PolicyRejected(String),
+    /// The PSBT is missing data needed to sign it, or its scripts are
+    /// inconsistent with its UTXOs.
+    MalformedPSBT(String),
+    /// An input the client expected the server to sign uses a script (or
+    /// sighash) the server can not sign for.
+    UnsupportedScript(String),
+    /// The server is watch-only, so it can verify signatures but not make
+    /// them.
+    WatchOnly,
+    /// A transaction sent to be verified lacks a valid signature by the
+    /// server's key, with the input and reason.
+    VerificationFailed(String),
+    /// The request could not be finished before the deadline the client
+    /// gave (see `Timed`), so the server abandoned it.
+    DeadlineExceeded,
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for ServerError {}
+
+impl ServerError {
+    /// if the server closes the connection after sending this error
+    pub fn closes_connection(&self) -> bool {
+        match self {
+            ServerError::TooManyConnections | ServerError::RequestLimitReached => true,
+            ServerError::RateLimited
+            | ServerError::KeyMismatch(_)
+            | ServerError::Unauthorized
+            | ServerError::DerivationFailed
+            | ServerError::PolicyRejected(_)
+            | ServerError::MalformedPSBT(_)
+            | ServerError::UnsupportedScript(_)
+            | ServerError::WatchOnly
+            | ServerError::VerificationFailed(_)
+            | ServerError::DeadlineExceeded => false,
+        }
+    }
+    /// if the same request may succeed if sent again later. Other errors are
+    /// permanent until the request (or the client's configuration) changes.
+    pub fn retryable(&self) -> bool {
+        match self {
+            ServerError::TooManyConnections
+            | ServerError::RateLimited
+            | ServerError::RequestLimitReached
+            | ServerError::DeadlineExceeded => true,
+            ServerError::KeyMismatch(_)
+            | ServerError::Unauthorized
+            | ServerError::DerivationFailed
+            | ServerError::PolicyRejected(_)
+            | ServerError::MalformedPSBT(_)
+            | ServerError::UnsupportedScript(_)
+            | ServerError::WatchOnly
+            | ServerError::VerificationFailed(_) => false,
+        }
+    }
+    /// the `ServerError` carried by `e`, if any. Clients return the errors
+    /// a server responds with this way.
+    pub fn from_io(e: &std::io::Error) -> Option<&ServerError> {
+        e.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+}
+
+impl From<ServerError> for std::io::Error {
+    fn from(e: ServerError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::Other, e)
+    }
+}
 
 /// Wrapper for message serialization
 #[derive(Serialize, Deserialize)]
 pub enum Request {
     ConfirmKey(ConfirmKey),
     SignPSBT(PSBT),
+    /// Sign every PSBT, responded to with a `SignedBatch` in the same order.
+    ///
+    /// Only sent if the server advertises `protocol::Capabilities::BATCH`.
+    SignBatch(Vec<PSBT>),
+    /// Round one of MuSig2 signing, responded to with `MusigNonces`.
+    MusigNonce(MusigNonceRequest),
+    /// Round two of MuSig2 signing, responded to with `MusigPartialSigs`.
+    MusigSign(MusigSignRequest),
+    /// Presents a token authorizing the connection to request signatures,
+    /// responded to with `Authenticated`.
+    Authenticate(AuthToken),
+    /// Asks for the key for a CTV hash, responded to with `DerivedKey`. See
+    /// `derivation`.
+    DeriveKey(DeriveKey),
+    /// Asks the server to check its signatures on a signed PSBT, responded to
+    /// with an `Attestation`. See `servers::watch`.
+    Verify(PSBT),
+    /// Asks the server to prove it is online, responded to with `Alive`.
+    Liveness(Liveness),
+    /// Asks the server to sign a child spending a template's anchor output
+    /// to fees, responded to with the signed `PSBT`.
+    SignAnchor(AnchorSpend),
+}
+
+/// Asks a server to sign the input of `psbt` spending `anchor`, an output of
+/// a template transaction locked to `root`'s anchor key (see
+/// `derivation::anchor_hash`), so that the template can be bumped by
+/// child-pays-for-parent.
+///
+/// The server only signs (SIGHASH_ALL) if the anchor's value is within its
+/// policy's bound and goes entirely to fees, i.e. the child's outputs are
+/// paid for by its other inputs. As the anchor's signature does not commit to
+/// the other inputs' values, each must carry the transaction it spends
+/// (`non_witness_utxo`). Other inputs are left for the client to sign.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AnchorSpend {
+    pub root: ExtendedPubKey,
+    pub anchor: bitcoin::OutPoint,
+    pub psbt: PSBT,
+}
+
+/// Asks a server to commit to a nonce for every taproot input of `psbt`
+/// whose key is the aggregate of the keys derived from `participants` (which
+/// must include the server).
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MusigNonceRequest {
+    pub psbt: PSBT,
+    pub participants: Vec<ExtendedPubKey>,
+}
+
+/// A server's nonces, by input index, for the MuSig2 session `session`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MusigNonces {
+    pub session: [u8; 32],
+    pub nonces: Vec<(usize, crate::musig::PubNonce)>,
+}
+
+/// Asks a server to sign the inputs of the PSBT from round one of `session`,
+/// given the aggregate of every participant's nonce for each input.
+///
+/// A session may only be signed once.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MusigSignRequest {
+    pub session: [u8; 32],
+    pub aggnonces: Vec<(usize, crate::musig::PubNonce)>,
+}
+
+/// A server's partial signatures, by input index.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MusigPartialSigs(pub Vec<(usize, [u8; 32])>);
+
+/// The response to a `Request::SignBatch`. Either all PSBTs are signed, or the
+/// request fails and none are returned.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SignedBatch(pub Vec<PSBT>);
+
+/// Any response a server sends to a `Request`.
+///
+/// Serialized exactly as the wrapped type, so that every transport can be
+/// served from one handler without changing what is on the wire.
+///
+/// A request which fails is responded to with a `ServerError`, rather than
+/// the connection being closed, so that clients can tell (see
+/// `ServerError::retryable`) whether to try again.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum Response {
+    PSBT(PSBT),
+    SignedBatch(SignedBatch),
+    MusigNonces(MusigNonces),
+    MusigPartialSigs(MusigPartialSigs),
+    KeyConfirmed(KeyConfirmed),
+    Authenticated(Authenticated),
+    DerivedKey(DerivedKey),
+    Attestation(Attestation),
+    Alive(Alive),
+    Error(ServerError),
 }
 
 /// A visitor tage for a SafePSBT type that is size limited
@@ -70,9 +554,28 @@ impl<'de> Visitor<'de> for SafePSBT {
             v.push(seq.next_element()?.ok_or_else(length_error)?);
         }
 
-        return PartiallySignedTransaction::consensus_decode(&v[..])
+        return sapio_base::psbt::decode(&v[..])
+            .map_err(de::Error::custom)
+            .map(|(psbt, _)| PSBT(psbt));
+    }
+    /// Binary formats (e.g., CBOR) hand us the whole byte string at once
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        if v.len() < 4 {
+            return Err(de::Error::invalid_length(v.len(), &"Expected at least 4 bytes."));
+        }
+        let len = u32::from_be_bytes([v[0], v[1], v[2], v[3]]) as usize;
+        if len > self.0 {
+            return Err(de::Error::invalid_length(self.0, &"Length Exceeded Maximum"));
+        }
+        if v.len() - 4 != len {
+            return Err(de::Error::invalid_length(len, &"Expected enough bytes"));
+        }
+        sapio_base::psbt::decode(&v[4..])
             .map_err(de::Error::custom)
-            .map(PSBT);
+            .map(|(psbt, _)| PSBT(psbt))
     }
 }
 
@@ -0,0 +1,585 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! MuSig2 (BIP-327) for n-of-n federations of oracles.
+//!
+//! A federation's keys for a CTV hash are aggregated into a single taproot
+//! key, so that emulated covenant spends are indistinguishable from any other
+//! single key spend. Signing takes two rounds: every member first commits to
+//! a nonce, and then, given the aggregate of all nonces, produces a partial
+//! signature. The partial signatures sum to an ordinary BIP-340 signature.
+//!
+//! Our version of secp256k1 does not support MuSig2, so it is implemented
+//! here with scalar and point arithmetic.
+use super::*;
+use bitcoin::secp256k1::{PublicKey, SecretKey};
+use sapio_base::taproot::{self, tagged_hash};
+use serde_derive::{Deserialize, Serialize};
+
+fn invalid() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid MuSig2 Value")
+}
+
+/// interprets a hash as a scalar
+fn scalar(h: Sha256) -> Result<SecretKey, std::io::Error> {
+    SecretKey::from_slice(&h[..]).map_err(|_| invalid())
+}
+
+fn has_even_y(p: &PublicKey) -> bool {
+    p.serialize()[0] == 0x02
+}
+
+fn xonly(p: &PublicKey) -> [u8; 32] {
+    let mut x = [0u8; 32];
+    x.copy_from_slice(&p.serialize()[1..]);
+    x
+}
+
+/// The aggregate of a set of keys, with any tweaks applied.
+#[derive(Clone)]
+pub struct KeyAggContext {
+    keys: Vec<PublicKey>,
+    q: PublicKey,
+    /// if the accumulated sign is -1
+    gacc_negated: bool,
+    /// the accumulated tweak, None if 0
+    tacc: Option<SecretKey>,
+}
+
+impl KeyAggContext {
+    /// Aggregates `keys`, which are sorted first so that every participant
+    /// arrives at the same key regardless of the order they were given in.
+    pub fn new(mut keys: Vec<PublicKey>) -> Result<Self, std::io::Error> {
+        keys.sort_by_key(|k| k.serialize());
+        Self::ordered(keys)
+    }
+
+    /// Aggregates `keys` in the order given, as BIP-327's KeyAgg does.
+    pub fn ordered(keys: Vec<PublicKey>) -> Result<Self, std::io::Error> {
+        if keys.is_empty() {
+            return input_error("No Keys to Aggregate");
+        }
+        let q = taproot::key_agg(&keys[..]).map_err(|_| invalid())?;
+        Ok(KeyAggContext {
+            keys,
+            q,
+            gacc_negated: false,
+            tacc: None,
+        })
+    }
+
+    /// the coefficient for `key`, None if it is 1.
+    fn coefficient(&self, key: &PublicKey) -> Result<Option<SecretKey>, std::io::Error> {
+        taproot::key_agg_coefficient(&self.keys[..], key)
+            .map(scalar)
+            .transpose()
+    }
+
+    /// Applies an x-only tweak `t`, i.e., the key becomes `lift_x(Q) + t*G`.
+    pub fn tweak_xonly(&mut self, t: &[u8; 32]) -> Result<(), std::io::Error> {
+        self.tweak(t, true)
+    }
+
+    /// Applies a plain tweak `t`, i.e., the key becomes `Q + t*G`.
+    pub fn tweak_plain(&mut self, t: &[u8; 32]) -> Result<(), std::io::Error> {
+        self.tweak(t, false)
+    }
+
+    fn tweak(&mut self, t: &[u8; 32], is_xonly: bool) -> Result<(), std::io::Error> {
+        let t = SecretKey::from_slice(&t[..]).map_err(|_| invalid())?;
+        let negate = is_xonly && !has_even_y(&self.q);
+        SECP.with(|secp| {
+            if negate {
+                self.q.negate_assign(secp);
+            }
+            self.q.add_exp_assign(secp, &t[..]).map_err(|_| invalid())
+        })?;
+        if negate {
+            self.gacc_negated = !self.gacc_negated;
+        }
+        self.tacc = Some(match self.tacc.take() {
+            Some(mut tacc) => {
+                if negate {
+                    tacc.negate_assign();
+                }
+                tacc.add_assign(&t[..]).map_err(|_| invalid())?;
+                tacc
+            }
+            None => t,
+        });
+        Ok(())
+    }
+
+    /// Applies the BIP-86 taproot tweak (i.e., no script tree).
+    pub fn tweak_taproot(&mut self) -> Result<(), std::io::Error> {
+        let t = tagged_hash("TapTweak", &xonly(&self.q)[..]);
+        self.tweak_xonly(&t.into_inner())
+    }
+
+    /// the aggregate key
+    pub fn public_key(&self) -> PublicKey {
+        self.q
+    }
+
+    /// the aggregate key in x-only form, as it appears in an output
+    pub fn xonly(&self) -> [u8; 32] {
+        xonly(&self.q)
+    }
+
+    /// if `key` is one of the aggregated keys
+    pub fn contains(&self, key: &PublicKey) -> bool {
+        self.keys.contains(key)
+    }
+}
+
+/// A participant's public nonce
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PubNonce(pub PublicKey, pub PublicKey);
+
+/// A participant's secret nonce, which must only ever be used once.
+///
+/// Deliberately not `Clone`.
+pub struct SecNonce(SecretKey, SecretKey);
+
+impl SecNonce {
+    /// generate a fresh nonce from the system rng
+    pub fn generate() -> (SecNonce, PubNonce) {
+        let random = || loop {
+            let bytes: [u8; 32] = rand::thread_rng().gen();
+            if let Ok(k) = SecretKey::from_slice(&bytes[..]) {
+                break k;
+            }
+        };
+        let (k1, k2) = (random(), random());
+        let p = SECP.with(|secp| {
+            PubNonce(
+                PublicKey::from_secret_key(secp, &k1),
+                PublicKey::from_secret_key(secp, &k2),
+            )
+        });
+        (SecNonce(k1, k2), p)
+    }
+}
+
+/// sums the nonces of every participant
+pub fn aggregate_nonces(nonces: &[PubNonce]) -> Result<PubNonce, std::io::Error> {
+    let (first, rest) = nonces.split_first().ok_or_else(invalid)?;
+    let mut agg = *first;
+    for n in rest {
+        agg.0 = agg.0.combine(&n.0).map_err(|_| invalid())?;
+        agg.1 = agg.1.combine(&n.1).map_err(|_| invalid())?;
+    }
+    Ok(agg)
+}
+
+/// the values shared by all signers of `msg` once nonces are aggregated
+struct SessionValues {
+    b: SecretKey,
+    r: PublicKey,
+    e: SecretKey,
+}
+
+impl SessionValues {
+    fn new(
+        ctx: &KeyAggContext,
+        aggnonce: &PubNonce,
+        msg: &[u8; 32],
+    ) -> Result<SessionValues, std::io::Error> {
+        let mut m = aggnonce.0.serialize().to_vec();
+        m.extend_from_slice(&aggnonce.1.serialize()[..]);
+        m.extend_from_slice(&ctx.xonly()[..]);
+        m.extend_from_slice(&msg[..]);
+        let b = scalar(tagged_hash("MuSig/noncecoef", &m[..]))?;
+        let mut r2 = aggnonce.1;
+        SECP.with(|secp| r2.mul_assign(secp, &b[..])).map_err(|_| invalid())?;
+        let r = aggnonce.0.combine(&r2).map_err(|_| invalid())?;
+        let mut m = xonly(&r).to_vec();
+        m.extend_from_slice(&ctx.xonly()[..]);
+        m.extend_from_slice(&msg[..]);
+        let e = scalar(tagged_hash("BIP0340/challenge", &m[..]))?;
+        Ok(SessionValues { b, r, e })
+    }
+}
+
+/// Produces a partial signature over `msg` with `sk`, which must be one of
+/// the keys aggregated in `ctx`. Consumes the secret nonce.
+pub fn partial_sign(
+    secnonce: SecNonce,
+    sk: &SecretKey,
+    ctx: &KeyAggContext,
+    aggnonce: &PubNonce,
+    msg: &[u8; 32],
+) -> Result<[u8; 32], std::io::Error> {
+    let pk = SECP.with(|secp| PublicKey::from_secret_key(secp, sk));
+    if !ctx.contains(&pk) {
+        return input_error("Key Not Part of Aggregate");
+    }
+    let v = SessionValues::new(ctx, aggnonce, msg)?;
+    let SecNonce(mut k1, mut k2) = secnonce;
+    if !has_even_y(&v.r) {
+        k1.negate_assign();
+        k2.negate_assign();
+    }
+    let mut d = *sk;
+    if !has_even_y(&ctx.q) != ctx.gacc_negated {
+        d.negate_assign();
+    }
+    // s = k1 + b*k2 + e*a*d
+    k2.mul_assign(&v.b[..]).map_err(|_| invalid())?;
+    d.mul_assign(&v.e[..]).map_err(|_| invalid())?;
+    if let Some(a) = ctx.coefficient(&pk)? {
+        d.mul_assign(&a[..]).map_err(|_| invalid())?;
+    }
+    k1.add_assign(&k2[..]).map_err(|_| invalid())?;
+    k1.add_assign(&d[..]).map_err(|_| invalid())?;
+    let mut s = [0u8; 32];
+    s.copy_from_slice(&k1[..]);
+    Ok(s)
+}
+
+/// Combines every participant's partial signature into a BIP-340 signature
+/// for the aggregate key.
+pub fn aggregate_partial_sigs(
+    ctx: &KeyAggContext,
+    aggnonce: &PubNonce,
+    msg: &[u8; 32],
+    partials: &[[u8; 32]],
+) -> Result<[u8; 64], std::io::Error> {
+    let v = SessionValues::new(ctx, aggnonce, msg)?;
+    let (first, rest) = partials.split_first().ok_or_else(invalid)?;
+    let mut s = SecretKey::from_slice(&first[..]).map_err(|_| invalid())?;
+    for p in rest {
+        s.add_assign(&p[..]).map_err(|_| invalid())?;
+    }
+    if let Some(mut t) = ctx.tacc {
+        if !has_even_y(&ctx.q) {
+            t.negate_assign();
+        }
+        t.mul_assign(&v.e[..]).map_err(|_| invalid())?;
+        s.add_assign(&t[..]).map_err(|_| invalid())?;
+    }
+    let mut sig = [0u8; 64];
+    sig[..32].copy_from_slice(&xonly(&v.r)[..]);
+    sig[32..].copy_from_slice(&s[..]);
+    Ok(sig)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::hex::FromHex;
+    use bitcoin::secp256k1::{schnorrsig, Message};
+
+    fn secret(i: u8) -> SecretKey {
+        SecretKey::from_slice(&[i; 32]).unwrap()
+    }
+    fn key(i: u8) -> PublicKey {
+        SECP.with(|secp| PublicKey::from_secret_key(secp, &secret(i)))
+    }
+    fn hex(s: &str) -> Vec<u8> {
+        Vec::<u8>::from_hex(s).unwrap()
+    }
+    fn bytes(s: &str) -> [u8; 32] {
+        let mut b = [0u8; 32];
+        b.copy_from_slice(&hex(s)[..]);
+        b
+    }
+    fn point(s: &str) -> PublicKey {
+        PublicKey::from_slice(&hex(s)[..]).unwrap()
+    }
+    fn nonce(s: &str) -> PubNonce {
+        let b = hex(s);
+        PubNonce(
+            PublicKey::from_slice(&b[..33]).unwrap(),
+            PublicKey::from_slice(&b[33..]).unwrap(),
+        )
+    }
+    fn verify(ctx: &KeyAggContext, msg: &[u8; 32], sig: &[u8; 64]) -> bool {
+        let sig = schnorrsig::Signature::from_slice(&sig[..]).unwrap();
+        let key = schnorrsig::PublicKey::from_slice(&ctx.xonly()[..]).unwrap();
+        let msg = Message::from_slice(&msg[..]).unwrap();
+        SECP.with(|secp| secp.schnorrsig_verify(&sig, &msg, &key).is_ok())
+    }
+
+    // the signer of BIP-327's sign_verify_vectors.json and tweak_vectors.json
+    const SK: &str = "7FB9E0E687ADA1EEBF7ECFE2F21E73EBDB51A7D450948DFE8D76D7F2D1007671";
+    const SECNONCE: &str = "508B81A611F100A6B2B6B29656590898AF488BCF2E1F55CF22E5CFB84421FE61FA27FD49B1D50085B481285E1CA205D55C82CC1B31FF5CD54A489829355901F7";
+    const AGGNONCE: &str = "028465FCF0BBDBCF443AABCCE533D42B4B5A10966AC09A49655E8C42DAAB8FCD61037496A3CC86926D452CAFCFD55D25972CA1675D549310DE296BFF42F72EEEA8C9";
+    const MSG: &str = "F95466D086770E689964664219266FE5ED215C92AE20BAB5C9D79ADDDDF3C0CF";
+
+    fn secnonce() -> SecNonce {
+        let b = hex(SECNONCE);
+        SecNonce(
+            SecretKey::from_slice(&b[..32]).unwrap(),
+            SecretKey::from_slice(&b[32..]).unwrap(),
+        )
+    }
+
+    #[test]
+    fn key_agg_vectors() {
+        // BIP-327 key_agg_vectors.json
+        let keys = [
+            point("02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9"),
+            point("03DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659"),
+            point("023590A94E768F8E1815C2F24B4D80A8E3149316C3518CE7B7AD338368D038CA66"),
+        ];
+        let cases: [(&[usize], &str); 4] = [
+            (
+                &[0, 1, 2],
+                "90539EEDE565F5D054F32CC0C220126889ED1E5D193BAF15AEF344FE59D4610C",
+            ),
+            (
+                &[2, 1, 0],
+                "6204DE8B083426DC6EAF9502D27024D53FC826BF7D2012148A0575435DF54B2B",
+            ),
+            (
+                &[0, 0, 0],
+                "B436E3BAD62B8CD409969A224731C193D051162D8C5AE8B109306127DA3AA935",
+            ),
+            (
+                &[0, 0, 1, 1],
+                "69BC22BFA5D106306E48A20679DE1D7389386124D07571D0D872686028C26A3E",
+            ),
+        ];
+        for (indices, expected) in cases.iter() {
+            let ctx = KeyAggContext::ordered(indices.iter().map(|i| keys[*i]).collect()).unwrap();
+            assert_eq!(ctx.xonly(), bytes(expected));
+        }
+        // sorting first makes the order the keys are given in irrelevant
+        assert_eq!(
+            KeyAggContext::new(keys.to_vec()).unwrap().public_key(),
+            KeyAggContext::new(keys.iter().rev().cloned().collect())
+                .unwrap()
+                .public_key()
+        );
+        assert!(KeyAggContext::new(vec![]).is_err());
+    }
+
+    #[test]
+    fn nonce_agg_vectors() {
+        // BIP-327 nonce_agg_vectors.json
+        let nonces = [
+            nonce("020151C80F435648DF67A22B749CD798CE54E0321D034B92B709B567D60A42E66603BA47FBC1834437B3212E89A84D8425E7BF12E0245D98262268EBDCB385D50641"),
+            nonce("03FF406FFD8ADB9CD29877E4985014F66A59F6CD01C0E88CAA8E5F3166B1F676A60248C264CDD57D3C24D79990B0F865674EB62A0F9018277A95011B41BFC193B833"),
+        ];
+        assert_eq!(
+            aggregate_nonces(&nonces[..]).unwrap(),
+            nonce("035FE1873B4F2967F52FEA4A06AD5A8ECCBE9D0FD73068012C894E2E87CCB5804B024725377345BDE0E9C33AF3C43C0A29A9249F2F2956FA8CFEB55C8573D0262DC8")
+        );
+        assert!(aggregate_nonces(&[]).is_err());
+    }
+
+    #[test]
+    fn sign_vectors() {
+        // BIP-327 sign_verify_vectors.json
+        let keys = [
+            point("03935F972DA013F80AE011890FA89B67A27B7BE6CCB24D3274D18B2D4067F261A9"),
+            point("02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9"),
+            point("02DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA661"),
+        ];
+        let nonces = [
+            nonce("0337C87821AFD50A8644D820A8F3E02E499C931865C2360FB43D0A0D20DAFE07EA0287BF891D2A6DEAEBADC909352AA9405D1428C15F4B75F04DAE642A95C2548480"),
+            nonce("0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F817980279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798"),
+            nonce("032DE2662628C90B03F5E720284EB52FF7D71F4284F627B68A853D78C78E1FFE9303E4C5524E83FFE1493B9077CF1CA6BEB2090C93D930321071AD40B2F44E599046"),
+        ];
+        assert_eq!(aggregate_nonces(&nonces[..]).unwrap(), nonce(AGGNONCE));
+        let sk = SecretKey::from_slice(&hex(SK)[..]).unwrap();
+        let cases: [([usize; 3], &str); 3] = [
+            (
+                [0, 1, 2],
+                "012ABBCB52B3016AC03AD82395A1A415C48B93DEF78718E62A7A90052FE224FB",
+            ),
+            (
+                [1, 0, 2],
+                "9FF2F7AAA856150CC8819254218D3ADEEB0535269051897724F9DB3789513A52",
+            ),
+            (
+                [1, 2, 0],
+                "FA23C359F6FAC4E7796BB93BC9F0532A95468C539BA20FF86D7C76ED92227900",
+            ),
+        ];
+        for (indices, expected) in cases.iter() {
+            let ctx = KeyAggContext::ordered(indices.iter().map(|i| keys[*i]).collect()).unwrap();
+            let sig = partial_sign(secnonce(), &sk, &ctx, &nonce(AGGNONCE), &bytes(MSG)).unwrap();
+            assert_eq!(sig, bytes(expected));
+        }
+        // the signer's key must be one of those aggregated
+        let ctx = KeyAggContext::ordered(keys[1..].to_vec()).unwrap();
+        assert!(partial_sign(secnonce(), &sk, &ctx, &nonce(AGGNONCE), &bytes(MSG)).is_err());
+    }
+
+    #[test]
+    fn tweak_vectors() {
+        // BIP-327 tweak_vectors.json
+        let keys = vec![
+            point("02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9"),
+            point("02DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659"),
+            point("03935F972DA013F80AE011890FA89B67A27B7BE6CCB24D3274D18B2D4067F261A9"),
+        ];
+        let tweaks = [
+            bytes("E8F791FF9225A2AF0102AFFF4A9A723D9612A682A25EBE79802B263CDFCD83BB"),
+            bytes("AE2EA797CC0FE72AC5B97B97F3C6957D7E4199A167A58EB08BCAFFDA70AC0455"),
+            bytes("F52ECBC565B3D8BEA2DFD5B75A4F457E54369809322E4120831626F290FA87E0"),
+            bytes("1969AD73CC177FA0B4FCED6DF1F7BF9907E665FDE9BA196A74FED0A3CF5AEF9D"),
+        ];
+        let sk = SecretKey::from_slice(&hex(SK)[..]).unwrap();
+        let cases: [(&[usize], &[bool], &str); 5] = [
+            (
+                &[0],
+                &[true],
+                "E28A5C66E61E178C2BA19DB77B6CF9F7E2F0F56C17918CD13135E60CC848FE91",
+            ),
+            (
+                &[0],
+                &[false],
+                "38B0767798252F21BF5702C48028B095428320F73A4B14DB1E25DE58543D2D2D",
+            ),
+            (
+                &[0, 1],
+                &[false, true],
+                "408A0A21C4A0F5DACAF9646AD6EB6FECD7F7A11F03ED1F48DFFF2185BC2C2408",
+            ),
+            (
+                &[0, 1, 2, 3],
+                &[false, false, true, true],
+                "45ABD206E61E3DF2EC9E264A6FEC8292141A633C28586388235541F9ADE75435",
+            ),
+            (
+                &[0, 1, 2, 3],
+                &[true, false, true, false],
+                "B255FDCAC27B40C7CE7848E2D3B7BF5EA0ED756DA81565AC804CCCA3E1D5D239",
+            ),
+        ];
+        for (indices, xonly, expected) in cases.iter() {
+            let mut ctx = KeyAggContext::ordered(keys.clone()).unwrap();
+            for (i, is_xonly) in indices.iter().zip(xonly.iter()) {
+                if *is_xonly {
+                    ctx.tweak_xonly(&tweaks[*i]).unwrap();
+                } else {
+                    ctx.tweak_plain(&tweaks[*i]).unwrap();
+                }
+            }
+            let sig = partial_sign(secnonce(), &sk, &ctx, &nonce(AGGNONCE), &bytes(MSG)).unwrap();
+            assert_eq!(sig, bytes(expected));
+        }
+        // a tweak must be a scalar
+        let mut ctx = KeyAggContext::ordered(keys).unwrap();
+        assert!(ctx.tweak_xonly(&[0xff; 32]).is_err());
+    }
+
+    #[test]
+    fn sig_agg_vectors() {
+        // BIP-327 sig_agg_vectors.json
+        let keys = [
+            point("03935F972DA013F80AE011890FA89B67A27B7BE6CCB24D3274D18B2D4067F261A9"),
+            point("02D2DC6F5DF7C56ACF38C7FA0AE7A759AE30E19B37359DFDE015872324C7EF6E05"),
+            point("03C7FB101D97FF930ACD0C6760852EF64E69083DE0B06AC6335724754BB4B0522C"),
+            point("02352433B21E7E05D3B452B81CAE566E06D2E003ECE16D1074AABA4289E0E3D581"),
+        ];
+        let tweaks = [
+            bytes("B511DA492182A91B0FFB9A98020D55F260AE86D7ECBD0399C7383D59A5F2AF7C"),
+            bytes("A815FE049EE3C5AAB66310477FBC8BCCCAC2F3395F59F921C364ACD78A2F48DC"),
+            bytes("75448A87274B056468B977BE06EB1E9F657577B7320B0A3376EA51FD420D18A8"),
+        ];
+        let psigs = [
+            bytes("B15D2CD3C3D22B04DAE438CE653F6B4ECF042F42CFDED7C41B64AAF9B4AF53FB"),
+            bytes("6193D6AC61B354E9105BBDC8937A3454A6D705B6D57322A5A472A02CE99FCB64"),
+            bytes("9A87D3B79EC67228CB97878B76049B15DBD05B8158D17B5B9114D3C226887505"),
+            bytes("66F82EA90923689B855D36C6B7E032FB9970301481B99E01CDB4D6AC7C347A15"),
+            bytes("4F5AEE41510848A6447DCD1BBC78457EF69024944C87F40250D3EF2C25D33EFE"),
+            bytes("DDEF427BBB847CC027BEFF4EDB01038148917832253EBC355FC33F4A8E2FCCE4"),
+            bytes("97B890A26C981DA8102D3BC294159D171D72810FDF7C6A691DEF02F0F7AF3FDC"),
+            bytes("53FA9E08BA5243CBCB0D797C5EE83BC6728E539EB76C2D0BF0F971EE4E909971"),
+        ];
+        let msg = bytes("599C67EA410D005B9DA90817CF03ED3B1C868E4DA4EDF00A5880B0082C237869");
+        let cases: [(&str, [usize; 2], &[(usize, bool)], [usize; 2], &str); 4] = [
+            (
+                "0341432722C5CD0268D829C702CF0D1CBCE57033EED201FD335191385227C3210C03D377F2D258B64AADC0E16F26462323D701D286046A2EA93365656AFD9875982B",
+                [0, 1],
+                &[],
+                [0, 1],
+                "041DA22223CE65C92C9A0D6C2CAC828AAF1EEE56304FEC371DDF91EBB2B9EF0912F1038025857FEDEB3FF696F8B99FA4BB2C5812F6095A2E0004EC99CE18DE1E",
+            ),
+            (
+                "0224AFD36C902084058B51B5D36676BBA4DC97C775873768E58822F87FE437D792028CB15929099EEE2F5DAE404CD39357591BA32E9AF4E162B8D3E7CB5EFE31CB20",
+                [0, 2],
+                &[],
+                [2, 3],
+                "1069B67EC3D2F3C7C08291ACCB17A9C9B8F2819A52EB5DF8726E17E7D6B52E9F01800260A7E9DAC450F4BE522DE4CE12BA91AEAF2B4279219EF74BE1D286ADD9",
+            ),
+            (
+                "0208C5C438C710F4F96A61E9FF3C37758814B8C3AE12BFEA0ED2C87FF6954FF186020B1816EA104B4FCA2D304D733E0E19CEAD51303FF6420BFD222335CAA402916D",
+                [0, 2],
+                &[(0, false)],
+                [4, 5],
+                "5C558E1DCADE86DA0B2F02626A512E30A22CF5255CAEA7EE32C38E9A71A0E9148BA6C0E6EC7683B64220F0298696F1B878CD47B107B81F7188812D593971E0CC",
+            ),
+            (
+                "02B5AD07AFCD99B6D92CB433FBD2A28FDEB98EAE2EB09B6014EF0F8197CD58403302E8616910F9293CF692C49F351DB86B25E352901F0E237BAFDA11F1C1CEF29FFD",
+                [0, 3],
+                &[(0, true), (1, false), (2, true)],
+                [6, 7],
+                "839B08820B681DBA8DAF4CC7B104E8F2638F9388F8D7A555DC17B6E6971D7426CE07BF6AB01F1DB50E4E33719295F4094572B79868E440FB3DEFD3FAC1DB589E",
+            ),
+        ];
+        for (aggnonce, indices, tweaked, partials, expected) in cases.iter() {
+            let mut ctx =
+                KeyAggContext::ordered(indices.iter().map(|i| keys[*i]).collect()).unwrap();
+            for (i, is_xonly) in tweaked.iter() {
+                if *is_xonly {
+                    ctx.tweak_xonly(&tweaks[*i]).unwrap();
+                } else {
+                    ctx.tweak_plain(&tweaks[*i]).unwrap();
+                }
+            }
+            let partials: Vec<_> = partials.iter().map(|i| psigs[*i]).collect();
+            let sig = aggregate_partial_sigs(&ctx, &nonce(aggnonce), &msg, &partials[..]).unwrap();
+            assert_eq!(sig[..], hex(expected)[..]);
+            assert!(verify(&ctx, &msg, &sig));
+        }
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        // two oracles sign for their aggregate, tweaked as a key path
+        let mut ctx = KeyAggContext::new(vec![key(1), key(2)]).unwrap();
+        ctx.tweak_taproot().unwrap();
+        let msg = [7u8; 32];
+        let (sec1, pub1) = SecNonce::generate();
+        let (sec2, pub2) = SecNonce::generate();
+        let aggnonce = aggregate_nonces(&[pub1, pub2]).unwrap();
+        let partials = [
+            partial_sign(sec1, &secret(1), &ctx, &aggnonce, &msg).unwrap(),
+            partial_sign(sec2, &secret(2), &ctx, &aggnonce, &msg).unwrap(),
+        ];
+        let sig = aggregate_partial_sigs(&ctx, &aggnonce, &msg, &partials[..]).unwrap();
+        assert!(verify(&ctx, &msg, &sig));
+        // but not for another message, or with one partial signature
+        assert!(!verify(&ctx, &[8u8; 32], &sig));
+        let sig = aggregate_partial_sigs(&ctx, &aggnonce, &msg, &partials[..1]).unwrap();
+        assert!(!verify(&ctx, &msg, &sig));
+        // nor may a third key take part
+        let (sec3, _) = SecNonce::generate();
+        assert!(partial_sign(sec3, &secret(3), &ctx, &aggnonce, &msg).is_err());
+    }
+
+    #[test]
+    fn aggregate_matches_compiler() {
+        // the internal key the compiler gives a key path shared by several
+        // keys is the one oracles signing together with MuSig2 aggregate to
+        let keys: Vec<_> = (1u8..4)
+            .map(|i| bitcoin::PublicKey {
+                compressed: true,
+                key: key(i),
+            })
+            .collect();
+        let musig = KeyAggContext::new(keys.iter().rev().map(|k| k.key).collect()).unwrap();
+        assert_eq!(
+            sapio_base::taproot::aggregate_keys(&keys).unwrap(),
+            musig.public_key()
+        );
+    }
+}
@@ -0,0 +1,374 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! An encrypted and authenticated channel between clients and oracles,
+//! without a CA.
+//!
+//! The handshake is `Noise_XK_secp256k1_ChaChaPoly_SHA256` (as in BOLT-8),
+//! with the oracle's static key being the public key of the root xpub the
+//! client already knows. Completing the handshake proves the server holds the
+//! root's secret key. The client's static key is only revealed to the oracle
+//! (encrypted) in the final message.
+//!
+//! An oracle with several roots learns which one the client expects by trying
+//! each of them against the first handshake message.
+//!
+//! Unlike the Noise specification, transport messages are not limited to
+//! 65535 bytes, they are bounded by the frame size negotiated in `protocol`.
+use super::*;
+use bitcoin::hashes::{sha256, Hmac, HmacEngine};
+use bitcoin::secp256k1::ecdh::SharedSecret;
+use bitcoin::secp256k1::{PublicKey, SecretKey};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+const PROTOCOL_NAME: &[u8] = b"Noise_XK_secp256k1_ChaChaPoly_SHA256";
+/// the size of an authentication tag
+pub const MAC_LEN: usize = 16;
+const ACT_ONE_LEN: usize = 33 + MAC_LEN;
+const ACT_TWO_LEN: usize = 33 + MAC_LEN;
+const ACT_THREE_LEN: usize = 33 + MAC_LEN + MAC_LEN;
+
+fn invalid() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, "Noise Decryption Failed")
+}
+
+fn hmac(key: &[u8], data: &[&[u8]]) -> [u8; 32] {
+    let mut engine = HmacEngine::<sha256::Hash>::new(key);
+    for d in data {
+        engine.input(d);
+    }
+    Hmac::from_engine(engine).into_inner()
+}
+
+/// HKDF with two outputs
+fn hkdf(ck: &[u8; 32], ikm: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let temp = hmac(&ck[..], &[ikm]);
+    let a = hmac(&temp[..], &[&[1]]);
+    let b = hmac(&temp[..], &[&a[..], &[2]]);
+    (a, b)
+}
+
+/// Diffie-Hellman as in BOLT-8, i.e. the sha256 of the compressed point
+pub fn ecdh(point: &PublicKey, secret: &SecretKey) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    out.copy_from_slice(&SharedSecret::new(point, secret)[..]);
+    out
+}
+
+fn random_key() -> SecretKey {
+    loop {
+        let bytes: [u8; 32] = rand::thread_rng().gen();
+        if let Ok(k) = SecretKey::from_slice(&bytes[..]) {
+            break k;
+        }
+    }
+}
+
+fn public(sk: &SecretKey) -> PublicKey {
+    SECP.with(|secp| PublicKey::from_secret_key(secp, sk))
+}
+
+/// A key and nonce, for one direction of the channel
+struct CipherState {
+    k: [u8; 32],
+    n: u64,
+}
+
+impl CipherState {
+    fn nonce(&self) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.n.to_le_bytes());
+        nonce
+    }
+    fn encrypt(&mut self, ad: &[u8], msg: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&self.k[..]));
+        let c = cipher
+            .encrypt(Nonce::from_slice(&self.nonce()[..]), Payload { msg, aad: ad })
+            .map_err(|_| invalid())?;
+        self.n += 1;
+        Ok(c)
+    }
+    fn decrypt(&mut self, ad: &[u8], msg: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&self.k[..]));
+        let p = cipher
+            .decrypt(Nonce::from_slice(&self.nonce()[..]), Payload { msg, aad: ad })
+            .map_err(|_| invalid())?;
+        self.n += 1;
+        Ok(p)
+    }
+}
+
+/// The handshake hash, chaining key, and cipher
+struct SymmetricState {
+    ck: [u8; 32],
+    h: [u8; 32],
+    k: Option<CipherState>,
+}
+
+impl SymmetricState {
+    /// initializes the state for a handshake with `responder`'s static key
+    fn new(prologue: &[u8], responder: &PublicKey) -> Self {
+        let h = sha256::Hash::hash(PROTOCOL_NAME).into_inner();
+        let mut s = SymmetricState { ck: h, h, k: None };
+        s.mix_hash(prologue);
+        s.mix_hash(&responder.serialize()[..]);
+        s
+    }
+    fn mix_hash(&mut self, data: &[u8]) {
+        let mut engine = sha256::Hash::engine();
+        engine.input(&self.h[..]);
+        engine.input(data);
+        self.h = sha256::Hash::from_engine(engine).into_inner();
+    }
+    fn mix_key(&mut self, ikm: &[u8; 32]) {
+        let (ck, k) = hkdf(&self.ck, &ikm[..]);
+        self.ck = ck;
+        self.k = Some(CipherState { k, n: 0 });
+    }
+    fn encrypt_and_hash(&mut self, msg: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        let h = self.h;
+        let c = self.k.as_mut().ok_or_else(invalid)?.encrypt(&h[..], msg)?;
+        self.mix_hash(&c[..]);
+        Ok(c)
+    }
+    fn decrypt_and_hash(&mut self, c: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        let h = self.h;
+        let p = self.k.as_mut().ok_or_else(invalid)?.decrypt(&h[..], c)?;
+        self.mix_hash(c);
+        Ok(p)
+    }
+    /// the initiator's and responder's sending keys
+    fn split(&self) -> (CipherState, CipherState) {
+        let (a, b) = hkdf(&self.ck, &[]);
+        (CipherState { k: a, n: 0 }, CipherState { k: b, n: 0 })
+    }
+}
+
+/// An established channel
+pub struct Transport {
+    send: CipherState,
+    recv: CipherState,
+    /// the static key of the other side
+    pub remote: PublicKey,
+}
+
+impl std::fmt::Debug for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Transport")
+            .field("remote", &self.remote)
+            .finish()
+    }
+}
+
+impl Transport {
+    /// encrypts a message
+    pub fn encrypt(&mut self, msg: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        self.send.encrypt(&[], msg)
+    }
+    /// decrypts a message, failing if it was tampered with or reordered
+    pub fn decrypt(&mut self, msg: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        self.recv.decrypt(&[], msg)
+    }
+}
+
+/// Runs the handshake as the client, with `local` as our static key and
+/// `remote` as the oracle's.
+pub async fn initiate<S>(
+    s: &mut S,
+    prologue: &[u8],
+    local: &SecretKey,
+    remote: &PublicKey,
+) -> Result<Transport, std::io::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut state = SymmetricState::new(prologue, remote);
+    // -> e, es
+    let e = random_key();
+    let e_pub = public(&e).serialize();
+    state.mix_hash(&e_pub[..]);
+    state.mix_key(&ecdh(remote, &e));
+    let mut act = e_pub.to_vec();
+    act.extend(state.encrypt_and_hash(&[])?);
+    s.write_all(&act[..]).await?;
+    s.flush().await?;
+    // <- e, ee
+    let mut act = [0u8; ACT_TWO_LEN];
+    s.read_exact(&mut act[..]).await?;
+    let re = PublicKey::from_slice(&act[..33]).map_err(|_| invalid())?;
+    state.mix_hash(&act[..33]);
+    state.mix_key(&ecdh(&re, &e));
+    state.decrypt_and_hash(&act[33..])?;
+    // -> s, se
+    let mut act = state.encrypt_and_hash(&public(local).serialize()[..])?;
+    state.mix_key(&ecdh(&re, local));
+    act.extend(state.encrypt_and_hash(&[])?);
+    s.write_all(&act[..]).await?;
+    s.flush().await?;
+    let (send, recv) = state.split();
+    Ok(Transport {
+        send,
+        recv,
+        remote: *remote,
+    })
+}
+
+/// Runs the handshake as the oracle, with any of `keys` as our static key.
+/// `dh(i, point)` must compute the Diffie-Hellman of `point` with the secret
+/// of `keys[i]` (see `ecdh`).
+///
+/// Returns the channel and the index of the key the client expected.
+pub async fn respond<S, F>(
+    s: &mut S,
+    prologue: &[u8],
+    keys: &[PublicKey],
+    dh: F,
+) -> Result<(Transport, usize), std::io::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: Fn(usize, &PublicKey) -> Result<[u8; 32], std::io::Error>,
+{
+    // <- e, es
+    let mut act = [0u8; ACT_ONE_LEN];
+    s.read_exact(&mut act[..]).await?;
+    let re = PublicKey::from_slice(&act[..33]).map_err(|_| invalid())?;
+    let mut found = None;
+    for (i, key) in keys.iter().enumerate() {
+        let mut state = SymmetricState::new(prologue, key);
+        state.mix_hash(&act[..33]);
+        state.mix_key(&dh(i, &re)?);
+        if state.decrypt_and_hash(&act[33..]).is_ok() {
+            found = Some((i, state));
+            break;
+        }
+    }
+    let (i, mut state) = match found {
+        Some(found) => found,
+        None => return input_error("Noise Handshake Not For Any Of Our Keys"),
+    };
+    // -> e, ee
+    let e = random_key();
+    let e_pub = public(&e).serialize();
+    state.mix_hash(&e_pub[..]);
+    state.mix_key(&ecdh(&re, &e));
+    let mut reply = e_pub.to_vec();
+    reply.extend(state.encrypt_and_hash(&[])?);
+    s.write_all(&reply[..]).await?;
+    s.flush().await?;
+    // <- s, se
+    let mut act = [0u8; ACT_THREE_LEN];
+    s.read_exact(&mut act[..]).await?;
+    let rs = state.decrypt_and_hash(&act[..33 + MAC_LEN])?;
+    let rs = PublicKey::from_slice(&rs[..]).map_err(|_| invalid())?;
+    state.mix_key(&ecdh(&rs, &e));
+    state.decrypt_and_hash(&act[33 + MAC_LEN..])?;
+    let (recv, send) = state.split();
+    Ok((
+        Transport {
+            send,
+            recv,
+            remote: rs,
+        },
+        i,
+    ))
+}
+
+/// a fresh static key for a client which does not need to be recognized
+pub fn ephemeral_key() -> SecretKey {
+    random_key()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secret(i: u8) -> SecretKey {
+        SecretKey::from_slice(&[i; 32]).unwrap()
+    }
+
+    /// Runs the handshake over a pipe, the client expecting `remote` and the
+    /// oracle holding `keys`, for which `secrets` compute the DH
+    fn handshake(
+        remote: PublicKey,
+        keys: Vec<PublicKey>,
+        secrets: Vec<SecretKey>,
+    ) -> (
+        Result<Transport, std::io::Error>,
+        Result<(Transport, usize), std::io::Error>,
+    ) {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let (mut a, mut b) = tokio::io::duplex(4096);
+            let client = async move { initiate(&mut a, b"test", &secret(1), &remote).await };
+            // the oracle's end is dropped as soon as it fails, so the client
+            // isn't left waiting
+            let server = async move {
+                respond(&mut b, b"test", &keys[..], |i, point| {
+                    Ok(ecdh(point, &secrets[i]))
+                })
+                .await
+            };
+            tokio::join!(client, server)
+        })
+    }
+
+    #[test]
+    fn round_trip() {
+        let keys = vec![public(&secret(2)), public(&secret(3))];
+        let (client, server) = handshake(keys[1], keys, vec![secret(2), secret(3)]);
+        let mut client = client.unwrap();
+        let (mut server, i) = server.unwrap();
+        // the oracle learns which of its keys the client expected, and the
+        // client's static key
+        assert_eq!(i, 1);
+        assert_eq!(client.remote, public(&secret(3)));
+        assert_eq!(server.remote, public(&secret(1)));
+        for msg in [&b"hello"[..], &[], &[7u8; 100_000][..]].iter() {
+            let c = client.encrypt(msg).unwrap();
+            assert_eq!(c.len(), msg.len() + MAC_LEN);
+            assert_eq!(&server.decrypt(&c[..]).unwrap()[..], *msg);
+            let c = server.encrypt(msg).unwrap();
+            assert_eq!(&client.decrypt(&c[..]).unwrap()[..], *msg);
+        }
+    }
+
+    #[test]
+    fn wrong_static_key() {
+        // a client expecting another oracle
+        let keys = vec![public(&secret(2))];
+        let (client, server) = handshake(public(&secret(4)), keys.clone(), vec![secret(2)]);
+        assert!(client.is_err());
+        assert!(server.is_err());
+        // an oracle claiming a key it doesn't hold
+        let (client, server) = handshake(keys[0], keys, vec![secret(5)]);
+        assert!(client.is_err());
+        assert!(server.is_err());
+    }
+
+    #[test]
+    fn tampered_ciphertext() {
+        let keys = vec![public(&secret(2))];
+        let (client, server) = handshake(keys[0], keys, vec![secret(2)]);
+        let mut client = client.unwrap();
+        let (mut server, _) = server.unwrap();
+        let c = client.encrypt(b"sign this").unwrap();
+        for i in 0..c.len() {
+            let mut tampered = c.clone();
+            tampered[i] ^= 1;
+            assert!(server.decrypt(&tampered[..]).is_err());
+        }
+        assert!(server.decrypt(&c[..c.len() - 1]).is_err());
+        // failures don't advance the nonce, so the message still decrypts,
+        // but only once
+        assert_eq!(&server.decrypt(&c[..]).unwrap()[..], b"sign this");
+        assert!(server.decrypt(&c[..]).is_err());
+        // and messages can't be reflected back to their sender
+        let c = client.encrypt(b"reflected").unwrap();
+        assert!(client.decrypt(&c[..]).is_err());
+    }
+}
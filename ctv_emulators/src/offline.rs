@@ -0,0 +1,228 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Signing with an air-gapped oracle, by carrying files to and from it.
+//!
+//! A client exports the PSBTs it needs signed as a `RequestBundle`, which is
+//! carried to an oracle that is never online (e.g. `emulator_server
+//! --offline`). The oracle signs each PSBT its policy permits (see
+//! `HDOracleEmulator::sign_bundle`) and writes a `ResponseBundle`, which is
+//! carried back and merged into the client's PSBTs with
+//! `ResponseBundle::merge`.
+//!
+//! Bundles are JSON. Where only a camera crosses the air gap, a bundle may
+//! instead be split into chunks (see `to_chunks`) of the form
+//! `CTVE:<index>/<count>:<digest>:<data>`, where the data is a part of the
+//! bundle in uppercase hex and the digest is the first 4 bytes of the
+//! bundle's sha256. Each chunk is encoded by a QR code in alphanumeric mode,
+//! and chunks may be scanned in any order. Readers accept either form.
+use super::*;
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The version of the bundle format
+pub const VERSION: u8 = 1;
+/// Prefixes every chunk
+const CHUNK_PREFIX: &str = "CTVE:";
+/// The default length of the data in a chunk, which (with its header) fits a
+/// version 20 QR code with medium error correction.
+pub const CHUNK_SIZE: usize = 800;
+
+/// PSBTs exported for an offline oracle to sign
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RequestBundle {
+    pub version: u8,
+    /// the root the client expects the oracle to sign with
+    pub root: ExtendedPubKey,
+    pub psbts: Vec<msgs::PSBT>,
+}
+
+impl RequestBundle {
+    /// a bundle asking the oracle with root `root` to sign `psbts`
+    pub fn new(root: ExtendedPubKey, psbts: Vec<PartiallySignedTransaction>) -> Self {
+        RequestBundle {
+            version: VERSION,
+            root,
+            psbts: psbts.into_iter().map(msgs::PSBT).collect(),
+        }
+    }
+    /// the bundle as written to a file
+    pub fn to_vec(&self) -> Result<Vec<u8>, std::io::Error> {
+        Ok(serde_json::to_vec_pretty(self)?)
+    }
+    /// reads a bundle written by `to_vec`, or its chunks
+    pub fn from_slice(contents: &[u8]) -> Result<Self, std::io::Error> {
+        let bundle: Self = decode(contents)?;
+        if bundle.version != VERSION {
+            return input_error("Unknown Bundle Version");
+        }
+        Ok(bundle)
+    }
+}
+
+/// The oracle's answer to one PSBT of a `RequestBundle`
+#[derive(Serialize, Deserialize, Clone)]
+pub enum Outcome {
+    /// the PSBT with the oracle's signatures
+    Signed(msgs::PSBT),
+    /// why the oracle would not sign it, e.g. a policy violation
+    Failed(msgs::ServerError),
+}
+
+/// An offline oracle's answers to a `RequestBundle`, in the same order
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ResponseBundle {
+    pub version: u8,
+    /// the root the oracle signed with
+    pub root: ExtendedPubKey,
+    pub outcomes: Vec<Outcome>,
+}
+
+impl ResponseBundle {
+    /// the oracle with root `root`'s answers
+    pub fn new(root: ExtendedPubKey, outcomes: Vec<Outcome>) -> Self {
+        ResponseBundle {
+            version: VERSION,
+            root,
+            outcomes,
+        }
+    }
+    /// the bundle as written to a file
+    pub fn to_vec(&self) -> Result<Vec<u8>, std::io::Error> {
+        Ok(serde_json::to_vec_pretty(self)?)
+    }
+    /// reads a bundle written by `to_vec`, or its chunks
+    pub fn from_slice(contents: &[u8]) -> Result<Self, std::io::Error> {
+        let bundle: Self = decode(contents)?;
+        if bundle.version != VERSION {
+            return input_error("Unknown Bundle Version");
+        }
+        Ok(bundle)
+    }
+    /// Merges the oracle's signatures into `psbts`, the PSBTs of the request
+    /// this answers (in the same order), returning each merged PSBT or the
+    /// error the oracle would not sign it for.
+    ///
+    /// Fails if the response is not from the oracle with root `root`, or
+    /// answers a different request.
+    pub fn merge(
+        self,
+        root: &ExtendedPubKey,
+        psbts: Vec<PartiallySignedTransaction>,
+    ) -> Result<Vec<Result<PartiallySignedTransaction, msgs::ServerError>>, std::io::Error> {
+        if self.root != *root {
+            return input_error("Response Is From a Different Oracle");
+        }
+        if self.outcomes.len() != psbts.len() {
+            return input_error("Response Is For a Different Request");
+        }
+        psbts
+            .into_iter()
+            .zip(self.outcomes)
+            .map(|(mut psbt, outcome)| match outcome {
+                Outcome::Signed(msgs::PSBT(signed)) => {
+                    if signed.global.unsigned_tx.txid() != psbt.global.unsigned_tx.txid() {
+                        return input_error("Response Is For a Different Request");
+                    }
+                    psbt.merge(signed)
+                        .or_else(|_| input_error("Could Not Merge Signed PSBT"))?;
+                    Ok(Ok(psbt))
+                }
+                Outcome::Failed(e) => Ok(Err(e)),
+            })
+            .collect()
+    }
+}
+
+/// a bundle written as JSON, or as chunks of it
+fn decode<T: DeserializeOwned>(contents: &[u8]) -> Result<T, std::io::Error> {
+    let text = std::str::from_utf8(contents).ok().map(str::trim_start);
+    match text {
+        Some(text) if text.starts_with(CHUNK_PREFIX) => {
+            Ok(serde_json::from_slice(&from_chunks(text.lines())?[..])?)
+        }
+        _ => Ok(serde_json::from_slice(contents)?),
+    }
+}
+
+/// the digest of `contents` which chunks are tagged with
+fn digest(contents: &[u8]) -> String {
+    Sha256::hash(contents)[..4].to_hex().to_uppercase()
+}
+
+/// Splits `contents` (e.g. a bundle, see `RequestBundle::to_vec`) into
+/// chunks with at most `size` characters of data each, to be shown as QR
+/// codes.
+pub fn to_chunks(contents: &[u8], size: usize) -> Vec<String> {
+    let digest = digest(contents);
+    let data = contents.to_hex().to_uppercase();
+    let parts: Vec<&[u8]> = data.as_bytes().chunks(size.max(1)).collect();
+    parts
+        .iter()
+        .enumerate()
+        .map(|(i, part)| {
+            // hex is ascii, so a part is valid utf8
+            let part = std::str::from_utf8(part).unwrap();
+            format!(
+                "{}{}/{}:{}:{}",
+                CHUNK_PREFIX,
+                i + 1,
+                parts.len(),
+                digest,
+                part
+            )
+        })
+        .collect()
+}
+
+/// Reassembles the contents split by `to_chunks`, from its chunks in any
+/// order (duplicates are ignored, as are blank lines).
+///
+/// Fails if a chunk is missing, or is from a different bundle.
+pub fn from_chunks<'a, I>(chunks: I) -> Result<Vec<u8>, std::io::Error>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut parts = BTreeMap::new();
+    let mut expected: Option<(usize, String)> = None;
+    for chunk in chunks.into_iter().map(str::trim).filter(|c| !c.is_empty()) {
+        let mut fields = match chunk.strip_prefix(CHUNK_PREFIX) {
+            Some(rest) => rest.splitn(3, ':'),
+            None => return input_error("Malformed Chunk"),
+        };
+        let (position, digest, data) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(position), Some(digest), Some(data)) => (position, digest, data),
+            _ => return input_error("Malformed Chunk"),
+        };
+        let mut position = position.splitn(2, '/').map(str::parse::<usize>);
+        let (index, count) = match (position.next(), position.next()) {
+            (Some(Ok(index)), Some(Ok(count))) if index >= 1 && index <= count => (index, count),
+            _ => return input_error("Malformed Chunk"),
+        };
+        match &expected {
+            Some((n, d)) if *n != count || !d.eq_ignore_ascii_case(digest) => {
+                return input_error("Chunks Are From Different Bundles")
+            }
+            Some(_) => {}
+            None => expected = Some((count, digest.to_uppercase())),
+        }
+        parts.insert(index, data.to_string());
+    }
+    let (count, digest_of) = match expected {
+        Some(expected) => expected,
+        None => return input_error("No Chunks Given"),
+    };
+    if parts.len() != count {
+        return input_error("Missing Chunks");
+    }
+    let data: String = parts.values().map(String::as_str).collect();
+    let contents = Vec::<u8>::from_hex(&data).or_else(|_| input_error("Malformed Chunk"))?;
+    if digest(&contents[..]) != digest_of {
+        return input_error("Chunks Do Not Match Their Digest");
+    }
+    Ok(contents)
+}
@@ -0,0 +1,494 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The versioned wire protocol spoken between oracle clients and servers.
+//!
+//! On connecting, a client sends a `Hello`:
+//!
+//! `magic:[u8;4] version:u8 capabilities:u32 max_message:u32`
+//!
+//! and the server replies with a `Hello` containing the negotiated values
+//! (the lower version and message size, and the common capabilities).
+//! Afterwards, every message is framed as `length:u32 data:[u8;length]`, and
+//! frames larger than the negotiated maximum are rejected.
+//!
+//! Clients which predate the handshake start directly with a frame. Servers
+//! detect this because the first 4 bytes are not the magic, and speak
+//! version 0 (no capabilities, `DEFAULT_MAX_MESSAGE`) with them.
+//!
+//! If `Capabilities::NOISE` is negotiated, a Noise handshake (see `noise`)
+//! follows the `Hello`s, with both of them as the prologue so that neither
+//! can be tampered with. Every frame is then sent as
+//! `encrypted(length:u32) encrypted(data)`.
+//!
+//! If `Capabilities::PIPELINE` is negotiated, every request and response is
+//! a `msgs::Correlated`, tagged with an id chosen by the client, so that a
+//! client may send several requests before reading their responses. Errors
+//! sent before any request (e.g. `ServerError::TooManyConnections`) have id 0.
+//!
+//! If `Capabilities::DEADLINE` is negotiated, every request is a
+//! `msgs::Timed` (inside the `msgs::Correlated`, if pipelined), carrying how
+//! long the client will wait for the response. The server abandons a request
+//! it can't finish in time, responding with `ServerError::DeadlineExceeded`.
+use bitcoin::secp256k1::{PublicKey, SecretKey};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Bytes identifying a handshake
+pub const MAGIC: [u8; 4] = *b"CTVE";
+/// The most recent protocol version this library speaks
+pub const PROTOCOL_VERSION: u8 = 1;
+/// The largest frame we accept unless a smaller one is negotiated. Large
+/// enough for a maximum size PSBT after JSON encoding.
+pub const DEFAULT_MAX_MESSAGE: u32 = 8_000_000;
+
+/// Optional features a peer supports, as a bit set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    /// No optional features
+    pub const NONE: Capabilities = Capabilities(0);
+    /// The server signs taproot inputs
+    pub const TAPROOT: Capabilities = Capabilities(1 << 0);
+    /// Messages are encoded with CBOR instead of JSON
+    pub const CBOR: Capabilities = Capabilities(1 << 1);
+    /// The server accepts `Request::SignBatch`
+    pub const BATCH: Capabilities = Capabilities(1 << 2);
+    /// The server participates in MuSig2 signing, see `musig`
+    pub const MUSIG: Capabilities = Capabilities(1 << 3);
+    /// The connection is encrypted and authenticated, see `noise`
+    pub const NOISE: Capabilities = Capabilities(1 << 4);
+    /// Messages are tagged with a request id, so that a client may send
+    /// requests without waiting for responses (see `msgs::Correlated`)
+    pub const PIPELINE: Capabilities = Capabilities(1 << 5);
+    /// Requests carry how long the client will wait for them (see
+    /// `msgs::Timed`), so that the server can abandon stale work
+    pub const DEADLINE: Capabilities = Capabilities(1 << 6);
+
+    /// all capabilities this library supports
+    pub fn supported() -> Capabilities {
+        Capabilities::TAPROOT
+            | Capabilities::CBOR
+            | Capabilities::BATCH
+            | Capabilities::MUSIG
+            | Capabilities::NOISE
+            | Capabilities::PIPELINE
+            | Capabilities::DEADLINE
+    }
+    /// the capabilities common to both
+    pub fn intersect(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+    /// these capabilities, without any of `other`'s
+    pub fn remove(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & !other.0)
+    }
+    /// if all of `other`'s capabilities are present
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for Capabilities {
+    type Output = Capabilities;
+    fn bitor(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+}
+
+/// The handshake message, sent by both sides.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hello {
+    /// the protocol version
+    pub version: u8,
+    /// optional features
+    pub capabilities: Capabilities,
+    /// the largest frame the sender will accept
+    pub max_message: u32,
+}
+
+impl Hello {
+    /// The Hello for this library's defaults
+    pub fn ours() -> Hello {
+        Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::supported(),
+            max_message: DEFAULT_MAX_MESSAGE,
+        }
+    }
+    /// compute the parameters both sides agree on
+    pub fn negotiate(&self, other: &Hello) -> Hello {
+        Hello {
+            version: std::cmp::min(self.version, other.version),
+            capabilities: self.capabilities.intersect(other.capabilities),
+            max_message: std::cmp::min(self.max_message, other.max_message),
+        }
+    }
+    /// the Hello as it is sent, including the magic
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut v = MAGIC.to_vec();
+        v.push(self.version);
+        v.extend_from_slice(&self.capabilities.0.to_be_bytes());
+        v.extend_from_slice(&self.max_message.to_be_bytes());
+        v
+    }
+    /// write the Hello, including the magic
+    pub async fn write<S: AsyncWrite + Unpin>(&self, s: &mut S) -> Result<(), std::io::Error> {
+        s.write_all(&self.to_bytes()[..]).await?;
+        s.flush().await
+    }
+    /// read a Hello, after the magic has already been read
+    async fn read_body<S: AsyncRead + Unpin>(s: &mut S) -> Result<Hello, std::io::Error> {
+        Ok(Hello {
+            version: s.read_u8().await?,
+            capabilities: Capabilities(s.read_u32().await?),
+            max_message: s.read_u32().await?,
+        })
+    }
+}
+
+/// Negotiated state for a single connection.
+#[derive(Debug)]
+pub struct Session {
+    /// the agreed parameters
+    pub params: Hello,
+    /// the length of the first frame from a legacy client, which was read
+    /// while looking for the magic
+    pending_len: Option<u32>,
+    /// the client's and then the server's Hello, the Noise prologue
+    hellos: Vec<u8>,
+    /// the encrypted channel, once the Noise handshake is complete
+    noise: Option<crate::noise::Transport>,
+}
+
+fn invalid_data(s: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, s)
+}
+
+impl Session {
+    /// the codec messages in this session are encoded with
+    pub fn codec(&self) -> crate::msgs::Codec {
+        if self.params.capabilities.contains(Capabilities::CBOR) {
+            crate::msgs::Codec::Cbor
+        } else {
+            crate::msgs::Codec::Json
+        }
+    }
+
+    /// Server side of the handshake.
+    pub async fn accept<S>(s: &mut S, ours: Hello) -> Result<Session, std::io::Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut magic = [0u8; 4];
+        s.read_exact(&mut magic[..]).await?;
+        if magic != MAGIC {
+            return Ok(Session {
+                params: Hello {
+                    version: 0,
+                    capabilities: Capabilities::NONE,
+                    max_message: ours.max_message,
+                },
+                pending_len: Some(u32::from_be_bytes(magic)),
+                hellos: vec![],
+                noise: None,
+            });
+        }
+        let theirs = Hello::read_body(s).await?;
+        let params = ours.negotiate(&theirs);
+        params.write(s).await?;
+        Ok(Session {
+            params,
+            pending_len: None,
+            hellos: [theirs.to_bytes(), params.to_bytes()].concat(),
+            noise: None,
+        })
+    }
+
+    /// Client side of the handshake.
+    pub async fn connect<S>(s: &mut S, ours: Hello) -> Result<Session, std::io::Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        ours.write(s).await?;
+        let mut magic = [0u8; 4];
+        s.read_exact(&mut magic[..]).await?;
+        if magic != MAGIC {
+            return Err(invalid_data("Server Did Not Complete Handshake"));
+        }
+        let params = Hello::read_body(s).await?;
+        if params.version > ours.version
+            || params.max_message > ours.max_message
+            || !ours.capabilities.contains(params.capabilities)
+        {
+            return Err(invalid_data("Server Negotiated Unsupported Parameters"));
+        }
+        Ok(Session {
+            params,
+            pending_len: None,
+            hellos: [ours.to_bytes(), params.to_bytes()].concat(),
+            noise: None,
+        })
+    }
+
+    /// if a Noise handshake is expected, i.e. `Capabilities::NOISE` was
+    /// negotiated.
+    pub fn wants_noise(&self) -> bool {
+        self.params.capabilities.contains(Capabilities::NOISE)
+    }
+
+    /// if messages are tagged with request ids, i.e.
+    /// `Capabilities::PIPELINE` was negotiated.
+    pub fn pipelined(&self) -> bool {
+        self.params.capabilities.contains(Capabilities::PIPELINE)
+    }
+
+    /// if requests carry deadlines, i.e. `Capabilities::DEADLINE` was
+    /// negotiated.
+    pub fn timed(&self) -> bool {
+        self.params.capabilities.contains(Capabilities::DEADLINE)
+    }
+
+    /// the static key of the other side, if the session is encrypted
+    pub fn remote_key(&self) -> Option<PublicKey> {
+        self.noise.as_ref().map(|t| t.remote)
+    }
+
+    /// Client side of the Noise handshake, expecting the server to hold
+    /// `remote`, and identifying ourselves with `local`.
+    pub async fn initiate_noise<S>(
+        &mut self,
+        s: &mut S,
+        local: &SecretKey,
+        remote: &PublicKey,
+    ) -> Result<(), std::io::Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.noise = Some(crate::noise::initiate(s, &self.hellos[..], local, remote).await?);
+        Ok(())
+    }
+
+    /// Server side of the Noise handshake, see `noise::respond`. Returns the
+    /// index of the key the client expected.
+    pub async fn respond_noise<S, F>(
+        &mut self,
+        s: &mut S,
+        keys: &[PublicKey],
+        dh: F,
+    ) -> Result<usize, std::io::Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+        F: Fn(usize, &PublicKey) -> Result<[u8; 32], std::io::Error>,
+    {
+        let (transport, i) = crate::noise::respond(s, &self.hellos[..], keys, dh).await?;
+        self.noise = Some(transport);
+        Ok(i)
+    }
+
+    /// read a frame, rejecting any larger than the negotiated maximum
+    pub async fn read_frame<S: AsyncRead + Unpin>(
+        &mut self,
+        s: &mut S,
+    ) -> Result<Vec<u8>, std::io::Error> {
+        if let Some(noise) = self.noise.as_mut() {
+            let mut l = [0u8; 4 + crate::noise::MAC_LEN];
+            s.read_exact(&mut l[..]).await?;
+            let l = noise.decrypt(&l[..])?;
+            let l = u32::from_be_bytes([l[0], l[1], l[2], l[3]]);
+            if l > self.params.max_message {
+                return Err(invalid_data("Message Too Large"));
+            }
+            let mut v = vec![0u8; l as usize + crate::noise::MAC_LEN];
+            s.read_exact(&mut v[..]).await?;
+            return noise.decrypt(&v[..]);
+        }
+        let l = match self.pending_len.take() {
+            Some(l) => l,
+            None => s.read_u32().await?,
+        };
+        if l > self.params.max_message {
+            return Err(invalid_data("Message Too Large"));
+        }
+        let mut v = vec![0u8; l as usize];
+        s.read_exact(&mut v[..]).await?;
+        Ok(v)
+    }
+
+    /// write a frame, refusing any larger than the negotiated maximum
+    pub async fn write_frame<S: AsyncWrite + Unpin>(
+        &mut self,
+        s: &mut S,
+        data: &[u8],
+    ) -> Result<(), std::io::Error> {
+        if data.len() > self.params.max_message as usize {
+            return Err(invalid_data("Message Too Large"));
+        }
+        let l = (data.len() as u32).to_be_bytes();
+        match self.noise.as_mut() {
+            Some(noise) => {
+                s.write_all(&noise.encrypt(&l[..])?[..]).await?;
+                s.write_all(&noise.encrypt(data)?[..]).await?;
+            }
+            None => {
+                s.write_all(&l[..]).await?;
+                s.write_all(data).await?;
+            }
+        }
+        s.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msgs::{AuthToken, Codec, Correlated, Request, Timed, PSBT};
+    use bitcoin::secp256k1::Secp256k1;
+    use bitcoin::util::psbt::PartiallySignedTransaction;
+    use tokio::io::DuplexStream;
+
+    fn rt() -> tokio::runtime::Runtime {
+        tokio::runtime::Runtime::new().unwrap()
+    }
+
+    /// Runs the handshake over a pipe, returning the client's session and end
+    /// of it, then the server's
+    async fn handshake(
+        client: Hello,
+        server: Hello,
+    ) -> ((Session, DuplexStream), (Session, DuplexStream)) {
+        let (mut a, mut b) = tokio::io::duplex(4096);
+        let (c, s) = tokio::join!(
+            Session::connect(&mut a, client),
+            Session::accept(&mut b, server)
+        );
+        ((c.unwrap(), a), (s.unwrap(), b))
+    }
+
+    #[test]
+    fn negotiate() {
+        rt().block_on(async {
+            let server = Hello {
+                version: 0,
+                capabilities: Capabilities::CBOR | Capabilities::PIPELINE,
+                max_message: 1000,
+            };
+            let ((client, _), (server, _)) = handshake(Hello::ours(), server).await;
+            assert_eq!(client.params, server.params);
+            assert_eq!(
+                client.params,
+                Hello {
+                    version: 0,
+                    capabilities: Capabilities::CBOR | Capabilities::PIPELINE,
+                    max_message: 1000,
+                }
+            );
+            assert_eq!(client.codec(), Codec::Cbor);
+            assert!(client.pipelined());
+            assert!(!client.timed());
+            assert!(!client.wants_noise());
+        })
+    }
+
+    #[test]
+    fn frames() {
+        rt().block_on(async {
+            let small = Hello {
+                max_message: 16,
+                ..Hello::ours()
+            };
+            let ((mut client, mut a), (mut server, mut b)) = handshake(Hello::ours(), small).await;
+            client.write_frame(&mut a, b"hello").await.unwrap();
+            assert_eq!(server.read_frame(&mut b).await.unwrap(), b"hello");
+            server.write_frame(&mut b, b"").await.unwrap();
+            assert_eq!(client.read_frame(&mut a).await.unwrap(), b"");
+            // frames over the negotiated maximum are refused by both sides
+            assert!(client.write_frame(&mut a, &[0; 17]).await.is_err());
+            a.write_all(&17u32.to_be_bytes()).await.unwrap();
+            let e = server.read_frame(&mut b).await.unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        })
+    }
+
+    #[test]
+    fn legacy_client() {
+        rt().block_on(async {
+            let (mut a, mut b) = tokio::io::duplex(4096);
+            a.write_all(&5u32.to_be_bytes()).await.unwrap();
+            a.write_all(b"hello").await.unwrap();
+            let mut server = Session::accept(&mut b, Hello::ours()).await.unwrap();
+            assert_eq!(server.params.version, 0);
+            assert_eq!(server.params.capabilities, Capabilities::NONE);
+            assert_eq!(server.codec(), Codec::Json);
+            assert_eq!(server.read_frame(&mut b).await.unwrap(), b"hello");
+        })
+    }
+
+    #[test]
+    fn bad_server_hello() {
+        rt().block_on(async {
+            // a server which doesn't speak the handshake
+            let (mut a, mut b) = tokio::io::duplex(4096);
+            b.write_all(&[0; 13]).await.unwrap();
+            assert!(Session::connect(&mut a, Hello::ours()).await.is_err());
+            // or negotiates more than the client offered
+            let (mut a, mut b) = tokio::io::duplex(4096);
+            let larger = Hello {
+                max_message: DEFAULT_MAX_MESSAGE + 1,
+                ..Hello::ours()
+            };
+            larger.write(&mut b).await.unwrap();
+            assert!(Session::connect(&mut a, Hello::ours()).await.is_err());
+        })
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let secp = Secp256k1::new();
+        let issuer = SecretKey::from_slice(&[1; 32]).unwrap();
+        let subject = PublicKey::from_secret_key(&secp, &SecretKey::from_slice(&[2; 32]).unwrap());
+        let tokens = [
+            AuthToken::issue(&issuer, 1000, None),
+            AuthToken::issue(&issuer, 1000, Some(subject)),
+        ];
+        let tx = bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![bitcoin::TxIn {
+                previous_output: Default::default(),
+                script_sig: Default::default(),
+                sequence: 0xffff_ffff,
+                witness: vec![],
+            }],
+            output: vec![bitcoin::TxOut {
+                value: 1000,
+                script_pubkey: Default::default(),
+            }],
+        };
+        let psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        for token in tokens.iter() {
+            assert_eq!(&token.to_string().parse::<AuthToken>().unwrap(), token);
+        }
+        for codec in [Codec::Json, Codec::Cbor].iter() {
+            for token in tokens.iter() {
+                let request = Correlated(7, Timed(Some(100), Request::Authenticate(token.clone())));
+                let bytes = codec.encode(&request).unwrap();
+                let Correlated(id, Timed(deadline, decoded)): Correlated<Timed<Request>> =
+                    codec.decode(&bytes[..]).unwrap();
+                assert_eq!((id, deadline), (7, Some(100)));
+                assert!(matches!(decoded, Request::Authenticate(t) if &t == token));
+            }
+            let bytes = codec
+                .encode(&Request::SignPSBT(PSBT(psbt.clone())))
+                .unwrap();
+            match codec.decode(&bytes[..]).unwrap() {
+                Request::SignPSBT(decoded) => assert_eq!(decoded.0, psbt),
+                _ => panic!("decoded another request"),
+            }
+            assert!(codec.decode::<Request>(&bytes[1..]).is_err());
+        }
+    }
+}
@@ -0,0 +1,204 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! An append-only, hash-chained log of every operation an oracle performs.
+//!
+//! Each entry commits to the hash of the entry before it, so an exported log
+//! can be checked with `AuditLog::verify` to prove no entry was removed or
+//! altered after the fact (given a trusted copy of the latest hash).
+use super::*;
+use bitcoin::Txid;
+use serde_derive::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// An input the oracle signed (or would have signed) for
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditedInput {
+    /// the input index
+    pub input: usize,
+    /// the derivation path from the oracle's root
+    pub path: Vec<u32>,
+    /// the derived key
+    pub key: bitcoin::PublicKey,
+}
+
+/// The result of a request
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Outcome {
+    /// A signature was released
+    Signed,
+    /// The oracle's signatures were found valid, and attested to
+    Verified,
+    /// The request failed or was refused, with the reason
+    Failed(String),
+}
+
+/// The operations which are recorded
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum AuditEvent {
+    /// A Request::SignPSBT
+    SignPSBT {
+        /// the txid of the transaction requested
+        txid: Txid,
+        /// the inputs the oracle signed for
+        inputs: Vec<AuditedInput>,
+        /// what happened
+        outcome: Outcome,
+    },
+    /// A Request::ConfirmKey
+    ConfirmKey {
+        /// the client's challenge
+        challenge: Sha256,
+        /// what happened
+        outcome: Outcome,
+    },
+    /// A Request::Verify
+    Verify {
+        /// the txid of the transaction checked
+        txid: Txid,
+        /// the inputs the oracle's key is needed for
+        inputs: Vec<AuditedInput>,
+        /// what happened
+        outcome: Outcome,
+    },
+    /// A Request::SignAnchor
+    SignAnchor {
+        /// the txid of the child spending the anchor
+        txid: Txid,
+        /// the anchor output
+        anchor: bitcoin::OutPoint,
+        /// what happened
+        outcome: Outcome,
+    },
+}
+
+/// A single record in the log
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEntry {
+    /// position in the log, starting at 0
+    pub index: u64,
+    /// unix time (seconds) the entry was written
+    pub timestamp: u64,
+    /// the hash of the prior entry, all zeros for the first
+    pub prev: Sha256,
+    /// the operation
+    pub event: AuditEvent,
+    /// the hash of this entry, committing to all of the above
+    pub hash: Sha256,
+}
+
+impl AuditEntry {
+    /// compute the hash an entry should have
+    fn compute_hash(index: u64, timestamp: u64, prev: &Sha256, event: &AuditEvent) -> Sha256 {
+        let mut engine = Sha256::engine();
+        engine.input(&index.to_be_bytes());
+        engine.input(&timestamp.to_be_bytes());
+        engine.input(&prev[..]);
+        engine.input(&serde_json::to_vec(event).expect("AuditEvent always serializes")[..]);
+        Sha256::from_engine(engine)
+    }
+}
+
+/// Errors found when verifying a log
+#[derive(Debug)]
+pub enum AuditError {
+    /// the entry at this position has the wrong index
+    BadIndex(u64),
+    /// the entry at this index does not commit to its predecessor
+    BrokenChain(u64),
+    /// the entry at this index has been modified
+    BadHash(u64),
+}
+impl std::fmt::Display for AuditError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for AuditError {}
+
+/// A sled backed audit log
+pub struct AuditLog {
+    db: sled::Db,
+    /// the next index and the hash of the last entry. Held while appending
+    /// so that concurrent requests are ordered.
+    head: Mutex<(u64, Sha256)>,
+}
+
+impl AuditLog {
+    /// Opens (or creates) an audit log at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, std::io::Error> {
+        let db = sled::open(path)?;
+        let head = match db.last()? {
+            Some((_, v)) => {
+                let last: AuditEntry = serde_json::from_slice(&v[..])?;
+                (last.index + 1, last.hash)
+            }
+            None => (0, Sha256::from_inner([0u8; 32])),
+        };
+        Ok(AuditLog {
+            db,
+            head: Mutex::new(head),
+        })
+    }
+
+    /// Appends an event to the log, durably flushing it before returning.
+    pub fn append(&self, event: AuditEvent) -> Result<AuditEntry, std::io::Error> {
+        let mut head = self.head.lock().unwrap();
+        let (index, prev) = *head;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let hash = AuditEntry::compute_hash(index, timestamp, &prev, &event);
+        let entry = AuditEntry {
+            index,
+            timestamp,
+            prev,
+            event,
+            hash,
+        };
+        self.db
+            .insert(index.to_be_bytes(), serde_json::to_vec(&entry)?)?;
+        self.db.flush()?;
+        *head = (index + 1, hash);
+        Ok(entry)
+    }
+
+    /// Returns every entry in the log, in order.
+    pub fn export(&self) -> Result<Vec<AuditEntry>, std::io::Error> {
+        self.db
+            .iter()
+            .map(|r| {
+                let (_, v) = r?;
+                Ok(serde_json::from_slice(&v[..])?)
+            })
+            .collect()
+    }
+
+    /// Checks that `entries` form a valid chain starting at index 0, returning
+    /// the hash of the last entry which can be compared to a trusted value.
+    pub fn verify(entries: &[AuditEntry]) -> Result<Sha256, AuditError> {
+        let mut prev = Sha256::from_inner([0u8; 32]);
+        for (i, entry) in entries.iter().enumerate() {
+            let i = i as u64;
+            if entry.index != i {
+                return Err(AuditError::BadIndex(i));
+            }
+            if entry.prev != prev {
+                return Err(AuditError::BrokenChain(i));
+            }
+            if AuditEntry::compute_hash(entry.index, entry.timestamp, &entry.prev, &entry.event)
+                != entry.hash
+            {
+                return Err(AuditError::BadHash(i));
+            }
+            prev = entry.hash;
+        }
+        Ok(prev)
+    }
+}
@@ -0,0 +1,129 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Restricting which clients an oracle signs for.
+//!
+//! By default an oracle signs for anyone who can reach it. With an
+//! `Authenticator` (see `HDOracleEmulator::with_auth`), a connection may only
+//! request signatures once it is authorized, either because the client's
+//! Noise key (see `noise`) is on the allowlist, or because it presented an
+//! `msgs::AuthToken` signed by a trusted issuer. `ConfirmKey` is always
+//! allowed, so clients can check who they are talking to first.
+use super::*;
+use bitcoin::secp256k1::PublicKey;
+
+/// the current unix time
+pub(crate) fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// The clients an oracle signs for
+#[derive(Default, Clone)]
+pub struct Authenticator {
+    allowed_keys: Vec<PublicKey>,
+    issuers: Vec<PublicKey>,
+}
+
+impl Authenticator {
+    /// an authenticator which authorizes nobody
+    pub fn new() -> Self {
+        Default::default()
+    }
+    /// authorize clients whose Noise key is `key`
+    pub fn with_allowed_key(mut self, key: PublicKey) -> Self {
+        self.allowed_keys.push(key);
+        self
+    }
+    /// authorize clients with a token signed by `issuer`
+    pub fn with_issuer(mut self, issuer: PublicKey) -> Self {
+        self.issuers.push(issuer);
+        self
+    }
+    /// if a client with Noise key `key` is on the allowlist
+    pub fn allows_key(&self, key: &PublicKey) -> bool {
+        self.allowed_keys.contains(key)
+    }
+    /// Checks a token presented by a client, whose Noise key is `key` if the
+    /// connection is encrypted.
+    pub fn check_token(
+        &self,
+        token: &msgs::AuthToken,
+        key: Option<&PublicKey>,
+    ) -> Result<(), msgs::ServerError> {
+        if token.expires <= now() {
+            return Err(msgs::ServerError::Unauthorized);
+        }
+        if token.subject.is_some() && token.subject.as_ref() != key {
+            return Err(msgs::ServerError::Unauthorized);
+        }
+        let msg = msgs::AuthToken::message(token.expires, token.subject.as_ref());
+        let signed = SECP.with(|secp| {
+            self.issuers
+                .iter()
+                .any(|issuer| secp.verify(&msg, &token.signature, issuer).is_ok())
+        });
+        if signed {
+            Ok(())
+        } else {
+            Err(msgs::ServerError::Unauthorized)
+        }
+    }
+}
+
+/// What the server knows about the client on a connection
+#[derive(Clone)]
+pub(crate) struct Client {
+    /// the client's Noise key, if the connection is encrypted
+    pub(crate) key: Option<PublicKey>,
+    /// when the client's token expires
+    expires: Option<u64>,
+}
+
+impl Client {
+    /// a client with Noise key `key`
+    pub(crate) fn new(key: Option<PublicKey>) -> Self {
+        Client { key, expires: None }
+    }
+    /// a client in the same process, e.g. an application embedding the
+    /// server (see `HDOracleEmulator::handle_request`), which authorizes its
+    /// own callers.
+    pub(crate) fn trusted() -> Self {
+        Client {
+            key: None,
+            expires: Some(u64::MAX),
+        }
+    }
+    /// if the client may request signatures, i.e. there is no `auth`, the
+    /// client is on its allowlist, or the client presented a token which has
+    /// not expired.
+    ///
+    /// `auth` is passed in each time (rather than when connecting) as it may
+    /// be reloaded while the connection is open.
+    pub(crate) fn authorized(&self, auth: Option<&Authenticator>) -> bool {
+        match auth {
+            None => true,
+            Some(auth) => {
+                self.key.as_ref().map_or(false, |k| auth.allows_key(k))
+                    || self.expires.map_or(false, |e| now() < e)
+            }
+        }
+    }
+    /// authorizes the client until the token expires, if it is valid
+    pub(crate) fn authenticate(
+        &mut self,
+        auth: Option<&Authenticator>,
+        token: &msgs::AuthToken,
+    ) -> Result<(), msgs::ServerError> {
+        if let Some(auth) = auth {
+            auth.check_token(token, self.key.as_ref())?;
+            self.expires = Some(token.expires);
+        }
+        Ok(())
+    }
+}
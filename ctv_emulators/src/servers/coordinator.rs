@@ -0,0 +1,234 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A server which signs for a k-of-n federation of oracles on behalf of its
+//! clients.
+//!
+//! The coordinator holds no keys. It speaks the same protocol as an
+//! `HDOracleEmulator`, forwards every PSBT to all of its members at once, and
+//! responds as soon as `threshold` of them have signed. Clients pair it with
+//! a `FederatedEmulatorConnection` (see `with_coordinator`) to get the
+//! matching threshold clause.
+use super::*;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, watch};
+use tracing::Instrument;
+
+/// Fans signing requests out to federation members.
+#[derive(Clone)]
+pub struct ThresholdCoordinator {
+    members: Vec<Arc<dyn CTVEmulator>>,
+    threshold: usize,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl ThresholdCoordinator {
+    /// create a coordinator requiring `threshold` of `members` to sign.
+    pub fn new(members: Vec<Arc<dyn CTVEmulator>>, threshold: usize) -> Self {
+        ThresholdCoordinator {
+            members,
+            threshold,
+            shutdown: watch::channel(false).1,
+        }
+    }
+    /// returns a handle which can be used to gracefully stop the server once bound
+    pub fn with_shutdown(mut self) -> (Self, shutdown::ShutdownHandle) {
+        let (handle, signal) = shutdown::ShutdownHandle::new();
+        self.shutdown = signal;
+        (self, handle)
+    }
+
+    /// binds the coordinator to a socket interface and runs the server.
+    ///
+    /// Semantics are identical to `HDOracleEmulator::bind`.
+    pub async fn bind<A: ToSocketAddrs>(self, a: A) -> std::io::Result<()> {
+        let listener = TcpListener::bind(a).await?;
+        let (drain, mut drained) = mpsc::channel::<()>(1);
+        loop {
+            tokio::select! {
+                r = listener.accept() => {
+                    let (socket, peer) = r?;
+                    self.serve(peer, socket, drain.clone());
+                }
+                _ = shutdown::stopped(self.shutdown.clone()) => break,
+            }
+        }
+        drop(drain);
+        drained.recv().await;
+        Ok(())
+    }
+
+    /// spawns a task serving requests on `socket` until it closes, in a
+    /// `connection` span.
+    fn serve<S>(&self, peer: std::net::SocketAddr, mut socket: S, drain: mpsc::Sender<()>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let this = self.clone();
+        let span = tracing::info_span!("connection", %peer);
+        let connection = async move {
+            let _drain = drain;
+            // the federation has no single key to authenticate a session
+            // with, serves requests strictly in turn, and leaves deadlines to
+            // its members' connections
+            let mut hello = protocol::Hello::ours();
+            hello.capabilities = hello.capabilities.remove(
+                protocol::Capabilities::NOISE
+                    | protocol::Capabilities::PIPELINE
+                    | protocol::Capabilities::DEADLINE,
+            );
+            let mut session = protocol::Session::accept(&mut socket, hello).await?;
+            loop {
+                let v = tokio::select! {
+                    r = session.read_frame(&mut socket) => r?,
+                    _ = shutdown::stopped(this.shutdown.clone()) => return Ok(()),
+                };
+                let response = match session.codec().decode(&v[..])? {
+                    msgs::Request::SignPSBT(msgs::PSBT(psbt)) => {
+                        session.codec().encode(&msgs::PSBT(this.sign(psbt).await?))?
+                    }
+                    msgs::Request::SignBatch(batch) => {
+                        let batch = batch.into_iter().map(|msgs::PSBT(p)| p).collect();
+                        let signed = this.sign_batch(batch).await?;
+                        session.codec().encode(&msgs::SignedBatch(
+                            signed.into_iter().map(msgs::PSBT).collect(),
+                        ))?
+                    }
+                    // The federation has no single key to confirm, derive,
+                    // verify, or prove liveness for, clients should ask the
+                    // members directly.
+                    msgs::Request::ConfirmKey(_) | msgs::Request::Liveness(_) => {
+                        return input_error("Coordinator Can Not Confirm Keys")
+                    }
+                    msgs::Request::DeriveKey(_) => {
+                        return input_error("Coordinator Can Not Derive Keys")
+                    }
+                    msgs::Request::Verify(_) => {
+                        return input_error("Coordinator Can Not Verify Signatures")
+                    }
+                    // anchors are locked to a member's key, not the federation's
+                    msgs::Request::SignAnchor(_) => {
+                        return input_error("Coordinator Can Not Sign Anchors")
+                    }
+                    // MuSig2 is n-of-n, clients run the rounds with every
+                    // member themselves (see `MusigEmulatorConnection`).
+                    msgs::Request::MusigNonce(_) | msgs::Request::MusigSign(_) => {
+                        return input_error("Coordinator Does Not Support MuSig2")
+                    }
+                    // Members authenticate the coordinator, not its clients
+                    msgs::Request::Authenticate(_) => {
+                        return input_error("Coordinator Does Not Authenticate Clients")
+                    }
+                };
+                session.write_frame(&mut socket, &response[..]).await?;
+            }
+        };
+        tokio::spawn(
+            async move {
+                if let Err(e) = connection.await {
+                    tracing::warn!(error = %e, "connection closed with error");
+                }
+            }
+            .instrument(span),
+        );
+    }
+
+    /// Sends `psbt` to every member concurrently, returning it with the
+    /// signatures of the first `threshold` members to sign.
+    ///
+    /// Fails if too many members fail to sign.
+    pub async fn sign(
+        &self,
+        psbt: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, std::io::Error> {
+        let mut signed = self
+            .fan_out(vec![psbt], |member, mut batch| {
+                Ok(vec![member.sign(batch.remove(0))?])
+            })
+            .await?;
+        Ok(signed.remove(0))
+    }
+
+    /// Sends the whole `batch` to every member concurrently, returning it with
+    /// the signatures of the first `threshold` members to sign all of it.
+    ///
+    /// Members which sign a batch all-or-nothing (see
+    /// `CTVEmulator::sign_batch`) record none of it if they refuse any PSBT.
+    /// Fails if too many members fail to sign.
+    pub async fn sign_batch(
+        &self,
+        batch: Vec<PartiallySignedTransaction>,
+    ) -> Result<Vec<PartiallySignedTransaction>, std::io::Error> {
+        self.fan_out(batch, |member, batch| member.sign_batch(batch))
+            .await
+    }
+
+    /// Has every member sign `batch` with `sign`, merging the signatures of
+    /// the first `threshold` members to sign into it.
+    async fn fan_out<F>(
+        &self,
+        batch: Vec<PartiallySignedTransaction>,
+        sign: F,
+    ) -> Result<Vec<PartiallySignedTransaction>, std::io::Error>
+    where
+        F: Fn(
+                &dyn CTVEmulator,
+                Vec<PartiallySignedTransaction>,
+            ) -> Result<Vec<PartiallySignedTransaction>, EmulatorError>
+            + Clone
+            + Send
+            + 'static,
+    {
+        let (tx, mut rx) = mpsc::channel(self.members.len().max(1));
+        for member in self.members.iter().cloned() {
+            let tx = tx.clone();
+            let batch = batch.clone();
+            let sign = sign.clone();
+            // members may block (e.g., `HDOracleEmulatorConnection`), so they
+            // get a thread each.
+            tokio::task::spawn_blocking(move || {
+                // the receiver is gone once we have enough signatures
+                let _ = tx.blocking_send(sign(&*member, batch));
+            });
+        }
+        drop(tx);
+        let txids: Vec<_> = batch.iter().map(|p| p.global.unsigned_tx.txid()).collect();
+        let mut signed = batch;
+        let mut n = 0;
+        let mut errors = vec![];
+        while let Some(r) = rx.recv().await {
+            match r {
+                Ok(ps) => {
+                    if ps.len() != signed.len() {
+                        return input_error("Member Signed the Wrong Number of PSBTs");
+                    }
+                    for (s, p) in signed.iter_mut().zip(ps) {
+                        s.merge(p)
+                            .or_else(|_| input_error("Member Returned a Different PSBT"))?;
+                    }
+                    n += 1;
+                    if n >= self.threshold {
+                        tracing::info!(?txids, signers = n, "federation signed");
+                        return Ok(signed);
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!(?txids, error = %e, "member failed to sign");
+                    errors.push(e.to_string())
+                }
+            }
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!(
+                "Only {} of the {} Required Members Signed: {}",
+                n,
+                self.threshold,
+                errors.join(", ")
+            ),
+        ))
+    }
+}
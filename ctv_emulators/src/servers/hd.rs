@@ -5,154 +5,1878 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use super::*;
-use bitcoin::SigHash;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, watch};
+use tracing::Instrument;
+
+/// checks if a script contains a push of the given public key
+fn script_has_key(script: &bitcoin::Script, pk: &bitcoin::PublicKey) -> bool {
+    let key = pk.to_bytes();
+    script.instructions().any(|i| match i {
+        Ok(bitcoin::blockdata::script::Instruction::PushBytes(b)) => b == &key[..],
+        _ => false,
+    })
+}
+
+/// If a p2wsh witness script requires a signature by `pk`.
+///
+/// A script which is a miniscript (e.g., `pk` along with timelocks or other
+/// keys) must name `pk` in a `pk` or `pk_h` fragment, so that keys pushed as
+/// data, rather than checked, don't count. Other scripts need only push `pk`.
+fn witness_script_has_key(script: &bitcoin::Script, pk: &bitcoin::PublicKey) -> bool {
+    use miniscript::{Miniscript, MiniscriptKey, PkPkh, Segwitv0};
+    match Miniscript::<bitcoin::PublicKey, Segwitv0>::parse_insane(script) {
+        Ok(ms) => ms.iter_pk_pkh().any(|k| match k {
+            PkPkh::PlainPubkey(k) => k == *pk,
+            PkPkh::HashedPubkey(h) => h == pk.to_pubkeyhash(),
+        }),
+        Err(_) => script_has_key(script, pk),
+    }
+}
+
+/// a `msgs::ServerError::MalformedPSBT`
+fn malformed(e: &str) -> std::io::Error {
+    msgs::ServerError::MalformedPSBT(e.into()).into()
+}
+
+/// a `msgs::ServerError::UnsupportedScript`
+fn unsupported(e: &str) -> std::io::Error {
+    msgs::ServerError::UnsupportedScript(e.into()).into()
+}
+
+/// fails with `msgs::ServerError::DeadlineExceeded` once `deadline` has passed
+fn before(deadline: Option<std::time::Instant>) -> Result<(), std::io::Error> {
+    match deadline {
+        Some(deadline) if std::time::Instant::now() >= deadline => {
+            tracing::debug!("request abandoned past its deadline");
+            Err(msgs::ServerError::DeadlineExceeded.into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Why a segwit v0 input can't be signed with a key
+enum Unsignable {
+    /// the input is well formed, but does not involve the key
+    NotOurs(&'static str),
+    /// the input's scripts do not match its UTXO
+    Malformed(&'static str),
+}
+
+/// Finds the BIP-143 scriptcode for signing a segwit v0 input spending `utxo`
+/// with `pk`, checking the input's scripts against the UTXO.
+///
+/// Handles p2wpkh and p2wsh, either native or wrapped in p2sh (in which case
+/// the input must have the `redeem_script`). A p2wsh `witness_script` must
+/// require a signature by `pk` (see `witness_script_has_key`), and is the
+/// scriptcode, as miniscripts have no OP_CODESEPARATOR.
+fn ecdsa_scriptcode(
+    pk: &bitcoin::PublicKey,
+    input: &bitcoin::util::psbt::Input,
+    utxo: &bitcoin::TxOut,
+) -> Result<bitcoin::Script, Unsignable> {
+    let program = if utxo.script_pubkey.is_p2sh() {
+        match &input.redeem_script {
+            Some(redeem) if redeem.to_p2sh() != utxo.script_pubkey => {
+                return Err(Unsignable::Malformed("Redeem Script Does Not Match UTXO"))
+            }
+            Some(redeem) => redeem,
+            None => return Err(Unsignable::NotOurs("P2SH Input Without Redeem Script")),
+        }
+    } else {
+        &utxo.script_pubkey
+    };
+    if program.is_v0_p2wpkh() {
+        let wpkh = pk.wpubkey_hash().map(|h| bitcoin::Script::new_v0_wpkh(&h));
+        if wpkh.as_ref() != Some(program) {
+            return Err(Unsignable::NotOurs(
+                "P2WPKH Input Is Not For The Derived Key",
+            ));
+        }
+        Ok(bitcoin::Script::new_p2pkh(&pk.pubkey_hash()))
+    } else if program.is_v0_p2wsh() {
+        let script = match &input.witness_script {
+            Some(script) => script,
+            None => return Err(Unsignable::NotOurs("P2WSH Input Without Witness Script")),
+        };
+        if &script.to_v0_p2wsh() != program {
+            return Err(Unsignable::Malformed("Witness Script Does Not Match UTXO"));
+        }
+        if !witness_script_has_key(script, pk) {
+            return Err(Unsignable::NotOurs(
+                "Witness Script Does Not Involve The Derived Key",
+            ));
+        }
+        Ok(script.clone())
+    } else {
+        Err(Unsignable::NotOurs("Input Is Not Segwit v0"))
+    }
+}
+
+/// If `input`'s scripts require a signature by `pk`, i.e. if the oracle would
+/// sign it with the key `pk` was derived for.
+///
+/// Clients use this to only name the oracle's key in the `bip32_derivation` of
+/// inputs which need it, as a transaction may also spend outputs not locked
+/// by the oracle, or locked by keys derived for other inputs' CTV hashes.
+pub fn involves_key(
+    pk: &bitcoin::PublicKey,
+    input: &bitcoin::util::psbt::Input,
+    secp: &Secp256k1<All>,
+) -> bool {
+    match &input.witness_utxo {
+        Some(utxo) if taproot::is_v1_witness(&utxo.script_pubkey) => {
+            taproot::spend_path(pk, input, utxo, secp).is_some()
+        }
+        Some(utxo) => ecdsa_scriptcode(pk, input, utxo).is_ok(),
+        None => false,
+    }
+}
+
+/// How long a MuSig2 session may wait between rounds
+const MUSIG_SESSION_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+/// The most MuSig2 sessions a server keeps at once
+const MAX_MUSIG_SESSIONS: usize = 1024;
+
+/// An input of a MuSig2 session
+struct MusigInput {
+    input: usize,
+    root: keys::RootKey,
+    path: Vec<ChildNumber>,
+    key: bitcoin::PublicKey,
+    ctx: musig::KeyAggContext,
+    nonce: musig::SecNonce,
+}
+
+/// A PSBT signed by `sign_unrecorded`, not yet released to the client
+struct Unreleased {
+    tx: bitcoin::Transaction,
+    psbt: PartiallySignedTransaction,
+    signed: Vec<(usize, bitcoin::PublicKey)>,
+}
+
+/// The state kept between the rounds of MuSig2 signing
+struct MusigSession {
+    psbt: PartiallySignedTransaction,
+    started: std::time::Instant,
+    inputs: Vec<MusigInput>,
+}
+
 #[derive(Clone)]
 pub struct HDOracleEmulator {
-    root: ExtendedPrivKey,
-    debug: bool,
+    keys: Arc<keys::KeyRing>,
+    policy: reload::Shared<dyn policy::OraclePolicy>,
+    audit: Option<Arc<audit::AuditLog>>,
+    limits: reload::Shared<limits::Limiter>,
+    shutdown: watch::Receiver<bool>,
+    musig: Arc<std::sync::Mutex<std::collections::HashMap<[u8; 32], MusigSession>>>,
+    metrics: Arc<metrics::Metrics>,
+    auth: reload::Shared<Option<Arc<auth::Authenticator>>>,
+    history: Option<Arc<history::SigningHistory>>,
+    scheme: derivation::DerivationScheme,
+    attestation_key: Option<bitcoin::secp256k1::SecretKey>,
 }
 
 impl HDOracleEmulator {
     /// create a new HDOracleEmulator
     ///
-    /// if debug is set, runs in a "single threaded" mode where we can observe errors on connections rather than ignoring them.
-    pub fn new(root: ExtendedPrivKey, debug: bool) -> Self {
-        HDOracleEmulator { root, debug }
+    /// The oracle signs for anything by default, see `with_policy` to restrict it.
+    ///
+    /// The server reports what it does with `tracing`: a span per connection
+    /// (with the peer and negotiated protocol), and events for every request
+    /// with the txid and the child paths derived for it. Install a subscriber
+    /// (e.g. `tracing_subscriber`) to see them.
+    pub fn new(root: ExtendedPrivKey) -> Self {
+        Self::from_signer(Arc::new(signer::LocalSigner::new(root)))
+    }
+    /// create a new HDOracleEmulator whose root on `network` is derived from
+    /// a BIP-39 mnemonic `phrase` and optional `passphrase`, see
+    /// `seed::from_mnemonic`.
+    pub fn from_mnemonic(
+        phrase: &str,
+        passphrase: &str,
+        network: bitcoin::Network,
+    ) -> Result<Self, std::io::Error> {
+        Ok(Self::new(seed::mnemonic_root(phrase, passphrase, network)?))
+    }
+    /// create a new HDOracleEmulator whose keys are held by `signer`, e.g. a
+    /// hardware device, rather than in memory.
+    pub fn from_signer(signer: Arc<dyn signer::Signer>) -> Self {
+        HDOracleEmulator {
+            keys: Arc::new(keys::KeyRing::new(signer)),
+            policy: reload::Shared::new(Arc::new(policy::AllowAll)),
+            audit: None,
+            limits: reload::Shared::new(Arc::new(limits::Limiter::new(Default::default()))),
+            shutdown: watch::channel(false).1,
+            musig: Default::default(),
+            metrics: Default::default(),
+            auth: reload::Shared::new(Arc::new(None)),
+            history: None,
+            scheme: Default::default(),
+            attestation_key: None,
+        }
+    }
+    /// create a watch-only HDOracleEmulator for `xpub`, which verifies
+    /// signatures by its keys rather than signing, see [`watch`].
+    pub fn watch_only(xpub: ExtendedPubKey) -> Self {
+        Self::from_signer(Arc::new(watch::WatchOnly::new(xpub)))
+    }
+    /// sign the `msgs::Attestation`s the server makes with `key`, so that
+    /// they may be checked by others than the client which requested them.
+    pub fn with_attestation_key(mut self, key: bitcoin::secp256k1::SecretKey) -> Self {
+        self.attestation_key = Some(key);
+        self
+    }
+    /// add another root key, which becomes the primary root from the block
+    /// height `active_from`. See [`keys`] for details.
+    pub fn with_root(self, signer: Arc<dyn signer::Signer>, active_from: u32) -> Self {
+        self.keys.add(signer, active_from);
+        self
+    }
+    /// the oracle's root keys, which may be used to rotate keys (and must be
+    /// used to update the block height) while the server runs.
+    pub fn key_ring(&self) -> Arc<keys::KeyRing> {
+        self.keys.clone()
+    }
+    /// record the server's metrics in `metrics`, e.g. to serve them with
+    /// `metrics::Metrics::bind`
+    pub fn with_metrics(mut self, metrics: Arc<metrics::Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+    /// only sign for clients authorized by `auth`, see [`auth`]
+    pub fn with_auth(self, auth: Arc<auth::Authenticator>) -> Self {
+        self.auth.store(Arc::new(Some(auth)));
+        self
+    }
+    /// returns a handle which can be used to gracefully stop the server once bound
+    pub fn with_shutdown(mut self) -> (Self, shutdown::ShutdownHandle) {
+        let (handle, signal) = shutdown::ShutdownHandle::new();
+        self.shutdown = signal;
+        (self, handle)
+    }
+    /// restrict how many connections and requests the server will serve
+    pub fn with_limits(self, limits: limits::Limits) -> Self {
+        self.limits.store(Arc::new(limits::Limiter::new(limits)));
+        self
+    }
+    /// record every request handled to `log`.
+    ///
+    /// If an entry can't be written, the request fails rather than going
+    /// unrecorded.
+    pub fn with_audit_log(mut self, log: Arc<audit::AuditLog>) -> Self {
+        self.audit = Some(log);
+        self
+    }
+    /// write an event to the audit log, if there is one
+    fn audit(&self, event: audit::AuditEvent) -> Result<(), std::io::Error> {
+        if let Some(log) = &self.audit {
+            log.append(event)?;
+        }
+        Ok(())
+    }
+    /// derive keys with `scheme` rather than `DerivationScheme::Unhardened`.
+    ///
+    /// Clients must be configured with the same scheme, see [`derivation`].
+    pub fn with_derivation(mut self, scheme: derivation::DerivationScheme) -> Self {
+        self.scheme = scheme;
+        self
+    }
+    /// remember the transaction signed for each CTV hash in `history`, and
+    /// refuse (or warn on) requests to sign a different one, see [`history`]
+    pub fn with_signing_history(mut self, history: Arc<history::SigningHistory>) -> Self {
+        self.history = Some(history);
+        self
+    }
+    /// Records that the `signed` inputs of each transaction are being signed
+    /// in the signing history, if there is one, returning the violation if an
+    /// input's CTV hash was signed for a different transaction and those are
+    /// refused, in which case none of them are recorded.
+    fn check_history(
+        &self,
+        txs: &[(&bitcoin::Transaction, &[(usize, bitcoin::PublicKey)])],
+    ) -> Result<Result<(), policy::PolicyViolation>, std::io::Error> {
+        let history = match &self.history {
+            Some(history) => history,
+            None => return Ok(Ok(())),
+        };
+        let batch: Vec<(bitcoin::Txid, Vec<(usize, Sha256)>)> = txs
+            .iter()
+            .map(|(tx, signed)| {
+                let hashes = signed
+                    .iter()
+                    .map(|(input, _)| (*input, tx.get_ctv_hash(*input as u32)))
+                    .collect();
+                (tx.txid(), hashes)
+            })
+            .collect();
+        let conflicts = history.record_all(&batch[..])?;
+        for ((txid, _), found) in batch.iter().zip(&conflicts) {
+            for (input, previous) in found {
+                tracing::warn!(%txid, input, %previous, "ctv hash already signed for a different transaction");
+            }
+        }
+        Ok(match conflicts.iter().flatten().next() {
+            Some((input, previous)) if history.refuses() => {
+                Err(policy::PolicyViolation::DoubleSign {
+                    input: *input,
+                    previous: *previous,
+                })
+            }
+            _ => Ok(()),
+        })
+    }
+    /// set the policy consulted before signing any PSBT
+    pub fn with_policy(self, policy: Arc<dyn policy::OraclePolicy>) -> Self {
+        self.policy.store(policy);
+        self
+    }
+    /// configures the server from `config`, returning a handle which may be
+    /// used to reload it while the server runs, see [`reload`].
+    ///
+    /// This replaces any policy, limits, or authenticator set before. The
+    /// policy's spend cap is kept in the signing history's ledger, if one
+    /// was set (see `with_signing_history`), and in memory otherwise.
+    pub fn with_reload(self, config: &reload::ServerConfig) -> (Self, reload::ReloadHandle) {
+        let rules = config.policy.clone().unwrap_or_default();
+        let config_policy = match &self.history {
+            Some(history) => policy::ConfigPolicy::with_ledger(rules, history.ledger()),
+            None => policy::ConfigPolicy::new(rules),
+        };
+        let handle = reload::ReloadHandle {
+            policy: self.policy.clone(),
+            config_policy: Arc::new(config_policy),
+            limits: self.limits.clone(),
+            auth: self.auth.clone(),
+        };
+        handle.apply(config);
+        (self, handle)
     }
     /// binds a HDOracleEmulator to a socket interface and runs the server
     ///
-    /// This will only return when debug = false if The TcpListener fails.
-    /// When debug = true, then we join each connection one at a time and return
-    /// any errors.
+    /// This will only return if The TcpListener fails or the server is shut
+    /// down (see `with_shutdown`). Errors on a connection close it and are
+    /// logged, rather than returned.
     pub async fn bind<A: ToSocketAddrs>(self, a: A) -> std::io::Result<()> {
         let listener = TcpListener::bind(a).await?;
+        let (drain, mut drained) = mpsc::channel::<()>(1);
+        loop {
+            tokio::select! {
+                r = listener.accept() => {
+                    let (socket, peer) = r?;
+                    self.keepalive(&socket);
+                    self.serve(Some(peer.ip()), drain.clone(), async move { Ok(socket) });
+                }
+                _ = shutdown::stopped(self.shutdown.clone()) => break,
+            }
+        }
+        drop(drain);
+        drained.recv().await;
+        Ok(())
+    }
+
+    /// binds a HDOracleEmulator to a unix domain socket at `path` and runs
+    /// the server.
+    ///
+    /// The wire protocol is identical to `bind`, so clients may connect with
+    /// a `unix://` address. Semantics are otherwise identical to `bind`.
+    #[cfg(unix)]
+    pub async fn bind_unix<P: AsRef<std::path::Path>>(self, path: P) -> std::io::Result<()> {
+        let listener = tokio::net::UnixListener::bind(path)?;
+        let (drain, mut drained) = mpsc::channel::<()>(1);
+        loop {
+            tokio::select! {
+                r = listener.accept() => {
+                    let (socket, _) = r?;
+                    self.serve(None, drain.clone(), async move { Ok(socket) });
+                }
+                _ = shutdown::stopped(self.shutdown.clone()) => break,
+            }
+        }
+        drop(drain);
+        drained.recv().await;
+        Ok(())
+    }
+
+    /// Serves a single connection over `stream`, e.g. one end of a
+    /// `tokio::io::duplex` pipe, as `bind` serves each accepted socket. This
+    /// runs an oracle in-process, see [`crate::testing`].
+    ///
+    /// Returns immediately, serving the connection on a task of the current
+    /// tokio runtime.
+    pub fn serve_stream<S>(&self, stream: S)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (drain, _) = mpsc::channel::<()>(1);
+        self.serve(None, drain, async move { Ok(stream) });
+    }
+
+    /// Serves a single request without any transport, for an application
+    /// which mounts the oracle inside its own tokio runtime (e.g. behind its
+    /// own API) rather than binding it to a socket.
+    ///
+    /// The request is served as `reply` describes, on a blocking task as
+    /// signing may block. The caller is trusted, so `with_auth` does not
+    /// apply: an embedding application authorizes its own callers. Requests
+    /// which fail with a `ServerError` are responded to with it, other errors
+    /// are returned.
+    ///
+    /// See `connections::client::OracleClient` for a typed client.
+    pub async fn handle_request(
+        &self,
+        request: msgs::Request,
+    ) -> Result<msgs::Response, std::io::Error> {
+        self.metrics.request(&request);
+        self.reply_blocking(request, &mut auth::Client::trusted(), None)
+            .instrument(tracing::info_span!("request", transport = "embedded"))
+            .await
+    }
+
+    /// binds a HDOracleEmulator to a socket interface and runs the server,
+    /// wrapping every connection in TLS as configured by `config` (see
+    /// [`super::tls::server_config`]).
+    ///
+    /// Semantics are otherwise identical to `bind`. Connections which fail the
+    /// TLS handshake are dropped (and logged).
+    #[cfg(feature = "tls")]
+    pub async fn bind_tls<A: ToSocketAddrs>(
+        self,
+        a: A,
+        config: Arc<super::tls::rustls::ServerConfig>,
+    ) -> std::io::Result<()> {
+        let listener = TcpListener::bind(a).await?;
+        let acceptor = tokio_rustls::TlsAcceptor::from(config);
+        let (drain, mut drained) = mpsc::channel::<()>(1);
         loop {
-            let (mut socket, _) = listener.accept().await?;
-            {
-                let this = self.clone();
-                let j: tokio::task::JoinHandle<Result<(), std::io::Error>> =
-                    tokio::spawn(async move {
-                        loop {
-                            socket.readable().await?;
-                            this.handle(&mut socket).await?;
-                        }
+            tokio::select! {
+                r = listener.accept() => {
+                    let (socket, peer) = r?;
+                    self.keepalive(&socket);
+                    let acceptor = acceptor.clone();
+                    self.serve(Some(peer.ip()), drain.clone(), async move {
+                        acceptor.accept(socket).await
                     });
-                if self.debug {
-                    tokio::join!(j).0??;
                 }
+                _ = shutdown::stopped(self.shutdown.clone()) => break,
+            }
+        }
+        drop(drain);
+        drained.recv().await;
+        Ok(())
+    }
+
+    /// binds a HDOracleEmulator to a socket interface and serves JSON-RPC over
+    /// HTTP (see [`http`]) rather than the raw protocol, e.g. to run behind a
+    /// reverse proxy.
+    ///
+    /// Semantics are otherwise identical to `bind`.
+    pub async fn bind_http<A: ToSocketAddrs>(self, a: A) -> std::io::Result<()> {
+        let listener = TcpListener::bind(a).await?;
+        let (drain, mut drained) = mpsc::channel::<()>(1);
+        loop {
+            tokio::select! {
+                r = listener.accept() => {
+                    let (socket, peer) = r?;
+                    self.keepalive(&socket);
+                    self.serve_http(peer.ip(), drain.clone(), socket);
+                }
+                _ = shutdown::stopped(self.shutdown.clone()) => break,
             }
         }
+        drop(drain);
+        drained.recv().await;
+        Ok(())
     }
-    /// helper to get an EPK for the oracle.
-    fn derive(&self, h: Sha256, secp: &Secp256k1<All>) -> Result<ExtendedPrivKey, Error> {
-        let c = hash_to_child_vec(h);
-        self.root.derive_priv(secp, &c)
+
+    /// enables TCP keepalive on an accepted connection, per our limits
+    fn keepalive(&self, socket: &TcpStream) {
+        if let Err(e) = self.limits.load().keepalive(socket) {
+            tracing::warn!(error = %e, "could not enable tcp keepalive");
+        }
+    }
+
+    /// spawns a task serving JSON-RPC calls on `socket` until it closes.
+    ///
+    /// Semantics are the same as `serve`, except that a request which fails
+    /// gets a JSON-RPC error rather than closing the connection, and a
+    /// connection over the limit gets a 503.
+    fn serve_http(&self, peer: std::net::IpAddr, drain: mpsc::Sender<()>, socket: TcpStream) {
+        let this = self.clone();
+        let permit = self.limits.load().connection();
+        let span = tracing::info_span!("connection", %peer, transport = "http");
+        let connection = async move {
+            let _drain = drain;
+            let _open = this.metrics.connection();
+            let mut conn = http::Connection::new(
+                socket,
+                protocol::DEFAULT_MAX_MESSAGE as usize,
+                this.metrics.clone(),
+            );
+            let _permit = match permit {
+                Ok(p) => p,
+                Err(e) => {
+                    tracing::warn!(error = ?e, "connection limit exceeded");
+                    return conn.unavailable().await;
+                }
+            };
+            let mut client = auth::Client::new(None);
+            let mut n_requests: u64 = 0;
+            loop {
+                let idle = this.limits.load().idle_timeout();
+                let next = limits::within(idle, "Idle Timeout", conn.next());
+                let (call, request) = tokio::select! {
+                    r = next => match r? {
+                        Some(call) => call,
+                        None => return Ok(()),
+                    },
+                    _ = shutdown::stopped(this.shutdown.clone()) => return Ok(()),
+                };
+                n_requests += 1;
+                let close = call.close;
+                if let Err(e) = this.limits.load().request(Some(peer), n_requests) {
+                    tracing::warn!(error = ?e, "request limit exceeded");
+                    let closes = e.closes_connection();
+                    conn.respond(call, Ok(msgs::Response::Error(e))).await?;
+                    if closes || close {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                if let Some(token) = &call.token {
+                    if let Err(e) = client.authenticate(this.auth.load().as_deref(), token) {
+                        tracing::debug!(error = ?e, "invalid bearer token");
+                    }
+                }
+                this.metrics.request(&request);
+                let reply = this
+                    .reply_blocking(request, &mut client, None)
+                    .instrument(tracing::info_span!("request", n = n_requests))
+                    .await;
+                if reply.is_err() {
+                    this.metrics.request_error();
+                }
+                conn.respond(call, reply).await?;
+                if close {
+                    return Ok(());
+                }
+            }
+        };
+        tokio::spawn(
+            async move {
+                match connection.await {
+                    Ok(()) => tracing::debug!("connection closed"),
+                    Err(e) => tracing::warn!(error = %e, "connection closed with error"),
+                }
+            }
+            .instrument(span),
+        );
     }
 
-    /// Signs a PSBT with the correct derived key.
+    /// spawns a task which first resolves the connection (e.g., performs a
+    /// handshake) and then serves requests on it until it closes.
     ///
-    /// Always signs for spending index 0.
+    /// `peer` is used for per-IP rate limiting, if known. If a limit is
+    /// exceeded, the client is sent a `msgs::ServerError`. Connections which
+    /// do not complete their handshake, or send their next request, in time
+    /// (see `limits::Limits`) are closed.
+    ///
+    /// The task holds `drain` until it exits, so that the listener can wait
+    /// for all connections to finish after a shutdown. Once shutdown is
+    /// signalled, the task finishes any request in-flight and then exits.
+    ///
+    /// The task runs in a `connection` span, and logs the error which closed
+    /// the connection, if any.
+    fn serve<S, F>(&self, peer: Option<std::net::IpAddr>, drain: mpsc::Sender<()>, connect: F)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        F: std::future::Future<Output = std::io::Result<S>> + Send + 'static,
+    {
+        let this = self.clone();
+        let permit = self.limits.load().connection();
+        let span = tracing::info_span!(
+            "connection",
+            peer = ?peer,
+            version = tracing::field::Empty,
+            capabilities = tracing::field::Empty,
+        );
+        let connection = async move {
+            let _drain = drain;
+            let _open = this.metrics.connection();
+            let handshake = async {
+                let mut socket = connect.await?;
+                // roots which can authenticate an encrypted session
+                let height = this.keys.height();
+                let noise_roots: Vec<keys::RootKey> = this
+                    .keys
+                    .roots()
+                    .into_iter()
+                    .filter(|r| r.signer.supports_ecdh() && r.usable_at(height))
+                    .collect();
+                let mut hello = protocol::Hello::ours();
+                if noise_roots.is_empty() {
+                    hello.capabilities = hello.capabilities.remove(protocol::Capabilities::NOISE);
+                }
+                let mut session = protocol::Session::accept(&mut socket, hello).await?;
+                let span = tracing::Span::current();
+                span.record("version", &session.params.version);
+                span.record("capabilities", &session.params.capabilities.0);
+                if session.wants_noise() {
+                    let keys: Vec<_> = noise_roots
+                        .iter()
+                        .map(|r| r.signer.xpub().public_key.key)
+                        .collect();
+                    let i = session
+                        .respond_noise(&mut socket, &keys[..], |i, point| {
+                            noise_roots[i].signer.ecdh(point)
+                        })
+                        .await?;
+                    tracing::debug!(
+                        root = %noise_roots[i].fingerprint(),
+                        client = ?session.remote_key(),
+                        "encrypted session established"
+                    );
+                }
+                Ok::<_, std::io::Error>((socket, session))
+            };
+            let (mut socket, mut session) = limits::within(
+                this.limits.load().handshake_timeout(),
+                "Handshake Timeout",
+                handshake,
+            )
+            .await?;
+            tracing::debug!("connection accepted");
+            let _permit = match permit {
+                Ok(p) => p,
+                Err(e) => {
+                    // responds to no request
+                    let id = if session.pipelined() { Some(0) } else { None };
+                    return this.respond(&mut socket, &mut session, id, &e).await;
+                }
+            };
+            let mut client = auth::Client::new(session.remote_key());
+            let mut n_requests: u64 = 0;
+            loop {
+                let requested = limits::within(
+                    this.limits.load().idle_timeout(),
+                    "Idle Timeout",
+                    this.requested(&mut socket, &mut session),
+                );
+                let (id, deadline, request) = tokio::select! {
+                    r = requested => r?,
+                    _ = shutdown::stopped(this.shutdown.clone()) => {
+                        tracing::debug!("closing connection for shutdown");
+                        return Ok(());
+                    }
+                };
+                n_requests += 1;
+                if let Err(e) = this.limits.load().request(peer, n_requests) {
+                    tracing::warn!(error = ?e, "request limit exceeded");
+                    this.respond(&mut socket, &mut session, id, &e).await?;
+                    if e.closes_connection() {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                this.metrics.request(&request);
+                let span = tracing::info_span!("request", n = n_requests);
+                if let Err(e) = this
+                    .handle(
+                        &mut socket,
+                        &mut session,
+                        &mut client,
+                        id,
+                        deadline,
+                        request,
+                    )
+                    .instrument(span)
+                    .await
+                {
+                    this.metrics.request_error();
+                    return Err(e);
+                }
+            }
+        };
+        tokio::spawn(
+            async move {
+                match connection.await {
+                    Ok(()) => tracing::debug!("connection closed"),
+                    Err(e) => tracing::warn!(error = %e, "connection closed with error"),
+                }
+            }
+            .instrument(span),
+        );
+    }
+
+    /// helper to get the path and key of `root` for a CTV hash.
+    fn derive(
+        &self,
+        root: &keys::RootKey,
+        h: Sha256,
+    ) -> Result<(Vec<ChildNumber>, bitcoin::PublicKey), std::io::Error> {
+        let c = self.scheme.path(h);
+        let key = root.signer.derive_xpub(&c)?.public_key;
+        Ok((c, key))
+    }
+
+    /// our root with xpub `epk`, if we have it for the same network
+    fn root_for(&self, epk: &ExtendedPubKey) -> Option<keys::RootKey> {
+        self.keys.get(epk.fingerprint()).filter(|root| {
+            let xpub = root.signer.xpub();
+            xpub.public_key == epk.public_key
+                && xpub.chain_code == epk.chain_code
+                && same_key_network(xpub.network, epk.network)
+        })
+    }
+
+    /// The roots which may sign `input`: those named by fingerprint in its
+    /// `bip32_derivation`, or the primary root if it names none of ours.
+    fn roots_for(&self, input: &bitcoin::util::psbt::Input) -> Vec<keys::RootKey> {
+        let hinted: Vec<keys::RootKey> = input
+            .bip32_derivation
+            .values()
+            .filter_map(|(fingerprint, _)| self.keys.get(*fingerprint))
+            .collect();
+        if hinted.is_empty() {
+            self.keys.primary().into_iter().collect()
+        } else {
+            hinted
+        }
+    }
+
+    /// Determines if, and how, input `idx` should be signed by `root`.
+    fn signing_input(
+        &self,
+        root: &keys::RootKey,
+        tx: &bitcoin::Transaction,
+        idx: usize,
+        input: &bitcoin::util::psbt::Input,
+        all_prevouts: bool,
+        secp: &Secp256k1<All>,
+    ) -> Result<Option<signer::SigningInput>, std::io::Error> {
+        let (path, pk) = match self.derive(root, tx.get_ctv_hash(idx as u32)) {
+            Ok(derived) => derived,
+            Err(e) => {
+                self.metrics.derivation_failure();
+                tracing::warn!(
+                    input = idx,
+                    root = %root.fingerprint(),
+                    error = %e,
+                    "could not derive key"
+                );
+                return Err(msgs::ServerError::DerivationFailed.into());
+            }
+        };
+        let utxo = match &input.witness_utxo {
+            Some(utxo) => utxo,
+            // Without the UTXO we can't produce a segwit signature, and
+            // it can't be one of ours anyways.
+            None => return Ok(None),
+        };
+        let sighash = input
+            .sighash_type
+            .unwrap_or(bitcoin::blockdata::transaction::SigHashType::All);
+        let kind = if taproot::is_v1_witness(&utxo.script_pubkey) {
+            if !all_prevouts {
+                return Err(malformed("Taproot Signing Requires All UTXOs"));
+            }
+            // we only sign taproot inputs with SIGHASH_DEFAULT, which
+            // commits to the same data as SIGHASH_ALL
+            if sighash != bitcoin::blockdata::transaction::SigHashType::All {
+                return Err(unsupported("Unsupported Taproot Sighash Type"));
+            }
+            match taproot::spend_path(&pk, input, utxo, secp) {
+                Some(path) => signer::InputKind::Taproot(path),
+                None => return Ok(None),
+            }
+        } else {
+            // Inputs which aren't ours are skipped, unless the client told
+            // us (in `bip32_derivation`) that it expects our key to sign.
+            let scriptcode = match ecdsa_scriptcode(&pk, input, utxo) {
+                Ok(scriptcode) => scriptcode,
+                Err(Unsignable::Malformed(e)) => return Err(malformed(e)),
+                Err(Unsignable::NotOurs(e)) if input.bip32_derivation.contains_key(&pk) => {
+                    return Err(unsupported(e))
+                }
+                Err(Unsignable::NotOurs(_)) => return Ok(None),
+            };
+            if !self.policy.load().allows_sighash(sighash) {
+                return Err(policy::PolicyViolation::DisallowedSighash {
+                    input: idx,
+                    sighash,
+                }
+                .into());
+            }
+            signer::InputKind::Ecdsa {
+                scriptcode,
+                sighash,
+            }
+        };
+        Ok(Some(signer::SigningInput {
+            input: idx,
+            path,
+            key: pk,
+            kind,
+        }))
+    }
+
+    /// Signs a PSBT with the correct derived keys.
+    ///
+    /// Every input is checked against the key derived from its own CTV hash
+    /// (i.e., `get_ctv_hash(i)` for input `i`) for each root that may sign it
+    /// (see `roots_for`). If the input's script requires that key, the root's
+    /// signer is asked to sign that input. Inputs which do not involve the
+    /// oracle are left untouched, unless their `bip32_derivation` names the
+    /// derived key, in which case signing fails. So does an input whose
+    /// scripts do not match its UTXO.
+    ///
+    /// Segwit v0 inputs get an ECDSA signature in `partial_sigs`, with the
+    /// input's `sighash_type` (SIGHASH_ALL if unset) if the policy allows it.
+    /// Witness v1 inputs get a BIP-340 signature over the x-only key (see
+    /// [`taproot`]).
+    ///
+    /// Returns the signed PSBT along with the index and key of every input
+    /// that was signed.
     ///
     /// May fail to sign if the PSBT is not properly formatted
     fn sign(
         &self,
-        mut b: PartiallySignedTransaction,
+        b: PartiallySignedTransaction,
         secp: &Secp256k1<All>,
-    ) -> Result<PartiallySignedTransaction, std::io::Error> {
-        let tx = b.clone().extract_tx();
-        let h = tx.get_ctv_hash(0);
-        if let Ok(key) = self.derive(h, secp) {
-            if let Some(utxo) = &b.inputs[0].witness_utxo {
-                // This is *funny*. In this case, we are assuming that our signature is required
-                // and if a scriptcode is not present than it must be the case that it is a p2wpkh
-                // script, so we generate a scriptcode for our key as a p2wpkh... this is a reasonable
-                // choice! We do not look at the utxo (for now) to verify this.
-
-                let scriptcode = b.inputs[0].witness_script.clone().unwrap_or_else(|| {
-                    let mut v = vec![0u8; 26];
-                    v[0..4].copy_from_slice(&[0x19, 0x76, 0xa9, 0x14]);
-                    v[4..24].copy_from_slice(&key.identifier(secp).as_hash()[..]);
-                    v[24..26].copy_from_slice(&[0x88, 0xac]);
-                    bitcoin::blockdata::script::Builder::from(v).into_script()
-                });
-                let mut sighash = bitcoin::util::bip143::SigHashCache::new(&tx);
-                let sighash = sighash.signature_hash(
-                    0,
-                    &scriptcode,
-                    utxo.value,
-                    bitcoin::blockdata::transaction::SigHashType::All,
+    ) -> Result<(PartiallySignedTransaction, Vec<(usize, bitcoin::PublicKey)>), std::io::Error>
+    {
+        let tx = &b.global.unsigned_tx;
+        // taproot sighashes commit to every spent output, so we may only sign
+        // for those if all the utxos are known.
+        let prevouts: Option<Vec<bitcoin::TxOut>> =
+            b.inputs.iter().map(|i| i.witness_utxo.clone()).collect();
+        // inputs to sign, grouped by root
+        let mut to_sign: Vec<(keys::RootKey, Vec<signer::SigningInput>)> = vec![];
+        for (idx, input) in b.inputs.iter().enumerate() {
+            for root in self.roots_for(input) {
+                if let Some(signing) =
+                    self.signing_input(&root, tx, idx, input, prevouts.is_some(), secp)?
+                {
+                    let fingerprint = root.fingerprint();
+                    tracing::debug!(
+                        txid = %tx.txid(),
+                        input = idx,
+                        root = %fingerprint,
+                        path = ?signing.path,
+                        key = %signing.key,
+                        "signing input"
+                    );
+                    match to_sign.iter_mut().find(|(r, _)| r.fingerprint() == fingerprint) {
+                        Some((_, inputs)) => inputs.push(signing),
+                        None => to_sign.push((root, vec![signing])),
+                    }
+                    break;
+                }
+            }
+        }
+        let signed = to_sign
+            .iter()
+            .flat_map(|(_, inputs)| inputs.iter().map(|s| (s.input, s.key)))
+            .collect();
+        let mut psbt = b;
+        for (root, inputs) in to_sign.iter() {
+            psbt = root
+                .signer
+                .sign_psbt(psbt, &inputs[..], prevouts.as_ref().map(|p| &p[..]))?;
+        }
+        Ok((psbt, signed))
+    }
+
+    /// record the outcome of signing `signed` inputs of `tx` in the audit log
+    fn audit_signing(
+        &self,
+        tx: &bitcoin::Transaction,
+        signed: &[(usize, bitcoin::PublicKey)],
+        checked: &Result<(), policy::PolicyViolation>,
+    ) -> Result<(), std::io::Error> {
+        match checked {
+            Ok(()) => tracing::info!(txid = %tx.txid(), inputs = ?signed, "signed psbt"),
+            Err(e) => tracing::warn!(
+                txid = %tx.txid(),
+                inputs = ?signed,
+                violation = %e,
+                "policy refused to sign psbt"
+            ),
+        }
+        self.audit(audit::AuditEvent::SignPSBT {
+            txid: tx.txid(),
+            inputs: self.audited(tx, signed),
+            outcome: match checked {
+                Ok(()) => audit::Outcome::Signed,
+                Err(e) => audit::Outcome::Failed(e.to_string()),
+            },
+        })
+    }
+
+    /// `inputs` of `tx` (by index and key), as recorded in the audit log
+    fn audited(
+        &self,
+        tx: &bitcoin::Transaction,
+        inputs: &[(usize, bitcoin::PublicKey)],
+    ) -> Vec<audit::AuditedInput> {
+        inputs
+            .iter()
+            .map(|(input, key)| audit::AuditedInput {
+                input: *input,
+                path: self
+                    .scheme
+                    .path(tx.get_ctv_hash(*input as u32))
+                    .into_iter()
+                    .map(u32::from)
+                    .collect(),
+                key: *key,
+            })
+            .collect()
+    }
+
+    /// Checks the oracle's signatures on a signed (possibly finalized) PSBT.
+    ///
+    /// Every input which needs the key derived from its CTV hash (as decided
+    /// for signing, see `sign`) must carry a valid signature by that key, and
+    /// at least one input must need it. Returns the index and key of those
+    /// inputs.
+    fn verify_signed(
+        &self,
+        psbt: &PartiallySignedTransaction,
+        secp: &Secp256k1<All>,
+    ) -> Result<Vec<(usize, bitcoin::PublicKey)>, std::io::Error> {
+        let tx = &psbt.global.unsigned_tx;
+        let prevouts: Option<Vec<bitcoin::TxOut>> =
+            psbt.inputs.iter().map(|i| i.witness_utxo.clone()).collect();
+        let failed = |idx: usize, reason: &str| {
+            std::io::Error::from(msgs::ServerError::VerificationFailed(format!(
+                "Input {}: {}",
+                idx, reason
+            )))
+        };
+        let mut verified = vec![];
+        for (idx, input) in psbt.inputs.iter().enumerate() {
+            let input = watch::with_final_scripts(input);
+            for root in self.roots_for(&input) {
+                let expected =
+                    self.signing_input(&root, tx, idx, &input, prevouts.is_some(), secp)?;
+                let expected = match expected {
+                    Some(expected) => expected,
+                    None => continue,
+                };
+                let prevouts = prevouts.as_ref().map(|p| &p[..]);
+                let valid = watch::has_signature(
+                    &expected.key,
+                    &input,
+                    &expected.kind,
+                    tx,
+                    idx,
+                    prevouts,
+                    secp,
+                )
+                .map_err(|e| failed(idx, &e.to_string()))?;
+                if !valid {
+                    return Err(failed(idx, "Missing Valid Signature"));
+                }
+                tracing::debug!(
+                    txid = %tx.txid(),
+                    input = idx,
+                    root = %root.fingerprint(),
+                    key = %expected.key,
+                    "verified input"
                 );
-                use bitcoin::secp256k1::ThirtyTwoByteHash;
-                struct Wrapped(SigHash);
-                impl ThirtyTwoByteHash for Wrapped {
-                    fn into_32(self) -> [u8; 32] {
-                        self.0.as_hash().into_inner()
+                verified.push((idx, expected.key));
+                break;
+            }
+        }
+        if verified.is_empty() {
+            let e = msgs::ServerError::VerificationFailed("No Inputs Use Our Keys".into());
+            return Err(e.into());
+        }
+        Ok(verified)
+    }
+
+    /// verifies a signed PSBT (see `verify_signed`), recording the outcome in
+    /// the audit log, and attests to it.
+    fn attest(
+        &self,
+        psbt: PartiallySignedTransaction,
+    ) -> Result<msgs::Attestation, std::io::Error> {
+        let tx = &psbt.global.unsigned_tx;
+        let txid = tx.txid();
+        let verified = SECP.with(|secp| self.verify_signed(&psbt, secp));
+        let inputs = match verified {
+            Ok(inputs) => inputs,
+            Err(e) => {
+                tracing::warn!(%txid, error = %e, "could not verify psbt");
+                self.audit(audit::AuditEvent::Verify {
+                    txid,
+                    inputs: vec![],
+                    outcome: audit::Outcome::Failed(e.to_string()),
+                })?;
+                return Err(e);
+            }
+        };
+        tracing::info!(%txid, inputs = ?inputs, "verified psbt");
+        self.audit(audit::AuditEvent::Verify {
+            txid,
+            inputs: self.audited(tx, &inputs[..]),
+            outcome: audit::Outcome::Verified,
+        })?;
+        let signature = self.attestation_key.map(|key| {
+            let msg = msgs::Attestation::message(&txid, &inputs[..]);
+            SECP.with(|secp| {
+                (
+                    bitcoin::secp256k1::PublicKey::from_secret_key(secp, &key),
+                    secp.sign(&msg, &key),
+                )
+            })
+        });
+        Ok(msgs::Attestation {
+            txid,
+            inputs,
+            signature,
+        })
+    }
+
+    /// Round one of MuSig2: commits to a nonce for every taproot input whose
+    /// output key is the (BIP-86 tweaked) aggregate of the participants' keys
+    /// for that input, including ours.
+    fn musig_nonces(
+        &self,
+        request: msgs::MusigNonceRequest,
+    ) -> Result<msgs::MusigNonces, std::io::Error> {
+        let psbt = request.psbt.0;
+        let prevouts: Vec<bitcoin::TxOut> =
+            match psbt.inputs.iter().map(|i| i.witness_utxo.clone()).collect() {
+                Some(prevouts) => prevouts,
+                None => return Err(malformed("MuSig2 Signing Requires All UTXOs")),
+            };
+        // every participant must derive every other participant's key
+        if self.scheme.hardened() {
+            return input_error("MuSig2 Requires an Unhardened Derivation Scheme");
+        }
+        let tx = &psbt.global.unsigned_tx;
+        let mut inputs = vec![];
+        let mut nonces = vec![];
+        SECP.with(|secp| {
+            for (idx, utxo) in prevouts.iter().enumerate() {
+                if !taproot::is_v1_witness(&utxo.script_pubkey) {
+                    continue;
+                }
+                let path = self.scheme.path(tx.get_ctv_hash(idx as u32));
+                let keys = request
+                    .participants
+                    .iter()
+                    .map(|x| x.derive_pub(secp, &path).map(|k| k.public_key.key))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| std::io::Error::from(msgs::ServerError::DerivationFailed))?;
+                let mut ctx = musig::KeyAggContext::new(keys)?;
+                let mut ours = None;
+                for root in self.roots_for(&psbt.inputs[idx]) {
+                    let key = root
+                        .signer
+                        .xpub()
+                        .derive_pub(secp, &path)
+                        .or_else(|e| {
+                            self.metrics.derivation_failure();
+                            tracing::warn!(
+                                input = idx,
+                                root = %root.fingerprint(),
+                                error = %e,
+                                "could not derive key"
+                            );
+                            Err(msgs::ServerError::DerivationFailed)
+                        })?
+                        .public_key;
+                    if ctx.contains(&key.key) {
+                        ours = Some((root, key));
+                        break;
                     }
                 }
-                let msg = bitcoin::secp256k1::Message::from(Wrapped(sighash));
-                let mut signature: Vec<u8> = secp
-                    .sign(&msg, &key.private_key.key)
-                    .serialize_der()
-                    .to_vec();
-                signature.push(0x01);
-                let pk = key.private_key.public_key(secp);
-                b.inputs[0].partial_sigs.insert(pk, signature);
-                return Ok(b);
-            } else {
-                input_error("Could not find UTXO")?;
+                let (root, key) = match ours {
+                    Some(ours) => ours,
+                    None => continue,
+                };
+                ctx.tweak_taproot()?;
+                if ctx.xonly()[..] != utxo.script_pubkey.as_bytes()[2..34] {
+                    continue;
+                }
+                let (nonce, public) = musig::SecNonce::generate();
+                tracing::debug!(
+                    txid = %tx.txid(),
+                    input = idx,
+                    root = %root.fingerprint(),
+                    path = ?path,
+                    "committed to musig nonce"
+                );
+                nonces.push((idx, public));
+                inputs.push(MusigInput {
+                    input: idx,
+                    root,
+                    path,
+                    key,
+                    ctx,
+                    nonce,
+                });
             }
-        } else {
-            input_error("Could Not Derive Key")?;
+            Ok::<_, std::io::Error>(())
+        })?;
+        let session: [u8; 32] = rand::thread_rng().gen();
+        let mut sessions = self.musig.lock().unwrap();
+        sessions.retain(|_, s| s.started.elapsed() < MUSIG_SESSION_TIMEOUT);
+        if sessions.len() >= MAX_MUSIG_SESSIONS {
+            return input_error("Too Many MuSig2 Sessions");
+        }
+        sessions.insert(
+            session,
+            MusigSession {
+                psbt,
+                started: std::time::Instant::now(),
+                inputs,
+            },
+        );
+        Ok(msgs::MusigNonces { session, nonces })
+    }
+
+    /// Round two of MuSig2: signs the inputs of a session with the aggregate
+    /// nonces, if the policy permits it.
+    ///
+    /// The session is removed first, so our nonces can never be reused.
+    fn musig_sign(
+        &self,
+        request: msgs::MusigSignRequest,
+    ) -> Result<msgs::MusigPartialSigs, std::io::Error> {
+        let session = match self.musig.lock().unwrap().remove(&request.session) {
+            Some(s) if s.started.elapsed() < MUSIG_SESSION_TIMEOUT => s,
+            _ => {
+                tracing::warn!("unknown or expired musig session");
+                return input_error("Unknown MuSig2 Session");
+            }
+        };
+        let tx = session.psbt.global.unsigned_tx.clone();
+        let prevouts: Vec<bitcoin::TxOut> = session
+            .psbt
+            .inputs
+            .iter()
+            .filter_map(|i| i.witness_utxo.clone())
+            .collect();
+        let aggnonce = |idx: usize| {
+            request
+                .aggnonces
+                .iter()
+                .find(|(i, _)| *i == idx)
+                .map(|(_, n)| *n)
+        };
+        let inputs: Vec<MusigInput> = session
+            .inputs
+            .into_iter()
+            .filter(|i| aggnonce(i.input).is_some())
+            .collect();
+        let signed: Vec<(usize, bitcoin::PublicKey)> =
+            inputs.iter().map(|i| (i.input, i.key)).collect();
+        let policy = self.policy.load();
+        let mut checked = policy.check(&session.psbt, &signed[..]);
+        if checked.is_ok() {
+            checked = self.check_history(&[(&tx, &signed[..])])?;
+        }
+        if checked.is_ok() {
+            checked = policy.commit(&[(&session.psbt, &signed[..])])?;
+        }
+        self.audit_signing(&tx, &signed[..], &checked)?;
+        checked?;
+        tracing::info!(txid = %tx.txid(), inputs = ?signed, "musig partial signing");
+        let mut partials = vec![];
+        for i in inputs {
+            let msg = taproot::sighash(&tx, &prevouts[..], i.input, &taproot::SpendPath::Key);
+            let aggnonce = aggnonce(i.input).expect("Filtered Above");
+            let partial = i.root.signer.musig_partial_sign(
+                &i.path,
+                i.nonce,
+                &i.ctx,
+                &aggnonce,
+                &msg.into_inner(),
+            )?;
+            partials.push((i.input, partial));
+        }
+        Ok(msgs::MusigPartialSigs(partials))
+    }
+
+    /// signs a PSBT if the policy permits it, recording the outcome in the
+    /// audit log.
+    fn sign_checked(
+        &self,
+        unsigned: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, std::io::Error> {
+        let prepared = self.sign_unrecorded(unsigned)?;
+        self.commit_signed(std::slice::from_ref(&prepared))?;
+        Ok(prepared.psbt)
+    }
+
+    /// Signs every PSBT of a batch if the policy permits it, recording them
+    /// in the signing history and the audit log only once all of them are
+    /// signed, so that either all are or none are.
+    ///
+    /// `deadline` is checked before each PSBT is signed.
+    fn sign_batch(
+        &self,
+        batch: Vec<PartiallySignedTransaction>,
+        deadline: Option<std::time::Instant>,
+    ) -> Result<Vec<PartiallySignedTransaction>, std::io::Error> {
+        let prepared = batch
+            .into_iter()
+            .map(|unsigned| {
+                before(deadline)?;
+                self.sign_unrecorded(unsigned)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        self.commit_signed(&prepared[..])?;
+        Ok(prepared.into_iter().map(|p| p.psbt).collect())
+    }
+
+    /// signs a PSBT and checks it against the policy, without releasing it.
+    ///
+    /// A PSBT which can't be signed or which the policy refuses is recorded
+    /// as failed in the audit log. One which is signed is recorded by
+    /// `commit_signed`.
+    fn sign_unrecorded(
+        &self,
+        unsigned: PartiallySignedTransaction,
+    ) -> Result<Unreleased, std::io::Error> {
+        let tx = unsigned.global.unsigned_tx.clone();
+        let txid = tx.txid();
+        let start = std::time::Instant::now();
+        let signing = SECP.with(|secp| self.sign(unsigned, secp));
+        self.metrics.signed(start.elapsed());
+        let (psbt, signed) = match signing {
+            Ok(r) => r,
+            Err(e) => {
+                tracing::warn!(%txid, error = %e, "could not sign psbt");
+                self.audit(audit::AuditEvent::SignPSBT {
+                    txid,
+                    inputs: vec![],
+                    outcome: audit::Outcome::Failed(e.to_string()),
+                })?;
+                return Err(e);
+            }
+        };
+        let checked = self.policy.load().check(&psbt, &signed[..]);
+        if checked.is_err() {
+            self.audit_signing(&tx, &signed[..], &checked)?;
         }
-        input_error("Unknown Failure to Sign")
+        checked?;
+        Ok(Unreleased { tx, psbt, signed })
     }
 
-    /// the main server business logic.
+    /// Records PSBTs signed by `sign_unrecorded` in the signing history, the
+    /// policy (see `OraclePolicy::commit`), and the audit log before they are
+    /// released.
     ///
-    /// - on receiving Request::SignPSBT, signs the PSBT.
-    /// - on receiving Request::ConfirmKey, signs the challenge prefixed by a nonce.
-    async fn handle(&self, t: &mut TcpStream) -> Result<(), std::io::Error> {
-        let request = Self::requested(t).await?;
+    /// If any of them was signed for a CTV hash already signed for a
+    /// different transaction (and those are refused), or the policy refuses
+    /// to commit them, all of them fail and none are charged by the policy.
+    fn commit_signed(&self, prepared: &[Unreleased]) -> Result<(), std::io::Error> {
+        let txs: Vec<_> = prepared.iter().map(|p| (&p.tx, &p.signed[..])).collect();
+        let mut checked = self.check_history(&txs[..])?;
+        if checked.is_ok() {
+            let psbts: Vec<_> = prepared.iter().map(|p| (&p.psbt, &p.signed[..])).collect();
+            checked = self.policy.load().commit(&psbts[..])?;
+        }
+        for p in prepared {
+            self.audit_signing(&p.tx, &p.signed[..], &checked)?;
+        }
+        checked?;
+        Ok(())
+    }
+
+    /// Signs the input of a child spending one of our anchors (see
+    /// `msgs::AnchorSpend`), recording the outcome in the audit log.
+    ///
+    /// Only the anchor's input is signed, with SIGHASH_ALL, and only if it
+    /// is locked to the root's anchor key (see `derivation::anchor_hash`) and
+    /// the policy permits spending it to fees (see `policy::check_anchor`),
+    /// for which the child's other inputs must carry the transactions they
+    /// spend.
+    /// The policy's other rules and the signing history do not apply, as
+    /// nothing is paid out of the anchor.
+    fn sign_anchor(
+        &self,
+        request: msgs::AnchorSpend,
+    ) -> Result<PartiallySignedTransaction, std::io::Error> {
+        let msgs::AnchorSpend {
+            root,
+            anchor,
+            psbt: msgs::PSBT(psbt),
+        } = request;
+        let txid = psbt.global.unsigned_tx.txid();
+        let signed = self.anchor_signed(&root, anchor, psbt);
+        match &signed {
+            Ok(_) => tracing::info!(%txid, %anchor, "signed anchor"),
+            Err(e) => tracing::warn!(%txid, %anchor, error = %e, "could not sign anchor"),
+        }
+        self.audit(audit::AuditEvent::SignAnchor {
+            txid,
+            anchor,
+            outcome: match &signed {
+                Ok(_) => audit::Outcome::Signed,
+                Err(e) => audit::Outcome::Failed(e.to_string()),
+            },
+        })?;
+        signed
+    }
+
+    /// signs the input of `psbt` spending `anchor` for `sign_anchor`
+    fn anchor_signed(
+        &self,
+        epk: &ExtendedPubKey,
+        anchor: bitcoin::OutPoint,
+        psbt: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, std::io::Error> {
+        let root = match self.root_for(epk) {
+            Some(root) => root,
+            None => return Err(msgs::ServerError::KeyMismatch(epk.fingerprint()).into()),
+        };
+        let mut spent = psbt.global.unsigned_tx.input.iter();
+        let idx = match spent.position(|i| i.previous_output == anchor) {
+            Some(idx) => idx,
+            None => return Err(malformed("PSBT Does Not Spend The Anchor")),
+        };
+        // every other input's value is checked against the transaction it
+        // spends by `policy::check_anchor`
+        let prevouts: Option<Vec<bitcoin::TxOut>> =
+            psbt.inputs.iter().map(|i| i.witness_utxo.clone()).collect();
+        let prevouts = match prevouts {
+            Some(prevouts) => prevouts,
+            None => return Err(malformed("Anchor Spends Require All UTXOs")),
+        };
+        let (path, key) = self.derive(&root, derivation::anchor_hash()).map_err(|e| {
+            self.metrics.derivation_failure();
+            tracing::warn!(root = %root.fingerprint(), error = %e, "could not derive key");
+            msgs::ServerError::DerivationFailed
+        })?;
+        let input = &psbt.inputs[idx];
+        let scriptcode = match ecdsa_scriptcode(&key, input, &prevouts[idx]) {
+            Ok(scriptcode) => scriptcode,
+            Err(Unsignable::Malformed(e)) => return Err(malformed(e)),
+            Err(Unsignable::NotOurs(_)) => return Err(unsupported("Anchor Is Not Ours")),
+        };
+        let sighash = bitcoin::blockdata::transaction::SigHashType::All;
+        if input.sighash_type.map_or(false, |s| s != sighash) {
+            return Err(unsupported("Anchors Are Only Signed With SIGHASH_ALL"));
+        }
+        policy::check_anchor(&psbt, idx, self.policy.load().max_anchor_value())?;
+        tracing::debug!(
+            input = idx,
+            root = %root.fingerprint(),
+            path = ?path,
+            %key,
+            "signing anchor"
+        );
+        let signing = signer::SigningInput {
+            input: idx,
+            path,
+            key,
+            kind: signer::InputKind::Ecdsa {
+                scriptcode,
+                sighash,
+            },
+        };
+        root.signer.sign_psbt(psbt, &[signing], Some(&prevouts[..]))
+    }
+
+    /// Signs the PSBTs of a bundle carried from a client, for an oracle run
+    /// without a network connection (see [`offline`]).
+    ///
+    /// Each PSBT is signed by the bundle's root if the policy permits it, as
+    /// if an authorized client had sent Request::SignPSBT, and is answered
+    /// with the `ServerError` otherwise. Fails with `ServerError::KeyMismatch`
+    /// if the root is not one of ours, and on other errors (e.g., if the
+    /// audit log can't be written).
+    pub fn sign_bundle(
+        &self,
+        bundle: offline::RequestBundle,
+    ) -> Result<offline::ResponseBundle, std::io::Error> {
+        let fingerprint = bundle.root.fingerprint();
+        let root = match self.root_for(&bundle.root) {
+            Some(root) => root,
+            None => return Err(msgs::ServerError::KeyMismatch(fingerprint).into()),
+        };
+        let mut outcomes = Vec::with_capacity(bundle.psbts.len());
+        for msgs::PSBT(mut unsigned) in bundle.psbts {
+            // name the bundle's root on the inputs it signs, so that it signs
+            // even if it isn't our primary one
+            for idx in 0..unsigned.inputs.len() {
+                let h = unsigned.global.unsigned_tx.get_ctv_hash(idx as u32);
+                let (path, key) = self.derive(&root, h)?;
+                let input = &mut unsigned.inputs[idx];
+                if SECP.with(|secp| involves_key(&key, input, secp)) {
+                    input
+                        .bip32_derivation
+                        .insert(key, (fingerprint, DerivationPath::from(path)));
+                }
+            }
+            outcomes.push(match self.sign_checked(unsigned) {
+                Ok(signed) => offline::Outcome::Signed(msgs::PSBT(signed)),
+                Err(e) => match msgs::ServerError::from_io(&e) {
+                    Some(server_error) => offline::Outcome::Failed(server_error.clone()),
+                    None => return Err(e),
+                },
+            });
+        }
+        tracing::info!(%fingerprint, psbts = outcomes.len(), "signed offline bundle");
+        Ok(offline::ResponseBundle::new(bundle.root, outcomes))
+    }
+
+    /// handles a request and sends the reply, see `reply`.
+    async fn handle<S>(
+        &self,
+        t: &mut S,
+        session: &mut protocol::Session,
+        client: &mut auth::Client,
+        id: Option<u64>,
+        deadline: Option<std::time::Instant>,
+        request: msgs::Request,
+    ) -> Result<(), std::io::Error>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        let reply = self.reply_blocking(request, client, deadline).await?;
+        self.respond(t, session, id, &reply).await
+    }
+
+    /// Runs `reply` on the blocking thread pool, in the current span, as
+    /// signing may block, e.g. on a hardware signer. `client` is updated if
+    /// the request authenticates it.
+    async fn reply_blocking(
+        &self,
+        request: msgs::Request,
+        client: &mut auth::Client,
+        deadline: Option<std::time::Instant>,
+    ) -> Result<msgs::Response, std::io::Error> {
+        let this = self.clone();
+        let mut moved = client.clone();
+        let span = tracing::Span::current();
+        let (reply, moved) = tokio::task::spawn_blocking(move || {
+            let reply = span.in_scope(|| this.reply(request, &mut moved, deadline));
+            (reply, moved)
+        })
+        .await
+        .or_else(|_| input_error("Request Handler Panicked"))?;
+        *client = moved;
+        reply
+    }
+
+    /// the main server business logic, shared by every transport.
+    ///
+    /// - on receiving Request::SignPSBT, signs the PSBT if the policy permits it.
+    /// - on receiving Request::SignBatch, signs every PSBT if the policy
+    ///   permits all of them, and none otherwise (see `sign_batch`).
+    /// - on receiving Request::MusigNonce/MusigSign, takes part in MuSig2
+    ///   signing (see `musig`).
+    /// - on receiving Request::ConfirmKey, if the key is one of our roots, signs
+    ///   the challenge prefixed by a nonce and suffixed by the root's
+    ///   fingerprint (and our derivation scheme). Otherwise responds with
+    ///   `ServerError::KeyMismatch`.
+    /// - on receiving Request::DeriveKey, if the key is one of our roots,
+    ///   responds with its key for the hash, signed by the root.
+    /// - on receiving Request::Verify, checks our signatures on the PSBT and
+    ///   responds with an attestation, see [`watch`].
+    /// - on receiving Request::Liveness, if the key is one of our roots, signs
+    ///   the challenge along with the current time and the root's fingerprint.
+    /// - on receiving Request::SignAnchor, signs the child's input spending
+    ///   our anchor if it only pays fees (see `sign_anchor`).
+    /// - on receiving Request::Authenticate, authorizes the client if the
+    ///   token is valid (see [`auth`]).
+    ///
+    /// Requests other than ConfirmKey and Liveness from an unauthorized
+    /// `client` are responded to with `ServerError::Unauthorized`.
+    ///
+    /// A request which fails with a `ServerError` (e.g., a policy violation
+    /// or a malformed PSBT) is responded to with it, other errors close the
+    /// connection.
+    ///
+    /// A request not served by `deadline` (see `msgs::Timed`) is abandoned
+    /// with `ServerError::DeadlineExceeded`, checked before it is started and
+    /// between the PSBTs of a batch.
+    fn reply(
+        &self,
+        request: msgs::Request,
+        client: &mut auth::Client,
+        deadline: Option<std::time::Instant>,
+    ) -> Result<msgs::Response, std::io::Error> {
+        match self.dispatch(request, client, deadline) {
+            Err(e) => match msgs::ServerError::from_io(&e) {
+                Some(server_error) => {
+                    tracing::debug!(error = %server_error, "request failed");
+                    self.metrics.request_error();
+                    Ok(msgs::Response::Error(server_error.clone()))
+                }
+                None => Err(e),
+            },
+            response => response,
+        }
+    }
+
+    /// serves a request, see `reply`
+    fn dispatch(
+        &self,
+        request: msgs::Request,
+        client: &mut auth::Client,
+        deadline: Option<std::time::Instant>,
+    ) -> Result<msgs::Response, std::io::Error> {
+        before(deadline)?;
+        let auth = self.auth.load();
+        if let msgs::Request::Authenticate(token) = &request {
+            return Ok(match client.authenticate(auth.as_deref(), token) {
+                Ok(()) => {
+                    tracing::info!(expires = token.expires, "client authenticated");
+                    msgs::Response::Authenticated(msgs::Authenticated {
+                        expires: token.expires,
+                    })
+                }
+                Err(e) => {
+                    tracing::warn!("invalid token");
+                    msgs::Response::Error(e)
+                }
+            });
+        }
+        let proof = matches!(
+            request,
+            msgs::Request::ConfirmKey(_) | msgs::Request::Liveness(_)
+        );
+        if !client.authorized(auth.as_deref()) && !proof {
+            tracing::warn!(client = ?client.key, "unauthorized request");
+            return Ok(msgs::Response::Error(msgs::ServerError::Unauthorized));
+        }
         match request {
             msgs::Request::SignPSBT(msgs::PSBT(unsigned)) => {
-                let psbt = SECP.with(|secp| self.sign(unsigned, secp))?;
-                Self::respond(t, &msgs::PSBT(psbt)).await
-            }
-            msgs::Request::ConfirmKey(msgs::ConfirmKey(_epk, s)) => {
-                let ck = SECP.with(|secp| {
-                    let key = self.root.private_key.key;
-                    let entropy: [u8; 32] = rand::thread_rng().gen();
-                    let h: Sha256 = Sha256::from_slice(&entropy).unwrap();
-                    let mut m = Sha256::engine();
-                    m.input(&h.into_inner());
-                    m.input(&s.into_inner());
-                    let msg = bitcoin::secp256k1::Message::from_slice(&Sha256::from_engine(m)[..])
-                        .unwrap();
-                    let signature = secp.sign(&msg, &key);
-                    msgs::KeyConfirmed(signature, h)
-                });
-                Self::respond(t, &ck).await
+                let signed = self.sign_checked(unsigned)?;
+                Ok(msgs::Response::PSBT(msgs::PSBT(signed)))
+            }
+            msgs::Request::SignBatch(batch) => {
+                let batch = batch.into_iter().map(|msgs::PSBT(p)| p).collect();
+                let signed = self.sign_batch(batch, deadline)?;
+                Ok(msgs::Response::SignedBatch(msgs::SignedBatch(
+                    signed.into_iter().map(msgs::PSBT).collect(),
+                )))
+            }
+            msgs::Request::MusigNonce(request) => {
+                Ok(msgs::Response::MusigNonces(self.musig_nonces(request)?))
+            }
+            msgs::Request::MusigSign(request) => {
+                Ok(msgs::Response::MusigPartialSigs(self.musig_sign(request)?))
+            }
+            msgs::Request::ConfirmKey(msgs::ConfirmKey(epk, s)) => {
+                let root = match self.root_for(&epk) {
+                    Some(root) => root,
+                    None => {
+                        tracing::warn!(root = %epk.fingerprint(), "asked to confirm unknown key");
+                        self.audit(audit::AuditEvent::ConfirmKey {
+                            challenge: s,
+                            outcome: audit::Outcome::Failed("Key Mismatch".into()),
+                        })?;
+                        let e = msgs::ServerError::KeyMismatch(epk.fingerprint());
+                        return Ok(msgs::Response::Error(e));
+                    }
+                };
+                self.audit(audit::AuditEvent::ConfirmKey {
+                    challenge: s,
+                    outcome: audit::Outcome::Signed,
+                })?;
+                let entropy: [u8; 32] = rand::thread_rng().gen();
+                let h: Sha256 = Sha256::from_slice(&entropy).unwrap();
+                let fingerprint = root.fingerprint();
+                tracing::info!(root = %fingerprint, "confirming key");
+                let msg = msgs::KeyConfirmed::message(&h, &s, &fingerprint, self.scheme);
+                let signature = root.signer.sign_challenge(&msg)?;
+                Ok(msgs::Response::KeyConfirmed(msgs::KeyConfirmed(
+                    signature,
+                    h,
+                    fingerprint,
+                    self.scheme,
+                )))
+            }
+            msgs::Request::DeriveKey(msgs::DeriveKey(epk, h)) => {
+                let root = match self.root_for(&epk) {
+                    Some(root) => root,
+                    None => {
+                        tracing::warn!(root = %epk.fingerprint(), "asked to derive for unknown key");
+                        let e = msgs::ServerError::KeyMismatch(epk.fingerprint());
+                        return Ok(msgs::Response::Error(e));
+                    }
+                };
+                let (path, key) = self.derive(&root, h).map_err(|e| {
+                    self.metrics.derivation_failure();
+                    tracing::warn!(root = %root.fingerprint(), error = %e, "could not derive key");
+                    msgs::ServerError::DerivationFailed
+                })?;
+                tracing::debug!(root = %root.fingerprint(), path = ?path, %key, "derived key");
+                let msg = msgs::DerivedKey::message(&h, self.scheme, &key);
+                let signature = root.signer.sign_challenge(&msg)?;
+                Ok(msgs::Response::DerivedKey(msgs::DerivedKey(key, signature)))
+            }
+            msgs::Request::Verify(msgs::PSBT(psbt)) => {
+                Ok(msgs::Response::Attestation(self.attest(psbt)?))
+            }
+            msgs::Request::SignAnchor(request) => {
+                let signed = self.sign_anchor(request)?;
+                Ok(msgs::Response::PSBT(msgs::PSBT(signed)))
+            }
+            msgs::Request::Liveness(msgs::Liveness(epk, challenge)) => {
+                let root = match self.root_for(&epk) {
+                    Some(root) => root,
+                    None => {
+                        tracing::warn!(root = %epk.fingerprint(), "asked for liveness of unknown key");
+                        let e = msgs::ServerError::KeyMismatch(epk.fingerprint());
+                        return Ok(msgs::Response::Error(e));
+                    }
+                };
+                let timestamp = auth::now();
+                let fingerprint = root.fingerprint();
+                tracing::debug!(root = %fingerprint, timestamp, "proving liveness");
+                let msg = msgs::Alive::message(&challenge, timestamp, &fingerprint);
+                let signature = root.signer.sign_challenge(&msg)?;
+                Ok(msgs::Response::Alive(msgs::Alive {
+                    timestamp,
+                    fingerprint,
+                    signature,
+                }))
             }
         }
     }
 
-    /// receive a request via the tcpstream.
-    /// wire format: length:u32 data:[u8;length]
-    ///
-    /// TODO: DoS Critical: limit the allowed max length we will attempt to derserialize
-    async fn requested(t: &mut TcpStream) -> Result<msgs::Request, std::io::Error> {
-        let l = t.read_u32().await? as usize;
-        let mut v = vec![0u8; l];
-        t.read_exact(&mut v[..]).await?;
-        Ok(serde_json::from_slice(&v[..])?)
+    /// receive a request via the stream, with its id if the session is
+    /// pipelined, and its deadline if the session is timed and the client
+    /// gave one.
+    /// wire format: see `protocol`, frames are bounded by the session's
+    /// negotiated maximum.
+    async fn requested<S: AsyncRead + Unpin>(
+        &self,
+        t: &mut S,
+        session: &mut protocol::Session,
+    ) -> Result<(Option<u64>, Option<std::time::Instant>, msgs::Request), std::io::Error> {
+        let v = session.read_frame(t).await?;
+        let read = std::time::Instant::now();
+        self.metrics.read(v.len() + 4);
+        let codec = session.codec();
+        let (id, ttl, request) = match (session.pipelined(), session.timed()) {
+            (true, true) => {
+                let msgs::Correlated(id, msgs::Timed(ttl, request)) = codec.decode(&v[..])?;
+                (Some(id), ttl, request)
+            }
+            (true, false) => {
+                let msgs::Correlated(id, request) = codec.decode(&v[..])?;
+                (Some(id), None, request)
+            }
+            (false, true) => {
+                let msgs::Timed(ttl, request) = codec.decode(&v[..])?;
+                (None, ttl, request)
+            }
+            (false, false) => (None, None, codec.decode(&v[..])?),
+        };
+        let deadline = ttl.map(|ms| read + std::time::Duration::from_millis(ms));
+        Ok((id, deadline, request))
     }
 
-    /// respond via the tcpstream.
-    /// wire format: length:u32 data:[u8;length]
-    async fn respond<T: Serialize>(t: &mut TcpStream, r: &T) -> Result<(), std::io::Error> {
-        let v = serde_json::to_vec(r)?;
-        t.write_u32(v.len() as u32).await?;
-        t.write_all(&v[..]).await?;
-        t.flush().await
+    /// respond via the stream, tagged with `id` if the session is pipelined.
+    /// wire format: see `protocol`
+    async fn respond<S: AsyncWrite + Unpin, T: Serialize>(
+        &self,
+        t: &mut S,
+        session: &mut protocol::Session,
+        id: Option<u64>,
+        r: &T,
+    ) -> Result<(), std::io::Error> {
+        let v = match id {
+            Some(id) => session.codec().encode(&msgs::Correlated(id, r))?,
+            None => session.codec().encode(r)?,
+        };
+        session.write_frame(t, &v[..]).await?;
+        self.metrics.written(v.len() + 4);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::derivation::DerivationScheme;
+    use crate::offline::{Outcome, RequestBundle};
+    use bitcoin::{Script, TxOut};
+
+    fn root(seed: u8) -> ExtendedPrivKey {
+        ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[seed; 32]).unwrap()
+    }
+
+    #[test]
+    fn sign_mixed_inputs() {
+        let secp = Secp256k1::new();
+        let other_key = ExtendedPubKey::from_private(&secp, &root(46)).public_key;
+        let root = root(45);
+        let pk_root = ExtendedPubKey::from_private(&secp, &root);
+
+        // inputs 0 and 2 spend two different emulated contracts, input 1
+        // doesn't involve the oracle at all
+        let tx = bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: (0..3u8)
+                .map(|i| bitcoin::TxIn {
+                    previous_output: bitcoin::OutPoint::new(Hash::hash(&[i][..]), i as u32),
+                    script_sig: Script::new(),
+                    sequence: 0xffffffff,
+                    witness: vec![],
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: 290_000_000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let derived: Vec<bitcoin::PublicKey> = (0..3)
+            .map(|i| {
+                let path = DerivationScheme::default().path(tx.get_ctv_hash(i));
+                pk_root.derive_pub(&secp, &path).unwrap().public_key
+            })
+            .collect();
+        // each input's key is derived from its own CTV hash
+        assert_ne!(derived[0], derived[2]);
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        for (idx, key) in [derived[0], other_key, derived[2]].iter().enumerate() {
+            psbt.inputs[idx].witness_utxo = Some(TxOut {
+                value: 100_000_000,
+                script_pubkey: Script::new_v0_wpkh(&key.wpubkey_hash().unwrap()),
+            });
+        }
+        assert!(involves_key(&derived[0], &psbt.inputs[0], &secp));
+        assert!(!involves_key(&derived[1], &psbt.inputs[1], &secp));
+        assert!(!involves_key(&derived[0], &psbt.inputs[2], &secp));
+
+        let oracle = HDOracleEmulator::new(root);
+        let response = oracle
+            .sign_bundle(RequestBundle::new(pk_root, vec![psbt]))
+            .unwrap();
+        let signed = match &response.outcomes[..] {
+            [Outcome::Signed(signed)] => &signed.0,
+            _ => panic!("oracle did not sign the mixed transaction"),
+        };
+        assert!(signed.inputs[0].partial_sigs.contains_key(&derived[0]));
+        assert!(signed.inputs[1].partial_sigs.is_empty());
+        assert!(signed.inputs[2].partial_sigs.contains_key(&derived[2]));
+    }
+
+    #[test]
+    fn sign_miniscript() {
+        use miniscript::{Miniscript, MiniscriptKey, Segwitv0};
+        use std::str::FromStr;
+
+        let secp = Secp256k1::new();
+        let other_key = ExtendedPubKey::from_private(&secp, &root(50)).public_key;
+        let root = root(49);
+        let pk_root = ExtendedPubKey::from_private(&secp, &root);
+
+        let tx = bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![bitcoin::TxIn {
+                previous_output: bitcoin::OutPoint::new(Hash::hash(b"ms"), 0),
+                script_sig: Script::new(),
+                sequence: 10,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value: 90_000_000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let path = DerivationScheme::default().path(tx.get_ctv_hash(0));
+        let derived = pk_root.derive_pub(&secp, &path).unwrap().public_key;
+        // the oracle's key only appears hashed, alongside another key and a
+        // timelock
+        let ms = Miniscript::<bitcoin::PublicKey, Segwitv0>::from_str(&format!(
+            "and_v(v:pk({}),and_v(v:older(10),c:pk_h({})))",
+            other_key,
+            derived.to_pubkeyhash()
+        ))
+        .unwrap();
+        let witness_script = ms.encode();
+        assert!(witness_script_has_key(&witness_script, &derived));
+        assert!(!witness_script_has_key(
+            &witness_script,
+            &pk_root.public_key
+        ));
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: 100_000_000,
+            script_pubkey: witness_script.to_v0_p2wsh(),
+        });
+        psbt.inputs[0].witness_script = Some(witness_script);
+
+        let oracle = HDOracleEmulator::new(root);
+        let response = oracle
+            .sign_bundle(RequestBundle::new(pk_root, vec![psbt]))
+            .unwrap();
+        match &response.outcomes[..] {
+            [Outcome::Signed(signed)] => {
+                assert!(signed.0.inputs[0].partial_sigs.contains_key(&derived))
+            }
+            _ => panic!("oracle did not sign the miniscript input"),
+        }
+    }
+
+    #[test]
+    fn sign_batch_atomically() {
+        let secp = Secp256k1::new();
+        let root = root(53);
+        let pk_root = ExtendedPubKey::from_private(&secp, &root);
+        // `spend(i, value)` spends outpoint `i` to `value`, so spends with
+        // the same value share a CTV hash
+        let spend = |i: u8, value: u64| {
+            let tx = bitcoin::Transaction {
+                version: 2,
+                lock_time: 0,
+                input: vec![bitcoin::TxIn {
+                    previous_output: bitcoin::OutPoint::new(Hash::hash(&[i][..]), 0),
+                    script_sig: Script::new(),
+                    sequence: 0xffffffff,
+                    witness: vec![],
+                }],
+                output: vec![TxOut {
+                    value,
+                    script_pubkey: Script::new(),
+                }],
+            };
+            let path = DerivationScheme::default().path(tx.get_ctv_hash(0));
+            let key = pk_root.derive_pub(&secp, &path).unwrap().public_key;
+            let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+            psbt.inputs[0].witness_utxo = Some(TxOut {
+                value: 100_000_000,
+                script_pubkey: Script::new_v0_wpkh(&key.wpubkey_hash().unwrap()),
+            });
+            psbt
+        };
+        let path = std::env::temp_dir().join(format!("ctve-batch-{}", std::process::id()));
+        let history =
+            Arc::new(history::SigningHistory::open(&path, history::OnConflict::Refuse).unwrap());
+        let oracle = HDOracleEmulator::new(root).with_signing_history(history.clone());
+        oracle.sign_checked(spend(0, 90_000_000)).unwrap();
+
+        // the second spend reuses the first's template for another outpoint
+        let fresh = spend(1, 80_000_000);
+        let hash = fresh.global.unsigned_tx.get_ctv_hash(0);
+        assert!(oracle
+            .sign_batch(vec![fresh.clone(), spend(2, 90_000_000)], None)
+            .is_err());
+        assert_eq!(history.get(&hash).unwrap(), None);
+
+        let signed = oracle.sign_batch(vec![fresh.clone()], None).unwrap();
+        assert_eq!(signed[0].inputs[0].partial_sigs.len(), 1);
+        assert_eq!(
+            history.get(&hash).unwrap(),
+            Some(fresh.global.unsigned_tx.txid())
+        );
+        drop(oracle);
+        drop(history);
+        let _ = std::fs::remove_dir_all(&path);
     }
 }
@@ -0,0 +1,222 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A persistent record of which transaction each CTV hash was signed for.
+//!
+//! The oracle's key for an input depends only on the input's CTV hash, which
+//! does not commit to the outpoints being spent. So a signature request for
+//! a different transaction with the same template is either a client bug or
+//! an attempt to use the oracle's key for something it never agreed to. The
+//! history remembers the first transaction signed for each CTV hash, so that
+//! such requests are refused (or warned on).
+//!
+//! Alongside it, a `SpendLedger` keeps the amounts signed for with each key,
+//! so that a `policy::ConfigPolicy`'s spend cap holds across restarts.
+use super::*;
+use bitcoin::{PublicKey, Txid};
+use sled::transaction::{self, TransactionError, TransactionResult};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// What to do when a CTV hash was signed for a different transaction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnConflict {
+    /// refuse to sign
+    Refuse,
+    /// sign anyways, logging a warning
+    Warn,
+}
+
+/// A sled backed map from CTV hash to the txid signed for it
+pub struct SigningHistory {
+    db: sled::Db,
+    on_conflict: OnConflict,
+    ledger: Arc<SpendLedger>,
+}
+
+impl SigningHistory {
+    /// Opens (or creates) a history at `path`
+    pub fn open<P: AsRef<Path>>(path: P, on_conflict: OnConflict) -> Result<Self, std::io::Error> {
+        let db = sled::open(path)?;
+        let ledger = Arc::new(SpendLedger(Ledger::Sled(db.open_tree("spent")?)));
+        Ok(SigningHistory {
+            db,
+            on_conflict,
+            ledger,
+        })
+    }
+
+    /// the ledger of amounts signed for, kept in the same database
+    pub fn ledger(&self) -> Arc<SpendLedger> {
+        self.ledger.clone()
+    }
+
+    /// if conflicting requests must be refused
+    pub fn refuses(&self) -> bool {
+        self.on_conflict == OnConflict::Refuse
+    }
+
+    /// the transaction first signed for `hash`, if any
+    pub fn get(&self, hash: &Sha256) -> Result<Option<Txid>, std::io::Error> {
+        Ok(match self.db.get(&hash[..])? {
+            Some(v) => Some(Txid::from_slice(&v[..]).or_else(|_| input_error("Corrupt History"))?),
+            None => None,
+        })
+    }
+
+    /// Records that `txid` is being signed for `hashes` (by input index),
+    /// returning the inputs whose hash was signed for a different
+    /// transaction, along with that transaction.
+    ///
+    /// If there are any and conflicts are refused, nothing is recorded.
+    /// Otherwise the conflicting hashes keep their original transaction.
+    pub fn record(
+        &self,
+        txid: Txid,
+        hashes: &[(usize, Sha256)],
+    ) -> Result<Vec<(usize, Txid)>, std::io::Error> {
+        let mut conflicts = self.record_all(&[(txid, hashes.to_vec())])?;
+        Ok(conflicts.pop().unwrap_or_default())
+    }
+
+    /// Records many transactions at once (e.g., a batch), as `record` does,
+    /// returning the conflicts of each.
+    ///
+    /// If there are any and conflicts are refused, none of the transactions
+    /// are recorded. A hash signed for two transactions of the batch
+    /// conflicts as if the first had been recorded before.
+    pub fn record_all(
+        &self,
+        batch: &[(Txid, Vec<(usize, Sha256)>)],
+    ) -> Result<Vec<Vec<(usize, Txid)>>, std::io::Error> {
+        type Conflicts = Vec<Vec<(usize, sled::IVec)>>;
+        let refuses = self.refuses();
+        let recorded: TransactionResult<Conflicts, Conflicts> = self.db.transaction(|db| {
+            let mut conflicts = vec![];
+            for (txid, hashes) in batch {
+                let mut found = vec![];
+                for (input, hash) in hashes {
+                    match db.get(&hash[..])? {
+                        Some(previous) if previous[..] != txid[..] => {
+                            found.push((*input, previous))
+                        }
+                        Some(_) => (),
+                        None => {
+                            db.insert(&hash[..], &txid[..])?;
+                        }
+                    }
+                }
+                conflicts.push(found);
+            }
+            if refuses && conflicts.iter().any(|c| !c.is_empty()) {
+                // rolls back everything inserted above
+                return transaction::abort(conflicts);
+            }
+            Ok(conflicts)
+        });
+        let conflicts = match recorded {
+            Ok(conflicts) | Err(TransactionError::Abort(conflicts)) => conflicts,
+            Err(TransactionError::Storage(e)) => return Err(e.into()),
+        };
+        self.db.flush()?;
+        conflicts
+            .into_iter()
+            .map(|found| {
+                found
+                    .into_iter()
+                    .map(|(input, previous)| {
+                        Txid::from_slice(&previous[..])
+                            .map(|previous| (input, previous))
+                            .or_else(|_| input_error("Corrupt History"))
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// The amounts signed for with each key, by transaction, so that re-signing
+/// a transaction does not count twice.
+///
+/// As a key is derived from a single CTV hash, and the signing history only
+/// lets a hash be signed for one transaction (when conflicts are refused),
+/// a key has an entry for about as many transactions as it signs.
+pub struct SpendLedger(Ledger);
+
+/// where a `SpendLedger` is kept
+enum Ledger {
+    Memory(Mutex<BTreeMap<Vec<u8>, u64>>),
+    Sled(sled::Tree),
+}
+
+/// a ledger entry's key, the signing key followed by the txid
+fn ledger_key(key: &PublicKey, txid: &Txid) -> Vec<u8> {
+    let mut k = key.key.serialize().to_vec();
+    k.extend_from_slice(&txid[..]);
+    k
+}
+
+impl SpendLedger {
+    /// A ledger kept in memory, forgotten when the server stops
+    pub fn in_memory() -> Self {
+        SpendLedger(Ledger::Memory(Mutex::new(BTreeMap::new())))
+    }
+
+    /// the total signed for with `key`, other than in `txids`
+    pub fn spent(&self, key: &PublicKey, txids: &[Txid]) -> Result<u64, std::io::Error> {
+        let prefix = key.key.serialize();
+        let counted = |k: &[u8]| {
+            k.starts_with(&prefix[..]) && !txids.iter().any(|t| k[prefix.len()..] == t[..])
+        };
+        Ok(match &self.0 {
+            Ledger::Memory(m) => m
+                .lock()
+                .unwrap()
+                .range(prefix.to_vec()..)
+                .take_while(|(k, _)| k.starts_with(&prefix[..]))
+                .filter(|(k, _)| counted(&k[..]))
+                .fold(0u64, |sum, (_, v)| sum.saturating_add(*v)),
+            Ledger::Sled(tree) => {
+                let mut sum = 0u64;
+                for entry in tree.scan_prefix(&prefix[..]) {
+                    let (k, v) = entry?;
+                    if counted(&k[..]) {
+                        let v = v[..]
+                            .try_into()
+                            .or_else(|_| input_error("Corrupt Ledger"))?;
+                        sum = sum.saturating_add(u64::from_be_bytes(v));
+                    }
+                }
+                sum
+            }
+        })
+    }
+
+    /// Records that each `(key, txid, value)` was signed for, replacing any
+    /// amount recorded for the same key and transaction. All are recorded,
+    /// or none are.
+    pub fn charge(&self, amounts: &[(PublicKey, Txid, u64)]) -> Result<(), std::io::Error> {
+        match &self.0 {
+            Ledger::Memory(m) => {
+                let mut m = m.lock().unwrap();
+                for (key, txid, value) in amounts {
+                    m.insert(ledger_key(key, txid), *value);
+                }
+            }
+            Ledger::Sled(tree) => {
+                let mut batch = sled::Batch::default();
+                for (key, txid, value) in amounts {
+                    batch.insert(ledger_key(key, txid), &value.to_be_bytes()[..]);
+                }
+                tree.apply_batch(batch)?;
+                tree.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,343 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! JSON-RPC 2.0 over HTTP, so that an oracle can sit behind standard reverse
+//! proxies, load balancers, and auth gateways (see
+//! `HDOracleEmulator::bind_http`).
+//!
+//! Every call is a `POST` (to any path) with a JSON-RPC request as the body.
+//! The method is the name of a `msgs::Request` variant and the params are its
+//! contents, serialized as JSON as they would be over the raw protocol, e.g.
+//!
+//! `{"jsonrpc": "2.0", "id": 1, "method": "SignPSBT", "params": <PSBT>}`
+//!
+//! The result is what the raw protocol would respond with. A
+//! `msgs::ServerError` is returned as an error with code `SERVER_ERROR` and
+//! the `ServerError` as its data, and a request which fails is returned as an
+//! error with code `REQUEST_FAILED`. Batches of calls are not supported, use
+//! `SignBatch` instead.
+//!
+//! If the oracle requires authorization (see [`auth`]), an `msgs::AuthToken`
+//! may be sent with each call in an `Authorization: Bearer <token>` header.
+use super::*;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader};
+
+/// The largest request line and headers we will read
+const MAX_HEAD: u64 = 16 * 1024;
+
+/// The methods which may be called, i.e. the `msgs::Request` variants
+const METHODS: [&str; 9] = [
+    "ConfirmKey",
+    "SignPSBT",
+    "SignBatch",
+    "MusigNonce",
+    "MusigSign",
+    "Authenticate",
+    "DeriveKey",
+    "Verify",
+    "Liveness",
+];
+
+/// The body could not be parsed as JSON
+pub const PARSE_ERROR: i64 = -32700;
+/// The body was not a JSON-RPC request
+pub const INVALID_REQUEST: i64 = -32600;
+/// The method is not a `msgs::Request` variant
+pub const METHOD_NOT_FOUND: i64 = -32601;
+/// The params are not valid for the method
+pub const INVALID_PARAMS: i64 = -32602;
+/// The request could not be served
+pub const REQUEST_FAILED: i64 = -32000;
+/// The server responded with a `msgs::ServerError`
+pub const SERVER_ERROR: i64 = -32001;
+
+#[derive(Deserialize)]
+struct RpcRequest {
+    jsonrpc: String,
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Serialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<msgs::ServerError>,
+}
+
+impl RpcError {
+    fn new<M: ToString>(code: i64, message: M) -> Self {
+        RpcError {
+            code,
+            message: message.to_string(),
+            data: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RpcResponse {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<msgs::Response>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+impl RpcResponse {
+    fn result(id: Value, result: msgs::Response) -> Self {
+        RpcResponse {
+            jsonrpc: "2.0",
+            id,
+            result: Some(result),
+            error: None,
+        }
+    }
+    fn error(id: Value, error: RpcError) -> Self {
+        RpcResponse {
+            jsonrpc: "2.0",
+            id,
+            result: None,
+            error: Some(error),
+        }
+    }
+}
+
+/// Parses a JSON-RPC call into its id and request, or the id (if known) and
+/// the error to respond with.
+fn parse(body: &[u8]) -> Result<(Value, msgs::Request), (Value, RpcError)> {
+    let value: Value =
+        serde_json::from_slice(body).map_err(|e| (Value::Null, RpcError::new(PARSE_ERROR, e)))?;
+    let call: RpcRequest = serde_json::from_value(value)
+        .map_err(|e| (Value::Null, RpcError::new(INVALID_REQUEST, e)))?;
+    let id = call.id;
+    if call.jsonrpc != "2.0" {
+        return Err((
+            id,
+            RpcError::new(INVALID_REQUEST, "Unsupported JSON-RPC Version"),
+        ));
+    }
+    if !METHODS.contains(&&call.method[..]) {
+        return Err((id, RpcError::new(METHOD_NOT_FOUND, "Unknown Method")));
+    }
+    // msgs::Request is externally tagged, i.e. {"Method": params}
+    let mut request = serde_json::Map::new();
+    request.insert(call.method, call.params);
+    match serde_json::from_value(Value::Object(request)) {
+        Ok(request) => Ok((id, request)),
+        Err(e) => Err((id, RpcError::new(INVALID_PARAMS, e))),
+    }
+}
+
+/// The request line and headers we care about
+struct Head {
+    method: String,
+    content_length: Option<usize>,
+    chunked: bool,
+    close: bool,
+    token: Option<msgs::AuthToken>,
+}
+
+/// A call read from a connection, which must be responded to.
+pub(crate) struct Call {
+    id: Value,
+    /// if the client asked for the connection to be closed after responding
+    pub(crate) close: bool,
+    /// the token in the `Authorization` header, if any
+    pub(crate) token: Option<msgs::AuthToken>,
+}
+
+/// A HTTP/1.1 connection carrying JSON-RPC calls, one at a time.
+pub(crate) struct Connection<S> {
+    stream: BufReader<S>,
+    max_body: usize,
+    metrics: Arc<metrics::Metrics>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    pub(crate) fn new(stream: S, max_body: usize, metrics: Arc<metrics::Metrics>) -> Self {
+        Connection {
+            stream: BufReader::new(stream),
+            max_body,
+            metrics,
+        }
+    }
+
+    /// Reads the next call.
+    ///
+    /// Calls which are not valid JSON-RPC are responded to here. Returns None
+    /// once the connection should be closed, e.g. because the client closed
+    /// it or sent something other than a JSON-RPC `POST`.
+    pub(crate) async fn next(&mut self) -> std::io::Result<Option<(Call, msgs::Request)>> {
+        loop {
+            let head = match self.head().await? {
+                Some(head) => head,
+                None => return Ok(None),
+            };
+            if head.method != "POST" {
+                self.status("405 Method Not Allowed").await?;
+                return Ok(None);
+            }
+            let len = match head.content_length {
+                _ if head.chunked => None,
+                Some(len) if len > self.max_body => {
+                    self.status("413 Payload Too Large").await?;
+                    return Ok(None);
+                }
+                len => len,
+            };
+            let len = match len {
+                Some(len) => len,
+                None => {
+                    self.status("411 Length Required").await?;
+                    return Ok(None);
+                }
+            };
+            let mut body = vec![0u8; len];
+            self.stream.read_exact(&mut body[..]).await?;
+            self.metrics.read(len);
+            match parse(&body[..]) {
+                Ok((id, request)) => {
+                    return Ok(Some((
+                        Call {
+                            id,
+                            close: head.close,
+                            token: head.token,
+                        },
+                        request,
+                    )))
+                }
+                Err((id, error)) => {
+                    tracing::debug!(code = error.code, message = %error.message, "invalid call");
+                    self.send(&RpcResponse::error(id, error), head.close).await?;
+                    if head.close {
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Responds to `call` with the outcome of handling it.
+    pub(crate) async fn respond(
+        &mut self,
+        call: Call,
+        reply: Result<msgs::Response, std::io::Error>,
+    ) -> std::io::Result<()> {
+        let response = match reply {
+            Ok(msgs::Response::Error(e)) => RpcResponse::error(
+                call.id,
+                RpcError {
+                    code: SERVER_ERROR,
+                    message: e.to_string(),
+                    data: Some(e),
+                },
+            ),
+            Ok(reply) => RpcResponse::result(call.id, reply),
+            Err(e) => RpcResponse::error(call.id, RpcError::new(REQUEST_FAILED, e)),
+        };
+        self.send(&response, call.close).await
+    }
+
+    /// Responds that the server can't serve the connection right now
+    pub(crate) async fn unavailable(&mut self) -> std::io::Result<()> {
+        self.status("503 Service Unavailable").await
+    }
+
+    /// reads the request line and headers, None if the connection closed
+    /// before a request.
+    async fn head(&mut self) -> std::io::Result<Option<Head>> {
+        let mut limited = (&mut self.stream).take(MAX_HEAD);
+        let mut line = String::new();
+        if limited.read_line(&mut line).await? == 0 {
+            return Ok(None);
+        }
+        let mut parts = line.split_whitespace();
+        let mut head = Head {
+            method: parts.next().unwrap_or("").to_string(),
+            content_length: None,
+            chunked: false,
+            close: parts.nth(1) == Some("HTTP/1.0"),
+            token: None,
+        };
+        loop {
+            line.clear();
+            if limited.read_line(&mut line).await? == 0 {
+                return input_error("Truncated HTTP Request");
+            }
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let (name, value) = match header.split_once(':') {
+                Some((name, value)) => (name.trim().to_ascii_lowercase(), value.trim()),
+                None => return input_error("Invalid HTTP Header"),
+            };
+            match &name[..] {
+                "content-length" => {
+                    head.content_length = Some(
+                        value
+                            .parse()
+                            .or_else(|_| input_error("Invalid Content-Length"))?,
+                    )
+                }
+                "transfer-encoding" => head.chunked = true,
+                // a malformed token is treated as no token
+                "authorization" => {
+                    head.token = value
+                        .strip_prefix("Bearer ")
+                        .and_then(|t| t.trim().parse().ok())
+                }
+                "connection" => {
+                    let value = value.to_ascii_lowercase();
+                    if value.contains("close") {
+                        head.close = true;
+                    } else if value.contains("keep-alive") {
+                        head.close = false;
+                    }
+                }
+                _ => (),
+            }
+        }
+        self.metrics.read((MAX_HEAD - limited.limit()) as usize);
+        Ok(Some(head))
+    }
+
+    /// sends a JSON-RPC response
+    async fn send(&mut self, response: &RpcResponse, close: bool) -> std::io::Result<()> {
+        let body = serde_json::to_vec(response)?;
+        let head = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}\r\n",
+            body.len(),
+            if close { "Connection: close\r\n" } else { "" }
+        );
+        self.write(&[head.as_bytes(), &body[..]].concat()).await
+    }
+
+    /// sends an empty response with `status`, after which the connection is
+    /// closed.
+    async fn status(&mut self, status: &str) -> std::io::Result<()> {
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            status
+        );
+        self.write(response.as_bytes()).await?;
+        self.stream.get_mut().shutdown().await
+    }
+
+    async fn write(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.stream.get_mut().write_all(bytes).await?;
+        self.metrics.written(bytes.len());
+        Ok(())
+    }
+}
@@ -0,0 +1,131 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A `Signer` backed by a hardware wallet, driven via the
+//! [HWI](https://github.com/bitcoin-core/HWI) command line tool.
+//!
+//! The oracle's xprv never leaves the device. Note that each signature
+//! requires a round trip to the device (and possibly a button press), so
+//! this backend is much slower than a `LocalSigner`.
+use super::signer::{InputKind, Signer, SigningInput};
+use super::*;
+use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::secp256k1::{Message, Signature};
+use bitcoin::TxOut;
+use serde_derive::Deserialize;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Output of `hwi getxpub`
+#[derive(Deserialize)]
+struct GetXpub {
+    xpub: ExtendedPubKey,
+}
+/// Output of `hwi signtx`
+#[derive(Deserialize)]
+struct SignTx {
+    psbt: String,
+}
+/// Output of `hwi` on failure
+#[derive(Deserialize)]
+struct HWIError {
+    error: String,
+}
+
+/// A `Signer` which delegates to the device with `fingerprint`.
+///
+/// Only segwit v0 inputs are supported, and the device can't sign the
+/// challenge of a `ConfirmKey` request as it only signs transactions.
+pub struct HWISigner {
+    hwi: PathBuf,
+    fingerprint: Fingerprint,
+    xpub: ExtendedPubKey,
+}
+
+/// run a hwi command against the device with `fingerprint` and parse the
+/// JSON it prints
+fn run<T: serde::de::DeserializeOwned>(
+    hwi: &Path,
+    fingerprint: Fingerprint,
+    args: &[&str],
+) -> Result<T, std::io::Error> {
+    let out = Command::new(hwi)
+        .arg("--fingerprint")
+        .arg(fingerprint.to_string())
+        .args(args)
+        .output()?;
+    if let Ok(e) = serde_json::from_slice::<HWIError>(&out.stdout[..]) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("HWI: {}", e.error),
+        ));
+    }
+    Ok(serde_json::from_slice(&out.stdout[..])?)
+}
+
+impl HWISigner {
+    /// connect to the device with `fingerprint`, using the `hwi` executable
+    /// at `hwi`, and fetch its root xpub.
+    pub fn new(hwi: PathBuf, fingerprint: Fingerprint) -> Result<Self, std::io::Error> {
+        let xpub = run::<GetXpub>(&hwi, fingerprint, &["getxpub", "m"])?.xpub;
+        if xpub.fingerprint() != fingerprint {
+            return input_error("Device Fingerprint Mismatch");
+        }
+        Ok(HWISigner {
+            hwi,
+            fingerprint,
+            xpub,
+        })
+    }
+}
+
+impl Signer for HWISigner {
+    fn xpub(&self) -> ExtendedPubKey {
+        self.xpub
+    }
+    /// Annotates each input with the derivation of its key, so the device
+    /// knows what to sign with, and copies back only the signatures requested.
+    fn sign_psbt(
+        &self,
+        mut psbt: PartiallySignedTransaction,
+        inputs: &[SigningInput],
+        _prevouts: Option<&[TxOut]>,
+    ) -> Result<PartiallySignedTransaction, std::io::Error> {
+        let mut annotated = psbt.clone();
+        for signing in inputs.iter() {
+            if let InputKind::Taproot(_) = signing.kind {
+                return input_error("HWI Signer Does Not Support Taproot");
+            }
+            annotated.inputs[signing.input].bip32_derivation.insert(
+                signing.key,
+                (self.fingerprint, DerivationPath::from(signing.path.clone())),
+            );
+        }
+        let encoded = base64::encode(&serialize(&annotated));
+        let response = run::<SignTx>(&self.hwi, self.fingerprint, &["signtx", &encoded])?;
+        let signed: PartiallySignedTransaction = base64::decode(&response.psbt)
+            .ok()
+            .and_then(|v| deserialize(&v[..]).ok())
+            .ok_or_else(|| input_error::<()>("HWI Returned an Invalid PSBT").unwrap_err())?;
+        if signed.inputs.len() != psbt.inputs.len() {
+            return input_error("HWI Returned an Invalid PSBT");
+        }
+        for signing in inputs.iter() {
+            match signed.inputs[signing.input].partial_sigs.get(&signing.key) {
+                Some(sig) => {
+                    psbt.inputs[signing.input]
+                        .partial_sigs
+                        .insert(signing.key, sig.clone());
+                }
+                None => return input_error("Device Did Not Sign"),
+            }
+        }
+        Ok(psbt)
+    }
+    fn sign_challenge(&self, _msg: &Message) -> Result<Signature, std::io::Error> {
+        input_error("HWI Signer Can Not Sign Challenges")
+    }
+}
@@ -0,0 +1,116 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The set of root keys an oracle signs with, and their rotation.
+//!
+//! Contracts commit to keys derived from whichever root was in use when they
+//! were compiled, so an oracle keeps signing with older roots after a new one
+//! is added. Clients indicate the root a PSBT input needs with the
+//! fingerprint in its `bip32_derivation`, otherwise the primary root is used.
+//!
+//! Roots become active, and may be retired, at a block height. The oracle
+//! does not follow the chain itself, so the operator is expected to keep the
+//! height up to date with `KeyRing::set_height`.
+use super::signer::Signer;
+use super::*;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::RwLock;
+
+/// A root key and the heights it is valid for
+#[derive(Clone)]
+pub struct RootKey {
+    /// the backend holding the key
+    pub signer: Arc<dyn Signer>,
+    /// the height from which this root is used for new contracts
+    pub active_from: u32,
+    /// the height from which this root may no longer sign, if retired
+    pub retired_at: Option<u32>,
+}
+
+impl RootKey {
+    /// the fingerprint of the root xpub
+    pub fn fingerprint(&self) -> Fingerprint {
+        self.signer.xpub().fingerprint()
+    }
+    /// if the root may sign at `height`
+    pub fn usable_at(&self, height: u32) -> bool {
+        self.retired_at.map_or(true, |r| height < r)
+    }
+}
+
+/// The root keys of an oracle, shared between the server and the operator.
+pub struct KeyRing {
+    roots: RwLock<Vec<RootKey>>,
+    height: AtomicU32,
+}
+
+impl KeyRing {
+    /// create a KeyRing with a single root, active from genesis
+    pub fn new(signer: Arc<dyn Signer>) -> Self {
+        KeyRing {
+            roots: RwLock::new(vec![RootKey {
+                signer,
+                active_from: 0,
+                retired_at: None,
+            }]),
+            height: AtomicU32::new(0),
+        }
+    }
+    /// add a root, which becomes the primary root from `active_from`
+    pub fn add(&self, signer: Arc<dyn Signer>, active_from: u32) {
+        self.roots.write().unwrap().push(RootKey {
+            signer,
+            active_from,
+            retired_at: None,
+        });
+    }
+    /// stop signing with the root with `fingerprint` from `height`, e.g.
+    /// because it was compromised
+    pub fn retire(&self, fingerprint: Fingerprint, height: u32) -> Result<(), std::io::Error> {
+        let mut roots = self.roots.write().unwrap();
+        match roots.iter_mut().find(|r| r.fingerprint() == fingerprint) {
+            Some(root) => {
+                root.retired_at = Some(height);
+                Ok(())
+            }
+            None => input_error("Unknown Root Fingerprint"),
+        }
+    }
+    /// update the current block height
+    pub fn set_height(&self, height: u32) {
+        self.height.store(height, Ordering::SeqCst);
+    }
+    /// the current block height, as last set
+    pub fn height(&self) -> u32 {
+        self.height.load(Ordering::SeqCst)
+    }
+    /// The root used when a PSBT does not say which to use: the most recently
+    /// activated root which is not retired.
+    pub fn primary(&self) -> Option<RootKey> {
+        let height = self.height();
+        self.roots
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|r| r.active_from <= height && r.usable_at(height))
+            .max_by_key(|r| r.active_from)
+            .cloned()
+    }
+    /// the root with `fingerprint`, if it may sign
+    pub fn get(&self, fingerprint: Fingerprint) -> Option<RootKey> {
+        let height = self.height();
+        self.roots
+            .read()
+            .unwrap()
+            .iter()
+            .find(|r| r.fingerprint() == fingerprint && r.usable_at(height))
+            .cloned()
+    }
+    /// every root, including retired ones
+    pub fn roots(&self) -> Vec<RootKey> {
+        self.roots.read().unwrap().clone()
+    }
+}
@@ -0,0 +1,156 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Connection and request limits for oracle servers.
+//!
+//! Besides bounding how much a client may ask of a server, limits bound how
+//! long a connection may sit idle, so that peers which vanish without closing
+//! their connection do not hold on to a task (and connection slot) forever.
+use super::*;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Configurable limits, `None` means unlimited.
+///
+/// The defaults suit long-lived federation links: connections may idle
+/// indefinitely, but TCP keepalive probes detect peers which are gone.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Limits {
+    /// the maximum number of connections served at once
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    /// the maximum number of requests accepted from one IP per minute
+    #[serde(default)]
+    pub requests_per_ip_per_minute: Option<u32>,
+    /// the maximum number of requests served on a single connection
+    #[serde(default)]
+    pub max_requests_per_connection: Option<u64>,
+    /// the longest (in seconds) a client may take to complete the protocol
+    /// handshake (including TLS and Noise) after connecting
+    #[serde(default = "default_handshake_timeout")]
+    pub handshake_timeout_secs: Option<u64>,
+    /// the longest (in seconds) a connection may go without sending a
+    /// request before it is closed
+    #[serde(default)]
+    pub idle_timeout_secs: Option<u64>,
+    /// how long (in seconds) a TCP connection may be idle before keepalive
+    /// probes are sent, `None` disables keepalive
+    #[serde(default = "default_tcp_keepalive")]
+    pub tcp_keepalive_secs: Option<u64>,
+}
+
+fn default_handshake_timeout() -> Option<u64> {
+    Some(30)
+}
+
+fn default_tcp_keepalive() -> Option<u64> {
+    Some(60)
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_connections: None,
+            requests_per_ip_per_minute: None,
+            max_requests_per_connection: None,
+            handshake_timeout_secs: default_handshake_timeout(),
+            idle_timeout_secs: None,
+            tcp_keepalive_secs: default_tcp_keepalive(),
+        }
+    }
+}
+
+/// Runs `f`, failing with `ErrorKind::TimedOut` (and `msg`) if it takes
+/// longer than `limit`.
+pub(crate) async fn within<T, F>(limit: Option<Duration>, msg: &str, f: F) -> std::io::Result<T>
+where
+    F: std::future::Future<Output = std::io::Result<T>>,
+{
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, f)
+            .await
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, msg))?,
+        None => f.await,
+    }
+}
+
+/// Enforces a set of `Limits` across all of a server's connections.
+pub struct Limiter {
+    limits: Limits,
+    connections: Option<Arc<Semaphore>>,
+    /// start of the current one minute window and the count within it, per IP
+    windows: Mutex<HashMap<IpAddr, (Instant, u32)>>,
+}
+
+impl Limiter {
+    /// create a new limiter enforcing `limits`
+    pub fn new(limits: Limits) -> Self {
+        Limiter {
+            connections: limits.max_connections.map(|n| Arc::new(Semaphore::new(n))),
+            limits,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// how long a client may take to complete its handshake
+    pub fn handshake_timeout(&self) -> Option<Duration> {
+        self.limits.handshake_timeout_secs.map(Duration::from_secs)
+    }
+
+    /// how long a connection may wait for its next request
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.limits.idle_timeout_secs.map(Duration::from_secs)
+    }
+
+    /// Enables TCP keepalive on `socket`, if configured.
+    pub fn keepalive(&self, socket: &TcpStream) -> std::io::Result<()> {
+        if let Some(secs) = self.limits.tcp_keepalive_secs {
+            let keepalive = socket2::TcpKeepalive::new().with_time(Duration::from_secs(secs));
+            socket2::SockRef::from(socket).set_tcp_keepalive(&keepalive)?;
+        }
+        Ok(())
+    }
+
+    /// Attempt to reserve a slot for a new connection. `Ok(None)` means
+    /// connections are not limited; the permit should be held until the
+    /// connection closes.
+    pub fn connection(&self) -> Result<Option<OwnedSemaphorePermit>, msgs::ServerError> {
+        match &self.connections {
+            Some(s) => s
+                .clone()
+                .try_acquire_owned()
+                .map(Some)
+                .map_err(|_| msgs::ServerError::TooManyConnections),
+            None => Ok(None),
+        }
+    }
+
+    /// Check if the `n`th request (counting from 1) on a connection from
+    /// `peer` may be served.
+    pub fn request(&self, peer: Option<IpAddr>, n: u64) -> Result<(), msgs::ServerError> {
+        if let Some(max) = self.limits.max_requests_per_connection {
+            if n > max {
+                return Err(msgs::ServerError::RequestLimitReached);
+            }
+        }
+        if let (Some(max), Some(ip)) = (self.limits.requests_per_ip_per_minute, peer) {
+            let now = Instant::now();
+            let mut windows = self.windows.lock().unwrap();
+            // drop stale windows so the map doesn't grow without bound
+            windows.retain(|_, (start, _)| now.duration_since(*start) < Duration::from_secs(60));
+            let (_, count) = windows.entry(ip).or_insert((now, 0));
+            if *count >= max {
+                return Err(msgs::ServerError::RateLimited);
+            }
+            *count += 1;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,186 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Metrics for an oracle server, exposed in the Prometheus text format.
+//!
+//! Every server keeps `Metrics`; to scrape them, share one with
+//! `HDOracleEmulator::with_metrics` and run `Metrics::bind` alongside the
+//! server.
+use super::*;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds (in seconds) of the signing latency histogram buckets
+const LATENCY_BUCKETS: [f64; 8] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// The request types, in the order they are counted
+const REQUEST_TYPES: [&str; 10] = [
+    "confirm_key",
+    "sign_psbt",
+    "sign_batch",
+    "musig_nonce",
+    "musig_sign",
+    "authenticate",
+    "derive_key",
+    "verify",
+    "liveness",
+    "sign_anchor",
+];
+
+fn request_type(r: &msgs::Request) -> usize {
+    match r {
+        msgs::Request::ConfirmKey(_) => 0,
+        msgs::Request::SignPSBT(_) => 1,
+        msgs::Request::SignBatch(_) => 2,
+        msgs::Request::MusigNonce(_) => 3,
+        msgs::Request::MusigSign(_) => 4,
+        msgs::Request::Authenticate(_) => 5,
+        msgs::Request::DeriveKey(_) => 6,
+        msgs::Request::Verify(_) => 7,
+        msgs::Request::Liveness(_) => 8,
+        msgs::Request::SignAnchor(_) => 9,
+    }
+}
+
+/// Counters and histograms for a server
+#[derive(Default)]
+pub struct Metrics {
+    requests: [AtomicU64; 10],
+    request_errors: AtomicU64,
+    derivation_failures: AtomicU64,
+    connections_accepted: AtomicU64,
+    connections_open: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    /// per bucket (not cumulative), with a final bucket for +Inf
+    signing_latency: [AtomicU64; 9],
+    signing_latency_sum_us: AtomicU64,
+}
+
+/// Decrements the open connection gauge when dropped
+pub(crate) struct OpenConnection(Arc<Metrics>);
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.connections_open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub(crate) fn connection(self: &Arc<Self>) -> OpenConnection {
+        self.connections_accepted.fetch_add(1, Ordering::Relaxed);
+        self.connections_open.fetch_add(1, Ordering::Relaxed);
+        OpenConnection(self.clone())
+    }
+    pub(crate) fn request(&self, r: &msgs::Request) {
+        self.requests[request_type(r)].fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn request_error(&self) {
+        self.request_errors.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn derivation_failure(&self) {
+        self.derivation_failures.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn read(&self, n: usize) {
+        self.bytes_read.fetch_add(n as u64, Ordering::Relaxed);
+    }
+    pub(crate) fn written(&self, n: usize) {
+        self.bytes_written.fetch_add(n as u64, Ordering::Relaxed);
+    }
+    pub(crate) fn signed(&self, latency: Duration) {
+        let secs = latency.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|b| secs <= *b)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.signing_latency[bucket].fetch_add(1, Ordering::Relaxed);
+        self.signing_latency_sum_us
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// the metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut s = String::new();
+        let get = |a: &AtomicU64| a.load(Ordering::Relaxed);
+        // Writes to a String can't fail
+        let _ = writeln!(s, "# TYPE oracle_requests_total counter");
+        for (name, count) in REQUEST_TYPES.iter().zip(self.requests.iter()) {
+            let _ = writeln!(s, "oracle_requests_total{{type=\"{}\"}} {}", name, get(count));
+        }
+        for (name, kind, value) in [
+            ("oracle_request_errors_total", "counter", &self.request_errors),
+            (
+                "oracle_derivation_failures_total",
+                "counter",
+                &self.derivation_failures,
+            ),
+            (
+                "oracle_connections_accepted_total",
+                "counter",
+                &self.connections_accepted,
+            ),
+            ("oracle_connections_open", "gauge", &self.connections_open),
+            ("oracle_bytes_read_total", "counter", &self.bytes_read),
+            ("oracle_bytes_written_total", "counter", &self.bytes_written),
+        ]
+        .iter()
+        {
+            let _ = writeln!(s, "# TYPE {} {}", name, kind);
+            let _ = writeln!(s, "{} {}", name, get(value));
+        }
+        let _ = writeln!(s, "# TYPE oracle_signing_latency_seconds histogram");
+        let mut cumulative = 0;
+        for (i, count) in self.signing_latency.iter().enumerate() {
+            cumulative += get(count);
+            let le = LATENCY_BUCKETS
+                .get(i)
+                .map(|b| b.to_string())
+                .unwrap_or_else(|| "+Inf".into());
+            let _ = writeln!(
+                s,
+                "oracle_signing_latency_seconds_bucket{{le=\"{}\"}} {}",
+                le, cumulative
+            );
+        }
+        let _ = writeln!(
+            s,
+            "oracle_signing_latency_seconds_sum {}",
+            get(&self.signing_latency_sum_us) as f64 / 1e6
+        );
+        let _ = writeln!(s, "oracle_signing_latency_seconds_count {}", cumulative);
+        s
+    }
+
+    /// Serves the metrics over HTTP at `a`, responding to every request with
+    /// `render()`. Only returns if the listener fails.
+    pub async fn bind<A: ToSocketAddrs>(self: Arc<Self>, a: A) -> std::io::Result<()> {
+        let listener = TcpListener::bind(a).await?;
+        loop {
+            let (mut socket, _) = listener.accept().await?;
+            let this = self.clone();
+            tokio::spawn(async move {
+                // read (and ignore) the request head, within reason
+                let mut head = vec![];
+                let mut buf = [0u8; 1024];
+                while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < 8192 {
+                    let n = socket.read(&mut buf).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    head.extend_from_slice(&buf[..n]);
+                }
+                let body = this.render();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                socket.write_all(response.as_bytes()).await?;
+                socket.shutdown().await
+            });
+        }
+    }
+}
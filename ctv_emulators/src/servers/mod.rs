@@ -5,4 +5,25 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use super::*;
+pub mod audit;
+pub mod auth;
+pub mod coordinator;
 pub mod hd;
+pub mod history;
+pub mod http;
+#[cfg(feature = "hwi")]
+pub mod hwi;
+pub mod keys;
+pub mod limits;
+pub mod metrics;
+pub mod policy;
+pub mod reload;
+pub mod seed;
+pub mod remote;
+pub mod shutdown;
+pub mod signer;
+pub mod taproot;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod tor;
+pub mod watch;
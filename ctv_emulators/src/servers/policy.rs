@@ -0,0 +1,706 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Policies an oracle server consults before releasing signatures.
+use super::*;
+use bitcoin::blockdata::transaction::SigHashType;
+use bitcoin::{PublicKey, Script, Txid};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Mutex, RwLock};
+
+/// The largest anchor (in sats) an oracle spends to fees by default
+pub const DEFAULT_MAX_ANCHOR_VALUE: u64 = 10_000;
+
+/// The reasons a policy may refuse to sign a transaction.
+#[derive(Debug, Clone)]
+pub enum PolicyViolation {
+    /// An output pays more than the maximum allowed value (in sats)
+    OutputTooLarge {
+        /// the output's index
+        vout: usize,
+        /// the output's value
+        value: u64,
+    },
+    /// An output uses a script type that is not permitted
+    DisallowedScript(Script),
+    /// The transaction's nLockTime is out of the permitted range
+    LockTimeOutOfRange(u32),
+    /// An input's nSequence is out of the permitted range
+    SequenceOutOfRange {
+        /// the input's index
+        input: usize,
+        /// the input's sequence
+        sequence: u32,
+    },
+    /// Signing would exceed the amount allowed to be spent by a key
+    SpendCapExceeded(PublicKey),
+    /// An input requests a sighash type the oracle may not sign with
+    DisallowedSighash {
+        /// the input's index
+        input: usize,
+        /// the requested sighash type
+        sighash: SigHashType,
+    },
+    /// An input's CTV hash was already signed for a different transaction,
+    /// see [`history`]
+    DoubleSign {
+        /// the input's index
+        input: usize,
+        /// the transaction previously signed
+        previous: Txid,
+    },
+    /// An anchor is worth more than the oracle may spend to fees
+    AnchorTooLarge {
+        /// the anchor's value
+        value: u64,
+        /// the most the oracle may spend
+        max: u64,
+    },
+    /// A child spending an anchor pays out more than its other inputs, so
+    /// part of the anchor would not go to fees
+    AnchorNotSpentToFees {
+        /// the total value of the child's outputs
+        outputs: u64,
+        /// the total value of the child's inputs other than the anchor
+        inputs: u64,
+    },
+    /// An input of a child spending an anchor does not carry the transaction
+    /// it spends, so its value can't be trusted, see `check_anchor`
+    AnchorInputUnproven(usize),
+}
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for PolicyViolation {}
+
+/// Carries the violation to the client as a `msgs::ServerError::PolicyRejected`
+impl From<PolicyViolation> for std::io::Error {
+    fn from(v: PolicyViolation) -> Self {
+        std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            msgs::ServerError::PolicyRejected(v.to_string()),
+        )
+    }
+}
+
+/// `OraclePolicy` is consulted by the server after it has determined which
+/// inputs it would sign, but before any signature is released.
+pub trait OraclePolicy: Send + Sync {
+    /// Checks that the oracle may sign `psbt`. `signing` lists each input index
+    /// the oracle would sign along with the derived key used for it.
+    ///
+    /// Policies which track state (e.g., spend caps) should not update it
+    /// here, see `commit`.
+    fn check(
+        &self,
+        psbt: &PartiallySignedTransaction,
+        signing: &[(usize, PublicKey)],
+    ) -> Result<(), PolicyViolation>;
+    /// Records that the oracle is about to release its signatures on every
+    /// PSBT of `signed` (along with the inputs signed, as for `check`), once
+    /// `check` and the signing history have passed for all of them.
+    ///
+    /// Policies which track state (e.g., spend caps) update it here, for all
+    /// of the PSBTs or for none of them, and may still refuse if the state
+    /// changed since `check` (e.g., by a concurrent request). Nothing is
+    /// recorded by default.
+    fn commit(
+        &self,
+        _signed: &[(&PartiallySignedTransaction, &[(usize, PublicKey)])],
+    ) -> Result<Result<(), PolicyViolation>, std::io::Error> {
+        Ok(Ok(()))
+    }
+    /// If the oracle may sign an input with `sighash`, as requested by the
+    /// PSBT. Checked before signing.
+    ///
+    /// Only SIGHASH_ALL is permitted by default, as anything weaker lets parts
+    /// of the transaction be changed after the oracle signs it.
+    fn allows_sighash(&self, sighash: SigHashType) -> bool {
+        sighash == SigHashType::All
+    }
+    /// The most (in sats) the oracle may spend to fees from an anchor, see
+    /// `msgs::Request::SignAnchor`.
+    fn max_anchor_value(&self) -> u64 {
+        DEFAULT_MAX_ANCHOR_VALUE
+    }
+}
+
+/// Checks that input `anchor` of `psbt`, a child spending an anchor output,
+/// is worth at most `max` and goes entirely to fees.
+///
+/// The anchor's value is taken from its `witness_utxo`, which its (segwit
+/// v0) signature commits to. That signature commits to no other input's
+/// value though, so every other input must carry the transaction it spends
+/// (`non_witness_utxo`) for its value to be trusted.
+pub fn check_anchor(
+    psbt: &PartiallySignedTransaction,
+    anchor: usize,
+    max: u64,
+) -> Result<(), PolicyViolation> {
+    let value = psbt.inputs[anchor]
+        .witness_utxo
+        .as_ref()
+        .map_or(0, |u| u.value);
+    if value > max {
+        return Err(PolicyViolation::AnchorTooLarge { value, max });
+    }
+    let mut inputs = 0u64;
+    let spent = psbt.global.unsigned_tx.input.iter().zip(psbt.inputs.iter());
+    for (idx, (txin, input)) in spent.enumerate() {
+        if idx == anchor {
+            continue;
+        }
+        let prevout = txin.previous_output;
+        let utxo = input
+            .non_witness_utxo
+            .as_ref()
+            .filter(|tx| tx.txid() == prevout.txid)
+            .and_then(|tx| tx.output.get(prevout.vout as usize));
+        match utxo {
+            Some(utxo) => inputs = inputs.saturating_add(utxo.value),
+            None => return Err(PolicyViolation::AnchorInputUnproven(idx)),
+        }
+    }
+    let outputs = psbt
+        .global
+        .unsigned_tx
+        .output
+        .iter()
+        .fold(0u64, |sum, o| sum.saturating_add(o.value));
+    if outputs > inputs {
+        return Err(PolicyViolation::AnchorNotSpentToFees { outputs, inputs });
+    }
+    Ok(())
+}
+
+/// A policy which permits everything, the default.
+///
+/// Note that only SIGHASH_ALL signatures are permitted still.
+pub struct AllowAll;
+impl OraclePolicy for AllowAll {
+    fn check(
+        &self,
+        _psbt: &PartiallySignedTransaction,
+        _signing: &[(usize, PublicKey)],
+    ) -> Result<(), PolicyViolation> {
+        Ok(())
+    }
+}
+
+/// Script types which may be restricted by a `PolicyConfig`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptType {
+    P2PKH,
+    P2SH,
+    P2WPKH,
+    P2WSH,
+    P2TR,
+    OpReturn,
+    NonStandard,
+}
+
+impl ScriptType {
+    /// classify a script
+    pub fn of(s: &Script) -> ScriptType {
+        if s.is_p2pkh() {
+            ScriptType::P2PKH
+        } else if s.is_p2sh() {
+            ScriptType::P2SH
+        } else if s.is_v0_p2wpkh() {
+            ScriptType::P2WPKH
+        } else if s.is_v0_p2wsh() {
+            ScriptType::P2WSH
+        } else if taproot::is_v1_witness(s) {
+            ScriptType::P2TR
+        } else if s.is_op_return() {
+            ScriptType::OpReturn
+        } else {
+            ScriptType::NonStandard
+        }
+    }
+}
+
+/// Sighash types which may be permitted by a `PolicyConfig`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SighashType {
+    All,
+    None,
+    Single,
+    AllPlusAnyoneCanPay,
+    NonePlusAnyoneCanPay,
+    SinglePlusAnyoneCanPay,
+}
+
+impl From<SighashType> for SigHashType {
+    fn from(s: SighashType) -> SigHashType {
+        match s {
+            SighashType::All => SigHashType::All,
+            SighashType::None => SigHashType::None,
+            SighashType::Single => SigHashType::Single,
+            SighashType::AllPlusAnyoneCanPay => SigHashType::AllPlusAnyoneCanPay,
+            SighashType::NonePlusAnyoneCanPay => SigHashType::NonePlusAnyoneCanPay,
+            SighashType::SinglePlusAnyoneCanPay => SigHashType::SinglePlusAnyoneCanPay,
+        }
+    }
+}
+
+/// The rules for a `ConfigPolicy`, all of which are optional.
+/// Ranges are inclusive.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PolicyConfig {
+    /// the maximum value (in sats) of any single output
+    #[serde(default)]
+    pub max_output_value: Option<u64>,
+    /// output script types the oracle will not sign for
+    #[serde(default)]
+    pub disallowed_script_types: Vec<ScriptType>,
+    /// the permitted nLockTime values
+    #[serde(default)]
+    pub lock_time_range: Option<(u32, u32)>,
+    /// the permitted nSequence values for the inputs being signed
+    #[serde(default)]
+    pub sequence_range: Option<(u32, u32)>,
+    /// the maximum total value (in sats) of inputs signed with any one derived key
+    #[serde(default)]
+    pub per_key_spend_cap: Option<u64>,
+    /// the sighash types the oracle may sign with, if empty only `All`
+    #[serde(default)]
+    pub allowed_sighash_types: Vec<SighashType>,
+    /// the most (in sats) the oracle may spend to fees from an anchor, if
+    /// unset `DEFAULT_MAX_ANCHOR_VALUE`
+    #[serde(default)]
+    pub max_anchor_value: Option<u64>,
+}
+
+/// the amounts of `signed` signed for with each key, by transaction
+fn amounts(
+    signed: &[(&PartiallySignedTransaction, &[(usize, PublicKey)])],
+) -> HashMap<PublicKey, HashMap<Txid, u64>> {
+    let mut amounts: HashMap<PublicKey, HashMap<Txid, u64>> = HashMap::new();
+    for (psbt, signing) in signed {
+        let txid = psbt.global.unsigned_tx.txid();
+        let mut this_tx: HashMap<PublicKey, u64> = HashMap::new();
+        for (input, key) in signing.iter() {
+            let value = psbt.inputs[*input]
+                .witness_utxo
+                .as_ref()
+                .map(|u| u.value)
+                .unwrap_or(0);
+            *this_tx.entry(*key).or_insert(0) += value;
+        }
+        for (key, value) in this_tx {
+            amounts.entry(key).or_default().insert(txid, value);
+        }
+    }
+    amounts
+}
+
+/// An `OraclePolicy` driven by a `PolicyConfig`, typically loaded from a
+/// JSON file.
+pub struct ConfigPolicy {
+    config: RwLock<PolicyConfig>,
+    /// amounts signed for per key, per transaction, charged on `commit`
+    spent: Arc<history::SpendLedger>,
+    /// held while charging the spend cap, so that concurrent requests can't
+    /// both fit under it
+    charging: Mutex<()>,
+}
+
+impl ConfigPolicy {
+    /// create a new policy from a config, keeping the amounts signed for
+    /// against the spend cap in memory
+    pub fn new(config: PolicyConfig) -> Self {
+        Self::with_ledger(config, Arc::new(history::SpendLedger::in_memory()))
+    }
+    /// create a new policy from a config, keeping the amounts signed for
+    /// against the spend cap in `ledger` (e.g. a `SigningHistory`'s, so that
+    /// they are kept across restarts)
+    pub fn with_ledger(config: PolicyConfig, ledger: Arc<history::SpendLedger>) -> Self {
+        ConfigPolicy {
+            config: RwLock::new(config),
+            spent: ledger,
+            charging: Mutex::new(()),
+        }
+    }
+    /// load a `PolicyConfig` from a JSON file
+    pub fn from_file<P: AsRef<std::path::Path>>(path: P) -> Result<Self, std::io::Error> {
+        let contents = std::fs::read(path)?;
+        Ok(Self::new(serde_json::from_slice(&contents[..])?))
+    }
+    /// replace the rules, keeping the amounts already signed for against
+    /// the spend cap
+    pub fn set_config(&self, config: PolicyConfig) {
+        *self.config.write().unwrap() = config;
+    }
+    /// the first key `amounts` would take over `cap`, counting what it
+    /// signed for in other transactions
+    fn over_cap(
+        &self,
+        cap: u64,
+        amounts: &HashMap<PublicKey, HashMap<Txid, u64>>,
+    ) -> Result<Option<PublicKey>, std::io::Error> {
+        for (key, by_tx) in amounts {
+            let txids: Vec<Txid> = by_tx.keys().copied().collect();
+            let prior = self.spent.spent(key, &txids[..])?;
+            if by_tx.values().fold(prior, |sum, v| sum.saturating_add(*v)) > cap {
+                return Ok(Some(*key));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl OraclePolicy for ConfigPolicy {
+    fn check(
+        &self,
+        psbt: &PartiallySignedTransaction,
+        signing: &[(usize, PublicKey)],
+    ) -> Result<(), PolicyViolation> {
+        let config = self.config.read().unwrap();
+        let tx = &psbt.global.unsigned_tx;
+        for (vout, out) in tx.output.iter().enumerate() {
+            if let Some(max) = config.max_output_value {
+                if out.value > max {
+                    return Err(PolicyViolation::OutputTooLarge {
+                        vout,
+                        value: out.value,
+                    });
+                }
+            }
+            if config
+                .disallowed_script_types
+                .contains(&ScriptType::of(&out.script_pubkey))
+            {
+                return Err(PolicyViolation::DisallowedScript(out.script_pubkey.clone()));
+            }
+        }
+        if let Some((min, max)) = config.lock_time_range {
+            if tx.lock_time < min || tx.lock_time > max {
+                return Err(PolicyViolation::LockTimeOutOfRange(tx.lock_time));
+            }
+        }
+        if let Some((min, max)) = config.sequence_range {
+            for (input, _) in signing.iter() {
+                let sequence = tx.input[*input].sequence;
+                if sequence < min || sequence > max {
+                    return Err(PolicyViolation::SequenceOutOfRange {
+                        input: *input,
+                        sequence,
+                    });
+                }
+            }
+        }
+        if let Some(cap) = config.per_key_spend_cap {
+            // an unreadable ledger fails the request on `commit`
+            if let Ok(Some(key)) = self.over_cap(cap, &amounts(&[(psbt, signing)])) {
+                return Err(PolicyViolation::SpendCapExceeded(key));
+            }
+        }
+        Ok(())
+    }
+    fn commit(
+        &self,
+        signed: &[(&PartiallySignedTransaction, &[(usize, PublicKey)])],
+    ) -> Result<Result<(), PolicyViolation>, std::io::Error> {
+        let cap = self.config.read().unwrap().per_key_spend_cap;
+        let amounts = amounts(signed);
+        let _charging = self.charging.lock().unwrap();
+        if let Some(cap) = cap {
+            if let Some(key) = self.over_cap(cap, &amounts)? {
+                return Ok(Err(PolicyViolation::SpendCapExceeded(key)));
+            }
+        }
+        let charged: Vec<(PublicKey, Txid, u64)> = amounts
+            .iter()
+            .flat_map(|(key, by_tx)| by_tx.iter().map(move |(txid, v)| (*key, *txid, *v)))
+            .collect();
+        self.spent.charge(&charged[..])?;
+        Ok(Ok(()))
+    }
+    fn allows_sighash(&self, sighash: SigHashType) -> bool {
+        let config = self.config.read().unwrap();
+        if config.allowed_sighash_types.is_empty() {
+            return sighash == SigHashType::All;
+        }
+        config
+            .allowed_sighash_types
+            .iter()
+            .any(|s| SigHashType::from(*s) == sighash)
+    }
+    fn max_anchor_value(&self) -> u64 {
+        let config = self.config.read().unwrap();
+        config.max_anchor_value.unwrap_or(DEFAULT_MAX_ANCHOR_VALUE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::secp256k1::SecretKey;
+    use bitcoin::{Transaction, TxIn, TxOut};
+
+    fn key(i: u8) -> PublicKey {
+        PublicKey {
+            compressed: true,
+            key: bitcoin::secp256k1::PublicKey::from_secret_key(
+                &Secp256k1::new(),
+                &SecretKey::from_slice(&[i; 32]).unwrap(),
+            ),
+        }
+    }
+
+    fn p2wpkh(value: u64) -> TxOut {
+        TxOut {
+            value,
+            script_pubkey: bitcoin::Address::p2wpkh(&key(9), bitcoin::Network::Regtest)
+                .unwrap()
+                .script_pubkey(),
+        }
+    }
+
+    /// a PSBT spending inputs worth `inputs` to `outputs`, each input
+    /// carrying the transaction it spends
+    fn psbt(inputs: &[u64], outputs: Vec<TxOut>) -> PartiallySignedTransaction {
+        let funding: Vec<Transaction> = inputs
+            .iter()
+            .enumerate()
+            .map(|(i, value)| Transaction {
+                version: 2,
+                lock_time: i as u32,
+                input: vec![],
+                output: vec![TxOut {
+                    value: *value,
+                    script_pubkey: Script::new(),
+                }],
+            })
+            .collect();
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: funding
+                .iter()
+                .map(|f| TxIn {
+                    previous_output: bitcoin::OutPoint::new(f.txid(), 0),
+                    script_sig: Script::new(),
+                    sequence: 0xffff_ffff,
+                    witness: vec![],
+                })
+                .collect(),
+            output: outputs,
+        };
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        for (input, f) in psbt.inputs.iter_mut().zip(funding) {
+            input.witness_utxo = Some(f.output[0].clone());
+            input.non_witness_utxo = Some(f);
+        }
+        psbt
+    }
+
+    #[test]
+    fn allow_all() {
+        let psbt = psbt(&[1000], vec![p2wpkh(u64::MAX)]);
+        assert!(AllowAll.check(&psbt, &[(0, key(1))]).is_ok());
+        assert!(AllowAll.allows_sighash(SigHashType::All));
+        assert!(!AllowAll.allows_sighash(SigHashType::None));
+        assert_eq!(AllowAll.max_anchor_value(), DEFAULT_MAX_ANCHOR_VALUE);
+    }
+
+    #[test]
+    fn outputs() {
+        let policy = ConfigPolicy::new(PolicyConfig {
+            max_output_value: Some(1000),
+            disallowed_script_types: vec![ScriptType::OpReturn],
+            ..Default::default()
+        });
+        let signing = [(0, key(1))];
+        assert!(policy
+            .check(&psbt(&[2000], vec![p2wpkh(1000)]), &signing)
+            .is_ok());
+        assert!(matches!(
+            policy.check(&psbt(&[2000], vec![p2wpkh(1000), p2wpkh(1001)]), &signing),
+            Err(PolicyViolation::OutputTooLarge {
+                vout: 1,
+                value: 1001
+            })
+        ));
+        let op_return = TxOut {
+            value: 0,
+            script_pubkey: Script::new_op_return(&[]),
+        };
+        assert!(matches!(
+            policy.check(&psbt(&[2000], vec![op_return]), &signing),
+            Err(PolicyViolation::DisallowedScript(_))
+        ));
+    }
+
+    #[test]
+    fn lock_time_and_sequence() {
+        let policy = ConfigPolicy::new(PolicyConfig {
+            lock_time_range: Some((100, 200)),
+            sequence_range: Some((0, 10)),
+            ..Default::default()
+        });
+        let mut psbt = psbt(&[1000, 1000], vec![p2wpkh(1000)]);
+        psbt.global.unsigned_tx.input[0].sequence = 10;
+        assert!(matches!(
+            policy.check(&psbt, &[(0, key(1))]),
+            Err(PolicyViolation::LockTimeOutOfRange(0))
+        ));
+        psbt.global.unsigned_tx.lock_time = 150;
+        assert!(policy.check(&psbt, &[(0, key(1))]).is_ok());
+        // only the sequences of the inputs being signed are checked
+        assert!(matches!(
+            policy.check(&psbt, &[(0, key(1)), (1, key(1))]),
+            Err(PolicyViolation::SequenceOutOfRange {
+                input: 1,
+                sequence: 0xffff_ffff
+            })
+        ));
+    }
+
+    #[test]
+    fn spend_cap() {
+        let policy = ConfigPolicy::new(PolicyConfig {
+            per_key_spend_cap: Some(1500),
+            ..Default::default()
+        });
+        let first = psbt(&[1000], vec![p2wpkh(900)]);
+        let mut second = first.clone();
+        second.global.unsigned_tx.lock_time = 1;
+        let mut third = first.clone();
+        third.global.unsigned_tx.lock_time = 2;
+        let (one, two) = ([(0, key(1))], [(0, key(2))]);
+        assert!(policy.check(&first, &one).is_ok());
+        // nothing is charged until committed
+        assert!(policy.check(&second, &one).is_ok());
+        assert!(policy.commit(&[(&first, &one[..])]).unwrap().is_ok());
+        // signing the same transaction again doesn't count twice
+        assert!(policy.check(&first, &one).is_ok());
+        assert!(policy.commit(&[(&first, &one[..])]).unwrap().is_ok());
+        assert!(matches!(
+            policy.check(&second, &one),
+            Err(PolicyViolation::SpendCapExceeded(k)) if k == key(1)
+        ));
+        // nor may it be committed, e.g. by a request which raced the first
+        assert!(policy.commit(&[(&second, &one[..])]).unwrap().is_err());
+        // other keys have caps of their own
+        assert!(policy.check(&second, &two).is_ok());
+        // a batch is charged all together, or not at all
+        let batch = [(&second, &two[..]), (&third, &two[..])];
+        assert!(policy.commit(&batch[..]).unwrap().is_err());
+        assert!(policy.commit(&[(&second, &two[..])]).unwrap().is_ok());
+        // and changing the rules keeps what was spent
+        policy.set_config(PolicyConfig {
+            per_key_spend_cap: Some(1999),
+            ..Default::default()
+        });
+        assert!(policy.check(&second, &one).is_err());
+    }
+
+    #[test]
+    fn spend_cap_persists() {
+        let path = std::env::temp_dir().join(format!("ctve-ledger-{}", std::process::id()));
+        let config = PolicyConfig {
+            per_key_spend_cap: Some(1500),
+            ..Default::default()
+        };
+        let first = psbt(&[1000], vec![p2wpkh(900)]);
+        let mut second = first.clone();
+        second.global.unsigned_tx.lock_time = 1;
+        let open = || {
+            let history =
+                history::SigningHistory::open(&path, history::OnConflict::Refuse).unwrap();
+            ConfigPolicy::with_ledger(config.clone(), history.ledger())
+        };
+        let one = [(0, key(1))];
+        let policy = open();
+        assert!(policy.commit(&[(&first, &one[..])]).unwrap().is_ok());
+        drop(policy);
+        // what was spent is remembered by the next server to open it
+        let policy = open();
+        assert!(policy.check(&second, &one).is_err());
+        drop(policy);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn sighash_types() {
+        let policy = ConfigPolicy::new(PolicyConfig::default());
+        assert!(policy.allows_sighash(SigHashType::All));
+        assert!(!policy.allows_sighash(SigHashType::AllPlusAnyoneCanPay));
+        policy.set_config(PolicyConfig {
+            allowed_sighash_types: vec![SighashType::AllPlusAnyoneCanPay],
+            ..Default::default()
+        });
+        assert!(!policy.allows_sighash(SigHashType::All));
+        assert!(policy.allows_sighash(SigHashType::AllPlusAnyoneCanPay));
+    }
+
+    #[test]
+    fn anchors() {
+        assert!(check_anchor(&psbt(&[330, 10_000], vec![p2wpkh(10_000)]), 0, 330).is_ok());
+        assert!(matches!(
+            check_anchor(&psbt(&[20_000, 10_000], vec![p2wpkh(10_000)]), 0, 10_000),
+            Err(PolicyViolation::AnchorTooLarge {
+                value: 20_000,
+                max: 10_000
+            })
+        ));
+        assert!(matches!(
+            check_anchor(&psbt(&[330, 10_000], vec![p2wpkh(10_001)]), 0, 330),
+            Err(PolicyViolation::AnchorNotSpentToFees {
+                outputs: 10_001,
+                inputs: 10_000
+            })
+        ));
+        // the other input's witness_utxo is not what its value is taken from
+        let mut overstated = psbt(&[330, 10_000], vec![p2wpkh(20_000)]);
+        overstated.inputs[1].witness_utxo.as_mut().unwrap().value = 20_000;
+        assert!(matches!(
+            check_anchor(&overstated, 0, 330),
+            Err(PolicyViolation::AnchorNotSpentToFees {
+                outputs: 20_000,
+                inputs: 10_000
+            })
+        ));
+        // nor may it be overstated in the transaction it spends
+        let tx = overstated.inputs[1].non_witness_utxo.as_mut().unwrap();
+        tx.output[0].value = 20_000;
+        assert!(matches!(
+            check_anchor(&overstated, 0, 330),
+            Err(PolicyViolation::AnchorInputUnproven(1))
+        ));
+        overstated.inputs[1].non_witness_utxo = None;
+        assert!(matches!(
+            check_anchor(&overstated, 0, 330),
+            Err(PolicyViolation::AnchorInputUnproven(1))
+        ));
+    }
+
+    #[test]
+    fn config_file() {
+        let config: PolicyConfig = serde_json::from_str(
+            r#"{"max_output_value": 5, "disallowed_script_types": ["P2TR"], "max_anchor_value": 1}"#,
+        )
+        .unwrap();
+        let policy = ConfigPolicy::new(config);
+        assert_eq!(policy.max_anchor_value(), 1);
+        let violation = policy
+            .check(&psbt(&[10], vec![p2wpkh(6)]), &[(0, key(1))])
+            .unwrap_err();
+        // sent to clients as a rejection
+        let e = std::io::Error::from(violation);
+        assert!(matches!(
+            msgs::ServerError::from_io(&e),
+            Some(msgs::ServerError::PolicyRejected(_))
+        ));
+    }
+}
@@ -0,0 +1,153 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Reloading a running oracle's configuration.
+//!
+//! The policy rules, limits, and authorization of a server (see
+//! `HDOracleEmulator::with_reload`) may be replaced while it runs, e.g. on
+//! SIGHUP. Open connections are kept, and use the new configuration from
+//! their next request. Some state starts afresh on reload: rate limit windows
+//! and the count of open connections (so more than `max_connections` may be
+//! open until the older ones close). Amounts signed for against a spend cap
+//! are kept, and across restarts too if the server has a signing history
+//! (see `history::SpendLedger`).
+use super::*;
+use bitcoin::secp256k1::PublicKey;
+use serde_derive::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+/// A value which may be replaced while the server runs, shared by every
+/// connection.
+pub(crate) struct Shared<T: ?Sized>(Arc<RwLock<Arc<T>>>);
+
+impl<T: ?Sized> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Shared(self.0.clone())
+    }
+}
+
+impl<T: ?Sized> Shared<T> {
+    pub(crate) fn new(value: Arc<T>) -> Self {
+        Shared(Arc::new(RwLock::new(value)))
+    }
+    /// the current value
+    pub(crate) fn load(&self) -> Arc<T> {
+        self.0.read().unwrap().clone()
+    }
+    /// replaces the value for every holder
+    pub(crate) fn store(&self, value: Arc<T>) {
+        *self.0.write().unwrap() = value;
+    }
+}
+
+/// The parts of a server's configuration which may be reloaded, typically
+/// read from a JSON file.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ServerConfig {
+    /// the rules for a `policy::ConfigPolicy`, or none to sign anything
+    #[serde(default)]
+    pub policy: Option<policy::PolicyConfig>,
+    /// connection and request limits
+    #[serde(default)]
+    pub limits: limits::Limits,
+    /// only sign for authorized clients, see [`auth`]
+    #[serde(default)]
+    pub require_auth: bool,
+    /// Noise keys of clients authorized without a token
+    #[serde(default)]
+    pub allowed_keys: Vec<PublicKey>,
+    /// keys trusted to issue `msgs::AuthToken`s
+    #[serde(default)]
+    pub issuers: Vec<PublicKey>,
+    /// a `tracing` filter (e.g. `emulator_connect=debug`), applied by the
+    /// binary running the server, which owns the subscriber
+    #[serde(default)]
+    pub log: Option<String>,
+}
+
+impl ServerConfig {
+    /// load a config from a JSON file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, std::io::Error> {
+        let contents = std::fs::read(path)?;
+        Ok(serde_json::from_slice(&contents[..])?)
+    }
+    /// the authenticator described, if clients must be authorized
+    pub fn authenticator(&self) -> Option<auth::Authenticator> {
+        if !self.require_auth {
+            return None;
+        }
+        let auth = self
+            .allowed_keys
+            .iter()
+            .fold(auth::Authenticator::new(), |a, k| a.with_allowed_key(*k));
+        Some(self.issuers.iter().fold(auth, |a, k| a.with_issuer(*k)))
+    }
+}
+
+/// A handle used to replace the configuration of a running server.
+pub struct ReloadHandle {
+    pub(crate) policy: Shared<dyn policy::OraclePolicy>,
+    /// kept across reloads, so spend caps remember what was signed
+    pub(crate) config_policy: Arc<policy::ConfigPolicy>,
+    pub(crate) limits: Shared<limits::Limiter>,
+    pub(crate) auth: Shared<Option<Arc<auth::Authenticator>>>,
+}
+
+impl ReloadHandle {
+    /// replaces the server's policy, limits, and authorization with those
+    /// in `config`
+    pub fn apply(&self, config: &ServerConfig) {
+        match &config.policy {
+            Some(rules) => {
+                self.config_policy.set_config(rules.clone());
+                self.policy.store(self.config_policy.clone());
+            }
+            None => self.policy.store(Arc::new(policy::AllowAll)),
+        }
+        self.limits
+            .store(Arc::new(limits::Limiter::new(config.limits.clone())));
+        self.auth
+            .store(Arc::new(config.authenticator().map(Arc::new)));
+    }
+
+    /// Reloads the config at `path` on every SIGHUP, calling `on_reload`
+    /// with each config before it is applied (e.g. to override it with
+    /// command line flags, or to update the log filter).
+    ///
+    /// A config which can't be read is logged and ignored, keeping the
+    /// current one. Never returns unless the signal can't be listened for.
+    pub async fn reload_on_signal<F>(self, path: PathBuf, on_reload: F) -> std::io::Result<()>
+    where
+        F: Fn(&mut ServerConfig) + Send,
+    {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut hup = signal(SignalKind::hangup())?;
+            while hup.recv().await.is_some() {
+                match ServerConfig::from_file(&path) {
+                    Ok(mut config) => {
+                        on_reload(&mut config);
+                        self.apply(&config);
+                        tracing::info!(path = %path.display(), "configuration reloaded");
+                    }
+                    Err(e) => tracing::warn!(
+                        path = %path.display(),
+                        error = %e,
+                        "could not reload configuration, keeping the current one"
+                    ),
+                }
+            }
+            Ok(())
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = (path, on_reload);
+            input_error("Reloading on SIGHUP Requires Unix")
+        }
+    }
+}
@@ -0,0 +1,153 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A `Signer` which keeps its keys in a remote KMS or HSM.
+//!
+//! The oracle computes every sighash itself and only asks the remote for the
+//! ECDSA operation, so any service which can sign a 32 byte digest with a
+//! BIP-32 child of its key can back an oracle.
+use super::signer::{add_ecdsa_signature, ecdsa_message, InputKind, Signer, SigningInput};
+use super::*;
+use bitcoin::secp256k1::{Message, Signature};
+use bitcoin::TxOut;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// A KMS or HSM which holds the oracle's root key.
+pub trait DigestSigner: Send + Sync {
+    /// Sign `msg` with the child at `path` of the root key, which is the
+    /// root itself if `path` is empty.
+    fn sign_digest(&self, path: &[ChildNumber], msg: &Message)
+        -> Result<Signature, std::io::Error>;
+}
+
+/// A `Signer` which delegates ECDSA to a `DigestSigner`.
+///
+/// Only segwit v0 inputs are supported.
+pub struct RemoteSigner<D> {
+    xpub: ExtendedPubKey,
+    remote: D,
+}
+
+impl<D: DigestSigner> RemoteSigner<D> {
+    /// create a signer for `remote`, whose root public key is `xpub`.
+    ///
+    /// Every signature the remote returns is checked against `xpub`, so a
+    /// misconfigured remote results in errors rather than invalid PSBTs.
+    pub fn new(xpub: ExtendedPubKey, remote: D) -> Self {
+        RemoteSigner { xpub, remote }
+    }
+
+    /// sign with the remote, checking the result
+    fn sign(
+        &self,
+        path: &[ChildNumber],
+        key: &bitcoin::PublicKey,
+        msg: &Message,
+    ) -> Result<Signature, std::io::Error> {
+        let mut signature = self.remote.sign_digest(path, msg)?;
+        // Not every KMS produces low-s signatures, which are required for
+        // standardness.
+        signature.normalize_s();
+        SECP.with(|secp| secp.verify(msg, &signature, &key.key))
+            .or_else(|_| input_error("Remote Signer Produced an Invalid Signature"))?;
+        Ok(signature)
+    }
+}
+
+impl<D: DigestSigner> Signer for RemoteSigner<D> {
+    fn xpub(&self) -> ExtendedPubKey {
+        self.xpub
+    }
+    fn sign_psbt(
+        &self,
+        mut psbt: PartiallySignedTransaction,
+        inputs: &[SigningInput],
+        _prevouts: Option<&[TxOut]>,
+    ) -> Result<PartiallySignedTransaction, std::io::Error> {
+        let tx = psbt.global.unsigned_tx.clone();
+        let mut sighash_cache = bitcoin::util::bip143::SigHashCache::new(&tx);
+        for signing in inputs.iter() {
+            let input = &mut psbt.inputs[signing.input];
+            match &signing.kind {
+                InputKind::Taproot(_) => {
+                    return input_error("Remote Signer Does Not Support Taproot")
+                }
+                InputKind::Ecdsa {
+                    scriptcode,
+                    sighash,
+                } => {
+                    let msg = ecdsa_message(
+                        &mut sighash_cache,
+                        input,
+                        signing.input,
+                        scriptcode,
+                        *sighash,
+                    )?;
+                    let signature = self.sign(&signing.path, &signing.key, &msg)?;
+                    add_ecdsa_signature(input, signing.key, &signature, *sighash);
+                }
+            }
+        }
+        Ok(psbt)
+    }
+    fn sign_challenge(&self, msg: &Message) -> Result<Signature, std::io::Error> {
+        self.sign(&[], &self.xpub.public_key, msg)
+    }
+}
+
+/// A `DigestSigner` which runs an external program for each signature,
+/// e.g., a small wrapper around a cloud KMS CLI or `pkcs11-tool`.
+///
+/// The program is invoked as `program [args..] <path> <digest>`, where path
+/// is a BIP-32 path like `m/1/2/3` and digest is 32 hex encoded bytes. It
+/// must print a DER (or hex encoded DER) signature to stdout and exit
+/// successfully.
+pub struct CommandSigner {
+    program: PathBuf,
+    args: Vec<String>,
+}
+
+impl CommandSigner {
+    /// create a new CommandSigner, with `args` passed before the path and digest
+    pub fn new(program: PathBuf, args: Vec<String>) -> Self {
+        CommandSigner { program, args }
+    }
+}
+
+impl DigestSigner for CommandSigner {
+    fn sign_digest(
+        &self,
+        path: &[ChildNumber],
+        msg: &Message,
+    ) -> Result<Signature, std::io::Error> {
+        let out = Command::new(&self.program)
+            .args(&self.args)
+            .arg(DerivationPath::from(path.to_vec()).to_string())
+            .arg(
+                msg[..]
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<String>(),
+            )
+            .output()?;
+        if !out.status.success() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!(
+                    "Remote Signer Failed: {}",
+                    String::from_utf8_lossy(&out.stderr[..])
+                ),
+            ));
+        }
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        let der = match <Vec<u8> as bitcoin::hashes::hex::FromHex>::from_hex(stdout.trim()) {
+            Ok(v) => v,
+            Err(_) => out.stdout.clone(),
+        };
+        Signature::from_der(&der[..]).or_else(|_| input_error("Remote Signer Returned Bad DER"))
+    }
+}
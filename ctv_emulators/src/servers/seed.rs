@@ -0,0 +1,440 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Loading an oracle's root seed, so it need not be stored in plaintext.
+//!
+//! A seed file is either the raw seed bytes, or the seed encrypted (see
+//! `encrypt`) with ChaCha20-Poly1305 under a key derived from a passphrase
+//! with Argon2id. An encrypted file is
+//! `magic || version || m_cost:u32 || t_cost:u32 || p_cost:u32 || salt:[u8; 16] || nonce:[u8; 12] || ciphertext`,
+//! where everything before the ciphertext is authenticated as well.
+//!
+//! The plaintext is either a seed or a base58 xprv, see `read_root`. The
+//! passphrase may be given directly, prompted for on the terminal, or sent
+//! by the operator over a unix socket once the server has started (see
+//! `Unlock`), so that it never needs to be stored on the oracle's host.
+//!
+//! The root may also be derived from a BIP-39 mnemonic, see `from_mnemonic`.
+use super::*;
+use bitcoin::Network;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::path::Path;
+
+/// Prefixes every encrypted seed file
+const MAGIC: &[u8; 8] = b"CTVESEED";
+/// The version of the encrypted format
+const VERSION: u8 = 1;
+/// The length of the header, which is authenticated along with the seed
+const HEADER: usize = 8 + 1 + 12 + 16 + 12;
+/// Argon2id memory cost in KiB
+const M_COST: u32 = 64 * 1024;
+/// Argon2id iterations
+const T_COST: u32 = 3;
+/// Argon2id parallelism
+const P_COST: u32 = 1;
+/// The most memory, in KiB, a seed file may ask the key derivation for, so a
+/// corrupt header can't exhaust it
+const MAX_M_COST: u32 = 4 * 1024 * 1024;
+/// The most iterations a seed file may ask the key derivation for
+const MAX_T_COST: u32 = 64;
+/// The most parallelism a seed file may ask the key derivation for
+const MAX_P_COST: u32 = 64;
+
+/// if `contents` is an encrypted seed file
+pub fn is_encrypted(contents: &[u8]) -> bool {
+    contents.starts_with(&MAGIC[..])
+}
+
+/// the encryption key for `passphrase` with the given salt and costs
+fn kdf(
+    passphrase: &str,
+    salt: &[u8],
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+) -> Result<[u8; 32], std::io::Error> {
+    let argon = argon2::Argon2::new(None, t_cost, m_cost, p_cost, argon2::Version::V0x13)
+        .or_else(|_| input_error("Invalid Key Derivation Parameters"))?;
+    let mut key = [0u8; 32];
+    argon
+        .hash_password_into(
+            argon2::Algorithm::Argon2id,
+            passphrase.as_bytes(),
+            salt,
+            &[],
+            &mut key,
+        )
+        .or_else(|_| input_error("Could Not Derive Encryption Key"))?;
+    Ok(key)
+}
+
+/// encrypts `seed` under `passphrase`, returning the contents of a seed file
+pub fn encrypt(seed: &[u8], passphrase: &str) -> Result<Vec<u8>, std::io::Error> {
+    encrypt_with_costs(seed, passphrase, [M_COST, T_COST, P_COST])
+}
+
+/// encrypts `seed` under `passphrase` with the given Argon2id costs
+fn encrypt_with_costs(
+    seed: &[u8],
+    passphrase: &str,
+    costs: [u32; 3],
+) -> Result<Vec<u8>, std::io::Error> {
+    let salt: [u8; 16] = rand::thread_rng().gen();
+    let nonce: [u8; 12] = rand::thread_rng().gen();
+    let mut v = MAGIC.to_vec();
+    v.push(VERSION);
+    for cost in costs.iter() {
+        v.extend_from_slice(&cost.to_be_bytes());
+    }
+    v.extend_from_slice(&salt[..]);
+    v.extend_from_slice(&nonce[..]);
+    let [m_cost, t_cost, p_cost] = costs;
+    let key = kdf(passphrase, &salt[..], m_cost, t_cost, p_cost)?;
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key[..]));
+    let ciphertext = cipher
+        .encrypt(
+            Nonce::from_slice(&nonce[..]),
+            Payload {
+                msg: seed,
+                aad: &v[..],
+            },
+        )
+        .or_else(|_| input_error("Could Not Encrypt Seed"))?;
+    v.extend_from_slice(&ciphertext[..]);
+    Ok(v)
+}
+
+/// decrypts the contents of an encrypted seed file
+pub fn decrypt(contents: &[u8], passphrase: &str) -> Result<Vec<u8>, std::io::Error> {
+    if !is_encrypted(contents) || contents.len() < HEADER {
+        return input_error("Not an Encrypted Seed");
+    }
+    if contents[8] != VERSION {
+        return input_error("Unknown Seed Encryption Version");
+    }
+    let cost = |i: usize| {
+        let mut b = [0u8; 4];
+        b.copy_from_slice(&contents[9 + 4 * i..13 + 4 * i]);
+        u32::from_be_bytes(b)
+    };
+    if cost(0) > MAX_M_COST || cost(1) > MAX_T_COST || cost(2) > MAX_P_COST {
+        return input_error("Seed Key Derivation Too Costly");
+    }
+    let (header, ciphertext) = contents.split_at(HEADER);
+    let salt = &header[21..37];
+    let nonce = &header[37..49];
+    let key = kdf(passphrase, salt, cost(0), cost(1), cost(2))?;
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key[..]));
+    cipher
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: header,
+            },
+        )
+        .or_else(|_| input_error("Wrong Passphrase or Corrupt Seed"))
+}
+
+/// reads the seed in the file at `path`, decrypting it with `passphrase` if
+/// it is encrypted.
+pub fn read<P: AsRef<Path>>(path: P, passphrase: Option<&str>) -> Result<Vec<u8>, std::io::Error> {
+    let contents = std::fs::read(path)?;
+    if !is_encrypted(&contents[..]) {
+        return Ok(contents);
+    }
+    match passphrase {
+        Some(passphrase) => decrypt(&contents[..], passphrase),
+        None => input_error("Seed Is Encrypted, but No Passphrase Was Given"),
+    }
+}
+
+/// The root key in `plaintext`: a base58 xprv, or otherwise a seed for a
+/// root on `network`.
+pub fn parse_root(plaintext: &[u8], network: Network) -> Result<ExtendedPrivKey, std::io::Error> {
+    let xprv = std::str::from_utf8(plaintext)
+        .ok()
+        .and_then(|s| s.trim().parse::<ExtendedPrivKey>().ok());
+    match xprv {
+        Some(root) if !same_key_network(root.network, network) => {
+            input_error("Root Key Is For a Different Network")
+        }
+        Some(root) => Ok(root),
+        None => {
+            ExtendedPrivKey::new_master(network, plaintext).or_else(|_| input_error("Invalid Seed"))
+        }
+    }
+}
+
+/// reads the root key in the file at `path` (see `parse_root`), decrypting
+/// it with `passphrase` if it is encrypted.
+pub fn read_root<P: AsRef<Path>>(
+    path: P,
+    passphrase: Option<&str>,
+    network: Network,
+) -> Result<ExtendedPrivKey, std::io::Error> {
+    parse_root(&read(path, passphrase)?[..], network)
+}
+
+/// How the passphrase of an encrypted key file is obtained
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Unlock {
+    /// read from an environment variable
+    Env(String),
+    /// prompted for on the terminal
+    Prompt,
+    /// sent by the operator over a unix socket at this path, see
+    /// `unlock_over_socket`
+    Socket(std::path::PathBuf),
+}
+
+impl std::str::FromStr for Unlock {
+    type Err = std::io::Error;
+    /// `env:VAR`, `prompt`, or `socket:/path`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "prompt" {
+            Ok(Unlock::Prompt)
+        } else if let Some(var) = s.strip_prefix("env:") {
+            Ok(Unlock::Env(var.into()))
+        } else if let Some(path) = s.strip_prefix("socket:") {
+            Ok(Unlock::Socket(path.into()))
+        } else {
+            input_error("Unknown Unlock Method")
+        }
+    }
+}
+
+impl Unlock {
+    /// Loads the root key at `path` (see `read_root`), obtaining the
+    /// passphrase this way if the file is encrypted.
+    ///
+    /// With `Unlock::Socket` this waits until the operator sends the right
+    /// passphrase.
+    pub async fn root<P: AsRef<Path>>(
+        &self,
+        path: P,
+        network: Network,
+    ) -> Result<ExtendedPrivKey, std::io::Error> {
+        let contents = tokio::fs::read(path).await?;
+        if !is_encrypted(&contents[..]) {
+            return parse_root(&contents[..], network);
+        }
+        let passphrase = match self {
+            Unlock::Env(var) => match std::env::var(var) {
+                Ok(passphrase) => passphrase,
+                Err(_) => return input_error("Passphrase Variable Is Not Set"),
+            },
+            Unlock::Prompt => prompt("Seed Passphrase: ")?,
+            #[cfg(unix)]
+            Unlock::Socket(socket) => return unlock_over_socket(socket, contents, network).await,
+            #[cfg(not(unix))]
+            Unlock::Socket(_) => return input_error("Unlock Sockets Require Unix"),
+        };
+        parse_root(&decrypt(&contents[..], &passphrase)?[..], network)
+    }
+}
+
+/// prompts for a passphrase on the terminal, without echoing it
+pub fn prompt(msg: &str) -> Result<String, std::io::Error> {
+    rpassword::read_password_from_tty(Some(msg))
+}
+
+/// writes a file only the owner may read (e.g. an encrypted seed, or a
+/// service's key), failing if it exists
+pub fn write_secret<P: AsRef<Path>>(path: P, contents: &[u8]) -> Result<(), std::io::Error> {
+    use std::io::Write;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents)
+}
+
+/// Listens on a unix socket at `socket` for the passphrase of the encrypted
+/// key file `contents`, returning its root once one is right.
+///
+/// The operator sends the passphrase as a line (e.g. with
+/// `socat - UNIX-CONNECT:/path`) and is answered `unlocked` or `wrong
+/// passphrase`. The socket is only accessible to the server's user, and is
+/// removed once unlocked.
+#[cfg(unix)]
+pub async fn unlock_over_socket<P: AsRef<Path>>(
+    socket: P,
+    contents: Vec<u8>,
+    network: Network,
+) -> Result<ExtendedPrivKey, std::io::Error> {
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    let socket = socket.as_ref();
+    // a socket left behind by an earlier run
+    if socket.exists() {
+        std::fs::remove_file(socket)?;
+    }
+    let listener = tokio::net::UnixListener::bind(socket)?;
+    std::fs::set_permissions(socket, std::fs::Permissions::from_mode(0o600))?;
+    let contents = Arc::new(contents);
+    tracing::info!(socket = %socket.display(), "waiting for the seed passphrase");
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let (read, mut write) = tokio::io::split(stream);
+        let mut line = String::new();
+        if BufReader::new(read).read_line(&mut line).await.is_err() {
+            continue;
+        }
+        let passphrase = line.trim_end_matches(&['\r', '\n'][..]).to_string();
+        let encrypted = contents.clone();
+        // key derivation is deliberately slow
+        let decrypted =
+            tokio::task::spawn_blocking(move || decrypt(&encrypted[..], &passphrase)).await?;
+        match decrypted.and_then(|plaintext| parse_root(&plaintext[..], network)) {
+            Ok(root) => {
+                let _ = write.write_all(b"unlocked\n").await;
+                std::fs::remove_file(socket)?;
+                tracing::info!("seed unlocked");
+                return Ok(root);
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "could not unlock seed");
+                let _ = write.write_all(b"wrong passphrase\n").await;
+            }
+        }
+    }
+}
+
+/// the BIP-39 seed of the mnemonic `phrase` with the optional `passphrase`
+/// (the "25th word"), which must both be NFKD normalized (as ASCII is).
+pub fn from_mnemonic(phrase: &str, passphrase: &str) -> Result<[u8; 64], std::io::Error> {
+    let normalized = phrase.split_whitespace().collect::<Vec<_>>().join(" ");
+    let mnemonic = bip39::Mnemonic::parse_normalized(&normalized)
+        .or_else(|_| input_error("Invalid Mnemonic"))?;
+    Ok(mnemonic.to_seed_normalized(passphrase))
+}
+
+/// the root key on `network` for a BIP-39 mnemonic, see `from_mnemonic`
+pub fn mnemonic_root(
+    phrase: &str,
+    passphrase: &str,
+    network: Network,
+) -> Result<ExtendedPrivKey, std::io::Error> {
+    ExtendedPrivKey::new_master(network, &from_mnemonic(phrase, passphrase)?[..])
+        .or_else(|_| input_error("Invalid Seed"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// costs low enough for tests to derive keys quickly
+    const CHEAP: [u32; 3] = [8, 1, 1];
+
+    #[test]
+    fn encrypt_decrypt() {
+        let seed = [7u8; 32];
+        let contents = encrypt_with_costs(&seed[..], "hunter2", CHEAP).unwrap();
+        assert!(is_encrypted(&contents[..]));
+        assert!(!is_encrypted(&seed[..]));
+        assert_eq!(contents.len(), HEADER + seed.len() + 16);
+        assert_eq!(decrypt(&contents[..], "hunter2").unwrap(), seed.to_vec());
+        // the salt and nonce are fresh each time
+        assert_ne!(
+            contents,
+            encrypt_with_costs(&seed[..], "hunter2", CHEAP).unwrap()
+        );
+    }
+
+    #[test]
+    fn wrong_passphrase() {
+        let contents = encrypt_with_costs(&[7u8; 32], "hunter2", CHEAP).unwrap();
+        assert!(decrypt(&contents[..], "hunter3").is_err());
+        assert!(decrypt(&contents[..], "").is_err());
+    }
+
+    #[test]
+    fn corrupt_contents() {
+        let contents = encrypt_with_costs(&[7u8; 32], "hunter2", CHEAP).unwrap();
+        // every byte is authenticated, the header too
+        for i in 0..contents.len() {
+            let mut corrupt = contents.clone();
+            corrupt[i] ^= 1;
+            assert!(decrypt(&corrupt[..], "hunter2").is_err());
+        }
+        // as is the length
+        for len in 0..contents.len() {
+            assert!(decrypt(&contents[..len], "hunter2").is_err());
+        }
+        let mut longer = contents.clone();
+        longer.push(0);
+        assert!(decrypt(&longer[..], "hunter2").is_err());
+        // a header asking for an absurd key derivation is rejected before
+        // deriving anything
+        let mut costly = contents;
+        costly[9..13].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(decrypt(&costly[..], "hunter2").is_err());
+        assert!(decrypt(b"not a seed file", "hunter2").is_err());
+    }
+
+    #[test]
+    fn unlock() {
+        let root = ExtendedPrivKey::new_master(Network::Regtest, &[7u8; 32]).unwrap();
+        let contents = encrypt_with_costs(&[7u8; 32], "hunter2", CHEAP).unwrap();
+        let path = std::env::temp_dir().join(format!("ctve-seed-{}", std::process::id()));
+        std::fs::write(&path, &contents[..]).unwrap();
+        assert_eq!(
+            read_root(&path, Some("hunter2"), Network::Regtest).unwrap(),
+            root
+        );
+        assert!(read_root(&path, Some("hunter3"), Network::Regtest).is_err());
+        assert!(read_root(&path, None, Network::Regtest).is_err());
+        let var = format!("CTVE_SEED_TEST_{}", std::process::id());
+        let unlock: Unlock = format!("env:{}", var).parse().unwrap();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        assert!(rt.block_on(unlock.root(&path, Network::Regtest)).is_err());
+        std::env::set_var(&var, "hunter2");
+        assert_eq!(
+            rt.block_on(unlock.root(&path, Network::Regtest)).unwrap(),
+            root
+        );
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!("prompt".parse::<Unlock>().unwrap(), Unlock::Prompt);
+        assert!("email:root".parse::<Unlock>().is_err());
+    }
+
+    #[test]
+    fn roots() {
+        use bitcoin::hashes::hex::FromHex;
+        // BIP-39's first English vector
+        let phrase = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+        assert_eq!(
+            from_mnemonic(phrase, "TREZOR").unwrap().to_vec(),
+            Vec::<u8>::from_hex("c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04").unwrap()
+        );
+        // whitespace is normalized
+        assert_eq!(
+            from_mnemonic(&format!("  {}\n", phrase.replace(' ', "\t")), "TREZOR").unwrap()[..],
+            from_mnemonic(phrase, "TREZOR").unwrap()[..]
+        );
+        // a bad checksum
+        assert!(from_mnemonic(&phrase.replace("about", "abandon"), "").is_err());
+        assert_eq!(
+            mnemonic_root(phrase, "TREZOR", Network::Regtest).unwrap(),
+            ExtendedPrivKey::new_master(
+                Network::Regtest,
+                &from_mnemonic(phrase, "TREZOR").unwrap()[..]
+            )
+            .unwrap()
+        );
+        // a file may hold an xprv rather than a seed, for the same network
+        let root = ExtendedPrivKey::new_master(Network::Testnet, &[7u8; 32]).unwrap();
+        let xprv = format!("{}\n", root);
+        assert_eq!(parse_root(xprv.as_bytes(), Network::Regtest).unwrap(), root);
+        assert!(parse_root(xprv.as_bytes(), Network::Bitcoin).is_err());
+        assert_eq!(parse_root(&[7u8; 32], Network::Testnet).unwrap(), root);
+    }
+}
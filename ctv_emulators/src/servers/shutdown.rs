@@ -0,0 +1,55 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Graceful shutdown for oracle servers.
+use tokio::sync::watch;
+
+/// A handle used to stop a running server.
+///
+/// On shutdown the server stops accepting connections, lets in-flight
+/// requests finish and be responded to, closes every connection, and then
+/// returns from `bind`.
+#[derive(Debug)]
+pub struct ShutdownHandle(watch::Sender<bool>);
+
+impl ShutdownHandle {
+    /// create a new handle and the signal a server should watch
+    pub fn new() -> (ShutdownHandle, watch::Receiver<bool>) {
+        let (tx, rx) = watch::channel(false);
+        (ShutdownHandle(tx), rx)
+    }
+    /// signal the server to shut down
+    pub fn shutdown(&self) {
+        // if there are no receivers, the server is already gone.
+        let _ = self.0.send(true);
+    }
+    /// Waits for SIGINT (or SIGTERM on unix), then shuts down.
+    pub async fn shutdown_on_signal(self) -> Result<(), std::io::Error> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut term = signal(SignalKind::terminate())?;
+            tokio::select! {
+                r = tokio::signal::ctrl_c() => r?,
+                _ = term.recv() => {},
+            }
+        }
+        #[cfg(not(unix))]
+        tokio::signal::ctrl_c().await?;
+        self.shutdown();
+        Ok(())
+    }
+}
+
+/// resolves once shutdown has been signalled. If the `ShutdownHandle` was
+/// dropped without signalling, never resolves.
+pub(crate) async fn stopped(mut shutdown: watch::Receiver<bool>) {
+    while !*shutdown.borrow() {
+        if shutdown.changed().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
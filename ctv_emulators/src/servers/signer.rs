@@ -0,0 +1,225 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The backends which hold an oracle's keys and produce its signatures.
+//!
+//! With an unhardened `derivation::DerivationScheme` the oracle finds every
+//! public key it needs from the root xpub alone, hardened keys are derived by
+//! the `Signer`. A `Signer` is only asked for signatures once the oracle has
+//! decided which inputs to sign.
+use super::*;
+use bitcoin::blockdata::transaction::SigHashType;
+use bitcoin::secp256k1::{Message, Signature};
+use bitcoin::{PublicKey, Script, TxOut};
+
+/// How an input is to be signed
+pub enum InputKind {
+    /// A segwit v0 input, signed with ECDSA over the BIP-143 sighash
+    /// committing to `scriptcode`.
+    Ecdsa {
+        /// the script being satisfied
+        scriptcode: Script,
+        /// the sighash type to sign with
+        sighash: SigHashType,
+    },
+    /// A taproot input, signed with BIP-340 over the BIP-341 sighash.
+    Taproot(taproot::SpendPath),
+}
+
+/// An input the oracle has decided to sign
+pub struct SigningInput {
+    /// the index of the input in the PSBT
+    pub input: usize,
+    /// the path from the root to the signing key
+    pub path: Vec<ChildNumber>,
+    /// the public key at `path`
+    pub key: PublicKey,
+    /// how to sign
+    pub kind: InputKind,
+}
+
+/// A backend which can sign with any child of an xpub.
+pub trait Signer: Send + Sync {
+    /// The root key of the oracle
+    fn xpub(&self) -> ExtendedPubKey;
+    /// The key at `path` from the root.
+    ///
+    /// By default it is derived from `xpub`, which fails for hardened paths.
+    fn derive_xpub(&self, path: &[ChildNumber]) -> Result<ExtendedPubKey, std::io::Error> {
+        SECP.with(|secp| self.xpub().derive_pub(secp, &path))
+            .or_else(|_| input_error("Signer Can Not Derive Key"))
+    }
+    /// Add a signature to each input in `inputs`.
+    ///
+    /// `prevouts` has the spent output of every input of the PSBT, if known,
+    /// and is always present when any input is `InputKind::Taproot`.
+    fn sign_psbt(
+        &self,
+        psbt: PartiallySignedTransaction,
+        inputs: &[SigningInput],
+        prevouts: Option<&[TxOut]>,
+    ) -> Result<PartiallySignedTransaction, std::io::Error>;
+    /// Sign `msg` with the root key, to prove the oracle holds it.
+    fn sign_challenge(&self, msg: &Message) -> Result<Signature, std::io::Error>;
+    /// Produce a MuSig2 partial signature over `msg` with the key at `path`.
+    ///
+    /// Backends which can't (the default) return an error.
+    fn musig_partial_sign(
+        &self,
+        _path: &[ChildNumber],
+        _secnonce: musig::SecNonce,
+        _ctx: &musig::KeyAggContext,
+        _aggnonce: &musig::PubNonce,
+        _msg: &[u8; 32],
+    ) -> Result<[u8; 32], std::io::Error> {
+        input_error("Signer Does Not Support MuSig2")
+    }
+    /// if the signer supports `ecdh`, and so can authenticate Noise sessions
+    fn supports_ecdh(&self) -> bool {
+        false
+    }
+    /// Diffie-Hellman of `point` with the root key (see `noise::ecdh`).
+    ///
+    /// Backends which can't (the default) return an error.
+    fn ecdh(&self, _point: &bitcoin::secp256k1::PublicKey) -> Result<[u8; 32], std::io::Error> {
+        input_error("Signer Does Not Support ECDH")
+    }
+}
+
+/// Adapts a `SigHash` for use as a secp256k1 `Message`
+struct Wrapped(bitcoin::SigHash);
+impl bitcoin::secp256k1::ThirtyTwoByteHash for Wrapped {
+    fn into_32(self) -> [u8; 32] {
+        self.0.as_hash().into_inner()
+    }
+}
+
+/// The BIP-143 message an `InputKind::Ecdsa` input with `scriptcode` is
+/// signed over.
+pub fn ecdsa_message(
+    cache: &mut bitcoin::util::bip143::SigHashCache<&bitcoin::Transaction>,
+    input: &bitcoin::util::psbt::Input,
+    idx: usize,
+    scriptcode: &Script,
+    sighash: SigHashType,
+) -> Result<Message, std::io::Error> {
+    let value = match &input.witness_utxo {
+        Some(utxo) => utxo.value,
+        None => return input_error("Missing Witness UTXO"),
+    };
+    let h = cache.signature_hash(idx, scriptcode, value, sighash);
+    Ok(Message::from(Wrapped(h)))
+}
+
+/// Records an ECDSA signature (made over `ecdsa_message`) for `key`.
+pub fn add_ecdsa_signature(
+    input: &mut bitcoin::util::psbt::Input,
+    key: PublicKey,
+    signature: &Signature,
+    sighash: SigHashType,
+) {
+    let mut signature: Vec<u8> = signature.serialize_der().to_vec();
+    signature.push(sighash.as_u32() as u8);
+    input.partial_sigs.insert(key, signature);
+}
+
+/// A `Signer` holding the root xprv in memory.
+pub struct LocalSigner {
+    root: ExtendedPrivKey,
+    xpub: ExtendedPubKey,
+}
+
+impl LocalSigner {
+    /// create a signer for `root`
+    pub fn new(root: ExtendedPrivKey) -> Self {
+        let xpub = SECP.with(|secp| ExtendedPubKey::from_private(secp, &root));
+        LocalSigner { root, xpub }
+    }
+}
+
+impl Signer for LocalSigner {
+    fn xpub(&self) -> ExtendedPubKey {
+        self.xpub
+    }
+    fn derive_xpub(&self, path: &[ChildNumber]) -> Result<ExtendedPubKey, std::io::Error> {
+        SECP.with(|secp| {
+            self.root
+                .derive_priv(secp, &path)
+                .map(|k| ExtendedPubKey::from_private(secp, &k))
+        })
+        .or_else(|_| input_error("Could Not Derive Key"))
+    }
+    fn sign_psbt(
+        &self,
+        mut psbt: PartiallySignedTransaction,
+        inputs: &[SigningInput],
+        prevouts: Option<&[TxOut]>,
+    ) -> Result<PartiallySignedTransaction, std::io::Error> {
+        let tx = psbt.global.unsigned_tx.clone();
+        let mut sighash_cache = bitcoin::util::bip143::SigHashCache::new(&tx);
+        SECP.with(|secp| {
+            for signing in inputs.iter() {
+                let key = match self.root.derive_priv(secp, &signing.path) {
+                    Ok(key) => key,
+                    Err(_) => return input_error("Could Not Derive Key"),
+                };
+                let input = &mut psbt.inputs[signing.input];
+                match &signing.kind {
+                    InputKind::Taproot(path) => match prevouts {
+                        Some(prevouts) => taproot::sign_input(
+                            &key,
+                            input,
+                            &tx,
+                            prevouts,
+                            signing.input,
+                            path,
+                            secp,
+                        )?,
+                        None => return input_error("Taproot Signing Requires All UTXOs"),
+                    },
+                    InputKind::Ecdsa {
+                        scriptcode,
+                        sighash,
+                    } => {
+                        let msg = ecdsa_message(
+                            &mut sighash_cache,
+                            input,
+                            signing.input,
+                            scriptcode,
+                            *sighash,
+                        )?;
+                        let signature = secp.sign(&msg, &key.private_key.key);
+                        add_ecdsa_signature(input, signing.key, &signature, *sighash);
+                    }
+                }
+            }
+            Ok(())
+        })?;
+        Ok(psbt)
+    }
+    fn sign_challenge(&self, msg: &Message) -> Result<Signature, std::io::Error> {
+        Ok(SECP.with(|secp| secp.sign(msg, &self.root.private_key.key)))
+    }
+    fn musig_partial_sign(
+        &self,
+        path: &[ChildNumber],
+        secnonce: musig::SecNonce,
+        ctx: &musig::KeyAggContext,
+        aggnonce: &musig::PubNonce,
+        msg: &[u8; 32],
+    ) -> Result<[u8; 32], std::io::Error> {
+        let key = SECP
+            .with(|secp| self.root.derive_priv(secp, path))
+            .or_else(|_| input_error("Could Not Derive Key"))?;
+        musig::partial_sign(secnonce, &key.private_key.key, ctx, aggnonce, msg)
+    }
+    fn supports_ecdh(&self) -> bool {
+        true
+    }
+    fn ecdh(&self, point: &bitcoin::secp256k1::PublicKey) -> Result<[u8; 32], std::io::Error> {
+        Ok(noise::ecdh(point, &self.root.private_key.key))
+    }
+}
@@ -0,0 +1,358 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! BIP-340/341 signing helpers for oracles emulating CTV on taproot outputs.
+//!
+//! The version of rust-bitcoin we use predates taproot, so the sighash
+//! algorithm and the BIP-371 PSBT fields are implemented here directly.
+use super::*;
+use bitcoin::consensus::encode::Encodable;
+use bitcoin::secp256k1::schnorrsig;
+use bitcoin::util::psbt::raw;
+use bitcoin::{Script, Transaction, TxOut};
+pub use sapio_base::taproot::{leaf_hash, tagged_hash};
+
+/// PSBT_IN_TAP_KEY_SIG from BIP-371
+const PSBT_IN_TAP_KEY_SIG: u8 = 0x13;
+/// PSBT_IN_TAP_SCRIPT_SIG from BIP-371
+const PSBT_IN_TAP_SCRIPT_SIG: u8 = 0x14;
+/// SIGHASH_DEFAULT from BIP-341, signs everything and is omitted from the signature
+const SIGHASH_DEFAULT: u8 = 0x00;
+
+/// How a taproot input is being spent.
+pub enum SpendPath {
+    /// Spent via the output key directly.
+    Key,
+    /// Spent via a tapscript leaf with the given leaf hash.
+    Script(Sha256),
+}
+
+/// Is this script a segwit v1 (taproot) output?
+pub fn is_v1_witness(s: &Script) -> bool {
+    let b = s.as_bytes();
+    b.len() == 34 && b[0] == 0x51 && b[1] == 0x20
+}
+
+/// checks if a tapscript contains a push of the x-only key
+pub fn script_has_xonly(script: &Script, pk: &schnorrsig::PublicKey) -> bool {
+    let key = pk.serialize();
+    script.instructions().any(|i| match i {
+        Ok(bitcoin::blockdata::script::Instruction::PushBytes(b)) => b == &key[..],
+        _ => false,
+    })
+}
+
+/// Computes the BIP-341 signature hash with SIGHASH_DEFAULT for input `idx`.
+///
+/// `prevouts` must contain the spent output for every input of `tx`.
+pub fn sighash(tx: &Transaction, prevouts: &[TxOut], idx: usize, path: &SpendPath) -> Sha256 {
+    fn sha<F: Fn(&mut Vec<u8>)>(f: F) -> [u8; 32] {
+        let mut v = vec![];
+        f(&mut v);
+        Sha256::hash(&v[..]).into_inner()
+    }
+    let mut m: Vec<u8> = vec![];
+    // Epoch
+    m.push(0x00);
+    m.push(SIGHASH_DEFAULT);
+    // Writes to a Vec can never fail, so unwrap freely below.
+    tx.version.consensus_encode(&mut m).unwrap();
+    tx.lock_time.consensus_encode(&mut m).unwrap();
+    m.extend_from_slice(&sha(|v| {
+        for i in tx.input.iter() {
+            i.previous_output.consensus_encode(v).unwrap();
+        }
+    }));
+    m.extend_from_slice(&sha(|v| {
+        for p in prevouts.iter() {
+            p.value.consensus_encode(v).unwrap();
+        }
+    }));
+    m.extend_from_slice(&sha(|v| {
+        for p in prevouts.iter() {
+            p.script_pubkey.consensus_encode(v).unwrap();
+        }
+    }));
+    m.extend_from_slice(&sha(|v| {
+        for i in tx.input.iter() {
+            i.sequence.consensus_encode(v).unwrap();
+        }
+    }));
+    m.extend_from_slice(&sha(|v| {
+        for o in tx.output.iter() {
+            o.consensus_encode(v).unwrap();
+        }
+    }));
+    // spend_type = ext_flag * 2 + annex_present, we never have an annex
+    m.push(match path {
+        SpendPath::Key => 0,
+        SpendPath::Script(_) => 2,
+    });
+    (idx as u32).consensus_encode(&mut m).unwrap();
+    if let SpendPath::Script(leaf) = path {
+        m.extend_from_slice(&leaf[..]);
+        // key_version
+        m.push(0x00);
+        // codesep_pos: no OP_CODESEPARATOR executed
+        0xffff_ffffu32.consensus_encode(&mut m).unwrap();
+    }
+    tagged_hash("TapSighash", &m[..])
+}
+
+/// Records a key path signature (e.g., an aggregated MuSig2 signature) in
+/// the BIP-371 field.
+pub fn add_key_sig(input: &mut bitcoin::util::psbt::Input, sig: &[u8]) {
+    input.unknown.insert(
+        raw::Key {
+            type_value: PSBT_IN_TAP_KEY_SIG,
+            key: vec![],
+        },
+        sig.to_vec(),
+    );
+}
+
+/// the x-only form of an ECDSA public key
+pub fn xonly(pk: &bitcoin::PublicKey) -> schnorrsig::PublicKey {
+    schnorrsig::PublicKey::from_slice(&pk.key.serialize()[1..])
+        .expect("Every compressed key has a valid x-only form")
+}
+
+/// Determines how `key` may spend a taproot input, if at all.
+///
+/// The key path is used if the output key is the BIP-86 style tweak of the
+/// derived key (i.e., no script tree). Otherwise, if the input's
+/// `witness_script` is a tapscript leaf containing the derived x-only key, the
+/// script path is used.
+pub fn spend_path(
+    key: &bitcoin::PublicKey,
+    input: &bitcoin::util::psbt::Input,
+    utxo: &TxOut,
+    secp: &Secp256k1<All>,
+) -> Option<SpendPath> {
+    let internal = xonly(key);
+    let tweak = tagged_hash("TapTweak", &internal.serialize()[..]);
+    let mut tweaked = internal;
+    let output_key = &utxo.script_pubkey.as_bytes()[2..34];
+    if tweaked.tweak_add_assign(secp, &tweak[..]).is_ok()
+        && &tweaked.serialize()[..] == output_key
+    {
+        return Some(SpendPath::Key);
+    }
+    match &input.witness_script {
+        Some(script) if script_has_xonly(script, &internal) => {
+            Some(SpendPath::Script(leaf_hash(script)))
+        }
+        _ => None,
+    }
+}
+
+/// Signs a taproot input with `key` via `path` (see [`spend_path`]).
+///
+/// Signatures are recorded in the PSBT using the BIP-371 fields.
+pub fn sign_input(
+    key: &ExtendedPrivKey,
+    input: &mut bitcoin::util::psbt::Input,
+    tx: &Transaction,
+    prevouts: &[TxOut],
+    idx: usize,
+    path: &SpendPath,
+    secp: &Secp256k1<All>,
+) -> Result<(), std::io::Error> {
+    let keypair = schnorrsig::KeyPair::from_secret_key(secp, key.private_key.key);
+    let internal = schnorrsig::PublicKey::from_keypair(secp, &keypair);
+    let signer = match path {
+        SpendPath::Key => {
+            let tweak = tagged_hash("TapTweak", &internal.serialize()[..]);
+            let mut tweaked = keypair;
+            if tweaked.tweak_add_assign(secp, &tweak[..]).is_err() {
+                return input_error("Invalid Taproot Tweak");
+            }
+            tweaked
+        }
+        SpendPath::Script(_) => keypair,
+    };
+    let h = sighash(tx, prevouts, idx, path);
+    let msg = bitcoin::secp256k1::Message::from_slice(&h[..])
+        .expect("Hashes are always 32 bytes");
+    let aux: [u8; 32] = rand::thread_rng().gen();
+    let sig = secp.schnorrsig_sign_with_aux_rand(&msg, &signer, &aux);
+    let (type_value, key) = match path {
+        SpendPath::Key => (PSBT_IN_TAP_KEY_SIG, vec![]),
+        SpendPath::Script(leaf) => {
+            let mut k = internal.serialize().to_vec();
+            k.extend_from_slice(&leaf[..]);
+            (PSBT_IN_TAP_SCRIPT_SIG, k)
+        }
+    };
+    input
+        .unknown
+        .insert(raw::Key { type_value, key }, sig.as_ref().to_vec());
+    Ok(())
+}
+
+/// Checks that `sig` is a valid SIGHASH_DEFAULT signature by `key` spending
+/// input `idx` of `tx` via `path` (see [`spend_path`]).
+pub fn verify_input(
+    key: &bitcoin::PublicKey,
+    tx: &Transaction,
+    prevouts: &[TxOut],
+    idx: usize,
+    path: &SpendPath,
+    sig: &[u8],
+    secp: &Secp256k1<All>,
+) -> bool {
+    let sig = match schnorrsig::Signature::from_slice(sig) {
+        Ok(sig) => sig,
+        Err(_) => return false,
+    };
+    let mut signer = xonly(key);
+    if let SpendPath::Key = path {
+        let tweak = tagged_hash("TapTweak", &signer.serialize()[..]);
+        if signer.tweak_add_assign(secp, &tweak[..]).is_err() {
+            return false;
+        }
+    }
+    let h = sighash(tx, prevouts, idx, path);
+    let msg = bitcoin::secp256k1::Message::from_slice(&h[..])
+        .expect("Hashes are always 32 bytes");
+    secp.schnorrsig_verify(&sig, &msg, &signer).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::opcodes::all::OP_CHECKSIG;
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin::consensus::encode::deserialize;
+    use bitcoin::hashes::hex::FromHex;
+    use std::str::FromStr;
+
+    /// the transaction and spent outputs of BIP-341's key path spending
+    /// test vectors
+    fn vector() -> (Transaction, Vec<TxOut>) {
+        let tx = Vec::<u8>::from_hex(
+            "02000000097de20cbff686da83a54981d2b9bab3586f4ca7e48f57f5b55963115f3b334e9c01000000\
+             0000000000d7b7cab57b1393ace2d064f4d4a2cb8af6def61273e127517d44759b6dafdd990000000000\
+             fffffffff8e1f583384333689228c5d28eac13366be082dc57441760d957275419a41842000000000\
+             0fffffffff0689180aa63b30cb162a73c6d2a38b7eeda2a83ece74310fda0843ad604853b01000000\
+             00feffffffaa5202bdf6d8ccd2ee0f0202afbbb7461d9264a25e5bfd3c5a52ee1239e0ba6c00000000\
+             00feffffff956149bdc66faa968eb2be2d2faa29718acbfe3941215893a2a3446d32acd05000000000\
+             0000000000e664b9773b88c09c32cb70a2a3e4da0ced63b7ba3b22f848531bbb1d5d5f4c9401000000\
+             0000000000e9aa6b8e6c9de67619e6a3924ae25696bb7b694bb677a632a74ef7eadfd4eabf00000000\
+             00ffffffffa778eb6a263dc090464cd125c466b5a99667720b1c110468831d058aa1b82af101000000\
+             00ffffffff0200ca9a3b000000001976a91406afd46bcdfd22ef94ac122aa11f241244a37ecc88ac80\
+             7840cb0000000020ac9a87f5594be208f8532db38cff670c450ed2fea8fcdefcc9a663f78bab962b00\
+             65cd1d",
+        )
+        .unwrap();
+        let prevouts = [
+            (
+                "512053a1f6e454df1aa2776a2814a721372d6258050de330b3c6d10ee8f4e0dda343",
+                420000000,
+            ),
+            (
+                "5120147c9c57132f6e7ecddba9800bb0c4449251c92a1e60371ee77557b6620f3ea3",
+                462000000,
+            ),
+            (
+                "76a914751e76e8199196d454941c45d1b3a323f1433bd688ac",
+                294000000,
+            ),
+            (
+                "5120e4d810fd50586274face62b8a807eb9719cef49c04177cc6b76a9a4251d5450e",
+                504000000,
+            ),
+            (
+                "512091b64d5324723a985170e4dc5a0f84c041804f2cd12660fa5dec09fc21783605",
+                630000000,
+            ),
+            ("00147dd65592d0ab2fe0d0257d571abf032cd9db93dc", 378000000),
+            (
+                "512075169f4001aa68f15bbed28b218df1d0a62cbbcf1188c6665110c293c907b831",
+                672000000,
+            ),
+            (
+                "5120712447206d7a5238acc7ff53fbe94a3b64539ad291c7cdbc490b7577e4b17df5",
+                546000000,
+            ),
+            (
+                "512077e30a5522dd9f894c3f8b8bd4c4b2cf82ca7da8a3ea6a239655c39c050ab220",
+                588000000,
+            ),
+        ]
+        .iter()
+        .map(|(script, value)| TxOut {
+            value: *value,
+            script_pubkey: Script::from(Vec::<u8>::from_hex(script).unwrap()),
+        })
+        .collect();
+        (deserialize(&tx[..]).unwrap(), prevouts)
+    }
+
+    #[test]
+    fn sighash_vectors() {
+        let (tx, prevouts) = vector();
+        // input 4 is the vectors' only SIGHASH_DEFAULT signature
+        assert_eq!(
+            sighash(&tx, &prevouts, 4, &SpendPath::Key),
+            Sha256::from_hex("4f900a0bae3f1446fd48490c2958b5a023228f01661cda3496a11da502a7f7ef")
+                .unwrap()
+        );
+        // the vectors have no script path spends, this one is computed
+        // independently from BIP-341's pseudocode
+        let leaf = Sha256::from_inner([0; 32]);
+        assert_eq!(
+            sighash(&tx, &prevouts, 1, &SpendPath::Script(leaf)),
+            Sha256::from_hex("5effc404f673ba5e5850580465f28567bba1f694d23ce0061feb91146cef0e45")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn key_path() {
+        let (_, prevouts) = vector();
+        let secp = Secp256k1::new();
+        // the internal key of the vectors' first output, which has no
+        // script tree
+        let key = bitcoin::PublicKey::from_str(
+            "02d6889cb081036e0faefa3a35157ad71086b123b2b144b649798b494c300a961d",
+        )
+        .unwrap();
+        let input = bitcoin::util::psbt::Input::default();
+        assert!(matches!(
+            spend_path(&key, &input, &prevouts[0], &secp),
+            Some(SpendPath::Key)
+        ));
+        assert!(spend_path(&key, &input, &prevouts[1], &secp).is_none());
+    }
+
+    #[test]
+    fn sign_and_verify_script_path() {
+        let (tx, prevouts) = vector();
+        let secp = Secp256k1::new();
+        let xpriv = ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[1; 32]).unwrap();
+        let key = ExtendedPubKey::from_private(&secp, &xpriv).public_key;
+        let script = Builder::new()
+            .push_slice(&xonly(&key).serialize()[..])
+            .push_opcode(OP_CHECKSIG)
+            .into_script();
+        let mut input = bitcoin::util::psbt::Input {
+            witness_script: Some(script.clone()),
+            ..Default::default()
+        };
+        let path = spend_path(&key, &input, &prevouts[0], &secp).unwrap();
+        assert!(matches!(path, SpendPath::Script(leaf) if leaf == leaf_hash(&script)));
+        sign_input(&xpriv, &mut input, &tx, &prevouts, 0, &path, &secp).unwrap();
+        let (field, sig) = input.unknown.iter().next().unwrap();
+        assert_eq!(field.type_value, PSBT_IN_TAP_SCRIPT_SIG);
+        assert!(verify_input(&key, &tx, &prevouts, 0, &path, sig, &secp));
+        // not for another input, or another transaction
+        assert!(!verify_input(&key, &tx, &prevouts, 1, &path, sig, &secp));
+        let mut other = tx.clone();
+        other.lock_time += 1;
+        assert!(!verify_input(&key, &other, &prevouts, 0, &path, sig, &secp));
+    }
+}
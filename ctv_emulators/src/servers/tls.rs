@@ -0,0 +1,54 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! TLS configuration for exposing an oracle server publicly.
+use super::*;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+pub use tokio_rustls::rustls;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{AllowAnyAuthenticatedClient, NoClientAuth, RootCertStore};
+
+/// Creates a TLS `ServerConfig` from PEM encoded files.
+///
+/// - `cert` is the server's certificate chain.
+/// - `key` is the server's private key (PKCS#8 or RSA).
+/// - if `client_ca` is set, clients must present a certificate signed by one
+///   of the CAs in the file.
+pub fn server_config(
+    cert: &Path,
+    key: &Path,
+    client_ca: Option<&Path>,
+) -> Result<Arc<rustls::ServerConfig>, std::io::Error> {
+    let certs = pemfile::certs(&mut BufReader::new(File::open(cert)?))
+        .or_else(|_| input_error("Invalid Certificate File"))?;
+    let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(key)?))
+        .or_else(|_| input_error("Invalid Key File"))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut BufReader::new(File::open(key)?))
+            .or_else(|_| input_error("Invalid Key File"))?;
+    }
+    let key = match keys.pop() {
+        Some(key) => key,
+        None => return input_error("No Private Key Found"),
+    };
+    let verifier = match client_ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            roots
+                .add_pem_file(&mut BufReader::new(File::open(ca)?))
+                .or_else(|_| input_error("Invalid Client CA File"))?;
+            AllowAnyAuthenticatedClient::new(roots)
+        }
+        None => NoClientAuth::new(),
+    };
+    let mut config = rustls::ServerConfig::new(verifier);
+    config
+        .set_single_cert(certs, key)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    Ok(Arc::new(config))
+}
@@ -0,0 +1,142 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Publishing an oracle as a Tor onion service, via a running Tor daemon's
+//! control port.
+//!
+//! The oracle is bound as usual (e.g. to `127.0.0.1:port`), and Tor forwards
+//! connections to the onion address to it. The service lasts as long as the
+//! `OnionService` is kept, so it should be held for as long as the server
+//! runs. Clients reach it through a SOCKS5 proxy, see
+//! `connections::hd::OracleAddress`.
+use super::*;
+use bitcoin::hashes::hex::ToHex;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+/// The control port a local Tor daemon listens on when enabled
+pub const TOR_DEFAULT_CONTROL: &str = "127.0.0.1:9051";
+
+/// A running onion service
+pub struct OnionService {
+    /// closing the control connection removes the service
+    _control: BufReader<TcpStream>,
+    /// the service's address, without the port
+    pub onion: String,
+    /// the service's key, in Tor's format (`ED25519-V3:...`). Pass it to
+    /// `publish` to keep the same address across restarts.
+    pub key: String,
+}
+
+/// Sends a command and returns the lines of a successful (250) reply, without
+/// the status codes.
+async fn command(control: &mut BufReader<TcpStream>, cmd: &str) -> std::io::Result<Vec<String>> {
+    control.get_mut().write_all(cmd.as_bytes()).await?;
+    control.get_mut().write_all(b"\r\n").await?;
+    let mut lines = vec![];
+    loop {
+        let mut line = String::new();
+        if control.read_line(&mut line).await? == 0 {
+            return input_error("Tor Control Connection Closed");
+        }
+        let line = line.trim_end();
+        if line.len() < 4 {
+            return input_error("Invalid Tor Control Reply");
+        }
+        let (status, rest) = line.split_at(3);
+        if status != "250" {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("Tor Control Error: {}", line),
+            ));
+        }
+        lines.push(rest[1..].to_string());
+        // "250 " ends a reply, "250-" and "250+" continue it
+        if rest.starts_with(' ') {
+            return Ok(lines);
+        }
+    }
+}
+
+/// Authenticates to the control port with the first method Tor accepts of:
+/// no authentication, the cookie file, or `password`.
+async fn authenticate(
+    control: &mut BufReader<TcpStream>,
+    password: Option<&str>,
+) -> std::io::Result<()> {
+    let info = command(control, "PROTOCOLINFO 1").await?;
+    let auth = info
+        .iter()
+        .find_map(|l| l.strip_prefix("AUTH "))
+        .unwrap_or("");
+    let methods: Vec<&str> = auth
+        .split_whitespace()
+        .find_map(|f| f.strip_prefix("METHODS="))
+        .map(|m| m.split(',').collect())
+        .unwrap_or_default();
+    let cookie_file = auth
+        .split("COOKIEFILE=\"")
+        .nth(1)
+        .and_then(|f| f.split('"').next());
+    let cmd = if methods.contains(&"NULL") {
+        "AUTHENTICATE".to_string()
+    } else if let (true, Some(file)) = (methods.contains(&"COOKIE"), cookie_file) {
+        let cookie = tokio::fs::read(file).await?;
+        format!("AUTHENTICATE {}", cookie.to_hex())
+    } else if let (true, Some(password)) = (methods.contains(&"HASHEDPASSWORD"), password) {
+        format!("AUTHENTICATE \"{}\"", password.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        return input_error("No Supported Tor Control Authentication Method");
+    };
+    command(control, &cmd).await?;
+    Ok(())
+}
+
+/// Publishes the oracle listening on `local` as an onion service on
+/// `virtual_port`, via the Tor control port at `control`.
+///
+/// If `key` (as returned in `OnionService::key`) is given, the service keeps
+/// the same address, otherwise a new one is generated.
+pub async fn publish<A: ToSocketAddrs>(
+    control: A,
+    password: Option<&str>,
+    local: SocketAddr,
+    virtual_port: u16,
+    key: Option<&str>,
+) -> std::io::Result<OnionService> {
+    let mut control = BufReader::new(TcpStream::connect(control).await?);
+    authenticate(&mut control, password).await?;
+    let reply = command(
+        &mut control,
+        &format!(
+            "ADD_ONION {} Port={},{}",
+            key.unwrap_or("NEW:ED25519-V3"),
+            virtual_port,
+            local
+        ),
+    )
+    .await?;
+    let field = |name: &str| {
+        reply
+            .iter()
+            .find_map(|l| l.strip_prefix(name))
+            .map(String::from)
+    };
+    let onion = match field("ServiceID=") {
+        Some(id) => format!("{}.onion", id),
+        None => return input_error("Tor Did Not Return a Service ID"),
+    };
+    // Tor only returns the key if it generated it
+    let key = match field("PrivateKey=").or_else(|| key.map(String::from)) {
+        Some(key) => key,
+        None => return input_error("Tor Did Not Return a Key"),
+    };
+    tracing::info!(%onion, port = virtual_port, "published onion service");
+    Ok(OnionService {
+        _control: control,
+        onion,
+        key,
+    })
+}
@@ -0,0 +1,139 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Watch-only oracles, which verify signatures rather than produce them.
+//!
+//! An oracle run with a `WatchOnly` signer (see `HDOracleEmulator::watch_only`)
+//! holds only the root xpub. It refuses to sign, but serves
+//! `msgs::Request::Verify`: given a signed transaction, it derives the key
+//! each input's CTV hash commits to and checks that the input carries a valid
+//! signature by it. As the key is only ever used to sign for that template, a
+//! valid signature shows the transaction is the one committed to. The
+//! response is a `msgs::Attestation`, signed if the server has an attestation
+//! key, so that auditors can monitor a federation without holding its keys.
+use super::*;
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::blockdata::transaction::SigHashType;
+use bitcoin::secp256k1::{Message, Signature};
+use bitcoin::util::psbt::Input;
+use bitcoin::{PublicKey, Script, Transaction, TxOut};
+
+/// A `Signer` with only the root xpub, which can derive unhardened keys but
+/// never signs.
+pub struct WatchOnly {
+    xpub: ExtendedPubKey,
+}
+
+impl WatchOnly {
+    /// create a watch-only signer for `xpub`
+    pub fn new(xpub: ExtendedPubKey) -> Self {
+        WatchOnly { xpub }
+    }
+}
+
+impl signer::Signer for WatchOnly {
+    fn xpub(&self) -> ExtendedPubKey {
+        self.xpub
+    }
+    fn sign_psbt(
+        &self,
+        _psbt: PartiallySignedTransaction,
+        _inputs: &[signer::SigningInput],
+        _prevouts: Option<&[TxOut]>,
+    ) -> Result<PartiallySignedTransaction, std::io::Error> {
+        Err(msgs::ServerError::WatchOnly.into())
+    }
+    fn sign_challenge(&self, _msg: &Message) -> Result<Signature, std::io::Error> {
+        Err(msgs::ServerError::WatchOnly.into())
+    }
+}
+
+/// `input` with the scripts a finalizer clears recovered from its final
+/// script sig and witness, so that a finalized input may be checked like an
+/// unsigned one.
+pub fn with_final_scripts(input: &Input) -> Input {
+    let mut input = input.clone();
+    if input.redeem_script.is_none() {
+        // a p2sh wrapped segwit script sig is a single push of the redeem script
+        if let Some(script_sig) = &input.final_script_sig {
+            if let Some(Ok(Instruction::PushBytes(b))) = script_sig.instructions().last() {
+                input.redeem_script = Some(Script::from(b.to_vec()));
+            }
+        }
+    }
+    if input.witness_script.is_none() {
+        let taproot = input
+            .witness_utxo
+            .as_ref()
+            .map_or(false, |u| taproot::is_v1_witness(&u.script_pubkey));
+        if let Some(witness) = &input.final_script_witness {
+            // p2wsh witnesses end with the script, tapscript witnesses with
+            // the script then the control block
+            let script = match (taproot, witness.len()) {
+                (true, n) if n >= 2 => witness.get(n - 2),
+                (true, _) => None,
+                (false, _) => witness.last(),
+            };
+            input.witness_script = script.map(|s| Script::from(s.clone()));
+        }
+    }
+    input
+}
+
+/// Every signature `input` may carry for `key`: in `partial_sigs`, the
+/// BIP-371 taproot fields, or its final witness.
+fn candidates<'a>(input: &'a Input, key: &PublicKey) -> impl Iterator<Item = &'a Vec<u8>> {
+    input
+        .partial_sigs
+        .get(key)
+        .into_iter()
+        .chain(input.unknown.values())
+        .chain(input.final_script_witness.iter().flatten())
+}
+
+/// Checks that `input` (see `with_final_scripts`) carries a valid signature
+/// by `key`, which must be needed to spend it as `kind` describes.
+///
+/// ECDSA signatures may use any sighash type, taproot signatures must use
+/// SIGHASH_DEFAULT (as the oracle only signs with it), and need `prevouts`.
+pub fn has_signature(
+    key: &PublicKey,
+    input: &Input,
+    kind: &signer::InputKind,
+    tx: &Transaction,
+    idx: usize,
+    prevouts: Option<&[TxOut]>,
+    secp: &Secp256k1<All>,
+) -> Result<bool, std::io::Error> {
+    match kind {
+        signer::InputKind::Ecdsa { scriptcode, .. } => {
+            let mut cache = bitcoin::util::bip143::SigHashCache::new(tx);
+            for sig in candidates(input, key) {
+                let (sighash, der) = match sig.split_last() {
+                    Some((sighash, der)) => (SigHashType::from_u32(*sighash as u32), der),
+                    None => continue,
+                };
+                let sig = match Signature::from_der(der) {
+                    Ok(sig) => sig,
+                    Err(_) => continue,
+                };
+                let msg = signer::ecdsa_message(&mut cache, input, idx, scriptcode, sighash)?;
+                if secp.verify(&msg, &sig, &key.key).is_ok() {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        }
+        signer::InputKind::Taproot(path) => {
+            let prevouts = match prevouts {
+                Some(prevouts) => prevouts,
+                None => return input_error("Taproot Verification Requires All UTXOs"),
+            };
+            Ok(candidates(input, key)
+                .any(|sig| taproot::verify_input(key, tx, prevouts, idx, path, sig, secp)))
+        }
+    }
+}
@@ -0,0 +1,324 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! An in-process oracle and protocol conformance checks, for testing
+//! clients and servers without running one over TCP.
+//!
+//! A `MockOracle` is an `HDOracleEmulator` with a fixed root (see `SEED`)
+//! served over in-memory pipes, so its responses are deterministic. Its
+//! `vectors` are golden request and response frames: a client should encode
+//! each request exactly, and decode each response. `malformed` lists inputs
+//! (bad frames, truncated frames and handshakes) which a server must reject
+//! by closing the connection.
+//!
+//! `conformance` checks a server against both, sending every request a byte
+//! at a time as well as whole, e.g. `MockOracle::new().check()` checks ours.
+//! Every check uses protocol version 1 without optional capabilities, so
+//! that frames are unencrypted JSON.
+use super::*;
+use crate::servers::hd::HDOracleEmulator;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
+
+/// The seed of a `MockOracle`'s root, on regtest
+pub const SEED: [u8; 32] = [1u8; 32];
+/// The seed of a root no `MockOracle` has, for checking refusals
+const FOREIGN_SEED: [u8; 32] = [2u8; 32];
+/// How long a check waits for a server before failing
+const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+/// The size of an in-memory pipe's buffers
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// the root key for `seed` on regtest
+fn root(seed: &[u8]) -> ExtendedPrivKey {
+    ExtendedPrivKey::new_master(bitcoin::Network::Regtest, seed).expect("Seed Is Valid")
+}
+
+/// the Hello every check connects with
+fn hello() -> protocol::Hello {
+    protocol::Hello {
+        version: 1,
+        capabilities: protocol::Capabilities::NONE,
+        max_message: protocol::DEFAULT_MAX_MESSAGE,
+    }
+}
+
+/// A request a conformant server always answers with the same response
+pub struct Vector {
+    pub name: &'static str,
+    /// the request's frame contents
+    pub request: Vec<u8>,
+    /// the response's frame contents
+    pub response: Vec<u8>,
+}
+
+/// Input a conformant server closes the connection on, without responding
+pub struct Malformed {
+    pub name: &'static str,
+    /// if the input is sent after a handshake, rather than in place of one
+    pub after_handshake: bool,
+    /// the bytes sent, after which the client stops writing
+    pub bytes: Vec<u8>,
+}
+
+/// How a server failed a conformance check
+#[derive(Debug)]
+pub struct Violation {
+    /// the vector or malformed input checked
+    pub case: &'static str,
+    pub reason: String,
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}", self.case, self.reason)
+    }
+}
+impl std::error::Error for Violation {}
+
+/// An oracle with a fixed root, served in-process
+#[derive(Clone)]
+pub struct MockOracle {
+    root: ExtendedPrivKey,
+    oracle: HDOracleEmulator,
+}
+
+impl Default for MockOracle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockOracle {
+    /// an oracle with the root for `SEED` and default settings
+    pub fn new() -> Self {
+        let root = root(&SEED[..]);
+        MockOracle {
+            root,
+            oracle: HDOracleEmulator::new(root),
+        }
+    }
+    /// the oracle's root xpub, which clients connect with
+    pub fn xpub(&self) -> ExtendedPubKey {
+        SECP.with(|secp| ExtendedPubKey::from_private(secp, &self.root))
+    }
+    /// the oracle, e.g. to configure a policy before connecting
+    pub fn oracle(&self) -> &HDOracleEmulator {
+        &self.oracle
+    }
+    /// Opens a connection to the oracle over an in-memory pipe, returning the
+    /// client's end. Must be called within a tokio runtime, on which the
+    /// oracle serves the connection.
+    pub fn connect(&self) -> DuplexStream {
+        let (client, server) = tokio::io::duplex(PIPE_SIZE);
+        self.oracle.serve_stream(server);
+        client
+    }
+    /// The golden requests for this oracle, with the responses it sends.
+    pub fn vectors(&self) -> Result<Vec<Vector>, std::io::Error> {
+        let codec = msgs::Codec::Json;
+        let xpub = self.xpub();
+        let foreign = SECP.with(|secp| ExtendedPubKey::from_private(secp, &root(&FOREIGN_SEED)));
+        let h = Sha256::hash(b"CTVE conformance");
+        let scheme = derivation::DerivationScheme::default();
+        let key = SECP.with(|secp| {
+            self.root
+                .derive_priv(secp, &scheme.path(h))
+                .map(|k| ExtendedPubKey::from_private(secp, &k).public_key)
+        });
+        let key = key.or_else(|_| input_error("Could Not Derive Key"))?;
+        let msg = msgs::DerivedKey::message(&h, scheme, &key);
+        let signature = SECP.with(|secp| secp.sign(&msg, &self.root.private_key.key));
+        let mismatch = msgs::Response::Error(msgs::ServerError::KeyMismatch(foreign.fingerprint()));
+        Ok(vec![
+            Vector {
+                name: "derive_key",
+                request: codec.encode(&msgs::Request::DeriveKey(msgs::DeriveKey(xpub, h)))?,
+                response: codec.encode(&msgs::Response::DerivedKey(msgs::DerivedKey(
+                    key, signature,
+                )))?,
+            },
+            Vector {
+                name: "derive_key_unknown_root",
+                request: codec.encode(&msgs::Request::DeriveKey(msgs::DeriveKey(foreign, h)))?,
+                response: codec.encode(&mismatch)?,
+            },
+            Vector {
+                name: "confirm_unknown_key",
+                request: codec.encode(&msgs::Request::ConfirmKey(msgs::ConfirmKey(foreign, h)))?,
+                response: codec.encode(&mismatch)?,
+            },
+        ])
+    }
+    /// checks this oracle with `conformance`
+    pub async fn check(&self) -> Result<(), Violation> {
+        let vectors = self.vectors().map_err(|e| Violation {
+            case: "vectors",
+            reason: e.to_string(),
+        })?;
+        let this = self.clone();
+        conformance(&vectors[..], move || {
+            let stream = this.connect();
+            async move { Ok(stream) }
+        })
+        .await
+    }
+}
+
+/// a frame as sent without encryption
+fn frame(data: &[u8]) -> Vec<u8> {
+    let mut v = (data.len() as u32).to_be_bytes().to_vec();
+    v.extend_from_slice(data);
+    v
+}
+
+/// Inputs which must close the connection, see `Malformed`.
+pub fn malformed() -> Vec<Malformed> {
+    let request = br#"{"DeriveKey":["#;
+    let mut truncated = (100u32).to_be_bytes().to_vec();
+    truncated.extend_from_slice(&request[..]);
+    vec![
+        Malformed {
+            name: "truncated_hello",
+            after_handshake: false,
+            bytes: hello().to_bytes()[..6].to_vec(),
+        },
+        Malformed {
+            name: "truncated_length",
+            after_handshake: true,
+            bytes: vec![0, 0],
+        },
+        Malformed {
+            name: "truncated_frame",
+            after_handshake: true,
+            bytes: truncated,
+        },
+        Malformed {
+            name: "oversized_frame",
+            after_handshake: true,
+            bytes: u32::MAX.to_be_bytes().to_vec(),
+        },
+        Malformed {
+            name: "empty_frame",
+            after_handshake: true,
+            bytes: frame(b""),
+        },
+        Malformed {
+            name: "invalid_json",
+            after_handshake: true,
+            bytes: frame(b"{not json"),
+        },
+        Malformed {
+            name: "unknown_request",
+            after_handshake: true,
+            bytes: frame(br#"{"Unknown":null}"#),
+        },
+        Malformed {
+            name: "oversized_psbt",
+            after_handshake: true,
+            bytes: frame(br#"{"SignPSBT":[255,255,255,255]}"#),
+        },
+    ]
+}
+
+/// fails `case` with the reason
+fn violation(case: &'static str, reason: impl std::fmt::Display) -> Violation {
+    Violation {
+        case,
+        reason: reason.to_string(),
+    }
+}
+
+/// runs `f`, failing `case` if it errors or takes longer than `TIMEOUT`
+async fn within<T, F>(case: &'static str, f: F) -> Result<T, Violation>
+where
+    F: std::future::Future<Output = Result<T, std::io::Error>>,
+{
+    match tokio::time::timeout(TIMEOUT, f).await {
+        Ok(r) => r.map_err(|e| violation(case, e)),
+        Err(_) => Err(violation(case, "Timed Out")),
+    }
+}
+
+/// writes `bytes` a byte at a time, so that the peer's reads are partial
+async fn trickle<S: AsyncWrite + Unpin>(s: &mut S, bytes: &[u8]) -> Result<(), std::io::Error> {
+    for b in bytes {
+        s.write_all(&[*b][..]).await?;
+        s.flush().await?;
+        tokio::task::yield_now().await;
+    }
+    Ok(())
+}
+
+/// sends `vector`'s request on a new connection, checking the response
+async fn check_vector<S>(vector: &Vector, mut s: S, whole: bool) -> Result<(), Violation>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let name = vector.name;
+    let mut session = within(name, protocol::Session::connect(&mut s, hello())).await?;
+    if whole {
+        within(name, session.write_frame(&mut s, &vector.request[..])).await?;
+    } else {
+        within(name, trickle(&mut s, &frame(&vector.request[..])[..])).await?;
+    }
+    let response = within(name, session.read_frame(&mut s)).await?;
+    if response != vector.response {
+        return Err(violation(
+            name,
+            format!(
+                "Expected Response {}, Got {}",
+                String::from_utf8_lossy(&vector.response[..]),
+                String::from_utf8_lossy(&response[..])
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// sends `input` on a new connection, checking that it is closed unanswered
+async fn check_malformed<S>(input: &Malformed, mut s: S) -> Result<(), Violation>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let name = input.name;
+    if input.after_handshake {
+        within(name, protocol::Session::connect(&mut s, hello())).await?;
+    }
+    within(name, s.write_all(&input.bytes[..])).await?;
+    within(name, s.shutdown()).await?;
+    let mut rest = vec![];
+    // a reset is as good as a close
+    let _ = within(name, s.read_to_end(&mut rest)).await;
+    if !rest.is_empty() {
+        return Err(violation(name, "Server Responded Instead of Closing"));
+    }
+    Ok(())
+}
+
+/// Checks a server, connecting to it with `connect` for each case: every
+/// vector is sent whole and a byte at a time, every `malformed` input is
+/// sent, and finally the vectors are sent again to check that the server
+/// still serves.
+///
+/// `vectors` must be for the server's root, e.g. `MockOracle::vectors`.
+pub async fn conformance<F, Fut, S>(vectors: &[Vector], connect: F) -> Result<(), Violation>
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = Result<S, std::io::Error>>,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    for vector in vectors {
+        check_vector(vector, within(vector.name, connect()).await?, true).await?;
+        check_vector(vector, within(vector.name, connect()).await?, false).await?;
+    }
+    for input in malformed() {
+        check_malformed(&input, within(input.name, connect()).await?).await?;
+    }
+    for vector in vectors {
+        check_vector(vector, within(vector.name, connect()).await?, true).await?;
+    }
+    Ok(())
+}
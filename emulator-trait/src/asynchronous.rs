@@ -0,0 +1,62 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! an async version of `CTVEmulator`, for callers which should not block
+//! while an emulator waits on the network.
+use super::emulator::*;
+use bitcoin::hashes::sha256;
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+/// The future returned by an `AsyncCTVEmulator`'s methods
+pub type EmulatorFuture<'a, T> =
+    Pin<Box<dyn Future<Output = Result<T, EmulatorError>> + Send + 'a>>;
+
+/// `AsyncCTVEmulator` is `CTVEmulator` with methods which return futures,
+/// so that emulators which talk to oracles can be awaited rather than block
+/// the calling thread.
+pub trait AsyncCTVEmulator: Sync + Send {
+    /// For a given transaction hash, gets the corresponding Clause that the
+    /// Emulator would satisfy.
+    fn get_signer_for_async(&self, h: sha256::Hash) -> EmulatorFuture<'_, Clause>;
+    /// Adds the Emulators signature to the PSBT, if any.
+    fn sign_async(
+        &self,
+        b: PartiallySignedTransaction,
+    ) -> EmulatorFuture<'_, PartiallySignedTransaction>;
+}
+
+impl AsyncCTVEmulator for CTVAvailable {
+    fn get_signer_for_async(&self, h: sha256::Hash) -> EmulatorFuture<'_, Clause> {
+        Box::pin(std::future::ready(self.get_signer_for(h)))
+    }
+    fn sign_async(
+        &self,
+        b: PartiallySignedTransaction,
+    ) -> EmulatorFuture<'_, PartiallySignedTransaction> {
+        Box::pin(std::future::ready(self.sign(b)))
+    }
+}
+
+/// Wraps a `CTVEmulator` which never blocks (e.g., one which signs with a
+/// local key) as an `AsyncCTVEmulator` whose futures are ready immediately.
+///
+/// Emulators which do block should implement `AsyncCTVEmulator` themselves.
+pub struct Immediate(pub Arc<dyn CTVEmulator>);
+
+impl AsyncCTVEmulator for Immediate {
+    fn get_signer_for_async(&self, h: sha256::Hash) -> EmulatorFuture<'_, Clause> {
+        Box::pin(std::future::ready(self.0.get_signer_for(h)))
+    }
+    fn sign_async(
+        &self,
+        b: PartiallySignedTransaction,
+    ) -> EmulatorFuture<'_, PartiallySignedTransaction> {
+        Box::pin(std::future::ready(self.0.sign(b)))
+    }
+}
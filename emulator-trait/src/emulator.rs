@@ -49,6 +49,17 @@ pub trait CTVEmulator: Sync + Send {
         &self,
         b: PartiallySignedTransaction,
     ) -> Result<PartiallySignedTransaction, EmulatorError>;
+    /// Adds the Emulators signature to every PSBT of a batch.
+    ///
+    /// By default each PSBT is signed in turn, so an error may occur after
+    /// some have been signed. Emulators which sign a batch all-or-nothing
+    /// override this.
+    fn sign_batch(
+        &self,
+        batch: Vec<PartiallySignedTransaction>,
+    ) -> Result<Vec<PartiallySignedTransaction>, EmulatorError> {
+        batch.into_iter().map(|b| self.sign(b)).collect()
+    }
 }
 
 /// A wrapper for an optional internal emulator trait object. If no emulator is
@@ -7,3 +7,7 @@
 #[deny(missing_docs)]
 pub mod emulator;
 pub use emulator::*;
+#[cfg(feature = "async")]
+pub mod asynchronous;
+#[cfg(feature = "async")]
+pub use asynchronous::*;
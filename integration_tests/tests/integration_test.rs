@@ -32,15 +32,14 @@ impl<T> TestEmulation<T>
 where
     T: Compilable,
 {
-    then!(
-        complete | s,
-        ctx | {
+    then! {
+        fn complete(self, ctx) {
             ctx.template()
-                .add_output(s.amount, &s.to_contract, None)?
-                .set_sequence(0, RelTime::from(s.timeout).into())?
+                .add_output(self.amount, &self.to_contract, None)?
+                .set_sequence(0, RelTime::from(self.timeout).into())?
                 .into()
         }
-    );
+    }
 }
 
 impl<T: Compilable + 'static> Contract for TestEmulation<T> {
@@ -59,7 +58,7 @@ fn test_connect() {
     {
         let rt = rt1.clone();
         std::thread::spawn(move || {
-            let oracle = HDOracleEmulator::new(root, true);
+            let oracle = HDOracleEmulator::new(root);
             rt.block_on(async {
                 let server = tokio::spawn(oracle.bind("127.0.0.1:8080"));
                 quit.await.unwrap();
@@ -97,7 +96,7 @@ fn test_connect() {
         .compile(&Context::new(
             bitcoin::Network::Regtest,
             Amount::from_btc(1.0).unwrap(),
-            Some(rc_conn.clone()),
+            rc_conn.clone(),
         ))
         .unwrap();
     let txindex: Rc<dyn TxIndex> = Rc::new(TxIndexLogger::new());
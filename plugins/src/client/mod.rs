@@ -8,6 +8,7 @@ use super::*;
 pub use api::*;
 use bitcoin::hashes::Hash;
 use ext::*;
+use sapio::contract::schema::SchemaOf;
 use sapio::contract::Compiled;
 use sapio_ctv_emulator_trait::CTVEmulator;
 use serde_json::Value;
@@ -8,10 +8,10 @@
 use super::*;
 /// The `Plugin` trait is used to provide bindings for a WASM Plugin.
 /// It's not intended to be used internally, just as bindings.
-pub trait Plugin: JsonSchema + Sized + for<'a> Deserialize<'a> + Compilable {
-    /// gets the jsonschema for the plugin type, which is the API for calling create.
+pub trait Plugin: SchemaOf + Compilable {
+    /// gets the jsonschema for the plugin's arguments, which is the API for calling create.
     fn get_api_inner() -> *mut c_char {
-        encode_json(&schemars::schema_for!(Self))
+        encode_json(&Self::schema())
     }
 
     /// creates an instance of the plugin from a json pointer and outputs a result pointer
@@ -25,9 +25,10 @@ pub trait Plugin: JsonSchema + Sized + for<'a> Deserialize<'a> + Compilable {
     }
     unsafe fn create_result(c: *mut c_char) -> Result<String, Box<dyn Error>> {
         let s = CString::from_raw(c);
-        let CreateArgs::<Self>(s, net, amt) = serde_json::from_slice(s.to_bytes())?;
+        let CreateArgs::<Self::Args>(s, net, amt) = serde_json::from_slice(s.to_bytes())?;
         let ctx = Context::new(net, amt, Arc::new(client::WasmHostEmulator));
-        Ok(serde_json::to_string_pretty(&s.compile(&ctx)?)?)
+        let contract = Self::from_args(s)?;
+        Ok(serde_json::to_string_pretty(&contract.compile(&ctx)?)?)
     }
     /// binds this type to the wasm interface, must be called before the plugin can be used.
     unsafe fn register(name: &'static str) {
@@ -47,12 +48,18 @@ fn encode_json<S: Serialize>(s: &S) -> *mut c_char {
 }
 
 /// A helper macro to implement the plugin interface for a plugin-type
-/// and register it to the plugin entry point.
+/// and register it to the plugin entry point. A plugin-type which is its own
+/// arguments gets `SchemaOf` implemented, otherwise use `REGISTER![T, args]`
+/// for a type implementing `SchemaOf` itself.
 ///
 /// U.B. to call REGISTER more than once because of the internal #[no_mangle]
 #[macro_export]
 macro_rules! REGISTER {
     [$plugin:ident] => {
+        sapio::schema_of!($plugin);
+        $crate::REGISTER![$plugin, args];
+    };
+    [$plugin:ident, args] => {
         impl Plugin for $plugin {
         }
         #[no_mangle]
@@ -9,6 +9,10 @@
 pub mod util;
 pub use util::CTVHash;
 
+/// Reading and writing PSBTs as version 2 (BIP-370)
+pub mod psbt;
+/// Laying out clauses as taproot outputs (BIP-341)
+pub mod taproot;
 /// Helpers for making correct time locks
 pub mod timelocks;
 /// Trait & Structs for accessing Chain Data
@@ -17,10 +21,141 @@ pub mod txindex;
 /// Concrete Instantiation of Miniscript Policy. Because we need to be able to generate exact
 /// transactions, we only work with `bitcoin::PublicKey` types.
 pub type Clause = miniscript::policy::concrete::Policy<bitcoin::PublicKey>;
+
+/// The version of sapio-base, as recorded in compilation manifests
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 #[cfg(test)]
 mod tests {
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn psbt_v2_roundtrip() {
+        use super::psbt::*;
+        use bitcoin::util::psbt::PartiallySignedTransaction;
+        let tx = bitcoin::Transaction {
+            version: 2,
+            lock_time: 500,
+            input: vec![bitcoin::TxIn {
+                previous_output: bitcoin::OutPoint::default(),
+                script_sig: bitcoin::Script::new(),
+                sequence: 6,
+                witness: vec![],
+            }],
+            output: vec![bitcoin::TxOut {
+                value: 1000,
+                script_pubkey: bitcoin::Script::from(vec![0x51]),
+            }],
+        };
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_script = Some(bitcoin::Script::from(vec![0x51]));
+        let v2 = encode(&psbt, PSBTVersion::V2);
+        assert_eq!(version_of(&v2[..]).unwrap(), PSBTVersion::V2);
+        let (decoded, version) = decode(&v2[..]).unwrap();
+        assert_eq!(version, PSBTVersion::V2);
+        assert_eq!(decoded, psbt);
+        let v0 = encode(&decoded, PSBTVersion::V0);
+        assert_eq!(decode(&v0[..]).unwrap(), (psbt, PSBTVersion::V0));
+    }
+
+    #[test]
+    fn taproot_layout() {
+        use super::taproot::*;
+        use super::Clause;
+        use bitcoin::hashes::Hash;
+        let secp = bitcoin::secp256k1::Secp256k1::new();
+        let key = |i: u8| {
+            let sk = bitcoin::secp256k1::SecretKey::from_slice(&[i; 32]).unwrap();
+            bitcoin::PublicKey {
+                compressed: true,
+                key: bitcoin::secp256k1::PublicKey::from_secret_key(&secp, &sk),
+            }
+        };
+        let template = Clause::TxTemplate(Hash::hash(b"template"));
+        let branches = vec![
+            Clause::And(vec![Clause::Key(key(1)), Clause::Key(key(2))]),
+            Clause::And(vec![Clause::Older(144), template.clone()]),
+            Clause::Threshold(
+                2,
+                vec![
+                    Clause::Key(key(1)),
+                    Clause::Key(key(2)),
+                    Clause::Key(key(3)),
+                ],
+            ),
+            Clause::Key(key(3)),
+        ];
+        let out = TaprootOutput::from_clause(&Clause::Threshold(1, branches.clone())).unwrap();
+        // the first leaf of only keys becomes the key path
+        assert_eq!(out.key_path, vec![key(3)]);
+        assert_eq!(out.leaves.len(), 3);
+        assert_eq!(out.script_pubkey().len(), 34);
+        for leaf in out.leaves.iter() {
+            assert!(verify_control_block(
+                &out.output_key,
+                &leaf.script,
+                &leaf.control_block
+            ));
+            assert!(!verify_control_block(
+                &key(4),
+                &leaf.script,
+                &leaf.control_block
+            ));
+        }
+        // the layout does not depend on the order of the branches
+        let mut reversed = branches;
+        reversed.reverse();
+        let other = TaprootOutput::from_clause(&Clause::Threshold(1, reversed)).unwrap();
+        assert_eq!(out, other);
+        let mut out = out;
+        let h = Hash::hash(b"template");
+        assert!(out.link_template(h, &template));
+        assert!(out
+            .leaf_for(&h)
+            .unwrap()
+            .clauses
+            .contains(&Clause::Older(144)));
+        assert!(TaprootOutput::from_clause(&Clause::Unsatisfiable).is_err());
+        // several keys are only spent with the key path if aggregated
+        let both = Clause::And(vec![Clause::Key(key(1)), Clause::Key(key(2))]);
+        let branches = Clause::Threshold(1, vec![both, template]);
+        let out = TaprootOutput::from_clause(&branches).unwrap();
+        assert!(out.key_path.is_empty());
+        assert_eq!(out.leaves.len(), 2);
+        let out = TaprootOutput::from_clause_with(&branches, &CheckTemplateVerify, true).unwrap();
+        assert_eq!(out.key_path.len(), 2);
+        assert_eq!(
+            out.internal_key.key.serialize()[1..],
+            aggregate_keys(&[key(1), key(2)]).unwrap().serialize()[1..]
+        );
+        assert_eq!(out.leaves.len(), 1);
+    }
+
+    #[test]
+    fn timelock_units() {
+        use super::timelocks::units::*;
+        use super::timelocks::*;
+        use super::Clause;
+        use std::convert::TryFrom;
+        assert_eq!(Clause::from(Blocks(144)), Clause::Older(144));
+        // seconds round up to 512 second intervals
+        assert_eq!(
+            RelTime::try_from(Seconds(513)).unwrap().get(),
+            RelTime::from(2u16).get()
+        );
+        assert_eq!(
+            Seconds(512 * 10).relative().unwrap().get(),
+            RelTime::from(10u16).get()
+        );
+        assert!(Seconds(512 * 65536).relative().is_err());
+        assert_eq!(AbsoluteHeight(700_000).absolute().unwrap().get(), 700_000);
+        assert!(AbsoluteHeight(500_000_000).absolute().is_err());
+        assert_eq!(
+            Clause::try_from(MTP(1_600_000_000)).unwrap(),
+            Clause::After(1_600_000_000)
+        );
+        assert!(MTP(700_000).absolute().is_err());
+    }
 }
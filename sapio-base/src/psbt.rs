@@ -0,0 +1,378 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Reading and writing PSBTs in version 2 (BIP-370) as well as version 0.
+//!
+//! A version 2 PSBT has no unsigned transaction in its global map. Instead,
+//! the transaction's version and locktime are global fields, each input
+//! records the outpoint and sequence it spends, and each output its amount
+//! and script. The remaining fields are the same in both versions, so a PSBT
+//! is converted by moving the transaction in or out of those fields; in
+//! memory every PSBT is a (version 0) `PartiallySignedTransaction`.
+//!
+//! PSBTs written as version 2 set no `PSBT_GLOBAL_TX_MODIFIABLE` flags, as a
+//! transaction committed to by CTV can't have inputs or outputs added.
+use bitcoin::consensus::encode::{deserialize, serialize, Decodable, Encodable, Error, VarInt};
+use bitcoin::hashes::Hash;
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::{OutPoint, Script, Transaction, TxIn, TxOut, Txid};
+
+/// The magic bytes every PSBT starts with
+const MAGIC: &[u8] = b"psbt\xff";
+
+const PSBT_GLOBAL_UNSIGNED_TX: u8 = 0x00;
+const PSBT_GLOBAL_TX_VERSION: u8 = 0x02;
+const PSBT_GLOBAL_FALLBACK_LOCKTIME: u8 = 0x03;
+const PSBT_GLOBAL_INPUT_COUNT: u8 = 0x04;
+const PSBT_GLOBAL_OUTPUT_COUNT: u8 = 0x05;
+const PSBT_GLOBAL_TX_MODIFIABLE: u8 = 0x06;
+const PSBT_GLOBAL_VERSION: u8 = 0xfb;
+const PSBT_IN_PREVIOUS_TXID: u8 = 0x0e;
+const PSBT_IN_OUTPUT_INDEX: u8 = 0x0f;
+const PSBT_IN_SEQUENCE: u8 = 0x10;
+const PSBT_IN_REQUIRED_TIME_LOCKTIME: u8 = 0x11;
+const PSBT_IN_REQUIRED_HEIGHT_LOCKTIME: u8 = 0x12;
+const PSBT_OUT_AMOUNT: u8 = 0x03;
+const PSBT_OUT_SCRIPT: u8 = 0x04;
+
+/// The PSBT versions which can be read and written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PSBTVersion {
+    /// BIP-174, with a global unsigned transaction
+    V0,
+    /// BIP-370, with the transaction spread across the input and output maps
+    V2,
+}
+
+impl Default for PSBTVersion {
+    fn default() -> Self {
+        PSBTVersion::V0
+    }
+}
+
+/// A key-value map of a PSBT, in the order it was read
+type Map = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// the value of the field of type `t` in `map`, if any
+fn field(map: &Map, t: u8) -> Option<&[u8]> {
+    map.iter()
+        .find(|(k, _)| k.len() == 1 && k[0] == t)
+        .map(|(_, v)| &v[..])
+}
+
+/// decodes the value of the field of type `t` in `map`, if any
+fn decode_field<T: Decodable>(map: &Map, t: u8) -> Result<Option<T>, Error> {
+    field(map, t).map(deserialize).transpose()
+}
+
+/// decodes the value of the required field of type `t` in `map`
+fn required<T: Decodable>(map: &Map, t: u8, missing: &'static str) -> Result<T, Error> {
+    decode_field(map, t)?.ok_or(Error::ParseFailed(missing))
+}
+
+/// reads a map, up to and including its terminating zero length key
+fn read_map(mut d: &[u8]) -> Result<(Map, &[u8]), Error> {
+    let mut map = Map::new();
+    loop {
+        let VarInt(len) = Decodable::consensus_decode(&mut d)?;
+        if len == 0 {
+            return Ok((map, d));
+        }
+        if len as usize > d.len() {
+            return Err(Error::ParseFailed("PSBT Key Exceeds Input"));
+        }
+        let (key, rest) = d.split_at(len as usize);
+        d = rest;
+        let value: Vec<u8> = Decodable::consensus_decode(&mut d)?;
+        if map.iter().any(|(k, _)| k[..] == key[..]) {
+            return Err(Error::ParseFailed("Duplicate PSBT Key"));
+        }
+        map.push((key.to_vec(), value));
+    }
+}
+
+/// writes a map, sorted by key
+fn write_map(mut map: Map, out: &mut Vec<u8>) {
+    map.sort();
+    for (key, value) in map {
+        VarInt(key.len() as u64)
+            .consensus_encode(&mut *out)
+            .expect("Writing to a Vec can't fail");
+        out.extend_from_slice(&key[..]);
+        value
+            .consensus_encode(&mut *out)
+            .expect("Writing to a Vec can't fail");
+    }
+    out.push(0);
+}
+
+/// A PSBT's maps, regardless of version
+struct Maps {
+    global: Map,
+    inputs: Vec<Map>,
+    outputs: Vec<Map>,
+}
+
+impl Maps {
+    /// reads every map of a PSBT
+    fn read(bytes: &[u8]) -> Result<Self, Error> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(Error::ParseFailed("Missing PSBT Magic"));
+        }
+        let (global, mut rest) = read_map(&bytes[MAGIC.len()..])?;
+        let (n_inputs, n_outputs) = match version_of_global(&global)? {
+            PSBTVersion::V0 => {
+                let tx: Transaction = required(
+                    &global,
+                    PSBT_GLOBAL_UNSIGNED_TX,
+                    "Missing Unsigned Transaction",
+                )?;
+                (tx.input.len() as u64, tx.output.len() as u64)
+            }
+            PSBTVersion::V2 => {
+                let VarInt(i) = required(&global, PSBT_GLOBAL_INPUT_COUNT, "Missing Input Count")?;
+                let VarInt(o) =
+                    required(&global, PSBT_GLOBAL_OUTPUT_COUNT, "Missing Output Count")?;
+                (i, o)
+            }
+        };
+        // every map takes at least a byte, so larger counts can't be valid
+        if n_inputs.saturating_add(n_outputs) > rest.len() as u64 {
+            return Err(Error::ParseFailed("PSBT Has Too Few Maps"));
+        }
+        let mut inputs = Vec::with_capacity(n_inputs as usize);
+        for _ in 0..n_inputs {
+            let (map, r) = read_map(rest)?;
+            inputs.push(map);
+            rest = r;
+        }
+        let mut outputs = Vec::with_capacity(n_outputs as usize);
+        for _ in 0..n_outputs {
+            let (map, r) = read_map(rest)?;
+            outputs.push(map);
+            rest = r;
+        }
+        if !rest.is_empty() {
+            return Err(Error::ParseFailed("Data After PSBT"));
+        }
+        Ok(Maps {
+            global,
+            inputs,
+            outputs,
+        })
+    }
+    /// writes every map of a PSBT
+    fn write(self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        write_map(self.global, &mut out);
+        for map in self.inputs {
+            write_map(map, &mut out);
+        }
+        for map in self.outputs {
+            write_map(map, &mut out);
+        }
+        out
+    }
+}
+
+/// the version of a PSBT with global map `global`
+fn version_of_global(global: &Map) -> Result<PSBTVersion, Error> {
+    match decode_field::<u32>(global, PSBT_GLOBAL_VERSION)? {
+        None | Some(0) => Ok(PSBTVersion::V0),
+        Some(2) => Ok(PSBTVersion::V2),
+        Some(_) => Err(Error::ParseFailed("Unsupported PSBT Version")),
+    }
+}
+
+/// The version of the serialized PSBT `bytes`
+pub fn version_of(bytes: &[u8]) -> Result<PSBTVersion, Error> {
+    if !bytes.starts_with(MAGIC) {
+        return Err(Error::ParseFailed("Missing PSBT Magic"));
+    }
+    version_of_global(&read_map(&bytes[MAGIC.len()..])?.0)
+}
+
+/// removes the fields of `types` (whose keys are only their type) from `map`
+fn without(map: Map, types: &[u8]) -> Map {
+    map.into_iter()
+        .filter(|(k, _)| !(k.len() == 1 && types.contains(&k[0])))
+        .collect()
+}
+
+/// the locktime of a version 2 PSBT, per BIP-370: the greatest locktime any
+/// input requires, preferring heights if inputs allow either, or the fallback
+/// locktime if no input requires one.
+fn locktime(global: &Map, inputs: &[Map]) -> Result<u32, Error> {
+    let mut locks = vec![];
+    for input in inputs {
+        let time: Option<u32> = decode_field(input, PSBT_IN_REQUIRED_TIME_LOCKTIME)?;
+        let height: Option<u32> = decode_field(input, PSBT_IN_REQUIRED_HEIGHT_LOCKTIME)?;
+        if time.is_some() || height.is_some() {
+            locks.push((time, height));
+        }
+    }
+    if locks.is_empty() {
+        return Ok(decode_field(global, PSBT_GLOBAL_FALLBACK_LOCKTIME)?.unwrap_or(0));
+    }
+    if locks.iter().all(|(_, height)| height.is_some()) {
+        Ok(locks.iter().filter_map(|(_, h)| *h).max().unwrap_or(0))
+    } else if locks.iter().all(|(time, _)| time.is_some()) {
+        Ok(locks.iter().filter_map(|(t, _)| *t).max().unwrap_or(0))
+    } else {
+        Err(Error::ParseFailed("Inputs Require Incompatible Locktimes"))
+    }
+}
+
+/// Reads a serialized PSBT of either version.
+pub fn decode(bytes: &[u8]) -> Result<(PartiallySignedTransaction, PSBTVersion), Error> {
+    let version = version_of(bytes)?;
+    let psbt = match version {
+        PSBTVersion::V0 => deserialize(bytes)?,
+        PSBTVersion::V2 => from_v2(bytes)?,
+    };
+    Ok((psbt, version))
+}
+
+/// Serializes a PSBT as `version`.
+pub fn encode(psbt: &PartiallySignedTransaction, version: PSBTVersion) -> Vec<u8> {
+    match version {
+        PSBTVersion::V0 => serialize(psbt),
+        PSBTVersion::V2 => to_v2(psbt),
+    }
+}
+
+/// Reads a version 2 PSBT, see the module docs.
+pub fn from_v2(bytes: &[u8]) -> Result<PartiallySignedTransaction, Error> {
+    let maps = Maps::read(bytes)?;
+    if version_of_global(&maps.global)? != PSBTVersion::V2 {
+        return Err(Error::ParseFailed("Not a Version 2 PSBT"));
+    }
+    if field(&maps.global, PSBT_GLOBAL_UNSIGNED_TX).is_some() {
+        return Err(Error::ParseFailed(
+            "Version 2 PSBT With Unsigned Transaction",
+        ));
+    }
+    let mut input = Vec::with_capacity(maps.inputs.len());
+    for map in &maps.inputs {
+        let txid: Txid = required(map, PSBT_IN_PREVIOUS_TXID, "Missing Previous Txid")?;
+        let vout: u32 = required(map, PSBT_IN_OUTPUT_INDEX, "Missing Output Index")?;
+        input.push(TxIn {
+            previous_output: OutPoint::new(txid, vout),
+            script_sig: Script::new(),
+            sequence: decode_field(map, PSBT_IN_SEQUENCE)?.unwrap_or(0xffffffff),
+            witness: vec![],
+        });
+    }
+    let mut output = Vec::with_capacity(maps.outputs.len());
+    for map in &maps.outputs {
+        let value: u64 = required(map, PSBT_OUT_AMOUNT, "Missing Output Amount")?;
+        // the script is the whole value, without a length prefix
+        let script_pubkey =
+            field(map, PSBT_OUT_SCRIPT).ok_or(Error::ParseFailed("Missing Output Script"))?;
+        output.push(TxOut {
+            value,
+            script_pubkey: Script::from(script_pubkey.to_vec()),
+        });
+    }
+    let tx = Transaction {
+        version: required(&maps.global, PSBT_GLOBAL_TX_VERSION, "Missing Tx Version")?,
+        lock_time: locktime(&maps.global, &maps.inputs[..])?,
+        input,
+        output,
+    };
+    let mut global = without(
+        maps.global,
+        &[
+            PSBT_GLOBAL_TX_VERSION,
+            PSBT_GLOBAL_FALLBACK_LOCKTIME,
+            PSBT_GLOBAL_INPUT_COUNT,
+            PSBT_GLOBAL_OUTPUT_COUNT,
+            PSBT_GLOBAL_TX_MODIFIABLE,
+            PSBT_GLOBAL_VERSION,
+        ],
+    );
+    global.push((vec![PSBT_GLOBAL_UNSIGNED_TX], serialize(&tx)));
+    let v0 = Maps {
+        global,
+        inputs: maps
+            .inputs
+            .into_iter()
+            .map(|map| {
+                without(
+                    map,
+                    &[
+                        PSBT_IN_PREVIOUS_TXID,
+                        PSBT_IN_OUTPUT_INDEX,
+                        PSBT_IN_SEQUENCE,
+                        PSBT_IN_REQUIRED_TIME_LOCKTIME,
+                        PSBT_IN_REQUIRED_HEIGHT_LOCKTIME,
+                    ],
+                )
+            })
+            .collect(),
+        outputs: maps
+            .outputs
+            .into_iter()
+            .map(|map| without(map, &[PSBT_OUT_AMOUNT, PSBT_OUT_SCRIPT]))
+            .collect(),
+    };
+    deserialize(&v0.write()[..])
+}
+
+/// Serializes a PSBT as version 2, see the module docs.
+///
+/// The transaction's locktime is written as the fallback locktime, so that
+/// it is kept when read back.
+pub fn to_v2(psbt: &PartiallySignedTransaction) -> Vec<u8> {
+    let tx = &psbt.global.unsigned_tx;
+    let maps = Maps::read(&serialize(psbt)[..]).expect("A serialized PSBT can be read back");
+    let mut global = without(maps.global, &[PSBT_GLOBAL_UNSIGNED_TX, PSBT_GLOBAL_VERSION]);
+    global.push((vec![PSBT_GLOBAL_TX_VERSION], serialize(&tx.version)));
+    global.push((
+        vec![PSBT_GLOBAL_FALLBACK_LOCKTIME],
+        serialize(&tx.lock_time),
+    ));
+    global.push((
+        vec![PSBT_GLOBAL_INPUT_COUNT],
+        serialize(&VarInt(tx.input.len() as u64)),
+    ));
+    global.push((
+        vec![PSBT_GLOBAL_OUTPUT_COUNT],
+        serialize(&VarInt(tx.output.len() as u64)),
+    ));
+    global.push((vec![PSBT_GLOBAL_VERSION], serialize(&2u32)));
+    let inputs = maps
+        .inputs
+        .into_iter()
+        .zip(tx.input.iter())
+        .map(|(mut map, txin)| {
+            let outpoint = &txin.previous_output;
+            map.push((
+                vec![PSBT_IN_PREVIOUS_TXID],
+                outpoint.txid.into_inner().to_vec(),
+            ));
+            map.push((vec![PSBT_IN_OUTPUT_INDEX], serialize(&outpoint.vout)));
+            map.push((vec![PSBT_IN_SEQUENCE], serialize(&txin.sequence)));
+            map
+        })
+        .collect();
+    let outputs = maps
+        .outputs
+        .into_iter()
+        .zip(tx.output.iter())
+        .map(|(mut map, txout)| {
+            map.push((vec![PSBT_OUT_AMOUNT], serialize(&txout.value)));
+            map.push((vec![PSBT_OUT_SCRIPT], txout.script_pubkey.to_bytes()));
+            map
+        })
+        .collect();
+    Maps {
+        global,
+        inputs,
+        outputs,
+    }
+    .write()
+}
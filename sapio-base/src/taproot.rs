@@ -0,0 +1,606 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Laying out a `Clause` as a taproot output (BIP-341), for contracts compiled
+//! to witness v1 rather than segwit v0.
+//!
+//! The clause is put in disjunctive normal form, and each way of satisfying
+//! it (a conjunction of keys, timelocks, hashlocks, and CTV templates) becomes
+//! a tapscript (BIP-342) leaf. The first leaf of a single key, if any, is
+//! spent with the key path instead, its key being the internal key. If keys
+//! are aggregated, the first leaf of only keys is, with the MuSig2 (BIP-327)
+//! aggregate of its keys as the internal key. Otherwise the internal key is
+//! unspendable.
+//!
+//! Layout is deterministic: leaves are ordered by script and paired up into a
+//! balanced tree, so a clause yields the same output whatever order its
+//! branches were compiled in.
+//!
+//! Templates are checked with OP_CHECKTEMPLATEVERIFY unless another
+//! `TemplateCheck` is given, e.g. to evaluate other covenant proposals.
+//!
+//! The version of rust-bitcoin we use predates taproot, so the hashes, tweak,
+//! and control blocks are computed here directly.
+use super::Clause;
+use bitcoin::blockdata::opcodes::{self, all::*};
+use bitcoin::blockdata::script::{Builder, Script};
+use bitcoin::consensus::encode::Encodable;
+use bitcoin::hashes::sha256::Hash as Sha256;
+use bitcoin::hashes::{Hash, HashEngine};
+use bitcoin::secp256k1::{PublicKey, Secp256k1};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+/// The leaf version for BIP-342 tapscript
+pub const TAPSCRIPT_LEAF_VERSION: u8 = 0xc0;
+/// The most leaves a clause may be laid out as
+pub const MAX_LEAVES: usize = 1024;
+/// OP_CHECKSIGADD from BIP-342
+const OP_CHECKSIGADD: u8 = 0xba;
+/// OP_CHECKTEMPLATEVERIFY from BIP-119, formerly OP_NOP4
+const OP_CHECKTEMPLATEVERIFY: u8 = 0xb3;
+/// The point H from BIP-341, which has no known discrete logarithm
+const UNSPENDABLE_KEY: [u8; 33] = [
+    0x02, 0x50, 0x92, 0x9b, 0x74, 0xc1, 0xa0, 0x49, 0x54, 0xb7, 0x8b, 0x4b, 0x60, 0x35, 0xe9, 0x7a,
+    0x5e, 0x07, 0x8a, 0x5a, 0x0f, 0x28, 0xec, 0x96, 0xd5, 0x47, 0xbf, 0xee, 0x9a, 0xce, 0x80, 0x3a,
+    0xc0,
+];
+
+/// Errors laying out a clause as a taproot output
+#[derive(Debug, Clone)]
+pub enum TaprootError {
+    /// The clause can never be satisfied
+    Unsatisfiable,
+    /// The clause can be satisfied in more ways than `MAX_LEAVES`
+    TooManyLeaves,
+    /// The clause uses a fragment which has no tapscript equivalent
+    Unsupported(String),
+    /// Keys could not be aggregated or tweaked
+    InvalidKey,
+}
+
+impl fmt::Display for TaprootError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for TaprootError {}
+
+/// How a leaf checks that it is spent by a template (`Clause::TxTemplate`)
+pub trait TemplateCheck {
+    /// Appends the check that the spending transaction is template `h` to
+    /// `b`, leaving nothing on the stack if `verify`, and a truthy value only
+    /// if satisfied otherwise.
+    fn push_template(&self, b: Builder, h: &Sha256, verify: bool) -> Result<Builder, TaprootError>;
+}
+
+/// Checks templates with OP_CHECKTEMPLATEVERIFY (BIP-119)
+pub struct CheckTemplateVerify;
+
+impl TemplateCheck for CheckTemplateVerify {
+    fn push_template(&self, b: Builder, h: &Sha256, verify: bool) -> Result<Builder, TaprootError> {
+        let b = b
+            .push_slice(&h[..])
+            .push_opcode(opcodes::All::from(OP_CHECKTEMPLATEVERIFY));
+        Ok(match verify {
+            true => b.push_opcode(OP_DROP),
+            false => b,
+        })
+    }
+}
+
+/// BIP-340 tagged hash: sha256(sha256(tag) || sha256(tag) || msg)
+pub fn tagged_hash(tag: &str, msg: &[u8]) -> Sha256 {
+    let tag = Sha256::hash(tag.as_bytes());
+    let mut engine = Sha256::engine();
+    engine.input(&tag[..]);
+    engine.input(&tag[..]);
+    engine.input(msg);
+    Sha256::from_engine(engine)
+}
+
+/// computes the tapleaf hash for a tapscript
+pub fn leaf_hash(script: &Script) -> Sha256 {
+    let mut v = vec![TAPSCRIPT_LEAF_VERSION];
+    script
+        .consensus_encode(&mut v)
+        .expect("Vec writes do not fail");
+    tagged_hash("TapLeaf", &v[..])
+}
+
+/// computes the hash of a branch of the script tree, whose children are
+/// committed to in lexicographic order
+pub fn branch_hash(a: &Sha256, b: &Sha256) -> Sha256 {
+    let (a, b) = if a[..] <= b[..] { (a, b) } else { (b, a) };
+    let mut m = a.into_inner().to_vec();
+    m.extend_from_slice(&b[..]);
+    tagged_hash("TapBranch", &m[..])
+}
+
+/// the x-only form of a key, as it appears in tapscript and outputs
+fn xonly(pk: &PublicKey) -> [u8; 32] {
+    let mut x = [0u8; 32];
+    x.copy_from_slice(&pk.serialize()[1..]);
+    x
+}
+
+/// the key with even y and the same x as `pk`
+fn lift_x(mut pk: PublicKey) -> PublicKey {
+    if pk.serialize()[0] == 0x03 {
+        pk.negate_assign(&Secp256k1::verification_only());
+    }
+    pk
+}
+
+/// The MuSig2 (BIP-327) KeyAgg coefficient of `key` in `keys`, taken in the
+/// order given, or None if it is 1, as for the second distinct key.
+pub fn key_agg_coefficient(keys: &[PublicKey], key: &PublicKey) -> Option<Sha256> {
+    if keys.iter().find(|k| Some(*k) != keys.first()) == Some(key) {
+        return None;
+    }
+    let list: Vec<u8> = keys.iter().flat_map(|k| k.serialize().to_vec()).collect();
+    let mut m = tagged_hash("KeyAgg list", &list[..]).into_inner().to_vec();
+    m.extend_from_slice(&key.serialize()[..]);
+    Some(tagged_hash("KeyAgg coefficient", &m[..]))
+}
+
+/// MuSig2 (BIP-327) KeyAgg of `keys`, taken in the order given.
+pub fn key_agg(keys: &[PublicKey]) -> Result<PublicKey, TaprootError> {
+    let secp = Secp256k1::verification_only();
+    let mut q: Option<PublicKey> = None;
+    for k in keys.iter() {
+        let mut p = *k;
+        if let Some(a) = key_agg_coefficient(keys, k) {
+            p.mul_assign(&secp, &a[..])
+                .map_err(|_| TaprootError::InvalidKey)?;
+        }
+        q = Some(match q {
+            Some(q) => q.combine(&p).map_err(|_| TaprootError::InvalidKey)?,
+            None => p,
+        });
+    }
+    q.ok_or(TaprootError::InvalidKey)
+}
+
+/// The MuSig2 (BIP-327) aggregate of `keys`, which are sorted first, as
+/// aggregated by oracles signing together (see `emulator_connect::musig`).
+pub fn aggregate_keys(keys: &[bitcoin::PublicKey]) -> Result<PublicKey, TaprootError> {
+    let mut keys: Vec<PublicKey> = keys.iter().map(|k| k.key).collect();
+    keys.sort_by_key(|k| k.serialize());
+    key_agg(&keys[..])
+}
+
+/// the output key committing to `internal` (with even y) and `merkle_root`
+fn tweak(internal: &PublicKey, merkle_root: Option<Sha256>) -> Result<PublicKey, TaprootError> {
+    let mut m = xonly(internal).to_vec();
+    if let Some(root) = merkle_root {
+        m.extend_from_slice(&root[..]);
+    }
+    let t = tagged_hash("TapTweak", &m[..]);
+    let mut q = *internal;
+    q.add_exp_assign(&Secp256k1::verification_only(), &t[..])
+        .map_err(|_| TaprootError::InvalidKey)?;
+    Ok(q)
+}
+
+/// if `c` is spent by a single leaf condition, rather than combining others
+fn is_atom(c: &Clause) -> bool {
+    match c {
+        Clause::Key(_)
+        | Clause::After(_)
+        | Clause::Older(_)
+        | Clause::Sha256(_)
+        | Clause::Hash256(_)
+        | Clause::Ripemd160(_)
+        | Clause::Hash160(_)
+        | Clause::TxTemplate(_) => true,
+        _ => false,
+    }
+}
+
+/// every conjunction of one of each of `a` and `b`
+fn product(a: Vec<Vec<Clause>>, b: Vec<Vec<Clause>>) -> Result<Vec<Vec<Clause>>, TaprootError> {
+    if a.len().saturating_mul(b.len()) > MAX_LEAVES {
+        return Err(TaprootError::TooManyLeaves);
+    }
+    Ok(a.iter()
+        .flat_map(|x| {
+            b.iter().map(move |y| {
+                let mut conj = x.clone();
+                conj.extend(y.iter().cloned());
+                conj
+            })
+        })
+        .collect())
+}
+
+/// every conjunction of one of each of `cs`
+fn all_of(cs: &[Clause]) -> Result<Vec<Vec<Clause>>, TaprootError> {
+    cs.iter()
+        .try_fold(vec![vec![]], |acc, c| product(acc, dnf(c)?))
+}
+
+/// every conjunction of any of `cs`
+fn any_of<'a, I>(cs: I) -> Result<Vec<Vec<Clause>>, TaprootError>
+where
+    I: Iterator<Item = &'a Clause>,
+{
+    let mut disj = vec![];
+    for c in cs {
+        disj.extend(dnf(c)?);
+        if disj.len() > MAX_LEAVES {
+            return Err(TaprootError::TooManyLeaves);
+        }
+    }
+    Ok(disj)
+}
+
+/// the number of ways of choosing `k` of `n` items, or `usize::MAX` once it
+/// exceeds `MAX_LEAVES`
+fn binomial(n: usize, k: usize) -> usize {
+    let mut c = 1usize;
+    for i in 0..k.min(n - k) {
+        c = c * (n - i) / (i + 1);
+        if c > MAX_LEAVES {
+            return usize::MAX;
+        }
+    }
+    c
+}
+
+/// every way of choosing `k` of `n` items, by index
+fn combinations(n: usize, k: usize) -> Vec<Vec<usize>> {
+    if k == 0 {
+        return vec![vec![]];
+    }
+    (k - 1..n)
+        .flat_map(|last| {
+            combinations(last, k - 1).into_iter().map(move |mut c| {
+                c.push(last);
+                c
+            })
+        })
+        .collect()
+}
+
+/// The disjunctive normal form of `c`: every conjunction of atoms (see
+/// `is_atom`, plus thresholds of keys) which satisfies it.
+fn dnf(c: &Clause) -> Result<Vec<Vec<Clause>>, TaprootError> {
+    match c {
+        Clause::Trivial => Ok(vec![vec![]]),
+        Clause::Unsatisfiable => Ok(vec![]),
+        c if is_atom(c) => Ok(vec![vec![c.clone()]]),
+        Clause::And(cs) => all_of(&cs[..]),
+        Clause::Or(cs) => any_of(cs.iter().map(|(_, c)| c)),
+        Clause::Threshold(0, _) => Ok(vec![vec![]]),
+        Clause::Threshold(k, cs) if *k > cs.len() => Ok(vec![]),
+        Clause::Threshold(1, cs) => any_of(cs.iter()),
+        Clause::Threshold(k, cs) if *k == cs.len() => all_of(&cs[..]),
+        Clause::Threshold(_, cs) if cs.iter().all(|c| matches!(c, Clause::Key(_))) => {
+            Ok(vec![vec![c.clone()]])
+        }
+        Clause::Threshold(k, cs) => {
+            if binomial(cs.len(), *k) > MAX_LEAVES {
+                return Err(TaprootError::TooManyLeaves);
+            }
+            let mut disj = vec![];
+            for chosen in combinations(cs.len(), *k) {
+                let chosen: Vec<Clause> = chosen.into_iter().map(|i| cs[i].clone()).collect();
+                disj.extend(all_of(&chosen[..])?);
+                if disj.len() > MAX_LEAVES {
+                    return Err(TaprootError::TooManyLeaves);
+                }
+            }
+            Ok(disj)
+        }
+        c => Err(TaprootError::Unsupported(c.to_string())),
+    }
+}
+
+/// Appends the check for `atom` to `b`, leaving nothing on the stack if
+/// `verify`, and a truthy value only if satisfied otherwise.
+fn push_atom(
+    b: Builder,
+    atom: &Clause,
+    verify: bool,
+    templates: &dyn TemplateCheck,
+) -> Result<Builder, TaprootError> {
+    let check = |b: Builder, op: opcodes::All| match verify {
+        true => b.push_opcode(op).push_opcode(OP_DROP),
+        false => b.push_opcode(op),
+    };
+    let (checksig, numequal, equal) = match verify {
+        true => (OP_CHECKSIGVERIFY, OP_NUMEQUALVERIFY, OP_EQUALVERIFY),
+        false => (OP_CHECKSIG, OP_NUMEQUAL, OP_EQUAL),
+    };
+    let preimage = |b: Builder, hash: opcodes::All| {
+        b.push_opcode(OP_SIZE)
+            .push_int(32)
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(hash)
+    };
+    Ok(match atom {
+        Clause::Key(pk) => b.push_slice(&xonly(&pk.key)[..]).push_opcode(checksig),
+        Clause::Threshold(k, keys) => keys
+            .iter()
+            .enumerate()
+            .fold(b, |b, (i, key)| {
+                let key = match key {
+                    Clause::Key(key) => key,
+                    _ => unreachable!("only thresholds of keys are atoms"),
+                };
+                b.push_slice(&xonly(&key.key)[..]).push_opcode(match i {
+                    0 => OP_CHECKSIG,
+                    _ => opcodes::All::from(OP_CHECKSIGADD),
+                })
+            })
+            .push_int(*k as i64)
+            .push_opcode(numequal),
+        Clause::After(n) => check(b.push_int(*n as i64), OP_CLTV),
+        Clause::Older(n) => check(b.push_int(*n as i64), OP_CSV),
+        Clause::TxTemplate(h) => templates.push_template(b, h, verify)?,
+        Clause::Sha256(h) => preimage(b, OP_SHA256).push_slice(&h[..]).push_opcode(equal),
+        Clause::Hash256(h) => preimage(b, OP_HASH256)
+            .push_slice(&h[..])
+            .push_opcode(equal),
+        Clause::Ripemd160(h) => preimage(b, OP_RIPEMD160)
+            .push_slice(&h[..])
+            .push_opcode(equal),
+        Clause::Hash160(h) => preimage(b, OP_HASH160)
+            .push_slice(&h[..])
+            .push_opcode(equal),
+        _ => unreachable!("only atoms are pushed"),
+    })
+}
+
+/// Orders the atoms of a conjunction (cheapest checks first, then by
+/// script), removing duplicates.
+fn normalize(
+    mut conj: Vec<Clause>,
+    templates: &dyn TemplateCheck,
+) -> Result<Vec<Clause>, TaprootError> {
+    let rank = |c: &Clause| match c {
+        Clause::After(_) | Clause::Older(_) => 0,
+        Clause::TxTemplate(_) => 1,
+        Clause::Key(_) => 3,
+        Clause::Threshold(..) => 4,
+        _ => 2,
+    };
+    let mut keyed = conj
+        .drain(..)
+        .map(|c| {
+            let script = push_atom(Builder::new(), &c, true, templates)?;
+            Ok(((rank(&c), script.into_script().into_bytes()), c))
+        })
+        .collect::<Result<Vec<_>, TaprootError>>()?;
+    keyed.sort_by(|a, b| a.0.cmp(&b.0));
+    conj.extend(keyed.into_iter().map(|(_, c)| c));
+    conj.dedup();
+    Ok(conj)
+}
+
+/// the tapscript for a conjunction of atoms
+fn leaf_script(conj: &[Clause], templates: &dyn TemplateCheck) -> Result<Script, TaprootError> {
+    match conj.split_last() {
+        None => Ok(Builder::new().push_opcode(OP_PUSHNUM_1).into_script()),
+        Some((last, rest)) => {
+            let b = rest
+                .iter()
+                .try_fold(Builder::new(), |b, c| push_atom(b, c, true, templates))?;
+            Ok(push_atom(b, last, false, templates)?.into_script())
+        }
+    }
+}
+
+/// A leaf of a taproot output's script tree
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct TapLeaf {
+    /// the tapscript
+    pub script: Script,
+    /// proves the leaf is in the tree, see `verify_control_block`
+    pub control_block: Vec<u8>,
+    /// the conditions the script checks, all of which must be met
+    pub clauses: Vec<Clause>,
+}
+
+/// A taproot output, see the module docs.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct TaprootOutput {
+    /// the internal key, with even y
+    #[schemars(with = "String")]
+    pub internal_key: bitcoin::PublicKey,
+    /// if the internal key is spendable, the keys aggregated into it
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub key_path: Vec<bitcoin::PublicKey>,
+    /// the root of the script tree, if any
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    #[schemars(with = "Option<String>")]
+    pub merkle_root: Option<Sha256>,
+    /// the output key, whose parity is that of the control blocks
+    #[schemars(with = "String")]
+    pub output_key: bitcoin::PublicKey,
+    /// the leaves of the script tree, in order
+    pub leaves: Vec<TapLeaf>,
+    /// the leaf (by index) spending to each template, see `link_template`
+    #[serde(skip_serializing_if = "HashMap::is_empty", default)]
+    pub templates: HashMap<Sha256, usize>,
+}
+
+impl TaprootOutput {
+    /// Lays out `clause` as a taproot output, see the module docs.
+    pub fn from_clause(clause: &Clause) -> Result<Self, TaprootError> {
+        Self::from_clause_with(clause, &CheckTemplateVerify, false)
+    }
+
+    /// Lays out `clause` as a taproot output, checking templates with
+    /// `templates`, and spending a leaf of several keys with the key path if
+    /// `aggregate`.
+    pub fn from_clause_with(
+        clause: &Clause,
+        templates: &dyn TemplateCheck,
+        aggregate: bool,
+    ) -> Result<Self, TaprootError> {
+        let mut leaves: Vec<(Script, Vec<Clause>)> = dnf(clause)?
+            .into_iter()
+            .map(|conj| {
+                let conj = normalize(conj, templates)?;
+                Ok((leaf_script(&conj[..], templates)?, conj))
+            })
+            .collect::<Result<_, TaprootError>>()?;
+        leaves.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+        leaves.dedup_by(|a, b| a.0 == b.0);
+        if leaves.is_empty() {
+            return Err(TaprootError::Unsatisfiable);
+        }
+        let keys_only = leaves.iter().position(|(_, conj)| {
+            (conj.len() == 1 || (aggregate && !conj.is_empty()))
+                && conj.iter().all(|c| matches!(c, Clause::Key(_)))
+        });
+        let key_path: Vec<bitcoin::PublicKey> = match keys_only {
+            Some(idx) => leaves
+                .remove(idx)
+                .1
+                .into_iter()
+                .filter_map(|c| match c {
+                    Clause::Key(key) => Some(key),
+                    _ => None,
+                })
+                .collect(),
+            None => vec![],
+        };
+        Self::new(key_path, leaves)
+    }
+
+    /// Builds the output with a key path for `key_path` (a single key is used
+    /// as is, more are aggregated, none is unspendable) and the leaves, in
+    /// order, paired up into a balanced tree.
+    pub fn new(
+        key_path: Vec<bitcoin::PublicKey>,
+        leaves: Vec<(Script, Vec<Clause>)>,
+    ) -> Result<Self, TaprootError> {
+        let internal = match &key_path[..] {
+            [] => PublicKey::from_slice(&UNSPENDABLE_KEY[..]).expect("H is a valid point"),
+            [key] => key.key,
+            keys => aggregate_keys(keys)?,
+        };
+        let internal = lift_x(internal);
+        let mut paths: Vec<Vec<Sha256>> = vec![vec![]; leaves.len()];
+        let mut level: Vec<(Sha256, Vec<usize>)> = leaves
+            .iter()
+            .enumerate()
+            .map(|(idx, (script, _))| (leaf_hash(script), vec![idx]))
+            .collect();
+        while level.len() > 1 {
+            let mut next = vec![];
+            let mut nodes = level.into_iter();
+            while let Some((a, mut under_a)) = nodes.next() {
+                match nodes.next() {
+                    Some((b, under_b)) => {
+                        under_a.iter().for_each(|idx| paths[*idx].push(b));
+                        under_b.iter().for_each(|idx| paths[*idx].push(a));
+                        under_a.extend(under_b);
+                        next.push((branch_hash(&a, &b), under_a));
+                    }
+                    None => next.push((a, under_a)),
+                }
+            }
+            level = next;
+        }
+        let merkle_root = level.pop().map(|(root, _)| root);
+        let output_key = tweak(&internal, merkle_root)?;
+        let parity = output_key.serialize()[0] & 1;
+        let leaves = leaves
+            .into_iter()
+            .zip(paths)
+            .map(|((script, clauses), path)| {
+                let mut control_block = vec![TAPSCRIPT_LEAF_VERSION | parity];
+                control_block.extend_from_slice(&xonly(&internal)[..]);
+                for node in path {
+                    control_block.extend_from_slice(&node[..]);
+                }
+                TapLeaf {
+                    script,
+                    control_block,
+                    clauses,
+                }
+            })
+            .collect();
+        Ok(TaprootOutput {
+            internal_key: bitcoin::PublicKey {
+                compressed: true,
+                key: internal,
+            },
+            key_path,
+            merkle_root,
+            output_key: bitcoin::PublicKey {
+                compressed: true,
+                key: output_key,
+            },
+            leaves,
+            templates: HashMap::new(),
+        })
+    }
+
+    /// the witness v1 script paying to the output key
+    pub fn script_pubkey(&self) -> Script {
+        Builder::new()
+            .push_int(1)
+            .push_slice(&xonly(&self.output_key.key)[..])
+            .into_script()
+    }
+
+    /// Records the leaf spending to template `h`: the first one checking
+    /// `clause` (`Clause::TxTemplate(h)`, or an emulator's key for `h`).
+    /// Returns false if no leaf does, e.g. as the clause is the key path's.
+    pub fn link_template(&mut self, h: Sha256, clause: &Clause) -> bool {
+        match self.leaves.iter().position(|l| l.clauses.contains(clause)) {
+            Some(idx) => {
+                self.templates.insert(h, idx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// the leaf spending to template `h`, see `link_template`
+    pub fn leaf_for(&self, h: &Sha256) -> Option<&TapLeaf> {
+        self.templates.get(h).and_then(|idx| self.leaves.get(*idx))
+    }
+}
+
+/// If `control_block` proves that `script` is a leaf of the tree committed
+/// to by `output_key` (as a leaf of a `TaprootOutput`).
+pub fn verify_control_block(
+    output_key: &bitcoin::PublicKey,
+    script: &Script,
+    control_block: &[u8],
+) -> bool {
+    if control_block.len() < 33 || (control_block.len() - 33) % 32 != 0 {
+        return false;
+    }
+    if control_block[0] & 0xfe != TAPSCRIPT_LEAF_VERSION {
+        return false;
+    }
+    let mut internal = [0x02; 33];
+    internal[1..].copy_from_slice(&control_block[1..33]);
+    let internal = match PublicKey::from_slice(&internal[..]) {
+        Ok(internal) => internal,
+        Err(_) => return false,
+    };
+    let root = control_block[33..]
+        .chunks(32)
+        .map(|node| Sha256::from_slice(node).expect("chunks are 32 bytes"))
+        .fold(leaf_hash(script), |h, node| branch_hash(&h, &node));
+    match tweak(&internal, Some(root)) {
+        Ok(q) => {
+            let q = q.serialize();
+            q[1..] == output_key.key.serialize()[1..] && q[0] & 1 == control_block[0] & 1
+        }
+        Err(_) => false,
+    }
+}
@@ -184,7 +184,7 @@ mod trait_impls {
     }
     impl From<u16> for RelHeight {
         fn from(u: u16) -> Self {
-            Self((u as u32) | (1 << 22), Default::default())
+            Self(u as u32, Default::default())
         }
     }
 
@@ -264,3 +264,158 @@ mod trait_impls {
         }
     }
 }
+
+/// Typed units for lock times, so that e.g. a number of blocks can't be
+/// passed where a number of 512 second intervals is expected. Each converts
+/// to the lock time it denotes, checking the ranges its type can't.
+pub mod units {
+    use super::*;
+    /// A relative lock of a number of blocks
+    #[derive(
+        JsonSchema, Serialize, Deserialize, Copy, Clone, Debug, PartialOrd, Ord, Eq, PartialEq,
+    )]
+    #[serde(transparent)]
+    pub struct Blocks(pub u16);
+    /// A relative lock of a number of seconds, rounded up to the 512 second
+    /// intervals sequences count in, of which there may be at most 65535
+    /// (about 388 days)
+    #[derive(
+        JsonSchema, Serialize, Deserialize, Copy, Clone, Debug, PartialOrd, Ord, Eq, PartialEq,
+    )]
+    #[serde(transparent)]
+    pub struct Seconds(pub u32);
+    /// An absolute lock until a block height, below 500,000,000
+    #[derive(
+        JsonSchema, Serialize, Deserialize, Copy, Clone, Debug, PartialOrd, Ord, Eq, PartialEq,
+    )]
+    #[serde(transparent)]
+    pub struct AbsoluteHeight(pub u32);
+    /// An absolute lock until a median time past, as a unix timestamp at or
+    /// after 500,000,000 (November 1985)
+    #[derive(
+        JsonSchema, Serialize, Deserialize, Copy, Clone, Debug, PartialOrd, Ord, Eq, PartialEq,
+    )]
+    #[serde(transparent)]
+    pub struct MTP(pub u32);
+
+    /// A relative lock time, in any unit
+    pub trait RelativeLock {
+        /// the lock time, if in range
+        fn relative(self) -> Result<AnyRelTimeLock, LockTimeError>;
+    }
+    /// An absolute lock time, in any unit
+    pub trait AbsoluteLock {
+        /// the lock time, if in range
+        fn absolute(self) -> Result<AnyAbsTimeLock, LockTimeError>;
+    }
+
+    impl From<Blocks> for RelHeight {
+        fn from(b: Blocks) -> Self {
+            RelHeight::from(b.0)
+        }
+    }
+    impl TryFrom<Seconds> for RelTime {
+        type Error = LockTimeError;
+        fn try_from(s: Seconds) -> Result<Self, Self::Error> {
+            let intervals = (s.0 as u64 + 511) / 512;
+            u16::try_from(intervals)
+                .or(Err(LockTimeError::DurationTooLong(Duration::from_secs(
+                    s.0 as u64,
+                ))))
+                .map(From::from)
+        }
+    }
+    impl TryFrom<AbsoluteHeight> for AbsHeight {
+        type Error = LockTimeError;
+        fn try_from(h: AbsoluteHeight) -> Result<Self, Self::Error> {
+            AbsHeight::try_from(h.0)
+        }
+    }
+    impl TryFrom<MTP> for AbsTime {
+        type Error = LockTimeError;
+        fn try_from(t: MTP) -> Result<Self, Self::Error> {
+            AbsTime::try_from(t.0)
+        }
+    }
+
+    impl From<Blocks> for AnyRelTimeLock {
+        fn from(b: Blocks) -> Self {
+            AnyRelTimeLock::RH(b.into())
+        }
+    }
+    impl From<Blocks> for Clause {
+        fn from(b: Blocks) -> Self {
+            RelHeight::from(b).into()
+        }
+    }
+    impl TryFrom<Seconds> for Clause {
+        type Error = LockTimeError;
+        fn try_from(s: Seconds) -> Result<Self, Self::Error> {
+            Ok(RelTime::try_from(s)?.into())
+        }
+    }
+    impl TryFrom<AbsoluteHeight> for Clause {
+        type Error = LockTimeError;
+        fn try_from(h: AbsoluteHeight) -> Result<Self, Self::Error> {
+            Ok(AbsHeight::try_from(h)?.into())
+        }
+    }
+    impl TryFrom<MTP> for Clause {
+        type Error = LockTimeError;
+        fn try_from(t: MTP) -> Result<Self, Self::Error> {
+            Ok(AbsTime::try_from(t)?.into())
+        }
+    }
+
+    impl RelativeLock for Blocks {
+        fn relative(self) -> Result<AnyRelTimeLock, LockTimeError> {
+            Ok(self.into())
+        }
+    }
+    impl RelativeLock for Seconds {
+        fn relative(self) -> Result<AnyRelTimeLock, LockTimeError> {
+            Ok(RelTime::try_from(self)?.into())
+        }
+    }
+    impl RelativeLock for RelHeight {
+        fn relative(self) -> Result<AnyRelTimeLock, LockTimeError> {
+            Ok(self.into())
+        }
+    }
+    impl RelativeLock for RelTime {
+        fn relative(self) -> Result<AnyRelTimeLock, LockTimeError> {
+            Ok(self.into())
+        }
+    }
+    impl RelativeLock for AnyRelTimeLock {
+        fn relative(self) -> Result<AnyRelTimeLock, LockTimeError> {
+            Ok(self)
+        }
+    }
+
+    impl AbsoluteLock for AbsoluteHeight {
+        fn absolute(self) -> Result<AnyAbsTimeLock, LockTimeError> {
+            Ok(AbsHeight::try_from(self)?.into())
+        }
+    }
+    impl AbsoluteLock for MTP {
+        fn absolute(self) -> Result<AnyAbsTimeLock, LockTimeError> {
+            Ok(AbsTime::try_from(self)?.into())
+        }
+    }
+    impl AbsoluteLock for AbsHeight {
+        fn absolute(self) -> Result<AnyAbsTimeLock, LockTimeError> {
+            Ok(self.into())
+        }
+    }
+    impl AbsoluteLock for AbsTime {
+        fn absolute(self) -> Result<AnyAbsTimeLock, LockTimeError> {
+            Ok(self.into())
+        }
+    }
+    impl AbsoluteLock for AnyAbsTimeLock {
+        fn absolute(self) -> Result<AnyAbsTimeLock, LockTimeError> {
+            Ok(self)
+        }
+    }
+}
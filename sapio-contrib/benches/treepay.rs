@@ -0,0 +1,51 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Compares compiling a wide `TreePay` on one thread and in parallel.
+//!
+//! Run with `cargo bench -p sapio-contrib`.
+use bitcoin::util::amount::Amount;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use sapio::contract::{Compilable, Context};
+use sapio_contrib::contracts::treepay::{Payment, TreePay};
+use std::str::FromStr;
+use std::sync::Arc;
+
+fn tree(n: usize) -> TreePay {
+    let address = bitcoin::Address::from_str("bc1qxy2kgdygjrsqtzq2n0yrf2493p83kkfjhx0wlh").unwrap();
+    TreePay {
+        participants: (0..n)
+            .map(|_| Payment {
+                amount: Amount::from_sat(10_000).into(),
+                address: address.clone(),
+            })
+            .collect(),
+        radix: 4,
+    }
+}
+
+fn compile(c: &mut Criterion) {
+    let ctx = Context::new(
+        bitcoin::Network::Regtest,
+        Amount::from_btc(1.0).unwrap(),
+        Arc::new(sapio_ctv_emulator_trait::CTVAvailable),
+    );
+    for n in [256usize, 1024, 4096].iter() {
+        let contract = tree(*n);
+        let mut group = c.benchmark_group(format!("treepay_{}_leaves", n));
+        group.sample_size(10);
+        for threads in [1usize, 4, 8].iter() {
+            let ctx = ctx.with_threads(*threads).unwrap();
+            group.bench_with_input(BenchmarkId::new("threads", threads), &ctx, |b, ctx| {
+                b.iter(|| contract.compile(ctx).unwrap())
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, compile);
+criterion_main!(benches);
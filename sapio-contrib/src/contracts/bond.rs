@@ -0,0 +1,315 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! bond has a contract, `SlashableBond`, for a bond forfeited if its owner
+//! equivocates, e.g., an oracle attesting to two outcomes of one event, or
+//! a federation member signing two conflicting states.
+//!
+//! The owner commits to a signing key `X` and a nonce `R` (a `Commitment`),
+//! and signs the one message it may sign with them, as in
+//! `derivatives::dlc`: `s = r + e*x`, with `e` the BIP-340 challenge of `R`,
+//! `X`, and the hash of the message. Signing a second message with the same
+//! nonce reveals `x` (see `Commitment::extract_key`), which is the proof of
+//! equivocation: a key for `X` forfeits the bond, as `Forfeit` says. If the
+//! owner never equivocates, they are refunded after the timeout.
+//!
+//! As the owner knows `x` too, a bond claimed by whoever knows `x`
+//! (`Forfeit::Claim`) is a race the owner may win, e.g., by bribing a miner.
+//! Burning the bond, or paying it to a fixed party, removes the incentive
+//! (as `staked_signer` burns its stake).
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::{self, Secp256k1, SecretKey};
+use bitcoin::util::amount::Amount;
+use sapio::contract::actions::ConditionalCompileType;
+use sapio::contract::combinators::or;
+use sapio::contract::*;
+use sapio::*;
+use sapio_base::timelocks::AnyTimeLock;
+use sapio_base::Clause;
+use schemars::*;
+use serde::*;
+use std::collections::LinkedList;
+use std::fmt;
+
+/// n - 2, with n the order of secp256k1, inverting scalars by Fermat
+const ORDER_MINUS_TWO: [u8; 32] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+    0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b, 0xbf, 0xd2, 0x5e, 0x8c, 0xd0, 0x36, 0x41, 0x3f,
+];
+
+/// Why a key couldn't be extracted from two signatures
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EquivocationError {
+    /// both signatures are of the same message, which isn't equivocating
+    SameMessage,
+    /// a signature isn't valid for the commitment
+    InvalidSignature,
+    /// a scalar was out of range
+    Secp256k1(secp256k1::Error),
+}
+
+impl fmt::Display for EquivocationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for EquivocationError {}
+
+impl From<secp256k1::Error> for EquivocationError {
+    fn from(e: secp256k1::Error) -> Self {
+        EquivocationError::Secp256k1(e)
+    }
+}
+
+/// A signing key and nonce a bond's owner commits to, see the module docs
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Commitment {
+    /// the signing key, `X`
+    pub key: bitcoin::PublicKey,
+    /// the nonce, `R`
+    pub nonce: bitcoin::PublicKey,
+}
+
+impl Commitment {
+    /// the challenge `e` of `message`
+    pub fn challenge(&self, message: &[u8]) -> sha256::Hash {
+        let tag = sha256::Hash::hash(b"BIP0340/challenge");
+        let mut engine = sha256::Hash::engine();
+        engine.input(&tag[..]);
+        engine.input(&tag[..]);
+        engine.input(&self.nonce.key.serialize()[1..]);
+        engine.input(&self.key.key.serialize()[1..]);
+        engine.input(&sha256::Hash::hash(message)[..]);
+        sha256::Hash::from_engine(engine)
+    }
+
+    /// `s = r + e*x`, signing `message` with the secret `key` and `nonce`
+    pub fn sign(
+        &self,
+        key: &SecretKey,
+        nonce: &SecretKey,
+        message: &[u8],
+    ) -> Result<SecretKey, secp256k1::Error> {
+        let mut s = *key;
+        s.mul_assign(&self.challenge(message)[..])?;
+        s.add_assign(&nonce[..])?;
+        Ok(s)
+    }
+
+    /// if `s` signs `message`, i.e., `s*G = R + e*X`
+    pub fn verify(&self, message: &[u8], s: &SecretKey) -> bool {
+        let secp = Secp256k1::new();
+        let mut point = self.key.key;
+        point
+            .mul_assign(&secp, &self.challenge(message)[..])
+            .and_then(|_| self.nonce.key.combine(&point))
+            .map_or(false, |p| {
+                secp256k1::PublicKey::from_secret_key(&secp, s) == p
+            })
+    }
+
+    /// The secret signing key, from signatures of two messages:
+    /// `x = (s1 - s2) / (e1 - e2)`.
+    pub fn extract_key(
+        &self,
+        first: (&[u8], &SecretKey),
+        second: (&[u8], &SecretKey),
+    ) -> Result<SecretKey, EquivocationError> {
+        if first.0 == second.0 {
+            return Err(EquivocationError::SameMessage);
+        }
+        if !self.verify(first.0, first.1) || !self.verify(second.0, second.1) {
+            return Err(EquivocationError::InvalidSignature);
+        }
+        let mut s = *second.1;
+        s.negate_assign();
+        s.add_assign(&first.1[..])?;
+        let mut e = SecretKey::from_slice(&self.challenge(second.0)[..])?;
+        e.negate_assign();
+        e.add_assign(&self.challenge(first.0)[..])?;
+        s.mul_assign(&invert(&e)?[..])?;
+        Ok(s)
+    }
+}
+
+/// `a^-1`, as `a^(n-2)`
+fn invert(a: &SecretKey) -> Result<SecretKey, secp256k1::Error> {
+    let mut one = [0u8; 32];
+    one[31] = 1;
+    let mut result = SecretKey::from_slice(&one)?;
+    for byte in ORDER_MINUS_TWO.iter() {
+        for bit in (0..8).rev() {
+            let square = result;
+            result.mul_assign(&square[..])?;
+            if (byte >> bit) & 1 == 1 {
+                result.mul_assign(&a[..])?;
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Where a `SlashableBond` goes once its owner equivocates
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Forfeit {
+    /// to an unspendable output
+    Burn,
+    /// to a fixed party, e.g., the federation the owner is a member of
+    Pay(bitcoin::Address),
+    /// to whoever knows the signing key, see the module docs
+    Claim,
+}
+
+/// A bond forfeited on equivocation, see the module docs
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct SlashableBond {
+    /// the owner's key, refunded after the timeout
+    pub owner: bitcoin::PublicKey,
+    /// the signing key and nonce the owner commits to
+    pub commitment: Commitment,
+    /// when the owner may be refunded
+    pub timeout: AnyTimeLock,
+    /// where the bond goes on equivocation
+    pub forfeit: Forfeit,
+    /// the fee paid forfeiting
+    #[schemars(with = "i64")]
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub fee: Amount,
+}
+
+impl SlashableBond {
+    compile_if! {
+        /// the bond must be forfeited with a transaction, rather than claimed
+        fn forfeits(self, ctx) {
+            let mut errors = LinkedList::new();
+            if self.commitment.key == self.owner {
+                errors.push_back("Signing key must not be the owner's key".into());
+            }
+            if ctx.funds() <= self.fee {
+                errors.push_back("Funds must cover the fee".into());
+            }
+            if !errors.is_empty() {
+                ConditionalCompileType::Fail(errors)
+            } else if self.forfeit == Forfeit::Claim {
+                ConditionalCompileType::Never
+            } else {
+                ConditionalCompileType::Required
+            }
+        }
+    }
+    guard! {fn equivocated(self, _ctx) { Clause::Key(self.commitment.key) }}
+    guard! {
+        /// the owner after the timeout, or, if claimable, whoever knows the
+        /// signing key
+        fn spendable(self, _ctx) {
+            let refund = Clause::And(vec![Clause::Key(self.owner), self.timeout.into()]);
+            if self.forfeit == Forfeit::Claim {
+                or(vec![refund, Clause::Key(self.commitment.key)])
+            } else {
+                refund
+            }
+        }
+    }
+
+    then! {
+        annotated: {label: "forfeit", doc: "burns the bond, or pays it to the fixed party, with the signing key"}
+        compile_if: [Self::forfeits]
+        guarded_by: [Self::equivocated]
+        fn forfeit(self, ctx) {
+            let builder = ctx.template().add_fees(self.fee)?;
+            let amount = builder.ctx().funds();
+            let to = match &self.forfeit {
+                Forfeit::Pay(address) => Compiled::from_address(address.clone(), None),
+                _ => Compiled::from_op_return(b"equivocated")?,
+            };
+            builder
+                .add_output(amount, &to, None)?
+                .set_label("forfeit".into())
+                .into()
+        }
+    }
+}
+
+impl Contract for SlashableBond {
+    declare! {then, Self::forfeit}
+    declare! {finish, Self::spendable}
+    declare! {non updatable}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::key;
+    use sapio_base::timelocks::RelHeight;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::sync::Arc;
+
+    fn secret(i: u8) -> SecretKey {
+        SecretKey::from_slice(&[i; 32]).unwrap()
+    }
+
+    #[test]
+    fn equivocation_reveals_key() {
+        let commitment = Commitment {
+            key: key(2),
+            nonce: key(3),
+        };
+        let yes = commitment.sign(&secret(2), &secret(3), b"yes").unwrap();
+        let no = commitment.sign(&secret(2), &secret(3), b"no").unwrap();
+        assert!(commitment.verify(b"yes", &yes));
+        assert!(!commitment.verify(b"no", &yes));
+        assert_eq!(
+            commitment.extract_key((b"yes", &yes), (b"no", &no)),
+            Ok(secret(2))
+        );
+        assert_eq!(
+            commitment.extract_key((b"yes", &yes), (b"yes", &yes)),
+            Err(EquivocationError::SameMessage)
+        );
+        assert_eq!(
+            commitment.extract_key((b"yes", &no), (b"no", &no)),
+            Err(EquivocationError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn slashable_bond() {
+        let bond = SlashableBond {
+            owner: key(1),
+            commitment: Commitment {
+                key: key(2),
+                nonce: key(3),
+            },
+            timeout: AnyTimeLock::R(RelHeight::from(4320).into()),
+            forfeit: Forfeit::Burn,
+            fee: Amount::from_sat(1000),
+        };
+        let ctx = Context::new(
+            bitcoin::Network::Regtest,
+            Amount::from_sat(1_000_000),
+            Arc::new(CTVAvailable),
+        );
+        let burnt = bond.compile(&ctx).unwrap();
+        assert_eq!(burnt.ctv_to_tx.len(), 1);
+        let tmpl = burnt.ctv_to_tx.values().next().unwrap();
+        assert!(tmpl.tx.output[0].script_pubkey.is_op_return());
+
+        let claimable = SlashableBond {
+            forfeit: Forfeit::Claim,
+            ..bond.clone()
+        };
+        assert!(claimable.compile(&ctx).unwrap().ctv_to_tx.is_empty());
+
+        let own_key = SlashableBond {
+            commitment: Commitment {
+                key: key(1),
+                nonce: key(3),
+            },
+            ..bond
+        };
+        assert!(own_key.compile(&ctx).is_err());
+    }
+}
@@ -0,0 +1,155 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! cancellable has a contract, `Cancellable`, wrapping any other contract in
+//! a window during which a designated key may cancel it.
+//!
+//! Funds sent to a `Cancellable<T>` only move to the inner contract `T` once
+//! the window (a relative lock) has passed, so none of `T`'s branches can be
+//! taken before then. During the window the canceller may redirect the funds
+//! to the recovery address instead, e.g., a cold wallet's, if the transfer
+//! (or the inner contract) turns out to be a mistake or an attack. The inner
+//! contract is compiled as is, with the funds left after the fee.
+use bitcoin::util::amount::Amount;
+use sapio::contract::*;
+use sapio::*;
+use sapio_base::timelocks::AnyRelTimeLock;
+use sapio_base::Clause;
+use schemars::*;
+use serde::*;
+
+/// A contract the canceller may redirect during a window, see the module
+/// docs
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct Cancellable<T> {
+    /// the contract the funds move to after the window
+    pub inner: T,
+    /// the key which may cancel during the window
+    pub canceller: bitcoin::PublicKey,
+    /// where cancelled funds go
+    pub recovery: bitcoin::Address,
+    /// how long the funds must be unspent before moving to the inner contract
+    pub window: AnyRelTimeLock,
+    /// the fee paid cancelling or activating
+    #[schemars(with = "i64")]
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub fee: Amount,
+}
+
+impl<T> Cancellable<T>
+where
+    T: Compilable + 'static,
+{
+    guard! {fn cancels(self, _ctx) { Clause::Key(self.canceller) }}
+
+    then! {
+        annotated: {label: "cancel", roles: ["canceller"], doc: "redirects the funds to the recovery address"}
+        guarded_by: [Self::cancels]
+        fn cancel(self, ctx) {
+            let builder = ctx.template().add_fees(self.fee)?;
+            let amount = builder.ctx().funds();
+            builder
+                .add_output(amount, &Compiled::from_address(self.recovery.clone(), None), None)?
+                .set_label("cancel".into())
+                .into()
+        }
+    }
+    then! {
+        annotated: {label: "activate", doc: "moves the funds to the inner contract after the window"}
+        fn activate(self, ctx) {
+            let builder = ctx.template().add_fees(self.fee)?;
+            let amount = builder.ctx().funds();
+            builder
+                .add_output(amount, &self.inner, None)?
+                .set_sequence(0, self.window)?
+                .set_label("activate".into())
+                .into()
+        }
+    }
+}
+
+impl<T> Contract for Cancellable<T>
+where
+    T: Compilable + 'static,
+{
+    declare! {then, Self::cancel, Self::activate}
+    declare! {non updatable}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::escrow::Escrow;
+    use super::*;
+    use crate::fixtures::key;
+    use sapio::simulation::{ChainState, Simulator};
+    use sapio_base::timelocks::RelHeight;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::sync::Arc;
+
+    fn address(i: u8) -> bitcoin::Address {
+        bitcoin::Address::p2wpkh(&key(i), bitcoin::Network::Regtest).unwrap()
+    }
+
+    #[test]
+    fn cancellable_escrow() {
+        let escrow = Escrow {
+            buyer: key(1),
+            buyer_address: address(1),
+            seller: key(2),
+            seller_address: address(2),
+            arbiter: key(3),
+            appeal: RelHeight::from(1008).into(),
+            fee: Amount::from_sat(1000),
+        };
+        let wrapped = Cancellable {
+            inner: escrow,
+            canceller: key(4),
+            recovery: address(4),
+            window: RelHeight::from(144).into(),
+            fee: Amount::from_sat(1000),
+        };
+        let funds = Amount::from_sat(1_000_000);
+        let ctx = Context::new(bitcoin::Network::Regtest, funds, Arc::new(CTVAvailable));
+        let compiled = wrapped.compile(&ctx).unwrap();
+        let activate = compiled
+            .ctv_to_tx
+            .values()
+            .find(|t| t.metadata_map_s2s.label() == Some("activate"))
+            .unwrap();
+        assert_eq!(activate.tx.input[0].sequence, 144);
+        // the escrow is compiled as it would be without the wrapper
+        let inner = wrapped
+            .inner
+            .compile(&ctx.with_amount(funds - wrapped.fee).unwrap())
+            .unwrap();
+        assert_eq!(
+            activate.tx.output[0].script_pubkey,
+            bitcoin::Script::from(inner.address)
+        );
+
+        let report = Simulator::new(ChainState {
+            height: 0,
+            median_time_past: 0,
+        })
+        .run(&compiled, funds);
+        assert_eq!(report.dead_branches().count(), 0);
+        let recovered = report
+            .outcomes
+            .iter()
+            .filter(|o| o.balances.contains_key(&wrapped.recovery.script_pubkey()))
+            .count();
+        assert_eq!(recovered, 1);
+        // nothing reaches the escrow's parties before the window
+        for outcome in report.outcomes.iter() {
+            if outcome
+                .balances
+                .contains_key(&wrapped.inner.seller_address.script_pubkey())
+            {
+                assert!(outcome.settled.height >= 144);
+            }
+        }
+    }
+}
@@ -7,6 +7,7 @@
 //! An example of how one might begin building a payment channel contract in Sapio
 use contract::*;
 use sapio::*;
+use sapio_base::timelocks::RelHeight;
 use sapio_base::Clause;
 
 use bitcoin;
@@ -182,12 +183,15 @@ struct Channel<T: State> {
     db: Arc<Mutex<dyn DB>>,
 }
 
+/// How many blocks a contest lasts before it can be finished
+const TIMEOUT: u16 = 100;
+
 /// Functionality Available for a channel regardless of state
 impl<T: State> Channel<T>
 where
     Channel<T>: Contract,
 {
-    guard! {fn timeout(self, _ctx) { Clause::Older(100) }}
+    guard! {fn timeout(self, _ctx) { RelHeight::from(TIMEOUT).into() }}
     guard! {cached fn signed(self, _ctx) {Clause::And(vec![Clause::Key(self.alice), Clause::Key(self.bob)])}}
 
     finish! {
@@ -243,7 +247,10 @@ impl FunctionalityAtState for Channel<Stop> {
     then! {
         guarded_by: [Self::timeout]
         fn finish_contest (self, ctx) {
-            ctx.template().add_output(self.amount.try_into()?, &self.resolution, None)?.into()
+            ctx.template()
+                .add_output(self.amount.try_into()?, &self.resolution, None)?
+                .set_sequence(0, RelHeight::from(TIMEOUT).into())?
+                .into()
         }
     }
 }
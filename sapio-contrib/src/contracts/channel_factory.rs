@@ -0,0 +1,281 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! channel_factory has a contract, `ChannelFactory`, opening many two-party
+//! Lightning channels from one UTXO.
+//!
+//! Like a `payment_pool::PaymentPool` whose members are channels, the
+//! factory's participants update it together (with every key), and without
+//! them anyone may open every channel at once, or splice one channel out,
+//! opening it and re-creating the factory with the rest. As with the pool,
+//! after `splices` splices the factory opens every channel instead. It is
+//! funded with the fees of the longest path to opening every channel (see
+//! `ChannelFactory::required`); a shorter path pays the rest as fees.
+//!
+//! Each channel is funded by a Lightning funding output, a 2-of-2 of its
+//! parties' keys (as in BOLT 3), so it is a channel like any other once
+//! open: `ChannelFactory::channels` exports its parameters from whichever
+//! transaction opened it, for a Lightning implementation to take over.
+use bitcoin::util::amount::Amount;
+use miniscript::{Descriptor, DescriptorTrait};
+use sapio::contract::actions::ConditionalCompileType;
+use sapio::contract::*;
+use sapio::template::Builder;
+use sapio::*;
+use sapio_base::Clause;
+use schemars::*;
+use serde::*;
+
+/// A channel opened by a `ChannelFactory`
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ChannelSpec {
+    /// one party's funding key
+    pub a: bitcoin::PublicKey,
+    /// the other party's funding key
+    pub b: bitcoin::PublicKey,
+    /// the channel's capacity
+    #[schemars(with = "i64")]
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub amount: Amount,
+}
+
+impl ChannelSpec {
+    /// the channel's funding output descriptor, a 2-of-2 of the parties' keys
+    pub fn descriptor(&self) -> Result<Descriptor<bitcoin::PublicKey>, CompilationError> {
+        Descriptor::new_wsh_sortedmulti(2, vec![self.a, self.b]).map_err(CompilationError::from)
+    }
+}
+
+/// What a Lightning implementation needs to take over an opened channel
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ChannelParams {
+    /// the channel
+    pub spec: ChannelSpec,
+    /// the outpoint funding the channel
+    #[schemars(with = "String")]
+    pub funding_outpoint: bitcoin::OutPoint,
+    /// the funding output's witness script
+    #[schemars(with = "String")]
+    pub funding_script: bitcoin::Script,
+}
+
+/// A channel factory, see the module docs
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct ChannelFactory {
+    /// the channels
+    pub channels: Vec<ChannelSpec>,
+    /// the fee paid by each transaction
+    #[schemars(with = "i64")]
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub fee: Amount,
+    /// how many more channels may be spliced out one at a time before the
+    /// factory opens every channel
+    pub splices: u8,
+}
+
+impl ChannelFactory {
+    /// the channels' capacity
+    pub fn total(&self) -> Amount {
+        self.channels
+            .iter()
+            .fold(Amount::from_sat(0), |a, c| a + c.amount)
+    }
+
+    /// The funds the factory needs: the channels' capacity, and a fee for
+    /// each transaction on the longest path to opening every channel.
+    pub fn required(&self) -> Amount {
+        let splices = std::cmp::min(self.splices as usize, self.channels.len().saturating_sub(1));
+        self.total() + self.fee * (splices as u64 + 1)
+    }
+
+    /// the factory without the channel at `idx`, one fewer splice away from
+    /// opening every channel
+    pub fn without(&self, idx: usize) -> ChannelFactory {
+        let mut channels = self.channels.clone();
+        channels.remove(idx);
+        ChannelFactory {
+            channels,
+            fee: self.fee,
+            splices: self.splices.saturating_sub(1),
+        }
+    }
+
+    /// The parameters of the channels `tx` opens, by funding output. `tx`
+    /// may be any transaction of the factory, with its input set.
+    pub fn channels(
+        &self,
+        tx: &bitcoin::Transaction,
+    ) -> Result<Vec<ChannelParams>, CompilationError> {
+        let txid = tx.txid();
+        let mut params = vec![];
+        for spec in self.channels.iter() {
+            let descriptor = spec.descriptor()?;
+            let script = descriptor.script_pubkey();
+            if let Some(vout) = tx
+                .output
+                .iter()
+                .position(|o| o.script_pubkey == script && o.value == spec.amount.as_sat())
+            {
+                params.push(ChannelParams {
+                    spec: spec.clone(),
+                    funding_outpoint: bitcoin::OutPoint {
+                        txid,
+                        vout: vout as u32,
+                    },
+                    funding_script: descriptor.explicit_script(),
+                });
+            }
+        }
+        Ok(params)
+    }
+
+    /// pays `spec`'s funding output
+    fn open(&self, builder: Builder, spec: &ChannelSpec) -> Result<Builder, CompilationError> {
+        builder.add_output(
+            spec.amount,
+            &Context::compiled_from_descriptor(spec.descriptor()?, None),
+            None,
+        )
+    }
+
+    compile_if! {
+        /// channels are spliced out one at a time while there are splices
+        /// left
+        fn splices_left(self, _ctx) {
+            if self.splices > 0 && self.channels.len() > 1 {
+                ConditionalCompileType::Required
+            } else {
+                ConditionalCompileType::Never
+            }
+        }
+    }
+    guard! {
+        /// every party has signed
+        fn all_sign(self, _ctx) {
+            // a party in several channels signs once
+            let mut keys = vec![];
+            for key in self.channels.iter().flat_map(|c| vec![c.a, c.b]) {
+                if !keys.contains(&key) {
+                    keys.push(key);
+                }
+            }
+            Clause::Threshold(keys.len(), keys.into_iter().map(Clause::Key).collect())
+        }
+    }
+
+    then! {
+        annotated: {label: "open", doc: "opens every channel"}
+        fn open_all(self, ctx) {
+            // the fees saved for splices pay this transaction's instead
+            let fees = ctx.funds().checked_sub(self.total()).ok_or(CompilationError::OutOfFunds)?;
+            let mut builder = ctx.template().add_fees(fees)?;
+            for spec in self.channels.iter() {
+                builder = self.open(builder, spec)?;
+            }
+            builder.set_label("open all".into()).into()
+        }
+    }
+    then! {
+        annotated: {label: "splice", doc: "opens one channel, and re-creates the factory with the rest"}
+        compile_if: [Self::splices_left]
+        fn splice_out(self, ctx) {
+            let mut templates = vec![];
+            for (idx, spec) in self.channels.iter().enumerate() {
+                let rest = self.without(idx);
+                let builder = self.open(ctx.template().add_fees(self.fee)?, spec)?;
+                let tmpl = builder
+                    .add_output(rest.required(), &rest, None)?
+                    .set_label(format!("splice out {}", idx))
+                    .finish()?;
+                templates.push(Ok(tmpl));
+            }
+            Ok(Box::new(templates.into_iter()))
+        }
+    }
+    finish! {
+        /// moves the factory to the channels every party agreed to
+        guarded_by: [Self::all_sign]
+        fn update(self, ctx, o) {
+            if let Some(update) = o {
+                let next = ChannelFactory {
+                    channels: update.channels.clone(),
+                    fee: self.fee,
+                    splices: self.splices,
+                };
+                ctx.template()
+                    .add_fees(self.fee)?
+                    .add_output(next.required(), &next, None)?
+                    .into()
+            } else {
+                Ok(Box::new(std::iter::empty()))
+            }
+        }
+    }
+}
+
+/// The next channels of a `ChannelFactory`, agreed by every party
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct FactoryUpdate {
+    /// the channels of the next factory, whose required funds (see
+    /// `ChannelFactory::required`) with the fee must be the factory's funds
+    pub channels: Vec<ChannelSpec>,
+}
+
+impl Contract for ChannelFactory {
+    declare! {then, Self::open_all, Self::splice_out}
+    declare! {updatable<FactoryUpdate>, Self::update}
+    declare! {effects}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::key;
+    use sapio::simulation::{ChainState, Simulator};
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::sync::Arc;
+
+    #[test]
+    fn channel_factory() {
+        // a hub in a channel with each of three others
+        let factory = ChannelFactory {
+            channels: (2..=4)
+                .map(|i| ChannelSpec {
+                    a: key(1),
+                    b: key(i),
+                    amount: Amount::from_sat(100_000 * i as u64),
+                })
+                .collect(),
+            fee: Amount::from_sat(1000),
+            splices: 2,
+        };
+        let funds = factory.required();
+        let ctx = Context::new(bitcoin::Network::Regtest, funds, Arc::new(CTVAvailable));
+        let compiled = factory.compile(&ctx).unwrap();
+        assert!(compiled.continue_points.contains_key("update"));
+        let report = Simulator::new(ChainState {
+            height: 0,
+            median_time_past: 0,
+        })
+        .run(&compiled, funds);
+        assert_eq!(report.dead_branches().count(), 0);
+
+        let open = compiled
+            .ctv_to_tx
+            .values()
+            .find(|t| t.metadata_map_s2s.label() == Some("open all"))
+            .unwrap();
+        let mut tx = open.tx.clone();
+        tx.input[0].previous_output = bitcoin::OutPoint::default();
+        let params = factory.channels(&tx).unwrap();
+        assert_eq!(params.len(), 3);
+        for p in params.iter() {
+            let out = &tx.output[p.funding_outpoint.vout as usize];
+            assert_eq!(out.value, p.spec.amount.as_sat());
+            assert_eq!(out.script_pubkey, p.funding_script.to_v0_p2wsh());
+        }
+    }
+}
@@ -0,0 +1,235 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! decaying_multisig has a multisig, `DecayingMultisig`, needing fewer
+//! signatures the longer its funds are unspent, e.g., 3-of-3 at first,
+//! 2-of-3 after six months and 1-of-3 after a year.
+//!
+//! Each threshold is a stage of the contract, spent by that many keys or,
+//! once the next stage's lock has passed, by a template moving the funds to
+//! it. Expressing every stage as a branch of a single policy would repeat the
+//! keys in each branch, which the miniscript compiler rejects, so decaying
+//! takes a transaction (which anyone may broadcast) but no signatures. The
+//! locks are relative to when the first stage is funded; each stage's
+//! template waits out the difference from the previous stage's lock.
+use bitcoin::util::amount::Amount;
+use sapio::contract::actions::ConditionalCompileType;
+use sapio::contract::*;
+use sapio::*;
+use sapio_base::timelocks::{AnyRelTimeLock, RelHeight, RelTime};
+use sapio_base::Clause;
+use schemars::*;
+use serde::*;
+use std::collections::LinkedList;
+
+/// A stage of a `DecayingMultisig`
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct Decay {
+    /// how many of the keys must sign, from this stage on
+    pub threshold: usize,
+    /// how long after the first stage is funded this stage begins
+    pub after: AnyRelTimeLock,
+}
+
+/// A multisig whose threshold decays over time, see the module docs
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct DecayingMultisig {
+    /// the keys which sign
+    pub keys: Vec<bitcoin::PublicKey>,
+    /// how many of the keys must sign at first
+    pub threshold: usize,
+    /// the later stages, with decreasing thresholds and increasing locks
+    pub decays: Vec<Decay>,
+    /// the fee paid moving to each stage
+    #[schemars(with = "i64")]
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub fee: Amount,
+}
+
+/// `a - b`, if both are locks of the same kind and `a` is longer
+fn lock_since(a: AnyRelTimeLock, b: Option<AnyRelTimeLock>) -> Option<AnyRelTimeLock> {
+    // relative time locks are in units of 512 seconds, in the low 16 bits
+    let units = |l: u32| (l & 0xffff) as u16;
+    match (a, b) {
+        (a, None) => Some(a),
+        (AnyRelTimeLock::RH(a), Some(AnyRelTimeLock::RH(b))) if a.get() > b.get() => {
+            Some(RelHeight::from(units(a.get()) - units(b.get())).into())
+        }
+        (AnyRelTimeLock::RT(a), Some(AnyRelTimeLock::RT(b))) if units(a.get()) > units(b.get()) => {
+            Some(RelTime::from(units(a.get()) - units(b.get())).into())
+        }
+        _ => None,
+    }
+}
+
+impl DecayingMultisig {
+    /// the next stage, with its locks relative to when it is funded
+    fn next_stage(&self) -> Option<DecayingMultisig> {
+        let first = self.decays.first()?;
+        Some(DecayingMultisig {
+            keys: self.keys.clone(),
+            threshold: first.threshold,
+            decays: self.decays[1..]
+                .iter()
+                .map(|d| {
+                    Some(Decay {
+                        threshold: d.threshold,
+                        after: lock_since(d.after, Some(first.after))?,
+                    })
+                })
+                .collect::<Option<Vec<_>>>()?,
+            fee: self.fee,
+        })
+    }
+
+    compile_if! {
+        /// thresholds must decrease, from at most the number of keys to at
+        /// least one, and locks increase, all in the same unit
+        fn monotone(self, _ctx) {
+            let mut errors = LinkedList::new();
+            if self.threshold == 0 || self.threshold > self.keys.len() {
+                errors.push_back("Threshold must be between 1 and the number of keys".into());
+            }
+            let mut threshold = self.threshold;
+            let mut after = None;
+            for decay in self.decays.iter() {
+                if decay.threshold == 0 || decay.threshold >= threshold {
+                    errors.push_back(format!(
+                        "Threshold {} must be at least 1 and below the previous, {}",
+                        decay.threshold, threshold
+                    ));
+                }
+                if lock_since(decay.after, after).is_none() {
+                    errors.push_back(format!(
+                        "Lock {} must be of the same unit as, and longer than, the previous",
+                        decay.after.get()
+                    ));
+                }
+                threshold = decay.threshold;
+                after = Some(decay.after);
+            }
+            if errors.is_empty() {
+                ConditionalCompileType::NoConstraint
+            } else {
+                ConditionalCompileType::Fail(errors)
+            }
+        }
+    }
+    compile_if! {
+        /// the last stage doesn't decay
+        fn decays_left(self, _ctx) {
+            if self.decays.is_empty() {
+                ConditionalCompileType::Never
+            } else {
+                ConditionalCompileType::Required
+            }
+        }
+    }
+    guard! {
+        /// enough of the keys have signed
+        fn signed(self, _ctx) {
+            Clause::Threshold(self.threshold, self.keys.iter().cloned().map(Clause::Key).collect())
+        }
+    }
+    guard! {
+        /// the next stage's lock has passed
+        fn next_lock(self, _ctx) {
+            self.decays
+                .first()
+                .map_or(Clause::Unsatisfiable, |d| d.after.into())
+        }
+    }
+
+    then! {
+        annotated: {label: "decay", doc: "moves the funds to the next stage, needing fewer signatures"}
+        compile_if: [Self::monotone, Self::decays_left]
+        guarded_by: [Self::next_lock]
+        fn decay(self, ctx) {
+            let next = self.next_stage().ok_or(CompilationError::TerminateCompilation)?;
+            let delay = self.decays[0].after;
+            let builder = ctx.template().add_fees(self.fee)?;
+            let amount = builder.ctx().funds();
+            builder
+                .add_output(amount, &next, None)?
+                .set_sequence(0, delay)?
+                .set_label(format!("decay to {} of {}", next.threshold, self.keys.len()))
+                .into()
+        }
+    }
+}
+
+impl Contract for DecayingMultisig {
+    declare! {then, Self::decay}
+    declare! {finish, Self::signed}
+    declare! {non updatable}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::secp256k1::{Secp256k1, SecretKey};
+    use sapio::simulation::{ChainState, Simulator};
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::sync::Arc;
+
+    fn multisig(decays: Vec<(usize, u16)>) -> DecayingMultisig {
+        let secp = Secp256k1::new();
+        DecayingMultisig {
+            keys: (1..=3u8)
+                .map(|i| bitcoin::PublicKey {
+                    compressed: true,
+                    key: bitcoin::secp256k1::PublicKey::from_secret_key(
+                        &secp,
+                        &SecretKey::from_slice(&[i; 32]).unwrap(),
+                    ),
+                })
+                .collect(),
+            threshold: 3,
+            decays: decays
+                .into_iter()
+                .map(|(threshold, blocks)| Decay {
+                    threshold,
+                    after: RelHeight::from(blocks).into(),
+                })
+                .collect(),
+            fee: Amount::from_sat(1000),
+        }
+    }
+
+    #[test]
+    fn decaying_multisig() {
+        let ctx = Context::new(
+            bitcoin::Network::Regtest,
+            Amount::from_sat(1_000_000),
+            Arc::new(CTVAvailable),
+        );
+        // 3-of-3, 2-of-3 after ~six months, 1-of-3 after ~a year
+        let compiled = multisig(vec![(2, 26_280), (1, 52_560)])
+            .compile(&ctx)
+            .unwrap();
+        let report = Simulator::new(ChainState {
+            height: 0,
+            median_time_past: 0,
+        })
+        .run(&compiled, ctx.funds());
+        assert_eq!(report.dead_branches().count(), 0);
+        // the second stage waits out the rest of the year
+        let stage = &compiled.ctv_to_tx.values().next().unwrap().outputs[0].contract;
+        let last = &stage.ctv_to_tx.values().next().unwrap();
+        assert_eq!(last.tx.input[0].sequence, 26_280);
+        assert!(last.outputs[0].contract.ctv_to_tx.is_empty());
+
+        for bad in [
+            vec![(3, 100)],
+            vec![(2, 200), (1, 100)],
+            vec![(2, 100), (0, 200)],
+        ]
+        .iter()
+        {
+            assert!(multisig(bad.clone()).compile(&ctx).is_err());
+        }
+    }
+}
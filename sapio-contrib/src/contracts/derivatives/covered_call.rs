@@ -0,0 +1,240 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! covered_call has a contract, `CoveredCall`, for a call option on coins
+//! the writer locks up front.
+//!
+//! The writer funds the contract with the underlying. Until the writer
+//! reclaims it, the holder may exercise the option: the exercise template
+//! has a second input, which the holder adds to pay the strike, and pays the
+//! strike to the writer and the underlying to the holder. Once the option
+//! has expired, the writer may reclaim the underlying.
+//!
+//! A transaction can't be made invalid by time passing, so the holder can
+//! still exercise after the expiry, until the writer's reclaim confirms: as
+//! with an HTLC's refund, the writer reclaims promptly.
+//!
+//! The premium is paid as the writer and holder agree, see `Premium`.
+use bitcoin::util::amount::Amount;
+use sapio::contract::actions::ConditionalCompileType;
+use sapio::contract::*;
+use sapio::*;
+use sapio_base::timelocks::AnyAbsTimeLock;
+use sapio_base::Clause;
+use schemars::*;
+use serde::*;
+use std::collections::LinkedList;
+
+/// How the holder of a `CoveredCall` pays its premium
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Premium {
+    /// outside of the contract, e.g., by an output of the transaction
+    /// funding it (see `sapio::util::funding`)
+    Upfront,
+    /// into the contract, along with the underlying, paying the writer
+    /// whether the option is exercised or not
+    Escrowed,
+    /// on exercise, along with the strike, so the writer is only paid it if
+    /// the option is exercised
+    OnExercise,
+}
+
+/// A covered call option, see the module docs
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct CoveredCall {
+    /// the writer's key, reclaiming
+    pub writer: bitcoin::PublicKey,
+    /// where the writer is paid the strike, or reclaims the underlying
+    pub writer_address: bitcoin::Address,
+    /// the holder's key, exercising
+    pub holder: bitcoin::PublicKey,
+    /// where the holder is paid the underlying
+    pub holder_address: bitcoin::Address,
+    /// the coins the holder may buy
+    #[schemars(with = "i64")]
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub underlying: Amount,
+    /// what the holder pays for the underlying
+    #[schemars(with = "i64")]
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub strike: Amount,
+    /// when the writer may reclaim the underlying
+    pub expiry: AnyAbsTimeLock,
+    /// what the holder pays for the option
+    #[schemars(with = "i64")]
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub premium: Amount,
+    /// how the premium is paid
+    pub premium_handling: Premium,
+    /// the fee paid exercising or reclaiming
+    #[schemars(with = "i64")]
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub fee: Amount,
+}
+
+impl CoveredCall {
+    /// the funds the contract needs: the underlying, the fee, and the
+    /// premium if escrowed
+    pub fn required(&self) -> Amount {
+        match self.premium_handling {
+            Premium::Escrowed => self.underlying + self.fee + self.premium,
+            _ => self.underlying + self.fee,
+        }
+    }
+
+    /// what the holder's input pays exercising: the strike, and the premium
+    /// if paid on exercise
+    pub fn exercise_cost(&self) -> Amount {
+        match self.premium_handling {
+            Premium::OnExercise => self.strike + self.premium,
+            _ => self.strike,
+        }
+    }
+
+    compile_if! {
+        /// the funds must cover the underlying, the fee and any escrowed
+        /// premium
+        fn funded(self, ctx) {
+            let mut errors = LinkedList::new();
+            if self.underlying == Amount::from_sat(0) {
+                errors.push_back("Underlying must be more than zero".into());
+            }
+            if ctx.funds() < self.required() {
+                errors.push_back("Funds must cover the underlying, the fee and any escrowed premium".into());
+            }
+            if errors.is_empty() {
+                ConditionalCompileType::NoConstraint
+            } else {
+                ConditionalCompileType::Fail(errors)
+            }
+        }
+    }
+    guard! {fn holder_exercises(self, _ctx) { Clause::Key(self.holder) }}
+    guard! {fn writer_reclaims(self, _ctx) {
+        Clause::And(vec![Clause::Key(self.writer), self.expiry.into()])
+    }}
+
+    then! {
+        annotated: {label: "exercise", roles: ["holder"], doc: "pays the strike, from an input the holder adds, to the writer and the underlying to the holder"}
+        compile_if: [Self::funded]
+        guarded_by: [Self::holder_exercises]
+        fn exercise(self, ctx) {
+            let builder = ctx
+                .template()
+                .add_amount(self.exercise_cost())
+                .add_sequence()
+                .add_fees(self.fee)?
+                .add_output(
+                    self.underlying,
+                    &Compiled::from_address(self.holder_address.clone(), None),
+                    None,
+                )?;
+            // the strike, and any premium or excess funds
+            let rest = builder.ctx().funds();
+            builder
+                .add_output(rest, &Compiled::from_address(self.writer_address.clone(), None), None)?
+                .set_label("exercise".into())
+                .into()
+        }
+    }
+    then! {
+        annotated: {label: "reclaim", roles: ["writer"], doc: "returns the underlying to the writer after the expiry"}
+        compile_if: [Self::funded]
+        guarded_by: [Self::writer_reclaims]
+        fn reclaim(self, ctx) {
+            let builder = ctx.template().add_fees(self.fee)?;
+            let amount = builder.ctx().funds();
+            builder
+                .add_output(amount, &Compiled::from_address(self.writer_address.clone(), None), None)?
+                .set_lock_time(self.expiry)?
+                .set_label("reclaim".into())
+                .into()
+        }
+    }
+}
+
+impl Contract for CoveredCall {
+    declare! {then, Self::exercise, Self::reclaim}
+    declare! {non updatable}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::key;
+    use sapio::template::Template;
+    use sapio_base::timelocks::AbsHeight;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+
+    fn paid(tmpl: &Template, address: &bitcoin::Address) -> u64 {
+        tmpl.tx
+            .output
+            .iter()
+            .filter(|o| o.script_pubkey == address.script_pubkey())
+            .map(|o| o.value)
+            .sum()
+    }
+
+    #[test]
+    fn covered_call() {
+        let call = CoveredCall {
+            writer: key(1),
+            writer_address: bitcoin::Address::p2wpkh(&key(1), bitcoin::Network::Regtest).unwrap(),
+            holder: key(2),
+            holder_address: bitcoin::Address::p2wpkh(&key(2), bitcoin::Network::Regtest).unwrap(),
+            underlying: Amount::from_sat(1_000_000),
+            strike: Amount::from_sat(300_000),
+            expiry: AbsHeight::try_from(800_000u32).unwrap().into(),
+            premium: Amount::from_sat(10_000),
+            premium_handling: Premium::Escrowed,
+            fee: Amount::from_sat(1000),
+        };
+        for (handling, writer_paid) in [
+            (Premium::Upfront, 300_000),
+            (Premium::Escrowed, 310_000),
+            (Premium::OnExercise, 310_000),
+        ]
+        .iter()
+        {
+            let call = CoveredCall {
+                premium_handling: *handling,
+                ..call.clone()
+            };
+            let ctx = Context::new(
+                bitcoin::Network::Regtest,
+                call.required(),
+                Arc::new(CTVAvailable),
+            );
+            let compiled = call.compile(&ctx).unwrap();
+            let find = |label| {
+                compiled
+                    .ctv_to_tx
+                    .values()
+                    .find(|t| t.metadata_map_s2s.label() == Some(label))
+                    .unwrap()
+            };
+            let exercise = find("exercise");
+            assert_eq!(exercise.tx.input.len(), 2);
+            assert_eq!(paid(exercise, &call.holder_address), 1_000_000);
+            assert_eq!(paid(exercise, &call.writer_address), *writer_paid);
+            let reclaim = find("reclaim");
+            assert_eq!(reclaim.tx.lock_time, 800_000);
+            assert_eq!(
+                paid(reclaim, &call.writer_address),
+                call.required().as_sat() - 1000
+            );
+
+            let short = Context::new(
+                bitcoin::Network::Regtest,
+                call.required() - Amount::from_sat(1),
+                Arc::new(CTVAvailable),
+            );
+            assert!(call.compile(&short).is_err());
+        }
+    }
+}
@@ -0,0 +1,332 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Discreet Log Contracts
+//!
+//! An oracle announces its key `P`, the nonce `R` it will sign an event's
+//! outcome with, and the outcomes it may sign. Once it signs outcome `m` it
+//! reveals `s = r + e*p`, with `e` the BIP-340 challenge of `R`, `P`, and the
+//! hash of `m`, so anyone can compute the attestation point `S = R + e*P` of
+//! every outcome beforehand: `S` is a key whose secret the oracle reveals
+//! exactly when it attests to that outcome.
+//!
+//! A `Dlc` binds each outcome's Contract Execution Transaction (CET), a CTV
+//! template paying the parties according to a `PayoutCurve`, to the outcome's
+//! attestation point, as in adaptor signatures: once the oracle attests,
+//! anyone can sign for the point and broadcast the CET, but no other CET. As
+//! a `then!` has one guard for all its templates, the CETs are the leaves of
+//! a binary tree (as in `GenericBet`), each level guarded by the attestation
+//! points of the outcomes below it. If the oracle never attests, the
+//! collateral is refunded after `refund_after`.
+use super::*;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::{self, Secp256k1};
+use sapio::contract::combinators::or;
+use sapio::template::Builder;
+use sapio_base::timelocks::AnyAbsTimeLock;
+use schemars::*;
+use serde::*;
+
+/// An oracle's announcement of an event, see the module docs
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug)]
+pub struct Announcement {
+    /// the oracle's key
+    pub oracle: bitcoin::PublicKey,
+    /// the nonce the oracle signs the outcome with
+    pub nonce: bitcoin::PublicKey,
+    /// the outcomes the oracle may attest to
+    pub outcomes: Vec<String>,
+}
+
+impl Announcement {
+    /// `R + e*P`, the point whose secret the oracle reveals attesting to
+    /// `outcome`. BIP-340 keys and nonces are x-only, so `R` and `P` are
+    /// those with the x coordinates of `nonce` and `oracle` and even y.
+    pub fn attestation_point(&self, outcome: &str) -> Result<bitcoin::PublicKey, secp256k1::Error> {
+        let secp = Secp256k1::verification_only();
+        let nonce = lift_x(&self.nonce)?;
+        let oracle = lift_x(&self.oracle)?;
+        let tag = sha256::Hash::hash(b"BIP0340/challenge");
+        let mut engine = sha256::Hash::engine();
+        engine.input(&tag[..]);
+        engine.input(&tag[..]);
+        engine.input(&nonce.serialize()[1..]);
+        engine.input(&oracle.serialize()[1..]);
+        engine.input(&sha256::Hash::hash(outcome.as_bytes())[..]);
+        let e = sha256::Hash::from_engine(engine);
+        let mut point = oracle;
+        point.mul_assign(&secp, &e[..])?;
+        Ok(bitcoin::PublicKey {
+            compressed: true,
+            key: nonce.combine(&point)?,
+        })
+    }
+
+    /// the attestation point of each outcome, as a clause
+    pub fn attestation_clauses(&self) -> Result<Vec<Clause>, secp256k1::Error> {
+        self.outcomes
+            .iter()
+            .map(|o| self.attestation_point(o).map(Clause::Key))
+            .collect()
+    }
+}
+
+/// the point with the x coordinate of `key` and an even y
+fn lift_x(key: &bitcoin::PublicKey) -> Result<secp256k1::PublicKey, secp256k1::Error> {
+    let mut bytes = key.key.serialize();
+    bytes[0] = 0x02;
+    secp256k1::PublicKey::from_slice(&bytes)
+}
+
+/// An announcement of numeric outcomes is an `Oracle` for a `GenericBet`
+/// on the symbol announced: the key for a price is satisfied by the
+/// attestation points of the outcomes below, or not, it. Outcomes which
+/// aren't numbers are skipped.
+impl Oracle for Announcement {
+    fn get_key_lt_gte(&self, _t: &Symbol, price: i64) -> (Clause, Clause) {
+        let (mut lt, mut gte) = (vec![], vec![]);
+        for outcome in self.outcomes.iter() {
+            if let (Ok(v), Ok(point)) = (outcome.parse::<i64>(), self.attestation_point(outcome)) {
+                if v < price {
+                    lt.push(Clause::Key(point));
+                } else {
+                    gte.push(Clause::Key(point));
+                }
+            }
+        }
+        (or(lt), or(gte))
+    }
+}
+
+/// The payout to the first party for each outcome, the second party being
+/// paid the rest. Given at some outcomes, by their index in the
+/// announcement, and interpolated linearly between them.
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug)]
+pub struct PayoutCurve {
+    /// the outcome indexes, ascending, and the payouts in sats at them
+    pub points: Vec<(usize, u64)>,
+}
+
+impl PayoutCurve {
+    /// the payout at outcome `idx`, which is constant before the first point
+    /// and after the last
+    pub fn payout(&self, idx: usize) -> Option<Amount> {
+        let first = self.points.first()?;
+        let mut prev = *first;
+        for &(x, y) in self.points.iter() {
+            if idx <= x {
+                if x == prev.0 {
+                    return Some(Amount::from_sat(y));
+                }
+                let (x0, y0) = (prev.0 as i128, prev.1 as i128);
+                let v = y0 + (y as i128 - y0) * (idx as i128 - x0) / (x as i128 - x0);
+                return Some(Amount::from_sat(v as u64));
+            }
+            prev = (x, y);
+        }
+        Some(Amount::from_sat(prev.1))
+    }
+}
+
+/// The arguments of a `Dlc`
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct DlcArgs {
+    /// the event the contract settles on
+    pub announcement: Announcement,
+    /// the first party's payout for each outcome
+    pub curve: PayoutCurve,
+    /// where the first party is paid
+    pub party_a: bitcoin::Address,
+    /// where the second party is paid
+    pub party_b: bitcoin::Address,
+    /// the first party's collateral, refunded if the oracle never attests,
+    /// the second party's being the rest
+    #[schemars(with = "i64")]
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub collateral_a: Amount,
+    /// the keys of the parties, which together may settle the contract
+    /// however they agree
+    pub keys: (bitcoin::PublicKey, bitcoin::PublicKey),
+    /// when the collateral is refunded
+    pub refund_after: AnyAbsTimeLock,
+}
+
+/// A Discreet Log Contract, see the module docs
+pub struct Dlc {
+    attestations: Rc<Vec<Clause>>,
+    payouts: Rc<Vec<Amount>>,
+    party_a: bitcoin::Address,
+    party_b: bitcoin::Address,
+    collateral_a: Amount,
+    cooperate: Clause,
+    refund_after: AnyAbsTimeLock,
+    /// the outcomes below this node of the tree
+    range: std::ops::Range<usize>,
+}
+
+impl TryFrom<DlcArgs> for Dlc {
+    type Error = CompilationError;
+    fn try_from(v: DlcArgs) -> Result<Self, CompilationError> {
+        let n = v.announcement.outcomes.len();
+        if n < 2 {
+            return Err(CompilationError::TerminateCompilation);
+        }
+        let attestations = v
+            .announcement
+            .attestation_clauses()
+            .map_err(CompilationError::custom)?;
+        let payouts = (0..n)
+            .map(|i| v.curve.payout(i))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(CompilationError::TerminateCompilation)?;
+        Ok(Dlc {
+            attestations: Rc::new(attestations),
+            payouts: Rc::new(payouts),
+            party_a: v.party_a,
+            party_b: v.party_b,
+            collateral_a: v.collateral_a,
+            cooperate: Clause::And(vec![Clause::Key(v.keys.0), Clause::Key(v.keys.1)]),
+            refund_after: v.refund_after,
+            range: 0..n,
+        })
+    }
+}
+
+impl Dlc {
+    fn lower_half(&self) -> std::ops::Range<usize> {
+        self.range.start..self.range.start + self.range.len() / 2
+    }
+    fn upper_half(&self) -> std::ops::Range<usize> {
+        self.range.start + self.range.len() / 2..self.range.end
+    }
+    /// a clause satisfied once the oracle attests to any outcome in `range`
+    fn attested(&self, range: std::ops::Range<usize>) -> Clause {
+        or(self.attestations[range].to_vec())
+    }
+    /// pays `a` to the first party and the rest to the second
+    fn pay(&self, ctx: &Context, a: Amount) -> Result<Builder, CompilationError> {
+        let a = std::cmp::min(a, ctx.funds());
+        let b = ctx.funds() - a;
+        let mut builder = ctx.template();
+        for (amount, address) in [(a, &self.party_a), (b, &self.party_b)].iter() {
+            if *amount > Amount::from_sat(0) {
+                builder = builder.add_output(
+                    *amount,
+                    &Compiled::from_address((*address).clone(), None),
+                    None,
+                )?;
+            }
+        }
+        Ok(builder)
+    }
+    /// the CET of the outcome, if `range` is one, else the subtree below it
+    fn execute(&self, ctx: &Context, range: std::ops::Range<usize>) -> TxTmplIt {
+        if range.len() == 1 {
+            self.pay(ctx, self.payouts[range.start])?
+                .set_label(format!("cet {}", range.start))
+                .into()
+        } else {
+            let subtree = Dlc {
+                attestations: self.attestations.clone(),
+                payouts: self.payouts.clone(),
+                party_a: self.party_a.clone(),
+                party_b: self.party_b.clone(),
+                collateral_a: self.collateral_a,
+                cooperate: self.cooperate.clone(),
+                refund_after: self.refund_after,
+                range,
+            };
+            ctx.template()
+                .add_output(ctx.funds(), &subtree, None)?
+                .into()
+        }
+    }
+
+    guard! {
+        /// the oracle attested to an outcome in the lower half
+        fn lower(self, _ctx) { self.attested(self.lower_half()) }
+    }
+    guard! {
+        /// the oracle attested to an outcome in the upper half
+        fn upper(self, _ctx) { self.attested(self.upper_half()) }
+    }
+    guard! {
+        /// both parties agree
+        fn cooperate(self, _ctx) { self.cooperate.clone() }
+    }
+    then! {
+        guarded_by: [Self::lower]
+        fn execute_lower(self, ctx) {
+            self.execute(ctx, self.lower_half())
+        }
+    }
+    then! {
+        guarded_by: [Self::upper]
+        fn execute_upper(self, ctx) {
+            self.execute(ctx, self.upper_half())
+        }
+    }
+    then! {
+        /// refunds the collateral if the oracle never attests
+        fn refund(self, ctx) {
+            self.pay(ctx, self.collateral_a)?
+                .set_lock_time(self.refund_after)?
+                .set_label("refund".into())
+                .into()
+        }
+    }
+}
+
+impl Contract for Dlc {
+    declare! {then, Self::execute_lower, Self::execute_upper, Self::refund}
+    declare! {finish, Self::cooperate}
+    declare! {non updatable}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::secp256k1::{schnorrsig, Message, SecretKey};
+
+    /// `key`, negated if `negated`
+    fn negate(key: &secp256k1::PublicKey, negated: bool) -> bitcoin::PublicKey {
+        let mut bytes = key.serialize();
+        if negated {
+            bytes[0] ^= 1;
+        }
+        bitcoin::PublicKey {
+            compressed: true,
+            key: secp256k1::PublicKey::from_slice(&bytes).unwrap(),
+        }
+    }
+
+    #[test]
+    fn attestation_point_of_signature() {
+        let secp = Secp256k1::new();
+        let oracle = schnorrsig::KeyPair::from_seckey_slice(&secp, &[3; 32]).unwrap();
+        let oracle_key =
+            secp256k1::PublicKey::from_secret_key(&secp, &SecretKey::from_slice(&[3; 32]).unwrap());
+        let msg = Message::from_slice(&sha256::Hash::hash(b"42")[..]).unwrap();
+        let sig = secp.schnorrsig_sign_no_aux_rand(&msg, &oracle);
+        let nonce_key =
+            secp256k1::PublicKey::from_slice(&[&[0x02][..], &sig[..32]].concat()).unwrap();
+        // the secret the oracle reveals, `s`, is that of the point
+        let s = SecretKey::from_slice(&sig[32..]).unwrap();
+        let expected = secp256k1::PublicKey::from_secret_key(&secp, &s);
+        // whatever the parity the keys are announced with
+        for (negate_nonce, negate_oracle) in
+            [(false, false), (false, true), (true, false), (true, true)].iter()
+        {
+            let announcement = Announcement {
+                oracle: negate(&oracle_key, *negate_oracle),
+                nonce: negate(&nonce_key, *negate_nonce),
+                outcomes: vec!["41".into(), "42".into()],
+            };
+            assert_eq!(announcement.attestation_point("42").unwrap().key, expected);
+            assert_ne!(announcement.attestation_point("41").unwrap().key, expected);
+        }
+    }
+}
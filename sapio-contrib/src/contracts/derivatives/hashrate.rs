@@ -0,0 +1,253 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! hashrate has a contract, `HashrateBet`, betting on the hashrate without
+//! an oracle, by racing a block height against a time.
+//!
+//! The bet settles with one of two templates: one locked until `height`,
+//! paying the party betting the hashrate rises, and one locked until the
+//! median-time-past reaches `time`, paying the party betting it falls.
+//! Whichever lock is reached first decides the bet, as the other template
+//! can't spend the funds once the first confirms. (A transaction's lock
+//! time is either a height or a time, never both, hence one template each.)
+//!
+//! `HashrateCalculator` picks the `time` (or `height`) at which the bet is
+//! fair, for a forecast of the time between blocks.
+use bitcoin::util::amount::Amount;
+use sapio::contract::actions::ConditionalCompileType;
+use sapio::contract::*;
+use sapio::simulation::ChainState;
+use sapio::template::Builder;
+use sapio::*;
+use sapio_base::timelocks::{AbsHeight, AbsTime};
+use schemars::*;
+use serde::*;
+use std::collections::LinkedList;
+use std::convert::TryFrom;
+
+/// A bet on the hashrate, see the module docs
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct HashrateBet {
+    /// the height which, reached first, pays the high side
+    pub height: u32,
+    /// the median-time-past which, reached first, pays the low side
+    pub time: u32,
+    /// where the party betting on a higher hashrate is paid
+    pub high_address: bitcoin::Address,
+    /// where the party betting on a lower hashrate is paid
+    pub low_address: bitcoin::Address,
+    /// the fee paid settling
+    #[schemars(with = "i64")]
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub fee: Amount,
+}
+
+impl HashrateBet {
+    /// pays the funds of `ctx`, less the fee, to `address`
+    fn pay(&self, ctx: &Context, address: &bitcoin::Address) -> Result<Builder, CompilationError> {
+        let builder = ctx.template().add_fees(self.fee)?;
+        let amount = builder.ctx().funds();
+        builder.add_output(amount, &Compiled::from_address(address.clone(), None), None)
+    }
+
+    compile_if! {
+        /// the height and time must be valid absolute lock times
+        fn valid_locks(self, _ctx) {
+            let mut errors = LinkedList::new();
+            if AbsHeight::try_from(self.height).is_err() {
+                errors.push_back("Height must be a valid absolute height lock".into());
+            }
+            if AbsTime::try_from(self.time).is_err() {
+                errors.push_back("Time must be a valid absolute time lock".into());
+            }
+            if errors.is_empty() {
+                ConditionalCompileType::NoConstraint
+            } else {
+                ConditionalCompileType::Fail(errors)
+            }
+        }
+    }
+
+    then! {
+        annotated: {label: "height_first", roles: ["high"], doc: "pays the high side once the height is reached"}
+        compile_if: [Self::valid_locks]
+        fn height_first(self, ctx) {
+            self.pay(ctx, &self.high_address)?
+                .set_lock_time(AbsHeight::try_from(self.height)?.into())?
+                .set_label("height first".into())
+                .into()
+        }
+    }
+    then! {
+        annotated: {label: "time_first", roles: ["low"], doc: "pays the low side once the median-time-past reaches the time"}
+        compile_if: [Self::valid_locks]
+        fn time_first(self, ctx) {
+            self.pay(ctx, &self.low_address)?
+                .set_lock_time(AbsTime::try_from(self.time)?.into())?
+                .set_label("time first".into())
+                .into()
+        }
+    }
+}
+
+impl Contract for HashrateBet {
+    declare! {then, Self::height_first, Self::time_first}
+    declare! {non updatable}
+}
+
+/// Prices a `HashrateBet`, modelling blocks as a Poisson process with a
+/// forecast mean time between blocks.
+///
+/// Times are measured from the median-time-past of the chain state given,
+/// as the time lock is, so its lag behind the tip cancels out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HashrateCalculator {
+    /// the forecast mean seconds between blocks
+    pub block_interval: f64,
+}
+
+impl HashrateCalculator {
+    /// a forecast of `block_interval` seconds between blocks
+    pub fn new(block_interval: f64) -> Self {
+        HashrateCalculator { block_interval }
+    }
+
+    /// the forecast for blocks of `difficulty` mined at `hashes_per_second`
+    pub fn from_hashrate(difficulty: f64, hashes_per_second: f64) -> Self {
+        HashrateCalculator::new(difficulty * 4_294_967_296.0 / hashes_per_second)
+    }
+
+    /// the chance `height` is reached before the median-time-past reaches
+    /// `time`, starting at `from`
+    pub fn probability_height_first(&self, from: &ChainState, height: u32, time: u32) -> f64 {
+        let blocks = height.saturating_sub(from.height);
+        if blocks == 0 {
+            return 1.0;
+        }
+        if time <= from.median_time_past {
+            return 0.0;
+        }
+        // the chance of fewer than `blocks` blocks by `time`, summing the
+        // Poisson terms in logs so large means don't underflow
+        let mean = f64::from(time - from.median_time_past) / self.block_interval;
+        let mut log_term = -mean;
+        let mut fewer = log_term.exp();
+        for k in 1..blocks {
+            log_term += mean.ln() - f64::from(k).ln();
+            fewer += log_term.exp();
+        }
+        (1.0 - fewer).max(0.0).min(1.0)
+    }
+
+    /// the time, paired with `height`, at which the bet is even
+    pub fn fair_time(&self, from: &ChainState, height: u32) -> u32 {
+        let blocks = height.saturating_sub(from.height);
+        let (mut low, mut high) = (
+            from.median_time_past,
+            from.median_time_past
+                .saturating_add((2.0 * f64::from(blocks.max(1)) * self.block_interval) as u32),
+        );
+        // the chance of the height first rises with the time
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.probability_height_first(from, height, mid) < 0.5 {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    }
+
+    /// the height, paired with `time`, at which the bet is (as near as a
+    /// whole block allows) even
+    pub fn fair_height(&self, from: &ChainState, time: u32) -> u32 {
+        let seconds = f64::from(time.saturating_sub(from.median_time_past));
+        let (mut low, mut high) = (
+            from.height,
+            from.height
+                .saturating_add((2.0 * seconds / self.block_interval) as u32 + 1),
+        );
+        // the chance of the height first falls with the height
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.probability_height_first(from, mid, time) > 0.5 {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::secp256k1::{Secp256k1, SecretKey};
+    use sapio::simulation::Simulator;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::sync::Arc;
+
+    fn address(i: u8) -> bitcoin::Address {
+        let key = bitcoin::PublicKey {
+            compressed: true,
+            key: bitcoin::secp256k1::PublicKey::from_secret_key(
+                &Secp256k1::new(),
+                &SecretKey::from_slice(&[i; 32]).unwrap(),
+            ),
+        };
+        bitcoin::Address::p2wpkh(&key, bitcoin::Network::Regtest).unwrap()
+    }
+
+    #[test]
+    fn hashrate_bet() {
+        let from = ChainState {
+            height: 700_000,
+            median_time_past: 1_630_000_000,
+        };
+        let calc = HashrateCalculator::new(600.0);
+        let height = from.height + 2016;
+        let time = calc.fair_time(&from, height);
+        // the median of the time to mine 2016 blocks is just under the mean
+        assert!(time < from.median_time_past + 2016 * 600);
+        assert!(time > from.median_time_past + 2000 * 600);
+        let p = calc.probability_height_first(&from, height, time);
+        assert!((p - 0.5).abs() < 0.01);
+        let fair_height = calc.fair_height(&from, time);
+        assert!(fair_height >= height - 1 && fair_height <= height + 1);
+        // a higher hashrate favours the height
+        let faster = HashrateCalculator::new(540.0);
+        assert!(faster.probability_height_first(&from, height, time) > 0.9);
+
+        let bet = HashrateBet {
+            height,
+            time,
+            high_address: address(1),
+            low_address: address(2),
+            fee: Amount::from_sat(1000),
+        };
+        let funds = Amount::from_sat(1_000_000);
+        let ctx = Context::new(bitcoin::Network::Regtest, funds, Arc::new(CTVAvailable));
+        let compiled = bet.compile(&ctx).unwrap();
+        let report = Simulator::new(from).run(&compiled, funds);
+        assert_eq!(report.dead_branches().count(), 0);
+        assert_eq!(report.outcomes.len(), 2);
+        let locks: Vec<u32> = compiled
+            .ctv_to_tx
+            .values()
+            .map(|t| t.tx.lock_time)
+            .collect();
+        assert!(locks.contains(&height) && locks.contains(&time));
+
+        let swapped = HashrateBet {
+            height: time,
+            time: height,
+            ..bet
+        };
+        assert!(swapped.compile(&ctx).is_err());
+    }
+}
@@ -20,7 +20,10 @@ pub use oracle::{Oracle, Symbol};
 
 pub mod apis;
 pub mod call;
+pub mod covered_call;
+pub mod dlc;
 pub mod exploding;
+pub mod hashrate;
 pub mod put;
 pub mod risk_reversal;
 
@@ -93,7 +96,7 @@ impl GenericBet {
                         },
                         None,
                     )?
-                    .into(),
+                    .finish()?,
             )),
         }
     }
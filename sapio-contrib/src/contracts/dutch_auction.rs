@@ -0,0 +1,196 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! dutch_auction has a contract, `DutchAuction`, settling the sale of an item
+//! at a price falling over time.
+//!
+//! The buyer funds the contract with at least the starting price, which
+//! commits to a schedule of prices, one per window of blocks. The buyer may
+//! accept the price of any window which has begun, paying the seller that
+//! price and refunding themselves the rest: one template per window, each
+//! locked until its window begins. As the seller may cancel the sale at any
+//! time, refunding the buyer, waiting for a lower price risks losing the
+//! item, and the buyer accepts the price of the current window (see
+//! `DutchAuction::current_window`) once it is low enough.
+use bitcoin::util::amount::Amount;
+use sapio::contract::actions::ConditionalCompileType;
+use sapio::contract::*;
+use sapio::*;
+use sapio_base::timelocks::AbsHeight;
+use sapio_base::Clause;
+use schemars::*;
+use serde::*;
+use std::collections::LinkedList;
+use std::convert::TryFrom;
+
+/// A Dutch auction's settlement, see the module docs
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct DutchAuction {
+    /// the buyer's key, accepting a price
+    pub buyer: bitcoin::PublicKey,
+    /// where the buyer is refunded
+    pub buyer_address: bitcoin::Address,
+    /// the seller's key, cancelling the sale
+    pub seller: bitcoin::PublicKey,
+    /// where the seller is paid
+    pub seller_address: bitcoin::Address,
+    /// the height the first window begins at
+    pub start: u32,
+    /// how many blocks each window lasts
+    pub window: u32,
+    /// the price of each window, in sats, decreasing
+    pub prices: Vec<u64>,
+    /// the fee paid settling or cancelling
+    #[schemars(with = "i64")]
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub fee: Amount,
+}
+
+impl DutchAuction {
+    /// the height window `i` begins at
+    pub fn window_start(&self, i: usize) -> u32 {
+        self.start
+            .saturating_add(self.window.saturating_mul(i as u32))
+    }
+
+    /// The window whose price the buyer may accept at `height`, the last
+    /// to have begun, if any has.
+    pub fn current_window(&self, height: u32) -> Option<usize> {
+        (0..self.prices.len())
+            .rev()
+            .find(|i| self.window_start(*i) <= height)
+    }
+
+    /// the price the buyer may accept at `height`, if any
+    pub fn price_at(&self, height: u32) -> Option<Amount> {
+        self.current_window(height)
+            .map(|i| Amount::from_sat(self.prices[i]))
+    }
+
+    compile_if! {
+        /// prices must decrease, and the funds cover the first and its fee
+        fn valid_schedule(self, ctx) {
+            let mut errors = LinkedList::new();
+            if self.prices.is_empty() || self.window == 0 {
+                errors.push_back("Schedule must have at least one window, of at least one block".into());
+            }
+            if self.prices.windows(2).any(|w| w[1] >= w[0]) {
+                errors.push_back("Prices must decrease".into());
+            }
+            let first = self.prices.first().map_or(0, |p| *p);
+            if Amount::from_sat(first) + self.fee > ctx.funds() {
+                errors.push_back("Funds must cover the starting price and the fee".into());
+            }
+            if errors.is_empty() {
+                ConditionalCompileType::NoConstraint
+            } else {
+                ConditionalCompileType::Fail(errors)
+            }
+        }
+    }
+    guard! {fn buyer_accepts(self, _ctx) { Clause::Key(self.buyer) }}
+    guard! {fn seller_cancels(self, _ctx) { Clause::Key(self.seller) }}
+
+    then! {
+        annotated: {label: "settle", roles: ["buyer"], doc: "pays the seller the price of a window which has begun, refunding the buyer the rest"}
+        compile_if: [Self::valid_schedule]
+        guarded_by: [Self::buyer_accepts]
+        fn settle(self, ctx) {
+            let mut templates = vec![];
+            for (i, price) in self.prices.iter().enumerate() {
+                let price = Amount::from_sat(*price);
+                let builder = ctx.template().add_fees(self.fee)?;
+                let refund = builder.ctx().funds() - price;
+                let mut builder = builder
+                    .add_output(price, &Compiled::from_address(self.seller_address.clone(), None), None)?;
+                if refund > Amount::from_sat(0) {
+                    builder = builder.add_output(
+                        refund,
+                        &Compiled::from_address(self.buyer_address.clone(), None),
+                        None,
+                    )?;
+                }
+                let tmpl = builder
+                    .set_lock_time(AbsHeight::try_from(self.window_start(i))?.into())?
+                    .set_label(format!("settle in window {}", i))
+                    .finish()?;
+                templates.push(Ok(tmpl));
+            }
+            Ok(Box::new(templates.into_iter()))
+        }
+    }
+    then! {
+        annotated: {label: "cancel", roles: ["seller"], doc: "refunds the buyer"}
+        compile_if: [Self::valid_schedule]
+        guarded_by: [Self::seller_cancels]
+        fn cancel(self, ctx) {
+            let builder = ctx.template().add_fees(self.fee)?;
+            let amount = builder.ctx().funds();
+            builder
+                .add_output(amount, &Compiled::from_address(self.buyer_address.clone(), None), None)?
+                .set_label("cancel".into())
+                .into()
+        }
+    }
+}
+
+impl Contract for DutchAuction {
+    declare! {then, Self::settle, Self::cancel}
+    declare! {non updatable}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::key;
+    use sapio::simulation::{ChainState, Simulator};
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::sync::Arc;
+
+    #[test]
+    fn dutch_auction() {
+        let auction = DutchAuction {
+            buyer: key(1),
+            buyer_address: bitcoin::Address::p2wpkh(&key(1), bitcoin::Network::Regtest).unwrap(),
+            seller: key(2),
+            seller_address: bitcoin::Address::p2wpkh(&key(2), bitcoin::Network::Regtest).unwrap(),
+            start: 1000,
+            window: 144,
+            prices: vec![100_000, 80_000, 60_000, 40_000],
+            fee: Amount::from_sat(1000),
+        };
+        assert_eq!(auction.current_window(999), None);
+        assert_eq!(auction.current_window(1000), Some(0));
+        assert_eq!(auction.current_window(1000 + 144 * 2 + 5), Some(2));
+        assert_eq!(auction.price_at(1_000_000), Some(Amount::from_sat(40_000)));
+
+        let funds = Amount::from_sat(101_000);
+        let ctx = Context::new(bitcoin::Network::Regtest, funds, Arc::new(CTVAvailable));
+        let compiled = auction.compile(&ctx).unwrap();
+        let report = Simulator::new(ChainState {
+            height: 900,
+            median_time_past: 0,
+        })
+        .run(&compiled, funds);
+        assert_eq!(report.dead_branches().count(), 0);
+        // each window's price, and the cancellation
+        assert_eq!(report.outcomes.len(), 5);
+        for outcome in report.outcomes.iter() {
+            let seller = outcome
+                .balances
+                .get(&auction.seller_address.script_pubkey())
+                .cloned()
+                .unwrap_or(Amount::from_sat(0));
+            assert!(seller == Amount::from_sat(0) || auction.prices.contains(&seller.as_sat()));
+        }
+
+        let rising = DutchAuction {
+            prices: vec![60_000, 80_000],
+            ..auction.clone()
+        };
+        assert!(rising.compile(&ctx).is_err());
+    }
+}
@@ -7,6 +7,7 @@
 //! example of using a dynamic contract
 use sapio::contract::DynamicContract;
 use sapio::contract::*;
+use sapio::util::split::{Rounding, Split};
 use sapio::*;
 use schemars::*;
 use serde::*;
@@ -50,14 +51,16 @@ impl DynamicExample {
         let d : D = D{v};
 
         let d2 = DynamicContract::<(), String> {
-            then: vec![|| None, || Some(sapio::contract::actions::ThenFunc{conditional_compile_if: &[], guard: &[], func: |_s, _ctx| Err(CompilationError::TerminateCompilation)})],
+            then: vec![|| None, || Some(sapio::contract::actions::ThenFunc{name: "terminate", conditional_compile_if: &[], guard: &[], func: |_s, _ctx| Err(CompilationError::TerminateCompilation), annotation: None})],
             finish: vec![],
             finish_or: vec![],
             data: "E.g., Create a Vault".into(),
         };
+        let split = Split::even(ctx.funds(), 2, Rounding::RemainderToFee)?;
+        let budgets = ctx.budgets().allocate_split(&["d", "d2"], &split)?;
         ctx.template()
-        .add_output(ctx.funds()/2, &d, None)?
-        .add_output(ctx.funds()/2, &d2, None)?
+        .add_budgeted_output(&budgets, "d", &d, None)?
+        .add_budgeted_output(&budgets, "d2", &d2, None)?
         .into()
     }}
 }
@@ -0,0 +1,289 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! escrow has a contract, `Escrow`, holding a buyer's payment until buyer
+//! and seller agree it is released to the seller or refunded, or an arbiter
+//! rules on it.
+//!
+//! Any two of the three decide where the funds go. Buyer and seller settle
+//! at once, together. The arbiter alone only picks which of them is paid:
+//! ruling moves the funds to a `Ruling`, which pays the party ruled for once
+//! the appeal window has passed. Until then, buyer and seller may still
+//! settle together, overriding the ruling.
+//!
+//! Every resolution is a template, so `Escrow::psbts_for` exports the
+//! transactions each role may sign (or broadcast) ahead of time.
+use bitcoin::util::amount::Amount;
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use sapio::contract::*;
+use sapio::template::Builder;
+use sapio::*;
+use sapio_base::timelocks::AnyRelTimeLock;
+use sapio_base::txindex::TxIndexLogger;
+use sapio_base::Clause;
+use sapio_ctv_emulator_trait::CTVEmulator;
+use schemars::*;
+use serde::*;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// A party to an `Escrow`
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// pays into the escrow
+    Buyer,
+    /// is paid out of the escrow
+    Seller,
+    /// rules on disputes
+    Arbiter,
+}
+
+impl Role {
+    /// the role's name in the contract's spend paths
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Buyer => "buyer",
+            Role::Seller => "seller",
+            Role::Arbiter => "arbiter",
+        }
+    }
+}
+
+/// An escrow with an arbiter, see the module docs
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct Escrow {
+    /// the buyer's key
+    pub buyer: bitcoin::PublicKey,
+    /// where the buyer is refunded
+    pub buyer_address: bitcoin::Address,
+    /// the seller's key
+    pub seller: bitcoin::PublicKey,
+    /// where the seller is paid
+    pub seller_address: bitcoin::Address,
+    /// the arbiter's key
+    pub arbiter: bitcoin::PublicKey,
+    /// how long after a ruling buyer and seller may still override it
+    pub appeal: AnyRelTimeLock,
+    /// the fee paid by each transaction
+    #[schemars(with = "i64")]
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub fee: Amount,
+}
+
+/// An arbiter's ruling on an `Escrow`, see the module docs
+pub struct Ruling {
+    escrow: Escrow,
+    /// the buyer or the seller
+    in_favor_of: Role,
+}
+
+/// A resolution of an `Escrow`, unsigned
+pub struct ResolutionPsbt {
+    /// the spend path the transaction is in, e.g., `"rule"`
+    pub path: String,
+    /// the transaction's label, e.g., `"release"`
+    pub label: String,
+    /// the transaction
+    pub psbt: PartiallySignedTransaction,
+}
+
+impl Escrow {
+    /// pays the funds of `ctx`, less the fee, to the buyer or the seller
+    fn pay(&self, ctx: &Context, to: Role) -> Result<Builder, CompilationError> {
+        let (address, label) = match to {
+            Role::Buyer => (&self.buyer_address, "refund"),
+            _ => (&self.seller_address, "release"),
+        };
+        let builder = ctx.template().add_fees(self.fee)?;
+        let amount = builder.ctx().funds();
+        Ok(builder
+            .add_output(amount, &Compiled::from_address(address.clone(), None), None)?
+            .set_label(label.into()))
+    }
+
+    /// releases the funds of `ctx` to the seller, or refunds the buyer
+    fn settlements(&self, ctx: &Context) -> TxTmplIt {
+        Ok(Box::new(
+            vec![
+                self.pay(ctx, Role::Seller)?.finish(),
+                self.pay(ctx, Role::Buyer)?.finish(),
+            ]
+            .into_iter(),
+        ))
+    }
+
+    /// Every resolution of the escrow funded at `out` with the funds of
+    /// `ctx`, including those of the rulings, signed by `emulator`.
+    pub fn psbts(
+        &self,
+        ctx: &Context,
+        out: bitcoin::OutPoint,
+        emulator: &dyn CTVEmulator,
+    ) -> Result<Vec<ResolutionPsbt>, CompilationError> {
+        let compiled = self.compile(ctx)?;
+        let (psbts, metadata) =
+            compiled.bind_psbt(out, HashMap::new(), Rc::new(TxIndexLogger::new()), emulator)?;
+        Ok(psbts
+            .into_iter()
+            .zip(metadata.iter())
+            .map(|(psbt, meta)| ResolutionPsbt {
+                path: meta["spend_path"].as_str().unwrap_or_default().into(),
+                label: meta["metadata"]["label"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .into(),
+                psbt,
+            })
+            .collect())
+    }
+
+    /// the resolutions of `psbts` which `role` signs, or broadcasts
+    pub fn psbts_for(
+        &self,
+        role: Role,
+        ctx: &Context,
+        out: bitcoin::OutPoint,
+        emulator: &dyn CTVEmulator,
+    ) -> Result<Vec<ResolutionPsbt>, CompilationError> {
+        let compiled = self.compile(ctx)?;
+        let mut roles = HashMap::new();
+        collect_roles(&compiled, &mut roles);
+        Ok(self
+            .psbts(ctx, out, emulator)?
+            .into_iter()
+            .filter(|r| {
+                roles
+                    .get(&r.path)
+                    .map_or(false, |rs| rs.iter().any(|s| s == role.as_str()))
+            })
+            .collect())
+    }
+
+    guard! {fn buyer_and_seller(self, _ctx) {
+        Clause::And(vec![Clause::Key(self.buyer), Clause::Key(self.seller)])
+    }}
+    guard! {fn arbiter_rules(self, _ctx) { Clause::Key(self.arbiter) }}
+
+    then! {
+        annotated: {label: "mutual", roles: ["buyer", "seller"], doc: "releases or refunds the funds at once, as buyer and seller agree"}
+        guarded_by: [Self::buyer_and_seller]
+        fn mutual(self, ctx) {
+            self.settlements(ctx)
+        }
+    }
+    then! {
+        annotated: {label: "rule", roles: ["arbiter"], doc: "rules for the buyer or the seller, who is paid after the appeal window"}
+        guarded_by: [Self::arbiter_rules]
+        fn rule(self, ctx) {
+            let mut templates = vec![];
+            for in_favor_of in [Role::Buyer, Role::Seller].iter() {
+                let ruling = Ruling {
+                    escrow: self.clone(),
+                    in_favor_of: *in_favor_of,
+                };
+                let builder = ctx.template().add_fees(self.fee)?;
+                let amount = builder.ctx().funds();
+                templates.push(
+                    builder
+                        .add_output(amount, &ruling, None)?
+                        .set_label(format!("rule for {}", in_favor_of.as_str()))
+                        .finish(),
+                );
+            }
+            Ok(Box::new(templates.into_iter()))
+        }
+    }
+}
+
+impl Contract for Escrow {
+    declare! {then, Self::mutual, Self::rule}
+    declare! {non updatable}
+}
+
+impl Ruling {
+    guard! {fn appeal_passed(self, _ctx) { self.escrow.appeal.into() }}
+    guard! {fn buyer_and_seller(self, _ctx) {
+        Clause::And(vec![Clause::Key(self.escrow.buyer), Clause::Key(self.escrow.seller)])
+    }}
+
+    then! {
+        annotated: {label: "enforce", roles: ["buyer", "seller"], doc: "pays the party ruled for, once the appeal window has passed"}
+        guarded_by: [Self::appeal_passed]
+        fn enforce(self, ctx) {
+            self.escrow
+                .pay(ctx, self.in_favor_of)?
+                .set_sequence(0, self.escrow.appeal)?
+                .into()
+        }
+    }
+    then! {
+        annotated: {label: "appeal", roles: ["buyer", "seller"], doc: "overrides the ruling, as buyer and seller agree"}
+        guarded_by: [Self::buyer_and_seller]
+        fn appeal(self, ctx) {
+            self.escrow.settlements(ctx)
+        }
+    }
+}
+
+impl Contract for Ruling {
+    declare! {then, Self::enforce, Self::appeal}
+    declare! {non updatable}
+}
+
+/// the roles of the spend paths of `object` and of the contracts it creates,
+/// by label
+fn collect_roles(object: &Compiled, roles: &mut HashMap<String, Vec<String>>) {
+    for path in object.spend_paths.values() {
+        roles.insert(path.label.clone(), path.roles.clone());
+    }
+    for tmpl in object.ctv_to_tx.values() {
+        for output in tmpl.outputs.iter() {
+            collect_roles(&output.contract, roles);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::key;
+    use sapio_base::timelocks::RelHeight;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::sync::Arc;
+
+    #[test]
+    fn escrow_psbts() {
+        let escrow = Escrow {
+            buyer: key(1),
+            buyer_address: bitcoin::Address::p2wpkh(&key(1), bitcoin::Network::Regtest).unwrap(),
+            seller: key(2),
+            seller_address: bitcoin::Address::p2wpkh(&key(2), bitcoin::Network::Regtest).unwrap(),
+            arbiter: key(3),
+            appeal: RelHeight::from(1008).into(),
+            fee: Amount::from_sat(1000),
+        };
+        let ctx = Context::new(
+            bitcoin::Network::Regtest,
+            Amount::from_sat(1_000_000),
+            Arc::new(CTVAvailable),
+        );
+        let out = bitcoin::OutPoint::default();
+        // mutual: 2, rule: 2, and per ruling enforce: 1 and appeal: 2
+        assert_eq!(escrow.psbts(&ctx, out, &CTVAvailable).unwrap().len(), 10);
+        let arbiter = escrow
+            .psbts_for(Role::Arbiter, &ctx, out, &CTVAvailable)
+            .unwrap();
+        assert_eq!(arbiter.len(), 2);
+        assert!(arbiter.iter().all(|r| r.path == "rule"));
+        let buyer = escrow
+            .psbts_for(Role::Buyer, &ctx, out, &CTVAvailable)
+            .unwrap();
+        assert_eq!(buyer.len(), 8);
+        for r in buyer.iter().filter(|r| r.path == "enforce") {
+            assert_eq!(r.psbt.global.unsigned_tx.input[0].sequence, 1008);
+        }
+    }
+}
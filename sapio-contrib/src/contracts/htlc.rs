@@ -0,0 +1,158 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Hash and point time-locked contracts, for channels and swaps.
+//!
+//! A `Tlc` pays the receiver once they unlock it, or refunds the sender after
+//! a timeout. An HTLC is unlocked by revealing the preimage of a hash, a PTLC
+//! by signing with the secret of a point (which the receiver learns, e.g.,
+//! from an adaptor signature, and which unlike a preimage isn't the same at
+//! every hop of a route).
+//!
+//! A `Tlc` is a contract on its own, spent by keys alone, so it can be paid to
+//! as an output. Contracts with other branches can instead embed its clauses
+//! in their guards:
+//! ```ignore
+//! guard! {fn claim(self, _ctx) { self.htlc.claim() }}
+//! guard! {fn refund(self, _ctx) { self.htlc.refund() }}
+//! ```
+use bitcoin::hashes::sha256;
+use miniscript::Descriptor;
+use sapio::contract::combinators::and;
+use sapio::contract::*;
+use sapio::*;
+use sapio_base::timelocks::AnyTimeLock;
+use sapio_base::Clause;
+use schemars::*;
+use serde::*;
+
+/// What unlocks a `Tlc` for the receiver
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Lock {
+    /// revealing the preimage of the hash
+    Hash(#[schemars(with = "String")] sha256::Hash),
+    /// signing with the secret of the point
+    Point(bitcoin::PublicKey),
+}
+
+impl From<Lock> for Clause {
+    fn from(l: Lock) -> Clause {
+        match l {
+            Lock::Hash(h) => Clause::Sha256(h),
+            Lock::Point(p) => Clause::Key(p),
+        }
+    }
+}
+
+/// A hash or point time-locked contract, see the module docs
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Copy)]
+pub struct Tlc {
+    /// the key the receiver claims with
+    pub receiver: bitcoin::PublicKey,
+    /// the key the sender is refunded with
+    pub sender: bitcoin::PublicKey,
+    /// what the receiver must also satisfy to claim
+    pub lock: Lock,
+    /// when the sender may be refunded
+    pub timeout: AnyTimeLock,
+}
+
+impl Tlc {
+    /// a Hash Time-Locked Contract
+    pub fn htlc(
+        receiver: bitcoin::PublicKey,
+        sender: bitcoin::PublicKey,
+        hash: sha256::Hash,
+        timeout: AnyTimeLock,
+    ) -> Tlc {
+        Tlc {
+            receiver,
+            sender,
+            lock: Lock::Hash(hash),
+            timeout,
+        }
+    }
+
+    /// a Point Time-Locked Contract
+    pub fn ptlc(
+        receiver: bitcoin::PublicKey,
+        sender: bitcoin::PublicKey,
+        point: bitcoin::PublicKey,
+        timeout: AnyTimeLock,
+    ) -> Tlc {
+        Tlc {
+            receiver,
+            sender,
+            lock: Lock::Point(point),
+            timeout,
+        }
+    }
+
+    /// the receiver's signature, and the lock
+    pub fn claim(&self) -> Clause {
+        and(vec![Clause::Key(self.receiver), self.lock.into()])
+    }
+
+    /// the sender's signature, after the timeout
+    pub fn refund(&self) -> Clause {
+        and(vec![Clause::Key(self.sender), self.timeout.into()])
+    }
+
+    /// the policy of the contract, claim or refund, as the compiler builds it
+    pub fn clause(&self) -> Clause {
+        Clause::Threshold(1, vec![self.claim(), self.refund()])
+    }
+
+    /// the contract as a segwit v0 descriptor, for use outside of sapio
+    pub fn descriptor(&self) -> Result<Descriptor<bitcoin::PublicKey>, CompilationError> {
+        let miniscript = self.clause().compile().map_err(CompilationError::from)?;
+        Descriptor::new_wsh(miniscript).map_err(CompilationError::from)
+    }
+
+    guard! {fn claim_guard(self, _ctx) { self.claim() }}
+    guard! {fn refund_guard(self, _ctx) { self.refund() }}
+}
+
+impl Contract for Tlc {
+    declare! {finish, Self::claim_guard, Self::refund_guard}
+    declare! {non updatable}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::key;
+    use bitcoin::hashes::Hash;
+    use miniscript::DescriptorTrait;
+    use sapio_base::timelocks::{RelHeight, RelTime};
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::sync::Arc;
+
+    #[test]
+    fn htlc_and_ptlc() {
+        let ctx = Context::new(
+            bitcoin::Network::Regtest,
+            bitcoin::Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+        );
+        let timeout = AnyTimeLock::R(RelHeight::from(144).into());
+        let htlc = Tlc::htlc(key(1), key(2), sha256::Hash::hash(&[0; 32]), timeout);
+        let ptlc = Tlc::ptlc(
+            key(1),
+            key(2),
+            key(3),
+            AnyTimeLock::R(RelTime::from(1000).into()),
+        );
+        for tlc in [htlc, ptlc].iter() {
+            let compiled = tlc.compile(&ctx).unwrap();
+            // paying to the contract and to its descriptor are the same
+            let expected = tlc.descriptor().unwrap().script_pubkey();
+            let got: bitcoin::Script = compiled.address.into();
+            assert_eq!(got, expected);
+        }
+        assert_ne!(htlc.claim(), ptlc.claim());
+    }
+}
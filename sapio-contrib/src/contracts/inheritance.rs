@@ -0,0 +1,167 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! inheritance has a dead man's switch contract, `Inheritance`, which the
+//! owner may always spend, and their heirs once it has been unspent for a
+//! long time.
+//!
+//! The heirs' timelock is relative, so it restarts whenever the funds move.
+//! While alive, the owner refreshes the contract before the deadline (see
+//! `Inheritance::deadline`) by rolling the funds to a new instance with
+//! `Inheritance::refresh`, possibly with new heirs. As the owner's key alone
+//! spends the contract, the refresh isn't a template of it (which would
+//! recurse forever) but a transaction the owner signs when they need it.
+use bitcoin::util::amount::Amount;
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use miniscript::DescriptorTrait;
+use sapio::contract::*;
+use sapio::simulation::ChainState;
+use sapio::*;
+use sapio_base::timelocks::{AbsHeight, AbsTime, AnyAbsTimeLock, AnyRelTimeLock, LockTimeError};
+use sapio_base::Clause;
+use schemars::*;
+use serde::*;
+use std::convert::TryFrom;
+
+/// A dead man's switch, see the module docs
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct Inheritance {
+    /// the owner's key, which may always spend
+    pub owner: bitcoin::PublicKey,
+    /// the heirs' keys
+    pub heirs: Vec<bitcoin::PublicKey>,
+    /// how many of the heirs must sign
+    pub threshold: usize,
+    /// how long the contract must be unspent before the heirs may spend it
+    pub delay: AnyRelTimeLock,
+    /// the fee paid refreshing the contract
+    #[schemars(with = "i64")]
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub fee: Amount,
+}
+
+impl Inheritance {
+    /// When the heirs may first spend the contract, confirmed at
+    /// `confirmed`. The owner must refresh it before then.
+    pub fn deadline(&self, confirmed: &ChainState) -> Result<AnyAbsTimeLock, LockTimeError> {
+        Ok(match self.delay {
+            AnyRelTimeLock::RH(blocks) => {
+                AbsHeight::try_from(confirmed.height.saturating_add(blocks.get()))?.into()
+            }
+            AnyRelTimeLock::RT(units) => AbsTime::try_from(
+                confirmed
+                    .median_time_past
+                    .saturating_add((units.get() & 0xffff).saturating_mul(512)),
+            )?
+            .into(),
+        })
+    }
+
+    /// if the heirs may spend the contract, confirmed at `confirmed`, at `now`
+    pub fn expired(&self, confirmed: &ChainState, now: &ChainState) -> Result<bool, LockTimeError> {
+        Ok(match self.deadline(confirmed)? {
+            AnyAbsTimeLock::AH(h) => now.height >= h.get(),
+            AnyAbsTimeLock::AT(t) => now.median_time_past >= t.get(),
+        })
+    }
+
+    /// The owner's transaction rolling the contract, funded at `out` with the
+    /// funds of `ctx`, to `next`, less the fee.
+    pub fn refresh(
+        &self,
+        ctx: &Context,
+        out: bitcoin::OutPoint,
+        next: &Inheritance,
+    ) -> Result<PartiallySignedTransaction, CompilationError> {
+        let compiled = self.compile(ctx)?;
+        let builder = ctx.template().add_fees(self.fee)?;
+        let amount = builder.ctx().funds();
+        let template = builder
+            .add_output(amount, next, None)?
+            .set_label("refresh".into())
+            .finish()?;
+        let mut tx = template.tx;
+        tx.input[0].previous_output = out;
+        let mut psbt =
+            PartiallySignedTransaction::from_unsigned_tx(tx).map_err(CompilationError::custom)?;
+        psbt.inputs[0].witness_utxo = Some(bitcoin::TxOut {
+            value: ctx.funds().as_sat(),
+            script_pubkey: compiled.address.into(),
+        });
+        psbt.inputs[0].witness_script = compiled.descriptor.map(|d| d.explicit_script());
+        Ok(psbt)
+    }
+
+    guard! {fn owner_spends(self, _ctx) { Clause::Key(self.owner) }}
+    guard! {fn heirs_spend(self, _ctx) {
+        Clause::And(vec![
+            Clause::Threshold(self.threshold, self.heirs.iter().cloned().map(Clause::Key).collect()),
+            self.delay.into(),
+        ])
+    }}
+}
+
+impl Contract for Inheritance {
+    declare! {finish, Self::owner_spends, Self::heirs_spend}
+    declare! {non updatable}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::key;
+    use sapio_base::timelocks::{RelHeight, RelTime};
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::sync::Arc;
+
+    #[test]
+    fn inheritance_refresh() {
+        let ctx = Context::new(
+            bitcoin::Network::Regtest,
+            Amount::from_sat(1_000_000),
+            Arc::new(CTVAvailable),
+        );
+        let will = Inheritance {
+            owner: key(1),
+            heirs: vec![key(2), key(3), key(4)],
+            threshold: 2,
+            delay: RelHeight::from(52_560).into(),
+            fee: Amount::from_sat(1000),
+        };
+        let confirmed = ChainState {
+            height: 700_000,
+            median_time_past: 1_600_000_000,
+        };
+        assert_eq!(will.deadline(&confirmed).unwrap().get(), 752_560);
+        let later = ChainState {
+            height: 752_560,
+            ..confirmed
+        };
+        assert!(!will.expired(&confirmed, &confirmed).unwrap());
+        assert!(will.expired(&confirmed, &later).unwrap());
+
+        let next = Inheritance {
+            delay: RelTime::from(1000).into(),
+            ..will.clone()
+        };
+        assert_eq!(
+            next.deadline(&confirmed).unwrap().get(),
+            1_600_000_000 + 1000 * 512
+        );
+        let psbt = will
+            .refresh(&ctx, bitcoin::OutPoint::default(), &next)
+            .unwrap();
+        let tx = &psbt.global.unsigned_tx;
+        let next_script: bitcoin::Script = next
+            .compile(&ctx.with_amount(Amount::from_sat(999_000)).unwrap())
+            .unwrap()
+            .address
+            .into();
+        assert_eq!(tx.output[0].script_pubkey, next_script);
+        assert_eq!(tx.output[0].value, 999_000);
+        assert!(psbt.inputs[0].witness_script.is_some());
+    }
+}
@@ -14,16 +14,29 @@ use schemars::*;
 use serde::*;
 use std::convert::TryInto;
 pub mod basic_examples;
+pub mod bond;
+pub mod cancellable;
 pub mod channel;
+pub mod channel_factory;
 pub mod coin_pool;
+pub mod decaying_multisig;
 pub mod derivatives;
+pub mod dutch_auction;
 pub mod dynamic;
 pub mod eltoo_channel;
+pub mod escrow;
 pub mod federated_sidechain;
 pub mod hodl_chicken;
+pub mod htlc;
+pub mod inheritance;
+pub mod payment_pool;
+pub mod probabilistic_payment;
 pub mod readme_contracts;
 pub mod staked_signer;
+pub mod submarine_swap;
+pub mod subscription;
 pub mod tic_tac_toe;
 pub mod treepay;
 pub mod undo_send;
 pub mod vault;
+pub mod vesting;
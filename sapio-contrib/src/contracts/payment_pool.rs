@@ -0,0 +1,249 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! payment_pool has a contract `PaymentPool` for N parties sharing a UTXO,
+//! each with a balance they can exit with unilaterally.
+//!
+//! While every member cooperates, they update their balances off-chain, and
+//! sign (with every key) a transaction moving the pool to its next state,
+//! see `PaymentPool::update`. If any member is offline or uncooperative, a
+//! member can exit instead, paying out their balance and re-creating the
+//! pool without them. As each pool's address commits to the pools its exits
+//! create, the number of pools compiled grows with the number of members to
+//! the power of `exits`, so after that many exits the pool dissolves instead,
+//! paying every member out at once.
+//!
+//! Exits need no signature: as they pay every member exactly what they are
+//! owed, anyone may broadcast them.
+use bitcoin::util::amount::Amount;
+use sapio::contract::actions::ConditionalCompileType;
+use sapio::contract::*;
+use sapio::util::split::{Rounding, Split};
+use sapio::*;
+use sapio_base::Clause;
+use schemars::*;
+use serde::*;
+
+/// A member of a `PaymentPool`, and their balance
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Balance {
+    /// the key the member signs updates with
+    pub key: bitcoin::PublicKey,
+    /// where the member's balance is paid when they exit
+    pub address: bitcoin::Address,
+    /// the member's balance
+    #[schemars(with = "i64")]
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub amount: Amount,
+}
+
+/// An N-party payment pool, see the module docs
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct PaymentPool {
+    /// the members, and their balances
+    pub balances: Vec<Balance>,
+    /// the fee paid by each transaction, by the member exiting or, when
+    /// dissolving, by every member in proportion to their balance
+    #[schemars(with = "i64")]
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub fee: Amount,
+    /// how many more members may exit one at a time before the pool
+    /// dissolves
+    pub exits: u8,
+}
+
+/// The next state of a `PaymentPool`, agreed by every member
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct PoolUpdate {
+    /// the members of the next pool, and their balances, which with the fee
+    /// must sum to the pool's funds
+    pub balances: Vec<Balance>,
+}
+
+impl PaymentPool {
+    /// the sum of the members' balances
+    pub fn total(&self) -> Amount {
+        self.balances
+            .iter()
+            .fold(Amount::from_sat(0), |a, b| a + b.amount)
+    }
+
+    /// the pool without the member at `idx`, one fewer exit away from
+    /// dissolving
+    pub fn without(&self, idx: usize) -> PaymentPool {
+        let mut balances = self.balances.clone();
+        balances.remove(idx);
+        PaymentPool {
+            balances,
+            fee: self.fee,
+            exits: self.exits.saturating_sub(1),
+        }
+    }
+
+    fn keys(&self) -> Vec<Clause> {
+        self.balances.iter().map(|b| Clause::Key(b.key)).collect()
+    }
+
+    compile_if! {
+        /// members exit one at a time while there are exits left
+        fn exits_left(self, _ctx) {
+            if self.exits > 0 && self.balances.len() > 1 {
+                ConditionalCompileType::Required
+            } else {
+                ConditionalCompileType::Never
+            }
+        }
+    }
+    compile_if! {
+        /// otherwise everyone is paid out at once
+        fn no_exits_left(self, _ctx) {
+            if self.exits > 0 && self.balances.len() > 1 {
+                ConditionalCompileType::Never
+            } else {
+                ConditionalCompileType::Required
+            }
+        }
+    }
+    guard! {
+        /// every member has signed
+        fn all_sign(self, _ctx) {
+            Clause::Threshold(self.balances.len(), self.keys())
+        }
+    }
+
+    then! {
+        annotated: {label: "exit", roles: ["member"], doc: "pays one member their balance, less the fee, and re-creates the pool without them"}
+        compile_if: [Self::exits_left]
+        fn exit(self, ctx) {
+            let mut templates = vec![];
+            for (idx, member) in self.balances.iter().enumerate() {
+                let payout = member
+                    .amount
+                    .checked_sub(self.fee)
+                    .ok_or(CompilationError::OutOfFunds)?;
+                let rest = self.without(idx);
+                let tmpl = ctx
+                    .template()
+                    .add_fees(self.fee)?
+                    .add_output(payout, &Compiled::from_address(member.address.clone(), None), None)?
+                    .add_output(rest.total(), &rest, None)?
+                    .set_label(format!("exit {}", idx))
+                    .finish()?;
+                templates.push(Ok(tmpl));
+            }
+            Ok(Box::new(templates.into_iter()))
+        }
+    }
+    then! {
+        annotated: {label: "dissolve", roles: ["member"], doc: "pays every member their balance, less their share of the fee"}
+        compile_if: [Self::no_exits_left]
+        fn dissolve(self, ctx) {
+            let weights: Vec<u64> = self.balances.iter().map(|b| b.amount.as_sat()).collect();
+            let fees = Split::weighted(self.fee, &weights, Rounding::RemainderToFirst)?;
+            let mut builder = ctx.template().add_fees(self.fee)?;
+            for (member, fee) in self.balances.iter().zip(fees.shares()) {
+                let payout = member
+                    .amount
+                    .checked_sub(*fee)
+                    .ok_or(CompilationError::OutOfFunds)?;
+                builder = builder.add_output(
+                    payout,
+                    &Compiled::from_address(member.address.clone(), None),
+                    None,
+                )?;
+            }
+            builder.set_label("dissolve".into()).into()
+        }
+    }
+    finish! {
+        /// moves the pool to the state every member agreed to
+        guarded_by: [Self::all_sign]
+        fn update(self, ctx, o) {
+            if let Some(update) = o {
+                let next = PaymentPool {
+                    balances: update.balances.clone(),
+                    fee: self.fee,
+                    exits: self.exits,
+                };
+                ctx.template()
+                    .add_fees(self.fee)?
+                    .add_output(next.total(), &next, None)?
+                    .into()
+            } else {
+                Ok(Box::new(std::iter::empty()))
+            }
+        }
+    }
+}
+
+impl Contract for PaymentPool {
+    declare! {then, Self::exit, Self::dissolve}
+    declare! {updatable<PoolUpdate>, Self::update}
+    declare! {effects}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::secp256k1::{Secp256k1, SecretKey};
+    use sapio::simulation::{ChainState, Simulator};
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::sync::Arc;
+
+    fn pool(n: u8, exits: u8) -> PaymentPool {
+        let secp = Secp256k1::new();
+        PaymentPool {
+            balances: (1..=n)
+                .map(|i| {
+                    let key = bitcoin::PublicKey {
+                        compressed: true,
+                        key: bitcoin::secp256k1::PublicKey::from_secret_key(
+                            &secp,
+                            &SecretKey::from_slice(&[i; 32]).unwrap(),
+                        ),
+                    };
+                    Balance {
+                        key,
+                        address: bitcoin::Address::p2wpkh(&key, bitcoin::Network::Regtest).unwrap(),
+                        amount: Amount::from_sat(100_000 * i as u64),
+                    }
+                })
+                .collect(),
+            fee: Amount::from_sat(1000),
+            exits,
+        }
+    }
+
+    #[test]
+    fn payment_pool_exits() {
+        for (n, exits) in [(2, 2), (8, 2), (32, 1)].iter() {
+            let pool = pool(*n, *exits);
+            let total = pool.total();
+            let ctx = Context::new(bitcoin::Network::Regtest, total, Arc::new(CTVAvailable));
+            let compiled = pool.compile(&ctx).unwrap();
+            assert!(compiled.continue_points.contains_key("update"));
+            let report = Simulator::new(ChainState {
+                height: 0,
+                median_time_past: 0,
+            })
+            .with_max_outcomes(10_000)
+            .run(&compiled, total);
+            assert_eq!(report.dead_branches().count(), 0);
+            assert!(!report.truncated);
+            // any member exiting first
+            assert!(report.outcomes.len() >= *n as usize);
+            for outcome in report.outcomes.iter() {
+                let paid = outcome.balances.values().fold(outcome.fees, |a, b| a + *b);
+                assert_eq!(paid, total);
+                // every member leaves with their balance, less at most the fee
+                for member in pool.balances.iter() {
+                    let got = outcome.balances[&member.address.script_pubkey()];
+                    assert!(got + pool.fee >= member.amount && got < member.amount);
+                }
+            }
+        }
+    }
+}
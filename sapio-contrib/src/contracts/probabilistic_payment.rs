@@ -0,0 +1,368 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Probabilistic payments: paying `amount` with probability `k/n`, so that
+//! many small payments can be amortized into a few larger ones with the same
+//! expected value.
+//!
+//! The random value is generated jointly by payer and payee with a
+//! commit-reveal coin flip, using hash preimages so that it can be settled
+//! on-chain:
+//!
+//! 1. The payee picks `n` random 32 byte secrets `b_0..b_n` and a value `y`
+//!    in `0..n`, and sends the payer `sha256(b_i)` for every `i` and the
+//!    commitment `ripemd160(b_y)`. The commitment hides `y`, as the payer can't
+//!    compute `ripemd160(b_i)` from `sha256(b_i)`, and binds the payee to it,
+//!    as only `b_y` opens it.
+//! 2. The payer picks a value `x` in `0..n` and funds the contract, which
+//!    records it. The random value is `(x + y) mod n`.
+//! 3. If the random value is below `k`, the payee reveals `b_y` spending to
+//!    `pay_to`. Otherwise, or if the payee never reveals, the funds return to
+//!    `refund` after `timeout`. The payee should reveal well before the
+//!    timeout, as after it both ways of spending are valid.
+//!
+//! Payer and payee may also settle together at any time, with both keys.
+//!
+//! The payee knows every `b_i`, so revealing must check that the preimage
+//! opening the commitment is the one whose `sha256` is a winning value.
+//! Policies can't express that, as each hash in a policy is checked against
+//! its own witness element: the payee could open the commitment with `b_y`
+//! and claim with any winning `b_i`. So a `ProbabilisticPayment` isn't a
+//! `Contract`, but compiles to a hand written segwit v0 script, see
+//! `ProbabilisticPayment::witness_script`.
+use bitcoin::blockdata::opcodes::all::*;
+use bitcoin::blockdata::script::{Builder, Script};
+use bitcoin::hashes::{ripemd160, sha256};
+use bitcoin::util::amount::Amount;
+use miniscript::Segwitv0;
+use sapio::contract::*;
+use sapio::template::Template;
+use sapio::util::amountrange::AmountRange;
+use sapio_base::timelocks::AnyRelTimeLock;
+use sapio_base::Clause;
+use schemars::*;
+use serde::*;
+use std::collections::LinkedList;
+
+/// A payment of `amount` to the payee with probability `threshold` out of
+/// the number of values, see the module docs.
+#[derive(JsonSchema, Serialize, Deserialize)]
+pub struct ProbabilisticPayment {
+    /// the payer's key
+    pub payer: bitcoin::PublicKey,
+    /// the payee's key
+    pub payee: bitcoin::PublicKey,
+    /// the contract to refund to, if the payee loses
+    pub refund: Compiled,
+    /// the contract to pay, if the payee wins
+    pub pay_to: Compiled,
+    /// the amount paid, if the payee wins
+    #[schemars(with = "i64")]
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub amount: Amount,
+    /// the payee's commitment to its value, `ripemd160(b_y)`
+    #[schemars(with = "String")]
+    pub commitment: ripemd160::Hash,
+    /// `sha256(b_i)` for each of the values the payee may have picked
+    #[schemars(with = "Vec<String>")]
+    pub values: Vec<sha256::Hash>,
+    /// the value the payer picked
+    pub payer_value: u64,
+    /// the payee wins if the random value is below this
+    pub threshold: u64,
+    /// how long the payee has to reveal its value
+    pub timeout: AnyRelTimeLock,
+}
+
+impl ProbabilisticPayment {
+    /// the values the payee wins with, given the payer's
+    pub fn winning_values(&self) -> impl Iterator<Item = &sha256::Hash> + '_ {
+        let n = self.values.len() as u64;
+        self.values
+            .iter()
+            .enumerate()
+            .filter(move |(y, _)| (self.payer_value + *y as u64) % n < self.threshold)
+            .map(|(_, h)| h)
+    }
+
+    /// the payment must be neither certain nor impossible, the payer's value
+    /// in range, and the contract compiled for segwit v0
+    fn valid(&self, ctx: &Context) -> Result<(), CompilationError> {
+        let n = self.values.len() as u64;
+        let mut errors = LinkedList::new();
+        if self.threshold == 0 || self.threshold >= n {
+            errors.push_back(
+                "Threshold must be between 0 and the number of values, exclusive".into(),
+            );
+        }
+        if self.payer_value >= n {
+            errors.push_back("Payer value out of range".into());
+        }
+        if ctx.target() != CompileTarget::SegwitV0 {
+            errors.push_back("Probabilistic payments only compile for segwit v0".into());
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(CompilationError::ConditionalCompilationFailed(errors))
+        }
+    }
+
+    /// the transactions paying the payee if it wins, and refunding the payer
+    /// otherwise
+    fn templates(&self, ctx: &Context) -> Result<(Template, Template), CompilationError> {
+        let win = ctx
+            .derive("win")
+            .template()
+            .add_output(self.amount, &self.pay_to, None)?
+            .finish()?;
+        let expire = ctx
+            .derive("expire")
+            .template()
+            .add_output(self.amount, &self.refund, None)?
+            .set_sequence(0, self.timeout)?
+            .finish()?;
+        Ok((win, expire))
+    }
+
+    /// the clause covenanting to spend with `template`
+    fn covenant(template: &Template, ctx: &Context) -> Result<Clause, CompilationError> {
+        let clause = ctx.ctv_emulator(template.hash())?;
+        let covenant = ctx.covenant();
+        if !covenant.segwit_v0() && matches!(clause, Clause::TxTemplate(_)) {
+            return Err(CompilationError::UnsupportedCovenant(covenant.name()));
+        }
+        Ok(clause)
+    }
+
+    /// The script the contract pays to, as P2WSH:
+    ///
+    /// ```text
+    /// OP_IF
+    ///     OP_SIZE 32 OP_EQUALVERIFY
+    ///     OP_DUP OP_RIPEMD160 <commitment> OP_EQUALVERIFY
+    ///     OP_SHA256
+    ///     OP_DUP <winning value> OP_EQUAL OP_SWAP
+    ///     (OP_DUP <winning value> OP_EQUAL OP_ROT OP_BOOLOR OP_SWAP)*
+    ///     OP_DROP OP_VERIFY
+    ///     <the covenant to `pay_to`>
+    /// OP_ELSE
+    ///     <both keys, or the covenant to `refund`>
+    /// OP_ENDIF
+    /// ```
+    ///
+    /// The payee wins with the witness of the covenant followed by `b_y` and
+    /// `1`, and anyone else spends with the witness of the other branch
+    /// followed by an empty element. The covenants are compiled from their
+    /// policies, with the context's emulator.
+    pub fn witness_script(&self, ctx: &Context) -> Result<Script, CompilationError> {
+        self.valid(ctx)?;
+        let (win, expire) = self.templates(ctx)?;
+        self.script(
+            &Self::covenant(&win, ctx)?,
+            &Clause::Threshold(
+                1,
+                vec![
+                    Clause::And(vec![Clause::Key(self.payer), Clause::Key(self.payee)]),
+                    Self::covenant(&expire, ctx)?,
+                ],
+            ),
+        )
+    }
+
+    fn script(&self, win: &Clause, otherwise: &Clause) -> Result<Script, CompilationError> {
+        let mut reveal = Builder::new()
+            .push_opcode(OP_IF)
+            .push_opcode(OP_SIZE)
+            .push_int(32)
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(OP_DUP)
+            .push_opcode(OP_RIPEMD160)
+            .push_slice(&self.commitment[..])
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(OP_SHA256);
+        for (i, value) in self.winning_values().enumerate() {
+            reveal = reveal
+                .push_opcode(OP_DUP)
+                .push_slice(&value[..])
+                .push_opcode(OP_EQUAL);
+            if i > 0 {
+                reveal = reveal.push_opcode(OP_ROT).push_opcode(OP_BOOLOR);
+            }
+            reveal = reveal.push_opcode(OP_SWAP);
+        }
+        let reveal = reveal.push_opcode(OP_DROP).push_opcode(OP_VERIFY);
+        let mut script = reveal.into_script().into_bytes();
+        script.extend(win.compile::<Segwitv0>()?.encode().as_bytes());
+        script.push(OP_ELSE.into_u8());
+        script.extend(otherwise.compile::<Segwitv0>()?.encode().as_bytes());
+        script.push(OP_ENDIF.into_u8());
+        Ok(script.into())
+    }
+
+    /// Compiles the payment, see `witness_script`. As the script isn't a
+    /// policy, the object has neither a policy nor a descriptor.
+    pub fn compile(&self, ctx: &Context) -> Result<Compiled, CompilationError> {
+        let script = self.witness_script(ctx)?;
+        let (win, expire) = self.templates(ctx)?;
+        let mut amount_range = AmountRange::new();
+        amount_range.update_range(win.max);
+        amount_range.update_range(expire.max);
+        let mut object =
+            Compiled::from_script(script.to_v0_p2wsh(), Some(amount_range), ctx.network)?;
+        object.ctv_to_tx = vec![win, expire]
+            .into_iter()
+            .map(|t| (t.hash(), t))
+            .collect();
+        Ok(object)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::key;
+    use bitcoin::blockdata::script::Instruction;
+    use bitcoin::hashes::Hash;
+    use sapio_base::timelocks::RelHeight;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::sync::Arc;
+
+    fn ctx() -> Context {
+        Context::new(
+            bitcoin::Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+        )
+    }
+
+    fn secret(i: usize) -> [u8; 32] {
+        [i as u8 + 1; 32]
+    }
+
+    /// a payment of 4 values the payee wins 2 of, 0 and 3, having committed
+    /// to `y`
+    fn payment(y: usize) -> ProbabilisticPayment {
+        let to = |i| {
+            let address = bitcoin::Address::p2wpkh(&key(i), bitcoin::Network::Regtest).unwrap();
+            Compiled::from_address(address, None)
+        };
+        ProbabilisticPayment {
+            payer: key(1),
+            payee: key(2),
+            refund: to(1),
+            pay_to: to(2),
+            amount: Amount::from_sat(100_000),
+            commitment: ripemd160::Hash::hash(&secret(y)),
+            values: (0..4).map(|i| sha256::Hash::hash(&secret(i))).collect(),
+            payer_value: 1,
+            threshold: 2,
+            timeout: RelHeight::from(144).into(),
+        }
+    }
+
+    /// runs the branch of `script` revealing `preimage`, up to the covenant
+    fn reveals(script: &Script, preimage: &[u8]) -> bool {
+        let truthy = |v: &[u8]| v.iter().any(|b| *b != 0);
+        let mut stack = vec![preimage.to_vec()];
+        for instruction in script.instructions().skip(1) {
+            let op = match instruction.unwrap() {
+                Instruction::PushBytes(b) => {
+                    stack.push(b.to_vec());
+                    continue;
+                }
+                Instruction::Op(op) => op,
+            };
+            let n = stack.len();
+            match op {
+                OP_SIZE => stack.push(vec![stack[n - 1].len() as u8]),
+                OP_DUP => stack.push(stack[n - 1].clone()),
+                OP_SWAP => stack.swap(n - 1, n - 2),
+                OP_ROT => {
+                    let third = stack.remove(n - 3);
+                    stack.push(third);
+                }
+                OP_DROP => {
+                    stack.pop();
+                }
+                OP_RIPEMD160 => {
+                    let top = stack.pop().unwrap();
+                    stack.push(ripemd160::Hash::hash(&top)[..].to_vec());
+                }
+                OP_SHA256 => {
+                    let top = stack.pop().unwrap();
+                    stack.push(sha256::Hash::hash(&top)[..].to_vec());
+                }
+                OP_EQUAL | OP_EQUALVERIFY => {
+                    let equal = stack.pop() == stack.pop();
+                    if op == OP_EQUALVERIFY && !equal {
+                        return false;
+                    }
+                    if op == OP_EQUAL {
+                        stack.push(if equal { vec![1] } else { vec![] });
+                    }
+                }
+                OP_BOOLOR => {
+                    let (a, b) = (stack.pop().unwrap(), stack.pop().unwrap());
+                    stack.push(if truthy(&a) || truthy(&b) {
+                        vec![1]
+                    } else {
+                        vec![]
+                    });
+                }
+                OP_VERIFY => return truthy(&stack.pop().unwrap()) && stack.is_empty(),
+                op => panic!("unexpected {:?}", op),
+            }
+        }
+        unreachable!("the branch has no covenant")
+    }
+
+    #[test]
+    fn only_the_committed_value_claims() {
+        let winner = payment(3).witness_script(&ctx()).unwrap();
+        assert!(reveals(&winner, &secret(3)));
+        // another winning value doesn't open the commitment
+        assert!(!reveals(&winner, &secret(0)));
+        assert!(!reveals(&winner, &[3; 20]));
+
+        let loser = payment(1).witness_script(&ctx()).unwrap();
+        for i in 0..4 {
+            assert!(!reveals(&loser, &secret(i)));
+        }
+        // the losing values don't appear in the script at all
+        for i in [1, 2].iter() {
+            let value = sha256::Hash::hash(&secret(*i));
+            assert!(!loser
+                .instructions()
+                .any(|ins| ins == Ok(Instruction::PushBytes(&value[..]))));
+        }
+    }
+
+    #[test]
+    fn compile() {
+        let pay = payment(3);
+        let compiled = pay.compile(&ctx()).unwrap();
+        let script = pay.witness_script(&ctx()).unwrap();
+        let address: Script = compiled.address.clone().into();
+        assert_eq!(address, script.to_v0_p2wsh());
+        assert!(compiled.policy.is_none());
+        assert_eq!(compiled.ctv_to_tx.len(), 2);
+        let sequences: Vec<u32> = compiled
+            .ctv_to_tx
+            .values()
+            .map(|t| t.tx.input[0].sequence)
+            .collect();
+        assert!(sequences.contains(&144));
+
+        let certain = ProbabilisticPayment {
+            threshold: 4,
+            ..payment(3)
+        };
+        assert!(certain.compile(&ctx()).is_err());
+        let taproot = ctx().with_target(CompileTarget::Taproot);
+        assert!(pay.compile(&taproot).is_err());
+    }
+}
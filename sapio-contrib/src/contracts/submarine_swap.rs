@@ -0,0 +1,263 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Submarine swaps, exchanging on-chain funds for a Lightning payment (loop
+//! in) or the other way around (loop out).
+//!
+//! Both are an HTLC (see `htlc::Tlc`) locking the on-chain funds to the
+//! payment hash of a Lightning invoice, so the Lightning payment settling
+//! reveals the preimage which claims them, and vice versa:
+//!
+//! - loop in: the user funds the swap, and the server pays the user's
+//!   invoice, learning the preimage, with which it claims the funds.
+//! - loop out: the server funds the swap, and the user pays the server's
+//!   (hold) invoice, whose preimage the user picked, then claims the funds,
+//!   revealing the preimage for the server to settle the invoice with.
+//!
+//! If the receiver never claims, the funder is refunded after the timeout,
+//! which must be well after the invoice's expiry (plus its final CLTV delta)
+//! so the funder can't be refunded after the Lightning payment settles.
+//!
+//! Before paying or funding, the counterparty checks the swap against the
+//! invoice with `SubmarineSwap::binding`. Once funded, `SubmarineSwap::psbts`
+//! produces the claim and refund transactions, for the receiver to add their
+//! signature and the preimage to, or the funder their signature.
+use super::htlc::Tlc;
+use bitcoin::hashes::sha256;
+use bitcoin::util::amount::Amount;
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use sapio::contract::*;
+use sapio::*;
+use sapio_base::timelocks::{AnyAbsTimeLock, AnyTimeLock};
+use sapio_base::txindex::TxIndexLogger;
+use sapio_ctv_emulator_trait::CTVEmulator;
+use schemars::*;
+use serde::*;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Which way a `SubmarineSwap` goes, see the module docs
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    /// on-chain funds from the user for a Lightning payment to them
+    LoopIn,
+    /// a Lightning payment from the user for on-chain funds to them
+    LoopOut,
+}
+
+/// A submarine swap between a user and a swap server, see the module docs
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct SubmarineSwap {
+    /// which way the swap goes
+    pub direction: Direction,
+    /// the user's key
+    pub user: bitcoin::PublicKey,
+    /// where the user is paid, claiming or refunded
+    pub user_address: bitcoin::Address,
+    /// the server's key
+    pub server: bitcoin::PublicKey,
+    /// where the server is paid, claiming or refunded
+    pub server_address: bitcoin::Address,
+    /// the invoice's payment hash
+    #[schemars(with = "String")]
+    pub payment_hash: sha256::Hash,
+    /// when the funder may be refunded
+    pub timeout: AnyAbsTimeLock,
+    /// the fee paid by the claim or refund transaction
+    #[schemars(with = "i64")]
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub fee: Amount,
+}
+
+/// What a counterparty checks a `SubmarineSwap` against an invoice with
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SwapBinding {
+    /// must be the invoice's payment hash
+    #[schemars(with = "String")]
+    pub payment_hash: sha256::Hash,
+    /// the address the swap is funded at
+    pub address: bitcoin::Address,
+    /// the amount the swap is funded with
+    #[schemars(with = "i64")]
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub amount: Amount,
+    /// must be after the invoice can no longer be paid
+    pub timeout: AnyAbsTimeLock,
+}
+
+/// The transactions spending a funded `SubmarineSwap`, unsigned
+pub struct SwapPsbts {
+    /// pays the receiver, with their signature and the preimage
+    pub claim: PartiallySignedTransaction,
+    /// refunds the funder, with their signature after the timeout
+    pub refund: PartiallySignedTransaction,
+}
+
+impl SubmarineSwap {
+    /// the HTLC the swap locks its funds in
+    pub fn htlc(&self) -> Tlc {
+        let (receiver, funder) = match self.direction {
+            Direction::LoopIn => (self.server, self.user),
+            Direction::LoopOut => (self.user, self.server),
+        };
+        Tlc::htlc(
+            receiver,
+            funder,
+            self.payment_hash,
+            AnyTimeLock::A(self.timeout),
+        )
+    }
+
+    /// where the receiver, and the funder, are paid
+    fn addresses(&self) -> (&bitcoin::Address, &bitcoin::Address) {
+        match self.direction {
+            Direction::LoopIn => (&self.server_address, &self.user_address),
+            Direction::LoopOut => (&self.user_address, &self.server_address),
+        }
+    }
+
+    /// the swap's terms, for checking against the invoice, when funded with
+    /// the funds of `ctx`
+    pub fn binding(&self, ctx: &Context) -> Result<SwapBinding, CompilationError> {
+        let compiled = self.compile(ctx)?;
+        let script: bitcoin::Script = compiled.address.into();
+        Ok(SwapBinding {
+            payment_hash: self.payment_hash,
+            address: bitcoin::Address::from_script(&script, ctx.network)
+                .ok_or(CompilationError::TerminateCompilation)?,
+            amount: ctx.funds(),
+            timeout: self.timeout,
+        })
+    }
+
+    /// the claim and refund transactions of the swap funded at `out` with
+    /// the funds of `ctx`
+    pub fn psbts(
+        &self,
+        ctx: &Context,
+        out: bitcoin::OutPoint,
+        emulator: &dyn CTVEmulator,
+    ) -> Result<SwapPsbts, CompilationError> {
+        let compiled = self.compile(ctx)?;
+        let (psbts, metadata) =
+            compiled.bind_psbt(out, HashMap::new(), Rc::new(TxIndexLogger::new()), emulator)?;
+        let (mut claim, mut refund) = (None, None);
+        for (psbt, meta) in psbts.into_iter().zip(metadata.iter()) {
+            match meta["spend_path"].as_str() {
+                Some("claim") => claim = Some(psbt),
+                Some("refund") => refund = Some(psbt),
+                _ => {}
+            }
+        }
+        let mut psbts = match (claim, refund) {
+            (Some(claim), Some(refund)) => SwapPsbts { claim, refund },
+            _ => return Err(CompilationError::TerminateCompilation),
+        };
+        let utxo = bitcoin::TxOut {
+            value: ctx.funds().as_sat(),
+            script_pubkey: compiled.address.into(),
+        };
+        psbts.claim.inputs[0].witness_utxo = Some(utxo.clone());
+        psbts.refund.inputs[0].witness_utxo = Some(utxo);
+        Ok(psbts)
+    }
+
+    guard! {fn claimable(self, _ctx) { self.htlc().claim() }}
+    guard! {fn refundable(self, _ctx) { self.htlc().refund() }}
+
+    then! {
+        annotated: {label: "claim", roles: ["receiver"], doc: "pays the receiver, revealing the preimage"}
+        guarded_by: [Self::claimable]
+        fn claim(self, ctx) {
+            ctx.template()
+                .add_fees(self.fee)?
+                .add_output(
+                    ctx.funds() - self.fee,
+                    &Compiled::from_address(self.addresses().0.clone(), None),
+                    None,
+                )?
+                .set_label("claim".into())
+                .into()
+        }
+    }
+    then! {
+        annotated: {label: "refund", roles: ["funder"], doc: "refunds the funder after the timeout"}
+        guarded_by: [Self::refundable]
+        fn refund(self, ctx) {
+            ctx.template()
+                .add_fees(self.fee)?
+                .add_output(
+                    ctx.funds() - self.fee,
+                    &Compiled::from_address(self.addresses().1.clone(), None),
+                    None,
+                )?
+                .set_lock_time(self.timeout)?
+                .set_label("refund".into())
+                .into()
+        }
+    }
+}
+
+impl Contract for SubmarineSwap {
+    declare! {then, Self::claim, Self::refund}
+    declare! {non updatable}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::key;
+    use bitcoin::hashes::Hash;
+    use sapio_base::timelocks::AbsHeight;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+
+    #[test]
+    fn submarine_swap_psbts() {
+        let ctx = Context::new(
+            bitcoin::Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+        );
+        for direction in [Direction::LoopIn, Direction::LoopOut].iter() {
+            let swap = SubmarineSwap {
+                direction: *direction,
+                user: key(1),
+                user_address: bitcoin::Address::p2wpkh(&key(1), bitcoin::Network::Regtest).unwrap(),
+                server: key(2),
+                server_address: bitcoin::Address::p2wpkh(&key(2), bitcoin::Network::Regtest)
+                    .unwrap(),
+                payment_hash: sha256::Hash::hash(&[7; 32]),
+                timeout: AbsHeight::try_from(1000u32).unwrap().into(),
+                fee: Amount::from_sat(500),
+            };
+            let binding = swap.binding(&ctx).unwrap();
+            assert_eq!(binding.payment_hash, swap.payment_hash);
+            let psbts = swap
+                .psbts(&ctx, bitcoin::OutPoint::default(), &CTVAvailable)
+                .unwrap();
+            let (claim, refund) = (
+                psbts.claim.global.unsigned_tx,
+                psbts.refund.global.unsigned_tx,
+            );
+            assert_eq!(claim.lock_time, 0);
+            assert_eq!(refund.lock_time, 1000);
+            let (receiver, funder) = swap.addresses();
+            assert_eq!(claim.output[0].script_pubkey, receiver.script_pubkey());
+            assert_eq!(refund.output[0].script_pubkey, funder.script_pubkey());
+            assert_eq!(claim.output[0].value, 99_500);
+            assert_eq!(
+                psbts.claim.inputs[0]
+                    .witness_utxo
+                    .as_ref()
+                    .unwrap()
+                    .script_pubkey,
+                binding.address.script_pubkey()
+            );
+        }
+    }
+}
@@ -0,0 +1,217 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! subscription has a contract, `Subscription`, pre-committing a payer's
+//! funds to a stream of periodic payments to a payee.
+//!
+//! Each period is a stage of the contract: once the period's payment is due
+//! (an absolute lock time, see `Schedule`), anyone may broadcast the template
+//! paying it, which moves the rest of the funds to the next period. The payer
+//! may cancel the periods not yet paid, refunding themselves, but only once
+//! the stage has been unspent for the notice period, giving the payee time
+//! to collect any payment already due.
+use bitcoin::util::amount::Amount;
+use sapio::contract::actions::ConditionalCompileType;
+use sapio::contract::*;
+use sapio::*;
+use sapio_base::timelocks::{AbsHeight, AnyRelTimeLock};
+use sapio_base::Clause;
+use schemars::*;
+use serde::*;
+use std::collections::LinkedList;
+use std::convert::TryFrom;
+
+/// When, and how much, a `Subscription` pays
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Schedule {
+    /// the height the first payment is due at
+    pub start: u32,
+    /// the blocks between payments
+    pub interval: u32,
+    /// how many payments there are
+    pub count: u32,
+    /// the amount of each payment
+    #[schemars(with = "i64")]
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub amount: Amount,
+}
+
+impl Schedule {
+    /// a schedule of `count` payments of `amount`, every `interval` blocks
+    /// from `start`
+    pub fn new(start: u32, interval: u32, count: u32, amount: Amount) -> Self {
+        Schedule {
+            start,
+            interval,
+            count,
+            amount,
+        }
+    }
+
+    /// the height payment `i` is due at
+    pub fn due(&self, i: u32) -> u32 {
+        self.start.saturating_add(self.interval.saturating_mul(i))
+    }
+
+    /// the height each payment is due at, and its amount
+    pub fn payments(&self) -> impl Iterator<Item = (u32, Amount)> + '_ {
+        (0..self.count).map(move |i| (self.due(i), self.amount))
+    }
+
+    /// what paying the payments from `i` on costs, with `fee` per payment
+    pub fn cost_from(&self, i: u32, fee: Amount) -> Amount {
+        (self.amount + fee) * u64::from(self.count.saturating_sub(i))
+    }
+}
+
+/// A subscription, see the module docs
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct Subscription {
+    /// the payer's key, cancelling
+    pub payer: bitcoin::PublicKey,
+    /// where the payer is refunded
+    pub payer_address: bitcoin::Address,
+    /// where the payments go
+    pub payee_address: bitcoin::Address,
+    /// the payments
+    pub schedule: Schedule,
+    /// how long a period must be unspent before the payer may cancel
+    pub notice: AnyRelTimeLock,
+    /// the fee paid by each payment, or cancellation
+    #[schemars(with = "i64")]
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub fee: Amount,
+    /// the payment this stage pays, those before it having been paid
+    pub period: u32,
+}
+
+impl Subscription {
+    compile_if! {
+        /// the funds must cover the payments left and their fees
+        fn funded(self, ctx) {
+            let mut errors = LinkedList::new();
+            if self.schedule.count == 0 || self.schedule.interval == 0 {
+                errors.push_back("Schedule must have at least one payment, at least a block apart".into());
+            }
+            if ctx.funds() < self.schedule.cost_from(self.period, self.fee) {
+                errors.push_back("Funds must cover the payments left and their fees".into());
+            }
+            if errors.is_empty() {
+                ConditionalCompileType::NoConstraint
+            } else {
+                ConditionalCompileType::Fail(errors)
+            }
+        }
+    }
+    guard! {fn payer_cancels(self, _ctx) {
+        Clause::And(vec![Clause::Key(self.payer), self.notice.into()])
+    }}
+
+    then! {
+        annotated: {label: "pay", doc: "pays the period's payment once due, funding the next period with the rest"}
+        compile_if: [Self::funded]
+        fn pay(self, ctx) {
+            let builder = ctx
+                .template()
+                .add_fees(self.fee)?
+                .add_output(
+                    self.schedule.amount,
+                    &Compiled::from_address(self.payee_address.clone(), None),
+                    None,
+                )?;
+            let rest = builder.ctx().funds();
+            let next = Subscription {
+                period: self.period + 1,
+                ..self.clone()
+            };
+            let builder = if next.period < self.schedule.count {
+                builder.add_output(rest, &next, None)?
+            } else if rest > Amount::from_sat(0) {
+                builder.add_output(rest, &Compiled::from_address(self.payer_address.clone(), None), None)?
+            } else {
+                builder
+            };
+            builder
+                .set_lock_time(AbsHeight::try_from(self.schedule.due(self.period))?.into())?
+                .set_label(format!("payment {}", self.period))
+                .into()
+        }
+    }
+    then! {
+        annotated: {label: "cancel", roles: ["payer"], doc: "refunds the payer the periods not yet paid, after the notice period"}
+        compile_if: [Self::funded]
+        guarded_by: [Self::payer_cancels]
+        fn cancel(self, ctx) {
+            let builder = ctx.template().add_fees(self.fee)?;
+            let amount = builder.ctx().funds();
+            builder
+                .add_output(amount, &Compiled::from_address(self.payer_address.clone(), None), None)?
+                .set_sequence(0, self.notice)?
+                .set_label(format!("cancel from payment {}", self.period))
+                .into()
+        }
+    }
+}
+
+impl Contract for Subscription {
+    declare! {then, Self::pay, Self::cancel}
+    declare! {non updatable}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::key;
+    use sapio::simulation::{ChainState, Simulator};
+    use sapio_base::timelocks::RelHeight;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::sync::Arc;
+
+    #[test]
+    fn subscription() {
+        let schedule = Schedule::new(1000, 4320, 6, Amount::from_sat(50_000));
+        assert_eq!(
+            schedule.payments().map(|(h, _)| h).collect::<Vec<_>>(),
+            vec![1000, 5320, 9640, 13960, 18280, 22600]
+        );
+        let fee = Amount::from_sat(1000);
+        let sub = Subscription {
+            payer: key(1),
+            payer_address: bitcoin::Address::p2wpkh(&key(1), bitcoin::Network::Regtest).unwrap(),
+            payee_address: bitcoin::Address::p2wpkh(&key(2), bitcoin::Network::Regtest).unwrap(),
+            schedule,
+            notice: RelHeight::from(144).into(),
+            fee,
+            period: 0,
+        };
+        let funds = schedule.cost_from(0, fee);
+        let ctx = Context::new(bitcoin::Network::Regtest, funds, Arc::new(CTVAvailable));
+        let compiled = sub.compile(&ctx).unwrap();
+        let report = Simulator::new(ChainState {
+            height: 0,
+            median_time_past: 0,
+        })
+        .run(&compiled, funds);
+        assert_eq!(report.dead_branches().count(), 0);
+        // cancelling before each payment, or paying them all
+        assert_eq!(report.outcomes.len(), 7);
+        let payee = sub.payee_address.script_pubkey();
+        let most = report
+            .outcomes
+            .iter()
+            .filter_map(|o| o.balances.get(&payee))
+            .max()
+            .cloned();
+        assert_eq!(most, Some(Amount::from_sat(300_000)));
+
+        let short = Context::new(
+            bitcoin::Network::Regtest,
+            funds - fee,
+            Arc::new(CTVAvailable),
+        );
+        assert!(sub.compile(&short).is_err());
+    }
+}
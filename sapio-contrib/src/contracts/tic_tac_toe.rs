@@ -110,7 +110,7 @@ impl TicTacToe {
                                                         &TicTacToe { board:bcopy,
                                                                     whose_turn: self.whose_turn.next(),
                                                                     ..self.clone()},
-                                                        None)?.into();
+                                                        None)?.finish()?;
                                 v.push(tmpl);
                             }
                         }
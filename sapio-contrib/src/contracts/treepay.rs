@@ -5,10 +5,14 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 //! contracts for paying a large set of recipients fee efficiently
+use bitcoin::util::amount::Amount;
 use sapio::contract::*;
+use sapio::template::fees::ANCHOR_SATS;
+use sapio::template::{FeeStrategy, Template};
 use sapio::*;
 use schemars::*;
 use serde::*;
+use std::collections::VecDeque;
 use std::convert::TryInto;
 /// instructions to send an amount of coin to an address
 #[derive(JsonSchema, Serialize, Deserialize, Clone)]
@@ -33,13 +37,21 @@ impl TreePay {
         let mut builder = ctx.template();
         if self.participants.len() > self.radix {
 
+            let mut subtrees = vec![];
             for c in self.participants.chunks(self.participants.len()/self.radix) {
                 let mut amt =  bitcoin::util::amount::Amount::from_sat(0);
                 for Payment{amount, ..}  in c {
                     amt += amount.clone().try_into()?;
                 }
-                builder = builder.add_output(amt, &TreePay {participants: c.to_vec(), radix: self.radix}, None)?;
+                subtrees.push((amt, TreePay {participants: c.to_vec(), radix: self.radix}));
             }
+            // the subtrees are independent, so may be compiled in parallel
+            builder = builder.add_outputs(
+                subtrees
+                    .iter()
+                    .map(|(amt, t)| (*amt, t as &(dyn Compilable + Sync), None))
+                    .collect(),
+            )?;
         } else {
             for Payment{amount, address} in self.participants.iter() {
                 builder = builder.add_output((*amount).try_into()?, &Compiled::from_address(address.clone(), None), None)?;
@@ -53,3 +65,170 @@ impl Contract for TreePay {
     declare! {then, Self::expand}
     declare! {non updatable}
 }
+
+/// A congestion control tree: commits to paying every participant with a
+/// tree of CTV expansions, each spending to at most `radix` subtrees or
+/// payments. The expansions pay no fees, each having a CPFP anchor output
+/// to `anchor` instead, so they can be broadcast gradually (in the order of
+/// `expansions`) once fees are low, with a child of the anchor paying for
+/// each.
+///
+/// A smaller radix makes each expansion smaller, so cheaper to confirm on
+/// its own, but the tree deeper, see `radix_for_depth`.
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct CongestionTree {
+    /// the list of payments to create
+    pub participants: Vec<Payment>,
+    /// the most outputs of an expansion, other than its anchor
+    pub radix: usize,
+    /// where each expansion's anchor output pays
+    pub anchor: bitcoin::Address,
+}
+
+impl CongestionTree {
+    /// the participants of each of the expansion's outputs
+    fn subtrees(&self) -> std::slice::Chunks<Payment> {
+        let size = (self.participants.len() + self.radix - 1) / self.radix;
+        self.participants.chunks(size.max(1))
+    }
+
+    /// the number of expansions of a tree paying `n` participants
+    pub fn transactions(n: usize, radix: usize) -> usize {
+        if n <= radix {
+            1
+        } else {
+            let size = (n + radix - 1) / radix;
+            1 + (0..n)
+                .step_by(size)
+                .map(|i| Self::transactions(size.min(n - i), radix))
+                .sum::<usize>()
+        }
+    }
+
+    /// the smallest radix (at least 2) paying `n` participants with at most
+    /// `depth` expansions on the way to any payment
+    pub fn radix_for_depth(n: usize, depth: u32) -> usize {
+        (2..n.max(2))
+            .find(|r| r.checked_pow(depth).map_or(true, |leaves| leaves >= n))
+            .unwrap_or_else(|| n.max(2))
+    }
+
+    /// the amount a subtree paying `participants` requires, including its
+    /// anchors
+    fn required(&self, participants: &[Payment]) -> Result<Amount, CompilationError> {
+        let mut amount = Amount::from_sat(ANCHOR_SATS)
+            .checked_mul(Self::transactions(participants.len(), self.radix) as u64)
+            .ok_or(CompilationError::OutOfFunds)?;
+        for Payment { amount: a, .. } in participants {
+            amount = amount
+                .checked_add((*a).try_into()?)
+                .ok_or(CompilationError::OutOfFunds)?;
+        }
+        Ok(amount)
+    }
+
+    then! {fn expand(self, ctx) {
+        if self.radix < 2 || self.participants.is_empty() {
+            return Err(CompilationError::TerminateCompilation);
+        }
+        let mut builder = ctx
+            .template()
+            .set_fee_strategy(FeeStrategy::Anchor)
+            .add_anchor(&Compiled::from_address(self.anchor.clone(), None), None)?;
+        if self.participants.len() > self.radix {
+            let subtrees = self
+                .subtrees()
+                .map(|c| {
+                    let tree = CongestionTree {
+                        participants: c.to_vec(),
+                        radix: self.radix,
+                        anchor: self.anchor.clone(),
+                    };
+                    Ok((self.required(c)?, tree))
+                })
+                .collect::<Result<Vec<_>, CompilationError>>()?;
+            builder = builder.add_outputs(
+                subtrees
+                    .iter()
+                    .map(|(amt, t)| (*amt, t as &(dyn Compilable + Sync), None))
+                    .collect(),
+            )?;
+        } else {
+            for Payment { amount, address } in self.participants.iter() {
+                builder = builder.add_output(
+                    (*amount).try_into()?,
+                    &Compiled::from_address(address.clone(), None),
+                    None,
+                )?;
+            }
+        }
+        builder.into()
+    }}
+}
+
+impl Contract for CongestionTree {
+    declare! {then, Self::expand}
+    declare! {non updatable}
+}
+
+/// The templates of `object`'s tree, parents before children, level by
+/// level: the order to broadcast the expansions of a `CongestionTree` in.
+pub fn expansions(object: &Compiled) -> Vec<&Template> {
+    let mut order = vec![];
+    let mut queue = VecDeque::new();
+    queue.push_back(object);
+    while let Some(object) = queue.pop_front() {
+        for template in object.ctv_to_tx.values() {
+            order.push(template);
+            queue.extend(template.outputs.iter().map(|o| &o.contract));
+        }
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    #[test]
+    fn congestion_tree() {
+        let address = bitcoin::Address::p2wsh(&bitcoin::Script::new(), bitcoin::Network::Regtest);
+        let tree = CongestionTree {
+            participants: (0..10)
+                .map(|_| Payment {
+                    amount: Amount::from_sat(10_000).into(),
+                    address: address.clone(),
+                })
+                .collect(),
+            radix: 3,
+            anchor: address,
+        };
+        assert_eq!(CongestionTree::transactions(10, 3), 8);
+        assert_eq!(CongestionTree::radix_for_depth(10, 2), 4);
+        let required = tree.required(&tree.participants).unwrap();
+        assert_eq!(required, Amount::from_sat(100_000 + 8 * ANCHOR_SATS));
+        let compiled = tree
+            .compile(&Context::new(
+                bitcoin::Network::Regtest,
+                required,
+                Arc::new(CTVAvailable),
+            ))
+            .unwrap();
+        let order = expansions(&compiled);
+        assert_eq!(order.len(), 8);
+        // every expansion has an anchor, and comes after the one creating it
+        let index: HashMap<_, _> = order.iter().enumerate().map(|(i, t)| (t.ctv, i)).collect();
+        for (i, template) in order.iter().enumerate() {
+            assert_eq!(template.outputs[0].amount, Amount::from_sat(ANCHOR_SATS));
+            assert!(template.outputs.len() <= 4);
+            for output in template.outputs.iter() {
+                for child in output.contract.ctv_to_tx.keys() {
+                    assert!(index[child] > i);
+                }
+            }
+        }
+    }
+}
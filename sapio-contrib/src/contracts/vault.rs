@@ -6,10 +6,11 @@
 
 //! Contract for managing movement of funds from cold to hot storage
 use super::undo_send::UndoSendInternal;
-use bitcoin::util::amount::CoinAmount;
+use bitcoin::util::amount::{Amount, CoinAmount};
 use sapio::contract::*;
 use sapio::*;
 use sapio_base::timelocks::AnyRelTimeLock;
+use sapio_base::Clause;
 use schemars::*;
 use serde::*;
 use std::convert::{TryFrom, TryInto};
@@ -149,3 +150,292 @@ impl TryFrom<VaultTree> for Vault {
         })
     }
 }
+
+/// A vault guarded by a hot key and a clawback key. Funds sent to its
+/// address are withdrawn through an unvault step: the hot key moves up to
+/// `max_withdrawal` into an `Unvaulting` contract, and the rest (less fees)
+/// back into the vault. The withdrawal is spendable by the hot key after
+/// `delay`, until which the clawback key can sweep it to `deep_cold`, as it
+/// can the vault at any time.
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct ClawbackVault {
+    /// the key unvaulting funds, and spending them after `delay`
+    pub hot_key: bitcoin::PublicKey,
+    /// the key sweeping funds to `deep_cold`
+    pub clawback_key: bitcoin::PublicKey,
+    /// where clawed back funds are swept to
+    pub deep_cold: bitcoin::Address,
+    /// the most withdrawn by each unvault
+    pub max_withdrawal: CoinAmount,
+    /// the fee paid by each transaction of the vault
+    pub fee: CoinAmount,
+    /// how long withdrawals can be clawed back for
+    pub delay: AnyRelTimeLock,
+}
+
+impl ClawbackVault {
+    guard! {fn hot(self, _ctx) { Clause::Key(self.hot_key) }}
+    guard! {fn clawback_key(self, _ctx) { Clause::Key(self.clawback_key) }}
+    then! {
+        annotated: {label: "unvault", roles: ["hot"], doc: "starts withdrawing funds, leaving the rest in the vault"}
+        guarded_by: [Self::hot]
+        fn unvault(self, ctx) {
+            let fee: Amount = self.fee.try_into()?;
+            let max: Amount = self.max_withdrawal.try_into()?;
+            if max == Amount::from_sat(0) {
+                return Err(CompilationError::TerminateCompilation);
+            }
+            let available = ctx.funds().checked_sub(fee).ok_or(CompilationError::OutOfFunds)?;
+            // a remainder too small to pay for its own unvault is withdrawn
+            // along with the rest
+            let withdrawal = if available > max + fee { max } else { available };
+            let remainder = available - withdrawal;
+            let unvaulting = Unvaulting {
+                hot_key: self.hot_key,
+                clawback_key: self.clawback_key,
+                deep_cold: self.deep_cold.clone(),
+                fee: self.fee,
+                delay: self.delay,
+            };
+            let builder = ctx
+                .template()
+                .add_fees(fee)?
+                .add_output(withdrawal, &unvaulting, None)?;
+            if remainder > Amount::from_sat(0) {
+                builder.add_output(remainder, self, None)?
+            } else {
+                builder
+            }
+            .set_label("unvault".into())
+            .into()
+        }
+    }
+    then! {
+        annotated: {label: "clawback", roles: ["clawback"], doc: "sweeps the vault to deep cold storage"}
+        guarded_by: [Self::clawback_key]
+        fn clawback(self, ctx) {
+            sweep(ctx, &self.deep_cold, self.fee)
+        }
+    }
+}
+
+impl Contract for ClawbackVault {
+    declare! {then, Self::unvault, Self::clawback}
+    declare! {non updatable}
+}
+
+/// A withdrawal from a `ClawbackVault`, spendable by the hot key after the
+/// vault's delay, and swept to deep cold storage by the clawback key before.
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct Unvaulting {
+    hot_key: bitcoin::PublicKey,
+    clawback_key: bitcoin::PublicKey,
+    deep_cold: bitcoin::Address,
+    fee: CoinAmount,
+    delay: AnyRelTimeLock,
+}
+
+impl Unvaulting {
+    guard! {fn matured(self, _ctx) { Clause::And(vec![Clause::Key(self.hot_key), self.delay.into()]) }}
+    guard! {fn clawback_key(self, _ctx) { Clause::Key(self.clawback_key) }}
+    then! {
+        annotated: {label: "clawback", roles: ["clawback"], doc: "sweeps the withdrawal to deep cold storage"}
+        guarded_by: [Self::clawback_key]
+        fn clawback(self, ctx) {
+            sweep(ctx, &self.deep_cold, self.fee)
+        }
+    }
+}
+
+impl Contract for Unvaulting {
+    declare! {then, Self::clawback}
+    declare! {finish, Self::matured}
+    declare! {non updatable}
+}
+
+/// a template sending all of the funds, less `fee`, to `to`
+fn sweep(ctx: &Context, to: &bitcoin::Address, fee: CoinAmount) -> TxTmplIt {
+    let fee: Amount = fee.try_into()?;
+    let amount = ctx
+        .funds()
+        .checked_sub(fee)
+        .ok_or(CompilationError::OutOfFunds)?;
+    ctx.template()
+        .add_fees(fee)?
+        .add_output(amount, &Compiled::from_address(to.clone(), None), None)?
+        .set_label("clawback".into())
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::key;
+    use sapio::template::Template;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::sync::Arc;
+
+    fn labeled<'a>(object: &'a Compiled, label: &str) -> &'a Template {
+        object
+            .ctv_to_tx
+            .values()
+            .find(|t| t.metadata_map_s2s.label() == Some(label))
+            .expect("a template with the label")
+    }
+
+    fn script(object: &Compiled) -> bitcoin::Script {
+        object.address.clone().into()
+    }
+
+    /// whether `clause` is a branch of `policy`
+    fn contains(policy: &Clause, clause: &Clause) -> bool {
+        policy == clause
+            || match policy {
+                Clause::And(subs) | Clause::Threshold(_, subs) => {
+                    subs.iter().any(|s| contains(s, clause))
+                }
+                Clause::Or(subs) => subs.iter().any(|(_, s)| contains(s, clause)),
+                _ => false,
+            }
+    }
+
+    fn ctx(amount: Amount) -> Context {
+        Context::new(bitcoin::Network::Regtest, amount, Arc::new(CTVAvailable))
+    }
+
+    fn vault() -> ClawbackVault {
+        ClawbackVault {
+            hot_key: key(1),
+            clawback_key: key(2),
+            deep_cold: bitcoin::Address::p2wpkh(&key(3), bitcoin::Network::Regtest).unwrap(),
+            max_withdrawal: Amount::from_btc(0.4).unwrap().into(),
+            fee: Amount::from_sat(1000).into(),
+            delay: sapio_base::timelocks::RelHeight::from(144).into(),
+        }
+    }
+
+    fn unvaulting(vault: &ClawbackVault) -> Unvaulting {
+        Unvaulting {
+            hot_key: vault.hot_key,
+            clawback_key: vault.clawback_key,
+            deep_cold: vault.deep_cold.clone(),
+            fee: vault.fee,
+            delay: vault.delay,
+        }
+    }
+
+    #[test]
+    fn clawback_vault_paths() {
+        let vault = vault();
+        let deep_cold = vault.deep_cold.clone();
+        let fee = Amount::from_sat(1000);
+        let ctx = ctx(Amount::from_btc(1.0).unwrap());
+        let mut object = vault.compile(&ctx).unwrap();
+        let mut funds = Amount::from_btc(1.0).unwrap();
+        let mut withdrawals = 0;
+        loop {
+            // the clawback key can always sweep the vault
+            let clawback = labeled(&object, "clawback");
+            assert_eq!(clawback.outputs.len(), 1);
+            assert_eq!(clawback.outputs[0].amount, funds - fee);
+            assert_eq!(
+                script(&clawback.outputs[0].contract),
+                deep_cold.script_pubkey()
+            );
+
+            let unvault = labeled(&object, "unvault");
+            let withdrawal = &unvault.outputs[0];
+            withdrawals += 1;
+            // the withdrawal can be clawed back until the hot key may spend it
+            let swept = labeled(&withdrawal.contract, "clawback");
+            assert_eq!(swept.outputs[0].amount, withdrawal.amount - fee);
+            assert_eq!(
+                script(&swept.outputs[0].contract),
+                deep_cold.script_pubkey()
+            );
+            assert!(withdrawal.contract.policy.is_some());
+            let spent = unvault
+                .outputs
+                .iter()
+                .fold(Amount::from_sat(0), |a, o| a + o.amount);
+            assert_eq!(spent + fee, funds);
+            match unvault.outputs.get(1) {
+                Some(remainder) => {
+                    assert_eq!(withdrawal.amount, Amount::from_btc(0.4).unwrap());
+                    funds = remainder.amount;
+                    object = remainder.contract.clone();
+                }
+                None => break,
+            }
+        }
+        assert_eq!(withdrawals, 3);
+        assert_eq!(object.spend_paths["unvault"].roles, vec!["hot"]);
+    }
+
+    #[test]
+    fn unvault_path() {
+        let vault = vault();
+        let fee = Amount::from_sat(1000);
+        let object = vault.compile(&ctx(Amount::from_btc(1.0).unwrap())).unwrap();
+        // only the hot key unvaults, and without waiting
+        let policy = object.policy.as_ref().unwrap();
+        assert!(contains(policy, &Clause::Key(key(1))));
+        assert!(!contains(policy, &vault.delay.into()));
+        let unvault = labeled(&object, "unvault");
+        assert_ne!(unvault.tx.input[0].sequence, 144);
+        assert_eq!(unvault.outputs.len(), 2);
+        // the withdrawal goes to an unvaulting, the rest back to the vault
+        let withdrawal = Amount::from_btc(0.4).unwrap();
+        let expected = unvaulting(&vault).compile(&ctx(withdrawal)).unwrap();
+        assert_eq!(unvault.outputs[0].amount, withdrawal);
+        assert_eq!(script(&unvault.outputs[0].contract), script(&expected));
+        let rest = Amount::from_btc(0.6).unwrap() - fee;
+        let expected = vault.compile(&ctx(rest)).unwrap();
+        assert_eq!(unvault.outputs[1].amount, rest);
+        assert_eq!(script(&unvault.outputs[1].contract), script(&expected));
+    }
+
+    #[test]
+    fn clawback_path() {
+        let vault = vault();
+        let fee = Amount::from_sat(1000);
+        let funds = Amount::from_btc(1.0).unwrap();
+        let object = vault.compile(&ctx(funds)).unwrap();
+        // the clawback key sweeps the vault at once, to deep cold
+        assert!(contains(
+            object.policy.as_ref().unwrap(),
+            &Clause::Key(key(2))
+        ));
+        let clawback = labeled(&object, "clawback");
+        assert_ne!(clawback.tx.input[0].sequence, 144);
+        assert_eq!(clawback.outputs.len(), 1);
+        assert_eq!(clawback.outputs[0].amount, funds - fee);
+        assert_eq!(
+            script(&clawback.outputs[0].contract),
+            vault.deep_cold.script_pubkey()
+        );
+        assert_eq!(object.spend_paths["clawback"].roles, vec!["clawback"]);
+    }
+
+    #[test]
+    fn sweep_path() {
+        let vault = vault();
+        let fee = Amount::from_sat(1000);
+        let funds = Amount::from_btc(0.4).unwrap();
+        let object = unvaulting(&vault).compile(&ctx(funds)).unwrap();
+        // the hot key sweeps the withdrawal only after the delay
+        let policy = object.policy.as_ref().unwrap();
+        let matured = Clause::And(vec![Clause::Key(key(1)), Clause::Older(144)]);
+        assert!(contains(policy, &matured));
+        // until which the clawback key can sweep it to deep cold, at once
+        let clawback = labeled(&object, "clawback");
+        assert_ne!(clawback.tx.input[0].sequence, 144);
+        assert_eq!(clawback.outputs.len(), 1);
+        assert_eq!(clawback.outputs[0].amount, funds - fee);
+        assert_eq!(
+            script(&clawback.outputs[0].contract),
+            vault.deep_cold.script_pubkey()
+        );
+        assert_eq!(object.ctv_to_tx.len(), 1);
+    }
+}
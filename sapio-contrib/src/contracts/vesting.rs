@@ -0,0 +1,367 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! vesting has a contract, `Vesting`, paying a beneficiary a grant which
+//! vests linearly, period by period, after a cliff.
+//!
+//! Each claim (see `VestingSchedule::claims`) is a stage of the contract:
+//! once the claim's height is reached, anyone may broadcast the template
+//! paying it, which moves the rest of the grant to the next claim. Nothing
+//! is claimable before the cliff, where the periods vested so far are
+//! claimed at once. A beneficiary who hasn't claimed for a while broadcasts
+//! each claim reached in turn (see `Vesting::claimable`).
+//!
+//! The grantor may terminate the grant, moving what is left to a
+//! `Termination`. There, the beneficiary may claim what had vested by any
+//! claim's height reached, the grantor getting the rest, until the grantor
+//! reclaims all of it after the notice period.
+use bitcoin::util::amount::Amount;
+use sapio::contract::actions::ConditionalCompileType;
+use sapio::contract::*;
+use sapio::*;
+use sapio_base::timelocks::{AbsHeight, AnyRelTimeLock};
+use sapio_base::Clause;
+use schemars::*;
+use serde::*;
+use std::collections::LinkedList;
+use std::convert::TryFrom;
+
+/// How a `Vesting` grant vests
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct VestingSchedule {
+    /// the height vesting starts at
+    pub start: u32,
+    /// the blocks after `start` before anything may be claimed
+    pub cliff: u32,
+    /// the blocks each period lasts
+    pub period: u32,
+    /// how many periods the grant vests over
+    pub periods: u32,
+    /// the grant
+    #[schemars(with = "i64")]
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub total: Amount,
+}
+
+/// A claim of a `Vesting` grant
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Claim {
+    /// the height the claim may be made at
+    pub height: u32,
+    /// what the claim pays
+    pub amount: Amount,
+}
+
+impl VestingSchedule {
+    /// what has vested at `height`, none of it before the cliff
+    pub fn vested(&self, height: u32) -> Amount {
+        if height < self.start.saturating_add(self.cliff) || self.period == 0 {
+            return Amount::from_sat(0);
+        }
+        let periods = std::cmp::min((height - self.start) / self.period, self.periods);
+        Amount::from_sat(
+            (u128::from(self.total.as_sat()) * u128::from(periods)
+                / u128::from(self.periods.max(1))) as u64,
+        )
+    }
+
+    /// The claims, in order: one for the periods vested at the cliff, and
+    /// one per period after it.
+    pub fn claims(&self) -> Vec<Claim> {
+        let cliff = self.start.saturating_add(self.cliff);
+        let mut claims = vec![];
+        let mut claimed = Amount::from_sat(0);
+        for i in 1..=self.periods {
+            let height = std::cmp::max(
+                self.start.saturating_add(self.period.saturating_mul(i)),
+                cliff,
+            );
+            let vested = self.vested(height);
+            if vested > claimed {
+                claims.push(Claim {
+                    height,
+                    amount: vested - claimed,
+                });
+                claimed = vested;
+            }
+        }
+        claims
+    }
+}
+
+/// A vesting grant, see the module docs
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct Vesting {
+    /// the beneficiary's key, claiming after a termination
+    pub beneficiary: bitcoin::PublicKey,
+    /// where the beneficiary is paid
+    pub beneficiary_address: bitcoin::Address,
+    /// the grantor's key, terminating
+    pub grantor: bitcoin::PublicKey,
+    /// where the grantor is paid
+    pub grantor_address: bitcoin::Address,
+    /// how the grant vests
+    pub schedule: VestingSchedule,
+    /// how long after a termination the beneficiary may still claim
+    pub notice: AnyRelTimeLock,
+    /// the fee paid by each transaction
+    #[schemars(with = "i64")]
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub fee: Amount,
+    /// the claims already made
+    pub claimed: usize,
+}
+
+impl Vesting {
+    /// The funds the grant needs: the grant, and a fee for each transaction
+    /// on the longest path (every claim but the last, a termination and a
+    /// settlement).
+    pub fn required(&self) -> Amount {
+        let claims = self.schedule.claims().len() as u64;
+        self.schedule.total + self.fee * (claims + 1)
+    }
+
+    /// the claims the beneficiary may make at `height`, in order
+    pub fn claimable(&self, height: u32) -> Vec<Claim> {
+        self.schedule
+            .claims()
+            .into_iter()
+            .skip(self.claimed)
+            .take_while(|c| c.height <= height)
+            .collect()
+    }
+
+    compile_if! {
+        /// the grant must vest, and the funds cover the claims left and
+        /// their fees
+        fn funded(self, ctx) {
+            let mut errors = LinkedList::new();
+            if self.schedule.periods == 0 || self.schedule.period == 0 {
+                errors.push_back("Schedule must have at least one period, of at least one block".into());
+            }
+            let claims = self.schedule.claims();
+            let left = claims
+                .iter()
+                .skip(self.claimed)
+                .fold(Amount::from_sat(0), |a, c| a + c.amount);
+            let fees = self.fee * (claims.len().saturating_sub(self.claimed) as u64 + 1);
+            if ctx.funds() < left + fees {
+                errors.push_back("Funds must cover the claims left and their fees".into());
+            }
+            if errors.is_empty() {
+                ConditionalCompileType::NoConstraint
+            } else {
+                ConditionalCompileType::Fail(errors)
+            }
+        }
+    }
+    compile_if! {
+        /// claims are made one at a time while there are claims left
+        fn claims_left(self, _ctx) {
+            if self.claimed < self.schedule.claims().len() {
+                ConditionalCompileType::Required
+            } else {
+                ConditionalCompileType::Never
+            }
+        }
+    }
+    guard! {fn grantor_terminates(self, _ctx) { Clause::Key(self.grantor) }}
+
+    then! {
+        annotated: {label: "claim", roles: ["beneficiary"], doc: "pays the next claim once its height is reached, moving the rest to the claim after it"}
+        compile_if: [Self::funded, Self::claims_left]
+        fn claim(self, ctx) {
+            let claims = self.schedule.claims();
+            let claim = claims[self.claimed];
+            let beneficiary = Compiled::from_address(self.beneficiary_address.clone(), None);
+            let builder = ctx.template().add_fees(self.fee)?;
+            let builder = if self.claimed + 1 < claims.len() {
+                let rest = builder.ctx().funds() - claim.amount;
+                let next = Vesting {
+                    claimed: self.claimed + 1,
+                    ..self.clone()
+                };
+                builder
+                    .add_output(claim.amount, &beneficiary, None)?
+                    .add_output(rest, &next, None)?
+            } else {
+                // the last claim, with the fees saved for a termination
+                let amount = builder.ctx().funds();
+                builder.add_output(amount, &beneficiary, None)?
+            };
+            builder
+                .set_lock_time(AbsHeight::try_from(claim.height)?.into())?
+                .set_label(format!("claim {}", self.claimed))
+                .into()
+        }
+    }
+    then! {
+        annotated: {label: "terminate", roles: ["grantor"], doc: "ends vesting, leaving the beneficiary the notice period to claim what has vested"}
+        compile_if: [Self::funded, Self::claims_left]
+        guarded_by: [Self::grantor_terminates]
+        fn terminate(self, ctx) {
+            let builder = ctx.template().add_fees(self.fee)?;
+            let amount = builder.ctx().funds();
+            builder
+                .add_output(amount, &Termination { vesting: self.clone() }, None)?
+                .set_label(format!("terminate from claim {}", self.claimed))
+                .into()
+        }
+    }
+}
+
+impl Contract for Vesting {
+    declare! {then, Self::claim, Self::terminate}
+    declare! {non updatable}
+}
+
+/// A terminated `Vesting` grant, see the module docs
+pub struct Termination {
+    vesting: Vesting,
+}
+
+impl Termination {
+    guard! {fn beneficiary_claims(self, _ctx) { Clause::Key(self.vesting.beneficiary) }}
+    guard! {fn grantor_reclaims(self, _ctx) {
+        Clause::And(vec![Clause::Key(self.vesting.grantor), self.vesting.notice.into()])
+    }}
+
+    then! {
+        annotated: {label: "settle", roles: ["beneficiary"], doc: "pays the beneficiary what vested by a claim's height, and the grantor the rest"}
+        guarded_by: [Self::beneficiary_claims]
+        fn settle(self, ctx) {
+            let v = &self.vesting;
+            let mut templates = vec![];
+            let mut vested = Amount::from_sat(0);
+            for (i, claim) in v.schedule.claims().iter().enumerate().skip(v.claimed) {
+                vested += claim.amount;
+                let builder = ctx.template().add_fees(v.fee)?;
+                let rest = builder.ctx().funds() - vested;
+                let mut builder = builder.add_output(
+                    vested,
+                    &Compiled::from_address(v.beneficiary_address.clone(), None),
+                    None,
+                )?;
+                if rest > Amount::from_sat(0) {
+                    builder = builder.add_output(
+                        rest,
+                        &Compiled::from_address(v.grantor_address.clone(), None),
+                        None,
+                    )?;
+                }
+                let tmpl = builder
+                    .set_lock_time(AbsHeight::try_from(claim.height)?.into())?
+                    .set_label(format!("settle through claim {}", i))
+                    .finish()?;
+                templates.push(Ok(tmpl));
+            }
+            Ok(Box::new(templates.into_iter()))
+        }
+    }
+    then! {
+        annotated: {label: "reclaim", roles: ["grantor"], doc: "returns what is left to the grantor after the notice period"}
+        guarded_by: [Self::grantor_reclaims]
+        fn reclaim(self, ctx) {
+            let builder = ctx.template().add_fees(self.vesting.fee)?;
+            let amount = builder.ctx().funds();
+            builder
+                .add_output(
+                    amount,
+                    &Compiled::from_address(self.vesting.grantor_address.clone(), None),
+                    None,
+                )?
+                .set_sequence(0, self.vesting.notice)?
+                .set_label("reclaim".into())
+                .into()
+        }
+    }
+}
+
+impl Contract for Termination {
+    declare! {then, Self::settle, Self::reclaim}
+    declare! {non updatable}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::key;
+    use sapio::simulation::{ChainState, Simulator};
+    use sapio_base::timelocks::RelHeight;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::sync::Arc;
+
+    #[test]
+    fn vesting() {
+        // a year of monthly periods, with a three and a half month cliff
+        let schedule = VestingSchedule {
+            start: 1000,
+            cliff: 4320 * 3 + 2160,
+            period: 4320,
+            periods: 12,
+            total: Amount::from_sat(1_200_000),
+        };
+        assert_eq!(schedule.vested(1000 + 4320 * 3), Amount::from_sat(0));
+        assert_eq!(
+            schedule.vested(1000 + 4320 * 3 + 2160),
+            Amount::from_sat(300_000)
+        );
+        assert_eq!(schedule.vested(1_000_000), schedule.total);
+        let claims = schedule.claims();
+        // the three months vested at the cliff, then one claim per month
+        assert_eq!(claims.len(), 10);
+        assert_eq!(claims[0].height, 1000 + 4320 * 3 + 2160);
+        assert_eq!(claims[0].amount, Amount::from_sat(300_000));
+        assert_eq!(claims[1].height, 1000 + 4320 * 4);
+        let sum = claims.iter().fold(Amount::from_sat(0), |a, c| a + c.amount);
+        assert_eq!(sum, schedule.total);
+
+        let vesting = Vesting {
+            beneficiary: key(1),
+            beneficiary_address: bitcoin::Address::p2wpkh(&key(1), bitcoin::Network::Regtest)
+                .unwrap(),
+            grantor: key(2),
+            grantor_address: bitcoin::Address::p2wpkh(&key(2), bitcoin::Network::Regtest).unwrap(),
+            schedule,
+            notice: RelHeight::from(1008).into(),
+            fee: Amount::from_sat(1000),
+            claimed: 0,
+        };
+        assert!(vesting.claimable(1000 + 4320 * 3).is_empty());
+        assert_eq!(vesting.claimable(1000 + 4320 * 5).len(), 3);
+        let later = Vesting {
+            claimed: 2,
+            ..vesting.clone()
+        };
+        assert_eq!(later.claimable(1000 + 4320 * 5).len(), 1);
+
+        let funds = vesting.required();
+        let ctx = Context::new(bitcoin::Network::Regtest, funds, Arc::new(CTVAvailable));
+        let compiled = vesting.compile(&ctx).unwrap();
+        let report = Simulator::new(ChainState {
+            height: 1000,
+            median_time_past: 0,
+        })
+        .run(&compiled, funds);
+        assert_eq!(report.dead_branches().count(), 0);
+        let beneficiary = vesting.beneficiary_address.script_pubkey();
+        let most = report
+            .outcomes
+            .iter()
+            .filter_map(|o| o.balances.get(&beneficiary))
+            .max()
+            .cloned();
+        // every claim, with the fee saved for a termination
+        assert_eq!(most, Some(schedule.total + vesting.fee));
+
+        let short = Context::new(
+            bitcoin::Network::Regtest,
+            funds - Amount::from_sat(1),
+            Arc::new(CTVAvailable),
+        );
+        assert!(vesting.compile(&short).is_err());
+    }
+}
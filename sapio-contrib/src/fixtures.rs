@@ -0,0 +1,19 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Helpers shared by the crate's unit tests
+use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+
+/// the key of the secret key `[i; 32]`
+pub fn key(i: u8) -> bitcoin::PublicKey {
+    bitcoin::PublicKey {
+        compressed: true,
+        key: PublicKey::from_secret_key(
+            &Secp256k1::new(),
+            &SecretKey::from_slice(&[i; 32]).unwrap(),
+        ),
+    }
+}
@@ -7,6 +7,8 @@
 #[deny(missing_docs)]
 pub mod contracts;
 #[cfg(test)]
+mod fixtures;
+#[cfg(test)]
 mod tests {
     #[test]
     fn it_works() {
@@ -8,6 +8,7 @@
 use bitcoin::hashes::hex::ToHex;
 use bitcoin::hashes::Hash;
 use bitcoin::util::amount::Amount;
+use sapio::contract::schema::SchemaOf;
 use sapio::contract::{Compilable, CompilationError, Compiled, Context};
 use sapio::util::extended_address::ExtendedAddress;
 use sapio_ctv_emulator_trait::CTVAvailable;
@@ -35,7 +36,10 @@ pub enum SessionError {
 impl std::error::Error for SessionError {}
 impl Display for SessionError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
-        write!(f, "{:?}", self)
+        match self {
+            SessionError::Compiler(e) => e.fmt(f),
+            _ => write!(f, "{:?}", self),
+        }
     }
 }
 
@@ -61,6 +65,16 @@ where
     c
 }
 
+/// Create a compiled object of type `T` from a JSON of its `SchemaOf::Args`.
+pub fn from_json_args<T>(s: serde_json::Value, ctx: &Context) -> Result<Compiled, SessionError>
+where
+    T: SchemaOf + Compilable,
+{
+    let args = serde_json::from_value(s).map_err(SessionError::Json)?;
+    let t = T::from_args(args).map_err(SessionError::Compiler)?;
+    ctx.compile(t).map_err(SessionError::Compiler)
+}
+
 /// Create a compiled object of type `T` from a JSON which we first pass through
 /// type `C`.
 pub fn from_json_convert<C, T, E>(
@@ -242,6 +256,22 @@ impl MenuBuilder {
         );
         self.menu.push(s);
     }
+    /// register type T, created from its `SchemaOf::Args`, with an optional
+    /// name. If no name is provided, infer it from the arguments' type.
+    pub fn register_schema_of<T: SchemaOf + Compilable>(&mut self, name: Option<String>) {
+        let mut s = self.gen.root_schema_for::<T::Args>();
+        let title: &mut Option<String> = &mut s.schema.metadata().title;
+        if name.is_some() {
+            *title = name;
+        }
+        self.internal_menu
+            .insert(title.clone().unwrap(), from_json_args::<T>);
+        self.schemas.insert(
+            title.clone().unwrap(),
+            serde_json::to_string_pretty(&s).unwrap(),
+        );
+        self.menu.push(s);
+    }
     fn gen_menu(&self) -> Value {
         json!({
             "$schema": "http://json-schema.org/draft-07/schema#",
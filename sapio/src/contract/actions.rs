@@ -114,9 +114,23 @@ pub enum ConditionallyCompileIf<ContractSelf> {
 /// A List of ConditionallyCompileIfs, for convenience
 pub type ConditionallyCompileIfList<'a, T> = &'a [fn() -> Option<ConditionallyCompileIf<T>>];
 
+/// Human-readable metadata of a `then!` or `finish!` branch, for wallets and
+/// UIs to describe its spend path, see `annotate!`
+#[derive(Clone, Copy, Debug)]
+pub struct Annotation {
+    /// a short label for the path
+    pub label: &'static str,
+    /// the parties who would spend through the path
+    pub roles: &'static [&'static str],
+    /// a longer description of the path
+    pub doc: Option<&'static str>,
+}
+
 /// A ThenFunc takes a list of Guards and a TxTmplIt generator.  Each TxTmpl returned from the
 /// ThenFunc is Covenant Permitted only if the AND of all guards is satisfied.
 pub struct ThenFunc<'a, ContractSelf: 'a> {
+    /// the function's name, for diagnostics
+    pub name: &'static str,
     /// Guards returns Clauses -- if any -- before the internal func's returned
     /// TxTmpls should execute on-chain
     pub guard: GuardList<'a, ContractSelf>,
@@ -127,11 +141,15 @@ pub struct ThenFunc<'a, ContractSelf: 'a> {
     /// Implementors should aim to return as few `TxTmpl`s as possible for enhanced
     /// semantics, preferring to split across multiple `ThenFunc`'s
     pub func: fn(&ContractSelf, &Context) -> TxTmplIt,
+    /// the branch's annotation, if any, recorded in `Object::spend_paths`
+    pub annotation: Option<Annotation>,
 }
 
 /// A function which by default finishes, but may receive some context object which can induce the
 /// generation of additional transactions (as a suggestion)
 pub struct FinishOrFunc<'a, ContractSelf: 'a, StatefulArguments> {
+    /// the function's name, for diagnostics
+    pub name: &'static str,
     /// Guards returns Clauses -- if any -- before the coins should be unlocked
     pub guard: GuardList<'a, ContractSelf>,
     /// conditional_compile_if returns ConditionallyCompileType to determine if a function
@@ -142,4 +160,6 @@ pub struct FinishOrFunc<'a, ContractSelf: 'a, StatefulArguments> {
     /// semantics, preferring to split across multiple `FinishOrFunc`'s.
     /// These `TxTmpl`s are non-binding, merely suggested.
     pub func: fn(&ContractSelf, &Context, Option<&StatefulArguments>) -> TxTmplIt,
+    /// the branch's annotation, if any, recorded in `Object::spend_paths`
+    pub annotation: Option<Annotation>,
 }
@@ -0,0 +1,424 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Static checks run by the compiler on each template, so that a contract
+//! which could never be spent fails to compile instead.
+//!
+//! A branch's guards may require timelocks (`Clause::After` and
+//! `Clause::Older`), but the transactions of its templates are fixed when
+//! compiled: if no way of satisfying the guards has timelocks the template's
+//! lock time and sequence meet, or the template's own locks aren't enforced
+//! by consensus, the branch can't be taken. As contracts are compiled from
+//! the inside out, a conflict in a nested contract is reported with the path
+//! to it from the outermost contract (see `TimelockConflict::path`).
+//!
+//! The amounts of each template are checked too, but as they may be
+//! intended (e.g. a contract paying a high fee to confirm quickly), problems
+//! are recorded in the compiled `Object` as `Warning`s rather than failing.
+//! An output below the dust limit for its script (see `dust_limit`) can't
+//! be relayed (and fails to compile under the default relay policy, see
+//! `standard`), and fees above `Context::max_fee_bps` of the value spent are
+//! likely a mistake. Warnings of nested contracts are included with their
+//! path from the outermost contract, so it has every warning of its tree.
+use super::{CompilationError, Compiled, Context};
+use crate::template::Template;
+use bitcoin::hashes::sha256;
+use bitcoin::util::amount::Amount;
+use bitcoin::Script;
+use sapio_base::Clause;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Lock times below this are heights, and at or above it times
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+/// If set, a sequence has no relative lock time (BIP-68)
+const SEQUENCE_DISABLE_FLAG: u32 = 1 << 31;
+/// If set, a relative lock time is in units of 512 seconds (BIP-68)
+const SEQUENCE_TYPE_FLAG: u32 = 1 << 22;
+const SEQUENCE_MASK: u32 = 0x0000ffff;
+
+/// The feerate, in sats per vbyte, outputs must pay to spend to be relayed
+const DUST_RELAY_FEE: u64 = 3;
+/// By default, fees above this fraction of the value spent, in basis points,
+/// are warned about
+pub const DEFAULT_MAX_FEE_BPS: u64 = 500;
+
+/// A step from a contract to one it creates
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PathStep {
+    /// the branch at this index, counting then functions before finish_or
+    /// functions
+    Branch(usize),
+    /// the output at this index of the branch's template
+    Output(usize),
+}
+
+impl fmt::Display for PathStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PathStep::Branch(i) => write!(f, "branch {}", i),
+            PathStep::Output(i) => write!(f, "output {}", i),
+        }
+    }
+}
+
+/// Why a template's timelocks can never be met
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConflictReason {
+    /// the template has a relative lock time, but a version below 2
+    RelativeLockNotEnforced,
+    /// the template has a lock time, but every input's sequence is final
+    LockTimeNotEnforced,
+    /// the branch's guards require timelocks the template doesn't meet
+    Unsatisfiable(Clause),
+}
+
+/// A template whose timelocks can never be met, see the module docs
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TimelockConflict {
+    /// the path to the template from the contract being compiled
+    pub path: Vec<PathStep>,
+    /// the template's hash
+    pub template: sha256::Hash,
+    /// why it can never be met
+    pub reason: ConflictReason,
+}
+
+impl fmt::Display for TimelockConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for step in self.path.iter() {
+            write!(f, "{} / ", step)?;
+        }
+        write!(f, "template {}: ", self.template)?;
+        match &self.reason {
+            ConflictReason::RelativeLockNotEnforced => {
+                write!(f, "relative lock time requires version 2")
+            }
+            ConflictReason::LockTimeNotEnforced => {
+                write!(f, "lock time requires a non-final sequence")
+            }
+            ConflictReason::Unsatisfiable(guard) => {
+                write!(f, "timelocks of guard {} are never met", guard)
+            }
+        }
+    }
+}
+
+/// Checks that `template` can be spent by the branch guarded by `guard`,
+/// the `branch`th of the contract being compiled.
+pub fn check_template(
+    guard: &Clause,
+    template: &Template,
+    branch: usize,
+) -> Result<(), CompilationError> {
+    let conflict = |reason| {
+        Err(CompilationError::TimelockConflict(TimelockConflict {
+            path: vec![PathStep::Branch(branch)],
+            template: template.hash(),
+            reason,
+        }))
+    };
+    let tx = &template.tx;
+    let relative = tx
+        .input
+        .iter()
+        .any(|i| i.sequence & SEQUENCE_DISABLE_FLAG == 0 && i.sequence & SEQUENCE_MASK != 0);
+    if relative && tx.version < 2 {
+        return conflict(ConflictReason::RelativeLockNotEnforced);
+    }
+    if tx.lock_time != 0 && tx.input.iter().all(|i| i.sequence == u32::MAX) {
+        return conflict(ConflictReason::LockTimeNotEnforced);
+    }
+    if !satisfiable(guard, tx) {
+        return conflict(ConflictReason::Unsatisfiable(guard.clone()));
+    }
+    Ok(())
+}
+
+/// If some way of satisfying `c` only has timelocks `tx` meets as the
+/// spender of its first input.
+fn satisfiable(c: &Clause, tx: &bitcoin::Transaction) -> bool {
+    match c {
+        Clause::Unsatisfiable => false,
+        Clause::After(n) => {
+            let sequence = tx.input.get(0).map_or(u32::MAX, |i| i.sequence);
+            sequence != u32::MAX
+                && (*n < LOCKTIME_THRESHOLD) == (tx.lock_time < LOCKTIME_THRESHOLD)
+                && tx.lock_time >= *n
+        }
+        Clause::Older(n) => {
+            let sequence = tx
+                .input
+                .get(0)
+                .map_or(SEQUENCE_DISABLE_FLAG, |i| i.sequence);
+            tx.version >= 2
+                && sequence & SEQUENCE_DISABLE_FLAG == 0
+                && sequence & SEQUENCE_TYPE_FLAG == n & SEQUENCE_TYPE_FLAG
+                && sequence & SEQUENCE_MASK >= n & SEQUENCE_MASK
+        }
+        Clause::And(subs) => subs.iter().all(|s| satisfiable(s, tx)),
+        Clause::Or(subs) => subs.iter().any(|(_, s)| satisfiable(s, tx)),
+        Clause::Threshold(k, subs) => subs.iter().filter(|s| satisfiable(s, tx)).count() >= *k,
+        _ => true,
+    }
+}
+
+/// What a `Warning` is about
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub enum WarningKind {
+    /// an output is below the dust limit for its script
+    Dust {
+        /// the output's index
+        output: usize,
+        /// the output's amount
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        #[schemars(with = "i64")]
+        amount: Amount,
+        /// the dust limit for its script
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        #[schemars(with = "i64")]
+        limit: Amount,
+    },
+    /// the template's fees are above the configured fraction of its value
+    ExcessiveFees {
+        /// the fees paid
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        #[schemars(with = "i64")]
+        fees: Amount,
+        /// the value the template spends
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        #[schemars(with = "i64")]
+        value: Amount,
+    },
+}
+
+/// A likely problem with a template's amounts, see the module docs
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct Warning {
+    /// the path to the template from the contract compiled
+    pub path: Vec<PathStep>,
+    /// the template's hash
+    pub template: sha256::Hash,
+    /// what the warning is about
+    pub kind: WarningKind,
+}
+
+/// If `script` is a witness program (BIP-141)
+fn is_witness_program(script: &Script) -> bool {
+    let b = script.as_bytes();
+    (4..=42).contains(&b.len())
+        && (b[0] == 0x00 || (0x51..=0x60).contains(&b[0]))
+        && b[1] as usize == b.len() - 2
+}
+
+/// The smallest output to `script` which is not dust: one worth spending at
+/// `DUST_RELAY_FEE`, counting the output and the input spending it, as
+/// Bitcoin Core's relay policy does. Outputs which can't be spent (OP_RETURN)
+/// have no limit.
+pub fn dust_limit(script: &Script) -> Amount {
+    if script.is_op_return() {
+        return Amount::from_sat(0);
+    }
+    // the amount, the script's length, and the script
+    let output = 8 + 1 + script.len() as u64;
+    // an outpoint, a script_sig and a sequence, and a signature either in
+    // the script_sig or, at a quarter, the witness
+    let input = if is_witness_program(script) {
+        32 + 4 + 1 + 4 + 107 / 4
+    } else {
+        32 + 4 + 1 + 107 + 4
+    };
+    Amount::from_sat((output + input) * DUST_RELAY_FEE)
+}
+
+/// The warnings about `template`, the `branch`th of the contract being
+/// compiled, and the contracts it creates.
+pub fn check_amounts(template: &Template, branch: usize, ctx: &Context) -> Vec<Warning> {
+    let h = template.hash();
+    let here = |kind| Warning {
+        path: vec![PathStep::Branch(branch)],
+        template: h,
+        kind,
+    };
+    let mut warnings = vec![];
+    for (idx, output) in template.outputs.iter().enumerate() {
+        let limit = dust_limit(&output.contract.address.clone().into());
+        if output.amount < limit {
+            warnings.push(here(WarningKind::Dust {
+                output: idx,
+                amount: output.amount,
+                limit,
+            }));
+        }
+    }
+    let value = template.max;
+    let fees = value - template.total_amount();
+    if fees.as_sat().saturating_mul(10_000) > value.as_sat().saturating_mul(ctx.max_fee_bps()) {
+        warnings.push(here(WarningKind::ExcessiveFees { fees, value }));
+    }
+    for (idx, output) in template.outputs.iter().enumerate() {
+        for w in output.contract.warnings.iter() {
+            let mut path = vec![PathStep::Branch(branch), PathStep::Output(idx)];
+            path.extend(w.path.iter().cloned());
+            warnings.push(Warning { path, ..w.clone() });
+        }
+    }
+    warnings
+}
+
+/// Where funds leave a contract, see `leaf_values`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LeafValue {
+    /// the templates, and the index of the output of each, leading to the
+    /// leaf
+    pub path: Vec<(sha256::Hash, usize)>,
+    /// the leaf's script
+    pub script: Script,
+    /// the value it receives
+    pub amount: Amount,
+}
+
+/// Every output of `object`'s tree of templates to a contract without
+/// templates (e.g. an address), where funds leave the contract, ordered by
+/// path.
+pub fn leaf_values(object: &Compiled) -> Vec<LeafValue> {
+    let mut leaves = vec![];
+    walk_leaves(object, &mut vec![], &mut leaves);
+    leaves
+}
+
+fn walk_leaves(
+    object: &Compiled,
+    path: &mut Vec<(sha256::Hash, usize)>,
+    leaves: &mut Vec<LeafValue>,
+) {
+    let mut templates: Vec<&Template> = object
+        .ctv_to_tx
+        .values()
+        .chain(object.suggested_txs.values())
+        .collect();
+    templates.sort_by_key(|t| t.hash());
+    for template in templates {
+        for (idx, output) in template.outputs.iter().enumerate() {
+            path.push((template.hash(), idx));
+            let child = &output.contract;
+            if child.ctv_to_tx.is_empty() && child.suggested_txs.is_empty() {
+                leaves.push(LeafValue {
+                    path: path.clone(),
+                    script: child.address.clone().into(),
+                    amount: output.amount,
+                });
+            } else {
+                walk_leaves(child, path, leaves);
+            }
+            path.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::Compilable;
+    use crate::fixtures::*;
+
+    #[test]
+    fn timelock_conflicts() {
+        let ctx = ctx(1.0);
+        let contract = |lock_inner| GuardedEmulation {
+            to_contract: GuardedEmulation {
+                to_contract: to(),
+                amount: Amount::from_btc(1.0).unwrap(),
+                timeout: 10,
+                lock: lock_inner,
+            },
+            amount: Amount::from_btc(1.0).unwrap(),
+            timeout: 4,
+            lock: true,
+        };
+        assert!(contract(true).compile(&ctx).is_ok());
+        // the inner template doesn't wait out its guard's relative lock
+        let e = contract(false).compile(&ctx).err().unwrap();
+        match e.root() {
+            CompilationError::TimelockConflict(c) => {
+                assert_eq!(
+                    c.path,
+                    vec![
+                        PathStep::Branch(0),
+                        PathStep::Output(0),
+                        PathStep::Branch(0)
+                    ]
+                );
+                assert!(matches!(c.reason, ConflictReason::Unsatisfiable(_)));
+            }
+            _ => panic!("compiled a contract which can't be spent"),
+        }
+        // the diagnostic names the contracts and functions on the way there
+        let d = e.diagnostic().unwrap();
+        assert_eq!(d.code, "timelock-conflict");
+        assert_eq!(e.code(), "timelock-conflict");
+        assert_eq!(
+            d.contract,
+            std::any::type_name::<GuardedEmulation<Compiled>>()
+        );
+        assert_eq!(d.clause, Some("complete"));
+        assert_eq!(
+            d.path,
+            vec![crate::contract::error::Frame {
+                contract: std::any::type_name::<GuardedEmulation<GuardedEmulation<Compiled>>>(),
+                clause: Some("complete"),
+                output: Some(0),
+            }]
+        );
+    }
+
+    #[test]
+    fn amount_warnings() {
+        use crate::contract::standard::RelayPolicy;
+        let contract = TestEmulation {
+            to_contract: TestEmulation {
+                to_contract: to(),
+                amount: Amount::from_sat(100),
+                timeout: 6,
+            },
+            amount: Amount::from_sat(100),
+            timeout: 4,
+        };
+        // dust fails under the default relay policy, see standard's tests
+        let compiled = contract
+            .compile(&ctx(1.0).with_relay_policy(RelayPolicy::Consensus))
+            .unwrap();
+        // both outputs are dust, the inner one reported with its path
+        let paths: Vec<_> = compiled.warnings.iter().map(|w| w.path.clone()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                vec![PathStep::Branch(0)],
+                vec![
+                    PathStep::Branch(0),
+                    PathStep::Output(0),
+                    PathStep::Branch(0)
+                ]
+            ]
+        );
+        for w in compiled.warnings.iter() {
+            match w.kind {
+                WarningKind::Dust { amount, limit, .. } => assert!(amount < limit),
+                _ => panic!("expected only dust"),
+            }
+        }
+        assert_eq!(
+            dust_limit(&address().script_pubkey()),
+            Amount::from_sat(294)
+        );
+        let leaves = leaf_values(&compiled);
+        assert_eq!(leaves.len(), 1);
+        assert_eq!(leaves[0].path.len(), 2);
+        assert_eq!(leaves[0].script, address().script_pubkey());
+        assert_eq!(leaves[0].amount, Amount::from_sat(100));
+    }
+}
@@ -0,0 +1,282 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Dividing a context's funds into named budgets for the contracts it
+//! creates, rather than doing the arithmetic by hand in each contract.
+//!
+//! `Context::budgets` starts a plan over the context's funds, to which
+//! budgets are allocated by name (`Budgets::allocate`), or many at once from
+//! a `Split` (`Budgets::allocate_split`). Allocating more than is left
+//! fails, naming the budget. Each child is then compiled with its budget,
+//! with `Budgets::compile` or as an output with
+//! `Builder::add_budgeted_output`, and checked not to spend more than it:
+//! no template of the child spending only its output (its first input) may
+//! pay out more than the budget. What is left unallocated is `remainder`,
+//! and `report` summarizes the plan, e.g. for the fees left over.
+use super::{Compilable, CompilationError, Compiled, Context};
+use crate::util::split::Split;
+use bitcoin::util::amount::Amount;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Errors allocating or spending budgets
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BudgetError {
+    /// a budget of the name was already allocated
+    Duplicate(String),
+    /// no budget of the name was allocated
+    Unknown(String),
+    /// the budget is more than is left unallocated
+    Overallocated {
+        /// the budget
+        name: String,
+        /// its amount
+        amount: Amount,
+        /// what is left
+        remaining: Amount,
+    },
+    /// a split has a different number of shares than names
+    Mismatch {
+        /// the number of names
+        names: usize,
+        /// the number of shares
+        shares: usize,
+    },
+    /// a child compiled with the budget spends more than it
+    Exceeded {
+        /// the budget
+        name: String,
+        /// its amount
+        amount: Amount,
+        /// what the child spends
+        spent: Amount,
+    },
+}
+
+impl fmt::Display for BudgetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for BudgetError {}
+
+/// A summary of `Budgets`, see the module docs
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct BudgetReport {
+    /// the funds divided
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    #[schemars(with = "i64")]
+    pub total: Amount,
+    /// the amount of each budget, in sats
+    pub allocated: BTreeMap<String, u64>,
+    /// what is left unallocated
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    #[schemars(with = "i64")]
+    pub unallocated: Amount,
+}
+
+/// A context's funds divided into named budgets, see the module docs
+#[derive(Clone)]
+pub struct Budgets {
+    ctx: Context,
+    budgets: BTreeMap<String, Amount>,
+    remaining: Amount,
+}
+
+impl Budgets {
+    pub(crate) fn new(ctx: &Context) -> Self {
+        Budgets {
+            ctx: ctx.clone(),
+            budgets: BTreeMap::new(),
+            remaining: ctx.funds(),
+        }
+    }
+
+    /// allocates `amount` to a budget `name`
+    pub fn allocate(mut self, name: &str, amount: Amount) -> Result<Self, CompilationError> {
+        if self.budgets.contains_key(name) {
+            return Err(BudgetError::Duplicate(name.into()).into());
+        }
+        if amount > self.remaining {
+            return Err(BudgetError::Overallocated {
+                name: name.into(),
+                amount,
+                remaining: self.remaining,
+            }
+            .into());
+        }
+        self.remaining -= amount;
+        self.budgets.insert(name.into(), amount);
+        Ok(self)
+    }
+
+    /// allocates each share of `split` to the budget of the name at its
+    /// index. The split's fee is left unallocated.
+    pub fn allocate_split(self, names: &[&str], split: &Split) -> Result<Self, CompilationError> {
+        if names.len() != split.shares().len() {
+            return Err(BudgetError::Mismatch {
+                names: names.len(),
+                shares: split.shares().len(),
+            }
+            .into());
+        }
+        names
+            .iter()
+            .zip(split.shares())
+            .try_fold(self, |budgets, (name, share)| {
+                budgets.allocate(name, *share)
+            })
+    }
+
+    /// the amount of the budget `name`
+    pub fn amount(&self, name: &str) -> Result<Amount, CompilationError> {
+        self.budgets
+            .get(name)
+            .cloned()
+            .ok_or_else(|| BudgetError::Unknown(name.into()).into())
+    }
+
+    /// a context with the funds of the budget `name`
+    pub fn context(&self, name: &str) -> Result<Context, CompilationError> {
+        self.ctx.with_amount(self.amount(name)?)
+    }
+
+    /// compiles `contract` with the budget `name`, checking it doesn't spend
+    /// more than it
+    pub fn compile(
+        &self,
+        name: &str,
+        contract: &dyn Compilable,
+    ) -> Result<Compiled, CompilationError> {
+        let object = contract.compile(&self.context(name)?)?;
+        check(name, self.amount(name)?, &object)?;
+        Ok(object)
+    }
+
+    /// what is left unallocated
+    pub fn remainder(&self) -> Amount {
+        self.remaining
+    }
+
+    /// summarizes the budgets
+    pub fn report(&self) -> BudgetReport {
+        BudgetReport {
+            total: self.ctx.funds(),
+            allocated: self
+                .budgets
+                .iter()
+                .map(|(name, amount)| (name.clone(), amount.as_sat()))
+                .collect(),
+            unallocated: self.remaining,
+        }
+    }
+}
+
+/// checks `object`, compiled with the budget `name` of `amount`, doesn't
+/// spend more than it
+pub(crate) fn check(name: &str, amount: Amount, object: &Compiled) -> Result<(), BudgetError> {
+    // other inputs may bring funds we can't know of
+    let spent = object
+        .ctv_to_tx
+        .values()
+        .chain(object.suggested_txs.values())
+        .filter(|t| t.tx.input.len() == 1)
+        .map(|t| t.total_amount())
+        .max()
+        .unwrap_or(Amount::from_sat(0));
+    if spent > amount {
+        Err(BudgetError::Exceeded {
+            name: name.into(),
+            amount,
+            spent,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::*;
+    use crate::util::split::Rounding;
+
+    fn budget_error<T>(r: Result<T, CompilationError>) -> BudgetError {
+        match r {
+            Err(CompilationError::Budget(e)) => e,
+            Err(e) => panic!("expected a budget error, got {:?}", e),
+            Ok(_) => panic!("expected a budget error"),
+        }
+    }
+
+    #[test]
+    fn budgets() {
+        let ctx = ctx(0.0001);
+        let budgets = ctx
+            .budgets()
+            .allocate("alice", Amount::from_sat(4_000))
+            .unwrap()
+            .allocate_split(
+                &["bob", "carol"],
+                &Split::even(Amount::from_sat(5_001), 2, Rounding::RemainderToFee).unwrap(),
+            )
+            .unwrap();
+        assert_eq!(budgets.amount("bob").unwrap(), Amount::from_sat(2_500));
+        // the split's fee is left unallocated too
+        assert_eq!(budgets.remainder(), Amount::from_sat(1_000));
+        let report = budgets.report();
+        assert_eq!(report.total, Amount::from_sat(10_000));
+        assert_eq!(report.allocated["alice"], 4_000);
+        assert_eq!(report.unallocated, Amount::from_sat(1_000));
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["unallocated"], 1_000);
+
+        assert!(matches!(
+            budget_error(budgets.clone().allocate("dave", Amount::from_sat(1_001))),
+            BudgetError::Overallocated { .. }
+        ));
+        assert_eq!(
+            budget_error(budgets.clone().allocate("alice", Amount::from_sat(1))),
+            BudgetError::Duplicate("alice".into())
+        );
+        assert_eq!(
+            budget_error(budgets.amount("dave")),
+            BudgetError::Unknown("dave".into())
+        );
+
+        // children are compiled with their budget
+        let fan = |amount| Fan {
+            n: 2,
+            amount,
+            to: address(),
+        };
+        let object = budgets
+            .compile("alice", &fan(Amount::from_sat(4_000)))
+            .unwrap();
+        assert_eq!(
+            object.ctv_to_tx.values().next().unwrap().total_amount(),
+            Amount::from_sat(4_000)
+        );
+        assert_eq!(
+            budgets
+                .compile("bob", &fan(Amount::from_sat(4_000)))
+                .unwrap_err()
+                .code(),
+            "out-of-funds"
+        );
+        let tmpl = ctx
+            .template()
+            .add_budgeted_output(&budgets, "alice", &fan(Amount::from_sat(4_000)), None)
+            .unwrap()
+            .add_budgeted_output(&budgets, "bob", &fan(Amount::from_sat(2_500)), None)
+            .unwrap()
+            .finish()
+            .unwrap();
+        assert_eq!(tmpl.total_amount(), Amount::from_sat(6_500));
+    }
+}
@@ -0,0 +1,190 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Combining guards (see `guard!`) into others, rather than writing the
+//! combined policy by hand in a single guard.
+//!
+//! `all_of`, `any_of`, and `threshold` evaluate guards and combine their
+//! clauses with `and`, `or`, and `thresh`, which simplify as they go: nested
+//! conjunctions (and disjunctions) are flattened, duplicates are removed, and
+//! trivial or unsatisfiable clauses are folded away, so the miniscript
+//! compiler is handed the smallest equivalent policy. Guards combined this
+//! way are evaluated each time the combined guard is, even if they are
+//! `cached`.
+//!
+//! `guard!` combines guards directly:
+//! ```ignore
+//! guard!(fn both = all_of[Self::alice, Self::bob]);
+//! guard!(fn either = any_of[Self::alice, Self::bob]);
+//! guard!(cached fn two = threshold(2)[Self::alice, Self::bob, Self::carol]);
+//! ```
+use super::actions::{Guard, GuardList};
+use super::Context;
+use sapio_base::Clause;
+
+/// the clauses of `guards`, skipping any which are absent
+pub fn eval<T>(guards: GuardList<T>, t: &T, ctx: &Context) -> Vec<Clause> {
+    guards
+        .iter()
+        .filter_map(|g| g())
+        .map(|g| match g {
+            Guard::Cache(f) | Guard::Fresh(f) => f(t, ctx),
+        })
+        .collect()
+}
+
+/// a clause satisfied if all of `guards` are
+pub fn all_of<T>(guards: GuardList<T>, t: &T, ctx: &Context) -> Clause {
+    and(eval(guards, t, ctx))
+}
+
+/// a clause satisfied if any of `guards` are
+pub fn any_of<T>(guards: GuardList<T>, t: &T, ctx: &Context) -> Clause {
+    or(eval(guards, t, ctx))
+}
+
+/// a clause satisfied if at least `k` of `guards` are
+pub fn threshold<T>(k: usize, guards: GuardList<T>, t: &T, ctx: &Context) -> Clause {
+    thresh(k, eval(guards, t, ctx))
+}
+
+/// appends the clauses `c` is a conjunction of to `out`
+fn conjuncts(c: Clause, out: &mut Vec<Clause>) {
+    match c {
+        Clause::Trivial => {}
+        Clause::And(cs) => cs.into_iter().for_each(|c| conjuncts(c, out)),
+        Clause::Threshold(k, cs) if k == cs.len() => cs.into_iter().for_each(|c| conjuncts(c, out)),
+        c => {
+            if !out.contains(&c) {
+                out.push(c)
+            }
+        }
+    }
+}
+
+/// appends the clauses `c` is a disjunction of to `out`
+fn disjuncts(c: Clause, out: &mut Vec<Clause>) {
+    match c {
+        Clause::Unsatisfiable => {}
+        Clause::Threshold(1, cs) => cs.into_iter().for_each(|c| disjuncts(c, out)),
+        c => {
+            if !out.contains(&c) {
+                out.push(c)
+            }
+        }
+    }
+}
+
+/// A clause satisfied if all of `clauses` are, as a chain of `Clause::And`s
+/// (as `guarded_by` combines guards).
+pub fn and(clauses: Vec<Clause>) -> Clause {
+    let mut flat = vec![];
+    clauses.into_iter().for_each(|c| conjuncts(c, &mut flat));
+    if flat.contains(&Clause::Unsatisfiable) {
+        return Clause::Unsatisfiable;
+    }
+    let mut flat = flat.into_iter();
+    match flat.next() {
+        None => Clause::Trivial,
+        Some(first) => flat.fold(first, |acc, c| Clause::And(vec![acc, c])),
+    }
+}
+
+/// A clause satisfied if any of `clauses` are, as a `Clause::Threshold` of
+/// one (which compiles equivalently to a tree of ORs).
+pub fn or(clauses: Vec<Clause>) -> Clause {
+    let mut flat = vec![];
+    clauses.into_iter().for_each(|c| disjuncts(c, &mut flat));
+    if flat.contains(&Clause::Trivial) {
+        return Clause::Trivial;
+    }
+    match flat.len() {
+        0 => Clause::Unsatisfiable,
+        1 => flat.pop().expect("Length of flat must be 1"),
+        _ => Clause::Threshold(1, flat),
+    }
+}
+
+/// A clause satisfied if at least `k` of `clauses` are. Trivial clauses
+/// lower the threshold, and unsatisfiable ones are dropped.
+pub fn thresh(k: usize, clauses: Vec<Clause>) -> Clause {
+    let trivial = clauses.iter().filter(|c| **c == Clause::Trivial).count();
+    let clauses: Vec<Clause> = clauses
+        .into_iter()
+        .filter(|c| *c != Clause::Trivial && *c != Clause::Unsatisfiable)
+        .collect();
+    let k = k.saturating_sub(trivial);
+    match k {
+        0 => Clause::Trivial,
+        k if k > clauses.len() => Clause::Unsatisfiable,
+        k if k == clauses.len() => and(clauses),
+        1 => or(clauses),
+        k => Clause::Threshold(k, clauses),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::Compilable;
+    use crate::fixtures::*;
+
+    #[test]
+    fn guard_combinators() {
+        let (a, b, c) = (
+            Clause::Key(key(1)),
+            Clause::Key(key(2)),
+            Clause::Key(key(3)),
+        );
+        // nested conjunctions are flattened and duplicates removed
+        assert_eq!(
+            and(vec![
+                a.clone(),
+                and(vec![b.clone(), a.clone()]),
+                Clause::Trivial,
+                c.clone()
+            ]),
+            Clause::And(vec![Clause::And(vec![a.clone(), b.clone()]), c.clone()])
+        );
+        assert_eq!(
+            and(vec![a.clone(), Clause::Unsatisfiable]),
+            Clause::Unsatisfiable
+        );
+        assert_eq!(and(vec![]), Clause::Trivial);
+        // as are disjunctions
+        assert_eq!(
+            or(vec![
+                a.clone(),
+                or(vec![b.clone(), c.clone()]),
+                Clause::Unsatisfiable
+            ]),
+            Clause::Threshold(1, vec![a.clone(), b.clone(), c.clone()])
+        );
+        assert_eq!(or(vec![a.clone(), Clause::Trivial]), Clause::Trivial);
+        assert_eq!(or(vec![b.clone(), b.clone()]), b);
+        // thresholds become conjunctions or disjunctions where they can
+        assert_eq!(
+            thresh(2, vec![a.clone(), Clause::Trivial, b.clone()]),
+            Clause::Threshold(1, vec![a.clone(), b.clone()])
+        );
+        assert_eq!(
+            thresh(2, vec![a.clone(), Clause::Unsatisfiable, b.clone()]),
+            Clause::And(vec![a.clone(), b.clone()])
+        );
+        assert_eq!(thresh(3, vec![a.clone(), b.clone()]), Clause::Unsatisfiable);
+
+        // a guard combined with `guard!`
+        let compiled = committee().compile(&ctx(1.0)).unwrap();
+        let h = *compiled.ctv_to_tx.keys().next().unwrap();
+        assert_eq!(
+            compiled.policy,
+            Some(Clause::And(vec![
+                Clause::Threshold(2, vec![a, b, c]),
+                Clause::TxTemplate(h)
+            ]))
+        );
+    }
+}
@@ -5,24 +5,64 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 //! The primary compilation traits and types
+use super::covenant;
 use super::AnyContract;
 use super::CompilationError;
+use super::CompileTarget;
 use super::Compiled;
 use super::Context;
 use crate::util::amountrange::AmountRange;
+use crate::util::extended_address::ExtendedAddress;
 use std::collections::LinkedList;
 
 use super::actions::Guard;
 use super::actions::{ConditionalCompileType, ConditionallyCompileIf};
+use super::analysis::{self, PathStep};
+use super::effects::ContinuationPoint;
+use super::object::SpendPath;
+use super::standard;
 use ::miniscript::*;
+use sapio_base::taproot::{self, TaprootOutput};
 use sapio_base::Clause;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 enum CacheEntry<T> {
     Cached(Clause),
     Fresh(fn(&T, &Context) -> Clause),
 }
 
+/// the keys `guard` checks, if it checks nothing else
+fn keys_of(guard: &Clause) -> Option<Vec<bitcoin::PublicKey>> {
+    match guard {
+        Clause::Key(key) => Some(vec![*key]),
+        Clause::And(subs) => subs
+            .iter()
+            .map(keys_of)
+            .collect::<Option<Vec<_>>>()
+            .map(|keys| keys.concat()),
+        _ => None,
+    }
+}
+
+/// Merges the guards which only check keys into one key, their aggregate,
+/// see `Context::with_key_aggregation`
+fn aggregate_guards(guards: Vec<Clause>) -> Result<Vec<Clause>, CompilationError> {
+    let (keyed, mut rest): (Vec<Clause>, Vec<Clause>) =
+        guards.into_iter().partition(|g| keys_of(g).is_some());
+    let mut keys: Vec<bitcoin::PublicKey> = keyed.iter().filter_map(keys_of).flatten().collect();
+    keys.sort_by_key(|k| k.to_bytes());
+    keys.dedup();
+    match &keys[..] {
+        [] => {}
+        [key] => rest.push(Clause::Key(*key)),
+        keys => rest.push(Clause::Key(bitcoin::PublicKey {
+            compressed: true,
+            key: taproot::aggregate_keys(keys)?,
+        })),
+    }
+    Ok(rest)
+}
+
 /// GuardCache assists with caching the computation of guard functions
 /// during compilation.
 struct GuardCache<T> {
@@ -61,6 +101,7 @@ mod private {
 
     /// Allow Contract to implement Compile
     impl ImplSeal for super::Compiled {}
+    impl<C> ImplSeal for crate::contract::memo::Memo<C> {}
     impl<'a, C> ImplSeal for C where C: super::AnyContract {}
 }
 /// Compilable is a trait for anything which can be compiled
@@ -95,6 +136,8 @@ where
             No,
         }
         let self_ref = self.get_inner_ref();
+        let contract = std::any::type_name::<T::Ref>();
+        let unlocated = |e: CompilationError| e.located(contract, None);
 
         // The code for then_fns and finish_or_fns is very similar, differing
         // only in that then_fns have a CTV enforcing the contract and
@@ -123,16 +166,22 @@ where
             .map(|(errors, nullability, x)| {
                 if errors.is_empty() {
                     (
+                        x.name,
                         nullability,
                         CTVRequired::Yes,
                         x.guard,
-                        (x.func)(self_ref, ctx),
+                        x.annotation,
+                        ctx.derive(x.name)
+                            .reserve_anchors()
+                            .and_then(|ctx| (x.func)(self_ref, &ctx)),
                     )
                 } else {
                     (
+                        x.name,
                         nullability,
                         CTVRequired::Yes,
                         x.guard,
+                        x.annotation,
                         Err(CompilationError::ConditionalCompilationFailed(errors)),
                     )
                 }
@@ -165,16 +214,22 @@ where
             .map(|(errors, x)| {
                 if errors.is_empty() {
                     (
+                        x.name,
                         Nullable::Yes,
                         CTVRequired::No,
                         x.guard,
-                        (x.func)(self_ref, ctx, arg),
+                        x.annotation,
+                        ctx.derive(x.name)
+                            .reserve_anchors()
+                            .and_then(|ctx| (x.func)(self_ref, &ctx, arg)),
                     )
                 } else {
                     (
+                        x.name,
                         Nullable::Yes,
                         CTVRequired::No,
                         x.guard,
+                        x.annotation,
                         Err(CompilationError::ConditionalCompilationFailed(errors)),
                     )
                 }
@@ -184,20 +239,38 @@ where
         let mut ctv_to_tx = HashMap::new();
         let mut suggested_txs = HashMap::new();
         let mut amount_range = AmountRange::new();
+        // the clause spending to each CTV template, to find its leaf if
+        // compiling to taproot
+        let mut template_clauses = vec![];
+        let mut warnings = vec![];
+        let mut continue_points = BTreeMap::new();
+        let mut spend_paths = BTreeMap::new();
+        // the branch and guard of each finish_or_fn, to graft effects under
+        let mut continuations = HashMap::new();
 
         // If no guards and not CTV, then nothing gets added (not interpreted as Trivial True)
         // If CTV and no guards, just CTV added.
         // If CTV and guards, CTV & guards added.
         let mut clause_accumulator = then_fns
             .chain(finish_or_fns)
-            .map(|(nullability, uses_ctv, guards, r_txtmpls)| {
+            .enumerate()
+            .map(|(branch, compiled_fn)| {
+                let (name, nullability, uses_ctv, guards, annotation, r_txtmpls) = compiled_fn;
+                let located = |e: CompilationError| e.located(contract, Some(name));
+                let here = |e: CompilationError| located(e.within(PathStep::Branch(branch)));
                 // Compute all guard clauses.
                 // Don't use a threshold here because then miniscript will just
                 // re-compile it into the And for again, causing extra allocations.
-                let mut guard = guards
+                let mut guards: Vec<Clause> = guards
                     .iter()
                     .filter_map(|x| guard_clauses.get(self_ref, *x, ctx))
                     .filter(|x| *x != Clause::Trivial) // no point in using any Trivials
+                    .collect();
+                if ctx.key_aggregation() {
+                    guards = aggregate_guards(guards).map_err(here)?;
+                }
+                let mut guard = guards
+                    .into_iter()
                     .fold(Clause::Trivial, |acc, item| match acc {
                         Clause::Trivial => item,
                         _ => Clause::And(vec![acc, item]),
@@ -205,10 +278,19 @@ where
 
                 // it would be an error if any of r_txtmpls is an error instead of just an empty
                 // iterator.
-                let mut txtmpl_clauses = r_txtmpls?
+                let mut hashes = vec![];
+                let mut txtmpl_clauses = r_txtmpls
+                    .map_err(here)?
                     .map(|r_txtmpl| {
-                        let txtmpl = r_txtmpl?;
+                        let txtmpl = r_txtmpl.map_err(here)?;
+                        // fail rather than compile a branch which can't be spent
+                        analysis::check_template(&guard, &txtmpl, branch).map_err(located)?;
+                        warnings.extend(analysis::check_amounts(&txtmpl, branch, ctx));
+                        for output in txtmpl.outputs.iter() {
+                            continue_points.extend(output.contract.continue_points.clone());
+                        }
                         let h = txtmpl.hash();
+                        hashes.push(h);
                         let txtmpl = match uses_ctv {
                             CTVRequired::Yes => &mut ctv_to_tx,
                             CTVRequired::No => &mut suggested_txs,
@@ -216,7 +298,11 @@ where
                         .entry(h)
                         .or_insert(txtmpl);
                         amount_range.update_range(txtmpl.max);
-                        ctx.ctv_emulator(h)
+                        let clause = ctx.ctv_emulator(h).map_err(located)?;
+                        if uses_ctv == CTVRequired::Yes {
+                            template_clauses.push((h, clause.clone()));
+                        }
+                        Ok(clause)
                     })
                     // Forces any error to abort the whole thing
                     .collect::<Result<Vec<_>, CompilationError>>()?;
@@ -227,7 +313,7 @@ where
                     } else {
                         let hashes = match txtmpl_clauses.len() {
                             0 => {
-                                return Err(CompilationError::MissingTemplates);
+                                return Err(located(CompilationError::MissingTemplates));
                             }
                             1 => txtmpl_clauses
                                 .pop()
@@ -239,6 +325,11 @@ where
                             _ => Clause::And(vec![guard, hashes]),
                         };
                     }
+                } else {
+                    continuations.insert(name, (branch, guard.clone()));
+                }
+                if let (Some(a), false) = (annotation, guard == Clause::Unsatisfiable) {
+                    spend_paths.insert(name.to_string(), SpendPath::new(&a, hashes));
                 }
                 Ok(guard)
             })
@@ -251,6 +342,55 @@ where
             })
             .collect::<Result<Vec<_>, _>>()?;
 
+        // Graft the templates of any effects under their continuation points,
+        // see `effects`. The guards of finish_or_fns don't depend on their
+        // arguments, so the policy is unchanged.
+        let handlers = self.effect_handlers();
+        for x in self.finish_or_fns().iter().filter_map(|x| x()) {
+            let (branch, guard) = match continuations.remove(x.name) {
+                Some(c) => c,
+                None => continue,
+            };
+            let located = |e: CompilationError| e.located(contract, Some(x.name));
+            let here = |e: CompilationError| located(e.within(PathStep::Branch(branch)));
+            let point = ctx.derive(x.name);
+            continue_points.insert(
+                point.path(),
+                ContinuationPoint {
+                    schema: handlers.as_ref().map(|h| (h.schema)()),
+                    guard: Some(guard.clone()),
+                },
+            );
+            for (id, args) in ctx.effects().get(&point.path()) {
+                let invalid = |error| {
+                    located(CompilationError::InvalidEffect {
+                        point: point.path(),
+                        id: id.clone(),
+                        error,
+                    })
+                };
+                let args = match handlers.as_ref() {
+                    Some(h) => (h.decode)(args.clone()).map_err(invalid)?,
+                    None => {
+                        return Err(invalid(serde::de::Error::custom(
+                            "the contract does not declare effects",
+                        )))
+                    }
+                };
+                let effect_ctx = point.derive(id).reserve_anchors().map_err(here)?;
+                for r_txtmpl in (x.func)(self_ref, &effect_ctx, Some(&args)).map_err(here)? {
+                    let txtmpl = r_txtmpl.map_err(here)?;
+                    analysis::check_template(&guard, &txtmpl, branch).map_err(located)?;
+                    warnings.extend(analysis::check_amounts(&txtmpl, branch, ctx));
+                    for output in txtmpl.outputs.iter() {
+                        continue_points.extend(output.contract.continue_points.clone());
+                    }
+                    let txtmpl = suggested_txs.entry(txtmpl.hash()).or_insert(txtmpl);
+                    amount_range.update_range(txtmpl.max);
+                }
+            }
+        }
+
         // Compute all finish_functions at this level, caching if requested.
         let finish_fns: Vec<_> = self
             .finish_fns()
@@ -264,26 +404,181 @@ where
         }
 
         let policy = match clause_accumulator.len() {
-            0 => return Err(CompilationError::EmptyPolicy),
+            0 => return Err(unlocated(CompilationError::EmptyPolicy)),
             1 => clause_accumulator
                 .pop()
                 .expect("Length of policy must be at least 1"),
             _ => Clause::Threshold(1, clause_accumulator),
         };
 
-        let miniscript = policy.compile().map_err(Into::<CompilationError>::into)?;
-        let descriptor = Descriptor::new_wsh(miniscript)?;
-        let address = descriptor.address(ctx.network)?.into();
-        let descriptor = Some(descriptor);
+        let (address, descriptor, taproot) = match ctx.target() {
+            CompileTarget::SegwitV0 => {
+                let covenant = ctx.covenant();
+                if !covenant.segwit_v0()
+                    && template_clauses
+                        .iter()
+                        .any(|(_, c)| matches!(c, Clause::TxTemplate(_)))
+                {
+                    return Err(unlocated(CompilationError::UnsupportedCovenant(
+                        covenant.name(),
+                    )));
+                }
+                let miniscript = policy
+                    .compile()
+                    .map_err(|e| unlocated(CompilationError::from(e)))?;
+                let descriptor =
+                    Descriptor::new_wsh(miniscript).map_err(|e| unlocated(e.into()))?;
+                let address = descriptor
+                    .address(ctx.network)
+                    .map_err(|e| unlocated(e.into()))?
+                    .into();
+                (address, Some(descriptor), None)
+            }
+            CompileTarget::Taproot => {
+                let templates = covenant::Templates {
+                    backend: ctx.covenant(),
+                    templates: &ctv_to_tx,
+                };
+                let mut taproot =
+                    TaprootOutput::from_clause_with(&policy, &templates, ctx.key_aggregation())
+                        .map_err(|e| unlocated(e.into()))?;
+                for (h, clause) in template_clauses.iter() {
+                    taproot.link_template(*h, clause);
+                }
+                // Our version of rust-bitcoin can't encode witness v1
+                // addresses (bech32m), so only the script is known.
+                let address = ExtendedAddress::Unknown(taproot.script_pubkey());
+                (address, None, Some(taproot))
+            }
+        };
         let policy = Some(policy);
 
-        Ok(Compiled {
+        let object = Compiled {
             ctv_to_tx,
             suggested_txs,
             address,
             descriptor,
+            taproot,
             policy,
             amount_range,
-        })
+            warnings,
+            continue_points,
+            spend_paths,
+            lazy: None,
+        };
+        if let Some(v) = standard::check(&object, ctx.relay_policy())
+            .into_iter()
+            .next()
+        {
+            return Err(unlocated(CompilationError::Nonstandard(v)));
+        }
+        Ok(object)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::Contract;
+    use crate::fixtures::*;
+    use bitcoin::util::amount::Amount;
+
+    /// Pays `to` if all of `keys` sign, each checked by a guard of its own
+    struct Cosigned {
+        keys: Vec<bitcoin::PublicKey>,
+        to: bitcoin::Address,
+    }
+
+    impl Cosigned {
+        guard! {fn alice(self, _ctx) { Clause::Key(self.keys[0]) }}
+        guard! {fn bob(self, _ctx) { Clause::Key(self.keys[1]) }}
+        guard! {fn carol(self, _ctx) { Clause::Key(self.keys[2]) }}
+        then! {
+            guarded_by: [Self::alice, Self::bob, Self::carol]
+            fn pay(self, ctx) {
+                let to = Compiled::from_address(self.to.clone(), None);
+                ctx.template().add_output(ctx.funds(), &to, None)?.into()
+            }
+        }
+    }
+
+    impl Contract for Cosigned {
+        declare! {then, Self::pay}
+        declare! {non updatable}
+    }
+
+    #[test]
+    fn taproot_compile() {
+        let contract = TestEmulation {
+            to_contract: to(),
+            amount: Amount::from_btc(1.0).unwrap(),
+            timeout: 6,
+        };
+        let ctx = ctx(1.0).with_target(CompileTarget::Taproot);
+        let compiled = contract.compile(&ctx).unwrap();
+        assert!(compiled.descriptor.is_none());
+        let output = compiled.taproot.as_ref().unwrap();
+        assert_eq!(
+            bitcoin::Script::from(compiled.address.clone()),
+            output.script_pubkey()
+        );
+        // every template is spent by a leaf committed to by the output key
+        for h in compiled.ctv_to_tx.keys() {
+            let leaf = output.leaf_for(h).unwrap();
+            assert!(taproot::verify_control_block(
+                &output.output_key,
+                &leaf.script,
+                &leaf.control_block
+            ));
+        }
+        // compiling again lays the leaves out the same way
+        assert_eq!(
+            Some(output),
+            contract.compile(&ctx).unwrap().taproot.as_ref()
+        );
+    }
+
+    #[test]
+    fn key_aggregation() {
+        let keys: Vec<_> = (1..4).map(key).collect();
+        let contract = Cosigned {
+            keys: keys.clone(),
+            to: address(),
+        };
+        let ctx = ctx(1.0).with_target(CompileTarget::Taproot);
+        let before = contract.compile(&ctx).unwrap();
+        let after = contract.compile(&ctx.with_key_aggregation()).unwrap();
+        // the same template, spent with one signature for the aggregate key
+        assert_eq!(
+            before.ctv_to_tx.keys().collect::<Vec<_>>(),
+            after.ctv_to_tx.keys().collect::<Vec<_>>()
+        );
+        let (h, _) = after.ctv_to_tx.iter().next().unwrap();
+        let aggregate = Clause::Key(bitcoin::PublicKey {
+            compressed: true,
+            key: taproot::aggregate_keys(&keys).unwrap(),
+        });
+        let leaf = after.taproot.as_ref().unwrap().leaf_for(h).unwrap();
+        assert!(leaf.clauses.contains(&aggregate));
+        assert!(keys
+            .iter()
+            .all(|k| !leaf.clauses.contains(&Clause::Key(*k))));
+        // two fewer signatures, and two fewer keys checked in the script
+        let (before, after) = (before.weights()[h], after.weights()[h]);
+        assert_eq!(
+            before.witness - after.witness,
+            2 * (1 + 64) + 2 * (1 + 32 + 1)
+        );
+        assert!(after.vsize < before.vsize);
+
+        // segwit v0 is unaffected
+        let ctx = ctx.with_target(CompileTarget::SegwitV0);
+        assert_eq!(
+            contract.compile(&ctx).unwrap().descriptor,
+            contract
+                .compile(&ctx.with_key_aggregation())
+                .unwrap()
+                .descriptor
+        );
     }
 }
@@ -5,23 +5,58 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 //! general non-parameter compilation state required by all contracts
-use super::{Amount, Compilable, CompilationError, Compiled};
+use super::budget::Budgets;
+use super::covenant::{self, CovenantBackend};
+use super::effects::MapEffectDB;
+use super::lazy::{self, LazyConfig, LazyToken};
+use super::memo::MemoCache;
+use super::standard::RelayPolicy;
+use super::{analysis, Amount, Compilable, CompilationError, Compiled};
+use crate::template::{Anchor, CommitmentHook};
 use crate::util::amountrange::AmountRange;
 use bitcoin::Network;
 use miniscript::Descriptor;
 use miniscript::DescriptorTrait;
 use sapio_ctv_emulator_trait::CTVEmulator;
-use std::collections::HashMap;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
+
+/// What contracts are compiled to
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompileTarget {
+    /// a P2WSH output with a miniscript witness script (the default)
+    SegwitV0,
+    /// a taproot output, see `sapio_base::taproot`
+    Taproot,
+}
+
 /// Context is used to track statet during compilation such as remaining value.
 /// Context type is not copyable/clonable externally
 #[derive(Clone)]
 pub struct Context {
     /* TODO: Add Context Fields! */
     available_funds: Amount,
+    /// funds held back for the anchors, see `reserve_anchors`
+    reserved: Amount,
     emulator: Arc<dyn CTVEmulator>,
     /// which network is the contract building for?
     pub network: Network,
+    target: CompileTarget,
+    covenant: Arc<dyn CovenantBackend>,
+    max_fee_bps: u64,
+    relay_policy: RelayPolicy,
+    commitments: Option<Arc<dyn CommitmentHook>>,
+    key_aggregation: bool,
+    anchors: Arc<Vec<Anchor>>,
+    path: Vec<String>,
+    effects: Arc<MapEffectDB>,
+    lazy: Option<Arc<LazyConfig>>,
+    /// how many levels below the contract compiled, or an expanded one
+    depth: usize,
+    threads: Option<Arc<rayon::ThreadPool>>,
+    memo: Option<MemoCache>,
 }
 
 impl Context {
@@ -29,8 +64,261 @@ impl Context {
     pub fn new(network: Network, amount: Amount, emulator: Arc<dyn CTVEmulator>) -> Self {
         Context {
             available_funds: amount,
+            reserved: Amount::from_sat(0),
             emulator: emulator,
             network,
+            target: CompileTarget::SegwitV0,
+            covenant: Arc::new(covenant::Ctv),
+            max_fee_bps: analysis::DEFAULT_MAX_FEE_BPS,
+            relay_policy: RelayPolicy::Standard,
+            commitments: None,
+            key_aggregation: false,
+            anchors: Arc::new(vec![]),
+            path: vec![],
+            effects: Arc::new(MapEffectDB::new()),
+            lazy: None,
+            depth: 0,
+            threads: None,
+            memo: None,
+        }
+    }
+
+    /// return a context compiling to `target` rather than segwit v0
+    pub fn with_target(&self, target: CompileTarget) -> Self {
+        Context {
+            target,
+            ..self.clone()
+        }
+    }
+
+    /// what contracts are compiled to
+    pub fn target(&self) -> CompileTarget {
+        self.target
+    }
+
+    /// return a context committing to templates with `covenant` rather than
+    /// CTV, see `covenant`
+    pub fn with_covenant(&self, covenant: Arc<dyn CovenantBackend>) -> Self {
+        Context {
+            covenant,
+            ..self.clone()
+        }
+    }
+
+    /// how contracts commit to their templates
+    pub fn covenant(&self) -> &dyn CovenantBackend {
+        self.covenant.as_ref()
+    }
+
+    /// return a context warning about templates paying fees above `bps`
+    /// basis points of their value, see `analysis`
+    pub fn with_max_fee_bps(&self, bps: u64) -> Self {
+        Context {
+            max_fee_bps: bps,
+            ..self.clone()
+        }
+    }
+
+    /// fees above this many basis points of a template's value are warned
+    /// about
+    pub fn max_fee_bps(&self) -> u64 {
+        self.max_fee_bps
+    }
+
+    /// return a context checking contracts against `policy` rather than the
+    /// default relay policy, see `standard`
+    pub fn with_relay_policy(&self, policy: RelayPolicy) -> Self {
+        Context {
+            relay_policy: policy,
+            ..self.clone()
+        }
+    }
+
+    /// which limits contracts are checked against
+    pub fn relay_policy(&self) -> RelayPolicy {
+        self.relay_policy
+    }
+
+    /// return a context calling `hook` for the commitments of each output of
+    /// each template, see `template::commitment`
+    pub fn with_commitment_hook(&self, hook: Arc<dyn CommitmentHook>) -> Self {
+        Context {
+            commitments: Some(hook),
+            ..self.clone()
+        }
+    }
+
+    /// the hook making overlay protocols' commitments, if any
+    pub fn commitment_hook(&self) -> Option<&dyn CommitmentHook> {
+        self.commitments.as_deref()
+    }
+
+    /// Return a context merging the guards of each branch which only check
+    /// keys into a single key, their MuSig2 aggregate (see
+    /// `sapio_base::taproot::aggregate_keys`), when compiling to taproot. A
+    /// branch is then spent with one signature rather than one for each key,
+    /// which the keys' holders must make together. A branch of only keys may
+    /// also be spent with the key path, see `sapio_base::taproot`.
+    /// Segwit v0 outputs are unaffected, as ECDSA keys can't be aggregated,
+    /// and miniscript checks each key no less cheaply than `multi()` would.
+    pub fn with_key_aggregation(&self) -> Self {
+        Context {
+            key_aggregation: true,
+            ..self.clone()
+        }
+    }
+
+    /// return a context adding `anchors` to every template, see
+    /// `template::anchor`
+    pub fn with_anchors(&self, anchors: Vec<Anchor>) -> Self {
+        Context {
+            anchors: Arc::new(anchors),
+            ..self.clone()
+        }
+    }
+
+    /// the anchors added to every template
+    pub fn anchors(&self) -> &[Anchor] {
+        &self.anchors
+    }
+
+    /// return a context holding back the value of the anchors from the funds
+    /// available, so a template spending all of them still has room for the
+    /// anchors `Builder::finish` adds
+    pub(crate) fn reserve_anchors(&self) -> Result<Self, CompilationError> {
+        let mut ctx = self.clone();
+        ctx.release_anchors();
+        let reserved = self
+            .anchors
+            .iter()
+            .fold(Amount::from_sat(0), |total, anchor| total + anchor.amount());
+        ctx.spend_amount(reserved)?;
+        ctx.reserved = reserved;
+        Ok(ctx)
+    }
+
+    /// make the funds held back by `reserve_anchors` available again
+    pub(crate) fn release_anchors(&mut self) {
+        self.available_funds += self.reserved;
+        self.reserved = Amount::from_sat(0);
+    }
+
+    /// are guards merged by key aggregation, see `with_key_aggregation`
+    pub fn key_aggregation(&self) -> bool {
+        self.key_aggregation && self.target == CompileTarget::Taproot
+    }
+
+    /// return a context compiling with `effects`, see `effects`
+    pub fn with_effects(&self, effects: Arc<MapEffectDB>) -> Self {
+        Context {
+            effects,
+            ..self.clone()
+        }
+    }
+
+    /// the effects supplied for the contract's continuation points
+    pub fn effects(&self) -> &MapEffectDB {
+        &self.effects
+    }
+
+    /// return a context for compiling what `segment` of the current contract
+    /// creates, e.g. a function's name or an output's index
+    pub fn derive(&self, segment: &str) -> Self {
+        let mut path = self.path.clone();
+        path.push(segment.into());
+        Context {
+            path,
+            ..self.clone()
+        }
+    }
+
+    /// the path from the contract compiled to the current one, joined with
+    /// `/`, see `effects`
+    pub fn path(&self) -> String {
+        self.path.join("/")
+    }
+
+    /// Return a context compiling the outputs added together with
+    /// `Builder::add_outputs` on up to `threads` threads. As each may call
+    /// the emulator, this bounds the requests made to it at once.
+    pub fn with_threads(&self, threads: usize) -> Result<Self, CompilationError> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .map_err(CompilationError::custom)?;
+        Ok(Context {
+            threads: Some(Arc::new(pool)),
+            ..self.clone()
+        })
+    }
+
+    /// the threads outputs are compiled on, if compiling in parallel
+    pub(crate) fn thread_pool(&self) -> Option<&rayon::ThreadPool> {
+        self.threads.as_deref()
+    }
+
+    /// return a context caching the objects `memo::Memo` contracts compile
+    /// to, shared by the contexts derived from it
+    pub fn with_memoization(&self) -> Self {
+        Context {
+            memo: Some(Default::default()),
+            ..self.clone()
+        }
+    }
+
+    /// the cache to memoize compilations in, unless compiling with effects
+    /// or lazily, see `memo`
+    pub(crate) fn memo_cache(&self) -> Option<&MemoCache> {
+        if self.lazy.is_some() || !self.effects.is_empty() {
+            None
+        } else {
+            self.memo.as_ref()
+        }
+    }
+
+    /// return a context pruning contracts created `depth` or more levels
+    /// below the one compiled, see `lazy`
+    pub fn with_lazy_depth(&self, depth: usize) -> Self {
+        let mut config = self.lazy.as_deref().cloned().unwrap_or_default();
+        config.depth = depth;
+        Context {
+            lazy: Some(Arc::new(config)),
+            ..self.clone()
+        }
+    }
+
+    /// return a context expanding the contract pruned as `token`, see `lazy`
+    pub fn with_expanded(&self, token: &LazyToken) -> Self {
+        let mut config = self.lazy.as_deref().cloned().unwrap_or_default();
+        config.expanded.insert(token.path.clone());
+        Context {
+            lazy: Some(Arc::new(config)),
+            ..self.clone()
+        }
+    }
+
+    /// return a context for compiling the contract created by the output at
+    /// `idx`, which `prune` may then be called with
+    pub(crate) fn output(&self, idx: usize) -> Self {
+        let mut ctx = self.derive(&idx.to_string());
+        ctx.depth = match self.lazy.as_deref() {
+            Some(config) if config.is_expanded(&ctx.path()) => 0,
+            _ => self.depth + 1,
+        };
+        ctx
+    }
+
+    /// prunes `object`, compiled with this context, if it is deep enough,
+    /// see `lazy`
+    pub(crate) fn prune(&self, object: &mut Compiled) {
+        if let Some(config) = self.lazy.as_deref() {
+            let path = self.path();
+            if self.depth >= config.depth
+                && !config.is_expanded(&path)
+                && !config.leads_to_expanded(&path)
+            {
+                lazy::prune(object, path);
+            }
         }
     }
 
@@ -60,8 +348,8 @@ impl Context {
         } else {
             Ok(Context {
                 available_funds: amount,
-                emulator: self.emulator.clone(),
-                ..*self
+                reserved: Amount::from_sat(0),
+                ..self.clone()
             })
         }
     }
@@ -75,6 +363,12 @@ impl Context {
         }
     }
 
+    /// return a plan dividing the available funds into named budgets, see
+    /// `budget`
+    pub fn budgets(&self) -> Budgets {
+        Budgets::new(self)
+    }
+
     /// Add funds to the context object (not typically needed)
     pub fn add_amount(&mut self, amount: Amount) {
         self.available_funds += amount;
@@ -82,10 +376,7 @@ impl Context {
 
     /// Get a template builder from this context object
     pub fn template(&self) -> crate::template::Builder {
-        crate::template::Builder::new(Context {
-            emulator: self.emulator.clone(),
-            ..*self
-        })
+        crate::template::Builder::new(self.clone())
     }
 
     /// converts a descriptor and an optional AmountRange to a Object object.
@@ -100,6 +391,11 @@ impl Context {
             policy: None,
             address: d.address(bitcoin::Network::Bitcoin).unwrap().into(),
             descriptor: Some(d),
+            taproot: None,
+            warnings: vec![],
+            continue_points: BTreeMap::new(),
+            spend_paths: BTreeMap::new(),
+            lazy: None,
             amount_range: a.unwrap_or_else(|| {
                 let mut a = AmountRange::new();
                 a.update_range(Amount::min_value());
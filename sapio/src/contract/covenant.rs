@@ -0,0 +1,404 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Covenant backends: how a contract's scripts commit to its templates, so
+//! that proposals other than CTV can be evaluated side by side.
+//!
+//! A backend is selected per compilation (see `Context::with_covenant`), and
+//! is used for the templates the emulator doesn't stub out with a signature
+//! (see `CTVAvailable`). Other than `Ctv`, backends can't be expressed in
+//! miniscript, so contracts using them must be compiled to taproot (see
+//! `CompileTarget`), where each leaf checking a template uses the backend's
+//! script for it. The backends other than `Ctv` use opcodes (or, for `Apo`,
+//! signature hash types) which are only proposed, so their scripts are for
+//! comparing costs and tradeoffs, not for use on any network.
+//!
+//! There is no backend for OP_CHECKSIGFROMSTACK (BIP-348) alone: it checks a
+//! signature of a message on the stack, and without OP_CAT the script can't
+//! build the spending transaction's message to check, see `Cat`.
+use crate::template::Template;
+use bitcoin::blockdata::opcodes::{self, all::*};
+use bitcoin::blockdata::script::Builder;
+use bitcoin::consensus::encode::Encodable;
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::{Hash, HashEngine};
+use bitcoin::secp256k1::{schnorrsig, Message, Secp256k1};
+use sapio_base::taproot::{CheckTemplateVerify, TaprootError, TemplateCheck};
+use std::collections::HashMap;
+
+/// OP_CAT, as proposed to be re-enabled in tapscript (formerly OP_SUCCESS126)
+const OP_CAT: u8 = 0x7e;
+/// OP_CHECKTEMPLATEVERIFY from BIP-119
+const OP_CHECKTEMPLATEVERIFY: u8 = 0xb3;
+/// OP_VAULT from BIP-345 (formerly OP_SUCCESS187)
+const OP_VAULT: u8 = 0xbb;
+/// SIGHASH_ANYPREVOUTANYSCRIPT | SIGHASH_ALL from BIP-118
+const SIGHASH_ANYPREVOUTANYSCRIPT_ALL: u8 = 0xc1;
+/// the secret key of G
+const ONE: [u8; 32] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1,
+];
+/// the x coordinate of the secp256k1 generator G
+const G_X: [u8; 32] = [
+    0x79, 0xbe, 0x66, 0x7e, 0xf9, 0xdc, 0xbb, 0xac, 0x55, 0xa0, 0x62, 0x95, 0xce, 0x87, 0x0b, 0x07,
+    0x02, 0x9b, 0xfc, 0xdb, 0x2d, 0xce, 0x28, 0xd9, 0x59, 0xf2, 0x81, 0x5b, 0x16, 0xf8, 0x17, 0x98,
+];
+
+/// How scripts commit to templates, see the module docs
+pub trait CovenantBackend: Send + Sync {
+    /// the backend's name, e.g. for manifests
+    fn name(&self) -> &'static str;
+    /// if miniscript's CTV fragment checks templates as this backend does, so
+    /// contracts may be compiled to segwit v0
+    fn segwit_v0(&self) -> bool {
+        false
+    }
+    /// Appends the check that the spending transaction is `template` to `b`,
+    /// as `TemplateCheck::push_template`.
+    fn push_template(
+        &self,
+        b: Builder,
+        template: &Template,
+        verify: bool,
+    ) -> Result<Builder, TaprootError>;
+}
+
+/// Checks templates with OP_CHECKTEMPLATEVERIFY (BIP-119), the default
+pub struct Ctv;
+
+impl CovenantBackend for Ctv {
+    fn name(&self) -> &'static str {
+        "ctv"
+    }
+    fn segwit_v0(&self) -> bool {
+        true
+    }
+    fn push_template(
+        &self,
+        b: Builder,
+        template: &Template,
+        verify: bool,
+    ) -> Result<Builder, TaprootError> {
+        CheckTemplateVerify.push_template(b, &template.hash(), verify)
+    }
+}
+
+/// Checks templates with OP_CAT and the Schnorr signature trick: with the
+/// key and nonce both G, a signature's s is its challenge plus one, so the
+/// script can build the signature from the transaction's BIP-341 signature
+/// message and check it against the transaction.
+///
+/// The spender supplies the message, as the bytes before and after the hash
+/// of the outputs (which the script fills in from the template), and the
+/// challenge without its last byte, grinding the transaction (e.g. its
+/// locktime) until that byte is zero so that adding one doesn't carry. Only
+/// the template's outputs are committed to, unlike CTV which also commits to
+/// e.g. its version, locktime, and sequences.
+///
+/// Witness: `<challenge[..31]> <suffix> <prefix>`
+pub struct Cat;
+
+impl Cat {
+    /// sha256(tag) || sha256(tag), as BIP-340 tagged hashes begin
+    fn tag(tag: &str) -> Vec<u8> {
+        let tag = sha256::Hash::hash(tag.as_bytes());
+        let mut v = tag.into_inner().to_vec();
+        v.extend_from_slice(&tag[..]);
+        v
+    }
+
+    /// the hash of `template`'s outputs, as in BIP-341 signature messages
+    fn sha_outputs(template: &Template) -> sha256::Hash {
+        let mut engine = sha256::Hash::engine();
+        for output in template.tx.output.iter() {
+            output
+                .consensus_encode(&mut engine)
+                .expect("engines do not fail");
+        }
+        sha256::Hash::from_engine(engine)
+    }
+}
+
+impl CovenantBackend for Cat {
+    fn name(&self) -> &'static str {
+        "cat"
+    }
+    fn push_template(
+        &self,
+        b: Builder,
+        template: &Template,
+        verify: bool,
+    ) -> Result<Builder, TaprootError> {
+        let cat = opcodes::All::from(OP_CAT);
+        // the signature message is the epoch, then prefix || sha_outputs || suffix
+        let mut sighash = Cat::tag("TapSighash");
+        sighash.push(0x00);
+        // the challenge commits to the nonce and key, both G
+        let mut challenge = Cat::tag("BIP0340/challenge");
+        challenge.extend_from_slice(&G_X[..]);
+        challenge.extend_from_slice(&G_X[..]);
+        let b = b
+            .push_slice(&Cat::sha_outputs(template)[..])
+            .push_opcode(cat)
+            .push_opcode(OP_SWAP)
+            .push_opcode(cat)
+            .push_slice(&sighash[..])
+            .push_opcode(OP_SWAP)
+            .push_opcode(cat)
+            .push_opcode(OP_SHA256)
+            .push_slice(&challenge[..])
+            .push_opcode(OP_SWAP)
+            .push_opcode(cat)
+            .push_opcode(OP_SHA256)
+            // check the challenge ends in zero, so s ends in one
+            .push_opcode(OP_OVER)
+            .push_slice(&[0x00])
+            .push_opcode(cat)
+            .push_opcode(OP_EQUALVERIFY)
+            .push_slice(&G_X[..])
+            .push_opcode(OP_SWAP)
+            .push_opcode(cat)
+            .push_opcode(OP_PUSHNUM_1)
+            .push_opcode(cat)
+            .push_slice(&G_X[..]);
+        Ok(b.push_opcode(match verify {
+            true => OP_CHECKSIGVERIFY,
+            false => OP_CHECKSIG,
+        }))
+    }
+}
+
+/// Checks templates with OP_VAULT (BIP-345): spending triggers an unvault,
+/// moving the funds (less any revaulted) to an output whose leaf is this one
+/// with the check replaced by `<template> OP_CHECKTEMPLATEVERIFY`. Spending
+/// to a template thus takes two transactions rather than one, and must be
+/// paired with an OP_VAULT_RECOVER leaf (e.g. a `finish!` function) for the
+/// unvault to be recoverable.
+///
+/// Witness: `<revault-amount> <revault-vout-idx> <trigger-vout-idx>`
+pub struct Vault;
+
+impl CovenantBackend for Vault {
+    fn name(&self) -> &'static str {
+        "vault"
+    }
+    fn push_template(
+        &self,
+        b: Builder,
+        template: &Template,
+        verify: bool,
+    ) -> Result<Builder, TaprootError> {
+        let b = b
+            .push_slice(&template.hash()[..])
+            .push_opcode(OP_PUSHNUM_1)
+            .push_slice(&[OP_CHECKTEMPLATEVERIFY])
+            .push_opcode(opcodes::All::from(OP_VAULT));
+        Ok(match verify {
+            true => b.push_opcode(OP_DROP),
+            false => b,
+        })
+    }
+}
+
+/// Checks templates with SIGHASH_ANYPREVOUTANYSCRIPT (BIP-118): the script
+/// holds a signature of the template by G, whose secret is one, so anyone
+/// can make it but only before the script exists. The signature hash type
+/// leaves out the coin spent and the script, so the signature is valid for
+/// any coin paying to the script, spent by exactly the template's version,
+/// locktime, outputs, and the sequence of its input.
+///
+/// Witness: none
+pub struct Apo;
+
+impl Apo {
+    /// the BIP-118 signature message of spending the template's input
+    fn sighash(template: &Template) -> Result<sha256::Hash, TaprootError> {
+        let input = template
+            .tx
+            .input
+            .get(template.ctv_index as usize)
+            .ok_or_else(|| TaprootError::Unsupported("template has no such input".into()))?;
+        let mut engine = sha256::Hash::engine();
+        engine.input(&Cat::tag("TapSighash"));
+        // the epoch, then the hash type
+        engine.input(&[0x00, SIGHASH_ANYPREVOUTANYSCRIPT_ALL]);
+        template
+            .tx
+            .version
+            .consensus_encode(&mut engine)
+            .expect("engines do not fail");
+        template
+            .tx
+            .lock_time
+            .consensus_encode(&mut engine)
+            .expect("engines do not fail");
+        engine.input(&Cat::sha_outputs(template)[..]);
+        // the spend type, a script path without an annex
+        engine.input(&[0x02]);
+        input
+            .sequence
+            .consensus_encode(&mut engine)
+            .expect("engines do not fail");
+        // the key version, then the position of the last OP_CODESEPARATOR
+        engine.input(&[0x01]);
+        engine.input(&u32::MAX.to_le_bytes());
+        Ok(sha256::Hash::from_engine(engine))
+    }
+
+    /// the signature of `template` by G, with its hash type
+    fn signature(template: &Template) -> Result<Vec<u8>, TaprootError> {
+        let secp = Secp256k1::signing_only();
+        let keypair =
+            schnorrsig::KeyPair::from_seckey_slice(&secp, &ONE).expect("one is a valid secret key");
+        let msg =
+            Message::from_slice(&Apo::sighash(template)?[..]).expect("hashes are valid messages");
+        let mut sig = secp.schnorrsig_sign_no_aux_rand(&msg, &keypair)[..].to_vec();
+        sig.push(SIGHASH_ANYPREVOUTANYSCRIPT_ALL);
+        Ok(sig)
+    }
+}
+
+impl CovenantBackend for Apo {
+    fn name(&self) -> &'static str {
+        "apo"
+    }
+    fn push_template(
+        &self,
+        b: Builder,
+        template: &Template,
+        verify: bool,
+    ) -> Result<Builder, TaprootError> {
+        // a BIP-118 key is G's x coordinate, prefixed with its key type
+        let mut key = vec![0x01];
+        key.extend_from_slice(&G_X[..]);
+        let b = b
+            .push_slice(&Apo::signature(template)?[..])
+            .push_slice(&key[..]);
+        Ok(b.push_opcode(match verify {
+            true => OP_CHECKSIGVERIFY,
+            false => OP_CHECKSIG,
+        }))
+    }
+}
+
+/// Checks the templates of a contract being compiled with a backend
+pub(crate) struct Templates<'a> {
+    pub backend: &'a dyn CovenantBackend,
+    pub templates: &'a HashMap<sha256::Hash, Template>,
+}
+
+impl<'a> TemplateCheck for Templates<'a> {
+    fn push_template(
+        &self,
+        b: Builder,
+        h: &sha256::Hash,
+        verify: bool,
+    ) -> Result<Builder, TaprootError> {
+        let template = self
+            .templates
+            .get(h)
+            .ok_or_else(|| TaprootError::Unsupported(format!("unknown template {}", h)))?;
+        self.backend.push_template(b, template, verify)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::{Compilable, CompileTarget};
+    use crate::fixtures::*;
+    use bitcoin::blockdata::script::Instruction;
+    use bitcoin::util::amount::Amount;
+    use std::sync::Arc;
+
+    #[test]
+    fn covenant_backends() {
+        let contract = TestEmulation {
+            to_contract: to(),
+            amount: Amount::from_btc(1.0).unwrap(),
+            timeout: 6,
+        };
+        let ctx = ctx(1.0);
+        let backends: Vec<(Arc<dyn CovenantBackend>, u8)> = vec![
+            (Arc::new(Ctv), 0xb3),
+            (Arc::new(Cat), 0x7e),
+            (Arc::new(Vault), 0xbb),
+            (Arc::new(Apo), 0xac),
+        ];
+        let mut scripts = vec![];
+        for (backend, opcode) in backends {
+            let ctx = ctx.with_covenant(backend.clone());
+            // only CTV can be expressed in miniscript
+            let segwit_v0 = contract.compile(&ctx);
+            assert_eq!(segwit_v0.is_ok(), backend.segwit_v0());
+            if let Err(e) = segwit_v0 {
+                assert_eq!(e.code(), "unsupported-covenant");
+            }
+            let compiled = contract
+                .compile(&ctx.with_target(CompileTarget::Taproot))
+                .unwrap();
+            let taproot = compiled.taproot.as_ref().unwrap();
+            // the same template, checked by the backend's script
+            assert_eq!(compiled.ctv_to_tx.len(), 1);
+            for h in compiled.ctv_to_tx.keys() {
+                let leaf = taproot.leaf_for(h).unwrap();
+                assert!(leaf.script.as_bytes().contains(&opcode));
+                scripts.push(leaf.script.clone());
+            }
+        }
+        scripts.dedup();
+        assert_eq!(scripts.len(), 4);
+    }
+
+    #[test]
+    fn apo_signs_template() {
+        let contract = TestEmulation {
+            to_contract: to(),
+            amount: Amount::from_btc(1.0).unwrap(),
+            timeout: 6,
+        };
+        let ctx = ctx(1.0)
+            .with_covenant(Arc::new(Apo))
+            .with_target(CompileTarget::Taproot);
+        let compiled = contract.compile(&ctx).unwrap();
+        let taproot = compiled.taproot.as_ref().unwrap();
+        let secp = Secp256k1::verification_only();
+        let g = schnorrsig::PublicKey::from_slice(&G_X[..]).unwrap();
+        for (h, template) in compiled.ctv_to_tx.iter() {
+            let leaf = taproot.leaf_for(h).unwrap();
+            let pushes: Vec<Vec<u8>> = leaf
+                .script
+                .instructions()
+                .filter_map(|i| match i {
+                    Ok(Instruction::PushBytes(b)) => Some(b.to_vec()),
+                    _ => None,
+                })
+                .collect();
+            let sig = pushes.iter().find(|p| p.len() == 65).unwrap();
+            assert!(pushes
+                .iter()
+                .any(|p| p[..] == [&[0x01][..], &G_X[..]].concat()[..]));
+            // a valid signature by G of the template, for any coin
+            assert_eq!(sig[64], SIGHASH_ANYPREVOUTANYSCRIPT_ALL);
+            let msg = Message::from_slice(&Apo::sighash(template).unwrap()[..]).unwrap();
+            let signature = schnorrsig::Signature::from_slice(&sig[..64]).unwrap();
+            assert!(secp.schnorrsig_verify(&signature, &msg, &g).is_ok());
+            // of no other spend, though of any coin
+            let mut other = template.clone();
+            other.tx.lock_time += 1;
+            assert_ne!(
+                Apo::sighash(&other).unwrap(),
+                Apo::sighash(template).unwrap()
+            );
+            let mut other = template.clone();
+            other.tx.input[0].previous_output.vout += 1;
+            assert_eq!(
+                Apo::sighash(&other).unwrap(),
+                Apo::sighash(template).unwrap()
+            );
+        }
+    }
+}
@@ -0,0 +1,401 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Diffing two compiled objects, e.g. a contract compiled with different
+//! arguments or by a different version, so a reviewer can audit what an
+//! update changes.
+//!
+//! As any change alters a template's hash, `diff` matches the templates of
+//! the two trees by where they are instead: by the spend path (see
+//! `SpendPath`) creating them and their label (see `Builder::set_label`),
+//! under the output of the template before them. Unlabeled templates are
+//! matched by their order in their spend path, or, outside of any, by lock
+//! time, sequences and amounts, so labelling templates makes a contract's
+//! diffs more precise. A branch is named by these steps, e.g.
+//! `rule:rule for buyer/0/enforce:refund` for the template labelled
+//! `refund`, in the `enforce` path of the contract created by output 0 of
+//! the template labelled `rule for buyer`, in the `rule` path.
+//!
+//! Matched templates are compared output by output, and each contract in the
+//! trees by the timelocks its policy requires.
+use super::Compiled;
+use crate::template::Template;
+use bitcoin::hashes::sha256;
+use bitcoin::util::amount::Amount;
+use bitcoin::Script;
+use sapio_base::Clause;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+/// A difference between two compiled objects, see the module docs
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case", tag = "change")]
+pub enum Change {
+    /// a branch only the new object has
+    Added {
+        /// the branch
+        branch: String,
+    },
+    /// a branch only the old object has
+    Removed {
+        /// the branch
+        branch: String,
+    },
+    /// the branch's lock time changed
+    LockTime {
+        /// the branch
+        branch: String,
+        /// the old lock time
+        old: u32,
+        /// the new lock time
+        new: u32,
+    },
+    /// the branch's inputs' sequences changed
+    Sequences {
+        /// the branch
+        branch: String,
+        /// the old sequences
+        old: Vec<u32>,
+        /// the new sequences
+        new: Vec<u32>,
+    },
+    /// the branch has a different number of outputs
+    Outputs {
+        /// the branch
+        branch: String,
+        /// the old number of outputs
+        old: usize,
+        /// the new number of outputs
+        new: usize,
+    },
+    /// an output of the branch pays a different amount
+    Amount {
+        /// the branch
+        branch: String,
+        /// the output
+        output: usize,
+        /// the old amount
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        old: Amount,
+        /// the new amount
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        new: Amount,
+    },
+    /// an output of the branch, where funds leave the contract, pays a
+    /// different script
+    Address {
+        /// the branch
+        branch: String,
+        /// the output
+        output: usize,
+        /// the old script
+        old: Script,
+        /// the new script
+        new: Script,
+    },
+    /// the timelocks a contract's policy requires changed
+    Timelocks {
+        /// the branch whose output creates the contract (`branch/output`),
+        /// empty for the objects themselves
+        contract: String,
+        /// the old timelocks
+        old: Vec<Clause>,
+        /// the new timelocks
+        new: Vec<Clause>,
+    },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let clauses = |cs: &[Clause]| {
+            cs.iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        match self {
+            Change::Added { branch } => write!(f, "+ {}", branch),
+            Change::Removed { branch } => write!(f, "- {}", branch),
+            Change::LockTime { branch, old, new } => {
+                write!(f, "~ {}: lock time {} -> {}", branch, old, new)
+            }
+            Change::Sequences { branch, old, new } => {
+                write!(f, "~ {}: sequences {:?} -> {:?}", branch, old, new)
+            }
+            Change::Outputs { branch, old, new } => {
+                write!(f, "~ {}: {} outputs -> {}", branch, old, new)
+            }
+            Change::Amount {
+                branch,
+                output,
+                old,
+                new,
+            } => write!(f, "~ {}: output {} pays {} -> {}", branch, output, old, new),
+            Change::Address {
+                branch,
+                output,
+                old,
+                new,
+            } => write!(
+                f,
+                "~ {}: output {} pays {:x} -> {:x}",
+                branch, output, old, new
+            ),
+            Change::Timelocks { contract, old, new } => write!(
+                f,
+                "~ {}: timelocks [{}] -> [{}]",
+                if contract.is_empty() {
+                    "(root)"
+                } else {
+                    contract.as_str()
+                },
+                clauses(old),
+                clauses(new)
+            ),
+        }
+    }
+}
+
+/// The branches, and contracts, of an object's tree by name
+#[derive(Default)]
+struct Tree<'a> {
+    branches: BTreeMap<String, &'a Template>,
+    contracts: BTreeMap<String, &'a Compiled>,
+}
+
+impl<'a> Tree<'a> {
+    fn new(object: &'a Compiled) -> Self {
+        let mut tree = Tree::default();
+        tree.walk(object, "");
+        tree
+    }
+
+    /// names the templates of `object`, created at `prefix`
+    fn walk(&mut self, object: &'a Compiled, prefix: &str) {
+        self.contracts.insert(prefix.into(), object);
+        let mut in_path: HashMap<sha256::Hash, (&str, usize)> = HashMap::new();
+        for (name, path) in object.spend_paths.iter() {
+            for (i, h) in path.templates.iter().enumerate() {
+                in_path.insert(*h, (name.as_str(), i));
+            }
+        }
+        let mut outside: Vec<&Template> = object
+            .ctv_to_tx
+            .values()
+            .chain(object.suggested_txs.values())
+            .filter(|t| !in_path.contains_key(&t.hash()))
+            .collect();
+        outside.sort_by_key(|t| {
+            (
+                t.tx.lock_time,
+                t.tx.input.iter().map(|i| i.sequence).collect::<Vec<_>>(),
+                t.outputs.iter().map(|o| o.amount).collect::<Vec<_>>(),
+                t.hash(),
+            )
+        });
+        let mut templates: Vec<(&str, usize, &Template)> = outside
+            .into_iter()
+            .enumerate()
+            .map(|(i, t)| ("-", i, t))
+            .collect();
+        for t in object
+            .ctv_to_tx
+            .values()
+            .chain(object.suggested_txs.values())
+        {
+            if let Some((name, i)) = in_path.get(&t.hash()) {
+                templates.push((*name, *i, t));
+            }
+        }
+        templates.sort_by_key(|(name, i, _)| (*name, *i));
+        let mut seen: HashMap<String, usize> = HashMap::new();
+        for (name, i, t) in templates {
+            let step = match t.metadata_map_s2s.label() {
+                Some(label) => format!("{}:{}", name, label),
+                None => format!("{}:#{}", name, i),
+            };
+            // templates with the same label in a path, told apart by order
+            let count = seen.entry(step.clone()).or_insert(0);
+            let step = if *count > 0 {
+                format!("{}#{}", step, count)
+            } else {
+                step
+            };
+            *count += 1;
+            let branch = if prefix.is_empty() {
+                step
+            } else {
+                format!("{}/{}", prefix, step)
+            };
+            for (idx, output) in t.outputs.iter().enumerate() {
+                self.walk(&output.contract, &format!("{}/{}", branch, idx));
+            }
+            self.branches.insert(branch, t);
+        }
+    }
+}
+
+/// the timelocks `clause` requires, in a canonical order
+fn timelocks(clause: &Option<Clause>) -> Vec<Clause> {
+    fn walk(c: &Clause, out: &mut Vec<Clause>) {
+        match c {
+            Clause::After(_) | Clause::Older(_) => out.push(c.clone()),
+            Clause::And(subs) | Clause::Threshold(_, subs) => {
+                subs.iter().for_each(|s| walk(s, out))
+            }
+            Clause::Or(subs) => subs.iter().for_each(|(_, s)| walk(s, out)),
+            _ => {}
+        }
+    }
+    let mut out = vec![];
+    if let Some(c) = clause {
+        walk(c, &mut out);
+    }
+    out.sort_by_key(|c| c.to_string());
+    out.dedup();
+    out
+}
+
+/// does `object` create no further templates, so funds leave the contract
+fn is_leaf(object: &Compiled) -> bool {
+    object.ctv_to_tx.is_empty() && object.suggested_txs.is_empty()
+}
+
+/// The differences from `old` to `new`, by branch, see the module docs.
+pub fn diff(old: &Compiled, new: &Compiled) -> Vec<Change> {
+    let (old, new) = (Tree::new(old), Tree::new(new));
+    let mut changes = vec![];
+    for (branch, o) in old.branches.iter() {
+        let n = match new.branches.get(branch) {
+            Some(n) => n,
+            None => {
+                changes.push(Change::Removed {
+                    branch: branch.clone(),
+                });
+                continue;
+            }
+        };
+        if o.tx.lock_time != n.tx.lock_time {
+            changes.push(Change::LockTime {
+                branch: branch.clone(),
+                old: o.tx.lock_time,
+                new: n.tx.lock_time,
+            });
+        }
+        let sequences = |t: &Template| t.tx.input.iter().map(|i| i.sequence).collect::<Vec<_>>();
+        if sequences(o) != sequences(n) {
+            changes.push(Change::Sequences {
+                branch: branch.clone(),
+                old: sequences(o),
+                new: sequences(n),
+            });
+        }
+        if o.outputs.len() != n.outputs.len() {
+            changes.push(Change::Outputs {
+                branch: branch.clone(),
+                old: o.outputs.len(),
+                new: n.outputs.len(),
+            });
+        }
+        for (output, (oo, no)) in o.outputs.iter().zip(n.outputs.iter()).enumerate() {
+            if oo.amount != no.amount {
+                changes.push(Change::Amount {
+                    branch: branch.clone(),
+                    output,
+                    old: oo.amount,
+                    new: no.amount,
+                });
+            }
+            let (os, ns): (Script, Script) = (
+                oo.contract.address.clone().into(),
+                no.contract.address.clone().into(),
+            );
+            if (is_leaf(&oo.contract) || is_leaf(&no.contract)) && os != ns {
+                changes.push(Change::Address {
+                    branch: branch.clone(),
+                    output,
+                    old: os,
+                    new: ns,
+                });
+            }
+        }
+    }
+    for branch in new.branches.keys() {
+        if !old.branches.contains_key(branch) {
+            changes.push(Change::Added {
+                branch: branch.clone(),
+            });
+        }
+    }
+    for (contract, o) in old.contracts.iter() {
+        if let Some(n) = new.contracts.get(contract) {
+            let (ol, nl) = (timelocks(&o.policy), timelocks(&n.policy));
+            if ol != nl {
+                changes.push(Change::Timelocks {
+                    contract: contract.clone(),
+                    old: ol,
+                    new: nl,
+                });
+            }
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::Compilable;
+    use crate::fixtures::*;
+
+    #[test]
+    fn diff_fans() {
+        let ctx = ctx(1.0);
+        let fan = |n| {
+            Fan {
+                n,
+                amount: Amount::from_sat(3000),
+                to: address(),
+            }
+            .compile(&ctx)
+            .unwrap()
+        };
+        let (two, three) = (fan(2), fan(3));
+        assert!(diff(&two, &two).is_empty());
+        let changes = diff(&two, &three);
+        assert!(changes.contains(&Change::Outputs {
+            branch: "-:#0".into(),
+            old: 2,
+            new: 3,
+        }));
+        assert!(changes.contains(&Change::Amount {
+            branch: "-:#0".into(),
+            output: 0,
+            old: Amount::from_sat(1500),
+            new: Amount::from_sat(1000),
+        }));
+        // the third split's emulation
+        assert!(changes.contains(&Change::Added {
+            branch: "-:#0/2/-:#0".into(),
+        }));
+        // the splits kept have the same relative locks
+        assert!(!changes
+            .iter()
+            .any(|c| matches!(c, Change::Sequences { .. })));
+        let back = diff(&three, &two);
+        assert!(back.contains(&Change::Removed {
+            branch: "-:#0/2/-:#0".into(),
+        }));
+        // changes serialize tagged by kind, for tools
+        let json = serde_json::to_value(&changes).unwrap();
+        assert!(json
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|c| c["change"] == "added"));
+    }
+}
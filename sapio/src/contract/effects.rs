@@ -0,0 +1,146 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Effects: arguments supplied to a contract's `finish!` functions after it
+//! is funded, growing its tree of templates for interactive protocols (e.g.
+//! payment pools).
+//!
+//! Each `finish!` function is a continuation point, named by its path from
+//! the contract compiled: the names of the functions and the indexes of the
+//! outputs leading to it, joined with `/` (e.g. `"bisect/0/next_pool"`).
+//! Compiling a contract records its continuation points, and those of the
+//! contracts it creates, in `Object::continue_points`, with a JSON schema of
+//! their arguments if the contract declares them with `declare!{effects}`.
+//!
+//! Effects are stored in a `MapEffectDB`, as JSON, by point and by an id
+//! chosen by whoever supplies them. Compiling the same contract again with
+//! them (see `Context::with_effects`) calls each point's function with each
+//! effect's arguments, and grafts the templates returned into
+//! `suggested_txs` of the object at the point. As a `finish!` function's
+//! guards don't depend on its arguments, the contract's policy and address
+//! are unchanged, so the new templates can spend the funds already sent to
+//! it.
+use sapio_base::Clause;
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Effects for continuation points, see the module docs
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq)]
+pub struct MapEffectDB {
+    /// the arguments of each effect, by point and by id
+    effects: BTreeMap<String, BTreeMap<String, serde_json::Value>>,
+}
+
+impl MapEffectDB {
+    /// an empty effect database
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Records the effect `id` at `point`, replacing any effect with the same
+    /// id. Ids should not contain `/`, as they are part of the paths of the
+    /// contracts the effect creates.
+    pub fn insert(&mut self, point: String, id: String, args: serde_json::Value) {
+        self.effects.entry(point).or_default().insert(id, args);
+    }
+    /// if there are no effects
+    pub fn is_empty(&self) -> bool {
+        self.effects.is_empty()
+    }
+    /// the effects at `point`, by id
+    pub fn get(&self, point: &str) -> impl Iterator<Item = (&String, &serde_json::Value)> {
+        self.effects.get(point).into_iter().flatten()
+    }
+}
+
+/// A `finish!` function effects may be supplied to, see the module docs
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct ContinuationPoint {
+    /// a JSON schema of the arguments taken, if declared
+    pub schema: Option<serde_json::Value>,
+    /// the guards of the function, which transactions spending through the
+    /// point must satisfy (see `migration`)
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub guard: Option<Clause>,
+}
+
+/// How a contract reads its effects, declared with `declare!{effects}`
+pub struct EffectHandlers<T> {
+    /// reads an effect's arguments
+    pub decode: fn(serde_json::Value) -> Result<T, serde_json::Error>,
+    /// a JSON schema of the arguments
+    pub schema: fn() -> serde_json::Value,
+}
+
+/// reads arguments of type `T`, for `declare!{effects}`
+pub fn decode<T: DeserializeOwned>(args: serde_json::Value) -> Result<T, serde_json::Error> {
+    serde_json::from_value(args)
+}
+
+/// a JSON schema of `T`, for `declare!{effects}`
+pub fn schema<T: JsonSchema>() -> serde_json::Value {
+    serde_json::to_value(schemars::schema_for!(T)).unwrap_or(serde_json::Value::Null)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::Compilable;
+    use crate::fixtures::*;
+    use bitcoin::util::amount::Amount;
+    use bitcoin::Script;
+    use std::sync::Arc;
+
+    #[test]
+    fn effects() {
+        let contract = TestEmulation {
+            to_contract: Pool {
+                key: key(1),
+                to: address(),
+            },
+            amount: Amount::from_btc(1.0).unwrap(),
+            timeout: 6,
+        };
+        let ctx = ctx(1.0);
+        let compiled = contract.compile(&ctx).unwrap();
+        // the pool's continuation point is recorded with its path
+        let point = compiled.continue_points.get("complete/0/pay").unwrap();
+        assert!(point.schema.is_some());
+        assert_eq!(compiled.continue_points.len(), 1);
+
+        let mut effects = MapEffectDB::new();
+        effects.insert(
+            "complete/0/pay".into(),
+            "alice".into(),
+            serde_json::json!({ "sats": 1000 }),
+        );
+        let updated = contract
+            .compile(&ctx.with_effects(Arc::new(effects)))
+            .unwrap();
+        // the address is unchanged, with the payout grafted under the pool
+        assert_eq!(
+            Script::from(updated.address.clone()),
+            Script::from(compiled.address.clone())
+        );
+        let pool = &updated.ctv_to_tx.values().next().unwrap().outputs[0].contract;
+        assert_eq!(pool.suggested_txs.len(), 1);
+        let payout = pool.suggested_txs.values().next().unwrap();
+        assert_eq!(payout.outputs[0].amount, Amount::from_sat(1000));
+
+        // effects which can't be read fail to compile
+        let mut effects = MapEffectDB::new();
+        effects.insert(
+            "complete/0/pay".into(),
+            "bob".into(),
+            serde_json::json!({ "sats": "lots" }),
+        );
+        let e = contract
+            .compile(&ctx.with_effects(Arc::new(effects)))
+            .unwrap_err();
+        assert_eq!(e.code(), "invalid-effect");
+    }
+}
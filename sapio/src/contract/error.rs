@@ -7,8 +7,16 @@
 //! error types that can be returned from Sapio.
 //! Where possible, concrete error types are wrapped, but in order to handle
 //! errors created by the user we allow boxing an error trait.
+//!
+//! Errors returned by compiling a contract are `CompilationError::Located`,
+//! a `Diagnostic` naming the contract and `then!`/`finish!` function the
+//! error arose in, the path to it through the contracts enclosing it, and
+//! a stable, machine-readable code (see `CompilationError::code`) so that
+//! frontends can tell errors apart without parsing messages.
+use crate::contract::analysis::PathStep;
 use crate::contract::object::ObjectError;
 use sapio_ctv_emulator_trait::EmulatorError;
+use serde::{Serialize, Serializer};
 use std::collections::LinkedList;
 use std::error::Error;
 use std::fmt;
@@ -23,6 +31,21 @@ pub enum CompilationError {
     EmptyPolicy,
     /// Error if a contract does not have sufficient funds available
     OutOfFunds,
+    /// Error if a template can't pay the fees its feerate requires
+    InsufficientFees {
+        /// the fees required
+        required: bitcoin::util::amount::Amount,
+        /// the fees the template could pay
+        available: bitcoin::util::amount::Amount,
+    },
+    /// Error if a template paying its fees by CPFP has no anchor output
+    MissingAnchor,
+    /// Error if a template with an ephemeral anchor pays fees, which nodes
+    /// don't relay, see `template::anchor`
+    EphemeralAnchorFees {
+        /// the fees the template pays
+        fees: bitcoin::util::amount::Amount,
+    },
     /// Error if a CheckSequenceVerify clause is incompatible with the sequence already set.
     /// E.g., blocks and time
     IncompatibleSequence,
@@ -31,6 +54,8 @@ pub enum CompilationError {
     IncompatibleLockTime,
     /// Error if a sequence at index j >= inputs.len() is attempted to be set
     NoSuchSequence,
+    /// Error if an output at index j >= outputs.len() is attempted to be used
+    NoSuchOutput,
     /// Error if parsing an Amount failed
     ParseAmountError(bitcoin::util::amount::ParseAmountError),
     /// Error from the Policy Compiler
@@ -39,18 +64,198 @@ pub enum CompilationError {
     MiniscriptE(miniscript::Error),
     /// Error with a Timelock
     TimeLockError(sapio_base::timelocks::LockTimeError),
+    /// Error if a template's timelocks can never be met
+    TimelockConflict(crate::contract::analysis::TimelockConflict),
+    /// Error if a contract breaks a limit of its relay policy, see `standard`
+    Nonstandard(crate::contract::standard::Violation),
+    /// Error laying out a taproot output
+    Taproot(sapio_base::taproot::TaprootError),
+    /// Error if a contract compiled to segwit v0 commits to templates with a
+    /// covenant backend miniscript can't express, see `covenant`
+    UnsupportedCovenant(&'static str),
     /// Error creating an object,
     CompiledObjectError(ObjectError),
+    /// Error if an effect's arguments can't be read, see `effects`
+    InvalidEffect {
+        /// the continuation point
+        point: String,
+        /// the effect's id
+        id: String,
+        /// why they can't be read
+        error: serde_json::Error,
+    },
+    /// Error building a migration, see `migration`
+    Migration(crate::contract::migration::MigrationError),
+    /// Error splitting an amount between branches, see `util::split`
+    Split(crate::util::split::SplitError),
+    /// Error allocating or spending a budget, see `budget`
+    Budget(crate::contract::budget::BudgetError),
     /// Failure in conditional compilation logic
     ConditionalCompilationFailed(LinkedList<String>),
     /// Unknown Error type -- either from a user or from some unhandled dependency
-    Custom(Box<dyn std::error::Error>),
+    Custom(Box<dyn std::error::Error + Send + Sync>),
+    /// An error with where in the contract it arose, see the module docs
+    Located(Box<Diagnostic>),
 }
+
+/// A contract enclosing the one an error arose in
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    /// the contract's type name
+    pub contract: &'static str,
+    /// the function whose template creates the next contract, if known
+    pub clause: Option<&'static str>,
+    /// the output of that template creating the next contract, if known
+    pub output: Option<usize>,
+}
+
+/// Where in a contract an error arose, see the module docs
+#[derive(Serialize, Debug)]
+pub struct Diagnostic {
+    /// the error's code, see `CompilationError::code`
+    pub code: &'static str,
+    /// the type name of the contract the error arose in
+    pub contract: &'static str,
+    /// the `then!` or `finish!` function the error arose in, if any
+    pub clause: Option<&'static str>,
+    /// the contracts enclosing it, outermost first
+    pub path: Vec<Frame>,
+    /// the error
+    #[serde(rename = "message", serialize_with = "display")]
+    pub error: CompilationError,
+    /// the output the contract was compiled for, until the enclosing
+    /// contract records it in its frame
+    #[serde(skip)]
+    output: Option<usize>,
+}
+
+fn display<S: Serializer>(e: &CompilationError, s: S) -> Result<S::Ok, S::Error> {
+    s.collect_str(e)
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] in {}", self.code, self.contract)?;
+        if let Some(clause) = self.clause {
+            write!(f, "::{}", clause)?;
+        }
+        for frame in self.path.iter().rev() {
+            write!(f, ", within {}", frame.contract)?;
+            if let Some(clause) = frame.clause {
+                write!(f, "::{}", clause)?;
+            }
+            if let Some(output) = frame.output {
+                write!(f, " output {}", output)?;
+            }
+        }
+        write!(f, ": {}", self.error)
+    }
+}
+
 impl CompilationError {
     /// Create a custom compilation error instance
-    pub fn custom<E: std::error::Error + 'static>(e: E) -> Self {
+    pub fn custom<E: std::error::Error + Send + Sync + 'static>(e: E) -> Self {
         CompilationError::Custom(Box::new(e))
     }
+
+    /// A stable code for the kind of error, for frontends to match on. A
+    /// `Located` error has the code of the error it locates.
+    pub fn code(&self) -> &'static str {
+        match self {
+            CompilationError::TerminateCompilation => "terminate-compilation",
+            CompilationError::MissingTemplates => "missing-templates",
+            CompilationError::EmptyPolicy => "empty-policy",
+            CompilationError::OutOfFunds => "out-of-funds",
+            CompilationError::InsufficientFees { .. } => "insufficient-fees",
+            CompilationError::MissingAnchor => "missing-anchor",
+            CompilationError::EphemeralAnchorFees { .. } => "ephemeral-anchor-fees",
+            CompilationError::IncompatibleSequence => "incompatible-sequence",
+            CompilationError::IncompatibleLockTime => "incompatible-lock-time",
+            CompilationError::NoSuchSequence => "no-such-sequence",
+            CompilationError::NoSuchOutput => "no-such-output",
+            CompilationError::ParseAmountError(_) => "parse-amount",
+            CompilationError::Miniscript(_) => "miniscript-compiler",
+            CompilationError::MiniscriptE(_) => "miniscript",
+            CompilationError::TimeLockError(_) => "timelock",
+            CompilationError::TimelockConflict(_) => "timelock-conflict",
+            CompilationError::Nonstandard(_) => "nonstandard",
+            CompilationError::Taproot(_) => "taproot",
+            CompilationError::UnsupportedCovenant(_) => "unsupported-covenant",
+            CompilationError::CompiledObjectError(_) => "object",
+            CompilationError::InvalidEffect { .. } => "invalid-effect",
+            CompilationError::Migration(_) => "migration",
+            CompilationError::Split(_) => "split",
+            CompilationError::Budget(_) => "budget",
+            CompilationError::ConditionalCompilationFailed(_) => "conditional-compilation-failed",
+            CompilationError::Custom(_) => "custom",
+            CompilationError::Located(d) => d.code,
+        }
+    }
+
+    /// the error, without where it arose
+    pub fn root(&self) -> &CompilationError {
+        match self {
+            CompilationError::Located(d) => d.error.root(),
+            e => e,
+        }
+    }
+
+    /// the diagnostic, if the error is located
+    pub fn diagnostic(&self) -> Option<&Diagnostic> {
+        match self {
+            CompilationError::Located(d) => Some(d),
+            _ => None,
+        }
+    }
+
+    /// Records that the error arose in `clause` of `contract`. If it already
+    /// arose in a contract `contract` encloses, `contract` is added to its
+    /// path instead.
+    pub fn located(self, contract: &'static str, clause: Option<&'static str>) -> Self {
+        match self {
+            CompilationError::Located(mut d) => {
+                let output = d.output.take();
+                d.path.insert(
+                    0,
+                    Frame {
+                        contract,
+                        clause,
+                        output,
+                    },
+                );
+                CompilationError::Located(d)
+            }
+            error => CompilationError::Located(Box::new(Diagnostic {
+                code: error.code(),
+                contract,
+                clause,
+                path: vec![],
+                error,
+                output: None,
+            })),
+        }
+    }
+
+    /// Adds `step` to the path of the error, as it is returned to the
+    /// contract enclosing the one it arose in: to the front of the path of a
+    /// `TimelockConflict`, and to the frame of the enclosing contract of a
+    /// `Located` error.
+    pub fn within(self, step: PathStep) -> Self {
+        match self {
+            CompilationError::TimelockConflict(mut c) => {
+                c.path.insert(0, step);
+                CompilationError::TimelockConflict(c)
+            }
+            CompilationError::Located(mut d) => {
+                if let PathStep::Output(idx) = step {
+                    d.output = Some(idx);
+                }
+                d.error = d.error.within(step);
+                CompilationError::Located(d)
+            }
+            e => e,
+        }
+    }
 }
 
 impl From<bitcoin::util::amount::ParseAmountError> for CompilationError {
@@ -59,11 +264,34 @@ impl From<bitcoin::util::amount::ParseAmountError> for CompilationError {
     }
 }
 
+impl From<crate::contract::migration::MigrationError> for CompilationError {
+    fn from(e: crate::contract::migration::MigrationError) -> Self {
+        CompilationError::Migration(e)
+    }
+}
+
+impl From<crate::util::split::SplitError> for CompilationError {
+    fn from(e: crate::util::split::SplitError) -> Self {
+        CompilationError::Split(e)
+    }
+}
+
+impl From<crate::contract::budget::BudgetError> for CompilationError {
+    fn from(e: crate::contract::budget::BudgetError) -> Self {
+        CompilationError::Budget(e)
+    }
+}
+
 impl From<sapio_base::timelocks::LockTimeError> for CompilationError {
     fn from(b: sapio_base::timelocks::LockTimeError) -> Self {
         CompilationError::TimeLockError(b)
     }
 }
+impl From<sapio_base::taproot::TaprootError> for CompilationError {
+    fn from(b: sapio_base::taproot::TaprootError) -> Self {
+        CompilationError::Taproot(b)
+    }
+}
 impl From<miniscript::policy::compiler::CompilerError> for CompilationError {
     fn from(v: miniscript::policy::compiler::CompilerError) -> Self {
         CompilationError::Miniscript(v)
@@ -82,7 +310,12 @@ impl From<ObjectError> for CompilationError {
 
 impl fmt::Display for CompilationError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            CompilationError::Located(d) => d.fmt(f),
+            CompilationError::TimelockConflict(c) => c.fmt(f),
+            CompilationError::Nonstandard(v) => v.fmt(f),
+            _ => write!(f, "{:?}", self),
+        }
     }
 }
 
@@ -0,0 +1,321 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Exploring the ways a compiled object may be spent, for analysis tools and
+//! `sapio-cli contract explain`.
+//!
+//! An `Explorer` walks an object's tree of templates, outermost first, as
+//! `Step`s: each template, the path to it, the spend path creating it (see
+//! `SpendPath`), and what spending the contract with it requires. As a
+//! contract's policy is any of its branches' guards, what a template
+//! requires is a list of alternatives, each `Requirements` of keys,
+//! timelocks, and hash preimages. A CTV template's alternatives are those of
+//! the policy committing to it; a suggested template's (from a
+//! `finish_or_fn`) are those of the policy committing to no template, as
+//! the policy doesn't say which guard it is for. A contract compiled with an
+//! emulator commits to its templates with the emulator's key instead, so
+//! their alternatives aren't known (and count as committing to no
+//! template).
+//!
+//! Queries over the steps answer e.g. which steps a role may take by itself
+//! (`Explorer::unilateral`).
+use super::object::SpendPath;
+use super::Compiled;
+use crate::template::Template;
+use bitcoin::hashes::sha256;
+use sapio_base::Clause;
+use std::collections::HashMap;
+
+/// A way of satisfying a clause
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Requirements {
+    /// the keys which must sign
+    pub keys: Vec<bitcoin::PublicKey>,
+    /// the timelocks which must be met (`Clause::After` and `Clause::Older`)
+    pub timelocks: Vec<Clause>,
+    /// the hashes whose preimages must be revealed
+    pub preimages: Vec<Clause>,
+    /// the templates which must be committed to
+    pub templates: Vec<sha256::Hash>,
+}
+
+impl Requirements {
+    fn and(&self, other: &Requirements) -> Requirements {
+        fn union<T: Clone + PartialEq>(a: &[T], b: &[T]) -> Vec<T> {
+            let mut out = a.to_vec();
+            for x in b {
+                if !out.contains(x) {
+                    out.push(x.clone());
+                }
+            }
+            out
+        }
+        Requirements {
+            keys: union(&self.keys, &other.keys),
+            timelocks: union(&self.timelocks, &other.timelocks),
+            preimages: union(&self.preimages, &other.preimages),
+            templates: union(&self.templates, &other.templates),
+        }
+    }
+
+    /// can it be met by signing with only `keys` (and waiting)
+    pub fn met_by(&self, keys: &[bitcoin::PublicKey]) -> bool {
+        self.preimages.is_empty() && self.keys.iter().all(|k| keys.contains(k))
+    }
+}
+
+/// Every way of satisfying `clause`
+pub fn alternatives(clause: &Clause) -> Vec<Requirements> {
+    let only = |r: Requirements| vec![r];
+    match clause {
+        Clause::Unsatisfiable => vec![],
+        Clause::Trivial => only(Requirements::default()),
+        Clause::Key(k) => only(Requirements {
+            keys: vec![*k],
+            ..Default::default()
+        }),
+        Clause::After(_) | Clause::Older(_) => only(Requirements {
+            timelocks: vec![clause.clone()],
+            ..Default::default()
+        }),
+        Clause::TxTemplate(h) => only(Requirements {
+            templates: vec![*h],
+            ..Default::default()
+        }),
+        Clause::And(subs) => all(&subs.iter().collect::<Vec<_>>()),
+        Clause::Or(subs) => subs.iter().flat_map(|(_, s)| alternatives(s)).collect(),
+        Clause::Threshold(k, subs) => {
+            let mut out = vec![];
+            combinations(subs, *k, &mut vec![], &mut out);
+            out
+        }
+        _ => only(Requirements {
+            preimages: vec![clause.clone()],
+            ..Default::default()
+        }),
+    }
+}
+
+/// every way of satisfying all of `clauses`
+fn all(clauses: &[&Clause]) -> Vec<Requirements> {
+    clauses
+        .iter()
+        .fold(vec![Requirements::default()], |acc, c| {
+            let alts = alternatives(c);
+            acc.iter()
+                .flat_map(|a| alts.iter().map(move |b| a.and(b)))
+                .collect()
+        })
+}
+
+/// every way of satisfying `k` of `subs`, having picked `picked`
+fn combinations<'a>(
+    subs: &'a [Clause],
+    k: usize,
+    picked: &mut Vec<&'a Clause>,
+    out: &mut Vec<Requirements>,
+) {
+    if picked.len() == k {
+        out.extend(all(picked));
+        return;
+    }
+    if subs.len() < k - picked.len() {
+        return;
+    }
+    picked.push(&subs[0]);
+    combinations(&subs[1..], k, picked, out);
+    picked.pop();
+    combinations(&subs[1..], k, picked, out);
+}
+
+/// A template of an object's tree, see the module docs
+#[derive(Clone, Debug)]
+pub struct Step<'a> {
+    /// the templates, and the index of the output of each, leading to the
+    /// contract spent
+    pub path: Vec<(sha256::Hash, usize)>,
+    /// the step before, by index in the `Explorer`, if any
+    pub parent: Option<usize>,
+    /// the contract spent
+    pub contract: &'a Compiled,
+    /// the name and spend path creating the template, if annotated
+    pub spend_path: Option<(&'a str, &'a SpendPath)>,
+    /// the template
+    pub template: &'a Template,
+    /// every way of spending the contract with the template, less the
+    /// template itself
+    pub requirements: Vec<Requirements>,
+}
+
+impl<'a> Step<'a> {
+    /// the keys some alternative requires, in order
+    pub fn keys(&self) -> Vec<bitcoin::PublicKey> {
+        self.requirements
+            .iter()
+            .fold(Requirements::default(), |acc, r| acc.and(r))
+            .keys
+    }
+    /// the timelocks some alternative requires, in order
+    pub fn timelocks(&self) -> Vec<Clause> {
+        self.requirements
+            .iter()
+            .fold(Requirements::default(), |acc, r| acc.and(r))
+            .timelocks
+    }
+}
+
+/// The steps of an object's tree, see the module docs
+pub struct Explorer<'a> {
+    steps: Vec<Step<'a>>,
+}
+
+impl<'a> Explorer<'a> {
+    /// explores `object`
+    pub fn new(object: &'a Compiled) -> Self {
+        let mut explorer = Explorer { steps: vec![] };
+        explorer.walk(object, None, &mut vec![]);
+        explorer
+    }
+
+    fn walk(
+        &mut self,
+        object: &'a Compiled,
+        parent: Option<usize>,
+        path: &mut Vec<(sha256::Hash, usize)>,
+    ) {
+        let policy = object
+            .policy
+            .as_ref()
+            .map_or_else(Vec::new, alternatives);
+        let mut names: HashMap<sha256::Hash, (&'a str, &'a SpendPath)> = HashMap::new();
+        for (name, sp) in object.spend_paths.iter() {
+            for h in sp.templates.iter() {
+                names.insert(*h, (name.as_str(), sp));
+            }
+        }
+        let mut templates: Vec<(&'a Template, bool)> = object
+            .ctv_to_tx
+            .values()
+            .map(|t| (t, true))
+            .chain(object.suggested_txs.values().map(|t| (t, false)))
+            .collect();
+        templates.sort_by_key(|(t, _)| t.hash());
+        for (template, ctv) in templates {
+            let h = template.hash();
+            let requirements = policy
+                .iter()
+                .filter(|r| {
+                    if ctv {
+                        r.templates.contains(&h)
+                    } else {
+                        r.templates.is_empty()
+                    }
+                })
+                .map(|r| Requirements {
+                    templates: r.templates.iter().filter(|t| **t != h).cloned().collect(),
+                    ..r.clone()
+                })
+                .collect();
+            let index = self.steps.len();
+            self.steps.push(Step {
+                path: path.clone(),
+                parent,
+                contract: object,
+                spend_path: names.get(&h).cloned(),
+                template,
+                requirements,
+            });
+            for (idx, output) in template.outputs.iter().enumerate() {
+                path.push((h, idx));
+                self.walk(&output.contract, Some(index), path);
+                path.pop();
+            }
+        }
+    }
+
+    /// every step, outermost first
+    pub fn iter(&self) -> std::slice::Iter<'_, Step<'a>> {
+        self.steps.iter()
+    }
+
+    /// the steps before `step`, outermost first
+    pub fn ancestors(&self, step: &Step<'a>) -> Vec<&Step<'a>> {
+        let mut out = vec![];
+        let mut parent = step.parent;
+        while let Some(i) = parent {
+            out.push(&self.steps[i]);
+            parent = self.steps[i].parent;
+        }
+        out.reverse();
+        out
+    }
+
+    /// the steps created by spend paths annotated with `role`
+    pub fn by_role<'b>(&'b self, role: &'b str) -> impl Iterator<Item = &'b Step<'a>> + 'b {
+        self.iter().filter(move |s| {
+            s.spend_path
+                .map_or(false, |(_, sp)| sp.roles.iter().any(|r| r == role))
+        })
+    }
+
+    /// the steps which signing with only `keys` (and waiting) may take, along
+    /// with every step before them, e.g. the steps a role holding `keys` may
+    /// take unilaterally
+    pub fn unilateral<'b>(
+        &'b self,
+        keys: &'b [bitcoin::PublicKey],
+    ) -> impl Iterator<Item = &'b Step<'a>> + 'b {
+        let takes = move |s: &Step<'a>| s.requirements.iter().any(|r| r.met_by(keys));
+        self.iter()
+            .filter(move |s| takes(s) && self.ancestors(s).into_iter().all(|a| takes(a)))
+    }
+}
+
+impl<'a, 'b> IntoIterator for &'b Explorer<'a> {
+    type Item = &'b Step<'a>;
+    type IntoIter = std::slice::Iter<'b, Step<'a>>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::Compilable;
+    use crate::fixtures::*;
+
+    #[test]
+    fn explore() {
+        let keys: Vec<_> = (1..4).map(key).collect();
+        let ctx = ctx(1.0);
+        let escrow = escrow().compile(&ctx).unwrap();
+        let explorer = Explorer::new(&escrow);
+        // disputing, releasing, resolving, and paying the seller twice
+        assert_eq!(explorer.iter().count(), 5);
+        let release = explorer
+            .iter()
+            .find(|s| s.template.tx.input[0].sequence == 144)
+            .unwrap();
+        assert!(release.keys().is_empty());
+        assert_eq!(release.timelocks(), vec![Clause::Older(144)]);
+        // anyone may release, and pay the seller, after a day
+        assert_eq!(explorer.unilateral(&[]).count(), 2);
+        // the buyer may dispute, but not resolve, alone
+        assert_eq!(explorer.unilateral(&keys[..1]).count(), 3);
+        assert_eq!(explorer.unilateral(&keys[..2]).count(), 5);
+
+        let committee = committee().compile(&ctx).unwrap();
+        let explorer = Explorer::new(&committee);
+        let pay = explorer.iter().next().unwrap();
+        assert_eq!(pay.spend_path.unwrap().0, "pay");
+        // any two of the three keys
+        assert_eq!(pay.requirements.len(), 3);
+        assert_eq!(explorer.by_role("alice").count(), 1);
+        assert_eq!(explorer.unilateral(&keys[..1]).count(), 0);
+        assert_eq!(explorer.unilateral(&keys[1..]).count(), 1);
+    }
+}
@@ -0,0 +1,205 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Rendering a compiled object's tree of templates as a graph, in Graphviz
+//! DOT or Mermaid, for documentation and audits.
+//!
+//! Contracts and templates are nodes: an edge from a contract to a template
+//! is a way of spending it, labelled by the spend path creating the
+//! template (see `SpendPath`), and an edge from a template to a contract is
+//! an output, labelled by its amount. Templates show their label and their
+//! locks, and contracts without templates, where funds leave, their address.
+use super::explore::Explorer;
+use super::Compiled;
+use crate::template::Template;
+use crate::util::extended_address::ExtendedAddress;
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// Lock times below this are heights, and at or above it times
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+/// If set, a sequence has no relative lock time (BIP-68)
+const SEQUENCE_DISABLE_FLAG: u32 = 1 << 31;
+/// If set, a relative lock time is in units of 512 seconds (BIP-68)
+const SEQUENCE_TYPE_FLAG: u32 = 1 << 22;
+const SEQUENCE_MASK: u32 = 0x0000ffff;
+
+/// A graph language to render to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Graphviz DOT
+    Dot,
+    /// Mermaid flowcharts
+    Mermaid,
+}
+
+impl FromStr for Format {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dot" => Ok(Format::Dot),
+            "mermaid" => Ok(Format::Mermaid),
+            _ => Err(format!(
+                "Unknown graph format {}, expected dot or mermaid",
+                s
+            )),
+        }
+    }
+}
+
+/// the lines describing a template's node
+fn describe(template: &Template) -> Vec<String> {
+    let tx = &template.tx;
+    let mut lines = vec![template
+        .metadata_map_s2s
+        .label()
+        .unwrap_or("template")
+        .to_string()];
+    if tx.lock_time != 0 {
+        lines.push(if tx.lock_time < LOCKTIME_THRESHOLD {
+            format!("after height {}", tx.lock_time)
+        } else {
+            format!("after time {}", tx.lock_time)
+        });
+    }
+    for (i, input) in tx.input.iter().enumerate() {
+        let sequence = input.sequence;
+        if sequence & SEQUENCE_DISABLE_FLAG != 0 || sequence & SEQUENCE_MASK == 0 {
+            continue;
+        }
+        lines.push(if sequence & SEQUENCE_TYPE_FLAG != 0 {
+            format!("input {} older {}s", i, (sequence & SEQUENCE_MASK) * 512)
+        } else {
+            format!("input {} older {} blocks", i, sequence & SEQUENCE_MASK)
+        });
+    }
+    lines.push(format!("{} out", template.total_amount()));
+    lines
+}
+
+/// the lines describing a contract's node
+fn describe_contract(object: &Compiled) -> Vec<String> {
+    let address = match &object.address {
+        ExtendedAddress::Address(a) => a.to_string(),
+        ExtendedAddress::OpReturn(_) => "OP_RETURN".into(),
+        ExtendedAddress::Unknown(s) => format!("{:x}", s),
+    };
+    if object.ctv_to_tx.is_empty() && object.suggested_txs.is_empty() {
+        vec![address]
+    } else {
+        vec!["contract".into(), address]
+    }
+}
+
+/// Renders `object`'s tree of templates as `format`, see the module docs.
+pub fn render(object: &Compiled, format: Format) -> String {
+    let mut nodes: Vec<(String, Vec<String>)> = vec![("c".into(), {
+        let mut lines = describe_contract(object);
+        lines.push(format!("{} in", object.amount_range.max()));
+        lines
+    })];
+    let mut edges: Vec<(String, String, String)> = vec![];
+    for (i, step) in Explorer::new(object).iter().enumerate() {
+        let from = match (step.parent, step.path.last()) {
+            (Some(p), Some((_, idx))) => format!("c{}_{}", p, idx),
+            _ => "c".into(),
+        };
+        let node = format!("t{}", i);
+        nodes.push((node.clone(), describe(step.template)));
+        let label = step
+            .spend_path
+            .map_or_else(String::new, |(name, _)| name.into());
+        edges.push((from, node.clone(), label));
+        for (idx, output) in step.template.outputs.iter().enumerate() {
+            let to = format!("c{}_{}", i, idx);
+            nodes.push((to.clone(), describe_contract(&output.contract)));
+            edges.push((node.clone(), to, output.amount.to_string()));
+        }
+    }
+    let mut out = String::new();
+    // writing to a String can't fail
+    match format {
+        Format::Dot => {
+            let escape = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+            let _ = writeln!(out, "digraph contract {{");
+            for (id, lines) in nodes.iter() {
+                let shape = if id.starts_with('t') {
+                    "box"
+                } else {
+                    "ellipse"
+                };
+                let lines: Vec<_> = lines.iter().map(|l| escape(l)).collect();
+                let _ = writeln!(
+                    out,
+                    "    {} [shape={}, label=\"{}\"];",
+                    id,
+                    shape,
+                    lines.join("\\n")
+                );
+            }
+            for (from, to, label) in edges.iter() {
+                let _ = writeln!(out, "    {} -> {} [label=\"{}\"];", from, to, escape(label));
+            }
+            let _ = writeln!(out, "}}");
+        }
+        Format::Mermaid => {
+            let escape = |s: &str| s.replace('"', "#quot;");
+            let _ = writeln!(out, "flowchart TD");
+            for (id, lines) in nodes.iter() {
+                let lines: Vec<_> = lines.iter().map(|l| escape(l)).collect();
+                let (open, close) = if id.starts_with('t') {
+                    ("[", "]")
+                } else {
+                    ("([", "])")
+                };
+                let _ = writeln!(
+                    out,
+                    "    {}{}\"{}\"{}",
+                    id,
+                    open,
+                    lines.join("<br/>"),
+                    close
+                );
+            }
+            for (from, to, label) in edges.iter() {
+                if label.is_empty() {
+                    let _ = writeln!(out, "    {} --> {}", from, to);
+                } else {
+                    let _ = writeln!(out, "    {} -->|\"{}\"| {}", from, escape(label), to);
+                }
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::Compilable;
+    use crate::fixtures::*;
+
+    #[test]
+    fn graph() {
+        let escrow = escrow().compile(&ctx(1.0)).unwrap();
+        let dot = render(&escrow, Format::Dot);
+        assert!(dot.starts_with("digraph contract {"));
+        // the contract, five templates, and their five outputs
+        assert_eq!(dot.matches("shape=").count(), 11);
+        assert_eq!(dot.matches(" -> ").count(), 10);
+        assert!(dot.contains("input 0 older 144 blocks"));
+        // both ways of paying the seller end at the seller's address
+        assert_eq!(dot.matches(&address().to_string()).count(), 2);
+        let mermaid = render(&escrow, Format::Mermaid);
+        assert!(mermaid.starts_with("flowchart TD"));
+        assert_eq!(
+            mermaid.matches(" --> ").count() + mermaid.matches(" -->|").count(),
+            10
+        );
+        assert_eq!("mermaid".parse::<Format>(), Ok(Format::Mermaid));
+        assert!("svg".parse::<Format>().is_err());
+    }
+}
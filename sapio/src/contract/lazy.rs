@@ -0,0 +1,148 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Lazy compilation, for template trees too large to keep in memory (e.g.
+//! congestion control trees with tens of thousands of leaves).
+//!
+//! A contract's address commits to its templates, which commit to the
+//! addresses of the contracts they create, so every contract in the tree must
+//! still be compiled. But with `Context::with_lazy_depth`, contracts created
+//! that many levels below the one compiled (or below an expanded one) are
+//! pruned once compiled: their templates are dropped, keeping their policy
+//! and address (which commit to the CTV hashes of their templates), and
+//! replaced by a `LazyToken`. As each level is pruned as soon as it is
+//! compiled, memory grows with the depth of the tree rather than its size.
+//!
+//! A token resumes compilation: compiling the same contract again with the
+//! token expanded (see `Context::with_expanded`) keeps the contracts on the
+//! way to it, and the subtree below it down to the same depth again. As a
+//! pruned contract has no templates, analyses of the tree (e.g.
+//! `analysis::leaf_values` or `simulation`) see it as a leaf until expanded.
+use super::Compiled;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// Resumes the compilation of a pruned contract, see the module docs
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LazyToken {
+    /// the contract's path from the one compiled, see `Context::path`
+    pub path: String,
+}
+
+/// How deep to compile, see `Context::with_lazy_depth`
+#[derive(Clone, Debug, Default)]
+pub(crate) struct LazyConfig {
+    /// contracts this many levels below the contract compiled, or an
+    /// expanded one, are pruned
+    pub depth: usize,
+    /// the paths of the contracts expanded
+    pub expanded: BTreeSet<String>,
+}
+
+impl LazyConfig {
+    /// if the contract at `path` was expanded
+    pub fn is_expanded(&self, path: &str) -> bool {
+        self.expanded.contains(path)
+    }
+    /// if a contract below the one at `path` was expanded, so it must be
+    /// kept to reach it
+    pub fn leads_to_expanded(&self, path: &str) -> bool {
+        let prefix = format!("{}/", path);
+        self.expanded
+            .range(prefix.clone()..)
+            .next()
+            .map_or(false, |p| p.starts_with(&prefix))
+    }
+}
+
+/// Drops the templates of `object`, compiled at `path`, leaving a token to
+/// resume from. Objects without templates are left as they are.
+pub(crate) fn prune(object: &mut Compiled, path: String) {
+    if object.ctv_to_tx.is_empty() && object.suggested_txs.is_empty() {
+        return;
+    }
+    object.ctv_to_tx.clear();
+    object.suggested_txs.clear();
+    object.lazy = Some(LazyToken { path });
+}
+
+/// every token in `object`'s tree, by path
+pub fn tokens(object: &Compiled) -> Vec<&LazyToken> {
+    let mut tokens = vec![];
+    let mut stack = vec![object];
+    while let Some(object) = stack.pop() {
+        tokens.extend(object.lazy.iter());
+        for template in object
+            .ctv_to_tx
+            .values()
+            .chain(object.suggested_txs.values())
+        {
+            stack.extend(template.outputs.iter().map(|o| &o.contract));
+        }
+    }
+    tokens.sort();
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::Compilable;
+    use crate::fixtures::*;
+    use bitcoin::util::amount::Amount;
+
+    #[test]
+    fn lazy_compilation() {
+        let amount = Amount::from_btc(1.0).unwrap();
+        let contract = TestEmulation {
+            to_contract: TestEmulation {
+                to_contract: TestEmulation {
+                    to_contract: to(),
+                    amount,
+                    timeout: 1,
+                },
+                amount,
+                timeout: 2,
+            },
+            amount,
+            timeout: 3,
+        };
+        let ctx = ctx(1.0);
+        let eager = contract.compile(&ctx).unwrap();
+        let first_child = |c: &Compiled| {
+            c.ctv_to_tx.values().next().unwrap().outputs[0]
+                .contract
+                .clone()
+        };
+
+        // the contract below the first level is pruned, with the same address
+        let ctx = ctx.with_lazy_depth(1);
+        let compiled = contract.compile(&ctx).unwrap();
+        assert_eq!(
+            serde_json::to_value(&compiled.address).unwrap(),
+            serde_json::to_value(&eager.address).unwrap()
+        );
+        let child = first_child(&compiled);
+        assert!(child.ctv_to_tx.is_empty());
+        let lazy = tokens(&compiled);
+        assert_eq!(lazy.len(), 1);
+        assert_eq!(lazy[0].path, "complete/0");
+
+        // expanding it compiles it one level deeper
+        let expanded = contract.compile(&ctx.with_expanded(lazy[0])).unwrap();
+        let child = first_child(&expanded);
+        assert_eq!(child.ctv_to_tx.len(), 1);
+        assert_eq!(
+            serde_json::to_value(&first_child(&eager).address).unwrap(),
+            serde_json::to_value(&child.address).unwrap()
+        );
+        let lazy = tokens(&expanded);
+        assert_eq!(lazy.len(), 1);
+        assert_eq!(lazy[0].path, "complete/0/complete/0");
+        assert!(first_child(&first_child(&expanded)).ctv_to_tx.is_empty());
+    }
+}
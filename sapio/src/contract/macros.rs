@@ -19,6 +19,9 @@ pub use paste::paste;
 /// /// nightly rust does not require this, but it is availble
 /// /// for compatibility
 /// declare!{non updatable}
+/// /// reads effects for the updatable functions as JSON, requiring X to
+/// /// be Deserialize and JsonSchema
+/// declare!{effects}
 /// ```
 #[macro_export]
 macro_rules! declare {
@@ -47,6 +50,14 @@ macro_rules! declare {
         const FINISH_OR_FUNCS: &'static [fn() -> Option<$crate::contract::actions::FinishOrFunc<'static, Self, Self::StatefulArguments>>] = &[$($a,)*];
         declare![state $($i)?];
     };
+    {effects} => {
+        /// reads effects for the `FinishOrFunc`s from JSON, see `effects`
+        const EFFECTS: Option<$crate::contract::effects::EffectHandlers<Self::StatefulArguments>> =
+            Some($crate::contract::effects::EffectHandlers {
+                decode: $crate::contract::effects::decode,
+                schema: $crate::contract::effects::schema::<Self::StatefulArguments>,
+            });
+    };
     {non updatable} => {
         #[cfg(not(feature = "nightly"))]
         declare![state ()];
@@ -72,6 +83,8 @@ macro_rules! declare {
 /// then!(compile_if: [compile_if_1, ... compile_if_n] fn name(self, ctx) {/*Result<Box<Iterator<TransactionTemplate>>>*/} );
 /// /// An Unguarded CTV Function
 /// then!(fn name(self, ctx) {/*Result<Box<Iterator<TransactionTemplate>>>*/} );
+/// /// An Annotated CTV Function, see `annotate!`
+/// then!(annotated: {label: "...", roles: ["..."], doc: "..."} guarded_by: [guard_1, ... guard_n] fn name(self, ctx) {/*Result<Box<Iterator<TransactionTemplate>>>*/} );
 /// /// Null Implementation
 /// then!(name);
 /// ```
@@ -95,6 +108,7 @@ macro_rules! then {
     };
     {
         $(#[$meta:meta])*
+        $(annotated: $annotation:tt)?
         compile_if: $conditional_compile_list:tt
         guarded_by: $guard_list:tt
         fn $name:ident($s:ident, $ctx:ident)
@@ -109,19 +123,23 @@ macro_rules! then {
             $(#[$meta])*
             fn $name<'a>() -> Option<$crate::contract::actions::ThenFunc<'a, Self>>{
                 Some($crate::contract::actions::ThenFunc{
+                    name: stringify!($name),
                     guard: &$guard_list,
                     conditional_compile_if: &$conditional_compile_list,
-                    func: Self::[<THEN_ $name>]
+                    func: Self::[<THEN_ $name>],
+                    annotation: $crate::annotate!{@opt $($annotation)?},
                 })
             }
         }
     };
     {
         $(#[$meta:meta])*
+        $(annotated: $annotation:tt)?
         fn $name:ident($s:ident, $ctx:ident) $b:block
     } => {
         then!{
             $(#[$meta])*
+            $(annotated: $annotation)?
             compile_if: []
             guarded_by: []
             fn $name($s, $ctx) $b
@@ -130,11 +148,13 @@ macro_rules! then {
 
     {
         $(#[$meta:meta])*
+        $(annotated: $annotation:tt)?
         guarded_by: $guard_list:tt
         fn $name:ident($s:ident, $ctx:ident) $b:block
     } => {
         then!{
             $(#[$meta])*
+            $(annotated: $annotation)?
             compile_if: []
             guarded_by: $guard_list
             fn $name($s, $ctx) $b }
@@ -142,11 +162,13 @@ macro_rules! then {
 
     {
         $(#[$meta:meta])*
+        $(annotated: $annotation:tt)?
         compile_if: $conditional_compile_list:tt
         fn $name:ident($s:ident, $ctx:ident) $b:block
     } => {
         then!{
             $(#[$meta])*
+            $(annotated: $annotation)?
             compile_if: $conditional_compile_list
             guarded_by: []
             fn $name($s, $ctx) $b }
@@ -161,6 +183,8 @@ macro_rules! then {
 /// finish!(guarded_by: [guard_1, ... guard_n] fn name(self, ctx, o) {/*Result<Box<Iterator<TransactionTemplate>>>*/} );
 /// /// A Conditional CTV Function
 /// finish!(compile_if: [compile_if_1, ... compile_if_n] guarded_by: [guard_1, ..., guard_n] fn name(self, ctx, o) {/*Result<Box<Iterator<TransactionTemplate>>>*/} );
+/// /// An Annotated CTV Function, see `annotate!`
+/// finish!(annotated: {label: "..."} guarded_by: [guard_1, ..., guard_n] fn name(self, ctx, o) {/*Result<Box<Iterator<TransactionTemplate>>>*/} );
 /// /// Null Implementation
 /// finish!(name);
 /// ```
@@ -185,6 +209,7 @@ macro_rules! finish {
     };
     {
         $(#[$meta:meta])*
+        $(annotated: $annotation:tt)?
         compile_if: $conditional_compile_list:tt
         guarded_by: $guard_list:tt
         fn $name:ident($s:ident, $ctx:ident, $o:ident)
@@ -199,20 +224,24 @@ macro_rules! finish {
             $(#[$meta])*
             fn $name<'a>() -> Option<$crate::contract::actions::FinishOrFunc<'a, Self, <Self as $crate::contract::AnyContract>::StatefulArguments>>{
                 Some($crate::contract::actions::FinishOrFunc{
+                    name: stringify!($name),
                     guard: &$guard_list,
                     conditional_compile_if: &$conditional_compile_list,
-                    func: Self::[<FINISH_ $name>]
+                    func: Self::[<FINISH_ $name>],
+                    annotation: $crate::annotate!{@opt $($annotation)?},
                 })
             }
         }
     };
     {
         $(#[$meta:meta])*
+        $(annotated: $annotation:tt)?
         guarded_by: $guard_list:tt
         fn $name:ident($s:ident, $ctx:ident, $o:ident) $b:block
     } => {
         finish!{
             $(#[$meta])*
+            $(annotated: $annotation)?
             compile_if: []
             guarded_by: $guard_list
             fn $name($s, $ctx, $o) $b }
@@ -221,15 +250,91 @@ macro_rules! finish {
 
 }
 
+/// The annotate macro is used to define an `Annotation` of a `then!` or
+/// `finish!` branch, given after `annotated:`, which compilation records in
+/// `Object::spend_paths`. Roles and doc are optional.
+/// ```ignore
+/// annotate!{label: "refund", roles: ["alice", "bob"], doc: "returns the funds after a timeout"}
+/// ```
+#[macro_export]
+macro_rules! annotate {
+    {@opt} => {
+        None
+    };
+    {@opt {$($annotation:tt)*}} => {
+        Some($crate::annotate!{$($annotation)*})
+    };
+    {
+        label: $label:expr
+        $(, roles: [$($role:expr),* $(,)?])?
+        $(, doc: $doc:expr)?
+        $(,)?
+    } => {
+        $crate::contract::actions::Annotation {
+            label: $label,
+            roles: &[$($($role),*)?],
+            doc: None $(.or(Some($doc)))?,
+        }
+    };
+}
+
 /// The guard macro is used to define a `Guard`. Guards may be cached or uncached.
 /// formats for calling are:
 /// ```ignore
 /// guard!(fn name(self, ctx) {/*Clause*/})
 /// /// The guard should only be invoked once
 /// guard!(cached fn name(self, ctx) {/*Clause*/})
+/// /// The guard combines others, see `combinators`
+/// guard!(fn name = all_of[guard_1, ... guard_n])
+/// guard!(fn name = any_of[guard_1, ... guard_n])
+/// guard!(cached fn name = threshold(k)[guard_1, ... guard_n])
 /// ```
 #[macro_export]
 macro_rules! guard {
+    {
+        $(#[$meta:meta])*
+        fn $name:ident = threshold($k:expr)[$($g:expr),* $(,)?]} => {
+            $crate::guard!{
+                $(#[$meta])*
+                fn $name(self, ctx) {
+                    $crate::contract::combinators::threshold($k, &[$($g),*], self, ctx)
+                }
+            }
+        };
+    {
+        $(#[$meta:meta])*
+        cached
+        fn $name:ident = threshold($k:expr)[$($g:expr),* $(,)?]} => {
+            $crate::guard!{
+                $(#[$meta])*
+                cached
+                fn $name(self, ctx) {
+                    $crate::contract::combinators::threshold($k, &[$($g),*], self, ctx)
+                }
+            }
+        };
+    {
+        $(#[$meta:meta])*
+        fn $name:ident = $combinator:ident[$($g:expr),* $(,)?]} => {
+            $crate::guard!{
+                $(#[$meta])*
+                fn $name(self, ctx) {
+                    $crate::contract::combinators::$combinator(&[$($g),*], self, ctx)
+                }
+            }
+        };
+    {
+        $(#[$meta:meta])*
+        cached
+        fn $name:ident = $combinator:ident[$($g:expr),* $(,)?]} => {
+            $crate::guard!{
+                $(#[$meta])*
+                cached
+                fn $name(self, ctx) {
+                    $crate::contract::combinators::$combinator(&[$($g),*], self, ctx)
+                }
+            }
+        };
     {
         $(#[$meta:meta])*
         $name:ident} => {
@@ -312,3 +417,126 @@ macro_rules! compile_if {
             }
         };
 }
+
+/// The state_machine macro declares a contract moving through a set of
+/// states, each a `Contract` whose `then!` functions are its transitions.
+/// Every state shares the same data, which must be `Clone`, and a transition
+/// moves all of the state's funds to the next one, optionally after a
+/// relative timeout (any `units::RelativeLock`). A state may also bind
+/// `then!` functions and `Guard`s defined for every state (in an
+/// `impl<S> Name<S>`), e.g. to pay out or to finish, which must not share a
+/// name with a transition.
+/// formats for calling are:
+/// ```ignore
+/// state_machine! {
+///     /// docs
+///     pub machine Escrow<EscrowData> {
+///         /// docs
+///         state Funded {
+///             /// docs
+///             transition dispute -> Disputed guarded_by [Self::buyer];
+///             transition release -> Released after Blocks(144);
+///             finish [Self::both];
+///         }
+///         state Disputed {
+///             transition resolve -> Released guarded_by [Self::arbiter];
+///         }
+///         state Released {
+///             then [Self::pay_seller];
+///         }
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! state_machine {
+    {
+        $(#[$meta:meta])*
+        $vis:vis machine $name:ident<$data:ty> {
+            $(
+                $(#[$smeta:meta])*
+                state $state:ident {
+                    $(
+                        $(#[$tmeta:meta])*
+                        transition $t:ident -> $next:ident
+                            $(guarded_by [$($g:expr),*])?
+                            $(after $timeout:expr)?;
+                    )*
+                    $(then [$($th:expr),*];)?
+                    $(finish [$($f:expr),*];)?
+                }
+            )*
+        }
+    } => {
+        $(#[$meta])*
+        $vis struct $name<S> {
+            /// the data shared by every state
+            pub data: $data,
+            _state: std::marker::PhantomData<S>,
+        }
+
+        impl<S> $name<S> {
+            /// the contract in state `S`
+            pub fn new(data: $data) -> Self {
+                $name {
+                    data,
+                    _state: std::marker::PhantomData,
+                }
+            }
+        }
+
+        $(
+            $(#[$smeta])*
+            $vis struct $state;
+
+            impl $name<$state> {
+                $(
+                    $crate::then! {
+                        $(#[$tmeta])*
+                        compile_if: []
+                        guarded_by: [$($($g),*)?]
+                        fn $t(self, ctx) {
+                            let next = $name::<$next>::new(self.data.clone());
+                            let builder = ctx.template().add_output(ctx.funds(), &next, None)?;
+                            $(let builder = builder.set_relative_lock(0, $timeout)?;)?
+                            builder.into()
+                        }
+                    }
+                )*
+            }
+
+            impl $crate::contract::Contract for $name<$state> {
+                $crate::declare! {then $(, Self::$t)* $($(, $th)*)?}
+                $crate::declare! {finish $($(, $f)*)?}
+                $crate::declare! {non updatable}
+            }
+        )*
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::contract::analysis;
+    use crate::contract::Compilable;
+    use crate::fixtures::*;
+    use bitcoin::util::amount::Amount;
+
+    #[test]
+    fn state_machine() {
+        let compiled = escrow().compile(&ctx(1.0)).unwrap();
+        // disputing and releasing
+        assert_eq!(compiled.ctv_to_tx.len(), 2);
+        let sequences: Vec<_> = compiled
+            .ctv_to_tx
+            .values()
+            .map(|t| t.tx.input[0].sequence)
+            .collect();
+        assert!(sequences.contains(&144));
+        // either way, the seller is paid everything
+        let leaves = analysis::leaf_values(&compiled);
+        assert_eq!(leaves.len(), 2);
+        for leaf in leaves {
+            assert_eq!(leaf.script, address().script_pubkey());
+            assert_eq!(leaf.amount, Amount::from_btc(1.0).unwrap());
+        }
+    }
+}
@@ -0,0 +1,159 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Manifests of a compilation, for counterparties to reproduce a contract's
+//! address before sending funds to it.
+//!
+//! Compilation is deterministic: the same contract compiled with the same
+//! `Context` yields the same `Object`, which serializes to identical bytes
+//! (maps are serialized in key order, see `util::ordered`). A `Manifest`
+//! records everything compilation depended on: hashes of the contract's
+//! arguments and of the effects supplied, the crate versions, the context's
+//! settings, and a fingerprint of the emulator. A counterparty given the
+//! arguments and the manifest can compile the contract themselves and
+//! `Manifest::verify` that they get the same object.
+use super::{Compilable, CompilationError, CompileTarget, Compiled, Context};
+use crate::util::extended_address::ExtendedAddress;
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::util::amount::Amount;
+use bitcoin::Network;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// What the emulator is asked to sign, to fingerprint it
+const EMULATOR_PROBE: &[u8] = b"sapio compilation manifest";
+
+/// What a compilation depended on, and what it produced, see the module docs
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct Manifest {
+    /// the contract's type name
+    pub contract: String,
+    /// the hash of the contract's arguments, serialized as JSON
+    pub args: sha256::Hash,
+    /// the versions of the crates compiling it
+    pub versions: BTreeMap<String, String>,
+    /// the network compiled for
+    #[schemars(with = "String")]
+    pub network: Network,
+    /// the funds compiled with
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    #[schemars(with = "i64")]
+    pub amount: Amount,
+    /// what the contract was compiled to
+    pub target: CompileTarget,
+    /// the covenant backend committing to templates, see `covenant`
+    pub covenant: String,
+    /// the fee warning threshold, see `analysis`
+    pub max_fee_bps: u64,
+    /// the hash of the effects supplied, serialized as JSON
+    pub effects: sha256::Hash,
+    /// the hash of the clause the emulator signs a fixed probe with, which
+    /// differs between emulators (e.g. oracles with different keys)
+    pub emulator: sha256::Hash,
+    /// the address of the contract
+    pub address: ExtendedAddress,
+    /// the hash of the compiled object, serialized as JSON
+    pub object: sha256::Hash,
+}
+
+/// the hash of `t` serialized as JSON
+fn json_hash<T: Serialize + ?Sized>(t: &T) -> Result<sha256::Hash, CompilationError> {
+    serde_json::to_vec(t)
+        .map(|v| sha256::Hash::hash(&v))
+        .map_err(CompilationError::custom)
+}
+
+impl Manifest {
+    /// Compiles `contract`, built from `args`, with `ctx`, returning the
+    /// object and its manifest.
+    pub fn new<C, A>(
+        contract: &C,
+        args: &A,
+        ctx: &Context,
+    ) -> Result<(Compiled, Self), CompilationError>
+    where
+        C: Compilable,
+        A: Serialize + ?Sized,
+    {
+        let object = contract.compile(ctx)?;
+        let mut versions = BTreeMap::new();
+        versions.insert("sapio".into(), env!("CARGO_PKG_VERSION").into());
+        versions.insert("sapio-base".into(), sapio_base::VERSION.into());
+        let probe = ctx.ctv_emulator(sha256::Hash::hash(EMULATOR_PROBE))?;
+        let manifest = Manifest {
+            contract: std::any::type_name::<C>().into(),
+            args: json_hash(args)?,
+            versions,
+            network: ctx.network,
+            amount: ctx.funds(),
+            target: ctx.target(),
+            covenant: ctx.covenant().name().into(),
+            max_fee_bps: ctx.max_fee_bps(),
+            effects: json_hash(ctx.effects())?,
+            emulator: json_hash(&probe)?,
+            address: object.address.clone(),
+            object: json_hash(&object)?,
+        };
+        Ok((object, manifest))
+    }
+
+    /// Compiles `contract`, built from `args`, with `ctx`, returning the
+    /// object if it and everything it depended on match the manifest.
+    pub fn verify<C, A>(
+        &self,
+        contract: &C,
+        args: &A,
+        ctx: &Context,
+    ) -> Result<Option<Compiled>, CompilationError>
+    where
+        C: Compilable,
+        A: Serialize + ?Sized,
+    {
+        let (object, manifest) = Manifest::new(contract, args, ctx)?;
+        if json_hash(self)? == json_hash(&manifest)? {
+            Ok(Some(object))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::*;
+
+    #[test]
+    fn reproducible_compilation() {
+        let args = serde_json::json!({
+            "buyer": key(1).to_string(),
+            "arbiter": key(2).to_string(),
+            "seller": address().to_string(),
+        });
+        let escrow = escrow();
+        let ctx = ctx(1.0);
+        // compiling twice serializes to the same bytes
+        let a = serde_json::to_vec(&escrow.compile(&ctx).unwrap()).unwrap();
+        let b = serde_json::to_vec(&escrow.compile(&ctx).unwrap()).unwrap();
+        assert_eq!(a, b);
+
+        let (compiled, manifest) = Manifest::new(&escrow, &args, &ctx).unwrap();
+        assert_eq!(serde_json::to_vec(&compiled).unwrap(), a);
+        assert!(manifest.verify(&escrow, &args, &ctx).unwrap().is_some());
+        // with other arguments or funds, it isn't reproduced
+        assert!(manifest
+            .verify(&escrow, &serde_json::json!({}), &ctx)
+            .unwrap()
+            .is_none());
+        let less = ctx.with_amount(Amount::from_btc(0.5).unwrap()).unwrap();
+        assert!(manifest.verify(&escrow, &args, &less).unwrap().is_none());
+        // the manifest itself round trips
+        let json = serde_json::to_string(&manifest).unwrap();
+        let parsed: Manifest = serde_json::from_str(&json).unwrap();
+        assert!(parsed.verify(&escrow, &args, &ctx).unwrap().is_some());
+    }
+}
@@ -0,0 +1,154 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Memoizing the compilation of identical sub-contracts (e.g. many equal
+//! leaf payouts), so each compiles once.
+//!
+//! Wrapping a contract in `Memo` caches its compiled object in the context
+//! (see `Context::with_memoization`), by a hash of the contract's type, its
+//! arguments serialized as JSON, and the settings of the context it is
+//! compiled with (its funds, network, target, covenant backend and fee
+//! threshold). Contexts
+//! derived from one share its cache, and its emulator, so equal keys compile
+//! to equal objects.
+//!
+//! Some features make an object depend on where in the tree it is compiled,
+//! which the key can't capture, so memoization is skipped while compiling
+//! with effects (see `effects`) or lazily (see `lazy`), and objects with
+//! continuation points are never cached.
+use super::{Compilable, CompilationError, Compiled, Context};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// The cache of a context, see `Context::with_memoization`
+pub(crate) type MemoCache = Arc<Mutex<HashMap<sha256::Hash, Compiled>>>;
+
+/// Compiles `C`, memoized in the context, see the module docs
+pub struct Memo<C>(pub C);
+
+impl<C> Compilable for Memo<C>
+where
+    C: Compilable + Serialize,
+{
+    fn compile(&self, ctx: &Context) -> Result<Compiled, CompilationError> {
+        let cache = match ctx.memo_cache() {
+            Some(cache) => cache,
+            None => return self.0.compile(ctx),
+        };
+        let key = key(&self.0, ctx)?;
+        if let Some(object) = cache.lock().ok().and_then(|c| c.get(&key).cloned()) {
+            return Ok(object);
+        }
+        let object = self.0.compile(ctx)?;
+        if object.continue_points.is_empty() {
+            if let Ok(mut c) = cache.lock() {
+                c.insert(key, object.clone());
+            }
+        }
+        Ok(object)
+    }
+}
+
+/// the cache key of compiling `contract` with `ctx`
+fn key<C: Serialize>(contract: &C, ctx: &Context) -> Result<sha256::Hash, CompilationError> {
+    let mut engine = sha256::Hash::engine();
+    let mut write = |bytes: &[u8]| {
+        engine.input(&(bytes.len() as u64).to_le_bytes());
+        engine.input(bytes);
+    };
+    write(std::any::type_name::<C>().as_bytes());
+    write(&serde_json::to_vec(contract).map_err(CompilationError::custom)?);
+    write(&ctx.funds().as_sat().to_le_bytes());
+    write(ctx.network.to_string().as_bytes());
+    write(&serde_json::to_vec(&ctx.target()).map_err(CompilationError::custom)?);
+    write(ctx.covenant().name().as_bytes());
+    write(&ctx.max_fee_bps().to_le_bytes());
+    Ok(sha256::Hash::from_engine(engine))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::Contract;
+    use crate::fixtures::*;
+    use crate::util::split::{Rounding, Split};
+    use bitcoin::util::amount::Amount;
+    use sapio_base::timelocks::RelHeight;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// How many times `Leaf::pay` has been compiled
+    static LEAVES_COMPILED: AtomicUsize = AtomicUsize::new(0);
+
+    /// Pays `to` after a day, counting how many times it is compiled
+    #[derive(serde_derive::Serialize)]
+    struct Leaf {
+        to: bitcoin::Address,
+    }
+
+    impl Leaf {
+        then! {
+            fn pay(self, ctx) {
+                LEAVES_COMPILED.fetch_add(1, Ordering::SeqCst);
+                ctx.template()
+                    .add_output(ctx.funds(), &Compiled::from_address(self.to.clone(), None), None)?
+                    .set_sequence(0, RelHeight::from(144).into())?
+                    .into()
+            }
+        }
+    }
+
+    impl Contract for Leaf {
+        declare! {then, Self::pay}
+        declare! {non updatable}
+    }
+
+    /// Pays `n` equal `Leaf`s, memoized
+    struct Leaves {
+        n: u64,
+        to: bitcoin::Address,
+    }
+
+    impl Leaves {
+        then! {
+            fn split(self, ctx) {
+                let split = Split::even(ctx.funds(), self.n as usize, Rounding::RemainderToFee)?;
+                let mut builder = ctx.template();
+                for each in split.shares() {
+                    let leaf = Memo(Leaf { to: self.to.clone() });
+                    builder = builder.add_output(*each, &leaf, None)?;
+                }
+                builder.into()
+            }
+        }
+    }
+
+    impl Contract for Leaves {
+        declare! {then, Self::split}
+        declare! {non updatable}
+    }
+
+    #[test]
+    fn memoization() {
+        let contract = Leaves {
+            n: 8,
+            to: address(),
+        };
+        let ctx = ctx(1.0);
+        let before = LEAVES_COMPILED.load(Ordering::SeqCst);
+        let plain = contract.compile(&ctx).unwrap();
+        assert_eq!(LEAVES_COMPILED.load(Ordering::SeqCst) - before, 8);
+        // the equal leaves compile once, to the same object
+        let before = LEAVES_COMPILED.load(Ordering::SeqCst);
+        let memoized = contract.compile(&ctx.with_memoization()).unwrap();
+        assert_eq!(LEAVES_COMPILED.load(Ordering::SeqCst) - before, 1);
+        assert_eq!(
+            serde_json::to_vec(&plain).unwrap(),
+            serde_json::to_vec(&memoized).unwrap()
+        );
+    }
+}
@@ -0,0 +1,263 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Migrating a compiled contract's funds to a new version of it, e.g. to
+//! upgrade long-lived vaults.
+//!
+//! A contract sanctions its upgrades with a `finish!` function (a
+//! continuation point, see `effects`) guarded by whoever may upgrade it, and
+//! perhaps a delay. As its guards don't commit to templates, a `Migration`
+//! can build a transaction through it to a contract which didn't exist when
+//! the old one was compiled: paying all of its funds, less fees, to the new
+//! contract, with the lock time and sequence the guards require. The
+//! migration is checked as the compiler checks templates (see `analysis`),
+//! and can be bound to the old contract's UTXO for the PSBTs of it and of
+//! the new contract's templates.
+use super::analysis::{self, Warning};
+use super::object::ObjectError;
+use super::{Compilable, CompilationError, Compiled, Context};
+use crate::template::Template;
+use bitcoin::util::amount::Amount;
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use sapio_base::timelocks::*;
+use sapio_base::txindex::TxIndex;
+use sapio_base::Clause;
+use sapio_ctv_emulator_trait::CTVEmulator;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
+
+/// Errors building a `Migration`
+#[derive(Debug)]
+pub enum MigrationError {
+    /// the object has no continuation point at the path
+    NoSuchPoint(String),
+    /// the point's guards are not a way of spending the object, e.g. as it is
+    /// a point of a contract the object creates
+    NotSpendable(String),
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for MigrationError {}
+
+/// The transaction migrating a contract's funds, see the module docs
+#[derive(Clone, Debug)]
+pub struct Migration {
+    /// the continuation point spent through
+    pub point: String,
+    /// the transaction, paying the new contract
+    pub template: Template,
+    /// likely problems with its amounts, see `analysis`
+    pub warnings: Vec<Warning>,
+    /// the old object, with the migration as its only template
+    object: Compiled,
+}
+
+impl Migration {
+    /// Migrates the funds of `from` (those of `ctx`) to `to` through the
+    /// continuation point at `point`, paying `fees`.
+    pub fn new(
+        from: &Compiled,
+        point: &str,
+        to: &dyn Compilable,
+        fees: Amount,
+        ctx: &Context,
+    ) -> Result<Self, CompilationError> {
+        let guard = from
+            .continue_points
+            .get(point)
+            .and_then(|p| p.guard.clone())
+            .ok_or_else(|| MigrationError::NoSuchPoint(point.into()))?;
+        if !from.policy.as_ref().map_or(false, |p| spends(p, &guard)) {
+            return Err(MigrationError::NotSpendable(point.into()).into());
+        }
+        let ctx = ctx.derive("migration");
+        let builder = ctx.template().add_fees(fees)?;
+        let amount = builder.ctx().funds();
+        let mut builder = builder
+            .add_output(amount, to, None)?
+            .set_label(format!("migration through {}", point));
+        let locks = locks(&guard).unwrap_or_default();
+        if let Some(n) = locks.older {
+            builder = builder.set_sequence(0, relative(n))?;
+        }
+        if let Some(n) = locks.after {
+            builder = builder.set_lock_time(absolute(n)?)?;
+        }
+        let template = builder.finish()?;
+        analysis::check_template(&guard, &template, 0)?;
+        let warnings = analysis::check_amounts(&template, 0, &ctx);
+        let mut suggested_txs = HashMap::new();
+        suggested_txs.insert(template.hash(), template.clone());
+        let object = Compiled {
+            ctv_to_tx: HashMap::new(),
+            suggested_txs,
+            warnings: vec![],
+            lazy: None,
+            ..from.clone()
+        };
+        Ok(Migration {
+            point: point.into(),
+            template,
+            warnings,
+            object,
+        })
+    }
+
+    /// Binds the migration to the UTXO of the old contract at `out_in`,
+    /// returning the PSBTs of the migration and of the new contract's
+    /// templates, as `Object::bind_psbt`.
+    pub fn bind_psbt(
+        &self,
+        out_in: bitcoin::OutPoint,
+        blockdata: Rc<dyn TxIndex>,
+        emulator: &dyn CTVEmulator,
+    ) -> Result<(Vec<PartiallySignedTransaction>, Vec<serde_json::Value>), ObjectError> {
+        self.object
+            .bind_psbt(out_in, HashMap::new(), blockdata, emulator)
+    }
+}
+
+/// if `guard` is one of the ways of spending a contract with `policy`
+fn spends(policy: &Clause, guard: &Clause) -> bool {
+    policy == guard
+        || match policy {
+            Clause::Threshold(1, subs) => subs.iter().any(|s| spends(s, guard)),
+            Clause::Or(subs) => subs.iter().any(|(_, s)| spends(s, guard)),
+            _ => false,
+        }
+}
+
+/// The relative and absolute locks (as in `Clause::Older` and
+/// `Clause::After`) of a way of satisfying a clause
+#[derive(Clone, Copy, Default)]
+struct Locks {
+    older: Option<u32>,
+    after: Option<u32>,
+}
+
+/// the locks of both `a` and `b`, if they are of the same kinds
+fn merge(a: Locks, b: Locks) -> Option<Locks> {
+    let max = |x: Option<u32>, y: Option<u32>, same: fn(u32, u32) -> bool| match (x, y) {
+        (Some(x), Some(y)) if same(x, y) => Some(Some(std::cmp::max(x, y))),
+        (Some(_), Some(_)) => None,
+        (x, y) => Some(x.or(y)),
+    };
+    Some(Locks {
+        older: max(a.older, b.older, |x, y| (x & (1 << 22)) == (y & (1 << 22)))?,
+        after: max(a.after, b.after, |x, y| {
+            (x < 500_000_000) == (y < 500_000_000)
+        })?,
+    })
+}
+
+/// the locks of the first way of satisfying `c` found, if any
+fn locks(c: &Clause) -> Option<Locks> {
+    match c {
+        Clause::Unsatisfiable => None,
+        Clause::Older(n) => Some(Locks {
+            older: Some(*n),
+            after: None,
+        }),
+        Clause::After(n) => Some(Locks {
+            older: None,
+            after: Some(*n),
+        }),
+        Clause::And(subs) => subs
+            .iter()
+            .try_fold(Locks::default(), |acc, s| merge(acc, locks(s)?)),
+        Clause::Or(subs) => subs.iter().find_map(|(_, s)| locks(s)),
+        Clause::Threshold(k, subs) => {
+            let mut acc = Locks::default();
+            let mut n = 0;
+            for s in subs.iter() {
+                if n == *k {
+                    break;
+                }
+                if let Some(l) = locks(s).and_then(|l| merge(acc, l)) {
+                    acc = l;
+                    n += 1;
+                }
+            }
+            if n == *k {
+                Some(acc)
+            } else {
+                None
+            }
+        }
+        _ => Some(Locks::default()),
+    }
+}
+
+/// the relative lock of a `Clause::Older`
+fn relative(n: u32) -> AnyRelTimeLock {
+    if n & (1 << 22) != 0 {
+        RelTime::from((n & 0xffff) as u16).into()
+    } else {
+        RelHeight::from((n & 0xffff) as u16).into()
+    }
+}
+
+/// the absolute lock of a `Clause::After`
+fn absolute(n: u32) -> Result<AnyAbsTimeLock, LockTimeError> {
+    if n < 500_000_000 {
+        Ok(AbsHeight::try_from(n)?.into())
+    } else {
+        Ok(AbsTime::try_from(n)?.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::*;
+    use sapio_base::txindex::TxIndexLogger;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+
+    #[test]
+    fn migration() {
+        let ctx = ctx(1.0);
+        let old = Upgradable { key: key(1) }.compile(&ctx).unwrap();
+        assert!(old.continue_points["upgrade"].guard.is_some());
+        let new = TestEmulation {
+            to_contract: to(),
+            amount: Amount::from_sat(99_999_000),
+            timeout: 6,
+        };
+        let fees = Amount::from_sat(1000);
+        let migration = Migration::new(&old, "upgrade", &new, fees, &ctx).unwrap();
+        // everything but the fees moves to the new contract, after the delay
+        assert_eq!(migration.template.outputs.len(), 1);
+        assert_eq!(
+            migration.template.outputs[0].amount,
+            Amount::from_btc(1.0).unwrap() - fees
+        );
+        assert_eq!(migration.template.tx.input[0].sequence, 144);
+        // the migration, then the new contract's template
+        let (psbts, _) = migration
+            .bind_psbt(
+                bitcoin::OutPoint::default(),
+                Rc::new(TxIndexLogger::new()),
+                &CTVAvailable,
+            )
+            .unwrap();
+        assert_eq!(psbts.len(), 2);
+        assert_eq!(
+            psbts[0].global.unsigned_tx.input[0].previous_output,
+            bitcoin::OutPoint::default()
+        );
+        // only sanctioned points, with enough funds, may be migrated through
+        let nope = Migration::new(&old, "nope", &new, fees, &ctx).unwrap_err();
+        assert_eq!(nope.code(), "migration");
+        let poor = Migration::new(&old, "upgrade", &new, Amount::from_sat(2000), &ctx);
+        assert_eq!(poor.unwrap_err().code(), "out-of-funds");
+    }
+}
@@ -10,12 +10,27 @@ use crate::template::Template as TransactionTemplate;
 #[macro_use]
 pub mod macros;
 pub mod actions;
+pub mod analysis;
+pub mod budget;
+pub mod combinators;
 pub mod compiler;
 pub mod error;
 pub mod object;
 pub use error::CompilationError;
 pub mod context;
-pub use context::Context;
+pub use context::{CompileTarget, Context};
+pub mod covenant;
+pub mod diff;
+pub mod effects;
+pub mod explore;
+pub mod graph;
+pub mod lazy;
+pub mod manifest;
+pub mod memo;
+pub mod migration;
+pub mod schema;
+pub mod standard;
+pub mod weight;
 
 use bitcoin::util::amount::Amount;
 pub use compiler::Compilable;
@@ -43,6 +58,9 @@ where
     declare! {then}
     declare! { updatable<> }
     declare! {finish}
+    /// how the contract reads effects, see `effects`. Only declared (with
+    /// `declare!{effects}`) if `StatefulArguments` can be deserialized.
+    const EFFECTS: Option<effects::EffectHandlers<Self::StatefulArguments>> = None;
 }
 
 /// DynamicContract wraps a struct S with a set of methods (that can be constructed dynamically)
@@ -110,6 +128,10 @@ where
     fn finish_fns<'a>(&'a self) -> &'a [fn() -> Option<actions::Guard<Self::Ref>>];
     /// obtain a reference to `Self::Ref` type.
     fn get_inner_ref<'a>(&'a self) -> &'a Self::Ref;
+    /// how effects for the `FinishOrFunc`s are read, if they can be
+    fn effect_handlers(&self) -> Option<effects::EffectHandlers<Self::StatefulArguments>> {
+        None
+    }
 }
 
 impl<C> AnyContract for C
@@ -135,4 +157,7 @@ where
     fn get_inner_ref<'a>(&'a self) -> &Self::Ref {
         self
     }
+    fn effect_handlers(&self) -> Option<effects::EffectHandlers<Self::StatefulArguments>> {
+        Self::EFFECTS
+    }
 }
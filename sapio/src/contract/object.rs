@@ -5,8 +5,14 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 //! Object is the output of Sapio Compilation & can be linked to a specific coin
+use super::actions::Annotation;
+use super::analysis::Warning;
+use super::effects::ContinuationPoint;
+use super::lazy::LazyToken;
+use super::weight::{self, TemplateWeight};
 use crate::template::Template;
 use crate::util::amountrange::AmountRange;
+use crate::util::descriptor;
 use crate::util::extended_address::ExtendedAddress;
 use ::miniscript::{self, *};
 
@@ -14,14 +20,17 @@ use bitcoin::hashes::sha256;
 use bitcoin::hashes::sha256::Hash as Sha256;
 use bitcoin::util::amount::Amount;
 use bitcoin::util::psbt::PartiallySignedTransaction;
+use sapio_base::taproot::TaprootOutput;
 use sapio_base::txindex::TxIndexError;
 use sapio_base::txindex::{TxIndex, TxIndexLogger};
 use sapio_base::Clause;
+#[cfg(feature = "async")]
+use sapio_ctv_emulator_trait::AsyncCTVEmulator;
 use sapio_ctv_emulator_trait::{CTVAvailable, CTVEmulator, EmulatorError};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::rc::Rc;
 use std::sync::Arc;
 
@@ -35,7 +44,7 @@ pub enum ObjectError {
     /// OpReturn Too Long
     OpReturnTooLong,
     /// The Error was for an unknown/unhandled reason
-    Custom(Box<dyn std::error::Error>),
+    Custom(Box<dyn std::error::Error + Send + Sync>),
 }
 impl std::error::Error for ObjectError {}
 impl From<EmulatorError> for ObjectError {
@@ -60,6 +69,51 @@ impl std::fmt::Display for ObjectError {
         write!(f, "{:?}", self)
     }
 }
+/// An output descriptor for an address a contract can create, see
+/// `Object::descriptors`
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct AddressDescriptor {
+    /// the templates, and the index of the output of each, leading to the
+    /// address, empty for the contract's own
+    pub path: Vec<(sha256::Hash, usize)>,
+    /// The most descriptive descriptor known: the `wsh` descriptor of a
+    /// compiled contract (whose miniscript may use sapio's `txtmpl` CTV
+    /// extension), else an `addr` or `raw` descriptor
+    pub descriptor: String,
+    /// a `raw` descriptor of the address's script, which any wallet supporting
+    /// descriptors can import
+    pub raw: String,
+}
+
+/// A spend path of a contract, annotated by its author with `annotate!`, for
+/// wallets and UIs to describe to users
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct SpendPath {
+    /// a short human-readable label for the path
+    pub label: String,
+    /// the parties who would spend through the path
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub roles: Vec<String>,
+    /// a longer description of the path
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub doc: Option<String>,
+    /// the hashes of the templates the path creates, empty for a `finish!`
+    /// path or a `then!` path creating none
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub templates: Vec<sha256::Hash>,
+}
+
+impl SpendPath {
+    pub(crate) fn new(a: &Annotation, templates: Vec<sha256::Hash>) -> Self {
+        SpendPath {
+            label: a.label.into(),
+            roles: a.roles.iter().map(|r| r.to_string()).collect(),
+            doc: a.doc.map(String::from),
+            templates,
+        }
+    }
+}
+
 /// Object holds a contract's complete context required post-compilation
 /// There is no guarantee that Object is properly constructed presently.
 //TODO: Make type immutable and correct by construction...
@@ -70,6 +124,7 @@ pub struct Object {
     #[serde(
         rename = "template_hash_to_template_map",
         skip_serializing_if = "HashMap::is_empty",
+        serialize_with = "crate::util::ordered::map",
         default
     )]
     pub ctv_to_tx: HashMap<sha256::Hash, Template>,
@@ -79,6 +134,7 @@ pub struct Object {
     #[serde(
         rename = "suggested_template_hash_to_template_map",
         skip_serializing_if = "HashMap::is_empty",
+        serialize_with = "crate::util::ordered::map",
         default
     )]
     pub suggested_txs: HashMap<sha256::Hash, Template>,
@@ -98,6 +154,45 @@ pub struct Object {
         default
     )]
     pub descriptor: Option<Descriptor<bitcoin::PublicKey>>,
+    /// The Object's taproot output -- if compiled to one
+    #[serde(
+        rename = "known_taproot",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub taproot: Option<TaprootOutput>,
+    /// Likely problems with the amounts of the Object's templates, see
+    /// `analysis`
+    #[serde(
+        rename = "compilation_warnings",
+        skip_serializing_if = "Vec::is_empty",
+        default
+    )]
+    pub warnings: Vec<Warning>,
+    /// The continuation points of the Object and the contracts it creates,
+    /// by path, see `effects`
+    #[serde(
+        rename = "continue_points",
+        skip_serializing_if = "BTreeMap::is_empty",
+        default
+    )]
+    pub continue_points: BTreeMap<String, ContinuationPoint>,
+    /// The annotated spend paths of the Object, by the name of the `then!`
+    /// or `finish!` function, see `annotate!`
+    #[serde(
+        rename = "spend_paths",
+        skip_serializing_if = "BTreeMap::is_empty",
+        default
+    )]
+    pub spend_paths: BTreeMap<String, SpendPath>,
+    /// If the Object's templates were pruned, the token to expand them
+    /// with, see `lazy`
+    #[serde(
+        rename = "lazy_token",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub lazy: Option<LazyToken>,
     /// The amount_range safe to send this object
     pub amount_range: AmountRange,
 }
@@ -112,6 +207,11 @@ impl Object {
             policy: None,
             address: address.into(),
             descriptor: None,
+            taproot: None,
+            warnings: vec![],
+            continue_points: BTreeMap::new(),
+            spend_paths: BTreeMap::new(),
+            lazy: None,
             amount_range: a.unwrap_or_else(|| {
                 let mut a = AmountRange::new();
                 a.update_range(Amount::min_value());
@@ -137,14 +237,90 @@ impl Object {
     where
         &'a [u8]: From<&'a I>,
     {
-        Ok(Object {
+        Ok(Object::from_extended_address(
+            ExtendedAddress::make_op_return(data)?,
+        ))
+    }
+
+    /// create a data carrier (an op_return) of any size, see
+    /// `Builder::add_data_carrier` for the limits nodes relay
+    pub fn from_data_carrier(data: &[u8]) -> Object {
+        Object::from_extended_address(ExtendedAddress::make_data_carrier(data))
+    }
+
+    pub(crate) fn from_extended_address(address: ExtendedAddress) -> Object {
+        Object {
             ctv_to_tx: HashMap::new(),
             suggested_txs: HashMap::new(),
             policy: None,
-            address: ExtendedAddress::make_op_return(data)?,
+            address,
             descriptor: None,
+            taproot: None,
+            warnings: vec![],
+            continue_points: BTreeMap::new(),
+            spend_paths: BTreeMap::new(),
+            lazy: None,
             amount_range: AmountRange::new(),
-        })
+        }
+    }
+
+    /// The estimated size of each of the Object's templates once signed, see
+    /// `weight`. Templates it can't estimate, e.g. as the Object has no
+    /// descriptor, are left out.
+    pub fn weights(&self) -> BTreeMap<sha256::Hash, TemplateWeight> {
+        self.ctv_to_tx
+            .values()
+            .chain(self.suggested_txs.values())
+            .filter_map(|t| weight::estimate(self, t).map(|w| (t.hash(), w)))
+            .collect()
+    }
+
+    /// The descriptors of every address in the Object's tree of templates,
+    /// the Object's own first, so watch-only wallets and indexers can track
+    /// the contract through its whole lifecycle. Each address is listed once,
+    /// with the first path to it, and OP_RETURNs, which can't be spent, are
+    /// left out.
+    pub fn descriptors(&self) -> Vec<AddressDescriptor> {
+        let mut seen = HashSet::new();
+        let mut descriptors = vec![];
+        self.walk_descriptors(&mut vec![], &mut seen, &mut descriptors);
+        descriptors
+    }
+
+    fn walk_descriptors(
+        &self,
+        path: &mut Vec<(sha256::Hash, usize)>,
+        seen: &mut HashSet<bitcoin::Script>,
+        descriptors: &mut Vec<AddressDescriptor>,
+    ) {
+        let script: bitcoin::Script = self.address.clone().into();
+        if !script.is_op_return() && seen.insert(script.clone()) {
+            let raw = format!("raw({:x})", script);
+            let desc = match (&self.descriptor, &self.address) {
+                (Some(d), _) => d.to_string(),
+                (None, ExtendedAddress::Address(a)) => format!("addr({})", a),
+                (None, _) => raw.clone(),
+            };
+            // our descriptors only have characters checksums cover
+            descriptors.push(AddressDescriptor {
+                path: path.clone(),
+                descriptor: descriptor::with_checksum(&desc).unwrap_or(desc),
+                raw: descriptor::with_checksum(&raw).unwrap_or(raw),
+            });
+        }
+        let mut templates: Vec<&Template> = self
+            .ctv_to_tx
+            .values()
+            .chain(self.suggested_txs.values())
+            .collect();
+        templates.sort_by_key(|t| t.hash());
+        for template in templates {
+            for (idx, output) in template.outputs.iter().enumerate() {
+                path.push((template.hash(), idx));
+                output.contract.walk_descriptors(path, seen, descriptors);
+                path.pop();
+            }
+        }
     }
 
     /// bind attaches an Object to a specific UTXO and returns a vec of transactions and
@@ -182,37 +358,102 @@ impl Object {
         ),
         ObjectError,
     > {
-        let mut txns = vec![];
-        let mut metadata_out = vec![];
-        // Could use a queue instead to do BFS linking, but order doesn't matter and stack is
-        // faster.
-        let mut stack = vec![(out_in, self)];
-
-        while let Some((
-            out,
-            Object {
-                descriptor,
-                ctv_to_tx,
-                suggested_txs,
-                ..
-            },
-        )) = stack.pop()
-        {
-            txns.reserve(ctv_to_tx.len() + suggested_txs.len());
-            metadata_out.reserve(ctv_to_tx.len() + suggested_txs.len());
-            for (
-                ctv_hash,
-                Template {
-                    metadata_map_s2s,
-                    outputs,
-                    tx,
+        let mut binder = Binder::new(self, out_in, output_map, blockdata);
+        while let Some((psbtx, template)) = binder.next_unsigned()? {
+            binder.signed(emulator.sign(psbtx)?, template)?;
+        }
+        Ok(binder.finish())
+    }
+
+    /// `bind_psbt`, awaiting the emulator rather than blocking on it.
+    #[cfg(feature = "async")]
+    pub async fn bind_psbt_async(
+        &self,
+        out_in: bitcoin::OutPoint,
+        output_map: HashMap<Sha256, Vec<Option<bitcoin::OutPoint>>>,
+        blockdata: Rc<dyn TxIndex>,
+        emulator: &dyn AsyncCTVEmulator,
+    ) -> Result<
+        (
+            Vec<bitcoin::util::psbt::PartiallySignedTransaction>,
+            Vec<serde_json::Value>,
+        ),
+        ObjectError,
+    > {
+        let mut binder = Binder::new(self, out_in, output_map, blockdata);
+        while let Some((psbtx, template)) = binder.next_unsigned()? {
+            binder.signed(emulator.sign_async(psbtx).await?, template)?;
+        }
+        Ok(binder.finish())
+    }
+}
+
+/// The state of `Object::bind_psbt`, shared by its sync and async versions:
+/// yields the PSBT of each template in turn to be signed, then links the
+/// outputs of the signed transaction to their contracts.
+struct Binder<'a> {
+    output_map: HashMap<Sha256, Vec<Option<bitcoin::OutPoint>>>,
+    blockdata: Rc<dyn TxIndex>,
+    // Could use a queue instead to do BFS linking, but order doesn't matter and stack is
+    // faster.
+    stack: Vec<(bitcoin::OutPoint, &'a Object)>,
+    /// the unsigned PSBTs of the object last taken from the stack
+    pending: Vec<(PartiallySignedTransaction, &'a Template)>,
+    txns: Vec<PartiallySignedTransaction>,
+    metadata_out: Vec<serde_json::Value>,
+    /// the labels of the annotated spend paths creating each template
+    labels: HashMap<Sha256, &'a str>,
+}
+
+impl<'a> Binder<'a> {
+    fn new(
+        object: &'a Object,
+        out_in: bitcoin::OutPoint,
+        output_map: HashMap<Sha256, Vec<Option<bitcoin::OutPoint>>>,
+        blockdata: Rc<dyn TxIndex>,
+    ) -> Self {
+        Binder {
+            output_map,
+            blockdata,
+            stack: vec![(out_in, object)],
+            pending: vec![],
+            txns: vec![],
+            metadata_out: vec![],
+            labels: HashMap::new(),
+        }
+    }
+
+    /// the next PSBT to sign and its template, if any are left
+    fn next_unsigned(
+        &mut self,
+    ) -> Result<Option<(PartiallySignedTransaction, &'a Template)>, ObjectError> {
+        while self.pending.is_empty() {
+            let (
+                out,
+                Object {
+                    descriptor,
+                    taproot,
+                    ctv_to_tx,
+                    suggested_txs,
+                    spend_paths,
                     ..
                 },
-            ) in ctv_to_tx.iter().chain(suggested_txs.iter())
-            {
-                let mut tx = tx.clone();
+            ) = match self.stack.pop() {
+                Some(next) => next,
+                None => return Ok(None),
+            };
+            for path in spend_paths.values() {
+                for h in path.templates.iter() {
+                    self.labels.insert(*h, &path.label);
+                }
+            }
+            self.txns.reserve(ctv_to_tx.len() + suggested_txs.len());
+            self.metadata_out
+                .reserve(ctv_to_tx.len() + suggested_txs.len());
+            for (ctv_hash, template) in ctv_to_tx.iter().chain(suggested_txs.iter()) {
+                let mut tx = template.tx.clone();
                 tx.input[0].previous_output = out;
-                if let Some(outputs) = output_map.get(ctv_hash) {
+                if let Some(outputs) = self.output_map.get(ctv_hash) {
                     for (i, inp) in tx.input.iter_mut().enumerate().skip(1) {
                         if let Some(out) = outputs[i] {
                             inp.previous_output = out;
@@ -221,29 +462,108 @@ impl Object {
                 }
                 let mut psbtx = PartiallySignedTransaction::from_unsigned_tx(tx.clone()).unwrap();
                 for (psbt_in, tx_in) in psbtx.inputs.iter_mut().zip(tx.input.iter()) {
-                    psbt_in.witness_utxo = blockdata.lookup_output(&tx_in.previous_output).ok();
+                    psbt_in.witness_utxo =
+                        self.blockdata.lookup_output(&tx_in.previous_output).ok();
                     psbt_in.sighash_type = Some(bitcoin::blockdata::transaction::SigHashType::All);
                 }
+                // overlay protocols' commitments, see `template::commitment`
+                for (psbt_out, output) in psbtx.outputs.iter_mut().zip(template.outputs.iter()) {
+                    for c in output.commitments.iter() {
+                        psbt_out.unknown.insert(c.psbt_key(), c.data.clone());
+                    }
+                }
                 // Missing other Witness Info.
                 if let Some(d) = descriptor {
                     psbtx.inputs[0].witness_script = Some(d.explicit_script());
                 }
-                psbtx = emulator.sign(psbtx)?;
-                let final_tx = psbtx.clone().extract_tx();
-                let txid = blockdata.add_tx(Arc::new(final_tx))?;
-                txns.push(psbtx);
-                metadata_out.push(json!({
-                    "color" : "green",
-                    "metadata" : metadata_map_s2s,
-                    "utxo_metadata" : outputs.iter().map(|x| &x.metadata).collect::<Vec<_>>()
-                }));
-                stack.reserve(outputs.len());
-                for (vout, v) in outputs.iter().enumerate() {
-                    let vout = vout as u32;
-                    stack.push((bitcoin::OutPoint { txid, vout }, &v.contract));
+                // the leaf spending to the template, for the emulator to
+                // find its key in
+                if let Some(leaf) = taproot.as_ref().and_then(|t| t.leaf_for(ctv_hash)) {
+                    psbtx.inputs[0].witness_script = Some(leaf.script.clone());
                 }
+                self.pending.push((psbtx, template));
             }
+            // taken from the back, so signed in the order of the templates
+            self.pending.reverse();
+        }
+        Ok(self.pending.pop())
+    }
+
+    /// records the signed PSBT of `template`, and queues the contracts its
+    /// outputs create
+    fn signed(
+        &mut self,
+        psbtx: PartiallySignedTransaction,
+        template: &'a Template,
+    ) -> Result<(), ObjectError> {
+        let final_tx = psbtx.clone().extract_tx();
+        let txid = self.blockdata.add_tx(Arc::new(final_tx))?;
+        self.txns.push(psbtx);
+        self.metadata_out.push(json!({
+            "color" : "green",
+            "spend_path" : self.labels.get(&template.ctv),
+            "metadata" : template.metadata_map_s2s,
+            "utxo_metadata" : template.outputs.iter().map(|x| &x.metadata).collect::<Vec<_>>()
+        }));
+        self.stack.reserve(template.outputs.len());
+        for (vout, v) in template.outputs.iter().enumerate() {
+            let vout = vout as u32;
+            self.stack
+                .push((bitcoin::OutPoint { txid, vout }, &v.contract));
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> (Vec<PartiallySignedTransaction>, Vec<serde_json::Value>) {
+        (self.txns, self.metadata_out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::Compilable;
+    use crate::fixtures::*;
+
+    #[test]
+    fn spend_paths() {
+        let ctx = ctx(1.0);
+        let committee = committee().compile(&ctx).unwrap();
+        let pay = &committee.spend_paths["pay"];
+        assert_eq!(pay.label, "pay");
+        assert_eq!(pay.roles, vec!["alice", "bob", "carol"]);
+        assert_eq!(pay.doc, None);
+        assert_eq!(
+            pay.templates,
+            committee.ctv_to_tx.keys().cloned().collect::<Vec<_>>()
+        );
+        // the labels of the paths survive serialization, and label the
+        // transactions bound
+        let json = serde_json::to_value(&committee).unwrap();
+        assert_eq!(json["spend_paths"]["pay"]["label"], "pay");
+        let (_, metadata) = committee
+            .bind_psbt(
+                bitcoin::OutPoint::default(),
+                HashMap::new(),
+                Rc::new(TxIndexLogger::new()),
+                &CTVAvailable,
+            )
+            .unwrap();
+        assert_eq!(metadata[0]["spend_path"], "pay");
+
+        let upgradable = Upgradable { key: key(1) }.compile(&ctx).unwrap();
+        let upgrade = &upgradable.spend_paths["upgrade"];
+        assert_eq!(upgrade.roles, vec!["owner"]);
+        assert!(upgrade.doc.is_some());
+        assert!(upgrade.templates.is_empty());
+        // unannotated branches have no spend path
+        let fan = Fan {
+            n: 2,
+            amount: Amount::from_sat(1000),
+            to: address(),
         }
-        Ok((txns, metadata_out))
+        .compile(&ctx)
+        .unwrap();
+        assert!(fan.spend_paths.is_empty());
     }
 }
@@ -0,0 +1,110 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! JSON Schemas of the arguments contracts are created from, which plugins
+//! and frontends export so that GUIs can generate forms for them and validate
+//! input before attempting compilation.
+//!
+//! A contract which is its own arguments (deriving `JsonSchema` and
+//! `Deserialize`) implements `SchemaOf` with `schema_of!`. Others may be
+//! created from arguments of another type, e.g. to accept amounts in a
+//! friendlier format, or to check them before compiling.
+use super::CompilationError;
+use schemars::schema::RootSchema;
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+
+/// A contract created from arguments with a JSON Schema, see the module docs
+pub trait SchemaOf: Sized {
+    /// the arguments the contract is created from
+    type Args: JsonSchema + DeserializeOwned;
+    /// create the contract from its arguments
+    fn from_args(args: Self::Args) -> Result<Self, CompilationError>;
+    /// the JSON Schema of the arguments
+    fn schema() -> RootSchema {
+        schemars::schema_for!(Self::Args)
+    }
+    /// create the contract from its arguments, as JSON
+    fn from_json(args: serde_json::Value) -> Result<Self, CompilationError> {
+        let args = serde_json::from_value(args).map_err(CompilationError::custom)?;
+        Self::from_args(args)
+    }
+}
+
+/// The schema_of macro implements `SchemaOf` for a contract which is its own
+/// arguments.
+/// formats for calling are:
+/// ```ignore
+/// schema_of!(Type);
+/// ```
+#[macro_export]
+macro_rules! schema_of {
+    ($t:ty) => {
+        impl $crate::contract::schema::SchemaOf for $t {
+            type Args = Self;
+            fn from_args(args: Self) -> Result<Self, $crate::contract::CompilationError> {
+                Ok(args)
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::*;
+    use bitcoin::util::amount::Amount;
+    use std::str::FromStr;
+
+    /// The arguments of a `Fan`, with the amount in BTC, as a GUI would fill in
+    #[derive(serde_derive::Deserialize, schemars::JsonSchema)]
+    pub struct FanArgs {
+        /// the number of contracts to split between
+        pub n: u64,
+        /// the amount split, in BTC
+        pub amount_btc: f64,
+        /// the address paid
+        pub to: String,
+    }
+
+    impl SchemaOf for Fan {
+        type Args = FanArgs;
+        fn from_args(args: FanArgs) -> Result<Self, CompilationError> {
+            if args.n == 0 {
+                return Err(CompilationError::TerminateCompilation);
+            }
+            Ok(Fan {
+                n: args.n,
+                amount: Amount::from_btc(args.amount_btc)?,
+                to: bitcoin::Address::from_str(&args.to).map_err(CompilationError::custom)?,
+            })
+        }
+    }
+
+    #[test]
+    fn schema_of() {
+        let schema = serde_json::to_value(Fan::schema()).unwrap();
+        assert_eq!(schema["title"], "FanArgs");
+        for field in ["n", "amount_btc", "to"].iter() {
+            assert!(schema["properties"].get(field).is_some());
+        }
+        let fan = Fan::from_json(serde_json::json!({
+            "n": 2,
+            "amount_btc": 1.0,
+            "to": address().to_string(),
+        }))
+        .unwrap();
+        assert_eq!(fan.amount, Amount::from_btc(1.0).unwrap());
+        // invalid input is rejected before compiling
+        assert!(Fan::from_json(serde_json::json!({ "n": 2 })).is_err());
+        assert!(Fan::from_json(serde_json::json!({
+            "n": 0,
+            "amount_btc": 1.0,
+            "to": address().to_string(),
+        }))
+        .is_err());
+    }
+}
@@ -0,0 +1,401 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checks run by the compiler on each contract against the limits on what
+//! nodes relay and blocks may contain, so that a contract can't compile into
+//! transactions which can't be broadcast.
+//!
+//! Which limits apply is the context's `RelayPolicy`: by default those of
+//! Bitcoin Core's default relay policy (standardness), or only those of
+//! consensus, e.g. for transactions given to a miner directly. A contract
+//! breaking one fails with `CompilationError::Nonstandard`, naming the
+//! `Rule` broken and the template breaking it, if not the contract's own
+//! script. Under `RelayPolicy::Consensus` dust outputs are only warned
+//! about (see `analysis`), as they were intended.
+//!
+//! The contract's own witness is estimated as an upper bound (see
+//! `weight::upper_bound`), and any other inputs' as `DEFAULT_WITNESS_SIZE`.
+use super::analysis::dust_limit;
+use super::weight;
+use super::Compiled;
+use crate::template::anchor::pay_to_anchor;
+use crate::template::fees::DEFAULT_WITNESS_SIZE;
+use crate::template::Template;
+use bitcoin::blockdata::opcodes::all::*;
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::hashes::sha256;
+use bitcoin::util::amount::Amount;
+use bitcoin::Script;
+use miniscript::DescriptorTrait;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// The largest OP_RETURN output relayed by default
+pub const MAX_OP_RETURN_RELAY: usize = 83;
+/// The most data an OP_RETURN output relayed by default may carry, less the
+/// OP_RETURN and a push of up to 80 bytes
+pub const MAX_DATA_CARRIER: usize = MAX_OP_RETURN_RELAY - 3;
+/// The largest witness script relayed by default
+const MAX_STANDARD_P2WSH_SCRIPT_SIZE: usize = 3600;
+/// The largest script valid in consensus
+const MAX_SCRIPT_SIZE: usize = 10_000;
+/// The largest transaction relayed by default
+const MAX_STANDARD_TX_WEIGHT: u64 = 400_000;
+/// The largest block, and so transaction, valid in consensus
+const MAX_BLOCK_WEIGHT: u64 = 4_000_000;
+/// The most signature operations, as cost, of a transaction relayed by
+/// default
+const MAX_STANDARD_TX_SIGOPS_COST: u64 = 16_000;
+/// The most signature operations, as cost, of a block valid in consensus
+const MAX_BLOCK_SIGOPS_COST: u64 = 80_000;
+/// Legacy signature operations cost this many witness ones
+const WITNESS_SCALE_FACTOR: u64 = 4;
+
+/// Which limits contracts are checked against, see the module docs
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RelayPolicy {
+    /// Bitcoin Core's default relay policy (the default)
+    Standard,
+    /// only consensus
+    Consensus,
+}
+
+impl Default for RelayPolicy {
+    fn default() -> Self {
+        RelayPolicy::Standard
+    }
+}
+
+/// A limit a contract breaks
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", tag = "rule")]
+pub enum Rule {
+    /// the contract's witness script is too large
+    ScriptSize {
+        /// its size
+        size: usize,
+        /// the limit
+        limit: usize,
+    },
+    /// the template, once signed, is too heavy
+    Weight {
+        /// its weight
+        weight: u64,
+        /// the limit
+        limit: u64,
+    },
+    /// the template has too many signature operations
+    Sigops {
+        /// their cost
+        cost: u64,
+        /// the limit
+        limit: u64,
+    },
+    /// an OP_RETURN output is too large
+    OpReturnSize {
+        /// the output
+        output: usize,
+        /// its script's size
+        size: usize,
+    },
+    /// the template has more than one OP_RETURN output
+    OpReturns {
+        /// how many
+        count: usize,
+    },
+    /// an output's script isn't of a standard type
+    NonstandardOutput {
+        /// the output
+        output: usize,
+    },
+    /// an output is below the dust limit for its script
+    Dust {
+        /// the output
+        output: usize,
+        /// its amount
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        #[schemars(with = "i64")]
+        amount: Amount,
+        /// the dust limit for its script
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        #[schemars(with = "i64")]
+        limit: Amount,
+    },
+}
+
+impl Rule {
+    /// the rule's name, stable for frontends
+    pub fn name(&self) -> &'static str {
+        match self {
+            Rule::ScriptSize { .. } => "script-size",
+            Rule::Weight { .. } => "tx-weight",
+            Rule::Sigops { .. } => "sigops",
+            Rule::OpReturnSize { .. } => "op-return-size",
+            Rule::OpReturns { .. } => "multiple-op-returns",
+            Rule::NonstandardOutput { .. } => "nonstandard-output",
+            Rule::Dust { .. } => "dust",
+        }
+    }
+}
+
+/// A contract breaking a `Rule`, see the module docs
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    /// the template breaking it, if not the contract's own script or a
+    /// template being built
+    pub template: Option<sha256::Hash>,
+    /// the rule
+    pub rule: Rule,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.rule.name())?;
+        if let Some(h) = self.template {
+            write!(f, " in template {}", h)?;
+        }
+        match &self.rule {
+            Rule::ScriptSize { size, limit } => {
+                write!(f, ": witness script of {} bytes, over {}", size, limit)
+            }
+            Rule::Weight { weight, limit } => write!(f, ": weight {}, over {}", weight, limit),
+            Rule::Sigops { cost, limit } => write!(f, ": sigops cost {}, over {}", cost, limit),
+            Rule::OpReturnSize { output, size } => write!(
+                f,
+                ": output {} is {} bytes, over {}",
+                output, size, MAX_OP_RETURN_RELAY
+            ),
+            Rule::OpReturns { count } => write!(f, ": {} OP_RETURN outputs", count),
+            Rule::NonstandardOutput { output } => write!(f, ": output {}", output),
+            Rule::Dust {
+                output,
+                amount,
+                limit,
+            } => write!(f, ": output {} pays {}, below {}", output, amount, limit),
+        }
+    }
+}
+
+/// is `script` of a type relayed by default
+pub fn is_standard_script(script: &Script) -> bool {
+    script.is_p2pkh()
+        || script.is_p2pk()
+        || script.is_p2sh()
+        || script.is_v0_p2wpkh()
+        || script.is_v0_p2wsh()
+        || script.is_witness_program()
+        || (script.is_op_return() && script.len() <= MAX_OP_RETURN_RELAY)
+}
+
+/// The rule adding output `output`, an OP_RETURN of `script`, to a template
+/// with `others` OP_RETURN outputs already breaks under `policy`, if any
+pub fn check_data_carrier(
+    output: usize,
+    script: &Script,
+    others: usize,
+    policy: RelayPolicy,
+) -> Option<Rule> {
+    if policy == RelayPolicy::Consensus {
+        None
+    } else if script.len() > MAX_OP_RETURN_RELAY {
+        Some(Rule::OpReturnSize {
+            output,
+            size: script.len(),
+        })
+    } else if others > 0 {
+        Some(Rule::OpReturns { count: others + 1 })
+    } else {
+        None
+    }
+}
+
+/// The signature operations of `script`, counting a multisig's keys if
+/// `accurate` and the most it may have otherwise, as Bitcoin Core does.
+fn sigops(script: &Script, accurate: bool) -> u64 {
+    let mut count = 0;
+    let mut last = None;
+    for ins in script.instructions() {
+        let op = match ins {
+            Ok(Instruction::Op(op)) => op,
+            Ok(Instruction::PushBytes(_)) => {
+                last = None;
+                continue;
+            }
+            // the rest can't be parsed, nor so executed
+            Err(_) => break,
+        };
+        if op == OP_CHECKSIG || op == OP_CHECKSIGVERIFY {
+            count += 1;
+        } else if op == OP_CHECKMULTISIG || op == OP_CHECKMULTISIGVERIFY {
+            count += match last {
+                Some(n)
+                    if accurate
+                        && (OP_PUSHNUM_1.into_u8()..=OP_PUSHNUM_16.into_u8()).contains(&n) =>
+                {
+                    (n - OP_PUSHNUM_1.into_u8() + 1) as u64
+                }
+                _ => 20,
+            };
+        }
+        last = Some(op.into_u8());
+    }
+    count
+}
+
+/// the violations of `template` of `object`, breaking `policy`
+fn check_template(object: &Compiled, template: &Template, policy: RelayPolicy) -> Vec<Rule> {
+    let mut rules = vec![];
+    let tx = &template.tx;
+    let limit = match policy {
+        RelayPolicy::Standard => MAX_STANDARD_TX_WEIGHT,
+        RelayPolicy::Consensus => MAX_BLOCK_WEIGHT,
+    };
+    let weight = weight::upper_bound(object, template).map_or_else(
+        || tx.get_weight() as u64 + 2 + DEFAULT_WITNESS_SIZE * tx.input.len() as u64,
+        |w| w.weight,
+    );
+    if weight > limit {
+        rules.push(Rule::Weight { weight, limit });
+    }
+    let limit = match policy {
+        RelayPolicy::Standard => MAX_STANDARD_TX_SIGOPS_COST,
+        RelayPolicy::Consensus => MAX_BLOCK_SIGOPS_COST,
+    };
+    let witness = object
+        .descriptor
+        .as_ref()
+        .map_or(0, |d| sigops(&d.explicit_script(), true));
+    let cost = witness
+        + tx.output
+            .iter()
+            .map(|o| sigops(&o.script_pubkey, false) * WITNESS_SCALE_FACTOR)
+            .sum::<u64>();
+    if cost > limit {
+        rules.push(Rule::Sigops { cost, limit });
+    }
+    if policy == RelayPolicy::Consensus {
+        return rules;
+    }
+    let mut op_returns = 0;
+    for (output, o) in template.outputs.iter().enumerate() {
+        let script: Script = o.contract.address.clone().into();
+        if script.is_op_return() {
+            op_returns += 1;
+            if script.len() > MAX_OP_RETURN_RELAY {
+                rules.push(Rule::OpReturnSize {
+                    output,
+                    size: script.len(),
+                });
+            }
+        } else if !is_standard_script(&script) {
+            rules.push(Rule::NonstandardOutput { output });
+        }
+        let limit = dust_limit(&script);
+        // an ephemeral anchor, see `template::anchor`
+        let ephemeral = o.amount == Amount::from_sat(0)
+            && script == pay_to_anchor()
+            && tx.version == 3
+            && template.max == template.total_amount();
+        if o.amount < limit && !ephemeral {
+            rules.push(Rule::Dust {
+                output,
+                amount: o.amount,
+                limit,
+            });
+        }
+    }
+    if op_returns > 1 {
+        rules.push(Rule::OpReturns { count: op_returns });
+    }
+    rules
+}
+
+/// Every limit `object` breaks under `policy`, see the module docs.
+pub fn check(object: &Compiled, policy: RelayPolicy) -> Vec<Violation> {
+    let mut violations = vec![];
+    if let Some(descriptor) = &object.descriptor {
+        let size = descriptor.explicit_script().len();
+        let limit = match policy {
+            RelayPolicy::Standard => MAX_STANDARD_P2WSH_SCRIPT_SIZE,
+            RelayPolicy::Consensus => MAX_SCRIPT_SIZE,
+        };
+        if size > limit {
+            violations.push(Violation {
+                template: None,
+                rule: Rule::ScriptSize { size, limit },
+            });
+        }
+    }
+    let mut templates: Vec<&Template> = object
+        .ctv_to_tx
+        .values()
+        .chain(object.suggested_txs.values())
+        .collect();
+    templates.sort_by_key(|t| t.hash());
+    for t in templates {
+        violations.extend(
+            check_template(object, t, policy)
+                .into_iter()
+                .map(|rule| Violation {
+                    template: Some(t.hash()),
+                    rule,
+                }),
+        );
+    }
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::{Compilable, CompilationError};
+    use crate::fixtures::*;
+
+    #[test]
+    fn standardness() {
+        let ctx = ctx(1.0);
+        let dust = TestEmulation {
+            to_contract: to(),
+            amount: Amount::from_sat(100),
+            timeout: 4,
+        };
+        let err = dust.compile(&ctx).unwrap_err();
+        assert_eq!(err.code(), "nonstandard");
+        assert!(err.to_string().contains(": dust in template"));
+        assert!(dust
+            .compile(&ctx.with_relay_policy(RelayPolicy::Consensus))
+            .is_ok());
+
+        // an OP_RETURN output needs no amount
+        let memo = TestEmulation {
+            to_contract: Compiled::from_op_return(&b"memo"[..]).unwrap(),
+            amount: Amount::from_sat(0),
+            timeout: 4,
+        };
+        assert!(memo.compile(&ctx).is_ok());
+
+        // too many outputs to relay, though not to mine
+        let fan = Fan {
+            n: 2000,
+            amount: Amount::from_btc(1.0).unwrap(),
+            to: address(),
+        };
+        match fan.compile(&ctx) {
+            Err(CompilationError::Located(d)) => match d.error {
+                CompilationError::Nonstandard(v) => {
+                    assert!(v.template.is_some());
+                    assert!(matches!(v.rule, Rule::Weight { limit: 400_000, .. }));
+                }
+                e => panic!("expected a nonstandard template, got {:?}", e),
+            },
+            r => panic!("expected a located error, got {:?}", r.map(|_| ())),
+        }
+        assert!(fan
+            .compile(&ctx.with_relay_policy(RelayPolicy::Consensus))
+            .is_ok());
+    }
+}
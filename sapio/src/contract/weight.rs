@@ -0,0 +1,274 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Estimating the size of each template of a compiled object once signed, so
+//! callers can work out the fees each path really needs (see
+//! `Object::weights`).
+//!
+//! A template's transaction is fixed when compiled, except for the witness
+//! of its first input, spending the contract, which depends on how the
+//! contract was compiled and the branch taken:
+//!
+//! - to taproot, the template is spent with the leaf checking it (see
+//!   `TaprootOutput::leaf_for`), whether by CTV or an emulator's key, so the
+//!   witness is exact: a signature for each key, a preimage for each
+//!   hashlock, the leaf's script, and its control block. A suggested
+//!   template, which no leaf checks, is spent with the key path if any, or
+//!   else the cheapest leaf checking no template;
+//! - to segwit v0, the witness is the witness script, a signature for each
+//!   key (of the largest size, 73 bytes), a preimage for each hashlock, and
+//!   up to two bytes for each choice between branches the script makes, of
+//!   the cheapest way of satisfying the policy with the template (see
+//!   `explore`). It is an upper bound, tight for simple policies.
+//!
+//! Any other inputs' witnesses are assumed to be `DEFAULT_WITNESS_SIZE`.
+use super::explore::alternatives;
+use super::Compiled;
+use crate::template::fees::{self, DEFAULT_WITNESS_SIZE};
+use crate::template::Template;
+use bitcoin::util::amount::Amount;
+use miniscript::DescriptorTrait;
+use sapio_base::taproot::TapLeaf;
+use sapio_base::Clause;
+use serde::{Deserialize, Serialize};
+
+/// The largest size of an ECDSA signature, DER encoded with a sighash byte,
+/// with its length
+const ECDSA_SIG: u64 = 1 + 73;
+/// The size of a Schnorr signature (BIP-340) with the default sighash, with
+/// its length
+const SCHNORR_SIG: u64 = 1 + 64;
+/// The size of a hashlock's preimage, with its length
+const PREIMAGE: u64 = 1 + 32;
+
+/// The estimated size of a template once signed, see the module docs
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TemplateWeight {
+    /// the size of the witness spending the contract, in bytes
+    pub witness: u64,
+    /// the weight of the signed transaction
+    pub weight: u64,
+    /// the virtual size of the signed transaction
+    pub vsize: u64,
+}
+
+impl TemplateWeight {
+    /// the fees paying `sat_per_vbyte` for the template
+    pub fn fee_for(&self, sat_per_vbyte: u64) -> Amount {
+        fees::fee_for(sat_per_vbyte, self.vsize)
+    }
+}
+
+/// the size of `n` as a compact size (varint)
+fn varint(n: u64) -> u64 {
+    match n {
+        0..=0xfc => 1,
+        0xfd..=0xffff => 3,
+        0x1_0000..=0xffff_ffff => 5,
+        _ => 9,
+    }
+}
+
+/// the size of a witness of `count` items, of `items` bytes in all (with
+/// their lengths)
+fn witness_size(count: u64, items: u64) -> u64 {
+    varint(count) + items
+}
+
+/// the size of a witness spending `leaf`
+fn leaf_witness(leaf: &TapLeaf) -> u64 {
+    let (mut count, mut items) = (2, 0);
+    for clause in leaf.clauses.iter() {
+        match clause {
+            Clause::Key(_) => {
+                count += 1;
+                items += SCHNORR_SIG;
+            }
+            // a signature for k keys, an empty item for the others
+            Clause::Threshold(k, keys) => {
+                count += keys.len() as u64;
+                items += *k as u64 * SCHNORR_SIG + (keys.len() - *k) as u64;
+            }
+            Clause::Sha256(_) | Clause::Hash256(_) | Clause::Ripemd160(_) | Clause::Hash160(_) => {
+                count += 1;
+                items += PREIMAGE;
+            }
+            _ => {}
+        }
+    }
+    let script = leaf.script.len() as u64;
+    let control = leaf.control_block.len() as u64;
+    witness_size(
+        count,
+        items + varint(script) + script + varint(control) + control,
+    )
+}
+
+/// the number of choices between branches satisfying `c` makes
+fn choices(c: &Clause) -> u64 {
+    match c {
+        Clause::And(subs) => subs.iter().map(choices).sum(),
+        Clause::Or(subs) => 1 + subs.iter().map(|(_, s)| choices(s)).sum::<u64>(),
+        Clause::Threshold(k, subs) if *k < subs.len() => {
+            subs.len() as u64 + subs.iter().map(choices).sum::<u64>()
+        }
+        Clause::Threshold(_, subs) => subs.iter().map(choices).sum(),
+        _ => 0,
+    }
+}
+
+/// the size of a segwit v0 witness with signatures of `keys` keys and
+/// `preimages` preimages, with a witness script of `script` bytes and
+/// `choices` choices between branches
+fn wsh_witness(keys: u64, preimages: u64, script: u64, choices: u64) -> u64 {
+    witness_size(
+        keys + preimages + choices + 1,
+        keys * ECDSA_SIG + preimages * PREIMAGE + 2 * choices + varint(script) + script,
+    )
+}
+
+/// the number of keys and of hashlocks in `c`, on any branch
+fn atoms(c: &Clause) -> (u64, u64) {
+    let subs: Vec<&Clause> = match c {
+        Clause::Key(_) => return (1, 0),
+        Clause::Sha256(_) | Clause::Hash256(_) | Clause::Ripemd160(_) | Clause::Hash160(_) => {
+            return (0, 1)
+        }
+        Clause::And(subs) | Clause::Threshold(_, subs) => subs.iter().collect(),
+        Clause::Or(subs) => subs.iter().map(|(_, s)| s).collect(),
+        _ => return (0, 0),
+    };
+    subs.into_iter()
+        .map(atoms)
+        .fold((0, 0), |(k, p), (dk, dp)| (k + dk, p + dp))
+}
+
+/// the size of `template` once the contract's witness is `witness` bytes
+fn signed(template: &Template, witness: u64) -> TemplateWeight {
+    let tx = &template.tx;
+    let others = tx.input.len().saturating_sub(1) as u64;
+    // with the segwit marker and flag
+    let weight = tx.get_weight() as u64 + 2 + witness + DEFAULT_WITNESS_SIZE * others;
+    TemplateWeight {
+        witness,
+        weight,
+        vsize: (weight + 3) / 4,
+    }
+}
+
+/// The estimated size of `template`, of `object`, once signed, see the
+/// module docs. None if the object has neither a descriptor nor a taproot
+/// output, or the template can't be spent from it.
+pub fn estimate(object: &Compiled, template: &Template) -> Option<TemplateWeight> {
+    let h = template.hash();
+    let ctv = object.ctv_to_tx.contains_key(&h);
+    let witness = if let Some(taproot) = &object.taproot {
+        match taproot.leaf_for(&h) {
+            Some(leaf) => leaf_witness(leaf),
+            None if !taproot.key_path.is_empty() => witness_size(1, SCHNORR_SIG),
+            None => taproot
+                .leaves
+                .iter()
+                .filter(|l| !l.clauses.iter().any(|c| matches!(c, Clause::TxTemplate(_))))
+                .map(leaf_witness)
+                .min()?,
+        }
+    } else if let (Some(descriptor), Some(policy)) = (&object.descriptor, &object.policy) {
+        let script = descriptor.explicit_script().len() as u64;
+        let choices = choices(policy);
+        alternatives(policy)
+            .iter()
+            .filter(|r| {
+                if ctv {
+                    r.templates.contains(&h)
+                } else {
+                    r.templates.is_empty()
+                }
+            })
+            .map(|r| {
+                wsh_witness(
+                    r.keys.len() as u64,
+                    r.preimages.len() as u64,
+                    script,
+                    choices,
+                )
+            })
+            .min()?
+    } else {
+        return None;
+    };
+    Some(signed(template, witness))
+}
+
+/// An upper bound on the size of `template`, of `object`, once signed,
+/// however the contract is spent. Unlike `estimate` it doesn't enumerate
+/// the ways of satisfying the contract's policy, so is cheap to compute for
+/// any policy. None if the object has neither a descriptor nor a taproot
+/// output.
+pub fn upper_bound(object: &Compiled, template: &Template) -> Option<TemplateWeight> {
+    let witness = if let Some(taproot) = &object.taproot {
+        match taproot.leaf_for(&template.hash()) {
+            Some(leaf) => leaf_witness(leaf),
+            None => taproot
+                .leaves
+                .iter()
+                .map(leaf_witness)
+                .fold(witness_size(1, SCHNORR_SIG), u64::max),
+        }
+    } else if let (Some(descriptor), Some(policy)) = (&object.descriptor, &object.policy) {
+        let (keys, preimages) = atoms(policy);
+        let script = descriptor.explicit_script().len() as u64;
+        wsh_witness(keys, preimages, script, choices(policy))
+    } else {
+        return None;
+    };
+    Some(signed(template, witness))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::{Compilable, CompileTarget};
+    use crate::fixtures::*;
+
+    #[test]
+    fn template_weights() {
+        let contract = TestEmulation {
+            to_contract: to(),
+            amount: Amount::from_btc(1.0).unwrap(),
+            timeout: 6,
+        };
+        let ctx = ctx(1.0);
+        // spending with CTV only reveals the witness script
+        let segwit = contract.compile(&ctx).unwrap();
+        let weights = segwit.weights();
+        let (h, tmpl) = segwit.ctv_to_tx.iter().next().unwrap();
+        let script = segwit.descriptor.as_ref().unwrap().explicit_script();
+        assert_eq!(weights[h].witness, 1 + 1 + script.len() as u64);
+        assert_eq!(
+            weights[h].weight,
+            tmpl.tx.get_weight() as u64 + 2 + weights[h].witness
+        );
+        assert_eq!(weights[h].vsize, (weights[h].weight + 3) / 4);
+
+        // with taproot, the leaf's script and control block
+        let taproot = contract
+            .compile(&ctx.with_target(CompileTarget::Taproot))
+            .unwrap();
+        let (h, _) = taproot.ctv_to_tx.iter().next().unwrap();
+        let leaf = taproot.taproot.as_ref().unwrap().leaf_for(h).unwrap();
+        assert_eq!(
+            taproot.weights()[h].witness,
+            1 + 1 + leaf.script.len() as u64 + 1 + leaf.control_block.len() as u64
+        );
+
+        // two of three keys sign
+        let committee = committee().compile(&ctx).unwrap();
+        let weight = committee.weights().values().next().cloned().unwrap();
+        assert!(weight.witness > 2 * 73);
+        assert_eq!(weight.fee_for(10).as_sat(), 10 * weight.vsize);
+    }
+}
@@ -0,0 +1,289 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Contracts and helpers shared by the crate's unit tests
+use crate::contract::*;
+use crate::util::split::{Rounding, Split};
+use bitcoin::util::amount::Amount;
+use sapio_base::timelocks::{RelHeight, RelTime};
+use sapio_ctv_emulator_trait::CTVAvailable;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// the address test contracts pay out to
+pub fn address() -> bitcoin::Address {
+    bitcoin::Address::from_str("bc1qxy2kgdygjrsqtzq2n0yrf2493p83kkfjhx0wlh").unwrap()
+}
+
+/// a contract paying `address`
+pub fn to() -> Compiled {
+    Compiled::from_address(address(), None)
+}
+
+/// a context of `btc` on regtest, with CTV
+pub fn ctx(btc: f64) -> Context {
+    Context::new(
+        bitcoin::Network::Regtest,
+        Amount::from_btc(btc).unwrap(),
+        Arc::new(CTVAvailable),
+    )
+}
+
+/// the key of the secret key `[i; 32]`
+pub fn key(i: u8) -> bitcoin::PublicKey {
+    let secp = bitcoin::secp256k1::Secp256k1::new();
+    let sk = bitcoin::secp256k1::SecretKey::from_slice(&[i; 32]).unwrap();
+    bitcoin::PublicKey {
+        compressed: true,
+        key: bitcoin::secp256k1::PublicKey::from_secret_key(&secp, &sk),
+    }
+}
+
+/// Pays `amount` to `to_contract` after a relative lock of `timeout`
+pub struct TestEmulation<T> {
+    pub to_contract: T,
+    pub amount: Amount,
+    pub timeout: u16,
+}
+
+impl<T> TestEmulation<T>
+where
+    T: Compilable,
+{
+    then! {
+        fn complete(self, ctx) {
+            ctx.template()
+                .add_output(self.amount, &self.to_contract, None)?
+                .set_sequence(0, RelTime::from(self.timeout).into())?
+                .into()
+        }
+    }
+}
+
+impl<T: Compilable + 'static> Contract for TestEmulation<T> {
+    declare! {then, Self::complete}
+    declare! {non updatable}
+}
+/// Pays `to_contract` after a relative lock of `timeout` blocks, which the
+/// template only sets if `lock`
+pub struct GuardedEmulation<T> {
+    pub to_contract: T,
+    pub amount: Amount,
+    pub timeout: u16,
+    pub lock: bool,
+}
+
+impl<T> GuardedEmulation<T>
+where
+    T: Compilable,
+{
+    guard! {fn timeout(self, _ctx) { RelHeight::from(self.timeout).into() }}
+    then! {
+        guarded_by: [Self::timeout]
+        fn complete(self, ctx) {
+            let builder = ctx.template().add_output(self.amount, &self.to_contract, None)?;
+            if self.lock {
+                builder.set_sequence(0, RelHeight::from(self.timeout).into())?.into()
+            } else {
+                builder.into()
+            }
+        }
+    }
+}
+
+impl<T: Compilable + 'static> Contract for GuardedEmulation<T> {
+    declare! {then, Self::complete}
+    declare! {non updatable}
+}
+
+/// Pays `to` whatever effects supplied after funding ask for, if `key` signs
+pub struct Pool {
+    pub key: bitcoin::PublicKey,
+    pub to: bitcoin::Address,
+}
+
+/// The arguments of `Pool::pay`
+#[derive(serde_derive::Deserialize, schemars::JsonSchema)]
+pub struct Payout {
+    pub sats: u64,
+}
+
+impl Pool {
+    guard! {fn signed(self, _ctx) { sapio_base::Clause::Key(self.key) }}
+    finish! {
+        guarded_by: [Self::signed]
+        fn pay(self, ctx, o) {
+            match o {
+                Some(payout) => {
+                    let to = Compiled::from_address(self.to.clone(), None);
+                    let tmpl = ctx
+                        .template()
+                        .add_output(Amount::from_sat(payout.sats), &to, None)?
+                        .into();
+                    Ok(Box::new(std::iter::once(tmpl)))
+                }
+                None => Ok(Box::new(std::iter::empty())),
+            }
+        }
+    }
+}
+
+impl Contract for Pool {
+    declare! {updatable<Payout>, Self::pay}
+    declare! {effects}
+}
+
+/// The data of an `Escrow`
+#[derive(Clone)]
+pub struct EscrowData {
+    pub buyer: bitcoin::PublicKey,
+    pub arbiter: bitcoin::PublicKey,
+    pub seller: bitcoin::Address,
+}
+
+state_machine! {
+    /// Releases funds to the seller after a day, unless the buyer disputes
+    /// it first and the arbiter resolves it
+    pub machine Escrow<EscrowData> {
+        /// awaiting release or a dispute
+        state Funded {
+            transition dispute -> Disputed guarded_by [Self::buyer];
+            transition release -> Released after RelHeight::from(144);
+        }
+        /// awaiting the arbiter
+        state Disputed {
+            transition resolve -> Released guarded_by [Self::arbiter];
+        }
+        /// paying the seller
+        state Released {
+            then [Self::pay_seller];
+        }
+    }
+}
+
+impl<S> Escrow<S> {
+    guard! {fn buyer(self, _ctx) { sapio_base::Clause::Key(self.data.buyer) }}
+    guard! {fn arbiter(self, _ctx) { sapio_base::Clause::Key(self.data.arbiter) }}
+    then! {
+        fn pay_seller(self, ctx) {
+            let seller = Compiled::from_address(self.data.seller.clone(), None);
+            ctx.template().add_output(ctx.funds(), &seller, None)?.into()
+        }
+    }
+}
+
+/// an `Escrow` between `key(1)` and `key(2)`, paying `address`
+pub fn escrow() -> Escrow<Funded> {
+    Escrow::new(EscrowData {
+        buyer: key(1),
+        arbiter: key(2),
+        seller: address(),
+    })
+}
+
+/// Splits `amount` between `n` contracts paying `to` after a timeout, added
+/// together so they may be compiled in parallel
+pub struct Fan {
+    pub n: u64,
+    pub amount: Amount,
+    pub to: bitcoin::Address,
+}
+
+impl Fan {
+    then! {
+        fn split(self, ctx) {
+            let split = Split::even(self.amount, self.n as usize, Rounding::RemainderToFee)?;
+            let children: Vec<_> = split
+                .shares()
+                .iter()
+                .enumerate()
+                .map(|(i, each)| TestEmulation {
+                    to_contract: Compiled::from_address(self.to.clone(), None),
+                    amount: *each,
+                    timeout: i as u16,
+                })
+                .collect();
+            ctx.template()
+                .add_outputs(
+                    children
+                        .iter()
+                        .map(|c| (c.amount, c as &(dyn Compilable + Sync), None))
+                        .collect(),
+                )?
+                .into()
+        }
+    }
+}
+
+impl Contract for Fan {
+    declare! {then, Self::split}
+    declare! {non updatable}
+}
+
+/// a `Fan` of 1 BTC between `n` contracts, paying `address`
+pub fn fan(n: u64) -> Fan {
+    Fan {
+        n,
+        amount: Amount::from_btc(1.0).unwrap(),
+        to: address(),
+    }
+}
+
+/// Pays `to` once two of three keys sign
+pub struct Committee {
+    pub keys: Vec<bitcoin::PublicKey>,
+    pub to: bitcoin::Address,
+}
+
+impl Committee {
+    guard! {fn alice(self, _ctx) { sapio_base::Clause::Key(self.keys[0]) }}
+    guard! {fn bob(self, _ctx) { sapio_base::Clause::Key(self.keys[1]) }}
+    guard! {fn carol(self, _ctx) { sapio_base::Clause::Key(self.keys[2]) }}
+    guard! {fn two = threshold(2)[Self::alice, Self::bob, Self::carol]}
+    then! {
+        annotated: {label: "pay", roles: ["alice", "bob", "carol"]}
+        guarded_by: [Self::two]
+        fn pay(self, ctx) {
+            let to = Compiled::from_address(self.to.clone(), None);
+            ctx.template().add_output(ctx.funds(), &to, None)?.into()
+        }
+    }
+}
+
+impl Contract for Committee {
+    declare! {then, Self::pay}
+    declare! {non updatable}
+}
+
+/// a `Committee` of `key(1)`, `key(2)` and `key(3)`, paying `address`
+pub fn committee() -> Committee {
+    Committee {
+        keys: (1..4).map(key).collect(),
+        to: address(),
+    }
+}
+
+/// Holds funds for `key`, who may upgrade it to a new version after a day
+pub struct Upgradable {
+    pub key: bitcoin::PublicKey,
+}
+
+impl Upgradable {
+    guard! {fn owner(self, _ctx) { sapio_base::Clause::Key(self.key) }}
+    guard! {fn delay(self, _ctx) { RelHeight::from(144).into() }}
+    guard! {fn upgrader = all_of[Self::owner, Self::delay]}
+    finish! {
+        annotated: {label: "upgrade", roles: ["owner"], doc: "moves the funds to a new version after a day"}
+        guarded_by: [Self::upgrader]
+        fn upgrade(self, _ctx, _o) {
+            Ok(Box::new(std::iter::empty()))
+        }
+    }
+}
+
+impl Contract for Upgradable {
+    declare! {updatable<Payout>, Self::upgrade}
+}
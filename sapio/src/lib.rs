@@ -11,7 +11,12 @@ extern crate serde;
 
 #[macro_use]
 pub mod contract;
+#[cfg(test)]
+mod fixtures;
+pub mod simulation;
 pub mod template;
+#[cfg(feature = "testing")]
+pub mod testing;
 pub mod util;
 
 pub use sapio_base;
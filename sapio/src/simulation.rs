@@ -0,0 +1,431 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Simulating a compiled contract against a virtual chain, to audit it
+//! before funding it.
+//!
+//! A `Simulator` walks every template of a `Compiled` object, and of the
+//! objects its outputs create, from a starting `ChainState`. Blocks are
+//! assumed to arrive every `block_interval` seconds, so the chain's
+//! median-time-past advances with its height. Each branch is reported with
+//! the earliest height it can confirm at, given its lock time and its
+//! relative lock time on the contract's input, or why it never can: the
+//! contract is underfunded for it, it can't confirm before the horizon, or
+//! its parent can't confirm. Objects without templates (e.g. an address)
+//! are where funds leave the contract, and the outcomes of the simulation
+//! are the balances they end up with, one per combination of branches.
+//!
+//! Only templates are simulated: spends authorized by keys alone (e.g.
+//! finish functions) are not, as their transactions aren't known.
+use crate::contract::Compiled;
+use crate::template::Template;
+use bitcoin::hashes::sha256;
+use bitcoin::util::amount::Amount;
+use bitcoin::Script;
+use std::collections::BTreeMap;
+
+/// Lock times below this are heights, and at or above it times
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+/// If set, a sequence has no relative lock time (BIP-68)
+const SEQUENCE_DISABLE_FLAG: u32 = 1 << 31;
+/// If set, a relative lock time is in units of 512 seconds (BIP-68)
+const SEQUENCE_TYPE_FLAG: u32 = 1 << 22;
+const SEQUENCE_MASK: u32 = 0x0000ffff;
+
+/// The tip of the virtual chain
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ChainState {
+    /// the height of the tip
+    pub height: u32,
+    /// the median-time-past of the tip
+    pub median_time_past: u32,
+}
+
+/// Why a branch can never confirm
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeadBranch {
+    /// the contract has less than the template spends
+    Underfunded {
+        /// what the template spends
+        required: Amount,
+        /// what the contract has
+        available: Amount,
+    },
+    /// the branch's lock times are not reached before the horizon
+    BeyondHorizon(ChainState),
+    /// the template creating the contract can't confirm
+    ParentDead,
+}
+
+/// One template of the contract, see the module docs
+#[derive(Clone, Debug)]
+pub struct Branch {
+    /// the template's hash
+    pub template: sha256::Hash,
+    /// the template's label, if any
+    pub label: Option<String>,
+    /// the template spending to the contract this one spends, if any
+    pub parent: Option<sha256::Hash>,
+    /// how many templates precede this one
+    pub depth: usize,
+    /// if the template is enforced by CTV, rather than suggested
+    pub ctv: bool,
+    /// the earliest tip the template can confirm in, or why it never can
+    pub reachable: Result<ChainState, DeadBranch>,
+    /// the fees paid, if the contract was funded for the template
+    pub fees: Amount,
+}
+
+/// The funds at each place funds leave the contract, for one combination of
+/// branches
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Outcome {
+    /// the templates confirmed, in the order they are walked
+    pub templates: Vec<sha256::Hash>,
+    /// the funds received by each script
+    pub balances: BTreeMap<Script, Amount>,
+    /// the fees paid across the templates
+    pub fees: Amount,
+    /// the earliest tip at which every template has confirmed
+    pub settled: ChainState,
+}
+
+/// The result of `Simulator::run`
+#[derive(Clone, Debug)]
+pub struct SimulationReport {
+    /// every branch, parents before their children
+    pub branches: Vec<Branch>,
+    /// every outcome in which every branch taken confirms
+    pub outcomes: Vec<Outcome>,
+    /// if there were more outcomes than `Simulator::with_max_outcomes`
+    pub truncated: bool,
+}
+
+impl SimulationReport {
+    /// the branches which can never confirm
+    pub fn dead_branches(&self) -> impl Iterator<Item = &Branch> {
+        self.branches.iter().filter(|b| b.reachable.is_err())
+    }
+    /// the branches which can confirm, with the earliest tip they can
+    pub fn reachable_branches(&self) -> impl Iterator<Item = (&Branch, ChainState)> {
+        self.branches
+            .iter()
+            .filter_map(|b| b.reachable.clone().ok().map(|s| (b, s)))
+    }
+}
+
+/// Simulates compiled contracts, see the module docs.
+#[derive(Clone, Debug)]
+pub struct Simulator {
+    start: ChainState,
+    block_interval: u32,
+    horizon: u32,
+    max_outcomes: usize,
+}
+
+impl Simulator {
+    /// a simulator starting at `start`, with ten minute blocks, a horizon of
+    /// about ten years, and at most 1000 outcomes
+    pub fn new(start: ChainState) -> Self {
+        Simulator {
+            start,
+            block_interval: 600,
+            horizon: 525_960,
+            max_outcomes: 1000,
+        }
+    }
+    /// assume blocks arrive every `seconds`
+    pub fn with_block_interval(mut self, seconds: u32) -> Self {
+        self.block_interval = seconds.max(1);
+        self
+    }
+    /// report branches which can't confirm within `blocks` of the start as
+    /// dead
+    pub fn with_horizon(mut self, blocks: u32) -> Self {
+        self.horizon = blocks;
+        self
+    }
+    /// report at most `n` outcomes
+    pub fn with_max_outcomes(mut self, n: usize) -> Self {
+        self.max_outcomes = n;
+        self
+    }
+
+    /// Simulates `object` funded with `funding` at the start, see the module
+    /// docs.
+    pub fn run(&self, object: &Compiled, funding: Amount) -> SimulationReport {
+        let mut branches = vec![];
+        let mut truncated = false;
+        let outcomes = self.walk(
+            object,
+            funding,
+            Ok(self.start),
+            None,
+            0,
+            &mut branches,
+            &mut truncated,
+        );
+        SimulationReport {
+            branches,
+            outcomes,
+            truncated,
+        }
+    }
+
+    /// the median-time-past at `height`
+    fn mtp_at(&self, height: u32) -> u32 {
+        let blocks = height.saturating_sub(self.start.height);
+        self.start
+            .median_time_past
+            .saturating_add(blocks.saturating_mul(self.block_interval))
+    }
+
+    /// the first height at which the median-time-past exceeds `time`
+    fn height_after(&self, time: u32) -> u32 {
+        if time < self.start.median_time_past {
+            return self.start.height;
+        }
+        let blocks = (time - self.start.median_time_past) / self.block_interval + 1;
+        self.start.height.saturating_add(blocks)
+    }
+
+    /// the tip at `height`
+    fn state_at(&self, height: u32) -> ChainState {
+        ChainState {
+            height,
+            median_time_past: self.mtp_at(height),
+        }
+    }
+
+    /// The earliest tip `tx` can confirm in, spending an output confirmed in
+    /// `parent`: the next block, and the block after its lock times.
+    fn earliest(&self, tx: &bitcoin::Transaction, parent: ChainState) -> ChainState {
+        let mut height = parent.height.saturating_add(1);
+        let locked = tx.input.iter().any(|i| i.sequence != u32::MAX);
+        if locked && tx.lock_time != 0 {
+            // the lock time must be below the height or time of the block
+            // (BIP-113)
+            height = height.max(if tx.lock_time < LOCKTIME_THRESHOLD {
+                tx.lock_time.saturating_add(1)
+            } else {
+                self.height_after(tx.lock_time).saturating_add(1)
+            });
+        }
+        let sequence = tx
+            .input
+            .get(0)
+            .map_or(SEQUENCE_DISABLE_FLAG, |i| i.sequence);
+        if tx.version >= 2 && sequence & SEQUENCE_DISABLE_FLAG == 0 {
+            let value = sequence & SEQUENCE_MASK;
+            height = height.max(if sequence & SEQUENCE_TYPE_FLAG != 0 {
+                let time = parent
+                    .median_time_past
+                    .saturating_add(value.saturating_mul(512));
+                // the time must be reached by the block before (BIP-68)
+                self.height_after(time.saturating_sub(1)).saturating_add(1)
+            } else {
+                parent.height.saturating_add(value)
+            });
+        }
+        self.state_at(height)
+    }
+
+    /// Records the branches of `object`, confirmed at `confirmed` with
+    /// `amount`, returning its outcomes.
+    #[allow(clippy::too_many_arguments)]
+    fn walk(
+        &self,
+        object: &Compiled,
+        amount: Amount,
+        confirmed: Result<ChainState, DeadBranch>,
+        parent: Option<sha256::Hash>,
+        depth: usize,
+        branches: &mut Vec<Branch>,
+        truncated: &mut bool,
+    ) -> Vec<Outcome> {
+        let mut templates: Vec<(&Template, bool)> = object
+            .ctv_to_tx
+            .values()
+            .map(|t| (t, true))
+            .chain(object.suggested_txs.values().map(|t| (t, false)))
+            .collect();
+        // in a deterministic order
+        templates.sort_by_key(|(t, _)| t.hash());
+        let confirmed_at = match &confirmed {
+            Ok(state) => *state,
+            Err(_) => self.start,
+        };
+        if templates.is_empty() {
+            let mut balances = BTreeMap::new();
+            balances.insert(Script::from(object.address.clone()), amount);
+            return vec![Outcome {
+                templates: vec![],
+                balances,
+                fees: Amount::from_sat(0),
+                settled: confirmed_at,
+            }];
+        }
+        let mut outcomes = vec![];
+        for (template, ctv) in templates {
+            let required = template.total_amount();
+            let reachable = confirmed
+                .clone()
+                .map_err(|_| DeadBranch::ParentDead)
+                .and_then(|parent_state| {
+                    if amount < required {
+                        return Err(DeadBranch::Underfunded {
+                            required,
+                            available: amount,
+                        });
+                    }
+                    let state = self.earliest(&template.tx, parent_state);
+                    if state.height > self.start.height.saturating_add(self.horizon) {
+                        Err(DeadBranch::BeyondHorizon(state))
+                    } else {
+                        Ok(state)
+                    }
+                });
+            let fees = if amount < required {
+                Amount::from_sat(0)
+            } else {
+                amount - required
+            };
+            let h = template.hash();
+            branches.push(Branch {
+                template: h,
+                label: template.metadata_map_s2s.label().map(String::from),
+                parent,
+                depth,
+                ctv,
+                reachable: reachable.clone(),
+                fees,
+            });
+            // every combination of the outcomes of each output
+            let mut combined = vec![Outcome {
+                templates: vec![h],
+                balances: BTreeMap::new(),
+                fees,
+                settled: confirmed_at,
+            }];
+            for output in template.outputs.iter() {
+                let children = self.walk(
+                    &output.contract,
+                    output.amount,
+                    reachable.clone(),
+                    Some(h),
+                    depth + 1,
+                    branches,
+                    truncated,
+                );
+                let mut next = vec![];
+                for so_far in combined.iter() {
+                    for child in children.iter() {
+                        if next.len() >= self.max_outcomes {
+                            *truncated = true;
+                            break;
+                        }
+                        next.push(merge(so_far, child));
+                    }
+                }
+                combined = next;
+            }
+            if let Ok(state) = reachable {
+                for mut outcome in combined {
+                    if outcomes.len() >= self.max_outcomes {
+                        *truncated = true;
+                        break;
+                    }
+                    outcome.settled = outcome.settled.max(state);
+                    outcomes.push(outcome);
+                }
+            }
+        }
+        outcomes
+    }
+}
+
+/// the outcome of both `a` and `b` happening
+fn merge(a: &Outcome, b: &Outcome) -> Outcome {
+    let mut merged = a.clone();
+    merged.templates.extend(b.templates.iter().cloned());
+    for (script, amount) in b.balances.iter() {
+        let balance = merged
+            .balances
+            .entry(script.clone())
+            .or_insert_with(|| Amount::from_sat(0));
+        *balance += *amount;
+    }
+    merged.fees += b.fees;
+    merged.settled = merged.settled.max(b.settled);
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::Compilable;
+    use crate::fixtures::*;
+
+    #[test]
+    fn simulation() {
+        let contract = TestEmulation {
+            to_contract: TestEmulation {
+                to_contract: to(),
+                amount: Amount::from_btc(1.0).unwrap(),
+                timeout: 6,
+            },
+            amount: Amount::from_btc(1.0).unwrap(),
+            timeout: 4,
+        };
+        let compiled = contract.compile(&ctx(1.0)).unwrap();
+        let start = ChainState {
+            height: 100,
+            median_time_past: 1_600_000_000,
+        };
+        let simulator = Simulator::new(start);
+
+        // each template waits out its relative lock time (in units of 512
+        // seconds) after its parent confirms
+        let report = simulator.run(&compiled, Amount::from_btc(1.0).unwrap());
+        let heights: Vec<_> = report
+            .reachable_branches()
+            .map(|(b, s)| (b.depth, s.height))
+            .collect();
+        assert_eq!(heights, vec![(0, 105), (1, 112)]);
+        assert_eq!(report.dead_branches().count(), 0);
+        assert_eq!(report.outcomes.len(), 1);
+        let outcome = &report.outcomes[0];
+        assert_eq!(outcome.settled.height, 112);
+        assert_eq!(
+            outcome.balances.get(&address().script_pubkey()),
+            Some(&Amount::from_btc(1.0).unwrap())
+        );
+
+        // underfunded, nothing confirms
+        let report = simulator.run(&compiled, Amount::from_btc(0.5).unwrap());
+        assert!(report.outcomes.is_empty());
+        match &report.branches[..] {
+            [outer, inner] => {
+                assert!(matches!(
+                    outer.reachable,
+                    Err(DeadBranch::Underfunded { .. })
+                ));
+                assert_eq!(inner.reachable, Err(DeadBranch::ParentDead));
+            }
+            _ => panic!("expected two branches"),
+        }
+
+        // the inner branch can't confirm within the horizon
+        let report = simulator
+            .with_horizon(8)
+            .run(&compiled, Amount::from_btc(1.0).unwrap());
+        assert!(report.outcomes.is_empty());
+        assert!(matches!(
+            report.branches[1].reachable,
+            Err(DeadBranch::BeyondHorizon(_))
+        ));
+    }
+}
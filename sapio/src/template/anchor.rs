@@ -0,0 +1,216 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Anchor outputs added to every template a context generates, so that a
+//! stuck contract transaction can be bumped by a child (CPFP).
+//!
+//! `Context::with_anchors` sets the anchors, and `Builder::finish` adds one
+//! output for each, in order, after the template's own outputs and before
+//! paying its fees, so each template, including those of the contracts it
+//! creates, has them. Their value is held back from the funds the context
+//! gives a contract's functions, so a template spending `ctx.funds()` still
+//! has room for them. Who may spend an anchor, and so bump the template, is
+//! set by its kind: anyone for a pay-to-anchor (P2A) output, the holder of a
+//! key, or whoever may spend a contract, e.g. any of the parties. Anchors
+//! count towards `FeeStrategy::Anchor`, and are labelled in their output's
+//! metadata under "anchor".
+//!
+//! An `Anchor::Ephemeral` anchor is a P2A output of no value, which nodes
+//! relay only in a transaction paying no fees (see `FeeStrategy::Anchor`) of
+//! version 3 (TRUC), so templates with one are made version 3, and fail with
+//! `CompilationError::EphemeralAnchorFees` if they pay any fees, including
+//! funds they leave unspent.
+use super::fees::ANCHOR_SATS;
+use super::OutputMeta;
+use crate::contract::{CompilationError, Compiled};
+use crate::util::extended_address::ExtendedAddress;
+use bitcoin::blockdata::opcodes::all::OP_PUSHNUM_1;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::util::amount::Amount;
+use bitcoin::{Network, Script};
+
+/// The value of a pay-to-anchor output, its dust limit
+pub const P2A_SATS: u64 = 240;
+/// The witness program of pay-to-anchor outputs
+const P2A_PROGRAM: [u8; 2] = [0x4e, 0x73];
+
+/// the pay-to-anchor (P2A) script, a witness v1 program spendable by anyone
+pub fn pay_to_anchor() -> Script {
+    Builder::new()
+        .push_opcode(OP_PUSHNUM_1)
+        .push_slice(&P2A_PROGRAM[..])
+        .into_script()
+}
+
+/// An anchor output, see the module docs
+#[derive(Clone, Debug)]
+pub enum Anchor {
+    /// a P2A output of `P2A_SATS`, which anyone may spend
+    PayToAnchor,
+    /// a P2A output of no value, which anyone may spend, see the module docs
+    Ephemeral,
+    /// a P2WPKH output of `ANCHOR_SATS` to a key, which only its holder may
+    /// spend
+    Key(bitcoin::PublicKey),
+    /// an output of `ANCHOR_SATS` to a contract, e.g. requiring the key of
+    /// any of the parties
+    Contract(Compiled),
+}
+
+impl Anchor {
+    /// the anchor's value
+    pub fn amount(&self) -> Amount {
+        Amount::from_sat(match self {
+            Anchor::PayToAnchor => P2A_SATS,
+            Anchor::Ephemeral => 0,
+            Anchor::Key(_) | Anchor::Contract(_) => ANCHOR_SATS,
+        })
+    }
+
+    /// the contract the anchor pays on `network`
+    pub fn contract(&self, network: Network) -> Result<Compiled, CompilationError> {
+        Ok(match self {
+            // our version of rust-bitcoin can't encode witness v1 addresses
+            Anchor::PayToAnchor | Anchor::Ephemeral => {
+                Compiled::from_extended_address(ExtendedAddress::Unknown(pay_to_anchor()))
+            }
+            Anchor::Key(key) => Compiled::from_address(
+                bitcoin::Address::p2wpkh(key, network).map_err(CompilationError::custom)?,
+                None,
+            ),
+            Anchor::Contract(contract) => contract.clone(),
+        })
+    }
+
+    /// the metadata of the anchor's output
+    pub fn metadata(&self) -> OutputMeta {
+        let kind = match self {
+            Anchor::PayToAnchor => "p2a",
+            Anchor::Ephemeral => "ephemeral",
+            Anchor::Key(_) => "key",
+            Anchor::Contract(_) => "contract",
+        };
+        vec![("anchor".to_string(), kind.to_string())]
+            .into_iter()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::Compilable;
+    use crate::fixtures::*;
+    use crate::template::fees::FeeStrategy;
+
+    #[test]
+    fn anchors() {
+        let ctx = ctx(1.0);
+        let anchored = ctx.with_anchors(vec![Anchor::PayToAnchor, Anchor::Key(key(1))]);
+        let contract = TestEmulation {
+            to_contract: TestEmulation {
+                to_contract: to(),
+                amount: Amount::from_btc(0.25).unwrap(),
+                timeout: 6,
+            },
+            amount: Amount::from_btc(0.5).unwrap(),
+            timeout: 4,
+        };
+        let compiled = contract.compile(&anchored).unwrap();
+        // every template, nested ones too, has both anchors after its outputs
+        let outer = compiled.ctv_to_tx.values().next().unwrap();
+        let inner = outer.outputs[0].contract.ctv_to_tx.values().next().unwrap();
+        for tmpl in [outer, inner].iter() {
+            assert_eq!(tmpl.outputs.len(), 3);
+            assert_eq!(tmpl.tx.output[1].script_pubkey, pay_to_anchor());
+            assert_eq!(tmpl.tx.output[1].value, P2A_SATS);
+            assert_eq!(tmpl.outputs[1].metadata["anchor"], "p2a");
+            assert_eq!(
+                tmpl.tx.output[2].script_pubkey,
+                bitcoin::Address::p2wpkh(&key(1), Network::Regtest)
+                    .unwrap()
+                    .script_pubkey()
+            );
+            assert_eq!(tmpl.tx.output[2].value, ANCHOR_SATS);
+            assert_eq!(tmpl.outputs[2].metadata["anchor"], "key");
+        }
+        // without anchors, the templates are unchanged
+        let plain = contract.compile(&ctx).unwrap();
+        assert_eq!(plain.ctv_to_tx.values().next().unwrap().outputs.len(), 1);
+
+        // anchors allow paying no fees
+        let tmpl = anchored
+            .template()
+            .add_output(Amount::from_btc(0.5).unwrap(), &compiled, None)
+            .unwrap()
+            .set_fee_strategy(FeeStrategy::Anchor)
+            .finish()
+            .unwrap();
+        assert_eq!(tmpl.tx.version, 2);
+        assert_eq!(tmpl.max, tmpl.total_amount());
+
+        // an ephemeral anchor has no value, and relays in a template paying no
+        // fees, of version 3
+        let ephemeral = ctx.with_anchors(vec![Anchor::Ephemeral]);
+        let compiled = TestEmulation {
+            to_contract: to(),
+            amount: Amount::from_btc(1.0).unwrap(),
+            timeout: 4,
+        }
+        .compile(&ephemeral)
+        .unwrap();
+        let tmpl = compiled.ctv_to_tx.values().next().unwrap();
+        assert_eq!(tmpl.tx.version, 3);
+        assert_eq!(tmpl.tx.output[1].value, 0);
+        assert_eq!(tmpl.tx.output[1].script_pubkey, pay_to_anchor());
+
+        // templates spending all of their funds leave room for the anchors
+        let compiled = committee()
+            .compile(&ctx.with_anchors(vec![Anchor::PayToAnchor, Anchor::Key(key(1))]))
+            .unwrap();
+        let tmpl = compiled.ctv_to_tx.values().next().unwrap();
+        assert_eq!(tmpl.outputs.len(), 3);
+        assert_eq!(
+            tmpl.tx.output[0].value,
+            Amount::from_btc(1.0).unwrap().as_sat() - P2A_SATS - ANCHOR_SATS
+        );
+        assert_eq!(tmpl.total_amount(), Amount::from_btc(1.0).unwrap());
+
+        // but a template with an ephemeral anchor may pay no fees
+        let partial = TestEmulation {
+            to_contract: to(),
+            amount: Amount::from_btc(0.5).unwrap(),
+            timeout: 4,
+        };
+        match partial.compile(&ephemeral).unwrap_err().root() {
+            CompilationError::EphemeralAnchorFees { fees } => {
+                assert_eq!(*fees, Amount::from_btc(0.5).unwrap())
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+        let err = ephemeral
+            .template()
+            .add_output(Amount::from_btc(0.5).unwrap(), &to(), None)
+            .unwrap()
+            .add_fees(Amount::from_btc(0.5).unwrap())
+            .unwrap()
+            .finish()
+            .unwrap_err();
+        assert_eq!(err.code(), "ephemeral-anchor-fees");
+        let err = ephemeral
+            .template()
+            .add_output(Amount::from_btc(0.5).unwrap(), &to(), None)
+            .unwrap()
+            .set_change(to(), None)
+            .set_fee_strategy(FeeStrategy::FeeRate {
+                sat_per_vbyte: 1,
+                witness_size: 0,
+            })
+            .finish()
+            .unwrap_err();
+        assert_eq!(err.code(), "ephemeral-anchor-fees");
+    }
+}
@@ -5,10 +5,16 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 //! Interactive Transaction Template Builder
+use super::fees::{self, FeeStrategy};
+use super::{Anchor, Commitment, Template, TemplateMetadata};
 pub use super::{Output, OutputMeta};
-use super::{Template, TemplateMetadata};
-use crate::contract::{CompilationError, Context};
+use crate::contract::analysis::PathStep;
+use crate::contract::budget::{self, Budgets};
+use crate::contract::standard;
+use crate::contract::{Compilable, CompilationError, Compiled, Context};
 use bitcoin::util::amount::Amount;
+use rayon::prelude::*;
+use sapio_base::timelocks::units::{AbsoluteLock, RelativeLock};
 use sapio_base::timelocks::*;
 use sapio_base::CTVHash;
 use std::collections::HashMap;
@@ -25,6 +31,10 @@ pub struct Builder {
     label: Option<String>,
     ctx: Context,
     fees: Amount,
+    fee_strategy: FeeStrategy,
+    /// the contract receiving the funds left over, see `set_change`
+    change: Option<(Box<dyn Compilable>, OutputMeta)>,
+    anchors: usize,
 }
 
 impl Builder {
@@ -37,6 +47,9 @@ impl Builder {
             lock_time: None,
             label: None,
             fees: Amount::from_sat(0),
+            fee_strategy: FeeStrategy::default(),
+            change: None,
+            anchors: 0,
             ctx,
         }
     }
@@ -59,6 +72,36 @@ impl Builder {
         Ok(c)
     }
 
+    /// set how the template pays its fees, see `FeeStrategy`. The strategy
+    /// is checked, and any fees it requires paid, by `finish`.
+    pub fn set_fee_strategy(mut self, strategy: FeeStrategy) -> Self {
+        self.fee_strategy = strategy;
+        self
+    }
+
+    /// Sends the funds left over once the outputs and fees are paid to
+    /// `contract`, compiled for that amount by `finish`. If there are fewer
+    /// than `fees::DUST_SATS` left over, they are paid as fees instead.
+    pub fn set_change<C: Compilable + 'static>(
+        mut self,
+        contract: C,
+        metadata: Option<OutputMeta>,
+    ) -> Self {
+        self.change = Some((Box::new(contract), metadata.unwrap_or_else(HashMap::new)));
+        self
+    }
+
+    /// Adds an anchor output of `fees::ANCHOR_SATS` to `contract`, which a
+    /// child can spend to pay (or bump) the template's fees.
+    pub fn add_anchor(
+        mut self,
+        contract: &dyn Compilable,
+        metadata: Option<OutputMeta>,
+    ) -> Result<Self, CompilationError> {
+        self.anchors += 1;
+        self.add_output(Amount::from_sat(fees::ANCHOR_SATS), contract, metadata)
+    }
+
     /// Creates a new Output, forcing the compilation of the compilable object and defaulting
     /// metadata if not provided to blank.
     pub fn add_output(
@@ -67,14 +110,125 @@ impl Builder {
         contract: &dyn crate::contract::Compilable,
         metadata: Option<OutputMeta>,
     ) -> Result<Self, CompilationError> {
+        let ctx = self.ctx.with_amount(amount)?.output(self.outputs.len());
+        let mut contract = contract
+            .compile(&ctx)
+            .map_err(|e| e.within(PathStep::Output(self.outputs.len())))?;
+        ctx.prune(&mut contract);
         self.outputs.push(Output {
             amount: amount,
-            contract: contract.compile(&self.ctx.with_amount(amount)?)?,
+            contract,
             metadata: metadata.unwrap_or_else(HashMap::new),
+            commitments: vec![],
         });
         self.spend_amount(amount)
     }
 
+    /// Adds a data carrier output of no value, an OP_RETURN embedding `data`,
+    /// e.g. a commitment, tag, or protocol identifier. Fails with
+    /// `CompilationError::Nonstandard` if it breaks the context's relay
+    /// policy (see `standard`): by default a template may have one data
+    /// carrier, of at most `standard::MAX_DATA_CARRIER` bytes.
+    pub fn add_data_carrier(
+        mut self,
+        data: &[u8],
+        metadata: Option<OutputMeta>,
+    ) -> Result<Self, CompilationError> {
+        let contract = Compiled::from_data_carrier(data);
+        let script: bitcoin::Script = contract.address.clone().into();
+        let others = self
+            .outputs
+            .iter()
+            .filter(|o| bitcoin::Script::from(o.contract.address.clone()).is_op_return())
+            .count();
+        let output = self.outputs.len();
+        if let Some(rule) =
+            standard::check_data_carrier(output, &script, others, self.ctx.relay_policy())
+        {
+            return Err(CompilationError::Nonstandard(standard::Violation {
+                template: None,
+                rule,
+            }));
+        }
+        self.outputs.push(Output {
+            amount: Amount::from_sat(0),
+            contract,
+            metadata: metadata.unwrap_or_else(HashMap::new),
+            commitments: vec![],
+        });
+        Ok(self)
+    }
+
+    /// Creates an Output, as `add_output` does, of the budget `name` of
+    /// `budgets`, checking the contract doesn't spend more than it (see
+    /// `budget`).
+    pub fn add_budgeted_output(
+        self,
+        budgets: &Budgets,
+        name: &str,
+        contract: &dyn Compilable,
+        metadata: Option<OutputMeta>,
+    ) -> Result<Self, CompilationError> {
+        let amount = budgets.amount(name)?;
+        let builder = self.add_output(amount, contract, metadata)?;
+        if let Some(output) = builder.outputs.last() {
+            budget::check(name, amount, &output.contract)?;
+        }
+        Ok(builder)
+    }
+
+    /// Creates an Output for each of `outputs`, in order, as `add_output`
+    /// does. If the context compiles in parallel (see
+    /// `Context::with_threads`), the contracts are compiled concurrently.
+    pub fn add_outputs(
+        mut self,
+        outputs: Vec<(Amount, &(dyn Compilable + Sync), Option<OutputMeta>)>,
+    ) -> Result<Self, CompilationError> {
+        let pool = match self.ctx.thread_pool() {
+            Some(pool) => pool,
+            None => {
+                for (amount, contract, metadata) in outputs {
+                    self = self.add_output(amount, contract, metadata)?;
+                }
+                return Ok(self);
+            }
+        };
+        // the context of each output, checking funds as add_output would
+        let mut remaining = self.ctx.clone();
+        let ctxs = outputs
+            .iter()
+            .enumerate()
+            .map(|(i, (amount, _, _))| {
+                let ctx = remaining
+                    .with_amount(*amount)?
+                    .output(self.outputs.len() + i);
+                remaining.spend_amount(*amount)?;
+                Ok(ctx)
+            })
+            .collect::<Result<Vec<_>, CompilationError>>()?;
+        let compiled: Vec<Result<Compiled, CompilationError>> = pool.install(|| {
+            outputs
+                .par_iter()
+                .zip(ctxs.par_iter())
+                .map(|((_, contract, _), ctx)| contract.compile(ctx))
+                .collect()
+        });
+        for (((amount, _, metadata), ctx), compiled) in outputs.into_iter().zip(ctxs).zip(compiled)
+        {
+            let mut compiled =
+                compiled.map_err(|e| e.within(PathStep::Output(self.outputs.len())))?;
+            ctx.prune(&mut compiled);
+            self.outputs.push(Output {
+                amount,
+                contract: compiled,
+                metadata: metadata.unwrap_or_else(HashMap::new),
+                commitments: vec![],
+            });
+            self = self.spend_amount(amount)?;
+        }
+        Ok(self)
+    }
+
     /// adds available funds to the builder's context object.
     /// TODO: Make guarantee there is some external input?
     pub fn add_amount(mut self, a: Amount) -> Self {
@@ -117,6 +271,15 @@ impl Builder {
         };
         Ok(self)
     }
+    /// set_relative_lock is set_sequence for a lock time in any unit (e.g.
+    /// `units::Blocks` or `units::Seconds`), failing if it is out of range.
+    pub fn set_relative_lock<L: RelativeLock>(
+        self,
+        ii: isize,
+        lock: L,
+    ) -> Result<Self, CompilationError> {
+        self.set_sequence(ii, lock.relative()?)
+    }
     /// set_lock_time adds a height or time based absolute lock time to the
     /// template. If a lock time is already set, it will check if it is of the
     /// same kind. Differing kinds will throw an error. Otherwise, it will merge
@@ -138,6 +301,33 @@ impl Builder {
         Ok(self)
     }
 
+    /// set_absolute_lock is set_lock_time for a lock time in any unit (e.g.
+    /// `units::AbsoluteHeight` or `units::MTP`), failing if it is out of
+    /// range.
+    pub fn set_absolute_lock<L: AbsoluteLock>(self, lock: L) -> Result<Self, CompilationError> {
+        self.set_lock_time(lock.absolute()?)
+    }
+
+    /// adds an overlay protocol's `commitment` to an output, see
+    /// `commitment`. Negative indexing works from the back, as for
+    /// `set_sequence`.
+    pub fn add_commitment(
+        mut self,
+        ii: isize,
+        commitment: Commitment,
+    ) -> Result<Self, CompilationError> {
+        let i = if ii >= 0 {
+            ii
+        } else {
+            self.outputs.len() as isize + ii
+        } as usize;
+        self.outputs
+            .get_mut(i)
+            .ok_or(CompilationError::NoSuchOutput)?
+            .commit(commitment);
+        Ok(self)
+    }
+
     /// overwrite any existing label with the provided string,
     /// or set a label if non provided thus far.
     pub fn set_label(mut self, label: String) -> Self {
@@ -145,6 +335,117 @@ impl Builder {
         self
     }
 
+    /// the estimated virtual size of the template, see `fees::estimated_vsize`
+    pub fn estimated_vsize(&self, witness_size: u64) -> u64 {
+        fees::estimated_vsize(&self.get_tx(), witness_size)
+    }
+
+    /// Pays the fees the fee strategy requires and sends any change, checking
+    /// that the template's amounts balance.
+    pub fn finish(mut self) -> Result<Template, CompilationError> {
+        // the anchors of every template, paid for by the funds reserved for
+        // them, see `anchor`
+        self.ctx.release_anchors();
+        let anchors = self.ctx.anchors().to_vec();
+        for anchor in anchors.iter() {
+            let contract = anchor.contract(self.ctx.network)?;
+            self.anchors += 1;
+            self = self.add_output(anchor.amount(), &contract, Some(anchor.metadata()))?;
+            if let Anchor::Ephemeral = anchor {
+                self.version = 3;
+            }
+        }
+        let change = self.change.take();
+        let strategy = self.fee_strategy;
+        match strategy {
+            FeeStrategy::Absolute => {}
+            FeeStrategy::Anchor => {
+                if self.anchors == 0 {
+                    return Err(CompilationError::MissingAnchor);
+                }
+            }
+            FeeStrategy::FeeRate {
+                sat_per_vbyte,
+                witness_size,
+            } => {
+                // estimate with the change output, as if it got all the funds
+                // left over
+                let mut tx = self.get_tx();
+                if let Some((contract, _)) = &change {
+                    let left = self.ctx.funds();
+                    tx.output.push(bitcoin::TxOut {
+                        value: left.as_sat(),
+                        script_pubkey: contract
+                            .compile(&self.ctx.with_amount(left)?)?
+                            .address
+                            .into(),
+                    });
+                }
+                let required =
+                    fees::fee_for(sat_per_vbyte, fees::estimated_vsize(&tx, witness_size));
+                if required > self.fees {
+                    let missing = required - self.fees;
+                    if missing > self.ctx.funds() {
+                        return Err(CompilationError::InsufficientFees {
+                            required,
+                            available: self.fees + self.ctx.funds(),
+                        });
+                    }
+                    self = self.add_fees(missing)?;
+                }
+            }
+        }
+        if let Some((contract, metadata)) = change {
+            let left = self.ctx.funds();
+            self = if left.as_sat() >= fees::DUST_SATS {
+                self.add_output(left, contract.as_ref(), Some(metadata))?
+            } else {
+                self.add_fees(left)?
+            };
+        }
+        // the change may have compiled to a longer script than estimated
+        if let FeeStrategy::FeeRate {
+            sat_per_vbyte,
+            witness_size,
+        } = strategy
+        {
+            let required = fees::fee_for(sat_per_vbyte, self.estimated_vsize(witness_size));
+            if required > self.fees {
+                return Err(CompilationError::InsufficientFees {
+                    required,
+                    available: self.fees,
+                });
+            }
+        }
+        // an ephemeral anchor relays only in a template paying no fees, which
+        // include the funds it leaves unspent
+        if anchors.iter().any(|a| matches!(a, Anchor::Ephemeral)) {
+            let fees = self.fees + self.ctx.funds();
+            if fees.as_sat() != 0 {
+                return Err(CompilationError::EphemeralAnchorFees { fees });
+            }
+        }
+        let tx = self.get_tx();
+        let mut metadata = TemplateMetadata::new();
+        metadata.label = self.label;
+        let mut template = Template {
+            outputs: self.outputs,
+            ctv: tx.get_ctv_hash(0),
+            ctv_index: 0,
+            max: tx.total_amount() + self.fees,
+            tx,
+            metadata_map_s2s: metadata,
+        };
+        if let Some(hook) = self.ctx.commitment_hook() {
+            for i in 0..template.outputs.len() {
+                for commitment in hook.commitments(&template, i)? {
+                    template.outputs[i].commit(commitment);
+                }
+            }
+        }
+        Ok(template)
+    }
+
     /// Creates a transaction from a Builder.
     /// Generally, should not be called directly.
     pub fn get_tx(&self) -> bitcoin::Transaction {
@@ -174,26 +475,16 @@ impl Builder {
         }
     }
 }
-impl From<Builder> for Template {
-    fn from(t: Builder) -> Template {
-        let tx = t.get_tx();
-        let mut metadata = TemplateMetadata::new();
-        metadata.label = t.label;
-        Template {
-            outputs: t.outputs,
-            ctv: tx.get_ctv_hash(0),
-            ctv_index: 0,
-            max: tx.total_amount() + t.fees,
-            tx,
-            metadata_map_s2s: metadata,
-        }
+impl TryFrom<Builder> for Template {
+    type Error = CompilationError;
+    fn try_from(t: Builder) -> Result<Template, CompilationError> {
+        t.finish()
     }
 }
-/// We don't implement TryFrom because this can never actually fail!
-/// We want to be able to use this anywhere we use into
+/// As well as TryFrom, so that this can be used anywhere we use into
 impl From<Builder> for Result<Template, CompilationError> {
     fn from(t: Builder) -> Self {
-        Ok(t.into())
+        t.finish()
     }
 }
 
@@ -206,3 +497,128 @@ impl From<Builder> for crate::contract::TxTmplIt {
         >::from(t))))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::*;
+
+    #[test]
+    fn fee_strategies() {
+        let ctx = ctx(1.0);
+        // a feerate with change spends every sat, paying at least the feerate
+        let tmpl = ctx
+            .template()
+            .add_output(Amount::from_btc(0.5).unwrap(), &to(), None)
+            .unwrap()
+            .set_fee_strategy(FeeStrategy::fee_rate(10))
+            .set_change(to(), None)
+            .finish()
+            .unwrap();
+        assert_eq!(tmpl.outputs.len(), 2);
+        assert_eq!(tmpl.max, Amount::from_btc(1.0).unwrap());
+        let vsize = fees::estimated_vsize(&tmpl.tx, fees::DEFAULT_WITNESS_SIZE);
+        assert!(tmpl.max - tmpl.total_amount() >= fees::fee_for(10, vsize));
+        // a feerate which can't be paid
+        match ctx
+            .template()
+            .add_output(Amount::from_btc(1.0).unwrap(), &to(), None)
+            .unwrap()
+            .set_fee_strategy(FeeStrategy::fee_rate(10))
+            .finish()
+        {
+            Err(CompilationError::InsufficientFees { .. }) => {}
+            _ => panic!("template should not have met its feerate"),
+        }
+        // paying fees by CPFP requires an anchor
+        let anchorless = ctx.template().set_fee_strategy(FeeStrategy::Anchor);
+        match Template::try_from(anchorless) {
+            Err(CompilationError::MissingAnchor) => {}
+            _ => panic!("template should require an anchor"),
+        }
+        let tmpl = ctx
+            .template()
+            .add_anchor(&to(), None)
+            .unwrap()
+            .set_fee_strategy(FeeStrategy::Anchor)
+            .finish()
+            .unwrap();
+        assert_eq!(tmpl.max, Amount::from_sat(fees::ANCHOR_SATS));
+    }
+
+    #[test]
+    fn parallel_compilation() {
+        let contract = fan(16);
+        let ctx = ctx(1.0);
+        let sequential = contract.compile(&ctx).unwrap();
+        let parallel = contract.compile(&ctx.with_threads(4).unwrap()).unwrap();
+        // the same object, with the outputs in order
+        assert_eq!(
+            serde_json::to_vec(&sequential).unwrap(),
+            serde_json::to_vec(&parallel).unwrap()
+        );
+        // running out of funds fails the same way
+        let poor = ctx
+            .with_amount(Amount::from_btc(0.5).unwrap())
+            .unwrap()
+            .with_threads(4)
+            .unwrap();
+        assert_eq!(contract.compile(&poor).unwrap_err().code(), "out-of-funds");
+    }
+
+    #[test]
+    fn data_carriers() {
+        use crate::contract::standard::{RelayPolicy, Rule, MAX_DATA_CARRIER};
+        let ctx = ctx(1.0);
+        let tmpl = ctx
+            .template()
+            .add_output(Amount::from_btc(0.5).unwrap(), &to(), None)
+            .unwrap()
+            .add_data_carrier(&[7; MAX_DATA_CARRIER], None)
+            .unwrap()
+            .finish()
+            .unwrap();
+        assert_eq!(tmpl.outputs.len(), 2);
+        assert_eq!(tmpl.tx.output[1].value, 0);
+        assert!(tmpl.tx.output[1].script_pubkey.is_op_return());
+        assert_eq!(tmpl.total_amount(), Amount::from_btc(0.5).unwrap());
+
+        let rule = |r: Result<Builder, CompilationError>| match r {
+            Err(CompilationError::Nonstandard(v)) => v.rule,
+            Err(e) => panic!("expected a nonstandard output, got {:?}", e),
+            Ok(_) => panic!("expected a nonstandard output"),
+        };
+        // too large to relay
+        assert_eq!(
+            rule(
+                ctx.template()
+                    .add_data_carrier(&[7; MAX_DATA_CARRIER + 1], None)
+            ),
+            Rule::OpReturnSize {
+                output: 0,
+                size: MAX_DATA_CARRIER + 4
+            }
+        );
+        // only one relays
+        assert_eq!(
+            rule(
+                ctx.template()
+                    .add_data_carrier(b"tag", None)
+                    .unwrap()
+                    .add_data_carrier(b"tag", None)
+            ),
+            Rule::OpReturns { count: 2 }
+        );
+        // but both may be mined
+        let tmpl = ctx
+            .with_relay_policy(RelayPolicy::Consensus)
+            .template()
+            .add_data_carrier(&[7; MAX_DATA_CARRIER + 1], None)
+            .unwrap()
+            .add_data_carrier(b"tag", None)
+            .unwrap()
+            .finish()
+            .unwrap();
+        assert_eq!(tmpl.outputs.len(), 2);
+    }
+}
@@ -0,0 +1,213 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Opaque commitments carried by the outputs of templates, so overlay
+//! protocols (e.g. RGB or Taro style assets) can build on sapio.
+//!
+//! An overlay protocol commits to its state in a transaction's outputs, e.g.
+//! by tweaking a taproot output's key (tapret) or in an OP_RETURN output
+//! (opret). Sapio doesn't interpret commitments: a `CommitmentHook`, set on
+//! the context with `Context::with_commitment_hook`, is called for each
+//! output of each template once the template is finished, and the
+//! commitments it returns are kept in the output's `commitments`.
+//! Commitments may also be added directly with `Builder::add_commitment`.
+//! An output has at most one commitment of each protocol and method, a later
+//! one replacing an earlier.
+//!
+//! Commitments are serialized with the compiled object, and
+//! `Object::bind_psbt` writes each into its output of the PSBT as a
+//! proprietary field (see `Commitment::psbt_key`), for the overlay's wallet
+//! to find. As the template hash commits to the outputs' scripts, a
+//! commitment changing a script (e.g. tapret) must be made by the contract
+//! compiled for the output; the commitment carried is then its proof.
+use super::{Output, Template};
+use crate::contract::CompilationError;
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::util::psbt::raw;
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// The type of proprietary PSBT fields (BIP-174)
+const PSBT_PROPRIETARY: u8 = 0xfc;
+/// The identifier of sapio's proprietary PSBT fields
+const PSBT_IDENTIFIER: &[u8] = b"sapio";
+/// The subtype of sapio's proprietary PSBT fields for commitments
+const PSBT_SUBTYPE_COMMITMENT: u8 = 0x00;
+
+/// An overlay protocol's commitment in an output, see the module docs
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct Commitment {
+    /// the overlay protocol, e.g. "rgb"
+    pub protocol: String,
+    /// how it is committed, e.g. "tapret" or "opret"
+    pub method: String,
+    /// the commitment, opaque to sapio
+    #[serde(serialize_with = "to_hex", deserialize_with = "from_hex")]
+    #[schemars(with = "String")]
+    pub data: Vec<u8>,
+}
+
+fn to_hex<S: Serializer>(data: &[u8], s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&data.to_hex())
+}
+
+fn from_hex<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+    let s = String::deserialize(d)?;
+    Vec::from_hex(&s).map_err(serde::de::Error::custom)
+}
+
+impl Commitment {
+    /// create a commitment of `protocol` by `method`
+    pub fn new(protocol: &str, method: &str, data: Vec<u8>) -> Self {
+        Commitment {
+            protocol: protocol.into(),
+            method: method.into(),
+            data,
+        }
+    }
+
+    /// The key of the commitment's proprietary PSBT output field: the
+    /// identifier "sapio", subtype 0, and the protocol and method separated
+    /// by a '/'. Its value is the commitment's data.
+    pub fn psbt_key(&self) -> raw::Key {
+        let mut key = vec![PSBT_IDENTIFIER.len() as u8];
+        key.extend_from_slice(PSBT_IDENTIFIER);
+        key.push(PSBT_SUBTYPE_COMMITMENT);
+        key.extend_from_slice(self.protocol.as_bytes());
+        key.push(b'/');
+        key.extend_from_slice(self.method.as_bytes());
+        raw::Key {
+            type_value: PSBT_PROPRIETARY,
+            key,
+        }
+    }
+}
+
+/// Makes the commitments of an overlay protocol, see the module docs
+pub trait CommitmentHook: Send + Sync {
+    /// the commitments to make in output `output` of `template`
+    fn commitments(
+        &self,
+        template: &Template,
+        output: usize,
+    ) -> Result<Vec<Commitment>, CompilationError>;
+}
+
+impl Output {
+    /// adds `commitment`, replacing any of the same protocol and method
+    pub fn commit(&mut self, commitment: Commitment) {
+        self.commitments
+            .retain(|c| c.protocol != commitment.protocol || c.method != commitment.method);
+        self.commitments.push(commitment);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::{Compilable, Compiled};
+    use crate::fixtures::*;
+    use bitcoin::util::amount::Amount;
+    use sapio_base::txindex::TxIndexLogger;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use std::sync::Arc;
+
+    /// commits to each output's index and the template's output count
+    struct Tagger;
+    impl CommitmentHook for Tagger {
+        fn commitments(
+            &self,
+            template: &Template,
+            output: usize,
+        ) -> Result<Vec<Commitment>, CompilationError> {
+            Ok(vec![Commitment::new(
+                "test",
+                "opret",
+                vec![output as u8, template.outputs.len() as u8],
+            )])
+        }
+    }
+
+    #[test]
+    fn commitments() {
+        let ctx = ctx(1.0);
+        let contract = TestEmulation {
+            to_contract: TestEmulation {
+                to_contract: to(),
+                amount: Amount::from_btc(1.0).unwrap(),
+                timeout: 6,
+            },
+            amount: Amount::from_btc(1.0).unwrap(),
+            timeout: 4,
+        };
+        let plain = contract.compile(&ctx).unwrap();
+        let compiled = contract
+            .compile(&ctx.with_commitment_hook(Arc::new(Tagger)))
+            .unwrap();
+        // commitments don't change the templates
+        assert_eq!(
+            compiled.ctv_to_tx.keys().collect::<Vec<_>>(),
+            plain.ctv_to_tx.keys().collect::<Vec<_>>()
+        );
+        let expected = Commitment::new("test", "opret", vec![0, 1]);
+        let outer = compiled.ctv_to_tx.values().next().unwrap();
+        assert_eq!(outer.outputs[0].commitments, vec![expected.clone()]);
+        // nested contracts are compiled with the hook too
+        let inner = outer.outputs[0].contract.ctv_to_tx.values().next().unwrap();
+        assert_eq!(inner.outputs[0].commitments, vec![expected.clone()]);
+        assert!(plain.ctv_to_tx.values().next().unwrap().outputs[0]
+            .commitments
+            .is_empty());
+
+        // they survive serialization
+        let json = serde_json::to_string(&compiled).unwrap();
+        let parsed: Compiled = serde_json::from_str(&json).unwrap();
+        let outer = parsed.ctv_to_tx.values().next().unwrap();
+        assert_eq!(outer.outputs[0].commitments, vec![expected.clone()]);
+
+        // and are written to the PSBTs' outputs
+        let (psbts, _) = compiled
+            .bind_psbt(
+                bitcoin::OutPoint::default(),
+                HashMap::new(),
+                Rc::new(TxIndexLogger::new()),
+                &CTVAvailable,
+            )
+            .unwrap();
+        assert_eq!(psbts.len(), 2);
+        for psbt in psbts.iter() {
+            assert_eq!(
+                psbt.outputs[0].unknown.get(&expected.psbt_key()),
+                Some(&expected.data)
+            );
+        }
+
+        // added directly, replacing one of the same protocol and method
+        let tmpl = ctx
+            .template()
+            .add_output(Amount::from_btc(0.5).unwrap(), &to(), None)
+            .unwrap()
+            .add_commitment(-1, Commitment::new("test", "tapret", vec![1]))
+            .unwrap()
+            .add_commitment(0, Commitment::new("test", "tapret", vec![2]))
+            .unwrap()
+            .finish()
+            .unwrap();
+        assert_eq!(
+            tmpl.outputs[0].commitments,
+            vec![Commitment::new("test", "tapret", vec![2])]
+        );
+        match ctx
+            .template()
+            .add_commitment(0, Commitment::new("test", "tapret", vec![]))
+        {
+            Err(e) => assert_eq!(e.code(), "no-such-output"),
+            Ok(_) => panic!("the template has no outputs"),
+        }
+    }
+}
@@ -0,0 +1,71 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! How a template pays its fees, see `Builder::set_fee_strategy`.
+//!
+//! By default a template pays exactly the fees added with `Builder::add_fees`
+//! (possibly none), and any funds it does not spend are left to the caller.
+//! Instead a template may target a feerate, paying enough fees for its
+//! estimated size, or pay no fees at all, leaving them to a child spending one
+//! of its anchor outputs (see `Builder::add_anchor` and `anchor`). Either way, the funds
+//! left over may be sent to a change output (see `Builder::set_change`).
+use bitcoin::util::amount::Amount;
+
+/// The value of an anchor output, as in Lightning's anchor outputs
+pub const ANCHOR_SATS: u64 = 330;
+/// Change smaller than this is paid as fees rather than creating an output
+pub const DUST_SATS: u64 = 546;
+/// The witness size, in bytes, assumed for each input when estimating a
+/// template's size: about that of a P2WSH spend with a signature and a short
+/// witness script
+pub const DEFAULT_WITNESS_SIZE: u64 = 150;
+
+/// How a template pays its fees, see the module docs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FeeStrategy {
+    /// pay exactly the fees added with `Builder::add_fees` (the default)
+    Absolute,
+    /// Pay at least `sat_per_vbyte` for the template's estimated size,
+    /// assuming each input's witness is `witness_size` bytes. Any fees added
+    /// with `Builder::add_fees` count towards it.
+    FeeRate {
+        /// the feerate targeted
+        sat_per_vbyte: u64,
+        /// the witness size assumed for each input
+        witness_size: u64,
+    },
+    /// pay no fees, requiring an anchor output for a child to pay them
+    Anchor,
+}
+
+impl Default for FeeStrategy {
+    fn default() -> Self {
+        FeeStrategy::Absolute
+    }
+}
+
+impl FeeStrategy {
+    /// target `sat_per_vbyte`, assuming `DEFAULT_WITNESS_SIZE` for each input
+    pub fn fee_rate(sat_per_vbyte: u64) -> Self {
+        FeeStrategy::FeeRate {
+            sat_per_vbyte,
+            witness_size: DEFAULT_WITNESS_SIZE,
+        }
+    }
+}
+
+/// The virtual size of `tx` (whose witnesses are empty, as a template's are)
+/// once each input has a witness of `witness_size` bytes.
+pub fn estimated_vsize(tx: &bitcoin::Transaction, witness_size: u64) -> u64 {
+    // the segwit marker and flag count once, each witness at a quarter
+    let weight = tx.get_weight() as u64 + 2 + witness_size * tx.input.len() as u64;
+    (weight + 3) / 4
+}
+
+/// the fees paying `sat_per_vbyte` for `vsize`
+pub fn fee_for(sat_per_vbyte: u64, vsize: u64) -> Amount {
+    Amount::from_sat(sat_per_vbyte.saturating_mul(vsize))
+}
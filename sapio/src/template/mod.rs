@@ -9,7 +9,7 @@ use bitcoin::hashes::sha256;
 use bitcoin::util::amount::Amount;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 pub mod output;
 pub use output::{Output, OutputMeta};
@@ -17,6 +17,15 @@ pub use output::{Output, OutputMeta};
 pub mod builder;
 pub use builder::Builder;
 
+pub mod fees;
+pub use fees::FeeStrategy;
+
+pub mod commitment;
+pub use commitment::{Commitment, CommitmentHook};
+
+pub mod anchor;
+pub use anchor::Anchor;
+
 /// Metadata Struct which has some standard defined fields
 /// and can be extended via a hashmap
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
@@ -24,7 +33,7 @@ pub struct TemplateMetadata {
     #[serde(skip_serializing_if = "Option::is_none", default)]
     label: Option<String>,
     #[serde(flatten)]
-    extra: HashMap<String, String>,
+    extra: BTreeMap<String, String>,
 }
 
 impl TemplateMetadata {
@@ -32,11 +41,15 @@ impl TemplateMetadata {
     pub fn skip_serializing(&self) -> bool {
         self.label.is_none() && self.extra.is_empty()
     }
+    /// the template's label, if any
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
     /// create a new `TemplateMetadata`
     pub fn new() -> Self {
         TemplateMetadata {
             label: None,
-            extra: HashMap::new(),
+            extra: BTreeMap::new(),
         }
     }
 }
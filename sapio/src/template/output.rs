@@ -25,7 +25,11 @@ pub struct Output {
     #[serde(
         rename = "metadata_map_s2s",
         skip_serializing_if = "HashMap::is_empty",
+        serialize_with = "crate::util::ordered::map",
         default
     )]
     pub metadata: OutputMeta,
+    /// overlay protocols' commitments in this output, see `commitment`
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub commitments: Vec<super::Commitment>,
 }
@@ -0,0 +1,389 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Property testing of contracts, enabled by the `testing` feature.
+//!
+//! `check` walks a compiled object's tree of templates for properties every
+//! contract should have, whatever its arguments:
+//!
+//! - amounts are conserved: no template spending only the contract's output
+//!   (its first input) pays out more than the output holds, and no template
+//!   pays out more than its maximum (see `Template::max`);
+//! - every output's script is standard, so the transactions relay;
+//! - every lock time and sequence is in range (see `Invariants`) and
+//!   enforced by consensus.
+//!
+//! `check_contract` runs it for a contract over random arguments with
+//! `proptest`, from a strategy for the arguments and a function making the
+//! contract of them. Arguments the contract refuses to compile are rejected
+//! rather than failing, so proptest reports a strategy making mostly
+//! invalid arguments. The strategies in this module (keys, addresses,
+//! amounts, and lock times) help writing ones for arguments:
+//!
+//! ```ignore
+//! check_contract(
+//!     Config::default(),
+//!     (public_keys(), addresses(Network::Regtest), amounts(1_000..10_000)),
+//!     amounts(100_000..100_000_000),
+//!     |(key, address, fee)| Ok(MyContract { key, address, fee }),
+//!     &Invariants::default(),
+//! )
+//! .unwrap();
+//! ```
+use crate::contract::standard::is_standard_script;
+use crate::contract::{Compilable, CompilationError, Compiled, Context};
+use crate::template::Template;
+use bitcoin::hashes::sha256;
+use bitcoin::secp256k1::{Secp256k1, SecretKey};
+use bitcoin::util::amount::Amount;
+use bitcoin::{Network, Script};
+use proptest::prelude::*;
+use proptest::test_runner::{Config, TestCaseError, TestError, TestRunner};
+use sapio_base::timelocks::units::{AbsoluteHeight, Blocks, Seconds, MTP};
+use sapio_ctv_emulator_trait::CTVAvailable;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+
+pub use proptest;
+
+/// Lock times below this are heights, and at or above it times
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+/// If set, a sequence has no relative lock time (BIP-68)
+const SEQUENCE_DISABLE_FLAG: u32 = 1 << 31;
+/// If set, a relative lock time is in units of 512 seconds (BIP-68)
+const SEQUENCE_TYPE_FLAG: u32 = 1 << 22;
+const SEQUENCE_MASK: u32 = 0x0000ffff;
+
+/// The ranges lock times are checked against, see the module docs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Invariants {
+    /// the largest height a lock time may be
+    pub max_height: u32,
+    /// the largest time a lock time may be
+    pub max_time: u32,
+    /// the largest relative lock in blocks, or 512 second intervals, a
+    /// sequence may be
+    pub max_relative: u16,
+}
+
+impl Default for Invariants {
+    /// heights for a hundred years of blocks, times until 2100, and any
+    /// relative lock
+    fn default() -> Self {
+        Invariants {
+            max_height: 5_256_000,
+            max_time: 4_102_444_800,
+            max_relative: u16::MAX,
+        }
+    }
+}
+
+/// A property a compiled object breaks, at the template `template`, reached
+/// by `path` (the templates, and the index of the output of each, leading to
+/// the contract creating it)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// the template pays out more than the funds it may spend
+    NotConserved {
+        /// the path to the contract
+        path: Vec<(sha256::Hash, usize)>,
+        /// the template
+        template: sha256::Hash,
+        /// what it may spend
+        funds: Amount,
+        /// what it pays out
+        spent: Amount,
+    },
+    /// an output's script isn't standard
+    Nonstandard {
+        /// the path to the contract
+        path: Vec<(sha256::Hash, usize)>,
+        /// the template
+        template: sha256::Hash,
+        /// the output
+        output: usize,
+        /// its script
+        script: Script,
+    },
+    /// the lock time is out of range, or isn't enforced
+    LockTime {
+        /// the path to the contract
+        path: Vec<(sha256::Hash, usize)>,
+        /// the template
+        template: sha256::Hash,
+        /// the lock time
+        lock_time: u32,
+    },
+    /// a sequence sets bits BIP-68 doesn't define, is out of range, or isn't
+    /// enforced
+    Sequence {
+        /// the path to the contract
+        path: Vec<(sha256::Hash, usize)>,
+        /// the template
+        template: sha256::Hash,
+        /// the input
+        input: usize,
+        /// the sequence
+        sequence: u32,
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::NotConserved {
+                template,
+                funds,
+                spent,
+                ..
+            } => write!(f, "{} spends {} of {}", template, spent, funds),
+            Violation::Nonstandard {
+                template,
+                output,
+                script,
+                ..
+            } => write!(
+                f,
+                "{} output {} has a nonstandard script {:x}",
+                template, output, script
+            ),
+            Violation::LockTime {
+                template,
+                lock_time,
+                ..
+            } => write!(f, "{} has a bad lock time {}", template, lock_time),
+            Violation::Sequence {
+                template,
+                input,
+                sequence,
+                ..
+            } => write!(
+                f,
+                "{} input {} has a bad sequence {:#x}",
+                template, input, sequence
+            ),
+        }
+    }
+}
+
+/// Every property `object`, holding `funds`, breaks, see the module docs.
+pub fn check(object: &Compiled, funds: Amount, invariants: &Invariants) -> Vec<Violation> {
+    let mut violations = vec![];
+    walk(object, funds, invariants, &mut vec![], &mut violations);
+    violations
+}
+
+fn walk(
+    object: &Compiled,
+    funds: Amount,
+    invariants: &Invariants,
+    path: &mut Vec<(sha256::Hash, usize)>,
+    violations: &mut Vec<Violation>,
+) {
+    let mut templates: Vec<&Template> = object
+        .ctv_to_tx
+        .values()
+        .chain(object.suggested_txs.values())
+        .collect();
+    templates.sort_by_key(|t| t.hash());
+    for t in templates {
+        let template = t.hash();
+        let spent = t.total_amount();
+        // other inputs may bring funds we can't know of
+        let available = if t.tx.input.len() == 1 {
+            std::cmp::min(funds, t.max)
+        } else {
+            t.max
+        };
+        if spent > available {
+            violations.push(Violation::NotConserved {
+                path: path.clone(),
+                template,
+                funds: available,
+                spent,
+            });
+        }
+        let lock_time = t.tx.lock_time;
+        let in_range = if lock_time < LOCKTIME_THRESHOLD {
+            lock_time <= invariants.max_height
+        } else {
+            lock_time <= invariants.max_time
+        };
+        let enforced = lock_time == 0 || t.tx.input.iter().any(|i| i.sequence != u32::MAX);
+        if !in_range || !enforced {
+            violations.push(Violation::LockTime {
+                path: path.clone(),
+                template,
+                lock_time,
+            });
+        }
+        for (input, i) in t.tx.input.iter().enumerate() {
+            let sequence = i.sequence;
+            if sequence & SEQUENCE_DISABLE_FLAG != 0 {
+                continue;
+            }
+            let defined = sequence & !(SEQUENCE_TYPE_FLAG | SEQUENCE_MASK) == 0;
+            let in_range = sequence & SEQUENCE_MASK <= u32::from(invariants.max_relative);
+            let enforced = sequence & SEQUENCE_MASK == 0 || t.tx.version >= 2;
+            if !defined || !in_range || !enforced {
+                violations.push(Violation::Sequence {
+                    path: path.clone(),
+                    template,
+                    input,
+                    sequence,
+                });
+            }
+        }
+        for (idx, output) in t.outputs.iter().enumerate() {
+            let script: Script = output.contract.address.clone().into();
+            if !is_standard_script(&script) {
+                violations.push(Violation::Nonstandard {
+                    path: path.clone(),
+                    template,
+                    output: idx,
+                    script,
+                });
+            }
+            path.push((template, idx));
+            walk(
+                &output.contract,
+                output.amount,
+                invariants,
+                path,
+                violations,
+            );
+            path.pop();
+        }
+    }
+}
+
+/// Checks the contracts `make` makes of arguments from `args`, compiled with
+/// funds from `funds`, for `invariants` (see the module docs), failing with
+/// the smallest arguments and funds breaking them that proptest finds.
+pub fn check_contract<S, T, F>(
+    config: Config,
+    args: S,
+    funds: impl Strategy<Value = Amount>,
+    make: F,
+    invariants: &Invariants,
+) -> Result<(), TestError<(S::Value, Amount)>>
+where
+    S: Strategy,
+    T: Compilable,
+    F: Fn(S::Value) -> Result<T, CompilationError>,
+{
+    let mut runner = TestRunner::new(config);
+    runner.run(&(args, funds), |(args, funds)| {
+        let ctx = Context::new(Network::Regtest, funds, Arc::new(CTVAvailable));
+        let object = make(args)
+            .and_then(|contract| contract.compile(&ctx))
+            .map_err(|e| TestCaseError::reject(format!("{:?}", e)))?;
+        let violations = check(&object, funds, invariants);
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(TestCaseError::fail(
+                violations
+                    .iter()
+                    .map(|v| v.to_string())
+                    .collect::<Vec<_>>()
+                    .join("; "),
+            ))
+        }
+    })
+}
+
+/// Random public keys
+pub fn public_keys() -> impl Strategy<Value = bitcoin::PublicKey> {
+    any::<[u8; 32]>().prop_filter_map("not a valid secret key", |bytes| {
+        SecretKey::from_slice(&bytes)
+            .ok()
+            .map(|secret| bitcoin::PublicKey {
+                compressed: true,
+                key: bitcoin::secp256k1::PublicKey::from_secret_key(&Secp256k1::new(), &secret),
+            })
+    })
+}
+
+/// Random addresses on `network`, of every standard type
+pub fn addresses(network: Network) -> impl Strategy<Value = bitcoin::Address> {
+    (public_keys(), 0..4u8).prop_map(move |(key, kind)| match kind {
+        0 => bitcoin::Address::p2pkh(&key, network),
+        1 => bitcoin::Address::p2shwpkh(&key, network).unwrap(),
+        2 => bitcoin::Address::p2wsh(
+            &bitcoin::Address::p2pkh(&key, network).script_pubkey(),
+            network,
+        ),
+        _ => bitcoin::Address::p2wpkh(&key, network).unwrap(),
+    })
+}
+
+/// Random amounts, in sats
+pub fn amounts(sats: Range<u64>) -> impl Strategy<Value = Amount> {
+    sats.prop_map(Amount::from_sat)
+}
+
+/// Random relative locks in blocks
+pub fn blocks() -> impl Strategy<Value = Blocks> {
+    (1..=u16::MAX).prop_map(Blocks)
+}
+
+/// Random relative locks in seconds, in range for a sequence
+pub fn seconds() -> impl Strategy<Value = Seconds> {
+    (1..=u32::from(u16::MAX) * 512).prop_map(Seconds)
+}
+
+/// Random absolute height locks, below `max`
+pub fn absolute_heights(max: u32) -> impl Strategy<Value = AbsoluteHeight> {
+    (1..max.min(LOCKTIME_THRESHOLD)).prop_map(AbsoluteHeight)
+}
+
+/// Random absolute time locks, below `max`
+pub fn mtps(max: u32) -> impl Strategy<Value = MTP> {
+    (LOCKTIME_THRESHOLD..max.max(LOCKTIME_THRESHOLD + 1)).prop_map(MTP)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::*;
+
+    #[test]
+    fn property_harness() {
+        let invariants = Invariants::default();
+        check_contract(
+            Config::with_cases(32),
+            (
+                1..8u64,
+                amounts(1000..1_000_000),
+                addresses(Network::Regtest),
+            ),
+            amounts(1_000_000..10_000_000),
+            |(n, amount, to)| Ok(Fan { n, amount, to }),
+            &invariants,
+        )
+        .unwrap();
+        // a lock time beyond the range checked
+        let ctx = ctx(0.01);
+        let fan = Fan {
+            n: 2,
+            amount: Amount::from_sat(1000),
+            to: address(),
+        }
+        .compile(&ctx)
+        .unwrap();
+        assert!(check(&fan, ctx.funds(), &invariants).is_empty());
+        let strict = Invariants {
+            max_relative: 0,
+            ..invariants
+        };
+        // the second split's emulation waits a block
+        assert!(check(&fan, ctx.funds(), &strict)
+            .iter()
+            .any(|v| matches!(v, Violation::Sequence { sequence: 1, .. })));
+    }
+}
@@ -0,0 +1,109 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Output descriptor checksums (BIP-380), for exporting the descriptors of a
+//! contract's addresses (see `Object::descriptors`).
+
+const INPUT_CHARSET: &str =
+    "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+fn poly_mod(mut c: u64, val: u64) -> u64 {
+    let c0 = c >> 35;
+    c = ((c & 0x7ffffffff) << 5) ^ val;
+    for (bit, gen) in [
+        0xf5dee51989u64,
+        0xa9fdca3312,
+        0x1bab10e32d,
+        0x3706b1677a,
+        0x644d626ffd,
+    ]
+    .iter()
+    .enumerate()
+    {
+        if c0 & (1 << bit) != 0 {
+            c ^= gen;
+        }
+    }
+    c
+}
+
+/// The checksum of `desc`, or None if it has characters descriptors can't
+pub fn checksum(desc: &str) -> Option<String> {
+    let mut c = 1;
+    let mut cls = 0;
+    let mut count = 0;
+    for ch in desc.chars() {
+        let pos = INPUT_CHARSET.find(ch)? as u64;
+        c = poly_mod(c, pos & 31);
+        cls = cls * 3 + (pos >> 5);
+        count += 1;
+        if count == 3 {
+            c = poly_mod(c, cls);
+            cls = 0;
+            count = 0;
+        }
+    }
+    if count > 0 {
+        c = poly_mod(c, cls);
+    }
+    for _ in 0..8 {
+        c = poly_mod(c, 0);
+    }
+    c ^= 1;
+    Some(
+        (0..8)
+            .map(|j| CHECKSUM_CHARSET[((c >> (5 * (7 - j))) & 31) as usize] as char)
+            .collect(),
+    )
+}
+
+/// `desc`, replacing any checksum it has with its own
+pub fn with_checksum(desc: &str) -> Option<String> {
+    let desc = desc.split('#').next().unwrap_or(desc);
+    checksum(desc).map(|c| format!("{}#{}", desc, c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::Compilable;
+    use crate::fixtures::*;
+    use bitcoin::util::amount::Amount;
+
+    #[test]
+    fn descriptor_export() {
+        assert_eq!(
+            with_checksum("raw(deadbeef)").unwrap(),
+            "raw(deadbeef)#89f8spxm"
+        );
+        let contract = TestEmulation {
+            to_contract: TestEmulation {
+                to_contract: to(),
+                amount: Amount::from_btc(1.0).unwrap(),
+                timeout: 6,
+            },
+            amount: Amount::from_btc(1.0).unwrap(),
+            timeout: 4,
+        };
+        let compiled = contract.compile(&ctx(1.0)).unwrap();
+        let descriptors = compiled.descriptors();
+        // the contract, the one it creates, and the address paid
+        assert_eq!(descriptors.len(), 3);
+        assert!(descriptors[0].path.is_empty());
+        assert!(descriptors[0].descriptor.starts_with("wsh("));
+        assert!(descriptors[1].descriptor.starts_with("wsh("));
+        assert_eq!(descriptors[2].path.len(), 2);
+        assert_eq!(
+            descriptors[2].descriptor,
+            with_checksum(&format!("addr({})", address())).unwrap()
+        );
+        for d in descriptors.iter() {
+            let (desc, sum) = d.raw.split_at(d.raw.find('#').unwrap());
+            assert_eq!(&sum[1..], checksum(desc).unwrap());
+        }
+    }
+}
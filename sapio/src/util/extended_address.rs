@@ -36,9 +36,12 @@ impl ExtendedAddress {
         if slice.len() > 40 {
             return Err(ObjectError::OpReturnTooLong);
         }
-        Ok(ExtendedAddress::OpReturn(OpReturn(
-            bitcoin::Script::new_op_return(slice),
-        )))
+        Ok(ExtendedAddress::make_data_carrier(slice))
+    }
+    /// create an OP_RETURN address type of any size, see
+    /// `Builder::add_data_carrier` for the limits nodes relay
+    pub fn make_data_carrier(data: &[u8]) -> Self {
+        ExtendedAddress::OpReturn(OpReturn(bitcoin::Script::new_op_return(data)))
     }
 }
 
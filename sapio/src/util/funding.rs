@@ -0,0 +1,396 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Funding several contracts, for several funders, in one transaction.
+//!
+//! A `FundingRound` collects each funder's registrations: the coins they
+//! spend (inputs), the contracts, or plain outputs, they pay, and where their
+//! change goes. `FundingRound::finish` then builds a single transaction for
+//! all of them, splitting its fee between the funders by the weight each
+//! adds, and returns it as a PSBT for every funder to sign their inputs of,
+//! along with the outpoint each contract is funded at (to bind it with
+//! `Object::bind_psbt`).
+//!
+//! As in a coinjoin, inputs and outputs are sorted (by outpoint, and by
+//! amount and script), so their order doesn't reveal which funder registered
+//! them.
+use super::split::{Rounding, Split, SplitError};
+use crate::contract::Compiled;
+use crate::template::fees::{estimated_vsize, fee_for, DEFAULT_WITNESS_SIZE, DUST_SATS};
+use bitcoin::consensus::encode::serialize;
+use bitcoin::util::amount::Amount;
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::{OutPoint, Script, Transaction, TxIn, TxOut};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+/// Errors building a funding transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FundingError {
+    /// a funder registered twice
+    DuplicateFunder(String),
+    /// a registration for a funder who didn't register
+    UnknownFunder(String),
+    /// an input registered twice
+    DuplicateInput(OutPoint),
+    /// nothing is funded
+    NoOutputs,
+    /// a funder's inputs don't cover their outputs and share of the fee
+    Underfunded {
+        /// the funder
+        funder: String,
+        /// what the funder's inputs are short by
+        missing: Amount,
+    },
+    /// the fee couldn't be split
+    Split(SplitError),
+}
+
+impl fmt::Display for FundingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for FundingError {}
+
+impl From<SplitError> for FundingError {
+    fn from(e: SplitError) -> Self {
+        FundingError::Split(e)
+    }
+}
+
+/// One funder's registrations
+struct Funder {
+    inputs: Vec<(OutPoint, TxOut)>,
+    outputs: Vec<TxOut>,
+    contracts: Vec<(TxOut, Compiled)>,
+    change: Script,
+}
+
+impl Funder {
+    fn spent(&self) -> Amount {
+        Amount::from_sat(self.inputs.iter().map(|(_, o)| o.value).sum())
+    }
+    fn paid(&self) -> Amount {
+        Amount::from_sat(
+            self.outputs
+                .iter()
+                .chain(self.contracts.iter().map(|(o, _)| o))
+                .map(|o| o.value)
+                .sum(),
+        )
+    }
+    /// the weight the funder's registrations, and their change, add
+    fn weight(&self) -> u64 {
+        // outpoint, script length and sequence, and the witness
+        let inputs = self.inputs.len() as u64 * (41 * 4 + DEFAULT_WITNESS_SIZE);
+        let outputs: u64 = self
+            .outputs
+            .iter()
+            .chain(self.contracts.iter().map(|(o, _)| o))
+            .chain(std::iter::once(&TxOut {
+                value: 0,
+                script_pubkey: self.change.clone(),
+            }))
+            .map(|o| serialize(o).len() as u64 * 4)
+            .sum();
+        inputs + outputs
+    }
+}
+
+/// A funding transaction being built, see the module docs
+pub struct FundingRound {
+    sat_per_vbyte: u64,
+    funders: BTreeMap<String, Funder>,
+    outpoints: HashSet<OutPoint>,
+}
+
+/// A funding transaction, see the module docs
+pub struct Funding {
+    /// the transaction, for the funders to sign their inputs of
+    pub psbt: PartiallySignedTransaction,
+    /// each funder's share of the fee
+    pub fees: BTreeMap<String, Amount>,
+    /// each funder's contracts, and the outpoints funding them
+    pub contracts: BTreeMap<String, Vec<(OutPoint, Compiled)>>,
+}
+
+impl FundingRound {
+    /// a round paying `sat_per_vbyte`
+    pub fn new(sat_per_vbyte: u64) -> Self {
+        FundingRound {
+            sat_per_vbyte,
+            funders: BTreeMap::new(),
+            outpoints: HashSet::new(),
+        }
+    }
+
+    /// Registers `funder`, whose change (if not dust) is paid to `change`.
+    pub fn register_funder(&mut self, funder: &str, change: Script) -> Result<(), FundingError> {
+        if self.funders.contains_key(funder) {
+            return Err(FundingError::DuplicateFunder(funder.into()));
+        }
+        self.funders.insert(
+            funder.into(),
+            Funder {
+                inputs: vec![],
+                outputs: vec![],
+                contracts: vec![],
+                change,
+            },
+        );
+        Ok(())
+    }
+
+    fn funder(&mut self, funder: &str) -> Result<&mut Funder, FundingError> {
+        self.funders
+            .get_mut(funder)
+            .ok_or_else(|| FundingError::UnknownFunder(funder.into()))
+    }
+
+    /// Registers `funder` spending the coin `utxo` at `outpoint`.
+    pub fn register_input(
+        &mut self,
+        funder: &str,
+        outpoint: OutPoint,
+        utxo: TxOut,
+    ) -> Result<(), FundingError> {
+        if self.outpoints.contains(&outpoint) {
+            return Err(FundingError::DuplicateInput(outpoint));
+        }
+        self.funder(funder)?.inputs.push((outpoint, utxo));
+        self.outpoints.insert(outpoint);
+        Ok(())
+    }
+
+    /// Registers `funder` paying `amount` to `script_pubkey`.
+    pub fn register_output(
+        &mut self,
+        funder: &str,
+        amount: Amount,
+        script_pubkey: Script,
+    ) -> Result<(), FundingError> {
+        self.funder(funder)?.outputs.push(TxOut {
+            value: amount.as_sat(),
+            script_pubkey,
+        });
+        Ok(())
+    }
+
+    /// Registers `funder` funding `contract`, compiled for `amount`.
+    pub fn register_contract(
+        &mut self,
+        funder: &str,
+        amount: Amount,
+        contract: Compiled,
+    ) -> Result<(), FundingError> {
+        let out = TxOut {
+            value: amount.as_sat(),
+            script_pubkey: contract.address.clone().into(),
+        };
+        self.funder(funder)?.contracts.push((out, contract));
+        Ok(())
+    }
+
+    /// Builds the funding transaction, see the module docs.
+    pub fn finish(self) -> Result<Funding, FundingError> {
+        if self
+            .funders
+            .values()
+            .all(|f| f.outputs.is_empty() && f.contracts.is_empty())
+        {
+            return Err(FundingError::NoOutputs);
+        }
+        let mut tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![],
+        };
+        let mut utxos = BTreeMap::new();
+        for f in self.funders.values() {
+            for (outpoint, utxo) in f.inputs.iter() {
+                tx.input.push(TxIn {
+                    previous_output: *outpoint,
+                    script_sig: Script::new(),
+                    sequence: 0xffff_fffd,
+                    witness: vec![],
+                });
+                utxos.insert(*outpoint, utxo.clone());
+            }
+            tx.output.extend(f.outputs.iter().cloned());
+            tx.output.extend(f.contracts.iter().map(|(o, _)| o.clone()));
+        }
+        // estimated with every funder's change, so never short
+        let mut with_change = tx.clone();
+        with_change
+            .output
+            .extend(self.funders.values().map(|f| TxOut {
+                value: 0,
+                script_pubkey: f.change.clone(),
+            }));
+        let fee = fee_for(
+            self.sat_per_vbyte,
+            estimated_vsize(&with_change, DEFAULT_WITNESS_SIZE),
+        );
+        let weights: Vec<u64> = self.funders.values().map(Funder::weight).collect();
+        let split = Split::weighted(fee, &weights, Rounding::RemainderToFirst)?;
+        let mut fees = BTreeMap::new();
+        for ((name, f), share) in self.funders.iter().zip(split.shares()) {
+            let owed = f.paid() + *share;
+            let change = f
+                .spent()
+                .checked_sub(owed)
+                .ok_or_else(|| FundingError::Underfunded {
+                    funder: name.clone(),
+                    missing: owed - f.spent(),
+                })?;
+            if change.as_sat() >= DUST_SATS {
+                tx.output.push(TxOut {
+                    value: change.as_sat(),
+                    script_pubkey: f.change.clone(),
+                });
+            }
+            fees.insert(name.clone(), *share);
+        }
+        tx.input.sort_by_key(|i| i.previous_output);
+        tx.output
+            .sort_by(|a, b| (a.value, &a.script_pubkey[..]).cmp(&(b.value, &b.script_pubkey[..])));
+        let txid = tx.txid();
+        let mut taken = HashSet::new();
+        let mut contracts = BTreeMap::new();
+        for (name, f) in self.funders.into_iter() {
+            let mut funded = vec![];
+            for (out, contract) in f.contracts.into_iter() {
+                // identical outputs are interchangeable, but each is taken once
+                let vout = (0..tx.output.len())
+                    .find(|i| tx.output[*i] == out && !taken.contains(i))
+                    .expect("every contract has an output");
+                taken.insert(vout);
+                funded.push((
+                    OutPoint {
+                        txid,
+                        vout: vout as u32,
+                    },
+                    contract,
+                ));
+            }
+            contracts.insert(name, funded);
+        }
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx.clone())
+            .expect("an unsigned transaction");
+        for (psbt_in, tx_in) in psbt.inputs.iter_mut().zip(tx.input.iter()) {
+            psbt_in.witness_utxo = utxos.get(&tx_in.previous_output).cloned();
+        }
+        Ok(Funding {
+            psbt,
+            fees,
+            contracts,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::{Compilable, Context};
+    use crate::fixtures::*;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::sync::Arc;
+
+    #[test]
+    fn funding_round() {
+        let keys = [key(1), key(2)];
+        let address = |k: &bitcoin::PublicKey| {
+            bitcoin::Address::p2wpkh(k, bitcoin::Network::Regtest).unwrap()
+        };
+        let pool = |k: &bitcoin::PublicKey, sats| {
+            let ctx = Context::new(
+                bitcoin::Network::Regtest,
+                Amount::from_sat(sats),
+                Arc::new(CTVAvailable),
+            );
+            Pool {
+                key: *k,
+                to: address(k),
+            }
+            .compile(&ctx)
+            .unwrap()
+        };
+        let coin = |i: u32, sats| {
+            (
+                bitcoin::OutPoint {
+                    txid: Default::default(),
+                    vout: i,
+                },
+                TxOut {
+                    value: sats,
+                    script_pubkey: address(&keys[0]).script_pubkey(),
+                },
+            )
+        };
+
+        let mut round = FundingRound::new(10);
+        round
+            .register_funder("alice", address(&keys[0]).script_pubkey())
+            .unwrap();
+        round
+            .register_funder("bob", address(&keys[1]).script_pubkey())
+            .unwrap();
+        assert_eq!(
+            round.register_funder("bob", Script::new()),
+            Err(FundingError::DuplicateFunder("bob".into()))
+        );
+        let (o, u) = coin(0, 1_000_000);
+        round.register_input("alice", o, u.clone()).unwrap();
+        assert_eq!(
+            round.register_input("bob", o, u),
+            Err(FundingError::DuplicateInput(o))
+        );
+        for (i, sats) in [(1, 300_000), (2, 300_000)].iter() {
+            let (o, u) = coin(*i, *sats);
+            round.register_input("bob", o, u).unwrap();
+        }
+        round
+            .register_contract("alice", Amount::from_sat(500_000), pool(&keys[0], 500_000))
+            .unwrap();
+        round
+            .register_contract("bob", Amount::from_sat(550_000), pool(&keys[1], 550_000))
+            .unwrap();
+        let funding = round.finish().unwrap();
+
+        let tx = &funding.psbt.global.unsigned_tx;
+        assert_eq!(tx.input.len(), 3);
+        assert!(funding.psbt.inputs.iter().all(|i| i.witness_utxo.is_some()));
+        // two contracts and two change outputs
+        assert_eq!(tx.output.len(), 4);
+        let fees = funding
+            .fees
+            .values()
+            .fold(Amount::from_sat(0), |a, b| a + *b);
+        let paid: u64 = tx.output.iter().map(|o| o.value).sum();
+        assert_eq!(paid + fees.as_sat(), 1_600_000);
+        // bob's two inputs weigh more than alice's one
+        assert!(funding.fees["bob"] > funding.fees["alice"]);
+        for (out, compiled) in funding.contracts.values().flatten() {
+            assert_eq!(out.txid, tx.txid());
+            let script: Script = compiled.address.clone().into();
+            assert_eq!(tx.output[out.vout as usize].script_pubkey, script);
+        }
+
+        let mut short = FundingRound::new(10);
+        short.register_funder("carol", Script::new()).unwrap();
+        let (o, u) = coin(3, 1000);
+        short.register_input("carol", o, u).unwrap();
+        short
+            .register_output("carol", Amount::from_sat(1000), Script::new())
+            .unwrap();
+        assert!(matches!(
+            short.finish(),
+            Err(FundingError::Underfunded { .. })
+        ));
+    }
+}
@@ -6,4 +6,8 @@
 
 //! Basic functionality / structs for Sapio
 pub mod amountrange;
+pub mod descriptor;
 pub mod extended_address;
+pub mod funding;
+pub mod ordered;
+pub mod split;
@@ -0,0 +1,20 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Serializing maps in key order, so that compiling the same contract twice
+//! serializes to identical bytes regardless of the order of a `HashMap`.
+use serde::{Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap};
+
+/// serializes `map` in key order, for `#[serde(serialize_with)]`
+pub fn map<S, K, V>(map: &HashMap<K, V>, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    K: Ord + Serialize,
+    V: Serialize,
+{
+    s.collect_map(map.iter().collect::<BTreeMap<_, _>>())
+}
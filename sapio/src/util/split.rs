@@ -0,0 +1,161 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Splitting an amount between the branches of a contract (e.g. the outputs
+//! of a template) without losing sats to rounding.
+//!
+//! Dividing an `Amount` between `n` branches rounds down, silently leaving up
+//! to `n - 1` sats to the fees. A `Split` makes what happens to the remainder
+//! explicit, and checks that the shares and the fee sum to the amount split
+//! before returning them.
+use bitcoin::util::amount::Amount;
+use std::fmt;
+
+/// What to do with the sats left over by rounding the shares of a `Split`
+/// down
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rounding {
+    /// add them to the first share
+    RemainderToFirst,
+    /// leave them unassigned, to be paid as fees
+    RemainderToFee,
+}
+
+/// Errors splitting an amount
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SplitError {
+    /// there were no branches to split between
+    NoBranches,
+    /// the weights of the branches sum to zero
+    ZeroWeight,
+    /// the shares and the fee didn't sum to the amount split
+    Unbalanced {
+        /// the amount split
+        total: Amount,
+        /// what the shares and the fee sum to
+        assigned: Amount,
+    },
+}
+
+impl fmt::Display for SplitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for SplitError {}
+
+/// An amount split between branches, see the module docs
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Split {
+    shares: Vec<Amount>,
+    fee: Amount,
+}
+
+impl Split {
+    /// Splits `total` into `n` equal shares, the remainder assigned by
+    /// `rounding`.
+    pub fn even(total: Amount, n: usize, rounding: Rounding) -> Result<Split, SplitError> {
+        Split::weighted(total, &vec![1; n], rounding)
+    }
+
+    /// Splits `total` into shares proportional to `weights`, each rounded
+    /// down, the remainder assigned by `rounding`.
+    pub fn weighted(
+        total: Amount,
+        weights: &[u64],
+        rounding: Rounding,
+    ) -> Result<Split, SplitError> {
+        if weights.is_empty() {
+            return Err(SplitError::NoBranches);
+        }
+        let sum: u128 = weights.iter().map(|w| *w as u128).sum();
+        if sum == 0 {
+            return Err(SplitError::ZeroWeight);
+        }
+        let sats = total.as_sat() as u128;
+        // each share is at most `total`, so fits in a u64
+        let mut shares: Vec<Amount> = weights
+            .iter()
+            .map(|w| Amount::from_sat((sats * *w as u128 / sum) as u64))
+            .collect();
+        let remainder = total - shares.iter().fold(Amount::from_sat(0), |a, b| a + *b);
+        let fee = match rounding {
+            Rounding::RemainderToFirst => {
+                shares[0] += remainder;
+                Amount::from_sat(0)
+            }
+            Rounding::RemainderToFee => remainder,
+        };
+        let split = Split { shares, fee };
+        split.verify(total)?;
+        Ok(split)
+    }
+
+    /// Checks that the shares and the fee sum to `total`.
+    pub fn verify(&self, total: Amount) -> Result<(), SplitError> {
+        let assigned = self
+            .shares
+            .iter()
+            .try_fold(self.fee, |a, b| a.checked_add(*b))
+            .unwrap_or(Amount::max_value());
+        if assigned == total {
+            Ok(())
+        } else {
+            Err(SplitError::Unbalanced { total, assigned })
+        }
+    }
+
+    /// the shares, in the order of the branches
+    pub fn shares(&self) -> &[Amount] {
+        &self.shares
+    }
+
+    /// the sats left unassigned, to be paid as fees
+    pub fn fee(&self) -> Amount {
+        self.fee
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split() {
+        let total = Amount::from_sat(1000);
+        let even = Split::even(total, 3, Rounding::RemainderToFee).unwrap();
+        assert_eq!(even.shares(), &[Amount::from_sat(333); 3][..]);
+        assert_eq!(even.fee(), Amount::from_sat(1));
+        let first = Split::even(total, 3, Rounding::RemainderToFirst).unwrap();
+        assert_eq!(first.shares()[0], Amount::from_sat(334));
+        assert_eq!(first.fee(), Amount::from_sat(0));
+        let weighted = Split::weighted(total, &[1, 2, 0], Rounding::RemainderToFirst).unwrap();
+        assert_eq!(
+            weighted.shares(),
+            &[
+                Amount::from_sat(334),
+                Amount::from_sat(666),
+                Amount::from_sat(0)
+            ][..]
+        );
+        weighted.verify(total).unwrap();
+        assert_eq!(
+            weighted.verify(Amount::from_sat(999)),
+            Err(SplitError::Unbalanced {
+                total: Amount::from_sat(999),
+                assigned: total
+            })
+        );
+        assert_eq!(
+            Split::even(total, 0, Rounding::RemainderToFee),
+            Err(SplitError::NoBranches)
+        );
+        assert_eq!(
+            Split::weighted(total, &[0, 0], Rounding::RemainderToFee),
+            Err(SplitError::ZeroWeight)
+        );
+    }
+}